@@ -2,7 +2,13 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
-use crate::DiskTopology;
+use crate::lifetime::DiskLifetime;
+use crate::scsi::{ScsiCommand, ScsiResponse};
+use crate::zoned::BlkZone;
+use crate::{AsyncIoBackend, DiskTopology, SECTOR_SIZE};
+use std::fs::File;
+use std::mem::ManuallyDrop;
+use std::os::unix::io::{FromRawFd, RawFd};
 use thiserror::Error;
 use vmm_sys_util::eventfd::EventFd;
 
@@ -14,16 +20,241 @@ pub enum DiskFileError {
     /// Failed creating a new AsyncIo.
     #[error("Failed creating a new AsyncIo: {0}")]
     NewAsyncIo(#[source] std::io::Error),
+    /// The disk file shrank.
+    #[error("The disk file shrank from {0} to {1} bytes")]
+    Shrunk(u64, u64),
+    /// Failed getting the allocated extents of the disk file.
+    #[error("Failed getting the allocated extents of the disk file: {0}")]
+    Extents(#[source] std::io::Error),
+    /// Failed getting the space left on the filesystem of the disk file.
+    #[error("Failed getting the space left on the filesystem of the disk file: {0}")]
+    AvailableSpace(#[source] std::io::Error),
+    /// The disk file doesn't track the blocks written to.
+    #[error("The disk file does not support dirty block tracking")]
+    DirtyTrackingNotSupported,
+    /// Dirty block tracking hasn't been started.
+    #[error("Dirty block tracking has not been started")]
+    DirtyTrackingNotStarted,
+    /// The dirty block tracking granularity is invalid.
+    #[error("Invalid dirty block tracking granularity: {0}")]
+    InvalidDirtyGranularity(u64),
+    /// Failed detecting the alignment required by O_DIRECT.
+    #[error("Failed detecting the O_DIRECT alignment of the disk, set logical_block_size: {0}")]
+    DirectIoAlignment(#[source] std::io::Error),
+    /// The file descriptor can't back a disk.
+    #[error("Invalid disk file descriptor: {0}")]
+    InvalidFd(#[source] std::io::Error),
+    /// A striped disk was given no children.
+    #[error("A striped disk needs at least one child")]
+    NoStripeChildren,
+    /// The children of a striped disk differ in size.
+    #[error("The children of the striped disk differ in size: {0} and {1} bytes")]
+    StripeChildSizeMismatch(u64, u64),
+    /// The stripe size isn't a multiple of the logical block size dividing
+    /// the size of the children.
+    #[error("Invalid stripe size: {0}")]
+    InvalidStripeSize(u64),
+    /// A concatenated disk was given no children.
+    #[error("A concatenated disk needs at least one child")]
+    NoConcatChildren,
+    /// The size of a child of a concatenated disk isn't a multiple of the
+    /// logical block size.
+    #[error("Invalid size of a concatenated disk child: {0} bytes")]
+    InvalidConcatChildSize(u64),
+    /// The disk isn't backed by a device taking SCSI commands.
+    #[error("The disk does not support SCSI pass-through")]
+    ScsiNotSupported,
+    /// Failed sending a SCSI command to the device backing the disk.
+    #[error("Failed sending the SCSI command: {0}")]
+    ScsiCommand(#[source] std::io::Error),
+    /// The manifest of the disk doesn't cover it exactly.
+    #[error("The manifest covers {0} bytes, the disk is {1} bytes large")]
+    ManifestSize(u64, u64),
+    /// Failed extending the file to the size of its DAX window.
+    #[error("Failed extending the disk file to its DAX window: {0}")]
+    DaxExtend(#[source] std::io::Error),
+    /// Failed mapping the file as a DAX window.
+    #[error("Failed mapping the disk file: {0}")]
+    DaxMap(#[source] std::io::Error),
+    /// Failed reading or writing the header or the bitmap of an overlay
+    /// scratch file.
+    #[error("Failed accessing the overlay scratch file: {0}")]
+    OverlayScratch(#[source] std::io::Error),
+    /// Failed reading the base image of an overlay to identify it.
+    #[error("Failed identifying the base image of the overlay: {0}")]
+    OverlayBaseIdentity(#[source] std::io::Error),
+    /// The overlay scratch file has content but no valid header.
+    #[error("The overlay scratch file has no valid header")]
+    InvalidOverlayHeader,
+    /// The overlay scratch file was written by another version of the format.
+    #[error("Unsupported overlay scratch file format version: {0}")]
+    UnsupportedOverlayVersion(u32),
+    /// The overlay scratch file was created on top of another base image.
+    #[error("The overlay scratch file does not match its base image")]
+    OverlayBaseMismatch,
 }
 
 pub type DiskFileResult<T> = std::result::Result<T, DiskFileError>;
 
+/// Alignment in bytes the requests handed to a backend must honor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IoAlignment {
+    /// Alignment of the offsets and lengths of the requests.
+    pub offset: u64,
+    /// Alignment of the addresses of their buffers, which O_DIRECT may
+    /// require to be smaller than the offset alignment.
+    pub memory: u64,
+}
+
+impl IoAlignment {
+    /// Alignment of the requests accepted by any backend.
+    pub const SECTOR: IoAlignment = IoAlignment {
+        offset: SECTOR_SIZE,
+        memory: SECTOR_SIZE,
+    };
+
+    /// No alignment at all, for backends going through the page cache or
+    /// realigning the requests themselves.
+    pub const NONE: IoAlignment = IoAlignment {
+        offset: 1,
+        memory: 1,
+    };
+
+    /// Returns the alignment required by the requests to the file behind
+    /// `fd`, which only needs one if it was opened with O_DIRECT.
+    pub fn probe(fd: RawFd) -> std::io::Result<Self> {
+        // SAFETY: FFI call with a valid fd
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        if flags & libc::O_DIRECT == 0 {
+            return Ok(IoAlignment::NONE);
+        }
+
+        // SAFETY: fd is valid for the duration of the call, and wrapping the
+        // File with ManuallyDrop prevents it from being closed.
+        let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+        if let Some(alignment) = DiskTopology::direct_io_requirements(&file)? {
+            return Ok(alignment);
+        }
+
+        // Older kernels don't report the alignment of regular files, the
+        // logical block size of the device being what they require.
+        let block_size = DiskTopology::probe(&file)?.logical_block_size;
+        Ok(IoAlignment {
+            offset: block_size,
+            memory: block_size,
+        })
+    }
+
+    /// Alignment satisfying both `self` and `other`.
+    pub fn max(self, other: IoAlignment) -> IoAlignment {
+        IoAlignment {
+            offset: self.offset.max(other.offset),
+            memory: self.memory.max(other.memory),
+        }
+    }
+}
+
+/// Priority of a request, a hint the backend may use to serve it ahead of
+/// the others. It never breaks the dependencies between requests, a request
+/// depending on another one being only submitted once that one completed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RequestPriority {
+    #[default]
+    Normal,
+    High,
+}
+
+/// Range of a disk holding data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DiskExtent {
+    pub offset: u64,
+    pub length: u64,
+}
+
 pub trait DiskFile: Send {
     fn size(&mut self) -> DiskFileResult<u64>;
     fn new_async_io(&self, ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>>;
     fn topology(&mut self) -> DiskTopology {
         DiskTopology::default()
     }
+    fn supports_discard(&self) -> bool {
+        false
+    }
+    fn supports_write_zeroes(&self) -> bool {
+        false
+    }
+    /// Whether the ranges discarded are guaranteed to read back as zeroes.
+    fn discard_zeroes(&self) -> bool {
+        false
+    }
+    /// Identifier of the storage backing the disk, reported to the guest
+    /// when no serial is configured.
+    fn serial(&mut self) -> Option<String> {
+        None
+    }
+    /// Backend the requests to the disk are served by.
+    fn backend(&self) -> AsyncIoBackend {
+        AsyncIoBackend::Sync
+    }
+    /// Whether the storage backing the disk is rotational. Files are
+    /// reported as non-rotational.
+    fn rotational(&mut self) -> bool {
+        false
+    }
+    /// Wear estimate of the flash storage backing the disk. Files and the
+    /// storage whose lifetime can't be read are reported with an unknown
+    /// one.
+    fn get_lifetime(&mut self) -> DiskLifetime {
+        DiskLifetime::default()
+    }
+    /// Bytes the filesystem holding the disk file can still allocate, for
+    /// the file to grow into as the guest writes. Unknown for the disks not
+    /// backed by a file which can grow, such as block devices.
+    fn available_space(&self) -> DiskFileResult<Option<u64>> {
+        Ok(None)
+    }
+    /// Re-read the size of a disk file whose backing storage may have grown
+    /// since it was opened. Shrinking is refused since the guest may still
+    /// be using the data that vanished.
+    fn resize(&mut self, current_size: u64) -> DiskFileResult<u64> {
+        let size = self.size()?;
+        if size < current_size {
+            return Err(DiskFileError::Shrunk(current_size, size));
+        }
+        Ok(size)
+    }
+    /// Returns the ranges of the disk holding data, in ascending order, so
+    /// that holes can be skipped when copying it. Backends unable to tell
+    /// report the whole disk as allocated.
+    fn extents(&mut self) -> DiskFileResult<Vec<DiskExtent>> {
+        let size = self.size()?;
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+        Ok(vec![DiskExtent {
+            offset: 0,
+            length: size,
+        }])
+    }
+    /// Start recording the blocks of `granularity` bytes modified by write,
+    /// write zeroes and discard requests, forgetting any previous record.
+    fn start_dirty_tracking(&mut self, _granularity: u64) -> DiskFileResult<()> {
+        Err(DiskFileError::DirtyTrackingNotSupported)
+    }
+    fn stop_dirty_tracking(&mut self) {}
+    /// Returns the ranges of the disk modified since tracking started or
+    /// since the previous call, in ascending order, and clears them.
+    fn get_dirty_blocks(&mut self) -> DiskFileResult<Vec<DiskExtent>> {
+        Err(DiskFileError::DirtyTrackingNotSupported)
+    }
+    /// Forward `command` to the SCSI device backing the disk, returning
+    /// once it has completed.
+    fn send_scsi_command(&mut self, _command: &ScsiCommand) -> DiskFileResult<ScsiResponse> {
+        Err(DiskFileError::ScsiNotSupported)
+    }
 }
 
 #[derive(Error, Debug)]
@@ -37,11 +268,103 @@ pub enum AsyncIoError {
     /// Failed synchronizing file.
     #[error("Failed synchronizing file: {0}")]
     Fsync(#[source] std::io::Error),
+    /// Failed discarding a range of the file.
+    #[error("Failed discarding a range of the file: {0}")]
+    Discard(#[source] std::io::Error),
+    /// The file does not support discarding ranges.
+    #[error("The file does not support discarding ranges")]
+    DiscardNotSupported,
+    /// Failed writing zeroes to a range of the file.
+    #[error("Failed writing zeroes to a range of the file: {0}")]
+    WriteZeroes(#[source] std::io::Error),
+    /// The file does not support writing zeroes to ranges.
+    #[error("The file does not support writing zeroes to ranges")]
+    WriteZeroesNotSupported,
+    /// The file does not support FUA writes.
+    #[error("The file does not support FUA writes")]
+    FuaNotSupported,
+    /// Failed registering buffers with the backend.
+    #[error("Failed registering buffers: {0}")]
+    RegisterBuffers(#[source] std::io::Error),
+    /// Failed submitting the queued requests.
+    #[error("Failed submitting the queued requests: {0}")]
+    Submit(#[source] std::io::Error),
+    /// The file is read-only.
+    #[error("The file is read-only")]
+    ReadOnly,
+    /// The file is not zoned.
+    #[error("The file is not zoned")]
+    ZonesNotSupported,
+    /// Failed reporting zones.
+    #[error("Failed reporting zones: {0}")]
+    ZoneReport(#[source] std::io::Error),
+    /// Failed managing a zone.
+    #[error("Failed managing a zone: {0}")]
+    ZoneManagement(#[source] std::io::Error),
+    /// Write not starting at the write pointer of a sequential zone.
+    #[error("Write at offset {0} doesn't match the zone write pointer at offset {1}")]
+    UnalignedZoneWrite(u64, u64),
+    /// Data read not matching its digest.
+    #[error("Block {0} doesn't match its digest")]
+    Corrupt(u64),
+    /// Failed canceling a request.
+    #[error("Failed canceling a request: {0}")]
+    Cancel(#[source] std::io::Error),
+    /// Too many completions are waiting to be reaped.
+    #[error("Too many completions waiting to be reaped")]
+    CompletionsFull,
 }
 
 pub type AsyncIoResult<T> = std::result::Result<T, AsyncIoError>;
 
+/// Completions a synchronous backend holds until they are reaped. Past
+/// those, it refuses new requests with [`AsyncIoError::CompletionsFull`]
+/// rather than growing its completion list without limit, until some are
+/// reaped through `next_completed_request()`.
+pub const MAX_PENDING_COMPLETIONS: usize = 1024;
+
+/// Number of requests a synchronous backend holding `pending` completions
+/// can still take.
+pub(crate) fn completion_room(pending: usize) -> usize {
+    MAX_PENDING_COMPLETIONS.saturating_sub(pending)
+}
+
+/// Refuses the request of a synchronous backend holding `pending`
+/// completions, once they reached [`MAX_PENDING_COMPLETIONS`].
+pub(crate) fn check_completion_room(pending: usize) -> AsyncIoResult<()> {
+    if completion_room(pending) == 0 {
+        return Err(AsyncIoError::CompletionsFull);
+    }
+
+    Ok(())
+}
+
+/// Waits, through its notifier, for the next request `io` completes, and
+/// returns its user data and result.
+#[cfg(test)]
+pub(crate) fn wait_completion(io: &mut dyn AsyncIo) -> (u64, i32) {
+    use std::os::unix::io::AsRawFd;
+
+    loop {
+        if let Some(completion) = io.next_completed_request() {
+            return completion;
+        }
+        let mut pollfd = libc::pollfd {
+            fd: io.notifier().as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: FFI call with a valid pollfd
+        assert_eq!(unsafe { libc::poll(&mut pollfd, 1, 1000) }, 1);
+        io.notifier().read().unwrap();
+    }
+}
+
 pub trait AsyncIo: Send {
+    /// Event signaled whenever requests complete. Its value is not a number
+    /// of completions: the wakeups may be coalesced, so every wakeup must be
+    /// followed by calls to `next_completed_request()` until it returns
+    /// `None`.
     fn notifier(&self) -> &EventFd;
     fn read_vectored(
         &mut self,
@@ -56,5 +379,122 @@ pub trait AsyncIo: Send {
         user_data: u64,
     ) -> AsyncIoResult<()>;
     fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()>;
+    /// `read_vectored()` with a priority, ignored by the backends unable to
+    /// honor it.
+    fn read_vectored_with_priority(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+        _priority: RequestPriority,
+    ) -> AsyncIoResult<()> {
+        self.read_vectored(offset, iovecs, user_data)
+    }
+    /// `write_vectored()` with a priority, ignored by the backends unable to
+    /// honor it.
+    fn write_vectored_with_priority(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+        _priority: RequestPriority,
+    ) -> AsyncIoResult<()> {
+        self.write_vectored(offset, iovecs, user_data)
+    }
+    /// `fsync()` with a priority, ignored by the backends unable to honor
+    /// it.
+    fn fsync_with_priority(
+        &mut self,
+        user_data: Option<u64>,
+        _priority: RequestPriority,
+    ) -> AsyncIoResult<()> {
+        self.fsync(user_data)
+    }
+    /// Write with Force Unit Access, completing once the data reached the
+    /// storage, without waiting for the other writes to be persisted like a
+    /// flush would.
+    fn write_vectored_fua(
+        &mut self,
+        _offset: libc::off_t,
+        _iovecs: &[libc::iovec],
+        _user_data: u64,
+    ) -> AsyncIoResult<()> {
+        Err(AsyncIoError::FuaNotSupported)
+    }
+    /// Registers memory regions the buffers of upcoming requests usually
+    /// point to, such as the guest RAM, sparing the backend from mapping
+    /// them again on every request. Requests pointing elsewhere are served
+    /// as usual. Returns whether the backend took advantage of the regions.
+    fn register_buffers(&mut self, _regions: &[libc::iovec]) -> AsyncIoResult<bool> {
+        Ok(false)
+    }
+    /// Alignment the requests must honor, checked by the caller before
+    /// handing them over rather than failing deep in the backend. Defaults
+    /// to the sector size, the alignment of any valid guest request.
+    fn required_alignment(&self) -> IoAlignment {
+        IoAlignment::SECTOR
+    }
+    /// Number of requests the backend takes before some of its completions
+    /// are reaped, the ones past it being refused with
+    /// `AsyncIoError::CompletionsFull`. Checked by the caller so that it
+    /// stops submitting instead. Unbounded by default, for the backends
+    /// whose completions are bounded by their ring.
+    fn submission_room(&self) -> usize {
+        usize::MAX
+    }
+    fn discard(
+        &mut self,
+        _offset: libc::off_t,
+        _length: u64,
+        _user_data: u64,
+    ) -> AsyncIoResult<()> {
+        Err(AsyncIoError::DiscardNotSupported)
+    }
+    fn write_zeroes(
+        &mut self,
+        _offset: libc::off_t,
+        _length: u64,
+        _unmap: bool,
+        _user_data: u64,
+    ) -> AsyncIoResult<()> {
+        Err(AsyncIoError::WriteZeroesNotSupported)
+    }
+    /// Fill `zones` with the descriptors of the zones starting from the one
+    /// containing `offset`, completing with the number of zones reported.
+    fn zone_report(
+        &mut self,
+        _offset: libc::off_t,
+        _zones: &mut [BlkZone],
+        _user_data: u64,
+    ) -> AsyncIoResult<()> {
+        Err(AsyncIoError::ZonesNotSupported)
+    }
+    fn zone_open(&mut self, _offset: libc::off_t, _user_data: u64) -> AsyncIoResult<()> {
+        Err(AsyncIoError::ZonesNotSupported)
+    }
+    fn zone_close(&mut self, _offset: libc::off_t, _user_data: u64) -> AsyncIoResult<()> {
+        Err(AsyncIoError::ZonesNotSupported)
+    }
+    fn zone_finish(&mut self, _offset: libc::off_t, _user_data: u64) -> AsyncIoResult<()> {
+        Err(AsyncIoError::ZonesNotSupported)
+    }
+    fn zone_reset(&mut self, _offset: libc::off_t, _user_data: u64) -> AsyncIoResult<()> {
+        Err(AsyncIoError::ZonesNotSupported)
+    }
+    /// Asks the backend to cancel the request submitted with `user_data`,
+    /// which then completes with an error, ECANCELED or EINTR, unless it
+    /// completes first or can't be interrupted. Returns whether the backend
+    /// is able to cancel requests at all.
+    fn cancel(&mut self, _user_data: u64) -> AsyncIoResult<bool> {
+        Ok(false)
+    }
+    /// Submit the requests queued since the last call. Backends batching
+    /// their submissions only guarantee that a request makes progress once
+    /// this has been called.
+    fn submit(&mut self) -> AsyncIoResult<()> {
+        Ok(())
+    }
+    /// Returns the next completed request, with its user data and result,
+    /// each request being returned exactly once.
     fn next_completed_request(&mut self) -> Option<(u64, i32)>;
 }
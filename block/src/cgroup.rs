@@ -0,0 +1,131 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Throttling of the disk requests by the io controller of cgroup v2, which
+//! limits the I/O of a cgroup per host block device through `io.max`.
+
+use libc::{S_IFBLK, S_IFMT};
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum CgroupError {
+    /// Failed getting the device backing the disk.
+    #[error("Failed getting the device backing the disk: {0}")]
+    Stat(#[source] io::Error),
+    /// The disk isn't backed by a block device.
+    #[error("The disk is not backed by a block device")]
+    NotBlockDevice,
+    /// Failed writing the limits of the cgroup.
+    #[error("Failed writing the limits to {0:?}: {1}")]
+    WriteIoMax(PathBuf, #[source] io::Error),
+    /// Failed moving the process to the cgroup.
+    #[error("Failed moving the process to {0:?}: {1}")]
+    JoinCgroup(PathBuf, #[source] io::Error),
+}
+
+pub type CgroupResult<T> = std::result::Result<T, CgroupError>;
+
+/// Limits of `io.max`, per second, `None` leaving a limit unchanged.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IoMax {
+    pub rbps: Option<u64>,
+    pub wbps: Option<u64>,
+    pub riops: Option<u64>,
+    pub wiops: Option<u64>,
+}
+
+impl IoMax {
+    pub fn is_empty(&self) -> bool {
+        *self == IoMax::default()
+    }
+
+    fn line(&self, (major, minor): (u32, u32)) -> String {
+        let mut line = format!("{major}:{minor}");
+        for (key, limit) in [
+            ("rbps", self.rbps),
+            ("wbps", self.wbps),
+            ("riops", self.riops),
+            ("wiops", self.wiops),
+        ] {
+            if let Some(limit) = limit {
+                line.push_str(&format!(" {key}={limit}"));
+            }
+        }
+        line
+    }
+}
+
+/// Returns the number of the whole block device backing `f`, which is
+/// either a block device or a file on a filesystem stored on one. The io
+/// controller doesn't take partitions.
+pub fn backing_device(f: &File) -> CgroupResult<(u32, u32)> {
+    let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+    // SAFETY: FFI call with a valid fd and buffer
+    let ret = unsafe { libc::fstat(f.as_raw_fd(), stat.as_mut_ptr()) };
+    if ret != 0 {
+        return Err(CgroupError::Stat(io::Error::last_os_error()));
+    }
+    // SAFETY: stat is valid at this point
+    let stat = unsafe { stat.assume_init() };
+    let dev = if stat.st_mode & S_IFMT == S_IFBLK {
+        stat.st_rdev
+    } else {
+        stat.st_dev
+    };
+
+    // SAFETY: major() and minor() only split the device number
+    let (major, minor) = unsafe { (libc::major(dev), libc::minor(dev)) };
+
+    // Filesystems without a block device, such as tmpfs or NFS, have a
+    // device number which isn't known to the block layer.
+    let sysfs_dir = format!("/sys/dev/block/{major}:{minor}");
+    if !Path::new(&sysfs_dir).exists() {
+        return Err(CgroupError::NotBlockDevice);
+    }
+    if !Path::new(&format!("{sysfs_dir}/partition")).exists() {
+        return Ok((major, minor));
+    }
+
+    let disk = fs::read_to_string(format!("{sysfs_dir}/../dev")).map_err(CgroupError::Stat)?;
+    disk.trim()
+        .split_once(':')
+        .and_then(|(major, minor)| Some((major.parse().ok()?, minor.parse().ok()?)))
+        .ok_or(CgroupError::NotBlockDevice)
+}
+
+/// Limits the I/O of `cgroup` to the block device backing `f`, and moves
+/// the process to it. The io controller isn't threaded, so the whole process
+/// has to be moved rather than the threads serving the disk.
+pub fn throttle(cgroup: &Path, f: &File, limits: &IoMax) -> CgroupResult<()> {
+    let device = backing_device(f)?;
+
+    // The limits are written first, a missing io controller making it fail
+    // before the process is moved.
+    let io_max = cgroup.join("io.max");
+    fs::write(&io_max, limits.line(device)).map_err(|e| CgroupError::WriteIoMax(io_max, e))?;
+
+    let procs = cgroup.join("cgroup.procs");
+    fs::write(&procs, std::process::id().to_string()).map_err(|e| CgroupError::JoinCgroup(procs, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_io_max_line() {
+        let limits = IoMax {
+            rbps: Some(1 << 20),
+            wiops: Some(100),
+            ..Default::default()
+        };
+        assert!(!limits.is_empty());
+        assert_eq!(limits.line((8, 16)), "8:16 rbps=1048576 wiops=100");
+        assert!(IoMax::default().is_empty());
+    }
+}
@@ -0,0 +1,412 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Concatenation of several [`DiskFile`]s, such as the files of a split
+//! image, spanning them one after the other.
+//!
+//! Each child covers the range of the disk following the previous child. A
+//! request is split in one segment per child it covers, bounded by the ends
+//! of the child. The request completes once every segment did.
+
+use crate::async_io::{
+    AsyncIo, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult, IoAlignment,
+};
+use crate::striped::{iovecs_len, ChildSegment, ChildrenIo};
+use crate::{AsyncIoBackend, DiskTopology};
+use vmm_sys_util::eventfd::EventFd;
+
+pub struct ConcatDiskFile {
+    children: Vec<Box<dyn DiskFile>>,
+    // Offset of the disk each child starts at, followed by the size of the
+    // disk.
+    starts: Vec<u64>,
+    topology: DiskTopology,
+}
+
+impl ConcatDiskFile {
+    /// Concatenates `children` in the given order, the size of each one
+    /// being a multiple of the largest logical block size among them.
+    pub fn new(mut children: Vec<Box<dyn DiskFile>>) -> DiskFileResult<Self> {
+        if children.is_empty() {
+            return Err(DiskFileError::NoConcatChildren);
+        }
+
+        let topologies: Vec<DiskTopology> =
+            children.iter_mut().map(|child| child.topology()).collect();
+        let logical_block_size = topologies
+            .iter()
+            .map(|topology| topology.logical_block_size)
+            .max()
+            .unwrap();
+
+        // A request aligned on the logical block size must stay aligned once
+        // split at the ends of the children.
+        let mut starts = vec![0];
+        for child in children.iter_mut() {
+            let size = child.size()?;
+            if size % logical_block_size != 0 {
+                return Err(DiskFileError::InvalidConcatChildSize(size));
+            }
+            starts.push(starts.last().unwrap() + size);
+        }
+
+        Ok(ConcatDiskFile {
+            topology: concat_topology(&topologies),
+            children,
+            starts,
+        })
+    }
+}
+
+// Topology of the concatenation, keeping the segments sent to each child
+// within its limits: a child segment only has the buffers of the request,
+// one of them possibly split at the end of the child.
+fn concat_topology(topologies: &[DiskTopology]) -> DiskTopology {
+    let max_segments = topologies
+        .iter()
+        .map(|topology| topology.max_segments)
+        .min()
+        .unwrap();
+
+    DiskTopology {
+        logical_block_size: topologies
+            .iter()
+            .map(|topology| topology.logical_block_size)
+            .max()
+            .unwrap(),
+        physical_block_size: topologies
+            .iter()
+            .map(|topology| topology.physical_block_size)
+            .max()
+            .unwrap(),
+        max_transfer_size: topologies
+            .iter()
+            .map(|topology| topology.max_transfer_size)
+            .filter(|size| *size != 0)
+            .min()
+            .unwrap_or(0),
+        max_segments: max_segments.saturating_sub(1).max(1),
+        max_discard_size: topologies
+            .iter()
+            .map(|topology| topology.max_discard_size)
+            .min()
+            .unwrap(),
+        max_write_zeroes_size: topologies
+            .iter()
+            .map(|topology| topology.max_write_zeroes_size)
+            .min()
+            .unwrap(),
+        ..Default::default()
+    }
+}
+
+impl DiskFile for ConcatDiskFile {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        Ok(*self.starts.last().unwrap())
+    }
+
+    fn new_async_io(&self, ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        let children = self
+            .children
+            .iter()
+            .map(|child| child.new_async_io(ring_depth))
+            .collect::<DiskFileResult<Vec<Box<dyn AsyncIo>>>>()?;
+
+        Ok(Box::new(ConcatAsyncIo {
+            io: ChildrenIo::new(children).map_err(DiskFileError::NewAsyncIo)?,
+            starts: self.starts.clone(),
+        }) as Box<dyn AsyncIo>)
+    }
+
+    fn topology(&mut self) -> DiskTopology {
+        self.topology.clone()
+    }
+
+    fn supports_discard(&self) -> bool {
+        self.children.iter().all(|child| child.supports_discard())
+    }
+
+    fn backend(&self) -> AsyncIoBackend {
+        // The children are usually opened alike.
+        self.children[0].backend()
+    }
+
+    fn supports_write_zeroes(&self) -> bool {
+        self.children
+            .iter()
+            .all(|child| child.supports_write_zeroes())
+    }
+
+    fn discard_zeroes(&self) -> bool {
+        self.children.iter().all(|child| child.discard_zeroes())
+    }
+
+    fn resize(&mut self, current_size: u64) -> DiskFileResult<u64> {
+        // Each child starts where the previous one ends, so none of them can
+        // change size without moving the data of the following ones.
+        let size = *self.starts.last().unwrap();
+        if size < current_size {
+            return Err(DiskFileError::Shrunk(current_size, size));
+        }
+
+        Ok(size)
+    }
+}
+
+// Splits the `length` bytes at `offset` into one segment per child, the
+// children starting at `starts`. Nothing is sent past the last child, whose
+// end is the size of the disk.
+fn split(starts: &[u64], offset: u64, length: u64) -> Vec<ChildSegment> {
+    let end = offset + length;
+
+    starts
+        .windows(2)
+        .enumerate()
+        .filter(|(_, range)| range[0] < range[1] && range[0] < end && offset < range[1])
+        .map(|(child, range)| {
+            let start = offset.max(range[0]);
+            let length = end.min(range[1]) - start;
+            ChildSegment {
+                child,
+                offset: start - range[0],
+                length,
+                pieces: vec![(start - offset, length)],
+            }
+        })
+        .collect()
+}
+
+pub struct ConcatAsyncIo {
+    io: ChildrenIo,
+    starts: Vec<u64>,
+}
+
+// SAFETY: the iovecs point to the guest memory of in flight requests, which
+// stays valid until the requests complete.
+unsafe impl Send for ConcatAsyncIo {}
+
+impl AsyncIo for ConcatAsyncIo {
+    fn notifier(&self) -> &EventFd {
+        self.io.notifier()
+    }
+
+    fn required_alignment(&self) -> IoAlignment {
+        self.io.required_alignment()
+    }
+
+    fn submission_room(&self) -> usize {
+        self.io.submission_room()
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let segments = split(&self.starts, offset as u64, iovecs_len(iovecs));
+        self.io
+            .submit_vectored(&segments, iovecs, user_data, |child, offset, iovecs| {
+                child.read_vectored(offset, iovecs, user_data)
+            })
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let segments = split(&self.starts, offset as u64, iovecs_len(iovecs));
+        self.io
+            .submit_vectored(&segments, iovecs, user_data, |child, offset, iovecs| {
+                child.write_vectored(offset, iovecs, user_data)
+            })
+    }
+
+    fn write_vectored_fua(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let segments = split(&self.starts, offset as u64, iovecs_len(iovecs));
+        self.io
+            .submit_vectored(&segments, iovecs, user_data, |child, offset, iovecs| {
+                child.write_vectored_fua(offset, iovecs, user_data)
+            })
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        self.io.fsync(user_data)
+    }
+
+    fn register_buffers(&mut self, regions: &[libc::iovec]) -> AsyncIoResult<bool> {
+        self.io.register_buffers(regions)
+    }
+
+    fn discard(&mut self, offset: libc::off_t, length: u64, user_data: u64) -> AsyncIoResult<()> {
+        let segments = split(&self.starts, offset as u64, length);
+        self.io
+            .submit_segments(&segments, user_data, |child, segment| {
+                child.discard(segment.offset as libc::off_t, segment.length, user_data)
+            })
+    }
+
+    fn write_zeroes(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        unmap: bool,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let segments = split(&self.starts, offset as u64, length);
+        self.io
+            .submit_segments(&segments, user_data, |child, segment| {
+                child.write_zeroes(
+                    segment.offset as libc::off_t,
+                    segment.length,
+                    unmap,
+                    user_data,
+                )
+            })
+    }
+
+    fn submit(&mut self) -> AsyncIoResult<()> {
+        self.io.submit()
+    }
+
+    fn next_completed_request(&mut self) -> Option<(u64, i32)> {
+        self.io.next_completed_request()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_io::wait_completion;
+    use crate::raw_sync::RawFileDiskSync;
+    use crate::CacheMode;
+    use std::os::unix::fs::FileExt;
+    use vmm_sys_util::tempfile::TempFile;
+
+    fn child_disk(size: u64) -> (TempFile, Box<dyn DiskFile>) {
+        let file = TempFile::new().unwrap();
+        file.as_file().set_len(size).unwrap();
+        let disk = Box::new(
+            RawFileDiskSync::new(
+                file.as_file().try_clone().unwrap(),
+                false,
+                CacheMode::Writeback,
+                None,
+            )
+            .unwrap(),
+        );
+        (file, disk)
+    }
+
+    fn iovec(buf: &mut [u8]) -> libc::iovec {
+        libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }
+    }
+
+    #[test]
+    fn test_split() {
+        let starts = [0, 4096, 4096, 12288];
+
+        // From the middle of the first child to the middle of the last one,
+        // skipping the empty child in between.
+        assert_eq!(
+            split(&starts, 2048, 4096),
+            vec![
+                ChildSegment {
+                    child: 0,
+                    offset: 2048,
+                    length: 2048,
+                    pieces: vec![(0, 2048)],
+                },
+                ChildSegment {
+                    child: 2,
+                    offset: 0,
+                    length: 2048,
+                    pieces: vec![(2048, 2048)],
+                },
+            ]
+        );
+
+        // Within a single child.
+        assert_eq!(
+            split(&starts, 8192, 1024),
+            vec![ChildSegment {
+                child: 2,
+                offset: 4096,
+                length: 1024,
+                pieces: vec![(0, 1024)],
+            }]
+        );
+
+        assert!(split(&starts, 0, 0).is_empty());
+        assert!(split(&starts, 12288, 512).is_empty());
+    }
+
+    #[test]
+    fn test_concat_read_write() {
+        let (file0, child0) = child_disk(4096);
+        let (file1, child1) = child_disk(8192);
+        let mut disk = ConcatDiskFile::new(vec![child0, child1]).unwrap();
+        assert_eq!(disk.size().unwrap(), 12288);
+        let mut io = disk.new_async_io(1).unwrap();
+
+        // The write crosses the end of the first child, with a buffer split
+        // between the two.
+        let mut head = vec![1u8; 2048];
+        let mut tail = vec![2u8; 4096];
+        io.write_vectored(3072, &[iovec(&mut head), iovec(&mut tail)], 1)
+            .unwrap();
+        io.submit().unwrap();
+        assert_eq!(wait_completion(io.as_mut()), (1, 6144));
+        assert_eq!(io.next_completed_request(), None);
+
+        let mut data = vec![0u8; 4096];
+        file0.as_file().read_exact_at(&mut data, 0).unwrap();
+        assert!(data[..3072].iter().all(|b| *b == 0));
+        assert!(data[3072..].iter().all(|b| *b == 1));
+        file1.as_file().read_exact_at(&mut data, 0).unwrap();
+        assert!(data[..1024].iter().all(|b| *b == 1));
+        assert!(data[1024..].iter().all(|b| *b == 2));
+        file1.as_file().read_exact_at(&mut data, 4096).unwrap();
+        assert!(data[..1024].iter().all(|b| *b == 2));
+        assert!(data[1024..].iter().all(|b| *b == 0));
+
+        let mut buf = vec![0xffu8; 12288];
+        io.read_vectored(0, &[iovec(&mut buf)], 2).unwrap();
+        io.submit().unwrap();
+        assert_eq!(wait_completion(io.as_mut()), (2, 12288));
+        assert!(buf[..3072].iter().all(|b| *b == 0));
+        assert!(buf[3072..5120].iter().all(|b| *b == 1));
+        assert!(buf[5120..9216].iter().all(|b| *b == 2));
+        assert!(buf[9216..].iter().all(|b| *b == 0));
+
+        // Flushing every child.
+        io.fsync(Some(3)).unwrap();
+        assert_eq!(wait_completion(io.as_mut()), (3, 0));
+    }
+
+    #[test]
+    fn test_concat_invalid() {
+        let (_file0, child0) = child_disk(4096);
+        let (_file1, child1) = child_disk(1000);
+        assert!(matches!(
+            ConcatDiskFile::new(vec![child0, child1]),
+            Err(DiskFileError::InvalidConcatChildSize(1000))
+        ));
+
+        assert!(matches!(
+            ConcatDiskFile::new(Vec::new()),
+            Err(DiskFileError::NoConcatChildren)
+        ));
+    }
+}
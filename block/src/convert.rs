@@ -0,0 +1,356 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Conversion of a disk to a new image of another format, without relying on
+//! external tools. Only the allocated extents of the source are copied, the
+//! rest of the destination image being left unallocated. As with imports,
+//! the image only appears at its path once the whole disk was converted.
+
+use crate::async_io::{AsyncIo, AsyncIoResult, DiskFile, DiskFileError};
+use crate::fixed_vhd::FixedVhd;
+use crate::fixed_vhd_sync::FixedVhdDiskSync;
+use crate::import::{data_runs, partial_path, PROGRESS_INTERVAL};
+use crate::qcow::{Error as QcowError, QcowFile, RawFile};
+use crate::qcow_sync::QcowDiskSync;
+use crate::raw_sync::RawFileDiskSync;
+use crate::vhd::VhdError;
+use crate::{CacheMode, ImageType};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use thiserror::Error;
+
+// Size of the chunks copied from the source.
+const CHUNK_SIZE: u64 = 1 << 20;
+// Version of the QCOW2 images created.
+const QCOW_VERSION: u32 = 3;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Converting to {0:?} images is not supported")]
+    UnsupportedFormat(ImageType),
+    #[error("Failed creating the image file: {0}")]
+    CreateFile(#[source] io::Error),
+    #[error("Failed creating the QCOW2 image: {0}")]
+    CreateQcow(#[source] QcowError),
+    #[error("Failed creating the VHD image: {0}")]
+    CreateVhd(#[source] VhdError),
+    #[error("Failed opening the QCOW2 image: {0}")]
+    OpenQcow(#[source] QcowError),
+    #[error("Failed opening the VHD image: {0}")]
+    OpenVhd(#[source] io::Error),
+    #[error("Failed accessing the source disk: {0}")]
+    Source(#[source] DiskFileError),
+    #[error("Failed accessing the converted image: {0}")]
+    Destination(#[source] DiskFileError),
+    #[error("Failed reading the source disk: {0}")]
+    Read(#[source] io::Error),
+    #[error("Failed writing the image: {0}")]
+    Write(#[source] io::Error),
+    #[error("Failed synchronizing the image: {0}")]
+    Sync(#[source] io::Error),
+    #[error("The converted image is {1} bytes large, the source disk is {0} bytes large")]
+    SizeMismatch(u64, u64),
+    #[error("Failed moving the image in place: {0}")]
+    Rename(#[source] io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Converts `src` to a new image of `format` at `path`, which can be RAW,
+/// QCOW2 or a fixed VHD, returning the size of the disk.
+///
+/// `progress` is called with the number of bytes of allocated extents
+/// copied so far, every gigabyte and once the conversion is complete. The
+/// size of the image is checked against the one of the source once written.
+/// On failure, nothing is left at `path`.
+pub fn convert_image<F>(
+    src: &mut dyn DiskFile,
+    format: ImageType,
+    path: &Path,
+    progress: F,
+) -> Result<u64>
+where
+    F: FnMut(u64),
+{
+    if !matches!(
+        format,
+        ImageType::Raw | ImageType::Qcow2 | ImageType::FixedVhd
+    ) {
+        return Err(Error::UnsupportedFormat(format));
+    }
+
+    let partial = partial_path(path);
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&partial)
+        .map_err(Error::CreateFile)?;
+
+    let result = convert_to(src, format, file, progress).and_then(|size| {
+        fs::rename(&partial, path)
+            .map(|_| size)
+            .map_err(Error::Rename)
+    });
+    if result.is_err() {
+        if let Err(e) = fs::remove_file(&partial) {
+            warn!(
+                "Failed removing the partially converted {:?}: {}",
+                partial, e
+            );
+        }
+    }
+
+    result
+}
+
+fn convert_to<F: FnMut(u64)>(
+    src: &mut dyn DiskFile,
+    format: ImageType,
+    file: File,
+    mut progress: F,
+) -> Result<u64> {
+    let size = src.size().map_err(Error::Source)?;
+    let extents = src.extents().map_err(Error::Source)?;
+
+    create_image(&file, format, size)?;
+    let dst = open_image(file.try_clone().map_err(Error::CreateFile)?, format)?;
+
+    let mut src_io = src.new_async_io(1).map_err(Error::Source)?;
+    let mut dst_io = dst.new_async_io(1).map_err(Error::Destination)?;
+    let mut buf = vec![0u8; CHUNK_SIZE as usize];
+    let mut copied = 0;
+    let mut next_report = PROGRESS_INTERVAL;
+    for extent in extents {
+        let end = (extent.offset + extent.length).min(size);
+        let mut offset = extent.offset;
+        while offset < end {
+            let len = CHUNK_SIZE.min(end - offset) as usize;
+            let chunk = &mut buf[..len];
+
+            complete_sync(src_io.as_mut(), chunk.len(), |io| {
+                io.read_vectored(offset as libc::off_t, &[iovec(chunk)], 0)
+            })
+            .map_err(Error::Read)?;
+            // The zeroes within an extent, such as in a preallocated RAW
+            // file, are left unallocated as well.
+            for run in data_runs(chunk) {
+                let data = &mut chunk[run.clone()];
+                complete_sync(dst_io.as_mut(), data.len(), |io| {
+                    io.write_vectored(
+                        (offset + run.start as u64) as libc::off_t,
+                        &[iovec(data)],
+                        0,
+                    )
+                })
+                .map_err(Error::Write)?;
+            }
+
+            offset += len as u64;
+            copied += len as u64;
+            if copied >= next_report {
+                progress(copied);
+                next_report = copied + PROGRESS_INTERVAL;
+            }
+        }
+    }
+
+    complete_sync(dst_io.as_mut(), 0, |io| io.fsync(Some(0))).map_err(Error::Sync)?;
+    drop(dst_io);
+    drop(dst);
+    file.sync_all().map_err(Error::Sync)?;
+
+    // The image is opened again, its size being the one found in the
+    // metadata written out.
+    let converted_size = open_image(file, format)?
+        .size()
+        .map_err(Error::Destination)?;
+    if converted_size != size {
+        return Err(Error::SizeMismatch(size, converted_size));
+    }
+    progress(copied);
+
+    Ok(size)
+}
+
+// Turns the empty `file` into an image of `format` of `size` bytes, without
+// any data allocated.
+fn create_image(file: &File, format: ImageType, size: u64) -> Result<()> {
+    let file = file.try_clone().map_err(Error::CreateFile)?;
+    match format {
+        ImageType::Raw => file.set_len(size).map_err(Error::CreateFile),
+        // The metadata is written out as the image is dropped.
+        ImageType::Qcow2 => QcowFile::new(RawFile::new(file, false), QCOW_VERSION, size)
+            .map(|_| ())
+            .map_err(Error::CreateQcow),
+        ImageType::FixedVhd => FixedVhd::create(file, size)
+            .map(|_| ())
+            .map_err(Error::CreateVhd),
+        format => Err(Error::UnsupportedFormat(format)),
+    }
+}
+
+fn open_image(file: File, format: ImageType) -> Result<Box<dyn DiskFile>> {
+    Ok(match format {
+        ImageType::Raw => Box::new(
+            RawFileDiskSync::new(file, false, CacheMode::Writeback, None)
+                .map_err(Error::Destination)?,
+        ) as Box<dyn DiskFile>,
+        ImageType::Qcow2 => {
+            Box::new(QcowDiskSync::new(file, false, None).map_err(Error::OpenQcow)?)
+                as Box<dyn DiskFile>
+        }
+        ImageType::FixedVhd => {
+            Box::new(FixedVhdDiskSync::new(file).map_err(Error::OpenVhd)?) as Box<dyn DiskFile>
+        }
+        format => return Err(Error::UnsupportedFormat(format)),
+    })
+}
+
+fn iovec(buf: &mut [u8]) -> libc::iovec {
+    libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    }
+}
+
+// Submits a request of `len` bytes through `io`, waiting for its completion.
+fn complete_sync<F>(io: &mut dyn AsyncIo, len: usize, submit: F) -> io::Result<()>
+where
+    F: FnOnce(&mut dyn AsyncIo) -> AsyncIoResult<()>,
+{
+    submit(io).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    io.submit()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+    loop {
+        if let Some((_, result)) = io.next_completed_request() {
+            if result < 0 {
+                return Err(io::Error::from_raw_os_error(-result));
+            }
+            if result as usize != len {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            return Ok(());
+        }
+
+        let mut pollfd = libc::pollfd {
+            fd: io.notifier().as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: FFI call with a valid pollfd
+        if unsafe { libc::poll(&mut pollfd, 1, -1) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+        // The completions are all picked up, the event can be consumed.
+        let _ = io.notifier().read();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{FileExt, MetadataExt};
+    use vmm_sys_util::tempdir::TempDir;
+
+    // Writes a RAW disk of 8 MiB holding data at the start, at 3 MiB and at
+    // the end, the rest being holes.
+    fn source_disk(dir: &TempDir) -> (Box<dyn DiskFile>, Vec<u8>) {
+        let mut content = vec![0u8; 8 << 20];
+        content[..4096].fill(0x11);
+        content[(3 << 20)..(3 << 20) + 100_000].fill(0x22);
+        content[(8 << 20) - 512..].fill(0x33);
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(dir.as_path().join("source.raw"))
+            .unwrap();
+        file.set_len(content.len() as u64).unwrap();
+        for range in [
+            0..4096,
+            (3 << 20)..(3 << 20) + 100_000,
+            (8 << 20) - 512..8 << 20,
+        ] {
+            file.write_all_at(&content[range.clone()], range.start as u64)
+                .unwrap();
+        }
+        let disk = RawFileDiskSync::new(file, false, CacheMode::Writeback, None).unwrap();
+
+        (Box::new(disk), content)
+    }
+
+    fn read_disk(disk: &mut dyn DiskFile) -> Vec<u8> {
+        let mut io = disk.new_async_io(1).unwrap();
+        let mut content = vec![0u8; disk.size().unwrap() as usize];
+        let len = content.len();
+        complete_sync(io.as_mut(), len, |io| {
+            io.read_vectored(0, &[iovec(&mut content)], 0)
+        })
+        .unwrap();
+        content
+    }
+
+    #[test]
+    fn test_convert_image() {
+        let dir = TempDir::new().unwrap();
+        let (mut src, content) = source_disk(&dir);
+
+        for format in [ImageType::Qcow2, ImageType::FixedVhd, ImageType::Raw] {
+            let path = dir.as_path().join(format!("{format:?}"));
+            let mut reports = Vec::new();
+            let size =
+                convert_image(src.as_mut(), format, &path, |bytes| reports.push(bytes)).unwrap();
+            assert_eq!(size, content.len() as u64);
+            assert_eq!(reports.len(), 1);
+            assert!(!partial_path(&path).exists());
+
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path)
+                .unwrap();
+            let mut image = open_image(file, format).unwrap();
+            assert_eq!(read_disk(image.as_mut()), content);
+        }
+
+        // The holes of the source are left unallocated.
+        let metadata = fs::metadata(dir.as_path().join("Raw")).unwrap();
+        assert!(metadata.blocks() * 512 < content.len() as u64 / 2);
+
+        // And back from QCOW2.
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(dir.as_path().join("Qcow2"))
+            .unwrap();
+        let mut qcow = open_image(file, ImageType::Qcow2).unwrap();
+        let path = dir.as_path().join("from_qcow2.raw");
+        convert_image(qcow.as_mut(), ImageType::Raw, &path, |_| {}).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), content);
+    }
+
+    #[test]
+    fn test_convert_image_unsupported() {
+        let dir = TempDir::new().unwrap();
+        let (mut src, _) = source_disk(&dir);
+        let path = dir.as_path().join("disk.vhdx");
+
+        assert!(matches!(
+            convert_image(src.as_mut(), ImageType::Vhdx, &path, |_| {}),
+            Err(Error::UnsupportedFormat(ImageType::Vhdx))
+        ));
+        assert!(!path.exists());
+        assert!(!partial_path(&path).exists());
+    }
+}
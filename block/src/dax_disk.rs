@@ -0,0 +1,144 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! [`DiskFile`] mapped into the guest as a DAX window, such as the memory of
+//! a virtio-pmem device, rather than served through the queues of a block
+//! device. The guest accesses the mapping directly, without any request
+//! going through the iovec path.
+
+use crate::async_io::{AsyncIo, DiskFile, DiskFileError, DiskFileResult};
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use vm_memory::bitmap::AtomicBitmap;
+use vm_memory::{FileOffset, MmapRegion};
+
+/// Alignment of the size of a DAX window, for the guest to be able to map
+/// it with huge pages.
+pub const DAX_ALIGNMENT: u64 = 2 << 20;
+
+pub struct DaxDisk {
+    file: File,
+    region: MmapRegion<AtomicBitmap>,
+}
+
+impl DaxDisk {
+    /// Maps `file` as a window of `size` bytes, or of the size of the file
+    /// if not given, rounded up to [`DAX_ALIGNMENT`]. A file smaller than
+    /// the window is extended first, the guest otherwise getting a bus error
+    /// accessing the mapping past its end.
+    pub fn new(mut file: File, size: Option<u64>) -> DiskFileResult<Self> {
+        let file_size = file.seek(SeekFrom::End(0)).map_err(DiskFileError::Size)?;
+        let size = size.unwrap_or(file_size).div_ceil(DAX_ALIGNMENT) * DAX_ALIGNMENT;
+        if file_size < size {
+            file.set_len(size).map_err(DiskFileError::DaxExtend)?;
+        }
+
+        let file_offset = FileOffset::new(file.try_clone().map_err(DiskFileError::DaxMap)?, 0);
+        let region = MmapRegion::build(
+            Some(file_offset),
+            size as usize,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_NORESERVE | libc::MAP_SHARED,
+        )
+        .map_err(|e| DiskFileError::DaxMap(io::Error::new(io::ErrorKind::Other, e)))?;
+
+        Ok(DaxDisk { file, region })
+    }
+
+    /// Host address of the mapping.
+    pub fn host_addr(&self) -> u64 {
+        self.region.as_ptr() as u64
+    }
+
+    /// Size of the mapping, which is the size of the window seen by the
+    /// guest.
+    pub fn window_size(&self) -> u64 {
+        self.region.size() as u64
+    }
+
+    /// Makes the writes of the guest to the mapping durable.
+    pub fn msync(&self) -> io::Result<()> {
+        msync(self.host_addr(), self.region.size())
+    }
+
+    /// Hands the file and its mapping over to the device exposing them to
+    /// the guest, the mapping staying valid as long as the region is kept.
+    pub fn into_parts(self) -> (File, MmapRegion<AtomicBitmap>) {
+        (self.file, self.region)
+    }
+}
+
+impl DiskFile for DaxDisk {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        Ok(self.window_size())
+    }
+
+    fn new_async_io(&self, _ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        Err(DiskFileError::NewAsyncIo(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "DAX disks are accessed through their mapping",
+        )))
+    }
+}
+
+/// Writes the pages of the shared file mapping of `len` bytes at `addr`
+/// modified by the guest back to the file, waiting for them to be durable.
+pub fn msync(addr: u64, len: usize) -> io::Result<()> {
+    // SAFETY: FFI call, the range being checked by the kernel to be mapped.
+    let ret = unsafe { libc::msync(addr as *mut libc::c_void, len, libc::MS_SYNC) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::fs::FileExt;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_dax_disk_extends_file() {
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(&[0xa5u8; 4096]).unwrap();
+
+        let mut disk = DaxDisk::new(file.as_file().try_clone().unwrap(), None).unwrap();
+        assert_eq!(disk.size().unwrap(), DAX_ALIGNMENT);
+        assert_eq!(file.as_file().metadata().unwrap().len(), DAX_ALIGNMENT);
+        assert!(disk.new_async_io(1).is_err());
+
+        // The guest view of the disk is the content of the file, and its
+        // writes reach the file once synced.
+        let addr = disk.host_addr() as *mut u8;
+        // SAFETY: the mapping is DAX_ALIGNMENT bytes large
+        unsafe {
+            assert_eq!(*addr.add(4095), 0xa5);
+            assert_eq!(*addr.add(4096), 0);
+            std::ptr::write_bytes(addr.add(8192), 0x5a, 512);
+        }
+        disk.msync().unwrap();
+
+        let mut data = [0u8; 512];
+        file.as_file().read_exact_at(&mut data, 8192).unwrap();
+        assert!(data.iter().all(|b| *b == 0x5a));
+    }
+
+    #[test]
+    fn test_dax_disk_window_size() {
+        let file = TempFile::new().unwrap();
+
+        let disk = DaxDisk::new(file.as_file().try_clone().unwrap(), Some(3 << 20)).unwrap();
+        assert_eq!(disk.window_size(), 2 * DAX_ALIGNMENT);
+        assert_eq!(file.as_file().metadata().unwrap().len(), 2 * DAX_ALIGNMENT);
+
+        // A file larger than the window is mapped partially, and left as is.
+        file.as_file().set_len(8 << 20).unwrap();
+        let disk = DaxDisk::new(file.as_file().try_clone().unwrap(), Some(1 << 20)).unwrap();
+        assert_eq!(disk.window_size(), DAX_ALIGNMENT);
+        assert_eq!(file.as_file().metadata().unwrap().len(), 8 << 20);
+    }
+}
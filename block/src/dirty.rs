@@ -0,0 +1,437 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Tracking of the blocks of a disk modified by the guest, so that a copy of
+//! the disk taken while the guest runs can be brought up to date by only
+//! copying those blocks again.
+
+use crate::async_io::{
+    AsyncIo, AsyncIoResult, DiskExtent, DiskFile, DiskFileError, DiskFileResult, IoAlignment,
+    RequestPriority,
+};
+use crate::lifetime::DiskLifetime;
+use crate::zoned::BlkZone;
+use crate::{AsyncIoBackend, DiskTopology};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use vmm_sys_util::eventfd::EventFd;
+
+/// Bitmap of the blocks of `granularity` bytes written to.
+struct DirtyBitmap {
+    granularity: u64,
+    bits: Vec<u64>,
+}
+
+impl DirtyBitmap {
+    fn new(granularity: u64, size: u64) -> Self {
+        let blocks = size.div_ceil(granularity);
+        DirtyBitmap {
+            granularity,
+            bits: vec![0; blocks.div_ceil(64) as usize],
+        }
+    }
+
+    fn mark(&mut self, offset: u64, length: u64) {
+        if length == 0 {
+            return;
+        }
+
+        let first = offset / self.granularity;
+        let last = (offset + length - 1) / self.granularity;
+        for block in first..=last {
+            // The disk may have grown since tracking started.
+            let word = (block / 64) as usize;
+            if word >= self.bits.len() {
+                self.bits.resize(word + 1, 0);
+            }
+            self.bits[word] |= 1 << (block % 64);
+        }
+    }
+
+    // Returns the runs of dirty blocks, clearing them.
+    fn take(&mut self) -> Vec<DiskExtent> {
+        let mut extents: Vec<DiskExtent> = Vec::new();
+        for (word_index, word) in self.bits.iter_mut().enumerate() {
+            let mut bits = std::mem::take(word);
+            while bits != 0 {
+                let bit = bits.trailing_zeros() as u64;
+                bits &= bits - 1;
+
+                let offset = (word_index as u64 * 64 + bit) * self.granularity;
+                match extents.last_mut() {
+                    Some(extent) if extent.offset + extent.length == offset => {
+                        extent.length += self.granularity;
+                    }
+                    _ => extents.push(DiskExtent {
+                        offset,
+                        length: self.granularity,
+                    }),
+                }
+            }
+        }
+        extents
+    }
+}
+
+// State shared between the disk and the AsyncIo instances of every queue.
+#[derive(Default)]
+struct DirtyTracker {
+    enabled: AtomicBool,
+    bitmap: Mutex<Option<DirtyBitmap>>,
+}
+
+impl DirtyTracker {
+    fn mark(&self, offset: u64, length: u64) {
+        if !self.enabled.load(Ordering::Acquire) {
+            return;
+        }
+        if let Some(bitmap) = self.bitmap.lock().unwrap().as_mut() {
+            bitmap.mark(offset, length);
+        }
+    }
+}
+
+/// [`DiskFile`] recording the ranges modified through it, once dirty
+/// tracking has been started.
+pub struct DirtyTrackingDisk {
+    inner: Box<dyn DiskFile>,
+    tracker: Arc<DirtyTracker>,
+}
+
+impl DirtyTrackingDisk {
+    pub fn new(inner: Box<dyn DiskFile>) -> Self {
+        DirtyTrackingDisk {
+            inner,
+            tracker: Arc::new(DirtyTracker::default()),
+        }
+    }
+}
+
+impl DiskFile for DirtyTrackingDisk {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        self.inner.size()
+    }
+
+    fn new_async_io(&self, ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        Ok(Box::new(DirtyTrackingAsyncIo {
+            inner: self.inner.new_async_io(ring_depth)?,
+            tracker: self.tracker.clone(),
+            inflight: HashMap::new(),
+        }) as Box<dyn AsyncIo>)
+    }
+
+    fn topology(&mut self) -> DiskTopology {
+        self.inner.topology()
+    }
+
+    fn supports_discard(&self) -> bool {
+        self.inner.supports_discard()
+    }
+
+    fn supports_write_zeroes(&self) -> bool {
+        self.inner.supports_write_zeroes()
+    }
+
+    fn discard_zeroes(&self) -> bool {
+        self.inner.discard_zeroes()
+    }
+
+    fn serial(&mut self) -> Option<String> {
+        self.inner.serial()
+    }
+
+    fn rotational(&mut self) -> bool {
+        self.inner.rotational()
+    }
+
+    fn get_lifetime(&mut self) -> DiskLifetime {
+        self.inner.get_lifetime()
+    }
+
+    fn available_space(&self) -> DiskFileResult<Option<u64>> {
+        self.inner.available_space()
+    }
+
+    fn backend(&self) -> AsyncIoBackend {
+        self.inner.backend()
+    }
+
+    fn resize(&mut self, current_size: u64) -> DiskFileResult<u64> {
+        self.inner.resize(current_size)
+    }
+
+    fn extents(&mut self) -> DiskFileResult<Vec<DiskExtent>> {
+        self.inner.extents()
+    }
+
+    fn start_dirty_tracking(&mut self, granularity: u64) -> DiskFileResult<()> {
+        if granularity == 0 {
+            return Err(DiskFileError::InvalidDirtyGranularity(granularity));
+        }
+
+        let size = self.inner.size()?;
+        *self.tracker.bitmap.lock().unwrap() = Some(DirtyBitmap::new(granularity, size));
+        self.tracker.enabled.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    fn stop_dirty_tracking(&mut self) {
+        self.tracker.enabled.store(false, Ordering::Release);
+        *self.tracker.bitmap.lock().unwrap() = None;
+    }
+
+    fn get_dirty_blocks(&mut self) -> DiskFileResult<Vec<DiskExtent>> {
+        self.tracker
+            .bitmap
+            .lock()
+            .unwrap()
+            .as_mut()
+            .map(DirtyBitmap::take)
+            .ok_or(DiskFileError::DirtyTrackingNotStarted)
+    }
+}
+
+/// [`AsyncIo`] marking the range of every write, write zeroes and discard
+/// request as dirty once it completes, so that the blocks are copied again
+/// if they were copied while the request was in flight.
+pub struct DirtyTrackingAsyncIo {
+    inner: Box<dyn AsyncIo>,
+    tracker: Arc<DirtyTracker>,
+    inflight: HashMap<u64, (u64, u64)>,
+}
+
+impl AsyncIo for DirtyTrackingAsyncIo {
+    fn notifier(&self) -> &EventFd {
+        self.inner.notifier()
+    }
+
+    fn required_alignment(&self) -> IoAlignment {
+        self.inner.required_alignment()
+    }
+
+    fn submission_room(&self) -> usize {
+        self.inner.submission_room()
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.inner.read_vectored(offset, iovecs, user_data)
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let length = iovecs.iter().map(|iovec| iovec.iov_len as u64).sum();
+        self.inner.write_vectored(offset, iovecs, user_data)?;
+        self.inflight.insert(user_data, (offset as u64, length));
+        Ok(())
+    }
+
+    fn write_vectored_fua(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let length = iovecs.iter().map(|iovec| iovec.iov_len as u64).sum();
+        self.inner.write_vectored_fua(offset, iovecs, user_data)?;
+        self.inflight.insert(user_data, (offset as u64, length));
+        Ok(())
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        self.inner.fsync(user_data)
+    }
+
+    fn read_vectored_with_priority(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+        priority: RequestPriority,
+    ) -> AsyncIoResult<()> {
+        self.inner
+            .read_vectored_with_priority(offset, iovecs, user_data, priority)
+    }
+
+    fn write_vectored_with_priority(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+        priority: RequestPriority,
+    ) -> AsyncIoResult<()> {
+        let length = iovecs.iter().map(|iovec| iovec.iov_len as u64).sum();
+        self.inner
+            .write_vectored_with_priority(offset, iovecs, user_data, priority)?;
+        self.inflight.insert(user_data, (offset as u64, length));
+        Ok(())
+    }
+
+    fn fsync_with_priority(
+        &mut self,
+        user_data: Option<u64>,
+        priority: RequestPriority,
+    ) -> AsyncIoResult<()> {
+        self.inner.fsync_with_priority(user_data, priority)
+    }
+
+    fn register_buffers(&mut self, regions: &[libc::iovec]) -> AsyncIoResult<bool> {
+        self.inner.register_buffers(regions)
+    }
+
+    fn discard(&mut self, offset: libc::off_t, length: u64, user_data: u64) -> AsyncIoResult<()> {
+        self.inner.discard(offset, length, user_data)?;
+        self.inflight.insert(user_data, (offset as u64, length));
+        Ok(())
+    }
+
+    fn write_zeroes(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        unmap: bool,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.inner.write_zeroes(offset, length, unmap, user_data)?;
+        self.inflight.insert(user_data, (offset as u64, length));
+        Ok(())
+    }
+
+    fn zone_report(
+        &mut self,
+        offset: libc::off_t,
+        zones: &mut [BlkZone],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.inner.zone_report(offset, zones, user_data)
+    }
+
+    fn zone_open(&mut self, offset: libc::off_t, user_data: u64) -> AsyncIoResult<()> {
+        self.inner.zone_open(offset, user_data)
+    }
+
+    fn zone_close(&mut self, offset: libc::off_t, user_data: u64) -> AsyncIoResult<()> {
+        self.inner.zone_close(offset, user_data)
+    }
+
+    fn zone_finish(&mut self, offset: libc::off_t, user_data: u64) -> AsyncIoResult<()> {
+        self.inner.zone_finish(offset, user_data)
+    }
+
+    fn zone_reset(&mut self, offset: libc::off_t, user_data: u64) -> AsyncIoResult<()> {
+        self.inner.zone_reset(offset, user_data)
+    }
+
+    fn cancel(&mut self, user_data: u64) -> AsyncIoResult<bool> {
+        self.inner.cancel(user_data)
+    }
+
+    fn submit(&mut self) -> AsyncIoResult<()> {
+        self.inner.submit()
+    }
+
+    fn next_completed_request(&mut self) -> Option<(u64, i32)> {
+        let (user_data, result) = self.inner.next_completed_request()?;
+        // Even a failed request may have modified part of its range.
+        if let Some((offset, length)) = self.inflight.remove(&user_data) {
+            self.tracker.mark(offset, length);
+        }
+
+        Some((user_data, result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::null_disk::NullDiskFile;
+
+    fn iovec(buf: &mut [u8]) -> libc::iovec {
+        libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }
+    }
+
+    #[test]
+    fn test_dirty_bitmap() {
+        let mut bitmap = DirtyBitmap::new(4096, 1 << 20);
+        bitmap.mark(0, 1);
+        bitmap.mark(4095, 2);
+        bitmap.mark(64 * 4096 - 1, 4096);
+        bitmap.mark(200 * 4096, 0);
+        assert_eq!(
+            bitmap.take(),
+            vec![
+                DiskExtent {
+                    offset: 0,
+                    length: 2 * 4096,
+                },
+                DiskExtent {
+                    offset: 63 * 4096,
+                    length: 2 * 4096,
+                },
+            ]
+        );
+        assert!(bitmap.take().is_empty());
+
+        // Writes past the size known when tracking started are recorded.
+        bitmap.mark(2 << 20, 512);
+        assert_eq!(
+            bitmap.take(),
+            vec![DiskExtent {
+                offset: 2 << 20,
+                length: 4096,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_dirty_tracking() {
+        let mut disk = DirtyTrackingDisk::new(Box::new(NullDiskFile::new(1 << 20, None)));
+        let mut io = disk.new_async_io(1).unwrap();
+        assert!(matches!(
+            disk.get_dirty_blocks(),
+            Err(DiskFileError::DirtyTrackingNotStarted)
+        ));
+
+        // Nothing is recorded before tracking starts.
+        let mut data = vec![0u8; 8192];
+        io.write_vectored(0, &[iovec(&mut data)], 1).unwrap();
+        assert_eq!(io.next_completed_request(), Some((1, 8192)));
+
+        disk.start_dirty_tracking(65536).unwrap();
+        io.read_vectored(0, &[iovec(&mut data)], 2).unwrap();
+        io.write_vectored(65536, &[iovec(&mut data)], 3).unwrap();
+        io.discard(3 * 65536, 65536, 4).unwrap();
+        io.write_zeroes(4 * 65536, 1, false, 5).unwrap();
+        while io.next_completed_request().is_some() {}
+        assert_eq!(
+            disk.get_dirty_blocks().unwrap(),
+            vec![
+                DiskExtent {
+                    offset: 65536,
+                    length: 65536,
+                },
+                DiskExtent {
+                    offset: 3 * 65536,
+                    length: 2 * 65536,
+                },
+            ]
+        );
+        assert!(disk.get_dirty_blocks().unwrap().is_empty());
+
+        disk.stop_dirty_tracking();
+        assert!(disk.get_dirty_blocks().is_err());
+    }
+}
@@ -0,0 +1,310 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Status of the device-mapper thin pools backing the disks. A thin volume
+//! only gets its blocks from the pool when written, so the writes fail once
+//! the pool is out of data space, whatever the size of the volume. The pool
+//! is looked up, and its status read, through the ioctls of
+//! `/dev/mapper/control`.
+
+use libc::{ioctl, S_IFBLK, S_IFMT};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use thiserror::Error;
+use vmm_sys_util::{ioctl_ioc_nr, ioctl_iowr_nr};
+
+#[derive(Error, Debug)]
+pub enum DmThinError {
+    /// Failed getting the device backing the disk.
+    #[error("Failed getting the device backing the disk: {0}")]
+    Stat(#[source] io::Error),
+    /// Failed opening the device-mapper control device.
+    #[error("Failed opening /dev/mapper/control: {0}")]
+    OpenControl(#[source] io::Error),
+    /// Failed reading the table or status of a device-mapper device.
+    #[error("Failed reading the table or status of device {0}:{1}: {2}")]
+    TableStatus(u32, u32, #[source] io::Error),
+    /// The status of the thin pool couldn't be parsed.
+    #[error("Invalid thin pool status {0:?}")]
+    InvalidStatus(String),
+}
+
+pub type DmThinResult<T> = std::result::Result<T, DmThinError>;
+
+// See include/uapi/linux/dm-ioctl.h in the kernel code.
+const DM_VERSION_MAJOR: u32 = 4;
+const DM_STATUS_TABLE_FLAG: u32 = 1 << 4;
+const DM_BUFFER_FULL_FLAG: u32 = 1 << 8;
+// Keeps the thin pool from committing its metadata for each status read.
+const DM_NOFLUSH_FLAG: u32 = 1 << 11;
+
+// Offsets in struct dm_ioctl, which is followed by the data.
+const DM_IOCTL_SIZE: usize = 312;
+const DM_DATA_SIZE_OFFSET: usize = 12;
+const DM_DATA_START_OFFSET: usize = 16;
+const DM_TARGET_COUNT_OFFSET: usize = 20;
+const DM_FLAGS_OFFSET: usize = 28;
+const DM_DEV_OFFSET: usize = 40;
+
+ioctl_iowr_nr!(DM_TABLE_STATUS, 0xfd, 12, [u8; DM_IOCTL_SIZE]);
+
+// Offsets in struct dm_target_spec, which is followed by the parameters or
+// the status of the target.
+const DM_TARGET_SPEC_SIZE: usize = 40;
+const DM_TARGET_NEXT_OFFSET: usize = 20;
+const DM_TARGET_TYPE_OFFSET: usize = 24;
+
+/// Utilization of a thin pool, in blocks of the pool.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ThinPoolStatus {
+    pub data_used_blocks: u64,
+    pub data_total_blocks: u64,
+    pub metadata_used_blocks: u64,
+    pub metadata_total_blocks: u64,
+    /// Whether the data blocks of the pool are all used, the pool failing
+    /// or queueing the writes needing new ones.
+    pub out_of_data_space: bool,
+    /// Whether the pool only serves reads, its metadata being full or the
+    /// pool having failed.
+    pub read_only: bool,
+}
+
+/// Thin pool providing the blocks of the dm-thin volume backing a disk.
+pub struct ThinPool {
+    control: File,
+    device: (u32, u32),
+}
+
+impl ThinPool {
+    /// Returns the thin pool of the dm-thin volume backing `f`, which is
+    /// either the volume or a file on a filesystem stored on it, and `None`
+    /// if `f` isn't backed by a dm-thin volume.
+    pub fn probe(f: &File) -> DmThinResult<Option<ThinPool>> {
+        let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+        // SAFETY: FFI call with a valid fd and buffer
+        let ret = unsafe { libc::fstat(f.as_raw_fd(), stat.as_mut_ptr()) };
+        if ret != 0 {
+            return Err(DmThinError::Stat(io::Error::last_os_error()));
+        }
+        // SAFETY: stat is valid at this point
+        let stat = unsafe { stat.assume_init() };
+        let dev = if stat.st_mode & S_IFMT == S_IFBLK {
+            stat.st_rdev
+        } else {
+            stat.st_dev
+        };
+        // SAFETY: major() and minor() only split the device number
+        let device = unsafe { (libc::major(dev), libc::minor(dev)) };
+
+        // Only the device-mapper devices have a dm directory in sysfs, which
+        // spares opening the control device for the others.
+        if !Path::new(&format!("/sys/dev/block/{}:{}/dm", device.0, device.1)).exists() {
+            return Ok(None);
+        }
+
+        let control = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/mapper/control")
+            .map_err(DmThinError::OpenControl)?;
+        let table = table_status(&control, device, DM_STATUS_TABLE_FLAG)?;
+        let pool = table
+            .iter()
+            .find(|(target_type, _)| target_type == "thin")
+            .and_then(|(_, params)| parse_thin_table(params));
+
+        Ok(pool.map(|device| ThinPool { control, device }))
+    }
+
+    /// Number of the device of the pool.
+    pub fn device(&self) -> (u32, u32) {
+        self.device
+    }
+
+    /// Reads the current status of the pool.
+    pub fn status(&self) -> DmThinResult<ThinPoolStatus> {
+        let status = table_status(&self.control, self.device, DM_NOFLUSH_FLAG)?;
+        let (_, status) = status
+            .iter()
+            .find(|(target_type, _)| target_type == "thin-pool")
+            .ok_or_else(|| DmThinError::InvalidStatus(String::new()))?;
+
+        parse_pool_status(status)
+    }
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn write_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_ne_bytes());
+}
+
+// Returns the type and the parameters, or the status, of each target of the
+// device-mapper device `device`.
+fn table_status(
+    control: &File,
+    device: (u32, u32),
+    flags: u32,
+) -> DmThinResult<Vec<(String, String)>> {
+    let mut size = 16 << 10;
+    loop {
+        let mut buf = vec![0u8; size];
+        write_u32(&mut buf, 0, DM_VERSION_MAJOR);
+        write_u32(&mut buf, DM_DATA_SIZE_OFFSET, size as u32);
+        write_u32(&mut buf, DM_DATA_START_OFFSET, DM_IOCTL_SIZE as u32);
+        write_u32(&mut buf, DM_FLAGS_OFFSET, flags);
+        let dev = libc::makedev(device.0, device.1);
+        buf[DM_DEV_OFFSET..DM_DEV_OFFSET + 8].copy_from_slice(&dev.to_ne_bytes());
+
+        // SAFETY: FFI call with a valid fd, and a buffer of the size it is
+        // told to be
+        let ret = unsafe {
+            ioctl(
+                control.as_raw_fd(),
+                DM_TABLE_STATUS() as _,
+                buf.as_mut_ptr(),
+            )
+        };
+        if ret < 0 {
+            return Err(DmThinError::TableStatus(
+                device.0,
+                device.1,
+                io::Error::last_os_error(),
+            ));
+        }
+        if read_u32(&buf, DM_FLAGS_OFFSET) & DM_BUFFER_FULL_FLAG != 0 {
+            size *= 2;
+            continue;
+        }
+
+        return Ok(parse_targets(&buf));
+    }
+}
+
+// Parses the targets following the header of a DM_TABLE_STATUS reply, each
+// giving the offset of the next from the start of the data.
+fn parse_targets(buf: &[u8]) -> Vec<(String, String)> {
+    let data_start = read_u32(buf, DM_DATA_START_OFFSET) as usize;
+    let data_size = (read_u32(buf, DM_DATA_SIZE_OFFSET) as usize).min(buf.len());
+    let c_str = |bytes: &[u8]| {
+        let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        String::from_utf8_lossy(&bytes[..len]).into_owned()
+    };
+
+    let mut targets = Vec::new();
+    let mut spec = data_start;
+    for _ in 0..read_u32(buf, DM_TARGET_COUNT_OFFSET) {
+        if spec + DM_TARGET_SPEC_SIZE > data_size {
+            break;
+        }
+        let target_type = c_str(&buf[spec + DM_TARGET_TYPE_OFFSET..spec + DM_TARGET_SPEC_SIZE]);
+        let params = c_str(&buf[spec + DM_TARGET_SPEC_SIZE..data_size]);
+        targets.push((target_type, params));
+        spec = data_start + read_u32(buf, spec + DM_TARGET_NEXT_OFFSET) as usize;
+    }
+
+    targets
+}
+
+// Parses the table of a thin target, "<pool major:minor> <thin id> [<origin>]",
+// into the number of the pool device.
+fn parse_thin_table(params: &str) -> Option<(u32, u32)> {
+    let (major, minor) = params.split_whitespace().next()?.split_once(':')?;
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+// Parses the status of a thin-pool target, "<transaction id>
+// <used>/<total metadata blocks> <used>/<total data blocks> <held root>
+// rw|ro|out_of_data_space ...", or "Fail" once the pool failed.
+fn parse_pool_status(status: &str) -> DmThinResult<ThinPoolStatus> {
+    let invalid = || DmThinError::InvalidStatus(status.to_owned());
+    let fields: Vec<&str> = status.split_whitespace().collect();
+    if fields.first() == Some(&"Fail") {
+        return Ok(ThinPoolStatus {
+            read_only: true,
+            ..Default::default()
+        });
+    }
+
+    let blocks = |field: Option<&&str>| -> Option<(u64, u64)> {
+        let (used, total) = field?.split_once('/')?;
+        Some((used.parse().ok()?, total.parse().ok()?))
+    };
+    let (metadata_used_blocks, metadata_total_blocks) =
+        blocks(fields.get(1)).ok_or_else(invalid)?;
+    let (data_used_blocks, data_total_blocks) = blocks(fields.get(2)).ok_or_else(invalid)?;
+    let mode = fields.get(4).ok_or_else(invalid)?;
+
+    Ok(ThinPoolStatus {
+        data_used_blocks,
+        data_total_blocks,
+        metadata_used_blocks,
+        metadata_total_blocks,
+        out_of_data_space: *mode == "out_of_data_space",
+        read_only: *mode == "ro",
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_targets() {
+        let len = DM_IOCTL_SIZE + 2 * 64;
+        let mut buf = vec![0u8; len];
+        write_u32(&mut buf, DM_DATA_SIZE_OFFSET, len as u32);
+        write_u32(&mut buf, DM_DATA_START_OFFSET, DM_IOCTL_SIZE as u32);
+        write_u32(&mut buf, DM_TARGET_COUNT_OFFSET, 2);
+        for (i, (target_type, params)) in [("linear", "8:16 2048"), ("thin", "253:2 1")]
+            .iter()
+            .enumerate()
+        {
+            let spec = DM_IOCTL_SIZE + i * 64;
+            write_u32(&mut buf, spec + DM_TARGET_NEXT_OFFSET, (i as u32 + 1) * 64);
+            buf[spec + DM_TARGET_TYPE_OFFSET..][..target_type.len()]
+                .copy_from_slice(target_type.as_bytes());
+            buf[spec + DM_TARGET_SPEC_SIZE..][..params.len()].copy_from_slice(params.as_bytes());
+        }
+
+        let targets = parse_targets(&buf);
+        assert_eq!(
+            targets,
+            vec![
+                ("linear".to_string(), "8:16 2048".to_string()),
+                ("thin".to_string(), "253:2 1".to_string()),
+            ]
+        );
+        assert_eq!(parse_thin_table(&targets[1].1), Some((253, 2)));
+        assert_eq!(parse_thin_table("pool 1"), None);
+    }
+
+    #[test]
+    fn test_parse_pool_status() {
+        assert_eq!(
+            parse_pool_status(
+                "1 310/4161600 16384/16384 - out_of_data_space discard_passdown \
+                 queue_if_no_space - 1024"
+            )
+            .unwrap(),
+            ThinPoolStatus {
+                data_used_blocks: 16384,
+                data_total_blocks: 16384,
+                metadata_used_blocks: 310,
+                metadata_total_blocks: 4161600,
+                out_of_data_space: true,
+                read_only: false,
+            }
+        );
+        let status = parse_pool_status("0 99/1024 12/2048 - rw discard_passdown").unwrap();
+        assert_eq!(status.data_used_blocks, 12);
+        assert!(!status.out_of_data_space && !status.read_only);
+        assert!(parse_pool_status("Fail").unwrap().read_only);
+        assert!(parse_pool_status("0 99 12/2048").is_err());
+    }
+}
@@ -0,0 +1,323 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! [`DiskFile`] decrypting the data segment of a LUKS2 volume stored in
+//! another [`DiskFile`], as dm-crypt does.
+
+use crate::async_io::{
+    AsyncIo, AsyncIoError, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult, IoAlignment,
+};
+use crate::lifetime::DiskLifetime;
+use crate::luks::{self, LuksKey, LuksVolume};
+use crate::{AsyncIoBackend, DiskTopology};
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::sync::Arc;
+use vmm_sys_util::eventfd::EventFd;
+
+// Alignment of the bounce buffers, suitable for O_DIRECT.
+const BUFFER_ALIGNMENT: usize = 4096;
+
+pub struct EncryptedDiskFile {
+    inner: Box<dyn DiskFile>,
+    volume: Arc<LuksVolume>,
+}
+
+impl EncryptedDiskFile {
+    /// Unlocks the LUKS2 volume whose header is read from `header`, and
+    /// whose data is accessed through `inner`.
+    pub fn new(inner: Box<dyn DiskFile>, header: &File, key: &LuksKey) -> luks::Result<Self> {
+        Ok(EncryptedDiskFile {
+            inner,
+            volume: Arc::new(LuksVolume::open(header, key)?),
+        })
+    }
+
+    /// Unlocked LUKS2 volume, shared with the I/O of the disk.
+    pub fn volume(&self) -> Arc<LuksVolume> {
+        self.volume.clone()
+    }
+
+    fn data_size(&self, inner_size: u64) -> u64 {
+        self.volume
+            .data_size
+            .unwrap_or_else(|| inner_size.saturating_sub(self.volume.data_offset))
+    }
+}
+
+impl DiskFile for EncryptedDiskFile {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        let inner_size = self.inner.size()?;
+        Ok(self.data_size(inner_size))
+    }
+
+    fn new_async_io(&self, ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        Ok(Box::new(EncryptedAsyncIo::new(
+            self.inner.new_async_io(ring_depth)?,
+            self.volume.clone(),
+        )) as Box<dyn AsyncIo>)
+    }
+
+    fn serial(&mut self) -> Option<String> {
+        self.inner.serial()
+    }
+
+    fn rotational(&mut self) -> bool {
+        self.inner.rotational()
+    }
+
+    fn get_lifetime(&mut self) -> DiskLifetime {
+        self.inner.get_lifetime()
+    }
+
+    fn available_space(&self) -> DiskFileResult<Option<u64>> {
+        self.inner.available_space()
+    }
+
+    fn backend(&self) -> AsyncIoBackend {
+        self.inner.backend()
+    }
+
+    fn topology(&mut self) -> DiskTopology {
+        let mut topology = self.inner.topology();
+        // Requests must cover whole encryption sectors.
+        topology.logical_block_size = topology.logical_block_size.max(self.volume.sector_size);
+        topology.physical_block_size = topology
+            .physical_block_size
+            .max(topology.logical_block_size);
+        topology
+    }
+
+    fn resize(&mut self, current_size: u64) -> DiskFileResult<u64> {
+        if self.volume.data_size.is_some() {
+            let size = self.size()?;
+            if size < current_size {
+                return Err(DiskFileError::Shrunk(current_size, size));
+            }
+            return Ok(size);
+        }
+
+        let inner_size = self.inner.resize(current_size + self.volume.data_offset)?;
+        Ok(self.data_size(inner_size))
+    }
+}
+
+// Heap buffer aligned on BUFFER_ALIGNMENT, holding the ciphertext of a
+// request.
+struct BounceBuffer {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+impl BounceBuffer {
+    fn new(len: usize) -> io::Result<Self> {
+        let layout = Layout::from_size_align(len.max(1), BUFFER_ALIGNMENT)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        // SAFETY: the layout has a non-zero size
+        let ptr = unsafe { alloc_zeroed(layout) };
+        if ptr.is_null() {
+            return Err(io::Error::from(io::ErrorKind::OutOfMemory));
+        }
+
+        Ok(BounceBuffer { ptr, layout })
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: ptr was allocated with layout.size() bytes
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+
+    fn iovec(&self, len: usize) -> libc::iovec {
+        libc::iovec {
+            iov_base: self.ptr as *mut libc::c_void,
+            iov_len: len,
+        }
+    }
+}
+
+impl Drop for BounceBuffer {
+    fn drop(&mut self) {
+        // SAFETY: ptr was allocated by alloc_zeroed with the same layout
+        unsafe { dealloc(self.ptr, self.layout) };
+    }
+}
+
+struct PendingRead {
+    buffer: BounceBuffer,
+    iovecs: Vec<libc::iovec>,
+    first_sector: u64,
+}
+
+pub struct EncryptedAsyncIo {
+    inner: Box<dyn AsyncIo>,
+    volume: Arc<LuksVolume>,
+    reads: HashMap<u64, PendingRead>,
+    // Buffers must outlive the writes using them.
+    writes: HashMap<u64, BounceBuffer>,
+}
+
+// SAFETY: the raw pointers are only the bounce buffers owned by this
+// structure and the guest memory iovecs of in flight requests, which stay
+// valid until the requests complete.
+unsafe impl Send for EncryptedAsyncIo {}
+
+impl EncryptedAsyncIo {
+    fn new(inner: Box<dyn AsyncIo>, volume: Arc<LuksVolume>) -> Self {
+        EncryptedAsyncIo {
+            inner,
+            volume,
+            reads: HashMap::new(),
+            writes: HashMap::new(),
+        }
+    }
+
+    // Checks the request covers whole encryption sectors, returning its
+    // length and the index of its first sector.
+    fn sectors(&self, offset: libc::off_t, iovecs: &[libc::iovec]) -> io::Result<(usize, u64)> {
+        let len: usize = iovecs.iter().map(|iovec| iovec.iov_len).sum();
+        let sector_size = self.volume.sector_size;
+        if offset < 0 || offset as u64 % sector_size != 0 || len as u64 % sector_size != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "request not aligned on the encryption sector size",
+            ));
+        }
+
+        Ok((len, offset as u64 / sector_size + self.volume.iv_tweak))
+    }
+
+    fn inner_offset(&self, offset: libc::off_t) -> libc::off_t {
+        offset + self.volume.data_offset as libc::off_t
+    }
+}
+
+impl AsyncIo for EncryptedAsyncIo {
+    fn notifier(&self) -> &EventFd {
+        self.inner.notifier()
+    }
+
+    fn required_alignment(&self) -> IoAlignment {
+        // The data goes through the bounce buffers, whatever the alignment
+        // of the guest buffers.
+        let inner = self.inner.required_alignment();
+        IoAlignment {
+            offset: inner.offset.max(self.volume.sector_size),
+            memory: 1,
+        }
+    }
+
+    fn submission_room(&self) -> usize {
+        self.inner.submission_room()
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let (len, first_sector) = self
+            .sectors(offset, iovecs)
+            .map_err(AsyncIoError::ReadVectored)?;
+        let buffer = BounceBuffer::new(len).map_err(AsyncIoError::ReadVectored)?;
+
+        self.inner
+            .read_vectored(self.inner_offset(offset), &[buffer.iovec(len)], user_data)?;
+        self.reads.insert(
+            user_data,
+            PendingRead {
+                buffer,
+                iovecs: iovecs.to_vec(),
+                first_sector,
+            },
+        );
+
+        Ok(())
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let (len, first_sector) = self
+            .sectors(offset, iovecs)
+            .map_err(AsyncIoError::WriteVectored)?;
+        let mut buffer = BounceBuffer::new(len).map_err(AsyncIoError::WriteVectored)?;
+
+        let data = &mut buffer.as_mut_slice()[..len];
+        let mut copied = 0;
+        for iovec in iovecs {
+            // SAFETY: the iovecs point to guest memory valid for the
+            // duration of the request.
+            let src =
+                unsafe { std::slice::from_raw_parts(iovec.iov_base as *const u8, iovec.iov_len) };
+            data[copied..copied + src.len()].copy_from_slice(src);
+            copied += src.len();
+        }
+        self.volume
+            .cipher
+            .encrypt(data, self.volume.sector_size as usize, first_sector);
+
+        self.inner
+            .write_vectored(self.inner_offset(offset), &[buffer.iovec(len)], user_data)?;
+        self.writes.insert(user_data, buffer);
+
+        Ok(())
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        self.inner.fsync(user_data)
+    }
+
+    fn cancel(&mut self, user_data: u64) -> AsyncIoResult<bool> {
+        self.inner.cancel(user_data)
+    }
+
+    fn submit(&mut self) -> AsyncIoResult<()> {
+        self.inner.submit()
+    }
+
+    fn next_completed_request(&mut self) -> Option<(u64, i32)> {
+        let (user_data, mut result) = self.inner.next_completed_request()?;
+
+        if let Some(mut read) = self.reads.remove(&user_data) {
+            if result > 0 {
+                let sector_size = self.volume.sector_size as usize;
+                let len = result as usize / sector_size * sector_size;
+                let data = &mut read.buffer.as_mut_slice()[..len];
+                self.volume
+                    .cipher
+                    .decrypt(data, sector_size, read.first_sector);
+
+                let mut copied = 0;
+                for iovec in read.iovecs.iter() {
+                    let count = iovec.iov_len.min(len - copied);
+                    // SAFETY: the iovecs point to guest memory valid for the
+                    // duration of the request.
+                    unsafe {
+                        std::ptr::copy_nonoverlapping(
+                            data[copied..].as_ptr(),
+                            iovec.iov_base as *mut u8,
+                            count,
+                        )
+                    };
+                    copied += count;
+                    if copied == len {
+                        break;
+                    }
+                }
+                // Only whole sectors can be decrypted.
+                result = len as i32;
+            }
+        } else {
+            self.writes.remove(&user_data);
+        }
+
+        Some((user_data, result))
+    }
+}
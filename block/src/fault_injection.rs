@@ -0,0 +1,462 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Deliberate failures and delays of the requests to a disk, letting tests
+//! check how the guest copes with misbehaving host storage.
+
+use crate::async_io::{
+    AsyncIo, AsyncIoError, AsyncIoResult, DiskExtent, DiskFile, DiskFileResult, IoAlignment,
+    RequestPriority,
+};
+use crate::lifetime::DiskLifetime;
+use crate::scsi::{ScsiCommand, ScsiResponse};
+use crate::zoned::BlkZone;
+use crate::{AsyncIoBackend, DiskTopology};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use vmm_sys_util::eventfd::EventFd;
+
+/// Faults injected into the requests to a disk. A request fails with EIO
+/// as soon as one of the conditions holds.
+#[derive(Clone, Debug, Default)]
+pub struct FaultPlan {
+    /// Percentage of the requests failed at random.
+    pub rate: u8,
+    /// Offsets in bytes failing the requests whose range covers one of them.
+    pub offsets: Vec<u64>,
+    /// Number of requests let through before all of them fail.
+    pub after: Option<u64>,
+    /// Delay every request is handed over to the backend after.
+    pub latency: Duration,
+}
+
+// State shared between the disk and the AsyncIo instances of every queue.
+struct FaultInjector {
+    plan: FaultPlan,
+    passed: AtomicU64,
+}
+
+impl FaultInjector {
+    // Returns why the request covering `length` bytes from `offset` must
+    // fail, if it must, counting it as let through otherwise.
+    fn fault(&self, offset: u64, length: u64, random: u64) -> Option<String> {
+        if let Some(after) = self.plan.after {
+            if self.passed.load(Ordering::Acquire) >= after {
+                return Some(format!("after {after} requests"));
+            }
+        }
+        if let Some(fault_offset) = self
+            .plan
+            .offsets
+            .iter()
+            .find(|o| **o >= offset && **o - offset < length)
+        {
+            return Some(format!("covering offset {fault_offset}"));
+        }
+        if random % 100 < u64::from(self.plan.rate) {
+            return Some(format!("at a {}% rate", self.plan.rate));
+        }
+
+        self.passed.fetch_add(1, Ordering::AcqRel);
+        None
+    }
+}
+
+/// [`DiskFile`] failing and delaying the requests made through it as its
+/// [`FaultPlan`] dictates.
+pub struct FaultInjectingDisk {
+    inner: Box<dyn DiskFile>,
+    injector: Arc<FaultInjector>,
+}
+
+impl FaultInjectingDisk {
+    pub fn new(inner: Box<dyn DiskFile>, plan: FaultPlan) -> Self {
+        FaultInjectingDisk {
+            inner,
+            injector: Arc::new(FaultInjector {
+                plan,
+                passed: AtomicU64::new(0),
+            }),
+        }
+    }
+}
+
+impl DiskFile for FaultInjectingDisk {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        self.inner.size()
+    }
+
+    fn new_async_io(&self, ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        // Only meant to tell the queues apart, any seed will do.
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+        Ok(Box::new(FaultInjectingAsyncIo {
+            inner: self.inner.new_async_io(ring_depth)?,
+            injector: self.injector.clone(),
+            faults: VecDeque::new(),
+            random: seed | 1,
+        }) as Box<dyn AsyncIo>)
+    }
+
+    fn topology(&mut self) -> DiskTopology {
+        self.inner.topology()
+    }
+
+    fn supports_discard(&self) -> bool {
+        self.inner.supports_discard()
+    }
+
+    fn supports_write_zeroes(&self) -> bool {
+        self.inner.supports_write_zeroes()
+    }
+
+    fn discard_zeroes(&self) -> bool {
+        self.inner.discard_zeroes()
+    }
+
+    fn serial(&mut self) -> Option<String> {
+        self.inner.serial()
+    }
+
+    fn rotational(&mut self) -> bool {
+        self.inner.rotational()
+    }
+
+    fn get_lifetime(&mut self) -> DiskLifetime {
+        self.inner.get_lifetime()
+    }
+
+    fn available_space(&self) -> DiskFileResult<Option<u64>> {
+        self.inner.available_space()
+    }
+
+    fn backend(&self) -> AsyncIoBackend {
+        self.inner.backend()
+    }
+
+    fn resize(&mut self, current_size: u64) -> DiskFileResult<u64> {
+        self.inner.resize(current_size)
+    }
+
+    fn extents(&mut self) -> DiskFileResult<Vec<DiskExtent>> {
+        self.inner.extents()
+    }
+
+    fn start_dirty_tracking(&mut self, granularity: u64) -> DiskFileResult<()> {
+        self.inner.start_dirty_tracking(granularity)
+    }
+
+    fn stop_dirty_tracking(&mut self) {
+        self.inner.stop_dirty_tracking()
+    }
+
+    fn get_dirty_blocks(&mut self) -> DiskFileResult<Vec<DiskExtent>> {
+        self.inner.get_dirty_blocks()
+    }
+
+    fn send_scsi_command(&mut self, command: &ScsiCommand) -> DiskFileResult<ScsiResponse> {
+        self.inner.send_scsi_command(command)
+    }
+}
+
+/// [`AsyncIo`] completing the requests picked by the [`FaultPlan`] with EIO
+/// instead of handing them over to the backend.
+pub struct FaultInjectingAsyncIo {
+    inner: Box<dyn AsyncIo>,
+    injector: Arc<FaultInjector>,
+    // Completions of the failed requests, returned ahead of the backend
+    // ones.
+    faults: VecDeque<(u64, i32)>,
+    // State of the xorshift generator the random faults are drawn from.
+    random: u64,
+}
+
+impl FaultInjectingAsyncIo {
+    fn next_random(&mut self) -> u64 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 7;
+        self.random ^= self.random << 17;
+        self.random
+    }
+
+    // Delays the request, then returns whether it has been failed in place
+    // of being handed over to the backend.
+    fn inject(
+        &mut self,
+        operation: &str,
+        offset: u64,
+        length: u64,
+        user_data: u64,
+    ) -> AsyncIoResult<bool> {
+        if !self.injector.plan.latency.is_zero() {
+            std::thread::sleep(self.injector.plan.latency);
+        }
+
+        let random = self.next_random();
+        let reason = match self.injector.fault(offset, length, random) {
+            Some(reason) => reason,
+            None => return Ok(false),
+        };
+        warn!(
+            "Injecting an I/O error into the {} of {} bytes at offset {} {}",
+            operation, length, offset, reason
+        );
+        self.faults.push_back((user_data, -libc::EIO));
+        // The completion is only noticed once the notifier fires.
+        self.inner
+            .notifier()
+            .write(1)
+            .map_err(AsyncIoError::Submit)?;
+
+        Ok(true)
+    }
+}
+
+fn iovecs_len(iovecs: &[libc::iovec]) -> u64 {
+    iovecs.iter().map(|iovec| iovec.iov_len as u64).sum()
+}
+
+impl AsyncIo for FaultInjectingAsyncIo {
+    fn notifier(&self) -> &EventFd {
+        self.inner.notifier()
+    }
+
+    fn required_alignment(&self) -> IoAlignment {
+        self.inner.required_alignment()
+    }
+
+    fn submission_room(&self) -> usize {
+        self.inner.submission_room()
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        if self.inject("read", offset as u64, iovecs_len(iovecs), user_data)? {
+            return Ok(());
+        }
+        self.inner.read_vectored(offset, iovecs, user_data)
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        if self.inject("write", offset as u64, iovecs_len(iovecs), user_data)? {
+            return Ok(());
+        }
+        self.inner.write_vectored(offset, iovecs, user_data)
+    }
+
+    fn write_vectored_fua(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        if self.inject("write", offset as u64, iovecs_len(iovecs), user_data)? {
+            return Ok(());
+        }
+        self.inner.write_vectored_fua(offset, iovecs, user_data)
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        // The synchronous flushes are issued by the device itself, such as
+        // after the writes of a writethrough disk, rather than the guest.
+        if let Some(user_data) = user_data {
+            if self.inject("flush", 0, 0, user_data)? {
+                return Ok(());
+            }
+        }
+        self.inner.fsync(user_data)
+    }
+
+    fn read_vectored_with_priority(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+        priority: RequestPriority,
+    ) -> AsyncIoResult<()> {
+        if self.inject("read", offset as u64, iovecs_len(iovecs), user_data)? {
+            return Ok(());
+        }
+        self.inner
+            .read_vectored_with_priority(offset, iovecs, user_data, priority)
+    }
+
+    fn write_vectored_with_priority(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+        priority: RequestPriority,
+    ) -> AsyncIoResult<()> {
+        if self.inject("write", offset as u64, iovecs_len(iovecs), user_data)? {
+            return Ok(());
+        }
+        self.inner
+            .write_vectored_with_priority(offset, iovecs, user_data, priority)
+    }
+
+    fn fsync_with_priority(
+        &mut self,
+        user_data: Option<u64>,
+        priority: RequestPriority,
+    ) -> AsyncIoResult<()> {
+        if let Some(user_data) = user_data {
+            if self.inject("flush", 0, 0, user_data)? {
+                return Ok(());
+            }
+        }
+        self.inner.fsync_with_priority(user_data, priority)
+    }
+
+    fn register_buffers(&mut self, regions: &[libc::iovec]) -> AsyncIoResult<bool> {
+        self.inner.register_buffers(regions)
+    }
+
+    fn discard(&mut self, offset: libc::off_t, length: u64, user_data: u64) -> AsyncIoResult<()> {
+        if self.inject("discard", offset as u64, length, user_data)? {
+            return Ok(());
+        }
+        self.inner.discard(offset, length, user_data)
+    }
+
+    fn write_zeroes(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        unmap: bool,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        if self.inject("write zeroes", offset as u64, length, user_data)? {
+            return Ok(());
+        }
+        self.inner.write_zeroes(offset, length, unmap, user_data)
+    }
+
+    fn zone_report(
+        &mut self,
+        offset: libc::off_t,
+        zones: &mut [BlkZone],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.inner.zone_report(offset, zones, user_data)
+    }
+
+    fn zone_open(&mut self, offset: libc::off_t, user_data: u64) -> AsyncIoResult<()> {
+        self.inner.zone_open(offset, user_data)
+    }
+
+    fn zone_close(&mut self, offset: libc::off_t, user_data: u64) -> AsyncIoResult<()> {
+        self.inner.zone_close(offset, user_data)
+    }
+
+    fn zone_finish(&mut self, offset: libc::off_t, user_data: u64) -> AsyncIoResult<()> {
+        self.inner.zone_finish(offset, user_data)
+    }
+
+    fn zone_reset(&mut self, offset: libc::off_t, user_data: u64) -> AsyncIoResult<()> {
+        self.inner.zone_reset(offset, user_data)
+    }
+
+    fn cancel(&mut self, user_data: u64) -> AsyncIoResult<bool> {
+        self.inner.cancel(user_data)
+    }
+
+    fn submit(&mut self) -> AsyncIoResult<()> {
+        self.inner.submit()
+    }
+
+    fn next_completed_request(&mut self) -> Option<(u64, i32)> {
+        self.faults
+            .pop_front()
+            .or_else(|| self.inner.next_completed_request())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::null_disk::NullDiskFile;
+
+    fn new_io(plan: FaultPlan) -> Box<dyn AsyncIo> {
+        let disk = FaultInjectingDisk::new(Box::new(NullDiskFile::new(1 << 20, None)), plan);
+        disk.new_async_io(1).unwrap()
+    }
+
+    fn write(io: &mut dyn AsyncIo, offset: libc::off_t, user_data: u64) -> Option<i32> {
+        let mut data = vec![0u8; 4096];
+        let iovec = libc::iovec {
+            iov_base: data.as_mut_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        io.write_vectored(offset, &[iovec], user_data).unwrap();
+        io.next_completed_request()
+            .filter(|(u, _)| *u == user_data)
+            .map(|(_, result)| result)
+    }
+
+    #[test]
+    fn test_fault_offsets() {
+        let mut io = new_io(FaultPlan {
+            offsets: vec![8192 + 512],
+            ..Default::default()
+        });
+        assert_eq!(write(io.as_mut(), 0, 1), Some(4096));
+        assert_eq!(write(io.as_mut(), 8192, 2), Some(-libc::EIO));
+        assert_eq!(write(io.as_mut(), 4096, 3), Some(4096));
+        assert_eq!(write(io.as_mut(), 8192 + 4096, 4), Some(4096));
+    }
+
+    #[test]
+    fn test_fault_after() {
+        let mut io = new_io(FaultPlan {
+            after: Some(2),
+            ..Default::default()
+        });
+        assert_eq!(write(io.as_mut(), 0, 1), Some(4096));
+        assert_eq!(write(io.as_mut(), 0, 2), Some(4096));
+        assert_eq!(write(io.as_mut(), 0, 3), Some(-libc::EIO));
+        assert_eq!(write(io.as_mut(), 0, 4), Some(-libc::EIO));
+        io.fsync(None).unwrap();
+        io.fsync(Some(5)).unwrap();
+        assert_eq!(io.next_completed_request(), Some((5, -libc::EIO)));
+    }
+
+    #[test]
+    fn test_fault_rate() {
+        let mut io = new_io(FaultPlan {
+            rate: 100,
+            ..Default::default()
+        });
+        assert_eq!(write(io.as_mut(), 0, 1), Some(-libc::EIO));
+
+        let mut io = new_io(FaultPlan::default());
+        for user_data in 0..100 {
+            assert_eq!(write(io.as_mut(), 0, user_data), Some(4096));
+        }
+    }
+
+    #[test]
+    fn test_fault_latency() {
+        let mut io = new_io(FaultPlan {
+            latency: Duration::from_millis(20),
+            ..Default::default()
+        });
+        let start = std::time::Instant::now();
+        assert_eq!(write(io.as_mut(), 0, 1), Some(4096));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+}
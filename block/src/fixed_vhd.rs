@@ -2,10 +2,11 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::vhd::VhdFooter;
+use crate::vhd::{VhdError, VhdFooter, VHD_FOOTER_SIZE};
 use crate::BlockBackend;
 use std::fs::File;
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
 use std::os::unix::io::{AsRawFd, RawFd};
 
 #[derive(Debug)]
@@ -18,6 +19,9 @@ pub struct FixedVhd {
 impl FixedVhd {
     pub fn new(mut file: File) -> std::io::Result<Self> {
         let footer = VhdFooter::new(&mut file)?;
+        let file_len = file.seek(SeekFrom::End(0))?;
+        footer.validate_fixed(file_len)?;
+        file.rewind()?;
 
         Ok(Self {
             file,
@@ -25,6 +29,23 @@ impl FixedVhd {
             position: 0,
         })
     }
+
+    /// Turns `file` into a fixed VHD of `size` bytes, appending the footer
+    /// after the data.
+    pub fn create(file: File, size: u64) -> Result<Self, VhdError> {
+        let footer = VhdFooter::new_fixed(size);
+        file.set_len(size + VHD_FOOTER_SIZE)
+            .map_err(VhdError::WriteFooter)?;
+        file.write_all_at(&footer.to_bytes(), size)
+            .map_err(VhdError::WriteFooter)?;
+        file.sync_all().map_err(VhdError::WriteFooter)?;
+
+        Ok(Self {
+            file,
+            size,
+            position: 0,
+        })
+    }
 }
 
 impl AsRawFd for FixedVhd {
@@ -3,11 +3,12 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::async_io::{
-    AsyncIo, AsyncIoError, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult,
+    AsyncIo, AsyncIoError, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult, IoAlignment,
+    RequestPriority,
 };
 use crate::fixed_vhd::FixedVhd;
 use crate::raw_async::RawFileAsync;
-use crate::BlockBackend;
+use crate::{AsyncIoBackend, BlockBackend};
 use std::fs::File;
 use std::os::unix::io::{AsRawFd, RawFd};
 use vmm_sys_util::eventfd::EventFd;
@@ -31,6 +32,10 @@ impl DiskFile for FixedVhdDiskAsync {
                 .map_err(DiskFileError::NewAsyncIo)?,
         ) as Box<dyn AsyncIo>)
     }
+
+    fn backend(&self) -> AsyncIoBackend {
+        AsyncIoBackend::IoUring
+    }
 }
 
 pub struct FixedVhdAsync {
@@ -40,7 +45,7 @@ pub struct FixedVhdAsync {
 
 impl FixedVhdAsync {
     pub fn new(fd: RawFd, ring_depth: u32, size: u64) -> std::io::Result<Self> {
-        let raw_file_async = RawFileAsync::new(fd, ring_depth)?;
+        let raw_file_async = RawFileAsync::new(fd, ring_depth, None)?;
 
         Ok(FixedVhdAsync {
             raw_file_async,
@@ -54,11 +59,42 @@ impl AsyncIo for FixedVhdAsync {
         self.raw_file_async.notifier()
     }
 
+    fn required_alignment(&self) -> IoAlignment {
+        self.raw_file_async.required_alignment()
+    }
+
+    fn submission_room(&self) -> usize {
+        self.raw_file_async.submission_room()
+    }
+
     fn read_vectored(
         &mut self,
         offset: libc::off_t,
         iovecs: &[libc::iovec],
         user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.read_vectored_with_priority(offset, iovecs, user_data, RequestPriority::Normal)
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.write_vectored_with_priority(offset, iovecs, user_data, RequestPriority::Normal)
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        self.raw_file_async.fsync(user_data)
+    }
+
+    fn read_vectored_with_priority(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+        priority: RequestPriority,
     ) -> AsyncIoResult<()> {
         if offset as u64 >= self.size {
             return Err(AsyncIoError::ReadVectored(std::io::Error::new(
@@ -70,14 +106,16 @@ impl AsyncIo for FixedVhdAsync {
             )));
         }
 
-        self.raw_file_async.read_vectored(offset, iovecs, user_data)
+        self.raw_file_async
+            .read_vectored_with_priority(offset, iovecs, user_data, priority)
     }
 
-    fn write_vectored(
+    fn write_vectored_with_priority(
         &mut self,
         offset: libc::off_t,
         iovecs: &[libc::iovec],
         user_data: u64,
+        priority: RequestPriority,
     ) -> AsyncIoResult<()> {
         if offset as u64 >= self.size {
             return Err(AsyncIoError::WriteVectored(std::io::Error::new(
@@ -90,11 +128,23 @@ impl AsyncIo for FixedVhdAsync {
         }
 
         self.raw_file_async
-            .write_vectored(offset, iovecs, user_data)
+            .write_vectored_with_priority(offset, iovecs, user_data, priority)
     }
 
-    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
-        self.raw_file_async.fsync(user_data)
+    fn fsync_with_priority(
+        &mut self,
+        user_data: Option<u64>,
+        priority: RequestPriority,
+    ) -> AsyncIoResult<()> {
+        self.raw_file_async.fsync_with_priority(user_data, priority)
+    }
+
+    fn cancel(&mut self, user_data: u64) -> AsyncIoResult<bool> {
+        self.raw_file_async.cancel(user_data)
+    }
+
+    fn submit(&mut self) -> AsyncIoResult<()> {
+        self.raw_file_async.submit()
     }
 
     fn next_completed_request(&mut self) -> Option<(u64, i32)> {
@@ -7,7 +7,7 @@ use crate::async_io::{
 };
 use crate::fixed_vhd::FixedVhd;
 use crate::raw_sync::RawFileSync;
-use crate::BlockBackend;
+use crate::{BlockBackend, CacheMode};
 use std::fs::File;
 use std::os::unix::io::{AsRawFd, RawFd};
 use vmm_sys_util::eventfd::EventFd;
@@ -41,7 +41,7 @@ pub struct FixedVhdSync {
 impl FixedVhdSync {
     pub fn new(fd: RawFd, size: u64) -> std::io::Result<Self> {
         Ok(FixedVhdSync {
-            raw_file_sync: RawFileSync::new(fd),
+            raw_file_sync: RawFileSync::new(fd, None, None, false, CacheMode::Writeback)?,
             size,
         })
     }
@@ -52,6 +52,10 @@ impl AsyncIo for FixedVhdSync {
         self.raw_file_sync.notifier()
     }
 
+    fn submission_room(&self) -> usize {
+        self.raw_file_sync.submission_room()
+    }
+
     fn read_vectored(
         &mut self,
         offset: libc::off_t,
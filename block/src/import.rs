@@ -0,0 +1,299 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Import of a RAW disk image from a stream, such as a pipe, optionally
+//! decompressing it on the fly. The image is written out to a sparse file
+//! which only appears at the disk path once the whole stream was imported,
+//! so that an interrupted import can't be mistaken for a usable disk.
+
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use thiserror::Error;
+
+// Size of the chunks read from the stream.
+const CHUNK_SIZE: usize = 1 << 20;
+// Blocks only holding zeroes are left as holes in the disk file.
+const ZERO_BLOCK_SIZE: usize = 4096;
+// Number of bytes imported between two progress reports.
+pub(crate) const PROGRESS_INTERVAL: u64 = 1 << 30;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed creating the disk file: {0}")]
+    CreateFile(#[source] io::Error),
+    #[error("Failed reading the image stream: {0}")]
+    Read(#[source] io::Error),
+    #[error("Failed setting up the decompression of the stream: {0}")]
+    Decompress(#[source] io::Error),
+    #[error("Decompressing {0:?} images is not supported by this build")]
+    CompressionNotSupported(ImportCompression),
+    #[error("Failed writing the disk file: {0}")]
+    Write(#[source] io::Error),
+    #[error("Failed synchronizing the disk file: {0}")]
+    Sync(#[source] io::Error),
+    #[error("Failed moving the disk file in place: {0}")]
+    Rename(#[source] io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Compression of the stream a disk image is imported from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ImportCompression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+#[derive(Debug)]
+pub enum ParseImportCompressionError {
+    InvalidValue(String),
+}
+
+impl FromStr for ImportCompression {
+    type Err = ParseImportCompressionError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(ImportCompression::None),
+            "gzip" => Ok(ImportCompression::Gzip),
+            "zstd" => Ok(ImportCompression::Zstd),
+            _ => Err(ParseImportCompressionError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+/// Path of the file the image is written to while being imported to `path`.
+pub fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = OsString::from(path.as_os_str());
+    partial.push(".partial");
+    PathBuf::from(partial)
+}
+
+/// Imports the RAW image read from `reader` to a new sparse file at `path`,
+/// returning the size of the image.
+///
+/// `progress` is called with the number of bytes imported so far, every
+/// gigabyte and once the import is complete. On failure, nothing is left at
+/// `path`.
+pub fn import_image<R, F>(
+    reader: R,
+    compression: ImportCompression,
+    path: &Path,
+    progress: F,
+) -> Result<u64>
+where
+    R: Read,
+    F: FnMut(u64),
+{
+    let partial = partial_path(path);
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&partial)
+        .map_err(Error::CreateFile)?;
+
+    let result = decoder(reader, compression)
+        .and_then(|mut reader| copy_sparse(&mut reader, &file, progress))
+        .and_then(|size| file.sync_all().map(|_| size).map_err(Error::Sync))
+        .and_then(|size| {
+            fs::rename(&partial, path)
+                .map(|_| size)
+                .map_err(Error::Rename)
+        });
+    if result.is_err() {
+        if let Err(e) = fs::remove_file(&partial) {
+            warn!(
+                "Failed removing the partially imported {:?}: {}",
+                partial, e
+            );
+        }
+    }
+
+    result
+}
+
+fn decoder<'a, R: Read + 'a>(
+    reader: R,
+    compression: ImportCompression,
+) -> Result<Box<dyn Read + 'a>> {
+    match compression {
+        ImportCompression::None => Ok(Box::new(reader)),
+        #[cfg(feature = "compressed_import")]
+        ImportCompression::Gzip => Ok(Box::new(flate2::read::MultiGzDecoder::new(reader))),
+        #[cfg(feature = "compressed_import")]
+        ImportCompression::Zstd => Ok(Box::new(
+            zstd::stream::read::Decoder::new(reader).map_err(Error::Decompress)?,
+        )),
+        #[cfg(not(feature = "compressed_import"))]
+        _ => Err(Error::CompressionNotSupported(compression)),
+    }
+}
+
+// Fill `buf` from `reader`, returning less than its length only at the end
+// of the stream, since a pipe hands the data over in small pieces.
+fn read_chunk(reader: &mut dyn Read, buf: &mut [u8]) -> Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(Error::Read(e)),
+        }
+    }
+    Ok(len)
+}
+
+// Returns the ranges of `buf` holding data, the blocks only holding zeroes
+// being left out.
+pub(crate) fn data_runs(buf: &[u8]) -> Vec<Range<usize>> {
+    let mut runs: Vec<Range<usize>> = Vec::new();
+    for (i, block) in buf.chunks(ZERO_BLOCK_SIZE).enumerate() {
+        if block.iter().all(|b| *b == 0) {
+            continue;
+        }
+        let start = i * ZERO_BLOCK_SIZE;
+        let end = start + block.len();
+        match runs.last_mut() {
+            Some(run) if run.end == start => run.end = end,
+            _ => runs.push(start..end),
+        }
+    }
+
+    runs
+}
+
+fn copy_sparse<F: FnMut(u64)>(reader: &mut dyn Read, file: &File, mut progress: F) -> Result<u64> {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut offset = 0;
+    let mut next_report = PROGRESS_INTERVAL;
+    loop {
+        let len = read_chunk(reader, &mut buf)?;
+        if len == 0 {
+            break;
+        }
+
+        // Write the runs of blocks holding data, skipping the zeroes.
+        for run in data_runs(&buf[..len]) {
+            file.write_all_at(&buf[run.clone()], offset + run.start as u64)
+                .map_err(Error::Write)?;
+        }
+
+        offset += len as u64;
+        if offset >= next_report {
+            progress(offset);
+            next_report = offset + PROGRESS_INTERVAL;
+        }
+    }
+
+    // The trailing holes were never written.
+    file.set_len(offset).map_err(Error::Write)?;
+    progress(offset);
+
+    Ok(offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempdir::TempDir;
+
+    // Stream failing after handing over some data.
+    struct BrokenReader(usize);
+
+    impl Read for BrokenReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0 == 0 {
+                return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+            }
+            let len = self.0.min(buf.len());
+            buf[..len].fill(0xaa);
+            self.0 -= len;
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn test_import_image() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("disk.raw");
+
+        let mut image = vec![0u8; 3 * CHUNK_SIZE + 1000];
+        image[..512].fill(0x55);
+        image[CHUNK_SIZE + 4096..CHUNK_SIZE + 3 * 4096].fill(0x66);
+        image[3 * CHUNK_SIZE..3 * CHUNK_SIZE + 10].fill(0x77);
+        *image.last_mut().unwrap() = 0x88;
+
+        let mut reports = Vec::new();
+        let size = import_image(image.as_slice(), ImportCompression::None, &path, |bytes| {
+            reports.push(bytes)
+        })
+        .unwrap();
+        assert_eq!(size, image.len() as u64);
+        assert_eq!(reports, vec![image.len() as u64]);
+        assert_eq!(fs::read(&path).unwrap(), image);
+        assert!(!partial_path(&path).exists());
+    }
+
+    #[test]
+    fn test_import_image_failure() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("disk.raw");
+
+        assert!(matches!(
+            import_image(
+                BrokenReader(CHUNK_SIZE + 10),
+                ImportCompression::None,
+                &path,
+                |_| {}
+            ),
+            Err(Error::Read(_))
+        ));
+        assert!(!path.exists());
+        assert!(!partial_path(&path).exists());
+    }
+
+    #[test]
+    fn test_parse_import_compression() {
+        assert_eq!(
+            "gzip".parse::<ImportCompression>().unwrap(),
+            ImportCompression::Gzip
+        );
+        assert_eq!(
+            "ZSTD".parse::<ImportCompression>().unwrap(),
+            ImportCompression::Zstd
+        );
+        assert!("xz".parse::<ImportCompression>().is_err());
+    }
+
+    #[cfg(feature = "compressed_import")]
+    #[test]
+    fn test_import_compressed_image() {
+        use std::io::Write;
+
+        let dir = TempDir::new().unwrap();
+        let image: Vec<u8> = (0..100_000).map(|i| (i % 7) as u8).collect();
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(&image).unwrap();
+        let gzip = encoder.finish().unwrap();
+        let path = dir.as_path().join("gzip.raw");
+        import_image(gzip.as_slice(), ImportCompression::Gzip, &path, |_| {}).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), image);
+
+        let zstd = zstd::stream::encode_all(image.as_slice(), 0).unwrap();
+        let path = dir.as_path().join("zstd.raw");
+        import_image(zstd.as_slice(), ImportCompression::Zstd, &path, |_| {}).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), image);
+    }
+}
@@ -0,0 +1,392 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Latency histograms of the requests going through an [`AsyncIo`].
+//!
+//! Latencies are recorded in microseconds into log-linear buckets: values
+//! are exact up to 16µs, beyond that each power of two is split into 8
+//! buckets, bounding the error to 12.5% whatever the magnitude.
+
+use crate::async_io::{AsyncIo, AsyncIoResult, IoAlignment, RequestPriority};
+use crate::zoned::BlkZone;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use vmm_sys_util::eventfd::EventFd;
+
+const SUB_BUCKET_BITS: u32 = 3;
+const SUB_BUCKET_COUNT: u64 = 1 << SUB_BUCKET_BITS;
+const LINEAR_BUCKET_COUNT: u64 = 2 * SUB_BUCKET_COUNT;
+const BUCKET_COUNT: usize = (65 - SUB_BUCKET_BITS as usize) * SUB_BUCKET_COUNT as usize;
+
+// Index of the bucket holding `value`.
+fn bucket_index(value: u64) -> usize {
+    if value < LINEAR_BUCKET_COUNT {
+        return value as usize;
+    }
+
+    let shift = 64 - value.leading_zeros() - SUB_BUCKET_BITS - 1;
+    (u64::from(shift) * SUB_BUCKET_COUNT + (value >> shift)) as usize
+}
+
+// Highest value held by the bucket at `index`.
+fn bucket_highest_value(index: usize) -> u64 {
+    let index = index as u64;
+    if index < LINEAR_BUCKET_COUNT {
+        return index;
+    }
+
+    let shift = index / SUB_BUCKET_COUNT - 1;
+    let mantissa = index - shift * SUB_BUCKET_COUNT;
+    (mantissa << shift) + ((1 << shift) - 1)
+}
+
+/// Latency distribution of a single type of operation.
+#[derive(Clone)]
+pub struct LatencyHistogram {
+    buckets: Vec<u64>,
+    count: u64,
+    min: u64,
+    max: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram {
+            buckets: vec![0; BUCKET_COUNT],
+            count: 0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    /// Record a latency, in microseconds.
+    pub fn record(&mut self, latency: u64) {
+        self.buckets[bucket_index(latency)] += 1;
+        self.count += 1;
+        self.min = self.min.min(latency);
+        self.max = self.max.max(latency);
+    }
+
+    /// Returns the latency below which `percentile` percents of the
+    /// recorded latencies are.
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+
+        let target = ((percentile / 100.0 * self.count as f64).ceil() as u64).clamp(1, self.count);
+        let mut seen = 0;
+        for (index, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return bucket_highest_value(index).clamp(self.min, self.max);
+            }
+        }
+
+        self.max
+    }
+
+    pub fn snapshot(&self) -> LatencySnapshot {
+        LatencySnapshot {
+            count: self.count,
+            min: if self.count == 0 { 0 } else { self.min },
+            max: self.max,
+            p50: self.percentile(50.0),
+            p90: self.percentile(90.0),
+            p99: self.percentile(99.0),
+            p999: self.percentile(99.9),
+        }
+    }
+}
+
+/// Summary of a latency distribution, in microseconds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LatencySnapshot {
+    pub count: u64,
+    pub min: u64,
+    pub max: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub p999: u64,
+}
+
+/// Latency distributions of the requests of a disk, per type of operation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockLatencySnapshot {
+    pub read: LatencySnapshot,
+    pub write: LatencySnapshot,
+    pub flush: LatencySnapshot,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum LatencyOp {
+    Read,
+    Write,
+    Flush,
+}
+
+#[derive(Default)]
+struct LatencyHistograms {
+    read: LatencyHistogram,
+    write: LatencyHistogram,
+    flush: LatencyHistogram,
+}
+
+/// Collects the latencies of the requests of a disk, shared by all the
+/// [`MeteredAsyncIo`] of its queues.
+#[derive(Clone, Default)]
+pub struct LatencyCollector {
+    histograms: Arc<Mutex<LatencyHistograms>>,
+}
+
+impl LatencyCollector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn record(&self, op: LatencyOp, latency: Duration) {
+        let latency = latency.as_micros() as u64;
+        let mut histograms = self.histograms.lock().unwrap();
+        match op {
+            LatencyOp::Read => histograms.read.record(latency),
+            LatencyOp::Write => histograms.write.record(latency),
+            LatencyOp::Flush => histograms.flush.record(latency),
+        }
+    }
+
+    pub fn snapshot(&self) -> BlockLatencySnapshot {
+        let histograms = self.histograms.lock().unwrap();
+        BlockLatencySnapshot {
+            read: histograms.read.snapshot(),
+            write: histograms.write.snapshot(),
+            flush: histograms.flush.snapshot(),
+        }
+    }
+}
+
+/// [`AsyncIo`] wrapper timing the reads, writes and flushes from their
+/// submission until their completion is fetched.
+pub struct MeteredAsyncIo {
+    inner: Box<dyn AsyncIo>,
+    collector: LatencyCollector,
+    inflight: HashMap<u64, (LatencyOp, Instant)>,
+}
+
+impl MeteredAsyncIo {
+    pub fn new(inner: Box<dyn AsyncIo>, collector: LatencyCollector) -> Self {
+        MeteredAsyncIo {
+            inner,
+            collector,
+            inflight: HashMap::new(),
+        }
+    }
+
+    fn track<F>(&mut self, op: LatencyOp, user_data: u64, submit: F) -> AsyncIoResult<()>
+    where
+        F: FnOnce(&mut dyn AsyncIo) -> AsyncIoResult<()>,
+    {
+        let start = Instant::now();
+        submit(self.inner.as_mut())?;
+        self.inflight.insert(user_data, (op, start));
+
+        Ok(())
+    }
+}
+
+impl AsyncIo for MeteredAsyncIo {
+    fn notifier(&self) -> &EventFd {
+        self.inner.notifier()
+    }
+
+    fn required_alignment(&self) -> IoAlignment {
+        self.inner.required_alignment()
+    }
+
+    fn submission_room(&self) -> usize {
+        self.inner.submission_room()
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.track(LatencyOp::Read, user_data, |inner| {
+            inner.read_vectored(offset, iovecs, user_data)
+        })
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.track(LatencyOp::Write, user_data, |inner| {
+            inner.write_vectored(offset, iovecs, user_data)
+        })
+    }
+
+    fn write_vectored_fua(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.track(LatencyOp::Write, user_data, |inner| {
+            inner.write_vectored_fua(offset, iovecs, user_data)
+        })
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        self.fsync_with_priority(user_data, RequestPriority::Normal)
+    }
+
+    fn read_vectored_with_priority(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+        priority: RequestPriority,
+    ) -> AsyncIoResult<()> {
+        self.track(LatencyOp::Read, user_data, |inner| {
+            inner.read_vectored_with_priority(offset, iovecs, user_data, priority)
+        })
+    }
+
+    fn write_vectored_with_priority(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+        priority: RequestPriority,
+    ) -> AsyncIoResult<()> {
+        self.track(LatencyOp::Write, user_data, |inner| {
+            inner.write_vectored_with_priority(offset, iovecs, user_data, priority)
+        })
+    }
+
+    fn fsync_with_priority(
+        &mut self,
+        user_data: Option<u64>,
+        priority: RequestPriority,
+    ) -> AsyncIoResult<()> {
+        if let Some(user_data) = user_data {
+            self.track(LatencyOp::Flush, user_data, |inner| {
+                inner.fsync_with_priority(Some(user_data), priority)
+            })
+        } else {
+            self.inner.fsync_with_priority(None, priority)
+        }
+    }
+
+    fn discard(&mut self, offset: libc::off_t, length: u64, user_data: u64) -> AsyncIoResult<()> {
+        self.inner.discard(offset, length, user_data)
+    }
+
+    fn write_zeroes(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        unmap: bool,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.inner.write_zeroes(offset, length, unmap, user_data)
+    }
+
+    fn zone_report(
+        &mut self,
+        offset: libc::off_t,
+        zones: &mut [BlkZone],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.inner.zone_report(offset, zones, user_data)
+    }
+
+    fn zone_open(&mut self, offset: libc::off_t, user_data: u64) -> AsyncIoResult<()> {
+        self.inner.zone_open(offset, user_data)
+    }
+
+    fn zone_close(&mut self, offset: libc::off_t, user_data: u64) -> AsyncIoResult<()> {
+        self.inner.zone_close(offset, user_data)
+    }
+
+    fn zone_finish(&mut self, offset: libc::off_t, user_data: u64) -> AsyncIoResult<()> {
+        self.inner.zone_finish(offset, user_data)
+    }
+
+    fn zone_reset(&mut self, offset: libc::off_t, user_data: u64) -> AsyncIoResult<()> {
+        self.inner.zone_reset(offset, user_data)
+    }
+
+    fn cancel(&mut self, user_data: u64) -> AsyncIoResult<bool> {
+        self.inner.cancel(user_data)
+    }
+
+    fn submit(&mut self) -> AsyncIoResult<()> {
+        self.inner.submit()
+    }
+
+    fn next_completed_request(&mut self) -> Option<(u64, i32)> {
+        let (user_data, result) = self.inner.next_completed_request()?;
+        if let Some((op, start)) = self.inflight.remove(&user_data) {
+            self.collector.record(op, start.elapsed());
+        }
+
+        Some((user_data, result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_boundaries() {
+        for value in 0..LINEAR_BUCKET_COUNT {
+            assert_eq!(bucket_index(value), value as usize);
+        }
+        assert_eq!(bucket_index(16), 16);
+        assert_eq!(bucket_index(17), 16);
+        assert_eq!(bucket_index(18), 17);
+        assert_eq!(bucket_index(31), 23);
+        assert_eq!(bucket_index(32), 24);
+        assert_eq!(bucket_index(u64::MAX), BUCKET_COUNT - 1);
+
+        // Every value falls in a bucket whose highest value is not below it.
+        for value in [0, 15, 16, 17, 100, 1000, 123_456, u64::MAX / 3, u64::MAX] {
+            let index = bucket_index(value);
+            assert!(bucket_highest_value(index) >= value);
+            if index > 0 {
+                assert!(bucket_highest_value(index - 1) < value);
+            }
+        }
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.snapshot(), LatencySnapshot::default());
+
+        for latency in 1..=100 {
+            histogram.record(latency);
+        }
+        histogram.record(10_000);
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 101);
+        assert_eq!(snapshot.min, 1);
+        assert_eq!(snapshot.max, 10_000);
+        // Bucket precision is 12.5% of the value.
+        assert!((51..=58).contains(&snapshot.p50));
+        assert!((100..=112).contains(&snapshot.p99));
+        assert_eq!(snapshot.p999, 10_000);
+    }
+}
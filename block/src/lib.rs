@@ -12,13 +12,39 @@
 extern crate log;
 
 pub mod async_io;
+pub mod cgroup;
+pub mod concat;
+pub mod convert;
+pub mod dax_disk;
+pub mod dirty;
+pub mod dm_thin;
+#[cfg(feature = "luks")]
+/// Enabled with the `"luks"` feature
+pub mod encrypted_disk;
+pub mod fault_injection;
 pub mod fixed_vhd;
 #[cfg(feature = "io_uring")]
 /// Enabled with the `"io_uring"` feature
 pub mod fixed_vhd_async;
 pub mod fixed_vhd_sync;
+pub mod import;
+pub mod latency;
+pub mod lifetime;
+#[cfg(feature = "luks")]
+/// LUKS2 header parsing and unlocking
+///
+/// Enabled with the `"luks"` feature
+pub mod luks;
+pub mod memory_disk;
+pub mod null_disk;
+pub mod numa;
+pub mod ordered;
+pub mod overlay;
+pub mod preallocate;
 pub mod qcow;
 pub mod qcow_sync;
+pub mod qed;
+pub mod qed_sync;
 #[cfg(feature = "io_uring")]
 /// Async primitives based on `io-uring`
 ///
@@ -26,17 +52,30 @@ pub mod qcow_sync;
 pub mod raw_async;
 pub mod raw_async_aio;
 pub mod raw_sync;
+pub mod readahead;
+pub mod scrubber;
+pub mod scsi;
+pub mod striped;
 pub mod vhd;
 pub mod vhdx;
 pub mod vhdx_sync;
-
-use crate::async_io::{AsyncIo, AsyncIoError, AsyncIoResult};
+#[cfg(feature = "verified")]
+/// Enabled with the `"verified"` feature
+pub mod verified_disk;
+pub mod zoned;
+
+use crate::async_io::{
+    check_completion_room, AsyncIo, AsyncIoError, AsyncIoResult, DiskExtent, IoAlignment,
+    RequestPriority,
+};
 use crate::fixed_vhd::FixedVhd;
+use crate::lifetime::{DiskLifetime, VIRTIO_BLK_T_GET_LIFETIME};
 use crate::qcow::{QcowFile, RawFile};
+use crate::qed::QedFile;
 use crate::vhdx::{Vhdx, VhdxError};
 #[cfg(feature = "io_uring")]
 use io_uring::{opcode, IoUring, Probe};
-use libc::{ioctl, S_IFBLK, S_IFMT};
+use libc::{ioctl, S_IFBLK, S_IFMT, S_IFREG};
 use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
 use std::alloc::{alloc_zeroed, dealloc, Layout};
@@ -46,9 +85,10 @@ use std::fmt::Debug;
 use std::fs::File;
 use std::io::{self, IoSlice, IoSliceMut, Read, Seek, SeekFrom, Write};
 use std::os::linux::fs::MetadataExt;
-use std::os::unix::io::AsRawFd;
-use std::path::Path;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
 use std::result;
+use std::str::FromStr;
 use std::sync::Arc;
 use std::sync::MutexGuard;
 use std::time::Instant;
@@ -62,11 +102,15 @@ use vm_memory::{
 use vm_virtio::{AccessPlatform, Translatable};
 use vmm_sys_util::aio;
 use vmm_sys_util::eventfd::EventFd;
-use vmm_sys_util::{ioctl_io_nr, ioctl_ioc_nr};
+use vmm_sys_util::seek_hole::SeekHole;
+use vmm_sys_util::{ioctl_io_nr, ioctl_ioc_nr, ioctl_ior_nr};
 
 const SECTOR_SHIFT: u8 = 9;
 pub const SECTOR_SIZE: u64 = 0x01 << SECTOR_SHIFT;
 
+// Flag of a write zeroes segment allowing the range to be deallocated.
+const WRITE_ZEROES_FLAG_UNMAP: u32 = 1 << 0;
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Guest gave us bad memory addresses")]
@@ -91,10 +135,14 @@ pub enum Error {
     InvalidOffset,
     #[error("Failure in qcow: {0}")]
     QcowError(qcow::Error),
+    #[error("Failure in qed: {0}")]
+    QedError(qed::Error),
     #[error("Failure in raw file: {0}")]
     RawFileError(std::io::Error),
     #[error("The requested operation does not support multiple descriptors")]
     TooManyDescriptors,
+    #[error("The request isn't aligned as required by the disk")]
+    UnalignedRequest,
     #[error("Failure in vhdx: {0}")]
     VhdxError(VhdxError),
 }
@@ -115,20 +163,128 @@ fn build_device_id(disk_path: &Path) -> result::Result<String, Error> {
 }
 
 pub fn build_serial(disk_path: &Path) -> Vec<u8> {
-    let mut default_serial = vec![0; VIRTIO_BLK_ID_BYTES as usize];
     match build_device_id(disk_path) {
         Err(_) => {
             warn!("Could not generate device id. We'll use a default.");
+            vec![0; VIRTIO_BLK_ID_BYTES as usize]
         }
-        Ok(m) => {
-            // The kernel only knows to read a maximum of VIRTIO_BLK_ID_BYTES.
-            // This will also zero out any leftover bytes.
-            let disk_id = m.as_bytes();
-            let bytes_to_copy = cmp::min(disk_id.len(), VIRTIO_BLK_ID_BYTES as usize);
-            default_serial[..bytes_to_copy].clone_from_slice(&disk_id[..bytes_to_copy])
-        }
+        Ok(m) => serial_bytes(&m),
+    }
+}
+
+/// Returns `serial` as reported to the guest, truncated or padded with
+/// zeroes to VIRTIO_BLK_ID_BYTES.
+pub fn serial_bytes(serial: &str) -> Vec<u8> {
+    let mut bytes = vec![0; VIRTIO_BLK_ID_BYTES as usize];
+    // The kernel only knows to read a maximum of VIRTIO_BLK_ID_BYTES.
+    let serial = serial.as_bytes();
+    let bytes_to_copy = cmp::min(serial.len(), bytes.len());
+    bytes[..bytes_to_copy].copy_from_slice(&serial[..bytes_to_copy]);
+    bytes
+}
+
+// Returns the sysfs directory of the block device `f` is opened on, if any.
+fn block_device_sysfs_dir(f: &File) -> Option<String> {
+    let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+    // SAFETY: FFI call with a valid fd and buffer
+    let ret = unsafe { libc::fstat(f.as_raw_fd(), stat.as_mut_ptr()) };
+    if ret != 0 {
+        return None;
+    }
+    // SAFETY: stat is valid at this point
+    let stat = unsafe { stat.assume_init() };
+    if stat.st_mode & S_IFMT != S_IFBLK {
+        return None;
     }
-    default_serial
+
+    // SAFETY: major() and minor() only split the device number
+    let (major, minor) = unsafe { (libc::major(stat.st_rdev), libc::minor(stat.st_rdev)) };
+    Some(format!("/sys/dev/block/{major}:{minor}"))
+}
+
+/// Returns the identifier of the block device `f` was opened from, as
+/// exposed by its driver through sysfs, preferring the World Wide Name over
+/// the serial number.
+pub(crate) fn block_device_serial(f: &File) -> Option<String> {
+    let sysfs_dir = block_device_sysfs_dir(f)?;
+    ["wwid", "device/wwid", "device/serial", "serial"]
+        .iter()
+        .filter_map(|attr| std::fs::read_to_string(format!("{sysfs_dir}/{attr}")).ok())
+        .map(|id| id.trim().to_string())
+        .find(|id| !id.is_empty())
+}
+
+/// Returns whether the block device `f` is opened on is rotational, the
+/// queue attributes of a partition being the ones of the whole disk.
+pub(crate) fn block_device_rotational(f: &File) -> Option<bool> {
+    let sysfs_dir = block_device_sysfs_dir(f)?;
+    ["queue/rotational", "../queue/rotational"]
+        .iter()
+        .filter_map(|attr| std::fs::read_to_string(format!("{sysfs_dir}/{attr}")).ok())
+        .find_map(|value| match value.trim() {
+            "0" => Some(false),
+            "1" => Some(true),
+            _ => None,
+        })
+}
+
+/// Returns the bytes the filesystem holding the disk file `f` can still
+/// allocate to unprivileged users. Block devices, which can't grow, have
+/// none to report.
+pub(crate) fn filesystem_available_space(f: &File) -> io::Result<Option<u64>> {
+    if block_device_sysfs_dir(f).is_some() {
+        return Ok(None);
+    }
+
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: FFI call with a valid fd and buffer
+    let ret = unsafe { libc::fstatvfs(f.as_raw_fd(), stat.as_mut_ptr()) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: stat is valid at this point
+    let stat = unsafe { stat.assume_init() };
+
+    Ok(Some(stat.f_bavail * stat.f_frsize))
+}
+
+/// Returns the size of the disk file `f`, or the capacity of the block device
+/// it is opened on, which `fstat()` doesn't report. Unlike seeking to its
+/// end, this leaves alone the offset of the file, which every handle
+/// duplicated from it shares.
+pub(crate) fn disk_file_size(f: &File) -> io::Result<u64> {
+    let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+    // SAFETY: FFI call with a valid fd and buffer
+    let ret = unsafe { libc::fstat(f.as_raw_fd(), stat.as_mut_ptr()) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: stat is valid at this point
+    let stat = unsafe { stat.assume_init() };
+    if stat.st_mode & S_IFMT != S_IFBLK {
+        return Ok(stat.st_size as u64);
+    }
+
+    let mut size: u64 = 0;
+    // SAFETY: FFI call with a valid fd and a buffer as large as the one the
+    // ioctl fills
+    let ret = unsafe { ioctl(f.as_raw_fd(), BLKGETSIZE64() as _, &mut size) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(size)
+}
+
+/// Returns the value of the queue limit `attr` in bytes of the block device
+/// `f` is opened on, such as `discard_max_bytes`, the limits of a partition
+/// being the ones of the whole disk.
+pub(crate) fn block_device_queue_limit(f: &File, attr: &str) -> Option<u64> {
+    let sysfs_dir = block_device_sysfs_dir(f)?;
+    [format!("queue/{attr}"), format!("../queue/{attr}")]
+        .iter()
+        .filter_map(|attr| std::fs::read_to_string(format!("{sysfs_dir}/{attr}")).ok())
+        .find_map(|value| value.trim().parse().ok())
 }
 
 #[derive(Error, Debug)]
@@ -159,6 +315,10 @@ pub enum ExecuteError {
     AsyncWrite(AsyncIoError),
     #[error("failed to async flush: {0}")]
     AsyncFlush(AsyncIoError),
+    #[error("Failed to async discard: {0}")]
+    AsyncDiscard(AsyncIoError),
+    #[error("Failed to async write zeroes: {0}")]
+    AsyncWriteZeroes(AsyncIoError),
     #[error("Failed allocating a temporary buffer: {0}")]
     TemporaryBufferAllocation(io::Error),
 }
@@ -179,20 +339,68 @@ impl ExecuteError {
             ExecuteError::AsyncRead(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::AsyncWrite(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::AsyncFlush(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::AsyncDiscard(AsyncIoError::DiscardNotSupported) => VIRTIO_BLK_S_UNSUPP,
+            ExecuteError::AsyncDiscard(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::AsyncWriteZeroes(AsyncIoError::WriteZeroesNotSupported) => {
+                VIRTIO_BLK_S_UNSUPP
+            }
+            ExecuteError::AsyncWriteZeroes(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::TemporaryBufferAllocation(_) => VIRTIO_BLK_S_IOERR,
         }
     }
 }
 
+/// Translates the result of an asynchronous request, a negative errno on
+/// failure, into the status reported to the guest.
+pub fn completion_status(result: i32) -> u32 {
+    if result >= 0 {
+        return VIRTIO_BLK_S_OK;
+    }
+
+    match -result {
+        libc::EOPNOTSUPP | libc::ENOTTY => VIRTIO_BLK_S_UNSUPP,
+        _ => VIRTIO_BLK_S_IOERR,
+    }
+}
+
+/// Completion result of a synchronous request failing with `e`, which lets
+/// the failure reach the guest like the asynchronous backends do.
+pub fn error_result(e: &io::Error) -> i32 {
+    -e.raw_os_error().unwrap_or(libc::EIO)
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum RequestType {
     In,
     Out,
     Flush,
     GetDeviceId,
+    GetLifetime,
+    Discard,
+    WriteZeroes,
     Unsupported(u32),
 }
 
+impl RequestType {
+    /// Whether the request modifies the content of the disk.
+    pub fn modifies_disk(&self) -> bool {
+        matches!(
+            self,
+            RequestType::Out | RequestType::Discard | RequestType::WriteZeroes
+        )
+    }
+
+    /// Priority the request is submitted with. Flushes are served ahead of
+    /// the data transfers, which they don't wait for anyway: a flush only
+    /// covers the writes already completed.
+    pub fn priority(&self) -> RequestPriority {
+        match self {
+            RequestType::Flush => RequestPriority::High,
+            _ => RequestPriority::Normal,
+        }
+    }
+}
+
 pub fn request_type<B: Bitmap + 'static>(
     mem: &vm_memory::GuestMemoryMmap<B>,
     desc_addr: GuestAddress,
@@ -203,6 +411,9 @@ pub fn request_type<B: Bitmap + 'static>(
         VIRTIO_BLK_T_OUT => Ok(RequestType::Out),
         VIRTIO_BLK_T_FLUSH => Ok(RequestType::Flush),
         VIRTIO_BLK_T_GET_ID => Ok(RequestType::GetDeviceId),
+        VIRTIO_BLK_T_GET_LIFETIME => Ok(RequestType::GetLifetime),
+        VIRTIO_BLK_T_DISCARD => Ok(RequestType::Discard),
+        VIRTIO_BLK_T_WRITE_ZEROES => Ok(RequestType::WriteZeroes),
         t => Ok(RequestType::Unsupported(t)),
     }
 }
@@ -220,11 +431,24 @@ fn sector<B: Bitmap + 'static>(
     mem.read_obj(addr).map_err(Error::GuestMemory)
 }
 
+/// Segment describing the range of a discard or write zeroes request.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct DiscardWriteZeroesSegment {
+    pub sector: u64,
+    pub num_sectors: u32,
+    pub flags: u32,
+}
+
+// SAFETY: data structure only contain a series of integers
+unsafe impl ByteValued for DiscardWriteZeroesSegment {}
+
+// Aligned buffer standing for the guest buffers `origins`, the data being
+// laid out in it one buffer after the other.
 #[derive(Debug)]
 pub struct AlignedOperation {
-    origin_ptr: u64,
+    origins: SmallVec<[(u64, usize); 1]>,
     aligned_ptr: u64,
-    size: usize,
     layout: Layout,
 }
 
@@ -290,13 +514,20 @@ impl Request {
         } else {
             req.data_descriptors.reserve_exact(1);
             while desc.has_next() {
-                if desc.is_write_only() && req.request_type == RequestType::Out {
+                if desc.is_write_only()
+                    && (req.request_type == RequestType::Out
+                        || req.request_type == RequestType::Discard
+                        || req.request_type == RequestType::WriteZeroes)
+                {
                     return Err(Error::UnexpectedWriteOnlyDescriptor);
                 }
                 if !desc.is_write_only() && req.request_type == RequestType::In {
                     return Err(Error::UnexpectedReadOnlyDescriptor);
                 }
-                if !desc.is_write_only() && req.request_type == RequestType::GetDeviceId {
+                if !desc.is_write_only()
+                    && (req.request_type == RequestType::GetDeviceId
+                        || req.request_type == RequestType::GetLifetime)
+                {
                     return Err(Error::UnexpectedReadOnlyDescriptor);
                 }
 
@@ -383,111 +614,135 @@ impl Request {
                     mem.write_slice(serial, *data_addr)
                         .map_err(ExecuteError::Write)?;
                 }
+                RequestType::GetLifetime => {
+                    return Err(ExecuteError::Unsupported(VIRTIO_BLK_T_GET_LIFETIME))
+                }
+                RequestType::Discard => {
+                    return Err(ExecuteError::Unsupported(VIRTIO_BLK_T_DISCARD))
+                }
+                RequestType::WriteZeroes => {
+                    return Err(ExecuteError::Unsupported(VIRTIO_BLK_T_WRITE_ZEROES))
+                }
                 RequestType::Unsupported(t) => return Err(ExecuteError::Unsupported(t)),
             };
         }
         Ok(len)
     }
 
+    /// Reads the segment of a discard or write zeroes request, checking it
+    /// fits within the disk.
+    pub fn discard_write_zeroes_segment<B: Bitmap + 'static>(
+        &self,
+        mem: &vm_memory::GuestMemoryMmap<B>,
+        disk_nsectors: u64,
+    ) -> result::Result<DiscardWriteZeroesSegment, ExecuteError> {
+        // Only a single segment per request is advertised to the guest.
+        let (data_addr, data_len) = if self.data_descriptors.len() == 1 {
+            (self.data_descriptors[0].0, self.data_descriptors[0].1)
+        } else {
+            return Err(ExecuteError::BadRequest(Error::TooManyDescriptors));
+        };
+        if (data_len as usize) < std::mem::size_of::<DiscardWriteZeroesSegment>() {
+            return Err(ExecuteError::BadRequest(Error::DescriptorLengthTooSmall));
+        }
+        if (data_len as usize) > std::mem::size_of::<DiscardWriteZeroesSegment>() {
+            return Err(ExecuteError::BadRequest(Error::TooManyDescriptors));
+        }
+
+        let segment: DiscardWriteZeroesSegment =
+            mem.read_obj(data_addr).map_err(ExecuteError::Read)?;
+        let top = segment
+            .sector
+            .checked_add(u64::from(segment.num_sectors))
+            .ok_or(ExecuteError::BadRequest(Error::InvalidOffset))?;
+        if top > disk_nsectors {
+            return Err(ExecuteError::BadRequest(Error::InvalidOffset));
+        }
+
+        Ok(segment)
+    }
+
     pub fn execute_async<B: Bitmap + 'static>(
         &mut self,
         mem: &vm_memory::GuestMemoryMmap<B>,
         disk_nsectors: u64,
         disk_image: &mut dyn AsyncIo,
         serial: &[u8],
+        lifetime: Option<&DiskLifetime>,
         user_data: u64,
     ) -> result::Result<bool, ExecuteError> {
         let sector = self.sector;
         let request_type = self.request_type;
         let offset = (sector << SECTOR_SHIFT) as libc::off_t;
 
-        let mut iovecs: SmallVec<[libc::iovec; 1]> =
-            SmallVec::with_capacity(self.data_descriptors.len());
-        for (data_addr, data_len) in &self.data_descriptors {
-            if *data_len == 0 {
-                continue;
-            }
-            let mut top: u64 = u64::from(*data_len) / SECTOR_SIZE;
-            if u64::from(*data_len) % SECTOR_SIZE != 0 {
-                top += 1;
-            }
-            top = top
-                .checked_add(sector)
-                .ok_or(ExecuteError::BadRequest(Error::InvalidOffset))?;
-            if top > disk_nsectors {
-                return Err(ExecuteError::BadRequest(Error::InvalidOffset));
+        // The data descriptor of discard and write zeroes requests only
+        // describes the range to operate on, there is no buffer to hand over
+        // to the backend.
+        if request_type == RequestType::Discard || request_type == RequestType::WriteZeroes {
+            let segment = self.discard_write_zeroes_segment(mem, disk_nsectors)?;
+            let offset = (segment.sector << SECTOR_SHIFT) as libc::off_t;
+            let length = u64::from(segment.num_sectors) << SECTOR_SHIFT;
+            if request_type == RequestType::Discard {
+                disk_image
+                    .discard(offset, length, user_data)
+                    .map_err(ExecuteError::AsyncDiscard)?;
+            } else {
+                disk_image
+                    .write_zeroes(
+                        offset,
+                        length,
+                        segment.flags & WRITE_ZEROES_FLAG_UNMAP != 0,
+                        user_data,
+                    )
+                    .map_err(ExecuteError::AsyncWriteZeroes)?;
             }
+            return Ok(true);
+        }
 
-            let origin_ptr = mem
-                .get_slice(*data_addr, *data_len as usize)
-                .map_err(ExecuteError::GetHostAddress)?
-                .ptr_guard();
-
-            // Verify the buffer alignment.
-            // In case it's not properly aligned, an intermediate buffer is
-            // created with the correct alignment, and a copy from/to the
-            // origin buffer is performed, depending on the type of operation.
-            let iov_base = if (origin_ptr.as_ptr() as u64) % SECTOR_SIZE != 0 {
-                let layout =
-                    Layout::from_size_align(*data_len as usize, SECTOR_SIZE as usize).unwrap();
-                // SAFETY: layout has non-zero size
-                let aligned_ptr = unsafe { alloc_zeroed(layout) };
-                if aligned_ptr.is_null() {
-                    return Err(ExecuteError::TemporaryBufferAllocation(
-                        io::Error::last_os_error(),
-                    ));
-                }
-
-                // We need to perform the copy beforehand in case we're writing
-                // data out.
-                if request_type == RequestType::Out {
-                    // SAFETY: destination buffer has been allocated with
-                    // the proper size.
-                    unsafe { std::ptr::copy(origin_ptr.as_ptr(), aligned_ptr, *data_len as usize) };
-                }
-
-                // Store both origin and aligned pointers for complete_async()
-                // to process them.
-                self.aligned_operations.push(AlignedOperation {
-                    origin_ptr: origin_ptr.as_ptr() as u64,
-                    aligned_ptr: aligned_ptr as u64,
-                    size: *data_len as usize,
-                    layout,
-                });
-
-                aligned_ptr as *mut libc::c_void
+        // The lifetime is only served once the guest has acknowledged
+        // VIRTIO_BLK_F_LIFETIME.
+        if request_type == RequestType::GetLifetime {
+            let lifetime = lifetime.ok_or(ExecuteError::Unsupported(VIRTIO_BLK_T_GET_LIFETIME))?;
+            let (data_addr, data_len) = if self.data_descriptors.len() == 1 {
+                (self.data_descriptors[0].0, self.data_descriptors[0].1)
             } else {
-                origin_ptr.as_ptr() as *mut libc::c_void
-            };
-
-            let iovec = libc::iovec {
-                iov_base,
-                iov_len: *data_len as libc::size_t,
+                return Err(ExecuteError::BadRequest(Error::TooManyDescriptors));
             };
-            iovecs.push(iovec);
+            if (data_len as usize) < std::mem::size_of::<DiskLifetime>() {
+                return Err(ExecuteError::BadRequest(Error::DescriptorLengthTooSmall));
+            }
+            mem.write_obj(*lifetime, data_addr)
+                .map_err(ExecuteError::Write)?;
+            return Ok(false);
         }
 
+        let iovecs = self.data_iovecs(mem, disk_nsectors, disk_image.required_alignment())?;
+
         // Queue operations expected to be submitted.
         match request_type {
             RequestType::In => {
-                for (data_addr, data_len) in &self.data_descriptors {
-                    mem.get_slice(*data_addr, *data_len as usize)
-                        .map_err(ExecuteError::GetHostAddress)?
-                        .bitmap()
-                        .mark_dirty(0, *data_len as usize);
-                }
                 disk_image
-                    .read_vectored(offset, &iovecs, user_data)
+                    .read_vectored_with_priority(
+                        offset,
+                        &iovecs,
+                        user_data,
+                        request_type.priority(),
+                    )
                     .map_err(ExecuteError::AsyncRead)?;
             }
             RequestType::Out => {
                 disk_image
-                    .write_vectored(offset, &iovecs, user_data)
+                    .write_vectored_with_priority(
+                        offset,
+                        &iovecs,
+                        user_data,
+                        request_type.priority(),
+                    )
                     .map_err(ExecuteError::AsyncWrite)?;
             }
             RequestType::Flush => {
                 disk_image
-                    .fsync(Some(user_data))
+                    .fsync_with_priority(Some(user_data), request_type.priority())
                     .map_err(ExecuteError::AsyncFlush)?;
             }
             RequestType::GetDeviceId => {
@@ -503,26 +758,190 @@ impl Request {
                     .map_err(ExecuteError::Write)?;
                 return Ok(false);
             }
+            RequestType::Discard | RequestType::WriteZeroes => {
+                unreachable!("Discard and write zeroes requests are submitted above")
+            }
+            RequestType::GetLifetime => {
+                unreachable!("Lifetime requests are served above")
+            }
             RequestType::Unsupported(t) => return Err(ExecuteError::Unsupported(t)),
         }
 
         Ok(true)
     }
 
+    /// Whether the range of a read or write request is aligned as `alignment`
+    /// requires, the other requests having no range to align.
+    pub fn is_aligned(&self, alignment: IoAlignment) -> bool {
+        if self.request_type != RequestType::In && self.request_type != RequestType::Out {
+            return true;
+        }
+
+        let length: u64 = self
+            .data_descriptors
+            .iter()
+            .map(|(_, data_len)| u64::from(*data_len))
+            .sum();
+        (self.sector << SECTOR_SHIFT) % alignment.offset == 0 && length % alignment.offset == 0
+    }
+
+    /// Returns the buffers of a read or write request, as handed over to a
+    /// backend requiring `alignment`. Buffers not aligned as it requires are
+    /// replaced with aligned ones, released by complete_async(), while
+    /// requests whose range isn't aligned are refused.
+    pub fn data_iovecs<B: Bitmap + 'static>(
+        &mut self,
+        mem: &vm_memory::GuestMemoryMmap<B>,
+        disk_nsectors: u64,
+        alignment: IoAlignment,
+    ) -> result::Result<SmallVec<[libc::iovec; 1]>, ExecuteError> {
+        let sector = self.sector;
+        if !self.is_aligned(alignment) {
+            return Err(ExecuteError::BadRequest(Error::UnalignedRequest));
+        }
+        let mut buffers: SmallVec<[(u64, usize); 1]> =
+            SmallVec::with_capacity(self.data_descriptors.len());
+        for (data_addr, data_len) in &self.data_descriptors {
+            if *data_len == 0 {
+                continue;
+            }
+            let mut top: u64 = u64::from(*data_len) / SECTOR_SIZE;
+            if u64::from(*data_len) % SECTOR_SIZE != 0 {
+                top += 1;
+            }
+            top = top
+                .checked_add(sector)
+                .ok_or(ExecuteError::BadRequest(Error::InvalidOffset))?;
+            if top > disk_nsectors {
+                return Err(ExecuteError::BadRequest(Error::InvalidOffset));
+            }
+
+            let origin_ptr = mem
+                .get_slice(*data_addr, *data_len as usize)
+                .map_err(ExecuteError::GetHostAddress)?
+                .ptr_guard();
+            buffers.push((origin_ptr.as_ptr() as u64, *data_len as usize));
+        }
+
+        // A buffer whose length isn't aligned can't be fixed up on its own,
+        // the whole request goes through a single aligned buffer instead.
+        if buffers.len() > 1
+            && buffers
+                .iter()
+                .any(|(_, len)| *len as u64 % alignment.memory != 0)
+        {
+            let iovec = self.bounce(buffers, alignment)?;
+            return self.mark_dirty(mem, smallvec::smallvec![iovec]);
+        }
+
+        let mut iovecs: SmallVec<[libc::iovec; 1]> = SmallVec::with_capacity(buffers.len());
+        for (origin_ptr, len) in buffers {
+            // Verify the buffer alignment.
+            // In case it's not properly aligned, an intermediate buffer is
+            // created with the correct alignment, and a copy from/to the
+            // origin buffer is performed, depending on the type of operation.
+            let iovec = if origin_ptr % alignment.memory != 0 {
+                self.bounce(smallvec::smallvec![(origin_ptr, len)], alignment)?
+            } else {
+                libc::iovec {
+                    iov_base: origin_ptr as *mut libc::c_void,
+                    iov_len: len as libc::size_t,
+                }
+            };
+            iovecs.push(iovec);
+        }
+
+        self.mark_dirty(mem, iovecs)
+    }
+
+    // Returns an aligned buffer standing for the guest buffers `origins`,
+    // holding their data already for a write.
+    fn bounce(
+        &mut self,
+        origins: SmallVec<[(u64, usize); 1]>,
+        alignment: IoAlignment,
+    ) -> result::Result<libc::iovec, ExecuteError> {
+        let size: usize = origins.iter().map(|(_, len)| len).sum();
+        let layout = Layout::from_size_align(size, alignment.memory as usize).unwrap();
+        // SAFETY: layout has non-zero size
+        let aligned_ptr = unsafe { alloc_zeroed(layout) };
+        if aligned_ptr.is_null() {
+            return Err(ExecuteError::TemporaryBufferAllocation(
+                io::Error::last_os_error(),
+            ));
+        }
+
+        // We need to perform the copy beforehand in case we're writing
+        // data out.
+        if self.request_type == RequestType::Out {
+            let mut offset = 0;
+            for (origin_ptr, len) in &origins {
+                // SAFETY: destination buffer has been allocated with the
+                // size of all the origin buffers.
+                unsafe { std::ptr::copy(*origin_ptr as *const u8, aligned_ptr.add(offset), *len) };
+                offset += len;
+            }
+        }
+
+        // Store both origin and aligned pointers for complete_async()
+        // to process them.
+        self.aligned_operations.push(AlignedOperation {
+            origins,
+            aligned_ptr: aligned_ptr as u64,
+            layout,
+        });
+
+        Ok(libc::iovec {
+            iov_base: aligned_ptr as *mut libc::c_void,
+            iov_len: size as libc::size_t,
+        })
+    }
+
+    // Marks the guest buffers of a read as dirty, returning `iovecs`.
+    fn mark_dirty<B: Bitmap + 'static>(
+        &self,
+        mem: &vm_memory::GuestMemoryMmap<B>,
+        iovecs: SmallVec<[libc::iovec; 1]>,
+    ) -> result::Result<SmallVec<[libc::iovec; 1]>, ExecuteError> {
+        if self.request_type == RequestType::In {
+            for (data_addr, data_len) in &self.data_descriptors {
+                mem.get_slice(*data_addr, *data_len as usize)
+                    .map_err(ExecuteError::GetHostAddress)?
+                    .bitmap()
+                    .mark_dirty(0, *data_len as usize);
+            }
+        }
+
+        Ok(iovecs)
+    }
+
+    /// Bytes of the request copied through aligned buffers by
+    /// data_iovecs(), until complete_async() releases them.
+    pub fn bounced_bytes(&self) -> u64 {
+        self.aligned_operations
+            .iter()
+            .map(|aligned_operation| aligned_operation.layout.size() as u64)
+            .sum()
+    }
+
     pub fn complete_async(&mut self) -> result::Result<(), Error> {
         for aligned_operation in self.aligned_operations.drain(..) {
             // We need to perform the copy after the data has been read inside
             // the aligned buffer in case we're reading data in.
             if self.request_type == RequestType::In {
-                // SAFETY: origin buffer has been allocated with the
-                // proper size.
-                unsafe {
-                    std::ptr::copy(
-                        aligned_operation.aligned_ptr as *const u8,
-                        aligned_operation.origin_ptr as *mut u8,
-                        aligned_operation.size,
-                    )
-                };
+                let mut offset = 0;
+                for (origin_ptr, len) in &aligned_operation.origins {
+                    // SAFETY: origin buffer has been allocated with the
+                    // proper size.
+                    unsafe {
+                        std::ptr::copy(
+                            (aligned_operation.aligned_ptr as *const u8).add(offset),
+                            *origin_ptr as *mut u8,
+                            *len,
+                        )
+                    };
+                    offset += len;
+                }
             }
 
             // Free the temporary aligned buffer.
@@ -655,6 +1074,8 @@ where
         eventfd: &EventFd,
         completion_list: &mut VecDeque<(u64, i32)>,
     ) -> AsyncIoResult<()> {
+        check_completion_room(completion_list.len())?;
+
         // Convert libc::iovec into IoSliceMut
         let mut slices: SmallVec<[IoSliceMut; 1]> = SmallVec::with_capacity(iovecs.len());
         for iovec in iovecs.iter() {
@@ -667,16 +1088,13 @@ where
         let result = {
             let mut file = self.file();
 
-            // Move the cursor to the right offset
+            // Move the cursor to the right offset, and read vectored
             file.seek(SeekFrom::Start(offset as u64))
-                .map_err(AsyncIoError::ReadVectored)?;
-
-            // Read vectored
-            file.read_vectored(slices.as_mut_slice())
-                .map_err(AsyncIoError::ReadVectored)?
+                .and_then(|_| file.read_vectored(slices.as_mut_slice()))
+                .map_or_else(|e| error_result(&e), |count| count as i32)
         };
 
-        completion_list.push_back((user_data, result as i32));
+        completion_list.push_back((user_data, result));
         eventfd.write(1).unwrap();
 
         Ok(())
@@ -690,6 +1108,8 @@ where
         eventfd: &EventFd,
         completion_list: &mut VecDeque<(u64, i32)>,
     ) -> AsyncIoResult<()> {
+        check_completion_room(completion_list.len())?;
+
         // Convert libc::iovec into IoSlice
         let mut slices: SmallVec<[IoSlice; 1]> = SmallVec::with_capacity(iovecs.len());
         for iovec in iovecs.iter() {
@@ -702,16 +1122,13 @@ where
         let result = {
             let mut file = self.file();
 
-            // Move the cursor to the right offset
+            // Move the cursor to the right offset, and write vectored
             file.seek(SeekFrom::Start(offset as u64))
-                .map_err(AsyncIoError::WriteVectored)?;
-
-            // Write vectored
-            file.write_vectored(slices.as_slice())
-                .map_err(AsyncIoError::WriteVectored)?
+                .and_then(|_| file.write_vectored(slices.as_slice()))
+                .map_or_else(|e| error_result(&e), |count| count as i32)
         };
 
-        completion_list.push_back((user_data, result as i32));
+        completion_list.push_back((user_data, result));
         eventfd.write(1).unwrap();
 
         Ok(())
@@ -723,19 +1140,19 @@ where
         eventfd: &EventFd,
         completion_list: &mut VecDeque<(u64, i32)>,
     ) -> AsyncIoResult<()> {
-        let result: i32 = {
-            let mut file = self.file();
+        if user_data.is_some() {
+            check_completion_room(completion_list.len())?;
+        }
 
-            // Flush
-            file.flush().map_err(AsyncIoError::Fsync)?;
+        let result = self.file().flush();
 
-            0
+        // Without a request to complete, nobody else can be told about the
+        // failure.
+        let Some(user_data) = user_data else {
+            return result.map_err(AsyncIoError::Fsync);
         };
-
-        if let Some(user_data) = user_data {
-            completion_list.push_back((user_data, result));
-            eventfd.write(1).unwrap();
-        }
+        completion_list.push_back((user_data, result.map_or_else(|e| error_result(&e), |_| 0)));
+        eventfd.write(1).unwrap();
 
         Ok(())
     }
@@ -743,13 +1160,177 @@ where
     fn file(&mut self) -> MutexGuard<F>;
 }
 
+/// Caching policy of a disk, defining how the host page cache is used and
+/// when the written data is persisted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum CacheMode {
+    /// Writes go through the host page cache and the flushes requested by
+    /// the guest are ignored, the data being persisted whenever the host
+    /// writes the cache back. Only suitable for disposable disks.
+    None,
+    /// Writes go through the host page cache, and are persisted by the
+    /// flushes requested by the guest.
+    #[default]
+    Writeback,
+    /// Writes go through the host page cache, and each of them is followed
+    /// by a data sync of the file before completing.
+    Writethrough,
+    /// The file is opened with O_DIRECT | O_DSYNC, each write bypassing the
+    /// host page cache and reaching the storage before completing. Requests
+    /// must be aligned on the logical block size.
+    DirectSync,
+}
+
+impl CacheMode {
+    /// Flags the disk file must be opened with.
+    pub fn open_flags(&self) -> libc::c_int {
+        match self {
+            CacheMode::DirectSync => libc::O_DIRECT | libc::O_DSYNC,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseCacheModeError {
+    InvalidValue(String),
+}
+
+impl FromStr for CacheMode {
+    type Err = ParseCacheModeError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(CacheMode::None),
+            "writeback" => Ok(CacheMode::Writeback),
+            "writethrough" => Ok(CacheMode::Writethrough),
+            "directsync" => Ok(CacheMode::DirectSync),
+            _ => Err(ParseCacheModeError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+/// Backend serving the requests to a disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum AsyncIoBackend {
+    /// The first backend supported by the host and the image, by order of
+    /// preference io_uring, AIO and then the synchronous one.
+    #[default]
+    Auto,
+    /// Requests are submitted to an io_uring.
+    IoUring,
+    /// Requests are submitted through Linux native AIO.
+    Aio,
+    /// Requests are completed synchronously as they are submitted.
+    Sync,
+}
+
+#[derive(Debug)]
+pub enum ParseAsyncIoBackendError {
+    InvalidValue(String),
+}
+
+impl FromStr for AsyncIoBackend {
+    type Err = ParseAsyncIoBackendError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(AsyncIoBackend::Auto),
+            "io_uring" => Ok(AsyncIoBackend::IoUring),
+            "aio" => Ok(AsyncIoBackend::Aio),
+            "sync" => Ok(AsyncIoBackend::Sync),
+            _ => Err(ParseAsyncIoBackendError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+/// How the writes failing with the host storage full are handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum EnospcPolicy {
+    /// The writes fail, the guest getting an I/O error.
+    #[default]
+    Report,
+    /// The writes are held, along with the next ones, until the operator
+    /// resumes the disk once room has been made, and then retried. Reads
+    /// go on meanwhile.
+    Pause,
+}
+
+#[derive(Debug)]
+pub enum ParseEnospcPolicyError {
+    InvalidValue(String),
+}
+
+impl FromStr for EnospcPolicy {
+    type Err = ParseEnospcPolicyError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "report" => Ok(EnospcPolicy::Report),
+            "pause" => Ok(EnospcPolicy::Pause),
+            _ => Err(ParseEnospcPolicyError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+/// What is done about the requests in flight past the request timeout.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum TimeoutPolicy {
+    /// The requests are reported, and left to complete.
+    #[default]
+    Report,
+    /// The requests are reported, and canceled if the backend can, the
+    /// guest getting an I/O error.
+    Cancel,
+}
+
+#[derive(Debug)]
+pub enum ParseTimeoutPolicyError {
+    InvalidValue(String),
+}
+
+impl FromStr for TimeoutPolicy {
+    type Err = ParseTimeoutPolicyError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "report" => Ok(TimeoutPolicy::Report),
+            "cancel" => Ok(TimeoutPolicy::Cancel),
+            _ => Err(ParseTimeoutPolicyError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+/// Format of a disk image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub enum ImageType {
     FixedVhd,
     Qcow2,
+    Qed,
     Raw,
     Vhdx,
 }
 
+#[derive(Debug)]
+pub enum ParseImageTypeError {
+    InvalidValue(String),
+}
+
+impl FromStr for ImageType {
+    type Err = ParseImageTypeError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "raw" => Ok(ImageType::Raw),
+            "qcow2" => Ok(ImageType::Qcow2),
+            "qed" => Ok(ImageType::Qed),
+            "vhd" => Ok(ImageType::FixedVhd),
+            "vhdx" => Ok(ImageType::Vhdx),
+            _ => Err(ParseImageTypeError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
 const QCOW_MAGIC: u32 = 0x5146_49fb;
 const VHDX_SIGN: u64 = 0x656C_6966_7864_6876;
 
@@ -777,6 +1358,8 @@ pub fn detect_image_type(f: &mut File) -> std::io::Result<ImageType> {
     // Check 4 first bytes to get the header value and determine the image type
     let image_type = if u32::from_be_bytes(block[0..4].try_into().unwrap()) == QCOW_MAGIC {
         ImageType::Qcow2
+    } else if u32::from_le_bytes(block[0..4].try_into().unwrap()) == qed::QED_MAGIC {
+        ImageType::Qed
     } else if vhd::is_fixed_vhd(f)? {
         ImageType::FixedVhd
     } else if u64::from_le_bytes(block[0..8].try_into().unwrap()) == VHDX_SIGN {
@@ -788,6 +1371,118 @@ pub fn detect_image_type(f: &mut File) -> std::io::Result<ImageType> {
     Ok(image_type)
 }
 
+/// Returns a duplicate of `fd`, a disk opened by another process such as one
+/// handed over through SCM_RIGHTS, sharing its flags. The descriptor must be
+/// a regular file or a block device, opened for writing if `writable`, and
+/// with every flag of `flags` set, as they can't all be changed afterwards.
+pub fn disk_file_from_fd(fd: RawFd, flags: libc::c_int, writable: bool) -> std::io::Result<File> {
+    let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+    // SAFETY: FFI call with a buffer of the right size
+    let ret = unsafe { libc::fstat(fd, stat.as_mut_ptr()) };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // SAFETY: stat is valid at this point
+    let mode = unsafe { stat.assume_init() }.st_mode & S_IFMT;
+    if mode != S_IFREG && mode != S_IFBLK {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "not a regular file or a block device",
+        ));
+    }
+
+    // SAFETY: FFI call, the descriptor being valid as fstat() succeeded
+    let fd_flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if fd_flags < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    if writable && fd_flags & libc::O_ACCMODE == libc::O_RDONLY {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "not opened for writing",
+        ));
+    }
+    if fd_flags & flags != flags {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("missing open flags {:#o}", flags & !fd_flags),
+        ));
+    }
+
+    // SAFETY: FFI call with a valid fd
+    let dup_fd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if dup_fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // SAFETY: dup_fd was just created and isn't owned by anything else
+    Ok(unsafe { File::from_raw_fd(dup_fd) })
+}
+
+// Relative backing file paths are relative to the directory of the image
+// referencing them, not to the current working directory.
+pub(crate) fn resolve_backing_file_path<F: AsRawFd>(image: &F, backing_file_path: &str) -> PathBuf {
+    let path = Path::new(backing_file_path);
+    if path.is_absolute() {
+        return path.to_path_buf();
+    }
+
+    match std::fs::read_link(format!("/proc/self/fd/{}", image.as_raw_fd())) {
+        Ok(image_path) => image_path
+            .parent()
+            .map_or_else(|| path.to_path_buf(), |dir| dir.join(path)),
+        Err(e) => {
+            warn!(
+                "Failed to find the path of the image, resolving backing file {} \
+                 from the current directory: {}",
+                backing_file_path, e
+            );
+            path.to_path_buf()
+        }
+    }
+}
+
+/// Returns the ranges of the first `size` bytes of `file` holding data,
+/// relying on SEEK_DATA and SEEK_HOLE.
+pub fn seek_extents<F: SeekHole>(file: &mut F, size: u64) -> std::io::Result<Vec<DiskExtent>> {
+    let mut extents = Vec::new();
+    let mut offset = 0;
+    while offset < size {
+        let Some(data) = file.seek_data(offset)? else {
+            break;
+        };
+        if data >= size {
+            break;
+        }
+        // There is always an implicit hole at the end of the file.
+        let hole = file.seek_hole(data)?.unwrap_or(size).min(size);
+        extents.push(DiskExtent {
+            offset: data,
+            length: hole - data,
+        });
+        offset = hole;
+    }
+
+    Ok(extents)
+}
+
+/// Sorts `extents`, merging the overlapping and adjacent ones.
+pub fn merge_extents(mut extents: Vec<DiskExtent>) -> Vec<DiskExtent> {
+    extents.sort_by_key(|extent| extent.offset);
+
+    let mut merged: Vec<DiskExtent> = Vec::with_capacity(extents.len());
+    for extent in extents {
+        match merged.last_mut() {
+            Some(last) if last.offset + last.length >= extent.offset => {
+                let end = (last.offset + last.length).max(extent.offset + extent.length);
+                last.length = end - last.offset;
+            }
+            _ => merged.push(extent),
+        }
+    }
+
+    merged
+}
+
 pub trait BlockBackend: Read + Write + Seek + Send + Debug {
     fn size(&self) -> Result<u64, Error>;
 }
@@ -801,6 +1496,10 @@ pub fn create_disk_file(mut file: File, direct_io: bool) -> Result<Box<dyn Block
             Box::new(QcowFile::from(RawFile::new(file, direct_io)).map_err(Error::QcowError)?)
                 as Box<dyn BlockBackend>
         }
+        ImageType::Qed => {
+            Box::new(QedFile::from(RawFile::new(file, direct_io)).map_err(Error::QedError)?)
+                as Box<dyn BlockBackend>
+        }
         ImageType::FixedVhd => {
             Box::new(FixedVhd::new(file).map_err(Error::FixedVhdError)?) as Box<dyn BlockBackend>
         }
@@ -811,12 +1510,23 @@ pub fn create_disk_file(mut file: File, direct_io: bool) -> Result<Box<dyn Block
     })
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct DiskTopology {
     pub logical_block_size: u64,
     pub physical_block_size: u64,
     pub minimum_io_size: u64,
     pub optimal_io_size: u64,
+    /// Size of the zones in bytes, 0 if the device is not zoned.
+    pub zone_size: u64,
+    pub nr_zones: u32,
+    /// Largest request in bytes accepted by the storage, 0 if unlimited.
+    pub max_transfer_size: u64,
+    /// Largest number of buffers a request can be made of.
+    pub max_segments: u32,
+    /// Largest discard in bytes handed over to the backend at once.
+    pub max_discard_size: u64,
+    /// Largest write zeroes in bytes handed over to the backend at once.
+    pub max_write_zeroes_size: u64,
 }
 
 impl Default for DiskTopology {
@@ -826,6 +1536,12 @@ impl Default for DiskTopology {
             physical_block_size: 512,
             minimum_io_size: 512,
             optimal_io_size: 0,
+            zone_size: 0,
+            nr_zones: 0,
+            max_transfer_size: 0,
+            max_segments: UIO_MAXIOV,
+            max_discard_size: MAX_ZEROING_SIZE,
+            max_write_zeroes_size: MAX_ZEROING_SIZE,
         }
     }
 }
@@ -834,6 +1550,17 @@ ioctl_io_nr!(BLKSSZGET, 0x12, 104);
 ioctl_io_nr!(BLKPBSZGET, 0x12, 123);
 ioctl_io_nr!(BLKIOMIN, 0x12, 120);
 ioctl_io_nr!(BLKIOOPT, 0x12, 121);
+ioctl_io_nr!(BLKSECTGET, 0x12, 103);
+ioctl_ior_nr!(BLKGETSIZE64, 0x12, 114, u64);
+
+// Number of iovecs a single preadv() or pwritev() accepts, which every
+// backend hands the buffers of a request over with.
+const UIO_MAXIOV: u32 = 1024;
+
+// Largest range discarded or zeroed by a single request. fallocate() has no
+// limit of its own, but the larger the range the longer it blocks the
+// backend, and the requests queued behind it.
+const MAX_ZEROING_SIZE: u64 = 1 << 30;
 
 enum BlockSize {
     LogicalBlock,
@@ -879,16 +1606,382 @@ impl DiskTopology {
         Ok(block_size)
     }
 
+    // Maximum number of sectors of a request, which BLKSECTGET reports as an
+    // unsigned short.
+    fn query_max_sectors(f: &File) -> std::io::Result<u64> {
+        let mut max_sectors: libc::c_ushort = 0;
+        // SAFETY: FFI call with correct arguments
+        let ret = unsafe { ioctl(f.as_raw_fd(), BLKSECTGET() as _, &mut max_sectors) };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error());
+        };
+
+        Ok(u64::from(max_sectors))
+    }
+
+    // The device may take less than the default limit at once, 0 meaning
+    // it doesn't support the operation, which is then emulated by the
+    // kernel.
+    fn zeroing_limit(f: &File, attr: &str) -> u64 {
+        match block_device_queue_limit(f, attr) {
+            Some(limit) if limit != 0 => limit.min(MAX_ZEROING_SIZE),
+            _ => MAX_ZEROING_SIZE,
+        }
+    }
+
     pub fn probe(f: &File) -> std::io::Result<Self> {
         if !Self::is_block_device(f)? {
             return Ok(DiskTopology::default());
         }
 
+        // Failing to query zones shouldn't prevent using the device as a
+        // regular one.
+        let zone_sectors = zoned::zone_sectors(f.as_raw_fd()).unwrap_or(0);
+        let nr_zones = if zone_sectors != 0 {
+            zoned::nr_zones(f.as_raw_fd()).unwrap_or(0)
+        } else {
+            0
+        };
+
+        let logical_block_size = Self::query_block_size(f, BlockSize::LogicalBlock)?;
+
+        // Not every driver provides the I/O hints, fall back on the logical
+        // block size when they are missing.
+        let io_size = |block_size_type| match Self::query_block_size(f, block_size_type) {
+            Ok(size) if size != 0 => size,
+            _ => logical_block_size,
+        };
+
         Ok(DiskTopology {
-            logical_block_size: Self::query_block_size(f, BlockSize::LogicalBlock)?,
+            logical_block_size,
             physical_block_size: Self::query_block_size(f, BlockSize::PhysicalBlock)?,
-            minimum_io_size: Self::query_block_size(f, BlockSize::MinimumIo)?,
-            optimal_io_size: Self::query_block_size(f, BlockSize::OptimalIo)?,
+            minimum_io_size: io_size(BlockSize::MinimumIo),
+            optimal_io_size: io_size(BlockSize::OptimalIo),
+            zone_size: u64::from(zone_sectors) * SECTOR_SIZE,
+            nr_zones,
+            // Without the limit, the host is left to split the requests.
+            max_transfer_size: Self::query_max_sectors(f).unwrap_or(0) * SECTOR_SIZE,
+            max_segments: UIO_MAXIOV,
+            max_discard_size: Self::zeroing_limit(f, "discard_max_bytes"),
+            max_write_zeroes_size: Self::zeroing_limit(f, "write_zeroes_max_bytes"),
         })
     }
+
+    /// Alignment in bytes of the offsets, lengths and buffers of the
+    /// requests to a file opened with O_DIRECT, which is the logical block
+    /// size of a block device. Regular files only report it through statx()
+    /// on Linux 6.1 and later, `None` meaning it can't be found out.
+    pub fn direct_io_alignment(f: &File) -> std::io::Result<Option<u64>> {
+        Ok(Self::direct_io_requirements(f)?
+            .map(|alignment| cmp::max(alignment.offset, alignment.memory)))
+    }
+
+    /// Same as `direct_io_alignment()`, keeping the alignment of the buffers
+    /// apart from the one of the offsets and lengths, which can be smaller.
+    pub fn direct_io_requirements(f: &File) -> std::io::Result<Option<IoAlignment>> {
+        if Self::is_block_device(f)? {
+            let block_size = Self::query_block_size(f, BlockSize::LogicalBlock)?;
+            return Ok(Some(IoAlignment {
+                offset: block_size,
+                memory: block_size,
+            }));
+        }
+
+        let mut stx = std::mem::MaybeUninit::<libc::statx>::zeroed();
+        // SAFETY: FFI call with a valid fd, path and buffer
+        let ret = unsafe {
+            libc::statx(
+                f.as_raw_fd(),
+                c"".as_ptr(),
+                libc::AT_EMPTY_PATH,
+                libc::STATX_DIOALIGN,
+                stx.as_mut_ptr(),
+            )
+        };
+        if ret != 0 {
+            let e = std::io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::ENOSYS) => Ok(None),
+                _ => Err(e),
+            };
+        }
+
+        // SAFETY: stx is valid at this point
+        let stx = unsafe { stx.assume_init() };
+        // Older kernels leave the mask bit cleared, and a zero alignment
+        // means the file doesn't support O_DIRECT at all.
+        if stx.stx_mask & libc::STATX_DIOALIGN == 0 {
+            return Ok(None);
+        }
+        if stx.stx_dio_offset_align == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(IoAlignment {
+            offset: u64::from(stx.stx_dio_offset_align),
+            memory: u64::from(stx.stx_dio_mem_align).max(1),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_io::DiskFile;
+    use std::os::unix::fs::OpenOptionsExt;
+    use vm_memory::GuestMemoryMmap;
+
+    const ALIGNMENT: IoAlignment = IoAlignment {
+        offset: 4096,
+        memory: 4096,
+    };
+
+    fn request(request_type: RequestType, buffers: &[(u64, u32)]) -> Request {
+        Request {
+            request_type,
+            sector: 0,
+            data_descriptors: buffers
+                .iter()
+                .map(|(addr, len)| (GuestAddress(*addr), *len))
+                .collect(),
+            status_addr: GuestAddress(0),
+            writeback: true,
+            aligned_operations: SmallVec::new(),
+            start: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn test_bounce_misaligned_buffers() {
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+
+        // Aligned buffers are handed over as they are.
+        let mut aligned = request(RequestType::Out, &[(0x1000, 4096)]);
+        let iovecs = aligned.data_iovecs(&mem, 128, ALIGNMENT).unwrap();
+        assert_eq!(aligned.bounced_bytes(), 0);
+        assert_eq!(iovecs[0].iov_base as u64 % 4096, 0);
+
+        // A misaligned buffer is copied to an aligned one before a write.
+        mem.write_slice(&[0x5a; 4096], GuestAddress(0x2200))
+            .unwrap();
+        let mut write = request(RequestType::Out, &[(0x2200, 4096)]);
+        let iovecs = write.data_iovecs(&mem, 128, ALIGNMENT).unwrap();
+        assert_eq!(write.bounced_bytes(), 4096);
+        assert_eq!(iovecs[0].iov_base as u64 % 4096, 0);
+        // SAFETY: the iovec points to the 4096 bytes of the aligned buffer
+        let data = unsafe { std::slice::from_raw_parts(iovecs[0].iov_base as *const u8, 4096) };
+        assert!(data.iter().all(|b| *b == 0x5a));
+        write.complete_async().unwrap();
+        assert_eq!(write.bounced_bytes(), 0);
+
+        // Buffers whose lengths aren't aligned share a single aligned
+        // buffer, copied back to them once the read completed.
+        let mut read = request(RequestType::In, &[(0x8000, 512), (0xa000, 3584)]);
+        let iovecs = read.data_iovecs(&mem, 128, ALIGNMENT).unwrap();
+        assert_eq!(iovecs.len(), 1);
+        assert_eq!(iovecs[0].iov_len, 4096);
+        assert_eq!(read.bounced_bytes(), 4096);
+        // SAFETY: the iovec points to the 4096 bytes of the aligned buffer
+        unsafe { std::ptr::write_bytes(iovecs[0].iov_base as *mut u8, 0xa5, 4096) };
+        read.complete_async().unwrap();
+        let mut data = [0u8; 3584];
+        mem.read_slice(&mut data[..512], GuestAddress(0x8000))
+            .unwrap();
+        assert!(data[..512].iter().all(|b| *b == 0xa5));
+        mem.read_slice(&mut data, GuestAddress(0xa000)).unwrap();
+        assert!(data.iter().all(|b| *b == 0xa5));
+    }
+
+    #[test]
+    fn test_completion_status() {
+        assert_eq!(completion_status(0), VIRTIO_BLK_S_OK);
+        assert_eq!(completion_status(4096), VIRTIO_BLK_S_OK);
+        assert_eq!(completion_status(-libc::EOPNOTSUPP), VIRTIO_BLK_S_UNSUPP);
+        assert_eq!(completion_status(-libc::ENOTTY), VIRTIO_BLK_S_UNSUPP);
+        assert_eq!(completion_status(-libc::ENOSPC), VIRTIO_BLK_S_IOERR);
+        assert_eq!(completion_status(-libc::EIO), VIRTIO_BLK_S_IOERR);
+
+        assert_eq!(
+            error_result(&io::Error::from_raw_os_error(libc::ENOSPC)),
+            -libc::ENOSPC
+        );
+        // Errors without an errno are reported as I/O errors.
+        assert_eq!(error_result(&io::Error::other("no errno")), -libc::EIO);
+    }
+
+    #[test]
+    fn test_serial_bytes() {
+        let mut padded = b"serial".to_vec();
+        padded.resize(VIRTIO_BLK_ID_BYTES as usize, 0);
+        assert_eq!(serial_bytes("serial"), padded);
+        assert_eq!(
+            serial_bytes("0123456789abcdefghijklmnop"),
+            b"0123456789abcdefghij"
+        );
+        assert_eq!(serial_bytes(""), [0; VIRTIO_BLK_ID_BYTES as usize]);
+
+        // Only block devices have a serial of their own.
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap();
+        assert_eq!(block_device_serial(file.as_file()), None);
+    }
+
+    #[test]
+    fn test_rotational() {
+        // Files are reported as non-rotational.
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap();
+        assert_eq!(block_device_rotational(file.as_file()), None);
+        let mut disk = raw_sync::RawFileDiskSync::new(
+            file.as_file().try_clone().unwrap(),
+            false,
+            CacheMode::Writeback,
+            None,
+        )
+        .unwrap();
+        assert!(!disk.rotational());
+
+        // Block devices as reported by sysfs, when one can be opened.
+        let Ok(loop_device) = File::open("/dev/loop0") else {
+            return;
+        };
+        let rotational = std::fs::read_to_string("/sys/dev/block/7:0/queue/rotational").unwrap();
+        assert_eq!(
+            block_device_rotational(&loop_device),
+            Some(rotational.trim() == "1")
+        );
+    }
+
+    #[test]
+    fn test_async_io_backend() {
+        assert_eq!(
+            "auto".parse::<AsyncIoBackend>().unwrap(),
+            AsyncIoBackend::Auto
+        );
+        assert_eq!(
+            "io_uring".parse::<AsyncIoBackend>().unwrap(),
+            AsyncIoBackend::IoUring
+        );
+        assert_eq!(
+            "AIO".parse::<AsyncIoBackend>().unwrap(),
+            AsyncIoBackend::Aio
+        );
+        assert_eq!(
+            "sync".parse::<AsyncIoBackend>().unwrap(),
+            AsyncIoBackend::Sync
+        );
+        assert!("uring".parse::<AsyncIoBackend>().is_err());
+
+        // Each disk reports the backend its queues are served by.
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap();
+        let clone = || file.as_file().try_clone().unwrap();
+        let disk =
+            raw_sync::RawFileDiskSync::new(clone(), false, CacheMode::Writeback, None).unwrap();
+        assert_eq!(disk.backend(), AsyncIoBackend::Sync);
+        if block_aio_is_supported() {
+            let disk = raw_async_aio::RawFileDiskAio::new(clone());
+            disk.new_async_io(1).unwrap();
+            assert_eq!(disk.backend(), AsyncIoBackend::Aio);
+        }
+        #[cfg(feature = "io_uring")]
+        if block_io_uring_is_supported() {
+            let disk = raw_async::RawFileDisk::new(clone());
+            disk.new_async_io(1).unwrap();
+            assert_eq!(disk.backend(), AsyncIoBackend::IoUring);
+        }
+    }
+
+    #[test]
+    fn test_required_alignment() {
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+
+        // Only the ranges of reads and writes have to be aligned.
+        let mut read = request(RequestType::In, &[(0x1000, 4096)]);
+        assert!(read.is_aligned(ALIGNMENT));
+        read.sector = 1;
+        assert!(!read.is_aligned(ALIGNMENT));
+        assert!(read.is_aligned(IoAlignment::SECTOR));
+        assert!(matches!(
+            read.data_iovecs(&mem, 128, ALIGNMENT),
+            Err(ExecuteError::BadRequest(Error::UnalignedRequest))
+        ));
+        let write = request(RequestType::Out, &[(0x1000, 512)]);
+        assert!(!write.is_aligned(ALIGNMENT));
+        let mut flush = request(RequestType::Flush, &[]);
+        flush.sector = 1;
+        assert!(flush.is_aligned(ALIGNMENT));
+
+        assert_eq!(
+            IoAlignment::SECTOR.max(IoAlignment {
+                offset: 1,
+                memory: 4096
+            }),
+            IoAlignment {
+                offset: 512,
+                memory: 4096
+            }
+        );
+
+        // Files going through the page cache require no alignment.
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap();
+        assert_eq!(
+            IoAlignment::probe(file.as_file().as_raw_fd()).unwrap(),
+            IoAlignment::NONE
+        );
+        let disk = raw_sync::RawFileDiskSync::new(
+            file.as_file().try_clone().unwrap(),
+            false,
+            CacheMode::Writeback,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            disk.new_async_io(1).unwrap().required_alignment(),
+            IoAlignment::NONE
+        );
+
+        // Whether O_DIRECT is supported depends on the filesystem holding the
+        // test file.
+        let Ok(direct) = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(file.as_path())
+        else {
+            return;
+        };
+        let alignment = IoAlignment::probe(direct.as_raw_fd()).unwrap();
+        assert!(alignment.offset.is_power_of_two() && alignment.offset >= 512);
+        assert!(alignment.memory.is_power_of_two());
+        #[cfg(feature = "io_uring")]
+        if block_io_uring_is_supported() {
+            let disk = raw_async::RawFileDisk::new(direct);
+            assert_eq!(
+                disk.new_async_io(1).unwrap().required_alignment(),
+                alignment
+            );
+        }
+    }
+
+    #[test]
+    fn test_detect_image_type() {
+        let detect = |header: &[u8]| {
+            let file = vmm_sys_util::tempfile::TempFile::new().unwrap();
+            let mut data = vec![0u8; 4096];
+            data[..header.len()].copy_from_slice(header);
+            file.as_file().write_all(&data).unwrap();
+            detect_image_type(&mut File::open(file.as_path()).unwrap()).unwrap()
+        };
+        assert_eq!(detect(&QCOW_MAGIC.to_be_bytes()), ImageType::Qcow2);
+        assert_eq!(detect(b"QED\0"), ImageType::Qed);
+        assert_eq!(detect(b"vhdxfile"), ImageType::Vhdx);
+        // Anything else is a RAW image, whatever the name of the file.
+        assert_eq!(detect(b"conectix"), ImageType::Raw);
+        assert_eq!(detect(&[]), ImageType::Raw);
+
+        assert_eq!("raw".parse::<ImageType>().unwrap(), ImageType::Raw);
+        assert_eq!("QCOW2".parse::<ImageType>().unwrap(), ImageType::Qcow2);
+        assert_eq!("qed".parse::<ImageType>().unwrap(), ImageType::Qed);
+        assert_eq!("vhd".parse::<ImageType>().unwrap(), ImageType::FixedVhd);
+        assert_eq!("vhdx".parse::<ImageType>().unwrap(), ImageType::Vhdx);
+        assert!("vmdk".parse::<ImageType>().is_err());
+    }
 }
@@ -0,0 +1,193 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Wear estimate of the flash storage backing a disk, as reported to the
+//! guest by the VIRTIO_BLK_T_GET_LIFETIME command. It is read from the SMART
+//! log of NVMe devices, and from the life time estimation of eMMC devices.
+
+use crate::block_device_sysfs_dir;
+use libc::ioctl;
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use vm_memory::ByteValued;
+
+// Not part of the virtio bindings yet.
+pub const VIRTIO_BLK_F_LIFETIME: u32 = 15;
+pub const VIRTIO_BLK_T_GET_LIFETIME: u32 = 10;
+
+/// The consumption of the reserved blocks is unknown.
+pub const VIRTIO_BLK_PRE_EOL_INFO_UNDEFINED: u16 = 0;
+/// Less than 80% of the reserved blocks are consumed.
+pub const VIRTIO_BLK_PRE_EOL_INFO_NORMAL: u16 = 1;
+/// 80% of the reserved blocks are consumed.
+pub const VIRTIO_BLK_PRE_EOL_INFO_WARNING: u16 = 2;
+/// 90% of the reserved blocks are consumed.
+pub const VIRTIO_BLK_PRE_EOL_INFO_URGENT: u16 = 3;
+
+/// Lifetime estimate of the storage backing a disk, laid out as `struct
+/// virtio_blk_lifetime`. The default one, all zeroes, tells the guest the
+/// lifetime is unknown.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct DiskLifetime {
+    /// Consumption of the reserved blocks, one of the
+    /// `VIRTIO_BLK_PRE_EOL_INFO_*` values.
+    pub pre_eol_info: u16,
+    /// Wear of the SLC cells, from 0x01 for 0-10% of their lifetime used up
+    /// to 0x0a for 90-100%, 0x0b once exceeded and 0 if unknown.
+    pub device_lifetime_est_typ_a: u16,
+    /// Wear of the MLC cells, in the same steps.
+    pub device_lifetime_est_typ_b: u16,
+}
+
+// SAFETY: data structure only contain a series of integers
+unsafe impl ByteValued for DiskLifetime {}
+
+impl DiskLifetime {
+    /// Translates the SMART log of an NVMe device, which doesn't tell the
+    /// cell types apart.
+    fn from_nvme_smart_log(available_spare: u8, spare_threshold: u8, percentage_used: u8) -> Self {
+        let pre_eol_info = if available_spare <= spare_threshold || available_spare <= 10 {
+            VIRTIO_BLK_PRE_EOL_INFO_URGENT
+        } else if available_spare <= 20 {
+            VIRTIO_BLK_PRE_EOL_INFO_WARNING
+        } else {
+            VIRTIO_BLK_PRE_EOL_INFO_NORMAL
+        };
+        let lifetime_est = u16::from(percentage_used.min(100) / 10) + 1;
+
+        DiskLifetime {
+            pre_eol_info,
+            device_lifetime_est_typ_a: lifetime_est,
+            device_lifetime_est_typ_b: lifetime_est,
+        }
+    }
+}
+
+// See include/uapi/linux/nvme_ioctl.h in the kernel code.
+const NVME_IOCTL_ADMIN_CMD: libc::c_ulong = 0xc048_4e41;
+
+const NVME_ADMIN_GET_LOG_PAGE: u8 = 0x02;
+const NVME_LOG_SMART: u32 = 0x02;
+const NVME_NSID_ALL: u32 = 0xffff_ffff;
+const NVME_SMART_LOG_SIZE: usize = 512;
+
+// Argument of NVME_IOCTL_ADMIN_CMD, as defined by `struct nvme_passthru_cmd`.
+#[derive(Default)]
+#[repr(C)]
+struct NvmePassthruCmd {
+    opcode: u8,
+    flags: u8,
+    rsvd1: u16,
+    nsid: u32,
+    cdw2: u32,
+    cdw3: u32,
+    metadata: u64,
+    addr: u64,
+    metadata_len: u32,
+    data_len: u32,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+    cdw14: u32,
+    cdw15: u32,
+    timeout_ms: u32,
+    result: u32,
+}
+
+// Reads the lifetime from the SMART log of the NVMe device `f` is opened
+// on, which requires CAP_SYS_ADMIN.
+fn nvme_lifetime(f: &File) -> Option<DiskLifetime> {
+    let mut log = [0u8; NVME_SMART_LOG_SIZE];
+    let num_dwords = (NVME_SMART_LOG_SIZE / 4) as u32;
+    let mut cmd = NvmePassthruCmd {
+        opcode: NVME_ADMIN_GET_LOG_PAGE,
+        nsid: NVME_NSID_ALL,
+        addr: log.as_mut_ptr() as u64,
+        data_len: NVME_SMART_LOG_SIZE as u32,
+        cdw10: NVME_LOG_SMART | ((num_dwords - 1) << 16),
+        ..Default::default()
+    };
+    // SAFETY: FFI call with a valid fd and a command pointing to a buffer
+    // of the size it gives, both outliving the call.
+    let ret = unsafe { ioctl(f.as_raw_fd(), NVME_IOCTL_ADMIN_CMD as _, &mut cmd) };
+    if ret != 0 {
+        return None;
+    }
+
+    Some(DiskLifetime::from_nvme_smart_log(log[3], log[4], log[5]))
+}
+
+// Parses a value of the eMMC sysfs attributes, printed in hexadecimal.
+fn parse_mmc_value(value: &str) -> Option<u16> {
+    u16::from_str_radix(value.trim_start_matches("0x"), 16).ok()
+}
+
+// Reads the lifetime the MMC driver exposes from the extended CSD register
+// of the eMMC device whose sysfs directory is `sysfs_dir`.
+fn mmc_lifetime(sysfs_dir: &str) -> Option<DiskLifetime> {
+    let read_attr = |attr: &str| {
+        ["device", "../device"]
+            .iter()
+            .find_map(|dir| std::fs::read_to_string(format!("{sysfs_dir}/{dir}/{attr}")).ok())
+    };
+    let pre_eol_info = parse_mmc_value(read_attr("pre_eol_info")?.trim())?;
+    let life_time = read_attr("life_time")?;
+    let mut life_time = life_time.split_whitespace().map(parse_mmc_value);
+
+    Some(DiskLifetime {
+        pre_eol_info,
+        device_lifetime_est_typ_a: life_time.next()??,
+        device_lifetime_est_typ_b: life_time.next()??,
+    })
+}
+
+/// Returns the lifetime of the block device `f` is opened on, if it is an
+/// NVMe or eMMC device.
+pub(crate) fn block_device_lifetime(f: &File) -> Option<DiskLifetime> {
+    let sysfs_dir = block_device_sysfs_dir(f)?;
+    nvme_lifetime(f).or_else(|| mmc_lifetime(&sysfs_dir))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_nvme_smart_log() {
+        let lifetime = DiskLifetime::from_nvme_smart_log(100, 10, 0);
+        assert_eq!(lifetime.pre_eol_info, VIRTIO_BLK_PRE_EOL_INFO_NORMAL);
+        assert_eq!(lifetime.device_lifetime_est_typ_a, 0x01);
+        assert_eq!(lifetime.device_lifetime_est_typ_b, 0x01);
+
+        let lifetime = DiskLifetime::from_nvme_smart_log(20, 10, 45);
+        assert_eq!(lifetime.pre_eol_info, VIRTIO_BLK_PRE_EOL_INFO_WARNING);
+        assert_eq!(lifetime.device_lifetime_est_typ_a, 0x05);
+
+        let lifetime = DiskLifetime::from_nvme_smart_log(15, 15, 99);
+        assert_eq!(lifetime.pre_eol_info, VIRTIO_BLK_PRE_EOL_INFO_URGENT);
+        assert_eq!(lifetime.device_lifetime_est_typ_a, 0x0a);
+
+        // The percentage used goes past 100 once the lifetime is exceeded.
+        let lifetime = DiskLifetime::from_nvme_smart_log(5, 10, 255);
+        assert_eq!(lifetime.pre_eol_info, VIRTIO_BLK_PRE_EOL_INFO_URGENT);
+        assert_eq!(lifetime.device_lifetime_est_typ_a, 0x0b);
+    }
+
+    #[test]
+    fn test_mmc_lifetime() {
+        assert_eq!(parse_mmc_value("0x0a"), Some(0x0a));
+        assert_eq!(parse_mmc_value("0x01"), Some(0x01));
+        assert_eq!(parse_mmc_value("bogus"), None);
+        assert_eq!(mmc_lifetime("/nonexistent"), None);
+    }
+
+    #[test]
+    fn test_file_lifetime() {
+        let file = TempFile::new().unwrap();
+        assert_eq!(block_device_lifetime(file.as_file()), None);
+    }
+}
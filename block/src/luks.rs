@@ -0,0 +1,1066 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! LUKS2 header parsing and keyslot unlocking, compatible with the volumes
+//! created by `cryptsetup luksFormat --type luks2`.
+//!
+//! Only the `aes-xts-plain64` cipher is supported, for both the keyslots and
+//! the data segment, with keyslot keys derived through PBKDF2-SHA256,
+//! Argon2i or Argon2id.
+
+use aes::cipher::KeyInit;
+use aes::{Aes128, Aes256};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Deserializer};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::fs::FileExt;
+use std::sync::Mutex;
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+use xts_mode::{get_tweak_default, Xts128};
+
+const LUKS2_MAGIC: &[u8] = b"LUKS\xba\xbe";
+// Magic of the secondary header, which follows the primary one.
+const LUKS2_SECONDARY_MAGIC: &[u8] = b"SKUL\xba\xbe";
+const LUKS2_VERSION: u16 = 2;
+// The binary header occupies the first 4KiB, followed by the JSON area.
+const BINARY_HEADER_SIZE: usize = 4096;
+const SEQID_OFFSET: usize = 16;
+const HEADER_OFFSET_OFFSET: usize = 256;
+const CHECKSUM_OFFSET: usize = 448;
+const CHECKSUM_SIZE: usize = 64;
+const MIN_HEADER_SIZE: u64 = 16 * 1024;
+const MAX_HEADER_SIZE: u64 = 4 * 1024 * 1024;
+// Keyslot areas are always encrypted with 512 bytes sectors.
+const KEYSLOT_SECTOR_SIZE: usize = 512;
+const SHA256_DIGEST_SIZE: usize = 32;
+// Keyslot areas are aligned on 4KiB, and their KDF salt is 32 bytes long,
+// as with cryptsetup.
+const KEYSLOT_AREA_ALIGNMENT: u64 = 4096;
+const KDF_SALT_SIZE: usize = 32;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to read the LUKS header: {0}")]
+    ReadHeader(#[source] io::Error),
+    #[error("Invalid LUKS header magic")]
+    InvalidMagic,
+    #[error("Unsupported LUKS version: {0}")]
+    UnsupportedVersion(u16),
+    #[error("Invalid LUKS header size: {0}")]
+    InvalidHeaderSize(u64),
+    #[error("LUKS header checksum mismatch")]
+    ChecksumMismatch,
+    #[error("Invalid LUKS metadata: {0}")]
+    InvalidMetadata(#[source] serde_json::Error),
+    #[error("Invalid base64 value in the LUKS metadata: {0}")]
+    InvalidBase64(#[source] base64::DecodeError),
+    #[error("Unsupported LUKS {0}: {1}")]
+    Unsupported(&'static str, String),
+    #[error("No LUKS data segment")]
+    NoSegment,
+    #[error("No LUKS digest for the data segment")]
+    NoDigest,
+    #[error("Failed to derive a LUKS keyslot key: {0}")]
+    KeyDerivation(String),
+    #[error("Failed to read a LUKS keyslot: {0}")]
+    ReadKeyslot(#[source] io::Error),
+    #[error("No LUKS keyslot can be unlocked with the passphrase")]
+    NoMatchingKeyslot,
+    #[error("The key doesn't match the LUKS volume key")]
+    InvalidVolumeKey,
+    #[error("No LUKS keyslot to take the parameters of the new one from")]
+    NoKeyslot,
+    #[error("No room left for another LUKS keyslot")]
+    NoFreeKeyslotArea,
+    #[error("LUKS metadata too large for the header: {0} bytes")]
+    MetadataTooLarge(usize),
+    #[error("Failed to generate random bytes: {0}")]
+    Random(#[source] io::Error),
+    #[error("Failed to write a LUKS keyslot: {0}")]
+    WriteKeyslot(#[source] io::Error),
+    #[error("Failed to write the LUKS header: {0}")]
+    WriteHeader(#[source] io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Byte buffer holding key material, zeroed when dropped and never printed.
+#[derive(Clone, Default)]
+pub struct SecretBytes(Vec<u8>);
+
+impl SecretBytes {
+    pub fn new(len: usize) -> Self {
+        SecretBytes(vec![0; len])
+    }
+}
+
+impl From<Vec<u8>> for SecretBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        SecretBytes(bytes)
+    }
+}
+
+impl Deref for SecretBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl DerefMut for SecretBytes {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        for byte in self.0.iter_mut() {
+            // SAFETY: the pointer comes from a valid mutable reference.
+            // Volatile writes prevent the zeroing from being optimized out.
+            unsafe { std::ptr::write_volatile(byte, 0) };
+        }
+        std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretBytes(<redacted>)")
+    }
+}
+
+/// Key material used to unlock a LUKS volume.
+#[derive(Debug)]
+pub enum LuksKey {
+    /// Passphrase of one of the keyslots.
+    Passphrase(SecretBytes),
+    /// Raw volume key, bypassing the keyslots.
+    VolumeKey(SecretBytes),
+}
+
+fn deserialize_u64_string<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<u64, D::Error> {
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+// Segment sizes are either a number of bytes or "dynamic", meaning the
+// segment spans up to the end of the device.
+fn deserialize_segment_size<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<u64>, D::Error> {
+    let size = String::deserialize(deserializer)?;
+    if size == "dynamic" {
+        return Ok(None);
+    }
+
+    size.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+#[derive(Deserialize)]
+struct Metadata {
+    keyslots: BTreeMap<String, Keyslot>,
+    segments: BTreeMap<String, Segment>,
+    digests: BTreeMap<String, VolumeDigest>,
+    config: MetadataConfig,
+}
+
+#[derive(Deserialize)]
+struct MetadataConfig {
+    // Size of the keyslots area, which follows both headers.
+    #[serde(deserialize_with = "deserialize_u64_string")]
+    keyslots_size: u64,
+}
+
+#[derive(Deserialize)]
+struct Keyslot {
+    #[serde(rename = "type")]
+    type_: String,
+    key_size: usize,
+    af: AntiForensic,
+    area: KeyslotArea,
+    kdf: Kdf,
+}
+
+#[derive(Deserialize)]
+struct AntiForensic {
+    #[serde(rename = "type")]
+    type_: String,
+    stripes: usize,
+    hash: String,
+}
+
+#[derive(Deserialize)]
+struct KeyslotArea {
+    #[serde(rename = "type")]
+    type_: String,
+    #[serde(deserialize_with = "deserialize_u64_string")]
+    offset: u64,
+    #[serde(deserialize_with = "deserialize_u64_string")]
+    size: u64,
+    encryption: String,
+    key_size: usize,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(tag = "type")]
+enum Kdf {
+    #[serde(rename = "pbkdf2")]
+    Pbkdf2 {
+        hash: String,
+        iterations: u32,
+        salt: String,
+    },
+    #[serde(rename = "argon2i")]
+    Argon2i {
+        time: u32,
+        memory: u32,
+        cpus: u32,
+        salt: String,
+    },
+    #[serde(rename = "argon2id")]
+    Argon2id {
+        time: u32,
+        memory: u32,
+        cpus: u32,
+        salt: String,
+    },
+}
+
+impl Kdf {
+    // Same derivation, with another salt.
+    fn with_salt(&self, new_salt: String) -> Kdf {
+        let mut kdf = self.clone();
+        match &mut kdf {
+            Kdf::Pbkdf2 { salt, .. } | Kdf::Argon2i { salt, .. } | Kdf::Argon2id { salt, .. } => {
+                *salt = new_salt
+            }
+        }
+        kdf
+    }
+}
+
+#[derive(Deserialize)]
+struct Segment {
+    #[serde(rename = "type")]
+    type_: String,
+    #[serde(deserialize_with = "deserialize_u64_string")]
+    offset: u64,
+    #[serde(deserialize_with = "deserialize_segment_size")]
+    size: Option<u64>,
+    #[serde(deserialize_with = "deserialize_u64_string")]
+    iv_tweak: u64,
+    encryption: String,
+    sector_size: u64,
+}
+
+#[derive(Deserialize)]
+struct VolumeDigest {
+    #[serde(rename = "type")]
+    type_: String,
+    keyslots: Vec<String>,
+    segments: Vec<String>,
+    hash: String,
+    iterations: u32,
+    salt: String,
+    digest: String,
+}
+
+fn decode_base64(value: &str) -> Result<Vec<u8>> {
+    BASE64.decode(value).map_err(Error::InvalidBase64)
+}
+
+/// AES-XTS cipher, either AES-128 or AES-256 depending on the key size.
+#[allow(clippy::large_enum_variant)]
+pub enum XtsCipher {
+    Aes128(Xts128<Aes128>),
+    Aes256(Xts128<Aes256>),
+}
+
+impl XtsCipher {
+    pub fn new(key: &[u8]) -> Result<Self> {
+        let half = key.len() / 2;
+        match key.len() {
+            32 => Ok(XtsCipher::Aes128(Xts128::new(
+                Aes128::new_from_slice(&key[..half]).unwrap(),
+                Aes128::new_from_slice(&key[half..]).unwrap(),
+            ))),
+            64 => Ok(XtsCipher::Aes256(Xts128::new(
+                Aes256::new_from_slice(&key[..half]).unwrap(),
+                Aes256::new_from_slice(&key[half..]).unwrap(),
+            ))),
+            len => Err(Error::Unsupported("key size", (len * 8).to_string())),
+        }
+    }
+
+    /// Encrypts `data`, made of `sector_size` long sectors, the first one
+    /// using `first_sector` as its plain64 IV.
+    pub fn encrypt(&self, data: &mut [u8], sector_size: usize, first_sector: u64) {
+        match self {
+            XtsCipher::Aes128(xts) => {
+                xts.encrypt_area(data, sector_size, first_sector.into(), get_tweak_default)
+            }
+            XtsCipher::Aes256(xts) => {
+                xts.encrypt_area(data, sector_size, first_sector.into(), get_tweak_default)
+            }
+        }
+    }
+
+    /// Decrypts `data`, made of `sector_size` long sectors, the first one
+    /// using `first_sector` as its plain64 IV.
+    pub fn decrypt(&self, data: &mut [u8], sector_size: usize, first_sector: u64) {
+        match self {
+            XtsCipher::Aes128(xts) => {
+                xts.decrypt_area(data, sector_size, first_sector.into(), get_tweak_default)
+            }
+            XtsCipher::Aes256(xts) => {
+                xts.decrypt_area(data, sector_size, first_sector.into(), get_tweak_default)
+            }
+        }
+    }
+}
+
+// Hashes each digest sized chunk of `data` with its big endian index as a
+// prefix, as done by the LUKS anti-forensic splitter.
+fn diffuse(data: &mut [u8]) {
+    for (index, chunk) in data.chunks_mut(SHA256_DIGEST_SIZE).enumerate() {
+        let mut hasher = Sha256::new();
+        hasher.update((index as u32).to_be_bytes());
+        hasher.update(&*chunk);
+        let hash = hasher.finalize();
+        chunk.copy_from_slice(&hash[..chunk.len()]);
+    }
+}
+
+// Recovers the key split in `stripes` stripes by the anti-forensic splitter.
+fn af_merge(split: &[u8], key_size: usize, stripes: usize) -> SecretBytes {
+    let mut key = SecretBytes::new(key_size);
+    for (index, stripe) in split.chunks_exact(key_size).take(stripes).enumerate() {
+        for (k, s) in key.iter_mut().zip(stripe) {
+            *k ^= s;
+        }
+        if index < stripes - 1 {
+            diffuse(&mut key);
+        }
+    }
+
+    key
+}
+
+// Splits `key` in `stripes` stripes into `split`, so that af_merge()
+// recovers it. All but the last stripe are random.
+fn af_split(key: &[u8], stripes: usize, split: &mut [u8]) -> Result<()> {
+    let key_size = key.len();
+    fill_random(&mut split[..(stripes - 1) * key_size])?;
+    let mut merged = SecretBytes::new(key_size);
+    for stripe in split.chunks_exact(key_size).take(stripes - 1) {
+        for (m, s) in merged.iter_mut().zip(stripe) {
+            *m ^= s;
+        }
+        diffuse(&mut merged);
+    }
+    let last = &mut split[(stripes - 1) * key_size..stripes * key_size];
+    for ((l, m), k) in last.iter_mut().zip(merged.iter()).zip(key) {
+        *l = m ^ k;
+    }
+
+    Ok(())
+}
+
+// Fills `buf` from the kernel random number generator.
+fn fill_random(buf: &mut [u8]) -> Result<()> {
+    getrandom::getrandom(buf).map_err(|e| Error::Random(e.into()))
+}
+
+fn derive_key(kdf: &Kdf, passphrase: &[u8], key_size: usize) -> Result<SecretBytes> {
+    let mut key = SecretBytes::new(key_size);
+    let (algorithm, time, memory, cpus, salt) = match kdf {
+        Kdf::Pbkdf2 {
+            hash,
+            iterations,
+            salt,
+        } => {
+            if hash != "sha256" {
+                return Err(Error::Unsupported("PBKDF2 hash", hash.clone()));
+            }
+            pbkdf2::pbkdf2_hmac::<Sha256>(passphrase, &decode_base64(salt)?, *iterations, &mut key);
+            return Ok(key);
+        }
+        Kdf::Argon2i {
+            time,
+            memory,
+            cpus,
+            salt,
+        } => (Algorithm::Argon2i, time, memory, cpus, salt),
+        Kdf::Argon2id {
+            time,
+            memory,
+            cpus,
+            salt,
+        } => (Algorithm::Argon2id, time, memory, cpus, salt),
+    };
+
+    let params = Params::new(*memory, *time, *cpus, Some(key_size))
+        .map_err(|e| Error::KeyDerivation(e.to_string()))?;
+    Argon2::new(algorithm, Version::V0x13, params)
+        .hash_password_into(passphrase, &decode_base64(salt)?, &mut key)
+        .map_err(|e| Error::KeyDerivation(e.to_string()))?;
+
+    Ok(key)
+}
+
+fn check_keyslot(keyslot: &Keyslot) -> Result<()> {
+    if keyslot.type_ != "luks2" {
+        return Err(Error::Unsupported("keyslot type", keyslot.type_.clone()));
+    }
+    if keyslot.af.type_ != "luks1" || keyslot.af.hash != "sha256" || keyslot.af.stripes == 0 {
+        return Err(Error::Unsupported(
+            "anti-forensic splitter",
+            keyslot.af.hash.clone(),
+        ));
+    }
+    if keyslot.area.type_ != "raw" || keyslot.area.encryption != "aes-xts-plain64" {
+        return Err(Error::Unsupported(
+            "keyslot encryption",
+            keyslot.area.encryption.clone(),
+        ));
+    }
+
+    Ok(())
+}
+
+fn unlock_keyslot(file: &File, keyslot: &Keyslot, passphrase: &[u8]) -> Result<SecretBytes> {
+    check_keyslot(keyslot)?;
+
+    let area_key = derive_key(&keyslot.kdf, passphrase, keyslot.area.key_size)?;
+    let split_len = keyslot.key_size * keyslot.af.stripes;
+    let mut split = SecretBytes::new(split_len.next_multiple_of(KEYSLOT_SECTOR_SIZE));
+    file.read_exact_at(&mut split, keyslot.area.offset)
+        .map_err(Error::ReadKeyslot)?;
+    XtsCipher::new(&area_key)?.decrypt(&mut split, KEYSLOT_SECTOR_SIZE, 0);
+
+    Ok(af_merge(
+        &split[..split_len],
+        keyslot.key_size,
+        keyslot.af.stripes,
+    ))
+}
+
+// Wraps `volume_key` in the keyslot area at `offset`, split as `keyslot`
+// splits its key and encrypted with a key derived from `passphrase` by
+// `kdf`.
+fn write_keyslot(
+    file: &File,
+    keyslot: &Keyslot,
+    kdf: &Kdf,
+    offset: u64,
+    volume_key: &[u8],
+    passphrase: &[u8],
+) -> Result<()> {
+    let area_key = derive_key(kdf, passphrase, keyslot.area.key_size)?;
+    let split_len = volume_key.len() * keyslot.af.stripes;
+    let mut split = SecretBytes::new(split_len.next_multiple_of(KEYSLOT_SECTOR_SIZE));
+    af_split(volume_key, keyslot.af.stripes, &mut split[..split_len])?;
+    XtsCipher::new(&area_key)?.encrypt(&mut split, KEYSLOT_SECTOR_SIZE, 0);
+
+    file.write_all_at(&split, offset)
+        .map_err(Error::WriteKeyslot)?;
+    file.sync_data().map_err(Error::WriteKeyslot)
+}
+
+// Finds room for a keyslot area of `size` bytes in the keyslots area,
+// which follows both headers.
+fn free_keyslot_area(metadata: &Metadata, header_size: u64, size: u64) -> Option<u64> {
+    let start = 2 * header_size;
+    let end = start + metadata.config.keyslots_size;
+    let mut areas: Vec<(u64, u64)> = metadata
+        .keyslots
+        .values()
+        .map(|keyslot| (keyslot.area.offset, keyslot.area.offset + keyslot.area.size))
+        .collect();
+    areas.sort_unstable();
+
+    let mut offset = start;
+    for (area_start, area_end) in areas {
+        if area_start >= offset + size {
+            break;
+        }
+        offset = offset.max(area_end.next_multiple_of(KEYSLOT_AREA_ALIGNMENT));
+    }
+
+    (offset + size <= end).then_some(offset)
+}
+
+fn verify_volume_key(digest: &VolumeDigest, key: &[u8]) -> Result<bool> {
+    let expected = decode_base64(&digest.digest)?;
+    let mut computed = vec![0; expected.len()];
+    pbkdf2::pbkdf2_hmac::<Sha256>(
+        key,
+        &decode_base64(&digest.salt)?,
+        digest.iterations,
+        &mut computed,
+    );
+
+    Ok(computed.ct_eq(&expected).into())
+}
+
+/// Unlocked LUKS2 volume, describing where its data segment lives in the
+/// underlying file and how it is encrypted.
+pub struct LuksVolume {
+    /// Offset of the data segment in the underlying file.
+    pub data_offset: u64,
+    /// Size of the data segment, `None` when it spans up to the end of the
+    /// underlying file.
+    pub data_size: Option<u64>,
+    /// Size of the encryption sectors.
+    pub sector_size: u64,
+    /// Value added to the sector number to get its IV.
+    pub iv_tweak: u64,
+    pub cipher: XtsCipher,
+    // Kept to wrap it in new keyslots.
+    volume_key: SecretBytes,
+    // Keyslot the volume was unlocked with, `None` when unlocked with the
+    // volume key.
+    keyslot: Mutex<Option<String>>,
+}
+
+impl LuksVolume {
+    /// Reads the LUKS2 header at the start of `file` and unlocks the data
+    /// segment with `key`.
+    pub fn open(file: &File, key: &LuksKey) -> Result<Self> {
+        let metadata = read_metadata(file)?;
+
+        // Volumes formatted by cryptsetup have a single data segment.
+        let (segment_id, segment) = metadata.segments.iter().next().ok_or(Error::NoSegment)?;
+        if segment.type_ != "crypt" {
+            return Err(Error::Unsupported("segment type", segment.type_.clone()));
+        }
+        if segment.encryption != "aes-xts-plain64" {
+            return Err(Error::Unsupported(
+                "segment encryption",
+                segment.encryption.clone(),
+            ));
+        }
+        if segment.sector_size != 512 && segment.sector_size != 4096 {
+            return Err(Error::Unsupported(
+                "sector size",
+                segment.sector_size.to_string(),
+            ));
+        }
+
+        let digest = metadata
+            .digests
+            .values()
+            .find(|d| d.segments.contains(segment_id))
+            .ok_or(Error::NoDigest)?;
+        if digest.type_ != "pbkdf2" || digest.hash != "sha256" {
+            return Err(Error::Unsupported("digest", digest.hash.clone()));
+        }
+
+        let (volume_key, keyslot) = match key {
+            LuksKey::VolumeKey(volume_key) => {
+                if !verify_volume_key(digest, volume_key)? {
+                    return Err(Error::InvalidVolumeKey);
+                }
+                (volume_key.clone(), None)
+            }
+            LuksKey::Passphrase(passphrase) => {
+                let mut unlocked = None;
+                for id in digest.keyslots.iter() {
+                    let Some(keyslot) = metadata.keyslots.get(id) else {
+                        continue;
+                    };
+                    match unlock_keyslot(file, keyslot, passphrase) {
+                        Ok(candidate) if verify_volume_key(digest, &candidate)? => {
+                            unlocked = Some((candidate, Some(id.clone())));
+                            break;
+                        }
+                        Ok(_) => {}
+                        Err(e) => debug!("Skipping LUKS keyslot {}: {}", id, e),
+                    }
+                }
+                unlocked.ok_or(Error::NoMatchingKeyslot)?
+            }
+        };
+
+        Ok(LuksVolume {
+            data_offset: segment.offset,
+            data_size: segment.size,
+            sector_size: segment.sector_size,
+            iv_tweak: segment.iv_tweak,
+            cipher: XtsCipher::new(&volume_key)?,
+            volume_key,
+            keyslot: Mutex::new(keyslot),
+        })
+    }
+
+    /// Wraps the volume key in a new keyslot unlocked by `passphrase`, and
+    /// then wipes the keyslot the volume was unlocked with, so that its
+    /// passphrase no longer unlocks the volume. The other keyslots are kept,
+    /// and none is wiped when the volume was unlocked with its volume key.
+    /// The data isn't re-encrypted, and can be accessed throughout. The new
+    /// keyslot takes the parameters of the replaced one, or of the first
+    /// keyslot of the data segment, with a new salt. It is the one retired
+    /// by the next rotation. Returns its id.
+    pub fn rotate_passphrase(&self, file: &File, passphrase: &[u8]) -> Result<String> {
+        let header = read_header(file)?;
+        let header_size = header.len() as u64;
+        let metadata: Metadata =
+            serde_json::from_slice(json_area(&header)).map_err(Error::InvalidMetadata)?;
+        // The metadata not described by Metadata is kept as is.
+        let mut json: serde_json::Value =
+            serde_json::from_slice(json_area(&header)).map_err(Error::InvalidMetadata)?;
+
+        let (segment_id, _) = metadata.segments.iter().next().ok_or(Error::NoSegment)?;
+        let (digest_id, digest) = metadata
+            .digests
+            .iter()
+            .find(|(_, digest)| digest.segments.contains(segment_id))
+            .ok_or(Error::NoDigest)?;
+        let mut unlocked = self.keyslot.lock().unwrap();
+        let retired: Vec<&String> = unlocked
+            .iter()
+            .filter(|id| digest.keyslots.contains(id) && metadata.keyslots.contains_key(*id))
+            .collect();
+        let template_id = retired
+            .first()
+            .copied()
+            .or_else(|| {
+                digest
+                    .keyslots
+                    .iter()
+                    .find(|id| metadata.keyslots.contains_key(*id))
+            })
+            .ok_or(Error::NoKeyslot)?;
+        let template = &metadata.keyslots[template_id];
+        check_keyslot(template)?;
+
+        let area_size = ((template.key_size * template.af.stripes) as u64)
+            .next_multiple_of(KEYSLOT_AREA_ALIGNMENT);
+        let offset =
+            free_keyslot_area(&metadata, header_size, area_size).ok_or(Error::NoFreeKeyslotArea)?;
+        let mut salt = SecretBytes::new(KDF_SALT_SIZE);
+        fill_random(&mut salt)?;
+        let salt = BASE64.encode(&*salt);
+        write_keyslot(
+            file,
+            template,
+            &template.kdf.with_salt(salt.clone()),
+            offset,
+            &self.volume_key,
+            passphrase,
+        )?;
+
+        let id = (0u32..)
+            .map(|id| id.to_string())
+            .find(|id| !metadata.keyslots.contains_key(id))
+            .unwrap();
+        let mut keyslot = json["keyslots"][template_id.as_str()].clone();
+        keyslot["kdf"]["salt"] = salt.into();
+        keyslot["area"]["offset"] = offset.to_string().into();
+        keyslot["area"]["size"] = area_size.to_string().into();
+        if let Some(keyslots) = json["keyslots"].as_object_mut() {
+            keyslots.retain(|id, _| !retired.contains(&id));
+            keyslots.insert(id.clone(), keyslot);
+        }
+        let mut digest_keyslots: Vec<&String> = digest
+            .keyslots
+            .iter()
+            .filter(|id| !retired.contains(id))
+            .collect();
+        digest_keyslots.push(&id);
+        json["digests"][digest_id.as_str()]["keyslots"] = serde_json::json!(digest_keyslots);
+        // Tokens, such as the ones of systemd-cryptenroll, no longer
+        // reference the retired keyslot.
+        if let Some(tokens) = json.get_mut("tokens").and_then(|t| t.as_object_mut()) {
+            for token in tokens.values_mut() {
+                if let Some(keyslots) = token.get_mut("keyslots").and_then(|k| k.as_array_mut()) {
+                    keyslots.retain(|id| !retired.iter().any(|r| id.as_str() == Some(r.as_str())));
+                }
+            }
+        }
+        let json = serde_json::to_vec(&json).map_err(Error::InvalidMetadata)?;
+
+        // The secondary header is written first, leaving cryptsetup a valid
+        // header to recover from if the primary one is torn.
+        let seqid = u64::from_be_bytes(header[SEQID_OFFSET..SEQID_OFFSET + 8].try_into().unwrap());
+        for (magic, header_offset) in [(LUKS2_SECONDARY_MAGIC, header_size), (LUKS2_MAGIC, 0)] {
+            let mut header = header.clone();
+            header[..magic.len()].copy_from_slice(magic);
+            header[SEQID_OFFSET..SEQID_OFFSET + 8].copy_from_slice(&(seqid + 1).to_be_bytes());
+            header[HEADER_OFFSET_OFFSET..HEADER_OFFSET_OFFSET + 8]
+                .copy_from_slice(&header_offset.to_be_bytes());
+            write_header(file, header, &json, header_offset)?;
+        }
+
+        // Only once no header references the retired keyslot is its area
+        // wiped.
+        for retired_id in retired {
+            let area = &metadata.keyslots[retired_id].area;
+            file.write_all_at(&vec![0u8; area.size as usize], area.offset)
+                .map_err(Error::WriteKeyslot)?;
+        }
+        file.sync_data().map_err(Error::WriteKeyslot)?;
+
+        *unlocked = Some(id.clone());
+        Ok(id)
+    }
+}
+
+fn read_metadata(file: &File) -> Result<Metadata> {
+    let header = read_header(file)?;
+    serde_json::from_slice(json_area(&header)).map_err(Error::InvalidMetadata)
+}
+
+fn json_area(header: &[u8]) -> &[u8] {
+    let json = &header[BINARY_HEADER_SIZE..];
+    &json[..json.iter().position(|b| *b == 0).unwrap_or(json.len())]
+}
+
+// Reads the primary header, binary header and JSON area, once checked.
+fn read_header(file: &File) -> Result<Vec<u8>> {
+    let mut binary_header = vec![0u8; BINARY_HEADER_SIZE];
+    file.read_exact_at(&mut binary_header, 0)
+        .map_err(Error::ReadHeader)?;
+
+    if &binary_header[..LUKS2_MAGIC.len()] != LUKS2_MAGIC {
+        return Err(Error::InvalidMagic);
+    }
+    let version = u16::from_be_bytes(binary_header[6..8].try_into().unwrap());
+    if version != LUKS2_VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    let header_size = u64::from_be_bytes(binary_header[8..16].try_into().unwrap());
+    if !(MIN_HEADER_SIZE..=MAX_HEADER_SIZE).contains(&header_size) {
+        return Err(Error::InvalidHeaderSize(header_size));
+    }
+    let checksum_algorithm = &binary_header[72..104];
+    let checksum_algorithm = &checksum_algorithm[..checksum_algorithm
+        .iter()
+        .position(|b| *b == 0)
+        .unwrap_or(checksum_algorithm.len())];
+    if checksum_algorithm != b"sha256" {
+        return Err(Error::Unsupported(
+            "checksum algorithm",
+            String::from_utf8_lossy(checksum_algorithm).into_owned(),
+        ));
+    }
+
+    // The checksum covers the binary header, with the checksum field zeroed,
+    // and the JSON area.
+    let mut header = vec![0u8; header_size as usize];
+    file.read_exact_at(&mut header, 0)
+        .map_err(Error::ReadHeader)?;
+    let expected = header[CHECKSUM_OFFSET..CHECKSUM_OFFSET + SHA256_DIGEST_SIZE].to_vec();
+    header[CHECKSUM_OFFSET..CHECKSUM_OFFSET + CHECKSUM_SIZE].fill(0);
+    if Sha256::digest(&header).as_slice() != expected.as_slice() {
+        return Err(Error::ChecksumMismatch);
+    }
+    header[CHECKSUM_OFFSET..CHECKSUM_OFFSET + SHA256_DIGEST_SIZE].copy_from_slice(&expected);
+
+    Ok(header)
+}
+
+// Writes `header` at `offset` with `json` as its JSON area, after updating
+// its checksum.
+fn write_header(file: &File, mut header: Vec<u8>, json: &[u8], offset: u64) -> Result<()> {
+    let json_area = &mut header[BINARY_HEADER_SIZE..];
+    // The JSON area is terminated by a zero.
+    if json.len() >= json_area.len() {
+        return Err(Error::MetadataTooLarge(json.len()));
+    }
+    json_area.fill(0);
+    json_area[..json.len()].copy_from_slice(json);
+
+    header[CHECKSUM_OFFSET..CHECKSUM_OFFSET + CHECKSUM_SIZE].fill(0);
+    let checksum = Sha256::digest(&header);
+    header[CHECKSUM_OFFSET..CHECKSUM_OFFSET + SHA256_DIGEST_SIZE].copy_from_slice(&checksum);
+    file.write_all_at(&header, offset)
+        .map_err(Error::WriteHeader)?;
+    file.sync_data().map_err(Error::WriteHeader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_af_merge_single_stripe() {
+        // With a single stripe, the split material is the key itself.
+        let split: Vec<u8> = (0..64).collect();
+        assert_eq!(&*af_merge(&split, 64, 1), split.as_slice());
+    }
+
+    #[test]
+    fn test_af_merge_two_stripes() {
+        let key_size = 40;
+        let mut first: Vec<u8> = (0..key_size as u8).collect();
+        let key: Vec<u8> = (100..100 + key_size as u8).collect();
+
+        // Split the key the way the LUKS splitter does: the last stripe is
+        // the key XORed with the diffused first stripe.
+        let mut split = first.clone();
+        diffuse(&mut first);
+        split.extend(first.iter().zip(key.iter()).map(|(d, k)| d ^ k));
+
+        assert_eq!(&*af_merge(&split, key_size, 2), key.as_slice());
+    }
+
+    #[test]
+    fn test_af_split_round_trip() {
+        let key: Vec<u8> = (0..64).collect();
+        let mut split = vec![0u8; 64 * 4];
+        af_split(&key, 4, &mut split).unwrap();
+        assert_ne!(&split[..64], key.as_slice());
+        assert_eq!(&*af_merge(&split, 64, 4), key.as_slice());
+    }
+
+    const TEST_HEADER_SIZE: u64 = MIN_HEADER_SIZE;
+    const TEST_KEYSLOTS_SIZE: u64 = 3 * KEYSLOT_AREA_ALIGNMENT;
+
+    fn test_salt() -> String {
+        let mut salt = vec![0u8; KDF_SALT_SIZE];
+        fill_random(&mut salt).unwrap();
+        BASE64.encode(salt)
+    }
+
+    // Formats `file` as a LUKS2 volume with a single keyslot unlocked by
+    // `passphrase`, using cheap KDF parameters.
+    fn format_volume(file: &File, passphrase: &[u8]) -> Vec<u8> {
+        let mut volume_key = vec![0u8; 64];
+        fill_random(&mut volume_key).unwrap();
+        let digest_salt = test_salt();
+        let mut digest = vec![0u8; SHA256_DIGEST_SIZE];
+        pbkdf2::pbkdf2_hmac::<Sha256>(
+            &volume_key,
+            &decode_base64(&digest_salt).unwrap(),
+            1000,
+            &mut digest,
+        );
+
+        let keyslots_offset = 2 * TEST_HEADER_SIZE;
+        let json = serde_json::json!({
+            "keyslots": {
+                "0": {
+                    "type": "luks2",
+                    "key_size": 64,
+                    "af": { "type": "luks1", "stripes": 4, "hash": "sha256" },
+                    "area": {
+                        "type": "raw",
+                        "offset": keyslots_offset.to_string(),
+                        "size": KEYSLOT_AREA_ALIGNMENT.to_string(),
+                        "encryption": "aes-xts-plain64",
+                        "key_size": 64,
+                    },
+                    "kdf": {
+                        "type": "pbkdf2",
+                        "hash": "sha256",
+                        "iterations": 1000,
+                        "salt": test_salt(),
+                    },
+                },
+            },
+            "tokens": {},
+            "segments": {
+                "0": {
+                    "type": "crypt",
+                    "offset": (keyslots_offset + TEST_KEYSLOTS_SIZE).to_string(),
+                    "size": "dynamic",
+                    "iv_tweak": "0",
+                    "encryption": "aes-xts-plain64",
+                    "sector_size": 512,
+                },
+            },
+            "digests": {
+                "0": {
+                    "type": "pbkdf2",
+                    "keyslots": ["0"],
+                    "segments": ["0"],
+                    "hash": "sha256",
+                    "iterations": 1000,
+                    "salt": digest_salt,
+                    "digest": BASE64.encode(&digest),
+                },
+            },
+            "config": {
+                "json_size": (TEST_HEADER_SIZE - BINARY_HEADER_SIZE as u64).to_string(),
+                "keyslots_size": TEST_KEYSLOTS_SIZE.to_string(),
+            },
+        });
+        let metadata: Metadata = serde_json::from_value(json.clone()).unwrap();
+        let keyslot = &metadata.keyslots["0"];
+        write_keyslot(
+            file,
+            keyslot,
+            &keyslot.kdf,
+            keyslot.area.offset,
+            &volume_key,
+            passphrase,
+        )
+        .unwrap();
+
+        let mut header = vec![0u8; TEST_HEADER_SIZE as usize];
+        header[..LUKS2_MAGIC.len()].copy_from_slice(LUKS2_MAGIC);
+        header[6..8].copy_from_slice(&LUKS2_VERSION.to_be_bytes());
+        header[8..16].copy_from_slice(&TEST_HEADER_SIZE.to_be_bytes());
+        header[SEQID_OFFSET..SEQID_OFFSET + 8].copy_from_slice(&1u64.to_be_bytes());
+        header[72..78].copy_from_slice(b"sha256");
+        let json = serde_json::to_vec(&json).unwrap();
+        write_header(file, header.clone(), &json, 0).unwrap();
+        header[..LUKS2_SECONDARY_MAGIC.len()].copy_from_slice(LUKS2_SECONDARY_MAGIC);
+        header[HEADER_OFFSET_OFFSET..HEADER_OFFSET_OFFSET + 8]
+            .copy_from_slice(&TEST_HEADER_SIZE.to_be_bytes());
+        write_header(file, header, &json, TEST_HEADER_SIZE).unwrap();
+
+        volume_key
+    }
+
+    fn passphrase(passphrase: &[u8]) -> LuksKey {
+        LuksKey::Passphrase(SecretBytes::from(passphrase.to_vec()))
+    }
+
+    #[test]
+    fn test_rotate_passphrase() {
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap();
+        let file = file.as_file();
+        let volume_key = format_volume(file, b"old");
+
+        let volume = LuksVolume::open(file, &passphrase(b"old")).unwrap();
+        let id = volume.rotate_passphrase(file, b"new").unwrap();
+        assert_eq!(id, "1");
+
+        let rotated = LuksVolume::open(file, &passphrase(b"new")).unwrap();
+        assert_eq!(&*rotated.volume_key, volume_key.as_slice());
+        assert!(matches!(
+            LuksVolume::open(file, &passphrase(b"old")),
+            Err(Error::NoMatchingKeyslot)
+        ));
+
+        // The retired keyslot area is wiped.
+        let mut area = vec![0u8; KEYSLOT_AREA_ALIGNMENT as usize];
+        file.read_exact_at(&mut area, 2 * TEST_HEADER_SIZE).unwrap();
+        assert!(area.iter().all(|b| *b == 0));
+
+        // Both headers are updated, the secondary one keeping its magic.
+        let header = read_header(file).unwrap();
+        assert_eq!(
+            u64::from_be_bytes(header[SEQID_OFFSET..SEQID_OFFSET + 8].try_into().unwrap()),
+            2
+        );
+        let mut secondary = vec![0u8; TEST_HEADER_SIZE as usize];
+        file.read_exact_at(&mut secondary, TEST_HEADER_SIZE)
+            .unwrap();
+        assert_eq!(
+            &secondary[..LUKS2_SECONDARY_MAGIC.len()],
+            LUKS2_SECONDARY_MAGIC
+        );
+        assert_eq!(json_area(&secondary), json_area(&header));
+
+        // The area freed by the first rotation is used by the next one.
+        let id = rotated.rotate_passphrase(file, b"newer").unwrap();
+        assert_eq!(id, "0");
+        assert!(LuksVolume::open(file, &passphrase(b"newer")).is_ok());
+        assert!(LuksVolume::open(file, &passphrase(b"new")).is_err());
+    }
+
+    #[test]
+    fn test_rotate_passphrase_keeps_other_keyslots() {
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap();
+        let file = file.as_file();
+        let volume_key = format_volume(file, b"old");
+
+        // Unlocked with the volume key, no keyslot is retired.
+        let volume = LuksVolume::open(file, &LuksKey::VolumeKey(volume_key.into())).unwrap();
+        assert_eq!(volume.rotate_passphrase(file, b"new").unwrap(), "1");
+        assert!(LuksVolume::open(file, &passphrase(b"old")).is_ok());
+        assert!(LuksVolume::open(file, &passphrase(b"new")).is_ok());
+
+        // The next rotation only retires the keyslot added by the previous
+        // one.
+        assert_eq!(volume.rotate_passphrase(file, b"newer").unwrap(), "2");
+        assert!(LuksVolume::open(file, &passphrase(b"old")).is_ok());
+        assert!(LuksVolume::open(file, &passphrase(b"newer")).is_ok());
+        assert!(matches!(
+            LuksVolume::open(file, &passphrase(b"new")),
+            Err(Error::NoMatchingKeyslot)
+        ));
+        let metadata = read_metadata(file).unwrap();
+        assert_eq!(
+            metadata.digests.values().next().unwrap().keyslots,
+            ["0", "2"]
+        );
+
+        let mut area = vec![0u8; KEYSLOT_AREA_ALIGNMENT as usize];
+        file.read_exact_at(&mut area, 2 * TEST_HEADER_SIZE + KEYSLOT_AREA_ALIGNMENT)
+            .unwrap();
+        assert!(area.iter().all(|b| *b == 0));
+        file.read_exact_at(&mut area, 2 * TEST_HEADER_SIZE).unwrap();
+        assert!(area.iter().any(|b| *b != 0));
+    }
+
+    #[test]
+    fn test_rotate_passphrase_no_room() {
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap();
+        let file = file.as_file();
+        format_volume(file, b"old");
+        let metadata = read_metadata(file).unwrap();
+
+        // Two keyslot areas are left after the current one.
+        assert_eq!(
+            free_keyslot_area(&metadata, TEST_HEADER_SIZE, 2 * KEYSLOT_AREA_ALIGNMENT),
+            Some(2 * TEST_HEADER_SIZE + KEYSLOT_AREA_ALIGNMENT)
+        );
+        assert_eq!(
+            free_keyslot_area(&metadata, TEST_HEADER_SIZE, 3 * KEYSLOT_AREA_ALIGNMENT),
+            None
+        );
+    }
+
+    #[test]
+    fn test_xts_round_trip() {
+        let cipher = XtsCipher::new(&[7u8; 64]).unwrap();
+        let plaintext: Vec<u8> = (0..4096).map(|i| i as u8).collect();
+
+        let mut data = plaintext.clone();
+        cipher.encrypt(&mut data, 512, 42);
+        assert_ne!(data, plaintext);
+        // Each sector uses its own IV.
+        assert_ne!(data[..512], data[512..1024]);
+
+        let mut sector = plaintext[512..1024].to_vec();
+        cipher.encrypt(&mut sector, 512, 43);
+        assert_eq!(sector, data[512..1024]);
+
+        cipher.decrypt(&mut data, 512, 42);
+        assert_eq!(data, plaintext);
+    }
+
+    #[test]
+    fn test_invalid_magic() {
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap();
+        file.as_file().set_len(MIN_HEADER_SIZE).unwrap();
+        assert!(matches!(
+            read_metadata(file.as_file()),
+            Err(Error::InvalidMagic)
+        ));
+    }
+
+    #[test]
+    fn test_secret_bytes_debug_redacted() {
+        let secret = SecretBytes::from(b"hunter2".to_vec());
+        assert!(!format!("{:?}", LuksKey::Passphrase(secret)).contains("hunter2"));
+    }
+}
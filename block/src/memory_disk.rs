@@ -0,0 +1,391 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! [`DiskFile`] stored in memory, meant to test the block device path
+//! without touching the filesystem. Requests complete as soon as they are
+//! submitted, and errors can be injected on given ranges of the disk.
+
+use crate::async_io::{
+    check_completion_room, completion_room, AsyncIo, AsyncIoResult, DiskExtent, DiskFile,
+    DiskFileResult, IoAlignment,
+};
+use crate::DiskTopology;
+use std::cmp;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use vmm_sys_util::eventfd::EventFd;
+
+#[derive(Default)]
+struct MemoryDisk {
+    data: Vec<u8>,
+    // Ranges failing with the given errno, as (offset, length, errno).
+    errors: Vec<(u64, u64, i32)>,
+}
+
+impl MemoryDisk {
+    fn error(&self, offset: u64, length: u64) -> Option<i32> {
+        self.errors
+            .iter()
+            .find(|(start, len, _)| offset < start + len && *start < offset + length)
+            .map(|(_, _, errno)| *errno)
+    }
+
+    fn zero(&mut self, offset: u64, length: u64) {
+        let size = self.data.len() as u64;
+        let start = cmp::min(offset, size) as usize;
+        let end = cmp::min(offset + length, size) as usize;
+        self.data[start..end].fill(0);
+    }
+}
+
+/// Disk holding its content in a buffer, which grows as it is written past
+/// its end. Clones share the same content, so that it can be inspected once
+/// the disk is handed to a device.
+#[derive(Clone)]
+pub struct MemoryDiskFile {
+    disk: Arc<Mutex<MemoryDisk>>,
+    logical_block_size: u64,
+}
+
+impl MemoryDiskFile {
+    /// Creates a zeroed disk of `size` bytes. As with `O_DIRECT`, requests
+    /// not aligned on `logical_block_size` fail with `EINVAL`.
+    pub fn new(size: u64, logical_block_size: u64) -> Self {
+        Self::with_data(vec![0; size as usize], logical_block_size)
+    }
+
+    /// Creates a disk holding `data`.
+    pub fn with_data(data: Vec<u8>, logical_block_size: u64) -> Self {
+        MemoryDiskFile {
+            disk: Arc::new(Mutex::new(MemoryDisk {
+                data,
+                errors: Vec::new(),
+            })),
+            logical_block_size,
+        }
+    }
+
+    /// Returns a copy of the content of the disk.
+    pub fn data(&self) -> Vec<u8> {
+        self.disk.lock().unwrap().data.clone()
+    }
+
+    /// Makes every request touching `length` bytes at `offset` fail with
+    /// `errno`, until the errors are cleared.
+    pub fn inject_error(&self, offset: u64, length: u64, errno: i32) {
+        self.disk
+            .lock()
+            .unwrap()
+            .errors
+            .push((offset, length, errno));
+    }
+
+    /// Removes the injected errors.
+    pub fn clear_errors(&self) {
+        self.disk.lock().unwrap().errors.clear();
+    }
+}
+
+impl DiskFile for MemoryDiskFile {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        Ok(self.disk.lock().unwrap().data.len() as u64)
+    }
+
+    fn new_async_io(&self, _ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        Ok(Box::new(MemoryAsyncIo::new(
+            self.disk.clone(),
+            self.logical_block_size,
+        )) as Box<dyn AsyncIo>)
+    }
+
+    fn topology(&mut self) -> DiskTopology {
+        DiskTopology {
+            logical_block_size: self.logical_block_size,
+            physical_block_size: self.logical_block_size,
+            minimum_io_size: self.logical_block_size,
+            ..Default::default()
+        }
+    }
+
+    fn supports_discard(&self) -> bool {
+        true
+    }
+
+    fn supports_write_zeroes(&self) -> bool {
+        true
+    }
+
+    fn discard_zeroes(&self) -> bool {
+        true
+    }
+
+    fn extents(&mut self) -> DiskFileResult<Vec<DiskExtent>> {
+        let length = self.size()?;
+        Ok(if length > 0 {
+            vec![DiskExtent { offset: 0, length }]
+        } else {
+            Vec::new()
+        })
+    }
+}
+
+pub struct MemoryAsyncIo {
+    disk: Arc<Mutex<MemoryDisk>>,
+    logical_block_size: u64,
+    eventfd: EventFd,
+    completion_list: VecDeque<(u64, i32)>,
+}
+
+impl MemoryAsyncIo {
+    fn new(disk: Arc<Mutex<MemoryDisk>>, logical_block_size: u64) -> Self {
+        MemoryAsyncIo {
+            disk,
+            logical_block_size,
+            eventfd: EventFd::new(libc::EFD_NONBLOCK)
+                .expect("Failed creating EventFd for MemoryAsyncIo"),
+            completion_list: VecDeque::new(),
+        }
+    }
+
+    fn complete(&mut self, user_data: u64, result: i32) {
+        self.completion_list.push_back((user_data, result));
+        self.eventfd.write(1).unwrap();
+    }
+
+    // Returns the errno the request must fail with, if any.
+    fn check(&self, offset: u64, length: u64) -> Option<i32> {
+        if offset % self.logical_block_size != 0 || length % self.logical_block_size != 0 {
+            return Some(libc::EINVAL);
+        }
+        self.disk.lock().unwrap().error(offset, length)
+    }
+}
+
+fn iovecs_len(iovecs: &[libc::iovec]) -> u64 {
+    iovecs.iter().map(|iovec| iovec.iov_len as u64).sum()
+}
+
+impl AsyncIo for MemoryAsyncIo {
+    fn notifier(&self) -> &EventFd {
+        &self.eventfd
+    }
+
+    fn required_alignment(&self) -> IoAlignment {
+        IoAlignment {
+            offset: self.logical_block_size,
+            memory: 1,
+        }
+    }
+
+    fn submission_room(&self) -> usize {
+        completion_room(self.completion_list.len())
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        check_completion_room(self.completion_list.len())?;
+        let offset = offset as u64;
+        if let Some(errno) = self.check(offset, iovecs_len(iovecs)) {
+            self.complete(user_data, -errno);
+            return Ok(());
+        }
+
+        let disk = self.disk.lock().unwrap();
+        // Reads stop at the end of the disk, as with a file.
+        let mut pos = cmp::min(offset, disk.data.len() as u64) as usize;
+        let start = pos;
+        for iovec in iovecs {
+            let len = cmp::min(iovec.iov_len, disk.data.len() - pos);
+            // SAFETY: the iovecs point to memory valid for the duration of
+            // the request.
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    disk.data[pos..].as_ptr(),
+                    iovec.iov_base as *mut u8,
+                    len,
+                )
+            };
+            pos += len;
+        }
+        drop(disk);
+
+        self.complete(user_data, (pos - start) as i32);
+
+        Ok(())
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        check_completion_room(self.completion_list.len())?;
+        let offset = offset as u64;
+        let length = iovecs_len(iovecs);
+        if let Some(errno) = self.check(offset, length) {
+            self.complete(user_data, -errno);
+            return Ok(());
+        }
+
+        let mut disk = self.disk.lock().unwrap();
+        let end = (offset + length) as usize;
+        if disk.data.len() < end {
+            disk.data.resize(end, 0);
+        }
+        let mut pos = offset as usize;
+        for iovec in iovecs {
+            // SAFETY: the iovecs point to memory valid for the duration of
+            // the request.
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    iovec.iov_base as *const u8,
+                    disk.data[pos..].as_mut_ptr(),
+                    iovec.iov_len,
+                )
+            };
+            pos += iovec.iov_len;
+        }
+        drop(disk);
+
+        self.complete(user_data, length as i32);
+
+        Ok(())
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        if let Some(user_data) = user_data {
+            check_completion_room(self.completion_list.len())?;
+            self.complete(user_data, 0);
+        }
+
+        Ok(())
+    }
+
+    fn discard(&mut self, offset: libc::off_t, length: u64, user_data: u64) -> AsyncIoResult<()> {
+        check_completion_room(self.completion_list.len())?;
+        let offset = offset as u64;
+        let result = match self.check(offset, length) {
+            Some(errno) => -errno,
+            None => {
+                self.disk.lock().unwrap().zero(offset, length);
+                0
+            }
+        };
+        self.complete(user_data, result);
+
+        Ok(())
+    }
+
+    fn write_zeroes(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        _unmap: bool,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        check_completion_room(self.completion_list.len())?;
+        let offset = offset as u64;
+        let result = match self.check(offset, length) {
+            Some(errno) => -errno,
+            None => {
+                self.disk.lock().unwrap().zero(offset, length);
+                length as i32
+            }
+        };
+        self.complete(user_data, result);
+
+        Ok(())
+    }
+
+    fn next_completed_request(&mut self) -> Option<(u64, i32)> {
+        self.completion_list.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn iovec(buf: &mut [u8]) -> libc::iovec {
+        libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }
+    }
+
+    #[test]
+    fn test_memory_disk() {
+        let mut disk = MemoryDiskFile::new(8192, 512);
+        assert_eq!(disk.size().unwrap(), 8192);
+        assert_eq!(disk.topology().logical_block_size, 512);
+
+        let mut io = disk.new_async_io(1).unwrap();
+        let mut data = vec![0xaau8; 1024];
+        io.write_vectored(512, &[iovec(&mut data[..512]), iovec(&mut data[512..])], 1)
+            .unwrap();
+        assert_eq!(io.next_completed_request(), Some((1, 1024)));
+
+        let mut buf = vec![0u8; 2048];
+        io.read_vectored(0, &[iovec(&mut buf)], 2).unwrap();
+        assert_eq!(io.next_completed_request(), Some((2, 2048)));
+        assert!(buf[..512].iter().all(|b| *b == 0));
+        assert!(buf[512..1536].iter().all(|b| *b == 0xaa));
+        assert!(buf[1536..].iter().all(|b| *b == 0));
+
+        io.write_zeroes(1024, 512, false, 3).unwrap();
+        io.discard(512, 512, 4).unwrap();
+        io.fsync(Some(5)).unwrap();
+        assert_eq!(io.next_completed_request(), Some((3, 512)));
+        assert_eq!(io.next_completed_request(), Some((4, 0)));
+        assert_eq!(io.next_completed_request(), Some((5, 0)));
+        assert_eq!(io.next_completed_request(), None);
+        assert_eq!(io.notifier().read().unwrap(), 5);
+        assert!(disk.data().iter().all(|b| *b == 0));
+
+        // Writes past the end grow the disk, reads past it are short.
+        io.write_vectored(8192, &[iovec(&mut data)], 6).unwrap();
+        assert_eq!(io.next_completed_request(), Some((6, 1024)));
+        assert_eq!(disk.size().unwrap(), 9216);
+        io.read_vectored(8704, &[iovec(&mut buf)], 7).unwrap();
+        assert_eq!(io.next_completed_request(), Some((7, 512)));
+        assert!(buf[..512].iter().all(|b| *b == 0xaa));
+    }
+
+    #[test]
+    fn test_memory_disk_errors() {
+        let disk = MemoryDiskFile::new(8192, 4096);
+        let mut io = disk.new_async_io(1).unwrap();
+        let mut buf = vec![0u8; 4096];
+        assert_eq!(
+            io.required_alignment(),
+            IoAlignment {
+                offset: 4096,
+                memory: 1
+            }
+        );
+
+        // Unaligned requests are rejected.
+        io.read_vectored(512, &[iovec(&mut buf)], 1).unwrap();
+        io.write_vectored(0, &[iovec(&mut buf[..512])], 2).unwrap();
+        assert_eq!(io.next_completed_request(), Some((1, -libc::EINVAL)));
+        assert_eq!(io.next_completed_request(), Some((2, -libc::EINVAL)));
+
+        disk.inject_error(5000, 1, libc::EIO);
+        io.read_vectored(0, &[iovec(&mut buf)], 3).unwrap();
+        io.read_vectored(4096, &[iovec(&mut buf)], 4).unwrap();
+        io.write_zeroes(0, 8192, false, 5).unwrap();
+        assert_eq!(io.next_completed_request(), Some((3, 4096)));
+        assert_eq!(io.next_completed_request(), Some((4, -libc::EIO)));
+        assert_eq!(io.next_completed_request(), Some((5, -libc::EIO)));
+
+        disk.clear_errors();
+        io.read_vectored(4096, &[iovec(&mut buf)], 6).unwrap();
+        assert_eq!(io.next_completed_request(), Some((6, 4096)));
+    }
+}
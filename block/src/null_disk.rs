@@ -0,0 +1,250 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! [`DiskFile`] without any backing storage, meant to measure the overhead
+//! of the block device path. Reads return zeroes and writes are dropped.
+
+use crate::async_io::{
+    check_completion_room, completion_room, AsyncIo, AsyncIoResult, DiskExtent, DiskFile,
+    DiskFileResult,
+};
+use std::collections::VecDeque;
+use std::time::Duration;
+use vmm_sys_util::eventfd::EventFd;
+
+pub struct NullDiskFile {
+    size: u64,
+    latency: Option<Duration>,
+}
+
+impl NullDiskFile {
+    /// Creates a disk of `size` bytes. With a `latency`, every request
+    /// blocks for that long before completing, modelling slow storage.
+    pub fn new(size: u64, latency: Option<Duration>) -> Self {
+        NullDiskFile { size, latency }
+    }
+}
+
+impl DiskFile for NullDiskFile {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        Ok(self.size)
+    }
+
+    fn new_async_io(&self, _ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        Ok(Box::new(NullAsyncIo::new(self.latency)) as Box<dyn AsyncIo>)
+    }
+
+    fn supports_discard(&self) -> bool {
+        true
+    }
+
+    fn supports_write_zeroes(&self) -> bool {
+        true
+    }
+
+    fn discard_zeroes(&self) -> bool {
+        true
+    }
+
+    fn extents(&mut self) -> DiskFileResult<Vec<DiskExtent>> {
+        // Nothing is ever stored.
+        Ok(Vec::new())
+    }
+}
+
+pub struct NullAsyncIo {
+    latency: Option<Duration>,
+    eventfd: EventFd,
+    completion_list: VecDeque<(u64, i32)>,
+}
+
+impl NullAsyncIo {
+    fn new(latency: Option<Duration>) -> Self {
+        NullAsyncIo {
+            latency,
+            eventfd: EventFd::new(libc::EFD_NONBLOCK)
+                .expect("Failed creating EventFd for NullAsyncIo"),
+            completion_list: VecDeque::new(),
+        }
+    }
+
+    fn complete(&mut self, user_data: u64, result: i32) {
+        if let Some(latency) = self.latency {
+            std::thread::sleep(latency);
+        }
+
+        self.completion_list.push_back((user_data, result));
+        self.eventfd.write(1).unwrap();
+    }
+}
+
+fn iovecs_len(iovecs: &[libc::iovec]) -> i32 {
+    iovecs.iter().map(|iovec| iovec.iov_len).sum::<usize>() as i32
+}
+
+impl AsyncIo for NullAsyncIo {
+    fn notifier(&self) -> &EventFd {
+        &self.eventfd
+    }
+
+    fn submission_room(&self) -> usize {
+        completion_room(self.completion_list.len())
+    }
+
+    fn read_vectored(
+        &mut self,
+        _offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        check_completion_room(self.completion_list.len())?;
+        for iovec in iovecs {
+            // SAFETY: the iovecs point to memory valid for the duration of
+            // the request.
+            unsafe { std::ptr::write_bytes(iovec.iov_base as *mut u8, 0, iovec.iov_len) };
+        }
+
+        self.complete(user_data, iovecs_len(iovecs));
+
+        Ok(())
+    }
+
+    fn write_vectored(
+        &mut self,
+        _offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        check_completion_room(self.completion_list.len())?;
+        self.complete(user_data, iovecs_len(iovecs));
+
+        Ok(())
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        if let Some(user_data) = user_data {
+            check_completion_room(self.completion_list.len())?;
+            self.complete(user_data, 0);
+        }
+
+        Ok(())
+    }
+
+    fn discard(&mut self, _offset: libc::off_t, _length: u64, user_data: u64) -> AsyncIoResult<()> {
+        check_completion_room(self.completion_list.len())?;
+        self.complete(user_data, 0);
+
+        Ok(())
+    }
+
+    fn write_zeroes(
+        &mut self,
+        _offset: libc::off_t,
+        length: u64,
+        _unmap: bool,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        check_completion_room(self.completion_list.len())?;
+        self.complete(user_data, length as i32);
+
+        Ok(())
+    }
+
+    fn next_completed_request(&mut self) -> Option<(u64, i32)> {
+        self.completion_list.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_io::{AsyncIoError, MAX_PENDING_COMPLETIONS};
+    use std::time::Instant;
+
+    fn iovec(buf: &mut [u8]) -> libc::iovec {
+        libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }
+    }
+
+    #[test]
+    fn test_null_disk() {
+        let mut disk = NullDiskFile::new(1 << 30, None);
+        assert_eq!(disk.size().unwrap(), 1 << 30);
+        assert!(disk.extents().unwrap().is_empty());
+
+        let mut io = disk.new_async_io(1).unwrap();
+        let mut data = vec![0xffu8; 4096];
+        io.write_vectored(0, &[iovec(&mut data)], 1).unwrap();
+        assert_eq!(io.next_completed_request(), Some((1, 4096)));
+
+        // Writes are dropped.
+        io.read_vectored(0, &[iovec(&mut data[..512]), iovec(&mut data[1024..])], 2)
+            .unwrap();
+        assert_eq!(io.next_completed_request(), Some((2, 3584)));
+        assert!(data[..512].iter().all(|b| *b == 0));
+        assert!(data[512..1024].iter().all(|b| *b == 0xff));
+        assert!(data[1024..].iter().all(|b| *b == 0));
+
+        io.fsync(Some(3)).unwrap();
+        io.discard(0, 4096, 4).unwrap();
+        io.write_zeroes(0, 4096, true, 5).unwrap();
+        assert_eq!(io.next_completed_request(), Some((3, 0)));
+        assert_eq!(io.next_completed_request(), Some((4, 0)));
+        assert_eq!(io.next_completed_request(), Some((5, 4096)));
+        assert_eq!(io.next_completed_request(), None);
+        assert_eq!(io.notifier().read().unwrap(), 5);
+    }
+
+    #[test]
+    fn test_null_disk_completions_bounded() {
+        let disk = NullDiskFile::new(1 << 20, None);
+        let mut io = disk.new_async_io(1).unwrap();
+        let mut data = vec![0u8; 512];
+
+        // Without the completions being reaped, the requests past the limit
+        // are refused instead of piling up.
+        for user_data in 0..MAX_PENDING_COMPLETIONS as u64 {
+            assert_eq!(
+                io.submission_room(),
+                MAX_PENDING_COMPLETIONS - user_data as usize
+            );
+            io.write_vectored(0, &[iovec(&mut data)], user_data)
+                .unwrap();
+        }
+        assert_eq!(io.submission_room(), 0);
+        assert!(matches!(
+            io.read_vectored(0, &[iovec(&mut data)], 0),
+            Err(AsyncIoError::CompletionsFull)
+        ));
+        assert!(matches!(
+            io.fsync(Some(0)),
+            Err(AsyncIoError::CompletionsFull)
+        ));
+        // A flush without a request to complete adds no completion.
+        io.fsync(None).unwrap();
+
+        // Reaping a completion makes room for one more request.
+        assert_eq!(io.next_completed_request(), Some((0, 512)));
+        assert_eq!(io.submission_room(), 1);
+        io.discard(0, 512, 1).unwrap();
+        assert!(matches!(
+            io.write_zeroes(0, 512, false, 2),
+            Err(AsyncIoError::CompletionsFull)
+        ));
+    }
+
+    #[test]
+    fn test_null_disk_latency() {
+        let latency = Duration::from_millis(10);
+        let disk = NullDiskFile::new(1 << 20, Some(latency));
+        let mut io = disk.new_async_io(1).unwrap();
+
+        let start = Instant::now();
+        io.fsync(Some(1)).unwrap();
+        assert!(start.elapsed() >= latency);
+        assert_eq!(io.next_completed_request(), Some((1, 0)));
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Host NUMA topology, letting the threads serving a disk run on the node
+//! holding the guest memory they access.
+
+use std::io;
+
+// See include/uapi/linux/mempolicy.h in the kernel code.
+const MPOL_F_NODE: libc::c_ulong = 1 << 0;
+const MPOL_F_ADDR: libc::c_ulong = 1 << 1;
+
+const SYSFS_NODE_DIR: &str = "/sys/devices/system/node";
+
+// Parses a list of ranges as printed by the kernel, such as "0-3,8,10-11".
+fn parse_list(list: &str) -> Option<Vec<usize>> {
+    let mut items = Vec::new();
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        let (start, end): (usize, usize) = match range.split_once('-') {
+            Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+            None => {
+                let item = range.parse().ok()?;
+                (item, item)
+            }
+        };
+        if start > end {
+            return None;
+        }
+        items.extend(start..=end);
+    }
+
+    Some(items)
+}
+
+fn read_list(path: &str) -> io::Result<Vec<usize>> {
+    let list = std::fs::read_to_string(path)?;
+    parse_list(&list).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid list in {path}: {list}"),
+        )
+    })
+}
+
+/// Returns the NUMA nodes of the host, a host without NUMA support having
+/// a single one.
+pub fn host_nodes() -> Vec<u32> {
+    read_list(&format!("{SYSFS_NODE_DIR}/online"))
+        .map(|nodes| nodes.into_iter().map(|node| node as u32).collect())
+        .unwrap_or_else(|_| vec![0])
+}
+
+/// Returns the CPUs of the host NUMA node `node`.
+pub fn node_cpus(node: u32) -> io::Result<Vec<usize>> {
+    read_list(&format!("{SYSFS_NODE_DIR}/node{node}/cpulist"))
+}
+
+/// Returns the host NUMA node the memory at `addr` is allocated from,
+/// allocating it according to the memory policy if it isn't yet.
+pub fn memory_node(addr: *const u8) -> io::Result<u32> {
+    let mut node: libc::c_int = 0;
+    // SAFETY: FFI call with a valid pointer to an int and no node mask,
+    // the address only being looked up.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_get_mempolicy,
+            &mut node as *mut libc::c_int,
+            std::ptr::null_mut::<libc::c_ulong>(),
+            0 as libc::c_ulong,
+            addr as libc::c_ulong,
+            MPOL_F_NODE | MPOL_F_ADDR,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(node as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_list() {
+        assert_eq!(parse_list("0\n"), Some(vec![0]));
+        assert_eq!(
+            parse_list("0-3,8,10-11\n"),
+            Some(vec![0, 1, 2, 3, 8, 10, 11])
+        );
+        assert_eq!(parse_list("\n"), Some(vec![]));
+        assert_eq!(parse_list("3-1"), None);
+        assert_eq!(parse_list("0-a"), None);
+    }
+
+    #[test]
+    fn test_memory_node() {
+        let nodes = host_nodes();
+        assert!(!nodes.is_empty());
+
+        let buf = vec![1u8; 4096];
+        // The host kernel may be built without NUMA support.
+        if let Ok(node) = memory_node(buf.as_ptr()) {
+            assert!(nodes.contains(&node));
+        }
+    }
+}
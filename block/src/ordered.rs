@@ -0,0 +1,331 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Completion of the requests to a disk in the order they were submitted,
+//! whatever order the backend completes them in. Meant for debugging, so
+//! that a failure depending on the completion order can be reproduced, at
+//! the cost of a request waiting for all those submitted before it.
+
+use crate::async_io::{
+    AsyncIo, AsyncIoResult, DiskExtent, DiskFile, DiskFileResult, IoAlignment, RequestPriority,
+};
+use crate::lifetime::DiskLifetime;
+use crate::scsi::{ScsiCommand, ScsiResponse};
+use crate::zoned::BlkZone;
+use crate::{AsyncIoBackend, DiskTopology};
+use std::collections::{HashMap, VecDeque};
+use vmm_sys_util::eventfd::EventFd;
+
+/// [`DiskFile`] whose [`AsyncIo`] instances complete the requests in the
+/// order they were submitted.
+pub struct OrderedDisk {
+    inner: Box<dyn DiskFile>,
+}
+
+impl OrderedDisk {
+    pub fn new(inner: Box<dyn DiskFile>) -> Self {
+        OrderedDisk { inner }
+    }
+}
+
+impl DiskFile for OrderedDisk {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        self.inner.size()
+    }
+
+    fn new_async_io(&self, ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        Ok(Box::new(OrderedAsyncIo {
+            inner: self.inner.new_async_io(ring_depth)?,
+            submitted: VecDeque::new(),
+            completed: HashMap::new(),
+        }) as Box<dyn AsyncIo>)
+    }
+
+    fn topology(&mut self) -> DiskTopology {
+        self.inner.topology()
+    }
+
+    fn supports_discard(&self) -> bool {
+        self.inner.supports_discard()
+    }
+
+    fn supports_write_zeroes(&self) -> bool {
+        self.inner.supports_write_zeroes()
+    }
+
+    fn discard_zeroes(&self) -> bool {
+        self.inner.discard_zeroes()
+    }
+
+    fn serial(&mut self) -> Option<String> {
+        self.inner.serial()
+    }
+
+    fn rotational(&mut self) -> bool {
+        self.inner.rotational()
+    }
+
+    fn get_lifetime(&mut self) -> DiskLifetime {
+        self.inner.get_lifetime()
+    }
+
+    fn available_space(&self) -> DiskFileResult<Option<u64>> {
+        self.inner.available_space()
+    }
+
+    fn backend(&self) -> AsyncIoBackend {
+        self.inner.backend()
+    }
+
+    fn resize(&mut self, current_size: u64) -> DiskFileResult<u64> {
+        self.inner.resize(current_size)
+    }
+
+    fn extents(&mut self) -> DiskFileResult<Vec<DiskExtent>> {
+        self.inner.extents()
+    }
+
+    fn start_dirty_tracking(&mut self, granularity: u64) -> DiskFileResult<()> {
+        self.inner.start_dirty_tracking(granularity)
+    }
+
+    fn stop_dirty_tracking(&mut self) {
+        self.inner.stop_dirty_tracking()
+    }
+
+    fn get_dirty_blocks(&mut self) -> DiskFileResult<Vec<DiskExtent>> {
+        self.inner.get_dirty_blocks()
+    }
+
+    fn send_scsi_command(&mut self, command: &ScsiCommand) -> DiskFileResult<ScsiResponse> {
+        self.inner.send_scsi_command(command)
+    }
+}
+
+/// [`AsyncIo`] holding the completions of the backend until those of the
+/// requests submitted before them have been returned.
+pub struct OrderedAsyncIo {
+    inner: Box<dyn AsyncIo>,
+    // User data of the requests in flight, in submission order.
+    submitted: VecDeque<u64>,
+    // Results of the requests completed by the backend ahead of their turn.
+    completed: HashMap<u64, i32>,
+}
+
+impl OrderedAsyncIo {
+    // Records a request once the backend accepted it.
+    fn track(&mut self, user_data: u64, result: AsyncIoResult<()>) -> AsyncIoResult<()> {
+        if result.is_ok() {
+            self.submitted.push_back(user_data);
+        }
+        result
+    }
+}
+
+impl AsyncIo for OrderedAsyncIo {
+    fn notifier(&self) -> &EventFd {
+        self.inner.notifier()
+    }
+
+    fn required_alignment(&self) -> IoAlignment {
+        self.inner.required_alignment()
+    }
+
+    fn submission_room(&self) -> usize {
+        self.inner.submission_room()
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let result = self.inner.read_vectored(offset, iovecs, user_data);
+        self.track(user_data, result)
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let result = self.inner.write_vectored(offset, iovecs, user_data);
+        self.track(user_data, result)
+    }
+
+    fn write_vectored_fua(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let result = self.inner.write_vectored_fua(offset, iovecs, user_data);
+        self.track(user_data, result)
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        let result = self.inner.fsync(user_data);
+        // The synchronous flushes return no completion.
+        match user_data {
+            Some(user_data) => self.track(user_data, result),
+            None => result,
+        }
+    }
+
+    fn read_vectored_with_priority(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+        priority: RequestPriority,
+    ) -> AsyncIoResult<()> {
+        let result = self
+            .inner
+            .read_vectored_with_priority(offset, iovecs, user_data, priority);
+        self.track(user_data, result)
+    }
+
+    fn write_vectored_with_priority(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+        priority: RequestPriority,
+    ) -> AsyncIoResult<()> {
+        let result = self
+            .inner
+            .write_vectored_with_priority(offset, iovecs, user_data, priority);
+        self.track(user_data, result)
+    }
+
+    fn fsync_with_priority(
+        &mut self,
+        user_data: Option<u64>,
+        priority: RequestPriority,
+    ) -> AsyncIoResult<()> {
+        let result = self.inner.fsync_with_priority(user_data, priority);
+        match user_data {
+            Some(user_data) => self.track(user_data, result),
+            None => result,
+        }
+    }
+
+    fn register_buffers(&mut self, regions: &[libc::iovec]) -> AsyncIoResult<bool> {
+        self.inner.register_buffers(regions)
+    }
+
+    fn discard(&mut self, offset: libc::off_t, length: u64, user_data: u64) -> AsyncIoResult<()> {
+        let result = self.inner.discard(offset, length, user_data);
+        self.track(user_data, result)
+    }
+
+    fn write_zeroes(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        unmap: bool,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let result = self.inner.write_zeroes(offset, length, unmap, user_data);
+        self.track(user_data, result)
+    }
+
+    fn zone_report(
+        &mut self,
+        offset: libc::off_t,
+        zones: &mut [BlkZone],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let result = self.inner.zone_report(offset, zones, user_data);
+        self.track(user_data, result)
+    }
+
+    fn zone_open(&mut self, offset: libc::off_t, user_data: u64) -> AsyncIoResult<()> {
+        let result = self.inner.zone_open(offset, user_data);
+        self.track(user_data, result)
+    }
+
+    fn zone_close(&mut self, offset: libc::off_t, user_data: u64) -> AsyncIoResult<()> {
+        let result = self.inner.zone_close(offset, user_data);
+        self.track(user_data, result)
+    }
+
+    fn zone_finish(&mut self, offset: libc::off_t, user_data: u64) -> AsyncIoResult<()> {
+        let result = self.inner.zone_finish(offset, user_data);
+        self.track(user_data, result)
+    }
+
+    fn zone_reset(&mut self, offset: libc::off_t, user_data: u64) -> AsyncIoResult<()> {
+        let result = self.inner.zone_reset(offset, user_data);
+        self.track(user_data, result)
+    }
+
+    fn cancel(&mut self, user_data: u64) -> AsyncIoResult<bool> {
+        self.inner.cancel(user_data)
+    }
+
+    fn submit(&mut self) -> AsyncIoResult<()> {
+        self.inner.submit()
+    }
+
+    fn next_completed_request(&mut self) -> Option<(u64, i32)> {
+        while let Some((user_data, result)) = self.inner.next_completed_request() {
+            self.completed.insert(user_data, result);
+        }
+
+        // Until the oldest request completes, which signals the notifier
+        // again, the later ones are held.
+        let user_data = *self.submitted.front()?;
+        let result = self.completed.remove(&user_data)?;
+        self.submitted.pop_front();
+
+        Some((user_data, result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fault_injection::{FaultInjectingDisk, FaultPlan};
+    use crate::null_disk::NullDiskFile;
+
+    fn write(io: &mut dyn AsyncIo, offset: libc::off_t, user_data: u64) {
+        let mut data = vec![0u8; 4096];
+        let iovec = libc::iovec {
+            iov_base: data.as_mut_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        io.write_vectored(offset, &[iovec], user_data).unwrap();
+    }
+
+    #[test]
+    fn test_ordered_completions() {
+        // The injected faults complete ahead of the requests let through.
+        let disk = OrderedDisk::new(Box::new(FaultInjectingDisk::new(
+            Box::new(NullDiskFile::new(1 << 20, None)),
+            FaultPlan {
+                offsets: vec![8192],
+                ..Default::default()
+            },
+        )));
+        let mut io = disk.new_async_io(1).unwrap();
+
+        write(io.as_mut(), 0, 1);
+        write(io.as_mut(), 8192, 2);
+        write(io.as_mut(), 4096, 3);
+        io.fsync(Some(4)).unwrap();
+        io.fsync(None).unwrap();
+
+        let mut completions = Vec::new();
+        while let Some(completion) = io.next_completed_request() {
+            completions.push(completion);
+        }
+        assert_eq!(
+            completions,
+            vec![(1, 4096), (2, -libc::EIO), (3, 4096), (4, 0)]
+        );
+    }
+}
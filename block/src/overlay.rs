@@ -0,0 +1,789 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Copy-on-write overlay on top of a read-only base [`DiskFile`].
+//!
+//! Writes land in a scratch file, at the same offset as in the disk, and the
+//! blocks they cover are marked dirty in an in-memory bitmap. Reads of clean
+//! blocks are served by the base, reads of dirty blocks by the scratch file.
+//! Unless persisted, the scratch content is meaningless once the overlay is
+//! gone and the file is truncated, making the overlay disposable.
+//!
+//! A persistent overlay stores a header past the data, at the next
+//! [`HEADER_ALIGNMENT`] boundary, followed by the bitmap, one little-endian
+//! 64-bit word per 64 blocks. The header records the base image it was
+//! created on top of, so that reopening the scratch file against another
+//! base, which would hand the guest a mix of both, is refused:
+//!
+//! | Offset | Size | Field                                            |
+//! |--------|------|--------------------------------------------------|
+//! | 0      | 8    | Magic, `CHOVRLAY`                                |
+//! | 8      | 4    | Format version, currently 1                      |
+//! | 12     | 4    | Block size                                       |
+//! | 16     | 8    | Base image size                                  |
+//! | 24     | 8    | Bitmap offset in the scratch file                |
+//! | 32     | 4    | Base image identity, CRC32C of its serial and    |
+//! |        |      | first [`IDENTITY_SIZE`] bytes                    |
+//! | 36     | 4    | CRC32C of the previous bytes                     |
+//!
+//! The bitmap is updated along with the data and flushed with it, so that
+//! the blocks written before the last flush are recovered on reopening.
+
+use crate::async_io::{
+    check_completion_room, completion_room, AsyncIo, AsyncIoError, AsyncIoResult, DiskFile,
+    DiskFileError, DiskFileResult, IoAlignment,
+};
+use crate::scrubber::checksum;
+use crate::{error_result, AsyncIoBackend, DiskTopology};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use vmm_sys_util::eventfd::EventFd;
+
+// Size of the chunks copied by flatten().
+const FLATTEN_CHUNK_SIZE: u64 = 1 << 20;
+
+const OVERLAY_MAGIC: [u8; 8] = *b"CHOVRLAY";
+const OVERLAY_VERSION: u32 = 1;
+const HEADER_SIZE: usize = 40;
+/// Alignment of the header and of the bitmap of a persistent overlay.
+pub const HEADER_ALIGNMENT: u64 = 4096;
+/// Number of bytes at the start of the base image identifying it.
+pub const IDENTITY_SIZE: u64 = 1 << 16;
+
+// Header of the scratch file of a persistent overlay.
+#[derive(Debug, PartialEq, Eq)]
+struct OverlayHeader {
+    block_size: u32,
+    base_size: u64,
+    bitmap_offset: u64,
+    base_identity: u32,
+}
+
+impl OverlayHeader {
+    fn parse(bytes: &[u8; HEADER_SIZE]) -> DiskFileResult<Self> {
+        if bytes[0..8] != OVERLAY_MAGIC
+            || u32::from_le_bytes(bytes[36..40].try_into().unwrap()) != checksum(&bytes[..36])
+        {
+            return Err(DiskFileError::InvalidOverlayHeader);
+        }
+        let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+        if version != OVERLAY_VERSION {
+            return Err(DiskFileError::UnsupportedOverlayVersion(version));
+        }
+
+        Ok(OverlayHeader {
+            block_size: u32::from_le_bytes(bytes[12..16].try_into().unwrap()),
+            base_size: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+            bitmap_offset: u64::from_le_bytes(bytes[24..32].try_into().unwrap()),
+            base_identity: u32::from_le_bytes(bytes[32..36].try_into().unwrap()),
+        })
+    }
+
+    fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+        bytes[0..8].copy_from_slice(&OVERLAY_MAGIC);
+        bytes[8..12].copy_from_slice(&OVERLAY_VERSION.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.block_size.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.base_size.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.bitmap_offset.to_le_bytes());
+        bytes[32..36].copy_from_slice(&self.base_identity.to_le_bytes());
+        let crc = checksum(&bytes[..36]);
+        bytes[36..40].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+}
+
+// Dirty block bitmap, shared by the queues of the disk.
+struct DirtyBitmap {
+    bits: Vec<AtomicU64>,
+}
+
+impl DirtyBitmap {
+    fn new(nr_blocks: u64) -> Self {
+        DirtyBitmap {
+            bits: (0..nr_blocks.div_ceil(64))
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+        }
+    }
+
+    fn is_dirty(&self, block: u64) -> bool {
+        self.bits
+            .get((block / 64) as usize)
+            .map(|word| word.load(Ordering::Acquire) & (1 << (block % 64)) != 0)
+            .unwrap_or(false)
+    }
+
+    fn set_dirty(&self, blocks: std::ops::Range<u64>) {
+        for block in blocks {
+            if let Some(word) = self.bits.get((block / 64) as usize) {
+                word.fetch_or(1 << (block % 64), Ordering::Release);
+            }
+        }
+    }
+
+    fn to_bytes(&self, words: std::ops::Range<usize>) -> Vec<u8> {
+        self.bits[words]
+            .iter()
+            .flat_map(|word| word.load(Ordering::Acquire).to_le_bytes())
+            .collect()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        DirtyBitmap {
+            bits: bytes
+                .chunks(8)
+                .map(|word| AtomicU64::new(u64::from_le_bytes(word.try_into().unwrap())))
+                .collect(),
+        }
+    }
+}
+
+// Scratch file, truncated once neither the disk nor its queues use it,
+// unless the overlay is persistent.
+struct Scratch {
+    file: File,
+    // Offset of the bitmap of a persistent overlay.
+    bitmap_offset: Option<u64>,
+    // Serializes the updates of the persisted bitmap, so that the words
+    // written last hold the bits set by all the queues.
+    bitmap_lock: Mutex<()>,
+}
+
+impl Scratch {
+    // Writes the words of the bitmap covering `blocks` to a persistent
+    // overlay.
+    fn persist(&self, bitmap: &DirtyBitmap, blocks: std::ops::Range<u64>) -> io::Result<()> {
+        let Some(bitmap_offset) = self.bitmap_offset else {
+            return Ok(());
+        };
+        if blocks.is_empty() {
+            return Ok(());
+        }
+
+        let words = (blocks.start / 64) as usize..blocks.end.div_ceil(64) as usize;
+        let _guard = self.bitmap_lock.lock().unwrap();
+        self.file.write_all_at(
+            &bitmap.to_bytes(words.clone()),
+            bitmap_offset + words.start as u64 * 8,
+        )
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        if self.bitmap_offset.is_some() {
+            return;
+        }
+        if let Err(e) = self.file.set_len(0) {
+            warn!("Failed to discard the overlay scratch file: {}", e);
+        }
+    }
+}
+
+// Returns the identity of `base`, which must be a read-only image.
+fn base_identity(base: &mut dyn DiskFile, size: u64) -> DiskFileResult<u32> {
+    let mut io = base.new_async_io(1)?;
+    let mut data = base.serial().unwrap_or_default().into_bytes();
+    let serial_len = data.len();
+    data.resize(serial_len + IDENTITY_SIZE.min(size) as usize, 0);
+    read_sync(io.as_mut(), 0, &mut data[serial_len..])
+        .map_err(DiskFileError::OverlayBaseIdentity)?;
+
+    Ok(checksum(&data))
+}
+
+pub struct OverlayDiskFile {
+    base: Box<dyn DiskFile>,
+    scratch: Arc<Scratch>,
+    bitmap: Arc<DirtyBitmap>,
+    block_size: u64,
+    size: u64,
+}
+
+impl OverlayDiskFile {
+    /// Creates an overlay on top of `base`, which is never written to,
+    /// storing the written blocks in `scratch`.
+    pub fn new(mut base: Box<dyn DiskFile>, scratch: File) -> DiskFileResult<Self> {
+        let size = base.size()?;
+        let block_size = base.topology().logical_block_size;
+
+        Ok(OverlayDiskFile {
+            base,
+            scratch: Arc::new(Scratch {
+                file: scratch,
+                bitmap_offset: None,
+                bitmap_lock: Mutex::new(()),
+            }),
+            bitmap: Arc::new(DirtyBitmap::new(size.div_ceil(block_size))),
+            block_size,
+            size,
+        })
+    }
+
+    /// Opens a persistent overlay on top of `base`, which is never written
+    /// to, storing the written blocks in `scratch` along with the header and
+    /// the bitmap needed to reopen it. An empty `scratch` is initialized,
+    /// while one created on top of another base image is refused.
+    pub fn open(mut base: Box<dyn DiskFile>, scratch: File) -> DiskFileResult<Self> {
+        let size = base.size()?;
+        let block_size = base.topology().logical_block_size;
+        let nr_blocks = size.div_ceil(block_size);
+        let bitmap_len = nr_blocks.div_ceil(64) * 8;
+        let base_identity = base_identity(base.as_mut(), size)?;
+
+        let header_offset = size.next_multiple_of(HEADER_ALIGNMENT);
+        let scratch_len = scratch
+            .metadata()
+            .map_err(DiskFileError::OverlayScratch)?
+            .len();
+
+        let (header, bitmap) = if scratch_len == 0 {
+            let header = OverlayHeader {
+                block_size: block_size as u32,
+                base_size: size,
+                bitmap_offset: header_offset + HEADER_ALIGNMENT,
+                base_identity,
+            };
+            scratch
+                .write_all_at(&header.to_bytes(), header_offset)
+                .and_then(|_| scratch.set_len(header.bitmap_offset + bitmap_len))
+                .and_then(|_| scratch.sync_all())
+                .map_err(DiskFileError::OverlayScratch)?;
+
+            (header, DirtyBitmap::new(nr_blocks))
+        } else {
+            // The header is only looked for where the size of the base puts
+            // it, a scratch file created on top of a larger base holding
+            // data there instead.
+            if scratch_len < header_offset + HEADER_SIZE as u64 {
+                return Err(DiskFileError::OverlayBaseMismatch);
+            }
+            let mut bytes = [0u8; HEADER_SIZE];
+            scratch
+                .read_exact_at(&mut bytes, header_offset)
+                .map_err(DiskFileError::OverlayScratch)?;
+            let header = OverlayHeader::parse(&bytes)?;
+            if header.base_size != size
+                || header.block_size as u64 != block_size
+                || header.base_identity != base_identity
+            {
+                return Err(DiskFileError::OverlayBaseMismatch);
+            }
+            if header.bitmap_offset < header_offset + HEADER_SIZE as u64
+                || scratch_len < header.bitmap_offset + bitmap_len
+            {
+                return Err(DiskFileError::InvalidOverlayHeader);
+            }
+
+            let mut bytes = vec![0u8; bitmap_len as usize];
+            scratch
+                .read_exact_at(&mut bytes, header.bitmap_offset)
+                .map_err(DiskFileError::OverlayScratch)?;
+
+            (header, DirtyBitmap::from_bytes(&bytes))
+        };
+
+        Ok(OverlayDiskFile {
+            base,
+            scratch: Arc::new(Scratch {
+                file: scratch,
+                bitmap_offset: Some(header.bitmap_offset),
+                bitmap_lock: Mutex::new(()),
+            }),
+            bitmap: Arc::new(bitmap),
+            block_size,
+            size,
+        })
+    }
+
+    /// Writes the content of the disk, merging the base and the overlay,
+    /// into `dest` which becomes a standalone image.
+    pub fn flatten(&mut self, dest: &File) -> io::Result<()> {
+        let mut base_io = self
+            .base
+            .new_async_io(1)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let mut buf = vec![0u8; FLATTEN_CHUNK_SIZE as usize];
+
+        let mut offset = 0;
+        while offset < self.size {
+            let len = FLATTEN_CHUNK_SIZE.min(self.size - offset);
+            let chunk = &mut buf[..len as usize];
+
+            read_sync(base_io.as_mut(), offset, chunk)?;
+            for (start, end) in dirty_runs(&self.bitmap, self.block_size, offset, len) {
+                self.scratch.file.read_exact_at(
+                    &mut chunk[(start - offset) as usize..(end - offset) as usize],
+                    start,
+                )?;
+            }
+            dest.write_all_at(chunk, offset)?;
+
+            offset += len;
+        }
+
+        dest.set_len(self.size)?;
+        dest.sync_all()
+    }
+}
+
+impl DiskFile for OverlayDiskFile {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        Ok(self.size)
+    }
+
+    fn new_async_io(&self, ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        Ok(Box::new(OverlayAsyncIo {
+            base: self.base.new_async_io(ring_depth)?,
+            scratch: self.scratch.clone(),
+            bitmap: self.bitmap.clone(),
+            block_size: self.block_size,
+            patches: HashMap::new(),
+            completion_list: VecDeque::new(),
+        }) as Box<dyn AsyncIo>)
+    }
+
+    fn topology(&mut self) -> DiskTopology {
+        self.base.topology()
+    }
+
+    fn serial(&mut self) -> Option<String> {
+        self.base.serial()
+    }
+
+    fn rotational(&mut self) -> bool {
+        self.base.rotational()
+    }
+
+    fn backend(&self) -> AsyncIoBackend {
+        self.base.backend()
+    }
+
+    fn resize(&mut self, current_size: u64) -> DiskFileResult<u64> {
+        // The bitmap is sized after the base, which never changes.
+        if self.size < current_size {
+            return Err(DiskFileError::Shrunk(current_size, self.size));
+        }
+
+        Ok(self.size)
+    }
+}
+
+// Returns the ranges of dirty blocks within [offset, offset + len).
+fn dirty_runs(bitmap: &DirtyBitmap, block_size: u64, offset: u64, len: u64) -> Vec<(u64, u64)> {
+    let mut runs: Vec<(u64, u64)> = Vec::new();
+    let first = offset / block_size;
+    let last = (offset + len).div_ceil(block_size);
+
+    for block in first..last {
+        if !bitmap.is_dirty(block) {
+            continue;
+        }
+        let start = (block * block_size).max(offset);
+        let end = ((block + 1) * block_size).min(offset + len);
+        match runs.last_mut() {
+            Some(run) if run.1 == start => run.1 = end,
+            _ => runs.push((start, end)),
+        }
+    }
+
+    runs
+}
+
+// Reads `buf` at `offset` through `io`, waiting for the completion.
+fn read_sync(io: &mut dyn AsyncIo, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    let iovec = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    io.read_vectored(offset as libc::off_t, &[iovec], 0)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    io.submit()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+    loop {
+        if let Some((_, result)) = io.next_completed_request() {
+            if result < 0 {
+                return Err(io::Error::from_raw_os_error(-result));
+            }
+            if result as usize != buf.len() {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            return Ok(());
+        }
+        io.notifier().read()?;
+    }
+}
+
+// Returns the iovecs covering `len` bytes of `iovecs`, skipping the first
+// `skip` bytes.
+pub(crate) fn sub_iovecs(
+    iovecs: &[libc::iovec],
+    mut skip: usize,
+    mut len: usize,
+) -> Vec<libc::iovec> {
+    let mut sub = Vec::new();
+    for iovec in iovecs {
+        if len == 0 {
+            break;
+        }
+        if skip >= iovec.iov_len {
+            skip -= iovec.iov_len;
+            continue;
+        }
+        let count = (iovec.iov_len - skip).min(len);
+        sub.push(libc::iovec {
+            // SAFETY: skip is within the iovec
+            iov_base: unsafe { (iovec.iov_base as *mut u8).add(skip) } as *mut libc::c_void,
+            iov_len: count,
+        });
+        skip = 0;
+        len -= count;
+    }
+
+    sub
+}
+
+fn preadv(file: &File, offset: u64, iovecs: &[libc::iovec]) -> io::Result<usize> {
+    // SAFETY: FFI call with valid arguments
+    let result = unsafe {
+        libc::preadv(
+            file.as_raw_fd(),
+            iovecs.as_ptr(),
+            iovecs.len() as libc::c_int,
+            offset as libc::off_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(result as usize)
+}
+
+pub struct OverlayAsyncIo {
+    base: Box<dyn AsyncIo>,
+    scratch: Arc<Scratch>,
+    bitmap: Arc<DirtyBitmap>,
+    block_size: u64,
+    // Base reads covering dirty blocks, to be patched from the scratch file
+    // on completion.
+    patches: HashMap<u64, (u64, Vec<libc::iovec>)>,
+    // Requests completed synchronously against the scratch file.
+    completion_list: VecDeque<(u64, i32)>,
+}
+
+// SAFETY: the iovecs point to the guest memory of in flight requests, which
+// stays valid until the requests complete.
+unsafe impl Send for OverlayAsyncIo {}
+
+impl OverlayAsyncIo {
+    fn check_alignment(&self, offset: libc::off_t, len: usize) -> io::Result<()> {
+        if offset < 0 || offset as u64 % self.block_size != 0 || len as u64 % self.block_size != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "request not aligned on the overlay block size",
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Completes a request handled against the scratch file, waking up the
+    // consumer through the notifier of the base.
+    fn complete(&mut self, user_data: u64, result: i32) {
+        self.completion_list.push_back((user_data, result));
+        self.base.notifier().write(1).unwrap();
+    }
+}
+
+impl AsyncIo for OverlayAsyncIo {
+    fn notifier(&self) -> &EventFd {
+        self.base.notifier()
+    }
+
+    fn required_alignment(&self) -> IoAlignment {
+        IoAlignment {
+            offset: self.block_size,
+            memory: 1,
+        }
+        .max(self.base.required_alignment())
+    }
+
+    fn submission_room(&self) -> usize {
+        completion_room(self.completion_list.len()).min(self.base.submission_room())
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let len: usize = iovecs.iter().map(|iovec| iovec.iov_len).sum();
+        self.check_alignment(offset, len)
+            .map_err(AsyncIoError::ReadVectored)?;
+
+        let offset = offset as u64;
+        let runs = dirty_runs(&self.bitmap, self.block_size, offset, len as u64);
+        let dirty: u64 = runs.iter().map(|(start, end)| end - start).sum();
+
+        if dirty == len as u64 {
+            check_completion_room(self.completion_list.len())?;
+            let result = preadv(&self.scratch.file, offset, iovecs)
+                .map_or_else(|e| error_result(&e), |count| count as i32);
+            self.complete(user_data, result);
+            return Ok(());
+        }
+
+        self.base
+            .read_vectored(offset as libc::off_t, iovecs, user_data)?;
+        if !runs.is_empty() {
+            self.patches.insert(user_data, (offset, iovecs.to_vec()));
+        }
+
+        Ok(())
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let len: usize = iovecs.iter().map(|iovec| iovec.iov_len).sum();
+        self.check_alignment(offset, len)
+            .map_err(AsyncIoError::WriteVectored)?;
+        check_completion_room(self.completion_list.len())?;
+
+        // SAFETY: FFI call with valid arguments
+        let result = unsafe {
+            libc::pwritev(
+                self.scratch.file.as_raw_fd(),
+                iovecs.as_ptr(),
+                iovecs.len() as libc::c_int,
+                offset,
+            )
+        };
+        if result < 0 {
+            self.complete(user_data, error_result(&io::Error::last_os_error()));
+            return Ok(());
+        }
+
+        // Only the blocks entirely written hold valid data.
+        let first = offset as u64 / self.block_size;
+        let blocks = first..first + result as u64 / self.block_size;
+        self.bitmap.set_dirty(blocks.clone());
+        if let Err(e) = self.scratch.persist(&self.bitmap, blocks) {
+            error!("Failed to write the overlay bitmap: {}", e);
+            self.complete(user_data, error_result(&e));
+            return Ok(());
+        }
+        self.complete(user_data, result as i32);
+
+        Ok(())
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        if user_data.is_some() {
+            check_completion_room(self.completion_list.len())?;
+        }
+        // The base is never written, only the scratch file can be dirty.
+        let result = self.scratch.file.sync_data();
+
+        match user_data {
+            Some(user_data) => {
+                self.complete(user_data, result.map_or_else(|e| error_result(&e), |_| 0))
+            }
+            None => result.map_err(AsyncIoError::Fsync)?,
+        }
+
+        Ok(())
+    }
+
+    fn submit(&mut self) -> AsyncIoResult<()> {
+        self.base.submit()
+    }
+
+    fn next_completed_request(&mut self) -> Option<(u64, i32)> {
+        if let Some(completion) = self.completion_list.pop_front() {
+            return Some(completion);
+        }
+
+        let (user_data, mut result) = self.base.next_completed_request()?;
+        if let Some((offset, iovecs)) = self.patches.remove(&user_data) {
+            let len: usize = iovecs.iter().map(|iovec| iovec.iov_len).sum();
+            if result as usize == len {
+                // Blocks written since the read was submitted are taken into
+                // account as well.
+                for (start, end) in dirty_runs(&self.bitmap, self.block_size, offset, len as u64) {
+                    let sub =
+                        sub_iovecs(&iovecs, (start - offset) as usize, (end - start) as usize);
+                    if let Err(e) = preadv(&self.scratch.file, start, &sub) {
+                        error!("Failed to read from the overlay scratch file: {}", e);
+                        result = -e.raw_os_error().unwrap_or(libc::EIO);
+                        break;
+                    }
+                }
+            }
+        }
+
+        Some((user_data, result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raw_sync::RawFileDiskSync;
+    use crate::CacheMode;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const BLOCK_SIZE: usize = 512;
+
+    fn base_disk() -> (TempFile, Box<dyn DiskFile>) {
+        let base = TempFile::new().unwrap();
+        let data: Vec<u8> = (0..4 * BLOCK_SIZE)
+            .map(|i| (i / BLOCK_SIZE) as u8)
+            .collect();
+        base.as_file().write_all_at(&data, 0).unwrap();
+        let disk = reopen(&base);
+        (base, disk)
+    }
+
+    fn reopen(base: &TempFile) -> Box<dyn DiskFile> {
+        Box::new(
+            RawFileDiskSync::new(
+                base.as_file().try_clone().unwrap(),
+                true,
+                CacheMode::Writeback,
+                None,
+            )
+            .unwrap(),
+        )
+    }
+
+    fn iovec(buf: &mut [u8]) -> libc::iovec {
+        libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }
+    }
+
+    #[test]
+    fn test_overlay_read_write() {
+        let (base, base_disk) = base_disk();
+        let scratch = TempFile::new().unwrap();
+        let disk = OverlayDiskFile::new(base_disk, scratch.as_file().try_clone().unwrap()).unwrap();
+        let mut io = disk.new_async_io(1).unwrap();
+
+        let mut data = vec![0xffu8; BLOCK_SIZE];
+        io.write_vectored(BLOCK_SIZE as libc::off_t, &[iovec(&mut data)], 1)
+            .unwrap();
+        assert_eq!(io.next_completed_request(), Some((1, BLOCK_SIZE as i32)));
+
+        // The read spans clean and dirty blocks.
+        let mut buf = vec![0u8; 3 * BLOCK_SIZE];
+        io.read_vectored(0, &[iovec(&mut buf)], 2).unwrap();
+        assert_eq!(
+            io.next_completed_request(),
+            Some((2, 3 * BLOCK_SIZE as i32))
+        );
+        assert!(buf[..BLOCK_SIZE].iter().all(|b| *b == 0));
+        assert!(buf[BLOCK_SIZE..2 * BLOCK_SIZE].iter().all(|b| *b == 0xff));
+        assert!(buf[2 * BLOCK_SIZE..].iter().all(|b| *b == 2));
+
+        // The base is untouched.
+        let mut base_block = vec![0u8; BLOCK_SIZE];
+        base.as_file()
+            .read_exact_at(&mut base_block, BLOCK_SIZE as u64)
+            .unwrap();
+        assert!(base_block.iter().all(|b| *b == 1));
+
+        // Unaligned requests are refused.
+        assert!(io.write_vectored(1, &[iovec(&mut data)], 3).is_err());
+    }
+
+    #[test]
+    fn test_overlay_flatten() {
+        let (_base, base_disk) = base_disk();
+        let scratch = TempFile::new().unwrap();
+        let mut disk =
+            OverlayDiskFile::new(base_disk, scratch.as_file().try_clone().unwrap()).unwrap();
+        let mut io = disk.new_async_io(1).unwrap();
+
+        let mut data = vec![0xffu8; BLOCK_SIZE];
+        io.write_vectored((3 * BLOCK_SIZE) as libc::off_t, &[iovec(&mut data)], 1)
+            .unwrap();
+        drop(io);
+
+        let dest = TempFile::new().unwrap();
+        disk.flatten(dest.as_file()).unwrap();
+
+        let mut flattened = vec![0u8; 4 * BLOCK_SIZE];
+        dest.as_file().read_exact_at(&mut flattened, 0).unwrap();
+        for (i, block) in flattened.chunks(BLOCK_SIZE).enumerate() {
+            let expected = if i == 3 { 0xff } else { i as u8 };
+            assert!(block.iter().all(|b| *b == expected));
+        }
+
+        // Dropping the overlay discards the scratch content.
+        drop(disk);
+        assert_eq!(scratch.as_file().metadata().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_overlay_reopen() {
+        let (base, disk) = base_disk();
+        let scratch = TempFile::new().unwrap();
+        let disk = OverlayDiskFile::open(disk, scratch.as_file().try_clone().unwrap()).unwrap();
+        let mut io = disk.new_async_io(1).unwrap();
+
+        let mut data = vec![0xffu8; BLOCK_SIZE];
+        io.write_vectored(BLOCK_SIZE as libc::off_t, &[iovec(&mut data)], 1)
+            .unwrap();
+        assert_eq!(io.next_completed_request(), Some((1, BLOCK_SIZE as i32)));
+        drop(io);
+        drop(disk);
+
+        // The written block is recovered against the same base.
+        let disk =
+            OverlayDiskFile::open(reopen(&base), scratch.as_file().try_clone().unwrap()).unwrap();
+        let mut io = disk.new_async_io(1).unwrap();
+        let mut buf = vec![0u8; 2 * BLOCK_SIZE];
+        io.read_vectored(0, &[iovec(&mut buf)], 2).unwrap();
+        assert_eq!(
+            io.next_completed_request(),
+            Some((2, 2 * BLOCK_SIZE as i32))
+        );
+        assert!(buf[..BLOCK_SIZE].iter().all(|b| *b == 0));
+        assert!(buf[BLOCK_SIZE..].iter().all(|b| *b == 0xff));
+        drop(io);
+        drop(disk);
+
+        // Another base of the same size is refused.
+        let (other, _) = base_disk();
+        other.as_file().write_all_at(&[0xaa], 0).unwrap();
+        assert!(matches!(
+            OverlayDiskFile::open(reopen(&other), scratch.as_file().try_clone().unwrap()),
+            Err(DiskFileError::OverlayBaseMismatch)
+        ));
+
+        // So is a scratch file with a corrupted header.
+        scratch
+            .as_file()
+            .write_all_at(&[0], HEADER_ALIGNMENT)
+            .unwrap();
+        assert!(matches!(
+            OverlayDiskFile::open(reopen(&base), scratch.as_file().try_clone().unwrap()),
+            Err(DiskFileError::InvalidOverlayHeader)
+        ));
+    }
+}
@@ -0,0 +1,185 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Allocation of the host storage backing a disk file created by the VMM,
+//! and its release from a disk file being reused.
+
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::io::AsRawFd;
+use std::str::FromStr;
+
+/// How much of the host storage is allocated when creating a disk file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PreallocationMode {
+    /// The file is sparse, the host storage being allocated as the guest
+    /// writes to the disk.
+    #[default]
+    Off,
+    /// The blocks are reserved with FALLOC_FL_KEEP_SIZE, without being
+    /// zeroed, on the filesystems tracking such reservations. The file is
+    /// sparse on the others.
+    Metadata,
+    /// The blocks are allocated and read as zeroes, so that the guest writes
+    /// can't fail with the host storage full.
+    Full,
+}
+
+impl PreallocationMode {
+    /// Sizes `file` to `size` bytes, allocating its blocks as requested.
+    /// The file is truncated back to 0 if they can't all be allocated, not
+    /// to be mistaken for a preallocated one.
+    pub fn apply(&self, file: &File, size: u64) -> io::Result<()> {
+        let result = match self {
+            PreallocationMode::Off => return file.set_len(size),
+            PreallocationMode::Metadata => match fallocate(file, libc::FALLOC_FL_KEEP_SIZE, size) {
+                Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+                    warn!("Can't reserve the blocks of the disk file, leaving it sparse");
+                    file.set_len(size)
+                }
+                r => r.and_then(|_| file.set_len(size)),
+            },
+            PreallocationMode::Full => fallocate(file, 0, size),
+        };
+
+        if result.is_err() {
+            let _ = file.set_len(0);
+        }
+
+        result
+    }
+}
+
+#[derive(Debug)]
+pub enum ParsePreallocationModeError {
+    InvalidValue(String),
+}
+
+impl FromStr for PreallocationMode {
+    type Err = ParsePreallocationModeError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(PreallocationMode::Off),
+            "metadata" => Ok(PreallocationMode::Metadata),
+            // Kept from when the preallocation was either on or off.
+            "full" | "on" => Ok(PreallocationMode::Full),
+            _ => Err(ParsePreallocationModeError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+/// Releases all the blocks of `file`, a RAW disk file or a block device,
+/// which then reads as zeroes. Its size is left unchanged.
+pub fn discard_all(mut file: &File) -> io::Result<()> {
+    // The size of a block device is only known from its end.
+    let size = file.seek(SeekFrom::End(0))?;
+    file.rewind()?;
+    if size == 0 {
+        return Ok(());
+    }
+
+    fallocate(
+        file,
+        libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+        size,
+    )
+}
+
+fn fallocate(file: &File, mode: libc::c_int, size: u64) -> io::Result<()> {
+    // SAFETY: FFI call with a valid file descriptor
+    let ret = unsafe { libc::fallocate(file.as_raw_fd(), mode, 0, size as libc::off_t) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::linux::fs::MetadataExt;
+    use std::os::unix::fs::FileExt;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const SIZE: u64 = 1 << 20;
+
+    fn allocated(file: &File) -> u64 {
+        file.metadata().unwrap().st_blocks() * 512
+    }
+
+    #[test]
+    fn test_preallocation_modes() {
+        let file = TempFile::new().unwrap();
+        let file = file.as_file();
+        PreallocationMode::Off.apply(file, SIZE).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), SIZE);
+        assert_eq!(allocated(file), 0);
+
+        let file = TempFile::new().unwrap();
+        let file = file.as_file();
+        PreallocationMode::Full.apply(file, SIZE).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), SIZE);
+        assert!(allocated(file) >= SIZE);
+
+        let file = TempFile::new().unwrap();
+        let file = file.as_file();
+        PreallocationMode::Metadata.apply(file, SIZE).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), SIZE);
+    }
+
+    #[test]
+    fn test_preallocation_failure() {
+        let file = TempFile::new().unwrap();
+        let file = file.as_file();
+        file.set_len(4096).unwrap();
+        // Larger than any filesystem, failing with EFBIG or ENOSPC.
+        PreallocationMode::Full
+            .apply(file, i64::MAX as u64)
+            .unwrap_err();
+        assert_eq!(file.metadata().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_discard_all() {
+        let file = TempFile::new().unwrap();
+        let file = file.as_file();
+        PreallocationMode::Full.apply(file, SIZE).unwrap();
+        file.write_all_at(&[0xa5; 4096], SIZE / 2).unwrap();
+
+        discard_all(file).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), SIZE);
+        assert_eq!(allocated(file), 0);
+        let mut buf = vec![0xffu8; 4096];
+        file.read_exact_at(&mut buf, SIZE / 2).unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+
+        // Nothing to release from an empty file.
+        let file = TempFile::new().unwrap();
+        discard_all(file.as_file()).unwrap();
+    }
+
+    #[test]
+    fn test_parse_preallocation_mode() {
+        assert_eq!(
+            "off".parse::<PreallocationMode>().unwrap(),
+            PreallocationMode::Off
+        );
+        assert_eq!(
+            "Metadata".parse::<PreallocationMode>().unwrap(),
+            PreallocationMode::Metadata
+        );
+        assert_eq!(
+            "full".parse::<PreallocationMode>().unwrap(),
+            PreallocationMode::Full
+        );
+        assert_eq!(
+            "on".parse::<PreallocationMode>().unwrap(),
+            PreallocationMode::Full
+        );
+        assert!("falloc".parse::<PreallocationMode>().is_err());
+    }
+}
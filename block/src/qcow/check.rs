@@ -0,0 +1,528 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Consistency check of qcow2 images, walking the metadata to find the
+//! clusters referenced by the image and comparing their number of references
+//! against the refcounts stored in the file.
+
+use crate::qcow::qcow_raw_file::QcowRawFile;
+use crate::qcow::{
+    compressed_host_clusters, Error, QcowFile, RawFile, Result, COMPRESSED_FLAG,
+    L1_TABLE_OFFSET_MASK, L2_TABLE_OFFSET_MASK,
+};
+use byteorder::{BigEndian, ReadBytesExt};
+use std::fmt;
+use std::io::{self, Seek, SeekFrom};
+use std::mem::size_of;
+
+// Size of the fixed part of a snapshot table entry.
+const SNAPSHOT_HEADER_SIZE: u64 = 40;
+
+/// Kind of metadata or data a cluster is referenced as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClusterKind {
+    Header,
+    L1Table,
+    L2Table,
+    Data,
+    RefcountTable,
+    RefcountBlock,
+    SnapshotTable,
+    SnapshotL1Table,
+}
+
+impl fmt::Display for ClusterKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self {
+            ClusterKind::Header => "header",
+            ClusterKind::L1Table => "L1 table",
+            ClusterKind::L2Table => "L2 table",
+            ClusterKind::Data => "data",
+            ClusterKind::RefcountTable => "refcount table",
+            ClusterKind::RefcountBlock => "refcount block",
+            ClusterKind::SnapshotTable => "snapshot table",
+            ClusterKind::SnapshotL1Table => "snapshot L1 table",
+        };
+        write!(f, "{kind}")
+    }
+}
+
+/// Inconsistency found in an image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CheckError {
+    /// A cluster is referenced past the end of the file.
+    OutOfRange { kind: ClusterKind, offset: u64 },
+    /// A cluster is referenced at an offset not aligned on the cluster size.
+    Unaligned { kind: ClusterKind, offset: u64 },
+    /// A cluster is referenced as two different kinds, or twice as a kind
+    /// which can't be shared.
+    Overlap {
+        offset: u64,
+        first: ClusterKind,
+        second: ClusterKind,
+    },
+    /// The refcount stored for a cluster doesn't match its number of
+    /// references.
+    RefcountMismatch {
+        offset: u64,
+        stored: u16,
+        computed: u32,
+    },
+}
+
+impl fmt::Display for CheckError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheckError::OutOfRange { kind, offset } => {
+                write!(
+                    f,
+                    "{kind} cluster at {offset:#x} is past the end of the file"
+                )
+            }
+            CheckError::Unaligned { kind, offset } => {
+                write!(f, "{kind} cluster at {offset:#x} is not cluster aligned")
+            }
+            CheckError::Overlap {
+                offset,
+                first,
+                second,
+            } => write!(
+                f,
+                "cluster at {offset:#x} is referenced as {first} and {second}"
+            ),
+            CheckError::RefcountMismatch {
+                offset,
+                stored,
+                computed,
+            } => write!(
+                f,
+                "cluster at {offset:#x} has refcount {stored} but {computed} references"
+            ),
+        }
+    }
+}
+
+/// Result of the check of an image.
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    /// Inconsistencies which could lead to data corruption.
+    pub errors: Vec<CheckError>,
+    /// Offsets of the clusters with a refcount but no reference. These only
+    /// waste space in the file.
+    pub leaks: Vec<u64>,
+    /// Number of leaked clusters freed by the repair.
+    pub leaks_fixed: usize,
+}
+
+impl CheckReport {
+    /// Returns true if neither errors nor leaks were found.
+    pub fn is_clean(&self) -> bool {
+        self.errors.is_empty() && self.leaks.is_empty()
+    }
+}
+
+// Reads the snapshot table entry at `offset`, returning the offset and size
+// of its L1 table along with the size of the entry.
+fn read_snapshot_entry(file: &mut RawFile, offset: u64) -> io::Result<(u64, u32, u64)> {
+    file.seek(SeekFrom::Start(offset))?;
+    let l1_offset = file.read_u64::<BigEndian>()?;
+    let l1_size = file.read_u32::<BigEndian>()?;
+    let id_size = file.read_u16::<BigEndian>()?;
+    let name_size = file.read_u16::<BigEndian>()?;
+    // Skip the dates, the VM clock and the VM state size.
+    file.seek(SeekFrom::Current(24))?;
+    let extra_size = file.read_u32::<BigEndian>()?;
+    let entry_size =
+        SNAPSHOT_HEADER_SIZE + u64::from(extra_size) + u64::from(id_size) + u64::from(name_size);
+
+    // Entries are aligned on 8 bytes.
+    Ok((l1_offset, l1_size, entry_size.next_multiple_of(8)))
+}
+
+struct Checker<'a> {
+    raw_file: &'a mut QcowRawFile,
+    cluster_size: u64,
+    file_clusters: u64,
+    // Number of references to each cluster of the file.
+    refcounts: Vec<u32>,
+    // Kind each cluster of the file was first referenced as.
+    kinds: Vec<Option<ClusterKind>>,
+    report: CheckReport,
+}
+
+impl<'a> Checker<'a> {
+    fn new(raw_file: &'a mut QcowRawFile, file_size: u64) -> Self {
+        let cluster_size = raw_file.cluster_size();
+        let file_clusters = file_size.div_ceil(cluster_size);
+        Checker {
+            raw_file,
+            cluster_size,
+            file_clusters,
+            refcounts: vec![0; file_clusters as usize],
+            kinds: vec![None; file_clusters as usize],
+            report: CheckReport::default(),
+        }
+    }
+
+    // Records a reference to the clusters covering `length` bytes from
+    // `offset`, returning false if they can't be read.
+    fn add(&mut self, kind: ClusterKind, offset: u64, length: u64) -> bool {
+        if offset % self.cluster_size != 0 {
+            self.report
+                .errors
+                .push(CheckError::Unaligned { kind, offset });
+            return false;
+        }
+        let first = offset / self.cluster_size;
+        let count = length.div_ceil(self.cluster_size).max(1);
+        if first
+            .checked_add(count)
+            .map_or(true, |end| end > self.file_clusters)
+        {
+            self.report
+                .errors
+                .push(CheckError::OutOfRange { kind, offset });
+            return false;
+        }
+
+        for index in first..first + count {
+            let index = index as usize;
+            self.refcounts[index] = self.refcounts[index].saturating_add(1);
+            match self.kinds[index] {
+                None => self.kinds[index] = Some(kind),
+                // Snapshots share L2 tables and data clusters with the active
+                // image, while the other kinds have a single reference.
+                Some(existing)
+                    if existing == kind
+                        && matches!(kind, ClusterKind::L2Table | ClusterKind::Data) => {}
+                Some(existing) => self.report.errors.push(CheckError::Overlap {
+                    offset: index as u64 * self.cluster_size,
+                    first: existing,
+                    second: kind,
+                }),
+            }
+        }
+
+        true
+    }
+
+    fn check_l1(&mut self, kind: ClusterKind, offset: u64, size: u32) -> io::Result<()> {
+        let length = u64::from(size) * size_of::<u64>() as u64;
+        if size == 0 || !self.add(kind, offset, length) {
+            return Ok(());
+        }
+
+        let l1_table = self.raw_file.read_pointer_table(
+            offset,
+            u64::from(size),
+            Some(L1_TABLE_OFFSET_MASK),
+        )?;
+        for l2_offset in l1_table {
+            if l2_offset != 0 && self.add(ClusterKind::L2Table, l2_offset, self.cluster_size) {
+                self.check_l2(l2_offset)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_l2(&mut self, offset: u64) -> io::Result<()> {
+        let l2_table = self.raw_file.read_pointer_cluster(offset, None)?;
+        let cluster_bits = self.cluster_size.trailing_zeros();
+        for entry in l2_table {
+            let data_offset = entry & L2_TABLE_OFFSET_MASK;
+            if entry & COMPRESSED_FLAG != 0 {
+                // The data of compressed clusters can share host clusters, each
+                // of them holding a reference to every cluster it touches.
+                for data_offset in compressed_host_clusters(entry, cluster_bits) {
+                    self.add(ClusterKind::Data, data_offset, self.cluster_size);
+                }
+            } else if data_offset != 0 {
+                self.add(ClusterKind::Data, data_offset, self.cluster_size);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_snapshots(&mut self, offset: u64, count: u32) -> io::Result<()> {
+        if count == 0 {
+            return Ok(());
+        }
+
+        // The size of the table is only known once every entry was parsed.
+        let mut l1_tables = Vec::new();
+        let file = self.raw_file.file_mut();
+        let mut entry_offset = offset;
+        for _ in 0..count {
+            let parsed = read_snapshot_entry(file, entry_offset);
+            match parsed {
+                Ok((l1_offset, l1_size, entry_size)) => {
+                    l1_tables.push((l1_offset, l1_size));
+                    entry_offset += entry_size;
+                }
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    self.report.errors.push(CheckError::OutOfRange {
+                        kind: ClusterKind::SnapshotTable,
+                        offset,
+                    });
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        if !self.add(ClusterKind::SnapshotTable, offset, entry_offset - offset) {
+            return Ok(());
+        }
+        for (l1_offset, l1_size) in l1_tables {
+            self.check_l1(ClusterKind::SnapshotL1Table, l1_offset, l1_size)?;
+        }
+
+        Ok(())
+    }
+
+    fn check_refcount_blocks(
+        &mut self,
+        table_offset: u64,
+        table_clusters: u32,
+    ) -> io::Result<Vec<u64>> {
+        let entries = u64::from(table_clusters) * self.cluster_size / size_of::<u64>() as u64;
+        let mut blocks = self
+            .raw_file
+            .read_pointer_table(table_offset, entries, None)?;
+        for block in blocks.iter_mut() {
+            if *block != 0 && !self.add(ClusterKind::RefcountBlock, *block, self.cluster_size) {
+                // Treat the clusters covered by a broken block as having no
+                // refcount.
+                *block = 0;
+            }
+        }
+
+        Ok(blocks)
+    }
+
+    // Compares the number of references found with the refcounts stored in
+    // the refcount blocks.
+    fn compare_refcounts(&mut self, blocks: &[u64]) -> io::Result<()> {
+        let refcounts_per_block = self.cluster_size / size_of::<u16>() as u64;
+        for (block_index, block) in blocks.iter().enumerate() {
+            let first = block_index as u64 * refcounts_per_block;
+            if first >= self.file_clusters && *block == 0 {
+                continue;
+            }
+
+            let stored = if *block != 0 {
+                self.raw_file.read_refcount_block(*block)?
+            } else {
+                vec![0; refcounts_per_block as usize]
+            };
+            for (i, stored) in stored.into_iter().enumerate() {
+                let index = first + i as u64;
+                let computed = self.refcounts.get(index as usize).copied().unwrap_or(0);
+                if u32::from(stored) == computed {
+                    continue;
+                }
+                let offset = index * self.cluster_size;
+                if computed == 0 {
+                    self.report.leaks.push(offset);
+                } else {
+                    self.report.errors.push(CheckError::RefcountMismatch {
+                        offset,
+                        stored,
+                        computed,
+                    });
+                }
+            }
+        }
+
+        // Clusters referenced but not covered by the refcount table.
+        let covered = blocks.len() as u64 * refcounts_per_block;
+        for index in covered..self.file_clusters {
+            let computed = self.refcounts[index as usize];
+            if computed != 0 {
+                self.report.errors.push(CheckError::RefcountMismatch {
+                    offset: index * self.cluster_size,
+                    stored: 0,
+                    computed,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl QcowFile {
+    /// Checks the consistency of the image, walking the L1 and L2 tables,
+    /// the snapshot table and the refcount blocks. The image isn't modified,
+    /// except for writing back the metadata cached in memory first.
+    pub fn check(&mut self) -> Result<CheckReport> {
+        self.sync_caches().map_err(Error::SyncingCaches)?;
+
+        let file_size = self
+            .raw_file
+            .file_mut()
+            .metadata()
+            .map_err(Error::GettingFileSize)?
+            .len();
+        let header = self.header.clone();
+        let cluster_size = self.raw_file.cluster_size();
+        let mut checker = Checker::new(&mut self.raw_file, file_size);
+
+        checker.add(ClusterKind::Header, 0, cluster_size);
+        checker
+            .check_l1(ClusterKind::L1Table, header.l1_table_offset, header.l1_size)
+            .map_err(Error::ReadingPointers)?;
+        checker
+            .check_snapshots(header.snapshots_offset, header.nb_snapshots)
+            .map_err(Error::ReadingSnapshots)?;
+        let refcount_table_size = u64::from(header.refcount_table_clusters) * cluster_size;
+        if checker.add(
+            ClusterKind::RefcountTable,
+            header.refcount_table_offset,
+            refcount_table_size,
+        ) {
+            let blocks = checker
+                .check_refcount_blocks(header.refcount_table_offset, header.refcount_table_clusters)
+                .map_err(Error::ReadingRefCounts)?;
+            checker
+                .compare_refcounts(&blocks)
+                .map_err(Error::ReadingRefCounts)?;
+        }
+
+        Ok(checker.report)
+    }
+
+    /// Checks the image and frees the leaked clusters, as long as no other
+    /// inconsistency was found. The returned report is the one of the check
+    /// run after the repair.
+    pub fn repair_leaks(&mut self) -> Result<CheckReport> {
+        let report = self.check()?;
+        if !report.errors.is_empty() || report.leaks.is_empty() {
+            return Ok(report);
+        }
+
+        let file_size = self
+            .raw_file
+            .file_mut()
+            .metadata()
+            .map_err(Error::GettingFileSize)?
+            .len();
+        let mut leaks = report.leaks.clone();
+        while let Some(offset) = leaks.pop() {
+            // The refcount blocks replaced by a copy along the way would be
+            // leaked as well.
+            let mut replaced = self
+                .set_cluster_refcount(offset, 0)
+                .map_err(Error::RepairingRefcounts)?;
+            leaks.append(&mut replaced);
+            // Leaks past the end of the file only had a stale refcount. The
+            // clusters already known to be free mustn't be handed out twice.
+            if offset < file_size
+                && !self.unref_clusters.contains(&offset)
+                && !self.avail_clusters.contains(&offset)
+            {
+                self.unref_clusters.push(offset);
+            }
+        }
+        self.sync_caches().map_err(Error::RepairingRefcounts)?;
+        self.avail_clusters.append(&mut self.unref_clusters);
+
+        let mut repaired = self.check()?;
+        repaired.leaks_fixed = report.leaks.len() - repaired.leaks.len();
+        Ok(repaired)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use vmm_sys_util::tempfile::TempFile;
+
+    fn new_file(virtual_size: u64) -> QcowFile {
+        let file = RawFile::new(TempFile::new().unwrap().into_file(), false);
+        QcowFile::new(file, 3, virtual_size).unwrap()
+    }
+
+    #[test]
+    fn test_check_clean() {
+        let mut qcow = new_file(0x10_0000);
+        assert!(qcow.check().unwrap().errors.is_empty());
+
+        qcow.write_all(&[0x55; 0x2_0000]).unwrap();
+        qcow.flush().unwrap();
+        assert!(qcow.check().unwrap().errors.is_empty());
+
+        // The refcount blocks are copied on write, the ones they replace
+        // keeping their refcount until reused, which the repair frees.
+        qcow.repair_leaks().unwrap();
+        assert!(qcow.check().unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_check_repair_leaks() {
+        let mut qcow = new_file(0x10_0000);
+        qcow.write_all(&[0x55; 0x1_0000]).unwrap();
+        let leaked = qcow.append_data_cluster(None).unwrap();
+
+        let report = qcow.check().unwrap();
+        assert!(report.errors.is_empty());
+        assert!(report.leaks.contains(&leaked));
+
+        let repaired = qcow.repair_leaks().unwrap();
+        assert!(repaired.is_clean());
+        assert_eq!(repaired.leaks_fixed, report.leaks.len());
+
+        // The freed clusters are reused, each of them once.
+        let first = qcow.append_data_cluster(None).unwrap();
+        let second = qcow.append_data_cluster(None).unwrap();
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_check_refcount_mismatch() {
+        let mut qcow = new_file(0x10_0000);
+        qcow.write_all(&[0x55; 0x1_0000]).unwrap();
+        let data = qcow.file_offset_read(0).unwrap().unwrap();
+        qcow.set_cluster_refcount(data, 0).unwrap();
+
+        let report = qcow.check().unwrap();
+        assert_eq!(
+            report.errors,
+            vec![CheckError::RefcountMismatch {
+                offset: data,
+                stored: 0,
+                computed: 1,
+            }]
+        );
+
+        // Leaks aren't repaired while the image has errors.
+        let report = qcow.repair_leaks().unwrap();
+        assert_eq!(report.leaks_fixed, 0);
+        assert_eq!(report.errors.len(), 1);
+    }
+
+    #[test]
+    fn test_check_invalid_l2_offsets() {
+        // Two L1 entries are needed.
+        let mut qcow = new_file(0x8000_0000);
+        let l1_table_offset = qcow.header.l1_table_offset;
+        let file_size = qcow.raw_file.file_mut().metadata().unwrap().len();
+        qcow.l1_table[0] = l1_table_offset;
+        qcow.l1_table[1] = file_size.next_multiple_of(0x1_0000) + 0x10_0000;
+
+        let report = qcow.check().unwrap();
+        assert!(report.errors.contains(&CheckError::Overlap {
+            offset: l1_table_offset,
+            first: ClusterKind::L1Table,
+            second: ClusterKind::L2Table,
+        }));
+        assert!(report.errors.contains(&CheckError::OutOfRange {
+            kind: ClusterKind::L2Table,
+            offset: file_size.next_multiple_of(0x1_0000) + 0x10_0000,
+        }));
+    }
+}
@@ -4,17 +4,20 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
+mod check;
 mod qcow_raw_file;
 mod raw_file;
+mod rebase;
 mod refcount;
 mod vec_cache;
 
+use crate::async_io::DiskExtent;
 use crate::qcow::{
     qcow_raw_file::QcowRawFile,
     refcount::RefCount,
     vec_cache::{CacheMap, Cacheable, VecCache},
 };
-use crate::BlockBackend;
+use crate::{merge_extents, resolve_backing_file_path, seek_extents, BlockBackend};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use libc::{EINVAL, ENOSPC, ENOTSUP};
 use remain::sorted;
@@ -22,6 +25,7 @@ use std::cmp::{max, min};
 use std::fs::OpenOptions;
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::mem::size_of;
+use std::os::unix::fs::MetadataExt;
 use std::str;
 use thiserror::Error;
 use vmm_sys_util::{
@@ -29,7 +33,9 @@ use vmm_sys_util::{
     write_zeroes::WriteZeroesAt,
 };
 
+pub use crate::qcow::check::{CheckError, CheckReport, ClusterKind};
 pub use crate::qcow::raw_file::RawFile;
+pub use crate::qcow::rebase::RebaseMode;
 
 /// Nesting depth limit for disk formats that can open other disk files.
 const MAX_NESTING_DEPTH: u32 = 10;
@@ -37,12 +43,16 @@ const MAX_NESTING_DEPTH: u32 = 10;
 #[sorted]
 #[derive(Debug, Error)]
 pub enum Error {
+    #[error("Backing file chain contains a cycle")]
+    BackingFileCycle,
     #[error("Backing file io error: {0}")]
     BackingFileIo(io::Error),
     #[error("Backing file open error: {0}")]
     BackingFileOpen(Box<Error>),
     #[error("Backing file name is too long: {0} bytes over")]
     BackingFileTooLong(usize),
+    #[error("Logical block size {0} larger than the cluster size {1}")]
+    BlockSizeTooLarge(u64, u64),
     #[error("Compressed blocks not supported")]
     CompressedBlocksNotSupported,
     #[error("Failed to evict cache: {0}")]
@@ -93,24 +103,34 @@ pub enum Error {
     ReadingRefCountBlock(refcount::Error),
     #[error("Failed to read ref counts: {0}")]
     ReadingRefCounts(io::Error),
+    #[error("Failed to read snapshots: {0}")]
+    ReadingSnapshots(io::Error),
     #[error("Failed to rebuild ref counts: {0}")]
     RebuildingRefCounts(io::Error),
     #[error("Refcount table offset past file end")]
     RefcountTableOffEnd,
     #[error("Too many clusters specified for refcount")]
     RefcountTableTooLarge,
+    #[error("Failed to repair ref counts: {0}")]
+    RepairingRefcounts(io::Error),
     #[error("Failed to seek file: {0}")]
     SeekingFile(io::Error),
     #[error("Failed to set file size: {0}")]
     SettingFileSize(io::Error),
     #[error("Failed to set refcount refcount: {0}")]
     SettingRefcountRefcount(io::Error),
+    #[error("Disk size {0} not a multiple of the logical block size {1}")]
+    SizeNotMultipleOfBlockSize(u64, u64),
     #[error("Size too small for number of clusters")]
     SizeTooSmallForNumberOfClusters,
+    #[error("Failed to sync caches: {0}")]
+    SyncingCaches(io::Error),
     #[error("L1 entry table too large: {0}")]
     TooManyL1Entries(u64),
     #[error("Ref count table too large: {0}")]
     TooManyRefcounts(u64),
+    #[error("Unsupported compression type: {0}")]
+    UnsupportedCompressionType(u8),
     #[error("Unsupported refcount order")]
     UnsupportedRefcountOrder,
     #[error("Unsupported version: {0}")]
@@ -156,6 +176,11 @@ const L2_TABLE_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
 const COMPRESSED_FLAG: u64 = 1 << 62;
 const CLUSTER_USED_FLAG: u64 = 1 << 63;
 const COMPATIBLE_FEATURES_LAZY_REFCOUNTS: u64 = 1;
+const INCOMPATIBLE_FEATURES_COMPRESSION_TYPE: u64 = 1 << 3;
+
+// Algorithms the compressed clusters can be stored with.
+const COMPRESSION_TYPE_ZLIB: u8 = 0;
+const COMPRESSION_TYPE_ZSTD: u8 = 1;
 
 // The format supports a "header extension area", that crosvm does not use.
 const QCOW_EMPTY_HEADER_EXTENSION_SIZE: u32 = 8;
@@ -191,6 +216,7 @@ pub struct QcowHeader {
     pub autoclear_features: u64,
     pub refcount_order: u32,
     pub header_size: u32,
+    pub compression_type: u8,
 
     // Post-header entries
     pub backing_file_path: Option<String>,
@@ -256,8 +282,18 @@ impl QcowHeader {
             } else {
                 read_u32_from_file(f)?
             },
+            compression_type: COMPRESSION_TYPE_ZLIB,
             backing_file_path: None,
         };
+        // The compression type follows the bare header, and is only set for
+        // the images which don't use zlib.
+        if header.incompatible_features & INCOMPATIBLE_FEATURES_COMPRESSION_TYPE != 0
+            && header.header_size > V3_BARE_HEADER_SIZE
+        {
+            f.seek(SeekFrom::Start(u64::from(V3_BARE_HEADER_SIZE)))
+                .map_err(Error::ReadingHeader)?;
+            header.compression_type = f.read_u8().map_err(Error::ReadingHeader)?;
+        }
         if header.backing_file_size > MAX_BACKING_FILE_SIZE {
             return Err(Error::BackingFileTooLong(header.backing_file_size as usize));
         }
@@ -337,6 +373,7 @@ impl QcowHeader {
             autoclear_features: 0,
             refcount_order: DEFAULT_REFCOUNT_ORDER,
             header_size,
+            compression_type: COMPRESSION_TYPE_ZLIB,
             backing_file_path: backing_file.map(String::from),
         })
     }
@@ -440,6 +477,9 @@ pub struct QcowFile {
     // removal of references to them have been synced to disk.
     avail_clusters: Vec<u64>,
     backing_file: Option<Box<Self>>,
+    // Last compressed cluster read, as its L2 entry and its decompressed data, so that reading it
+    // in several requests only decompresses it once.
+    compressed_cluster: Option<(u64, Vec<u8>)>,
 }
 
 impl QcowFile {
@@ -452,7 +492,24 @@ impl QcowFile {
 
     /// Creates a QcowFile from `file` and with a max nesting depth. File must be a valid qcow2
     /// image.
-    pub fn from_with_nesting_depth(mut file: RawFile, max_nesting_depth: u32) -> Result<QcowFile> {
+    pub fn from_with_nesting_depth(file: RawFile, max_nesting_depth: u32) -> Result<QcowFile> {
+        Self::from_with_chain(file, max_nesting_depth, &mut Vec::new())
+    }
+
+    // `chain` holds the device and inode numbers of the images already opened
+    // down the backing chain, which is how cycles are caught.
+    fn from_with_chain(
+        mut file: RawFile,
+        max_nesting_depth: u32,
+        chain: &mut Vec<(u64, u64)>,
+    ) -> Result<QcowFile> {
+        let metadata = file.metadata().map_err(Error::GettingFileSize)?;
+        let id = (metadata.dev(), metadata.ino());
+        if chain.contains(&id) {
+            return Err(Error::BackingFileCycle);
+        }
+        chain.push(id);
+
         let header = QcowHeader::new(&mut file)?;
 
         // Only v2 and v3 files are supported.
@@ -482,14 +539,15 @@ impl QcowFile {
             if max_nesting_depth == 0 {
                 return Err(Error::MaxNestingDepthExceeded);
             }
-            let path = backing_file_path.clone();
+            let path = resolve_backing_file_path(&file, backing_file_path);
             let backing_raw_file = OpenOptions::new()
                 .read(true)
                 .open(path)
                 .map_err(Error::BackingFileIo)?;
-            let backing_file = Self::from_with_nesting_depth(
+            let backing_file = Self::from_with_chain(
                 RawFile::new(backing_raw_file, direct_io),
                 max_nesting_depth - 1,
+                chain,
             )
             .map_err(|e| Error::BackingFileOpen(Box::new(e)))?;
             Some(Box::new(backing_file))
@@ -588,14 +646,19 @@ impl QcowFile {
 
         let l2_entries = cluster_size / size_of::<u64>() as u64;
 
-        // Check for compressed blocks
+        if header.compression_type != COMPRESSION_TYPE_ZLIB
+            && header.compression_type != COMPRESSION_TYPE_ZSTD
+        {
+            return Err(Error::UnsupportedCompressionType(header.compression_type));
+        }
+
+        // Check for compressed blocks, which can't be read without decompression support.
+        #[cfg(not(feature = "qcow_compression"))]
         for l2_addr_disk in l1_table.get_values() {
             if *l2_addr_disk != 0 {
-                if let Err(e) = Self::read_l2_cluster(&mut raw_file, *l2_addr_disk) {
-                    if let Some(os_error) = e.raw_os_error() {
-                        if os_error == ENOTSUP {
-                            return Err(Error::CompressedBlocksNotSupported);
-                        }
+                if let Ok(l2_table) = raw_file.read_pointer_cluster(*l2_addr_disk, None) {
+                    if l2_table.iter().any(|entry| entry & COMPRESSED_FLAG != 0) {
+                        return Err(Error::CompressedBlocksNotSupported);
                     }
                 }
             }
@@ -612,6 +675,7 @@ impl QcowFile {
             unref_clusters: Vec::new(),
             avail_clusters: Vec::new(),
             backing_file,
+            compressed_cluster: None,
         };
 
         // Check that the L1 and refcount tables fit in a 64bit address space.
@@ -844,12 +908,19 @@ impl QcowFile {
                         .read_pointer_table(
                             l2_addr_disk,
                             cluster_size / size_of::<u64>() as u64,
-                            Some(L2_TABLE_OFFSET_MASK),
+                            None,
                         )
                         .map_err(Error::ReadingPointers)?;
-                    for data_cluster_addr in l2_table {
-                        if data_cluster_addr != 0 {
-                            add_ref(refcounts, cluster_size, data_cluster_addr)?;
+                    for entry in l2_table {
+                        if entry & COMPRESSED_FLAG != 0 {
+                            // Compressed clusters reference every cluster their data touches.
+                            for data_cluster_addr in
+                                compressed_host_clusters(entry, header.cluster_bits)
+                            {
+                                add_ref(refcounts, cluster_size, data_cluster_addr)?;
+                            }
+                        } else if entry & L2_TABLE_OFFSET_MASK != 0 {
+                            add_ref(refcounts, cluster_size, entry & L2_TABLE_OFFSET_MASK)?;
                         }
                     }
                 }
@@ -1052,9 +1123,9 @@ impl QcowFile {
         (address / self.raw_file.cluster_size()) % self.l2_entries
     }
 
-    // Gets the offset of the given guest address in the host file. If L1, L2, or data clusters have
-    // yet to be allocated, return None.
-    fn file_offset_read(&mut self, address: u64) -> std::io::Result<Option<u64>> {
+    // Gets the L2 entry of the cluster containing the given guest address, which is 0 if the L2
+    // table or the cluster have yet to be allocated.
+    fn l2_entry(&mut self, address: u64) -> std::io::Result<u64> {
         if address >= self.virtual_size() {
             return Err(std::io::Error::from_raw_os_error(EINVAL));
         }
@@ -1067,7 +1138,7 @@ impl QcowFile {
 
         if l2_addr_disk == 0 {
             // Reading from an unallocated cluster will return zeros.
-            return Ok(None);
+            return Ok(0);
         }
 
         let l2_index = self.l2_table_index(address) as usize;
@@ -1088,13 +1159,71 @@ impl QcowFile {
             })?;
         };
 
-        let cluster_addr = self.l2_cache.get(l1_index).unwrap()[l2_index];
+        Ok(self.l2_cache.get(l1_index).unwrap()[l2_index])
+    }
+
+    // Gets the offset of the given guest address in the host file. If L1, L2, or data clusters have
+    // yet to be allocated, return None. Compressed clusters have no such offset, and return ENOTSUP.
+    fn file_offset_read(&mut self, address: u64) -> std::io::Result<Option<u64>> {
+        let cluster_addr = self.l2_entry(address)?;
         if cluster_addr == 0 {
             return Ok(None);
         }
+        if cluster_addr & COMPRESSED_FLAG != 0 {
+            return Err(std::io::Error::from_raw_os_error(ENOTSUP));
+        }
         Ok(Some(cluster_addr + self.raw_file.cluster_offset(address)))
     }
 
+    // Reads and decompresses the compressed cluster described by the L2 entry `entry`.
+    fn read_compressed_cluster(&mut self, entry: u64) -> std::io::Result<&[u8]> {
+        if self.compressed_cluster.as_ref().map(|(e, _)| *e) != Some(entry) {
+            let (offset, size) = compressed_cluster_range(entry, self.header.cluster_bits);
+            // The last sector of the compressed data may go past the end of the file.
+            let mut compressed = Vec::with_capacity(size as usize);
+            let file = self.raw_file.file_mut();
+            file.seek(SeekFrom::Start(offset))?;
+            file.take(size).read_to_end(&mut compressed)?;
+            let data = decompress_cluster(
+                self.header.compression_type,
+                &compressed,
+                self.raw_file.cluster_size() as usize,
+            )?;
+            self.compressed_cluster = Some((entry, data));
+        }
+        // The cluster was just stored if it wasn't already.
+        Ok(&self.compressed_cluster.as_ref().unwrap().1)
+    }
+
+    // Drops the reference of a compressed cluster to the host clusters its data is stored in.
+    fn unref_compressed_cluster(&mut self, entry: u64) -> std::io::Result<()> {
+        if self.compressed_cluster.as_ref().map(|(e, _)| *e) == Some(entry) {
+            self.compressed_cluster = None;
+        }
+
+        for cluster_addr in compressed_host_clusters(entry, self.header.cluster_bits) {
+            let refcount = self
+                .refcounts
+                .get_cluster_refcount(&mut self.raw_file, cluster_addr)
+                .map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("failed to get cluster refcount: {e}"),
+                    )
+                })?;
+            if refcount == 0 {
+                return Err(std::io::Error::from_raw_os_error(EINVAL));
+            }
+
+            let mut newly_unref = self.set_cluster_refcount(cluster_addr, refcount - 1)?;
+            self.unref_clusters.append(&mut newly_unref);
+            if refcount == 1 {
+                self.unref_clusters.push(cluster_addr);
+            }
+        }
+        Ok(())
+    }
+
     // Gets the offset of the given guest address in the host file. If L1, L2, or data clusters need
     // to be allocated, they will be.
     fn file_offset_write(&mut self, address: u64) -> std::io::Result<u64> {
@@ -1152,6 +1281,15 @@ impl QcowFile {
                 self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
                 cluster_addr
             }
+            entry if entry & COMPRESSED_FLAG != 0 => {
+                // Compressed clusters can't be written in place, their data is copied to a new
+                // cluster first.
+                let initial_data = self.read_compressed_cluster(entry)?.to_vec();
+                let cluster_addr = self.append_data_cluster(Some(initial_data))?;
+                self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
+                self.unref_compressed_cluster(entry)?;
+                cluster_addr
+            }
             a => a,
         };
 
@@ -1301,6 +1439,30 @@ impl QcowFile {
         Ok(None)
     }
 
+    /// Returns the ranges of the guest address space whose clusters are
+    /// allocated, either in this image or in its backing chain.
+    pub fn allocated_extents(&mut self) -> std::io::Result<Vec<DiskExtent>> {
+        let size = self.virtual_size();
+        let mut extents = seek_extents(self, size)?;
+
+        if let Some(backing_file) = self.backing_file.as_mut() {
+            // The backing file may be larger than this image.
+            extents.extend(
+                backing_file
+                    .allocated_extents()?
+                    .into_iter()
+                    .filter(|extent| extent.offset < size)
+                    .map(|extent| DiskExtent {
+                        offset: extent.offset,
+                        length: extent.length.min(size - extent.offset),
+                    }),
+            );
+            extents = merge_extents(extents);
+        }
+
+        Ok(extents)
+    }
+
     // Deallocate the storage for the cluster starting at `address`.
     // Any future reads of this cluster will return all zeroes.
     fn deallocate_cluster(&mut self, address: u64) -> std::io::Result<()> {
@@ -1342,6 +1504,13 @@ impl QcowFile {
             return Ok(());
         }
 
+        if cluster_addr & COMPRESSED_FLAG != 0 {
+            self.unref_compressed_cluster(cluster_addr)?;
+            // unwrap is safe as we just checked/inserted this entry.
+            self.l2_cache.get_mut(l1_index).unwrap()[l2_index] = 0;
+            return Ok(());
+        }
+
         // Decrement the refcount.
         let refcount = self
             .refcounts
@@ -1395,7 +1564,11 @@ impl QcowFile {
                 // Partial cluster - zero out the relevant bytes if it was allocated.
                 // Any space in unallocated clusters can be left alone, since
                 // unallocated clusters already read back as zeroes.
-                if let Some(offset) = self.file_offset_read(curr_addr)? {
+                if self.l2_entry(curr_addr)? & COMPRESSED_FLAG != 0 {
+                    // Compressed clusters are copied to a new cluster to be zeroed.
+                    let offset = self.file_offset_write(curr_addr)?;
+                    self.raw_file.file_mut().write_zeroes_at(offset, count)?;
+                } else if let Some(offset) = self.file_offset_read(curr_addr)? {
                     // Partial cluster - zero it out.
                     self.raw_file.file_mut().write_zeroes_at(offset, count)?;
                 }
@@ -1406,16 +1579,19 @@ impl QcowFile {
         Ok(())
     }
 
-    // Reads an L2 cluster from the disk, returning an error if the file can't be read or if any
-    // cluster is compressed.
+    // Reads an L2 cluster from the disk, returning an error if the file can't be read. The entries
+    // of compressed clusters keep their flag and descriptor, which can't be masked as an offset.
     fn read_l2_cluster(raw_file: &mut QcowRawFile, cluster_addr: u64) -> std::io::Result<Vec<u64>> {
         let file_values = raw_file.read_pointer_cluster(cluster_addr, None)?;
-        if file_values.iter().any(|entry| entry & COMPRESSED_FLAG != 0) {
-            return Err(std::io::Error::from_raw_os_error(ENOTSUP));
-        }
         Ok(file_values
             .iter()
-            .map(|entry| *entry & L2_TABLE_OFFSET_MASK)
+            .map(|entry| {
+                if entry & COMPRESSED_FLAG != 0 {
+                    *entry & !CLUSTER_USED_FLAG
+                } else {
+                    *entry & L2_TABLE_OFFSET_MASK
+                }
+            })
             .collect())
     }
 
@@ -1532,10 +1708,15 @@ impl Read for QcowFile {
         let mut nread: usize = 0;
         while nread < read_count {
             let curr_addr = address + nread as u64;
-            let file_offset = self.file_offset_read(curr_addr)?;
+            let entry = self.l2_entry(curr_addr)?;
             let count = self.limit_range_cluster(curr_addr, read_count - nread);
 
-            if let Some(offset) = file_offset {
+            if entry & COMPRESSED_FLAG != 0 {
+                let start = self.raw_file.cluster_offset(curr_addr) as usize;
+                let cluster = self.read_compressed_cluster(entry)?;
+                buf[nread..(nread + count)].copy_from_slice(&cluster[start..(start + count)]);
+            } else if entry != 0 {
+                let offset = entry + self.raw_file.cluster_offset(curr_addr);
                 self.raw_file.file_mut().seek(SeekFrom::Start(offset))?;
                 self.raw_file
                     .file_mut()
@@ -1691,6 +1872,66 @@ impl BlockBackend for QcowFile {
     }
 }
 
+// Returns the host offset and the size of the data of the compressed cluster described by the L2
+// entry `entry`, in an image of clusters of `cluster_bits`.
+fn compressed_cluster_range(entry: u64, cluster_bits: u32) -> (u64, u64) {
+    // The offset is followed by the number of additional 512 bytes sectors the data spans.
+    let offset_bits = 62 - (cluster_bits - 8);
+    let offset = entry & ((1 << offset_bits) - 1);
+    let sectors = ((entry & !(COMPRESSED_FLAG | CLUSTER_USED_FLAG)) >> offset_bits) + 1;
+    (offset, sectors * 512 - (offset & 511))
+}
+
+// Returns the addresses of the host clusters the data of a compressed cluster is stored in.
+fn compressed_host_clusters(entry: u64, cluster_bits: u32) -> impl Iterator<Item = u64> {
+    let (offset, size) = compressed_cluster_range(entry, cluster_bits);
+    let cluster_mask = (0x01u64 << cluster_bits) - 1;
+    let first = offset & !cluster_mask;
+    let last = (offset + size - 1) & !cluster_mask;
+    (first..=last).step_by(1 << cluster_bits)
+}
+
+#[cfg(feature = "qcow_compression")]
+fn decompress_cluster(
+    compression_type: u8,
+    compressed: &[u8],
+    cluster_size: usize,
+) -> std::io::Result<Vec<u8>> {
+    let mut data = vec![0u8; cluster_size];
+    let complete = match compression_type {
+        COMPRESSION_TYPE_ZLIB => {
+            // The data is a raw deflate stream, without any zlib header.
+            let mut decompress = flate2::Decompress::new(false);
+            decompress
+                .decompress(compressed, &mut data, flate2::FlushDecompress::Finish)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            decompress.total_out() == cluster_size as u64
+        }
+        // The data may be split in several frames.
+        COMPRESSION_TYPE_ZSTD => zstd::stream::read::Decoder::with_buffer(compressed)?
+            .read_exact(&mut data)
+            .is_ok(),
+        _ => return Err(std::io::Error::from_raw_os_error(ENOTSUP)),
+    };
+    if !complete {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "compressed cluster is truncated",
+        ));
+    }
+    Ok(data)
+}
+
+#[cfg(not(feature = "qcow_compression"))]
+fn decompress_cluster(
+    _compression_type: u8,
+    _compressed: &[u8],
+    _cluster_size: usize,
+) -> std::io::Result<Vec<u8>> {
+    // Images with compressed clusters are rejected when opened.
+    Err(std::io::Error::from_raw_os_error(ENOTSUP))
+}
+
 // Returns an Error if the given offset doesn't align to a cluster boundary.
 fn offset_is_cluster_boundary(offset: u64, cluster_bits: u32) -> Result<()> {
     if offset & ((0x01 << cluster_bits) - 1) != 0 {
@@ -1966,6 +2207,39 @@ mod tests {
         assert_eq!(&buf, b"test");
     }
 
+    #[test]
+    fn read_relative_backing_file() {
+        let test_dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let open_image = |name: &str| {
+            RawFile::new(
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(test_dir.as_path().join(name))
+                    .expect("Failed to create image file."),
+                false,
+            )
+        };
+
+        let mut backing = QcowFile::new(open_image("base.img"), 3, 0x10_0000).unwrap();
+        backing
+            .write_all(b"test first bytes")
+            .expect("Failed to write test string.");
+        backing.flush().expect("Failed to flush.");
+        drop(backing);
+
+        // The backing file is looked up next to the overlay, not in the
+        // current directory.
+        let header = QcowHeader::create_for_size_and_path(3, 0x10_0000, Some("base.img")).unwrap();
+        let mut wrapping = QcowFile::new_from_header(open_image("overlay.img"), header).unwrap();
+        let mut buf = [0u8; 4];
+        wrapping.seek(SeekFrom::Start(0)).expect("Failed to seek.");
+        wrapping.read_exact(&mut buf).expect("Failed to read.");
+        assert_eq!(&buf, b"test");
+    }
+
     #[test]
     fn default_header_v2() {
         let header = QcowHeader::create_for_size_and_path(2, 0x10_0000, None);
@@ -2072,12 +2346,9 @@ mod tests {
         assert!(matches!(res.unwrap_err(), Error::MaxNestingDepthExceeded));
     }
 
-    /// Create a qcow2 file with itself as its backing file.
-    ///
-    /// Without configuration `max_nesting_depth`, this will cause infinite recursion when loading
-    /// the file until stack overflow.
-    fn new_self_referential_qcow(path: &Path) -> Result<()> {
-        let header = QcowHeader::create_for_size_and_path(3, 0x10_0000, path.to_str())?;
+    /// Create a qcow2 file header referencing `backing_path` as its backing file.
+    fn new_qcow_with_backing(path: &Path, backing_path: &Path) -> Result<()> {
+        let header = QcowHeader::create_for_size_and_path(3, 0x10_0000, backing_path.to_str())?;
         let mut disk_file = RawFile::new(
             File::create(path).expect("Failed to create image file."),
             false,
@@ -2087,11 +2358,11 @@ mod tests {
     }
 
     #[test]
-    fn max_nesting_backing() {
+    fn self_referential_backing() {
         let test_dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
         let img_path = test_dir.as_path().join("test.img");
 
-        new_self_referential_qcow(img_path.as_path()).unwrap();
+        new_qcow_with_backing(img_path.as_path(), img_path.as_path()).unwrap();
 
         let err = QcowFile::from_with_nesting_depth(
             RawFile::new(
@@ -2102,6 +2373,28 @@ mod tests {
         )
         .expect_err("Opening qcow file with itself as backing file should fail.");
 
+        assert!(format!("{err:?}").contains(&format!("{:?}", Error::BackingFileCycle)));
+    }
+
+    #[test]
+    fn max_nesting_backing() {
+        let test_dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let img_path = |i: u32| test_dir.as_path().join(format!("test{i}.img"));
+
+        // Each image is backed by the next one, one more than allowed.
+        for i in 0..=MAX_NESTING_DEPTH {
+            new_qcow_with_backing(img_path(i).as_path(), img_path(i + 1).as_path()).unwrap();
+        }
+
+        let err = QcowFile::from_with_nesting_depth(
+            RawFile::new(
+                File::open(img_path(0).as_path()).expect("Failed to open qcow image file"),
+                false,
+            ),
+            MAX_NESTING_DEPTH,
+        )
+        .expect_err("Opening a too deep backing chain should fail.");
+
         // This type of error is complex. For comparing easily, we can check if it contains the
         // type name after formatting.
         assert!(format!("{err:?}").contains(&format!("{:?}", Error::MaxNestingDepthExceeded)));
@@ -2898,6 +3191,117 @@ mod tests {
         });
     }
 
+    #[test]
+    fn allocated_extents_backing() {
+        let new_file = |size| {
+            QcowFile::new(
+                RawFile::new(TempFile::new().unwrap().into_file(), false),
+                3,
+                size,
+            )
+            .unwrap()
+        };
+        let b = [0x55u8; 0x10000];
+
+        let mut backing = new_file(0x40000);
+        backing.seek(SeekFrom::Start(0)).unwrap();
+        backing.write_all(&b).unwrap();
+        // Beyond the end of the wrapping image.
+        backing.seek(SeekFrom::Start(0x30000)).unwrap();
+        backing.write_all(&b).unwrap();
+
+        let mut file = new_file(0x30000);
+        file.seek(SeekFrom::Start(0x10000)).unwrap();
+        file.write_all(&b).unwrap();
+        assert_eq!(
+            file.allocated_extents().unwrap(),
+            vec![DiskExtent {
+                offset: 0x10000,
+                length: 0x10000
+            }]
+        );
+
+        file.set_backing_file(Some(Box::new(backing)));
+        assert_eq!(
+            file.allocated_extents().unwrap(),
+            vec![DiskExtent {
+                offset: 0,
+                length: 0x20000
+            }]
+        );
+    }
+
+    #[cfg(feature = "qcow_compression")]
+    #[test]
+    fn read_write_compressed_cluster() {
+        for compression_type in [COMPRESSION_TYPE_ZLIB, COMPRESSION_TYPE_ZSTD] {
+            let data: Vec<u8> = (0..0x10000).map(|i| (i / 512) as u8).collect();
+            let compressed = if compression_type == COMPRESSION_TYPE_ZLIB {
+                let mut encoder =
+                    flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&data).unwrap();
+                encoder.finish().unwrap()
+            } else {
+                zstd::bulk::compress(&data, 0).unwrap()
+            };
+
+            // Store the cluster the way qemu-img does, its data replacing the one of a standard
+            // cluster, and the L2 entry holding the number of additional sectors it spans.
+            let mut qcow = QcowFile::new(
+                RawFile::new(TempFile::new().unwrap().into_file(), false),
+                3,
+                0x40000,
+            )
+            .unwrap();
+            qcow.write_all(&data).unwrap();
+            let host_offset = qcow.file_offset_read(0).unwrap().unwrap();
+            let sectors = compressed.len().div_ceil(512) as u64 - 1;
+            qcow.l2_cache.get_mut(0).unwrap()[0] = COMPRESSED_FLAG | (sectors << 54) | host_offset;
+            qcow.flush().unwrap();
+
+            let mut file = qcow.raw_file.file_mut().try_clone().unwrap();
+            file.seek(SeekFrom::Start(host_offset)).unwrap();
+            file.write_all(&compressed).unwrap();
+            file.write_all(&vec![0u8; 0x10000 - compressed.len()])
+                .unwrap();
+            if compression_type == COMPRESSION_TYPE_ZSTD {
+                file.seek(SeekFrom::Start(72)).unwrap();
+                file.write_u64::<BigEndian>(INCOMPATIBLE_FEATURES_COMPRESSION_TYPE)
+                    .unwrap();
+                file.seek(SeekFrom::Start(u64::from(V3_BARE_HEADER_SIZE)))
+                    .unwrap();
+                file.write_all(&[COMPRESSION_TYPE_ZSTD]).unwrap();
+            }
+            drop(qcow);
+
+            file.rewind().unwrap();
+            let mut qcow = QcowFile::from(file).unwrap();
+            assert_eq!(qcow.header.compression_type, compression_type);
+            assert!(qcow.check().unwrap().errors.is_empty());
+
+            let mut buf = vec![0u8; 0x10000];
+            qcow.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, data);
+            qcow.seek(SeekFrom::Start(0x1200)).unwrap();
+            qcow.read_exact(&mut buf[..0x400]).unwrap();
+            assert_eq!(buf[..0x400], data[0x1200..0x1600]);
+
+            // Writing copies the cluster to a standard one, unreferencing the compressed data.
+            qcow.seek(SeekFrom::Start(0x800)).unwrap();
+            qcow.write_all(&[0xaa; 0x200]).unwrap();
+            assert_ne!(qcow.file_offset_read(0).unwrap().unwrap(), host_offset);
+            qcow.rewind().unwrap();
+            qcow.read_exact(&mut buf).unwrap();
+            assert!(buf[0x800..0xa00].iter().all(|b| *b == 0xaa));
+            assert_eq!(buf[..0x800], data[..0x800]);
+            assert_eq!(buf[0xa00..], data[0xa00..]);
+            qcow.flush().unwrap();
+            let report = qcow.check().unwrap();
+            assert!(report.errors.is_empty());
+            assert!(!report.leaks.contains(&host_offset));
+        }
+    }
+
     #[test]
     fn rebuild_refcounts() {
         with_basic_file(&valid_header_v3(), |mut disk_file: RawFile| {
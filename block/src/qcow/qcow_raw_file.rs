@@ -4,7 +4,7 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
-use super::RawFile;
+use super::{RawFile, COMPRESSED_FLAG};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use std::io::{self, BufWriter, Seek, SeekFrom, Write};
 use std::mem::size_of;
@@ -59,8 +59,8 @@ impl QcowRawFile {
     }
 
     /// Writes `table` of u64 pointers to `offset` in the file.
-    /// `non_zero_flags` will be ORed with all non-zero values in `table`.
-    /// writing.
+    /// `non_zero_flags` will be ORed with all non-zero values in `table`, except for the entries
+    /// of compressed clusters, which are written as they are.
     pub fn write_pointer_table(
         &mut self,
         offset: u64,
@@ -70,8 +70,8 @@ impl QcowRawFile {
         self.file.seek(SeekFrom::Start(offset))?;
         let mut buffer = BufWriter::with_capacity(std::mem::size_of_val(table), &mut self.file);
         for addr in table {
-            let val = if *addr == 0 {
-                0
+            let val = if *addr == 0 || *addr & COMPRESSED_FLAG != 0 {
+                *addr
             } else {
                 *addr | non_zero_flags
             };
@@ -0,0 +1,322 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Rebase of qcow2 images onto another backing file, as done by
+//! `qemu-img rebase`, without changing the data the guest reads.
+
+use crate::qcow::{Error, QcowFile, RawFile, Result, MAX_BACKING_FILE_SIZE, MAX_NESTING_DEPTH};
+use crate::resolve_backing_file_path;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::cmp::min;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+
+// Offset of the backing file offset and size in the header.
+const BACKING_FILE_OFFSET_OFFSET: u64 = 8;
+
+/// How an image is moved onto its new backing file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RebaseMode {
+    /// The clusters the image doesn't allocate, and which read differently
+    /// from the old and the new backing file, are copied into the image
+    /// before the header is changed, so the image reads the same data.
+    Safe,
+    /// Only the header is changed, which is only correct if both backing
+    /// files hold the same data.
+    Unsafe,
+}
+
+// Reads the data at `address` of `backing` into `buf`, as zeroes past its
+// end or if there is no backing file.
+fn read_backing(backing: Option<&mut QcowFile>, address: u64, buf: &mut [u8]) -> io::Result<()> {
+    buf.fill(0);
+    if let Some(backing) = backing {
+        let count = backing.limit_range_file(address, buf.len());
+        if count > 0 {
+            backing.seek(SeekFrom::Start(address))?;
+            backing.read_exact(&mut buf[..count])?;
+        }
+    }
+    Ok(())
+}
+
+impl QcowFile {
+    /// Rebases the image onto the qcow2 backing file at `backing_file_path`,
+    /// or onto none, in which case the clusters the image doesn't allocate
+    /// read as zeroes. The path is stored in the header as given, a relative
+    /// one being looked up next to the image. Returns the number of clusters
+    /// copied into the image.
+    pub fn rebase(&mut self, backing_file_path: Option<&str>, mode: RebaseMode) -> Result<u64> {
+        let mut new_backing = match backing_file_path {
+            Some(path) => Some(Box::new(self.open_backing_file(path)?)),
+            None => None,
+        };
+
+        let copied = if mode == RebaseMode::Safe {
+            // The image is written without its old backing file, which would
+            // otherwise be read to fill the clusters allocated, these being
+            // written whole anyway.
+            let mut old_backing = self.backing_file.take();
+            let result = self
+                .copy_backing_differences(old_backing.as_deref_mut(), new_backing.as_deref_mut());
+            self.backing_file = old_backing;
+            result?
+        } else {
+            0
+        };
+        // The copied clusters must be on disk before the header stops
+        // referencing the old backing file.
+        self.flush().map_err(Error::SyncingCaches)?;
+
+        self.write_backing_file_path(backing_file_path)?;
+        self.backing_file = new_backing;
+        Ok(copied)
+    }
+
+    // Opens the backing file at `path` the way the image would open it, the
+    // image being part of the chain so that rebasing it onto itself, or onto
+    // an image backed by it, is caught as a cycle.
+    fn open_backing_file(&mut self, path: &str) -> Result<QcowFile> {
+        let file = self.raw_file.file_mut();
+        let metadata = file.metadata().map_err(Error::GettingFileSize)?;
+        let direct_io = file.is_direct();
+        let backing_raw_file = OpenOptions::new()
+            .read(true)
+            .open(resolve_backing_file_path(&*file, path))
+            .map_err(Error::BackingFileIo)?;
+        Self::from_with_chain(
+            RawFile::new(backing_raw_file, direct_io),
+            MAX_NESTING_DEPTH - 1,
+            &mut vec![(metadata.dev(), metadata.ino())],
+        )
+        .map_err(|e| Error::BackingFileOpen(Box::new(e)))
+    }
+
+    // Copies into the image the clusters it doesn't allocate, and which read
+    // differently from `old_backing` and `new_backing`.
+    fn copy_backing_differences(
+        &mut self,
+        mut old_backing: Option<&mut QcowFile>,
+        mut new_backing: Option<&mut QcowFile>,
+    ) -> Result<u64> {
+        let cluster_size = self.raw_file.cluster_size();
+        let mut old_data = vec![0u8; cluster_size as usize];
+        let mut new_data = vec![0u8; cluster_size as usize];
+        let mut copied = 0;
+        let mut address = 0;
+        while address < self.virtual_size() {
+            let count = min(cluster_size, self.virtual_size() - address) as usize;
+            if self.l2_entry(address).map_err(Error::ReadingData)? == 0 {
+                read_backing(old_backing.as_deref_mut(), address, &mut old_data[..count])
+                    .map_err(Error::BackingFileIo)?;
+                read_backing(new_backing.as_deref_mut(), address, &mut new_data[..count])
+                    .map_err(Error::BackingFileIo)?;
+                if old_data[..count] != new_data[..count] {
+                    self.seek(SeekFrom::Start(address))
+                        .map_err(Error::SeekingFile)?;
+                    self.write_all(&old_data[..count])
+                        .map_err(Error::WritingData)?;
+                    copied += 1;
+                }
+            }
+            address += cluster_size;
+        }
+        Ok(copied)
+    }
+
+    // Offset the name of the backing file is stored at, which is the one of
+    // the current name, or else the end of the header extensions, as with
+    // QEMU.
+    fn backing_file_name_offset(&mut self) -> Result<u64> {
+        if self.header.backing_file_offset != 0 {
+            return Ok(self.header.backing_file_offset);
+        }
+
+        let cluster_size = self.raw_file.cluster_size();
+        let file = self.raw_file.file_mut();
+        let mut offset = u64::from(self.header.header_size);
+        loop {
+            // Each extension starts with its type and length, the end of
+            // the extensions being marked by a type of 0.
+            if offset + 8 > cluster_size {
+                return Err(Error::InvalidOffset(offset));
+            }
+            file.seek(SeekFrom::Start(offset))
+                .map_err(Error::ReadingHeader)?;
+            let kind = file.read_u32::<BigEndian>().map_err(Error::ReadingHeader)?;
+            let length = file.read_u32::<BigEndian>().map_err(Error::ReadingHeader)?;
+            offset += 8;
+            if kind == 0 {
+                return Ok(offset);
+            }
+            offset += u64::from(length).next_multiple_of(8);
+        }
+    }
+
+    // Points the header at the backing file `path`, or at none, the name
+    // being written before the header references it.
+    fn write_backing_file_path(&mut self, path: Option<&str>) -> Result<()> {
+        let (offset, size) = match path {
+            Some(path) => {
+                let offset = self.backing_file_name_offset()?;
+                // The name must fit in the first cluster, with the header.
+                let max_length = min(
+                    u64::from(MAX_BACKING_FILE_SIZE),
+                    self.raw_file.cluster_size().saturating_sub(offset),
+                ) as usize;
+                if path.len() > max_length {
+                    return Err(Error::BackingFileTooLong(path.len() - max_length));
+                }
+                let file = self.raw_file.file_mut();
+                file.seek(SeekFrom::Start(offset))
+                    .map_err(Error::WritingHeader)?;
+                file.write_all(path.as_bytes())
+                    .map_err(Error::WritingHeader)?;
+                file.sync_data().map_err(Error::WritingHeader)?;
+                (offset, path.len() as u32)
+            }
+            None => (0, 0),
+        };
+
+        let file = self.raw_file.file_mut();
+        file.seek(SeekFrom::Start(BACKING_FILE_OFFSET_OFFSET))
+            .map_err(Error::WritingHeader)?;
+        file.write_u64::<BigEndian>(offset)
+            .map_err(Error::WritingHeader)?;
+        file.write_u32::<BigEndian>(size)
+            .map_err(Error::WritingHeader)?;
+        file.sync_data().map_err(Error::WritingHeader)?;
+
+        self.header.backing_file_offset = offset;
+        self.header.backing_file_size = size;
+        self.header.backing_file_path = path.map(String::from);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qcow::QcowHeader;
+    use vmm_sys_util::tempdir::TempDir;
+
+    const CLUSTER_SIZE: u64 = 0x1_0000;
+
+    fn open_image(dir: &TempDir, name: &str) -> RawFile {
+        RawFile::new(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(dir.as_path().join(name))
+                .unwrap(),
+            false,
+        )
+    }
+
+    // Creates the image `name`, with each cluster of `clusters` filled with
+    // its pattern.
+    fn new_image(dir: &TempDir, name: &str, backing: Option<&str>, clusters: &[(u64, u8)]) {
+        let header = QcowHeader::create_for_size_and_path(3, 4 * CLUSTER_SIZE, backing).unwrap();
+        let mut qcow = QcowFile::new_from_header(open_image(dir, name), header).unwrap();
+        for (cluster, pattern) in clusters {
+            qcow.seek(SeekFrom::Start(cluster * CLUSTER_SIZE)).unwrap();
+            qcow.write_all(&[*pattern; CLUSTER_SIZE as usize]).unwrap();
+        }
+        qcow.flush().unwrap();
+    }
+
+    fn reopen_image(dir: &TempDir, name: &str) -> QcowFile {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(dir.as_path().join(name))
+            .unwrap();
+        QcowFile::from(RawFile::new(file, false)).unwrap()
+    }
+
+    fn read_clusters(qcow: &mut QcowFile) -> Vec<u8> {
+        let mut data = vec![0u8; 4 * CLUSTER_SIZE as usize];
+        qcow.rewind().unwrap();
+        qcow.read_exact(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_rebase_safe() {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        new_image(&dir, "old.img", None, &[(0, 0x11), (1, 0x11), (2, 0x11)]);
+        new_image(&dir, "new.img", None, &[(1, 0x11), (2, 0x22), (3, 0x22)]);
+        new_image(&dir, "overlay.img", Some("old.img"), &[(0, 0x33)]);
+
+        let mut overlay = reopen_image(&dir, "overlay.img");
+        let data = read_clusters(&mut overlay);
+        // The first cluster is allocated by the overlay, and the second one
+        // matches, leaving the last two to copy.
+        assert_eq!(
+            overlay.rebase(Some("new.img"), RebaseMode::Safe).unwrap(),
+            2
+        );
+        assert_eq!(read_clusters(&mut overlay), data);
+        drop(overlay);
+
+        let mut overlay = reopen_image(&dir, "overlay.img");
+        assert_eq!(
+            overlay.header().backing_file_path.as_deref(),
+            Some("new.img")
+        );
+        assert_eq!(read_clusters(&mut overlay), data);
+        assert!(overlay.check().unwrap().errors.is_empty());
+    }
+
+    #[test]
+    fn test_rebase_unsafe() {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        new_image(&dir, "old.img", None, &[(0, 0x11)]);
+        new_image(&dir, "new.img", None, &[(0, 0x22)]);
+        new_image(&dir, "overlay.img", Some("old.img"), &[]);
+
+        // The overlay reads the data of the new backing file, nothing being
+        // copied.
+        let mut overlay = reopen_image(&dir, "overlay.img");
+        assert_eq!(
+            overlay.rebase(Some("new.img"), RebaseMode::Unsafe).unwrap(),
+            0
+        );
+        drop(overlay);
+        let mut overlay = reopen_image(&dir, "overlay.img");
+        assert_eq!(read_clusters(&mut overlay)[0], 0x22);
+    }
+
+    #[test]
+    fn test_rebase_onto_none() {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        new_image(&dir, "old.img", None, &[(1, 0x11)]);
+        new_image(&dir, "overlay.img", Some("old.img"), &[]);
+
+        let mut overlay = reopen_image(&dir, "overlay.img");
+        let data = read_clusters(&mut overlay);
+        assert_eq!(overlay.rebase(None, RebaseMode::Safe).unwrap(), 1);
+        drop(overlay);
+
+        let mut overlay = reopen_image(&dir, "overlay.img");
+        assert!(overlay.header().backing_file_path.is_none());
+        assert_eq!(read_clusters(&mut overlay), data);
+    }
+
+    #[test]
+    fn test_rebase_cycle() {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        new_image(&dir, "overlay.img", None, &[]);
+
+        let mut overlay = reopen_image(&dir, "overlay.img");
+        assert!(matches!(
+            overlay.rebase(Some("overlay.img"), RebaseMode::Unsafe),
+            Err(Error::BackingFileOpen(e)) if matches!(*e, Error::BackingFileCycle)
+        ));
+        assert!(overlay.header().backing_file_path.is_none());
+    }
+}
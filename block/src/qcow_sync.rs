@@ -2,9 +2,12 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
-use crate::async_io::{AsyncIo, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult};
-use crate::qcow::{QcowFile, RawFile, Result as QcowResult};
-use crate::AsyncAdaptor;
+use crate::async_io::{
+    completion_room, AsyncIo, AsyncIoResult, DiskExtent, DiskFile, DiskFileError, DiskFileResult,
+    IoAlignment,
+};
+use crate::qcow::{CheckReport, Error as QcowError, QcowFile, RawFile, Result as QcowResult};
+use crate::{AsyncAdaptor, DiskTopology};
 use std::collections::VecDeque;
 use std::fs::File;
 use std::io::{Seek, SeekFrom};
@@ -13,14 +16,45 @@ use vmm_sys_util::eventfd::EventFd;
 
 pub struct QcowDiskSync {
     qcow_file: Arc<Mutex<QcowFile>>,
+    logical_block_size: Option<u64>,
 }
 
 impl QcowDiskSync {
-    pub fn new(file: File, direct_io: bool) -> QcowResult<Self> {
+    /// Opens the image, reporting `logical_block_size` to the guest if
+    /// given. The clusters are still read and written whole, so it can't be
+    /// larger than them, and the virtual size must hold whole blocks.
+    pub fn new(file: File, direct_io: bool, logical_block_size: Option<u64>) -> QcowResult<Self> {
+        let qcow_file = QcowFile::from(RawFile::new(file, direct_io))?;
+        if let Some(block_size) = logical_block_size {
+            let header = qcow_file.header();
+            let cluster_size = 1u64 << header.cluster_bits;
+            if block_size > cluster_size {
+                return Err(QcowError::BlockSizeTooLarge(block_size, cluster_size));
+            }
+            if header.size % block_size != 0 {
+                return Err(QcowError::SizeNotMultipleOfBlockSize(
+                    header.size,
+                    block_size,
+                ));
+            }
+        }
+
         Ok(QcowDiskSync {
-            qcow_file: Arc::new(Mutex::new(QcowFile::from(RawFile::new(file, direct_io))?)),
+            qcow_file: Arc::new(Mutex::new(qcow_file)),
+            logical_block_size,
         })
     }
+
+    /// Checks the consistency of the image.
+    pub fn check(&self) -> QcowResult<CheckReport> {
+        self.qcow_file.lock().unwrap().check()
+    }
+
+    /// Checks the image and frees the leaked clusters if no other
+    /// inconsistency was found.
+    pub fn repair_leaks(&self) -> QcowResult<CheckReport> {
+        self.qcow_file.lock().unwrap().repair_leaks()
+    }
 }
 
 impl DiskFile for QcowDiskSync {
@@ -31,20 +65,44 @@ impl DiskFile for QcowDiskSync {
     }
 
     fn new_async_io(&self, _ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
-        Ok(Box::new(QcowSync::new(self.qcow_file.clone())) as Box<dyn AsyncIo>)
+        Ok(Box::new(QcowSync::new(
+            self.qcow_file.clone(),
+            self.logical_block_size,
+        )) as Box<dyn AsyncIo>)
+    }
+
+    fn topology(&mut self) -> DiskTopology {
+        let mut topology = DiskTopology::default();
+        if let Some(size) = self.logical_block_size {
+            topology.logical_block_size = size;
+            topology.physical_block_size = size;
+            topology.minimum_io_size = size;
+        }
+
+        topology
+    }
+
+    fn extents(&mut self) -> DiskFileResult<Vec<DiskExtent>> {
+        self.qcow_file
+            .lock()
+            .unwrap()
+            .allocated_extents()
+            .map_err(DiskFileError::Extents)
     }
 }
 
 pub struct QcowSync {
     qcow_file: Arc<Mutex<QcowFile>>,
+    logical_block_size: Option<u64>,
     eventfd: EventFd,
     completion_list: VecDeque<(u64, i32)>,
 }
 
 impl QcowSync {
-    pub fn new(qcow_file: Arc<Mutex<QcowFile>>) -> Self {
+    pub fn new(qcow_file: Arc<Mutex<QcowFile>>, logical_block_size: Option<u64>) -> Self {
         QcowSync {
             qcow_file,
+            logical_block_size,
             eventfd: EventFd::new(libc::EFD_NONBLOCK)
                 .expect("Failed creating EventFd for QcowSync"),
             completion_list: VecDeque::new(),
@@ -63,6 +121,22 @@ impl AsyncIo for QcowSync {
         &self.eventfd
     }
 
+    fn required_alignment(&self) -> IoAlignment {
+        // The guest must not write less than the block size it was given,
+        // whatever the image itself can serve.
+        match self.logical_block_size {
+            Some(offset) => IoAlignment {
+                offset,
+                ..IoAlignment::SECTOR
+            },
+            None => IoAlignment::SECTOR,
+        }
+    }
+
+    fn submission_room(&self) -> usize {
+        completion_room(self.completion_list.len())
+    }
+
     fn read_vectored(
         &mut self,
         offset: libc::off_t,
@@ -102,3 +176,77 @@ impl AsyncIo for QcowSync {
         self.completion_list.pop_front()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const DISK_SIZE: u64 = 0x10_0000;
+
+    fn new_image() -> TempFile {
+        let temp_file = TempFile::new().unwrap();
+        let file = RawFile::new(temp_file.as_file().try_clone().unwrap(), false);
+        QcowFile::new(file, 3, DISK_SIZE).unwrap();
+        temp_file
+    }
+
+    #[test]
+    fn test_qcow_logical_block_size() {
+        let temp_file = new_image();
+        let mut disk =
+            QcowDiskSync::new(temp_file.as_file().try_clone().unwrap(), false, Some(4096)).unwrap();
+        let topology = disk.topology();
+        assert_eq!(topology.logical_block_size, 4096);
+        assert_eq!(topology.physical_block_size, 4096);
+        assert_eq!(topology.minimum_io_size, 4096);
+        assert_eq!(disk.size().unwrap(), DISK_SIZE);
+
+        let mut io = disk.new_async_io(1).unwrap();
+        assert_eq!(io.required_alignment().offset, 4096);
+
+        let mut data = vec![0x55u8; 4096];
+        let iovecs = [libc::iovec {
+            iov_base: data.as_mut_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        }];
+        io.write_vectored(0x2000, &iovecs, 1).unwrap();
+        assert_eq!(io.next_completed_request(), Some((1, 4096)));
+
+        let mut buf = vec![0u8; 4096];
+        let iovecs = [libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }];
+        io.read_vectored(0x2000, &iovecs, 2).unwrap();
+        assert_eq!(io.next_completed_request(), Some((2, 4096)));
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn test_qcow_default_block_size() {
+        let temp_file = new_image();
+        let mut disk =
+            QcowDiskSync::new(temp_file.as_file().try_clone().unwrap(), false, None).unwrap();
+        assert_eq!(disk.topology().logical_block_size, 512);
+        let io = disk.new_async_io(1).unwrap();
+        assert_eq!(io.required_alignment(), IoAlignment::SECTOR);
+    }
+
+    #[test]
+    fn test_qcow_block_size_larger_than_cluster() {
+        let temp_file = new_image();
+        // The clusters of a new image are 64 KiB.
+        let err = QcowDiskSync::new(
+            temp_file.as_file().try_clone().unwrap(),
+            false,
+            Some(0x2_0000),
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(
+            err,
+            QcowError::BlockSizeTooLarge(0x2_0000, 0x1_0000)
+        ));
+    }
+}
@@ -0,0 +1,815 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! QEMU Enhanced Disk (QED) images.
+//!
+//! Guest clusters are mapped to clusters of the image file through a two
+//! level table. Unallocated clusters read from the backing file, if any.
+//! Metadata is written through, a new cluster being written before the
+//! table entry referencing it, so that tables never point to garbage.
+
+use crate::qcow::RawFile;
+use crate::{
+    create_disk_file, detect_image_type, resolve_backing_file_path, BlockBackend, ImageType,
+    SECTOR_SIZE,
+};
+use byteorder::{ByteOrder, LittleEndian};
+use remain::sorted;
+use std::cmp::min;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::str;
+use thiserror::Error;
+
+/// "QED\0", little endian.
+pub const QED_MAGIC: u32 = 0x0044_4551;
+
+// Size of the header structure at the start of the image.
+const HEADER_SIZE: usize = 64;
+
+const MIN_CLUSTER_SIZE: u32 = 4096;
+const MAX_CLUSTER_SIZE: u32 = 64 * 1024 * 1024;
+const MIN_TABLE_SIZE: u32 = 1;
+const MAX_TABLE_SIZE: u32 = 16;
+
+// Layout of the images created, matching qemu-img.
+const DEFAULT_CLUSTER_SIZE: u32 = 64 * 1024;
+const DEFAULT_TABLE_SIZE: u32 = 4;
+
+// The image references a backing file.
+const FEATURE_BACKING_FILE: u64 = 1 << 0;
+// The image may not have been closed cleanly, and may leak clusters.
+const FEATURE_NEED_CHECK: u64 = 1 << 1;
+// The backing file is a raw image, whose format must not be probed.
+const FEATURE_BACKING_FORMAT_NO_PROBE: u64 = 1 << 2;
+const SUPPORTED_FEATURES: u64 =
+    FEATURE_BACKING_FILE | FEATURE_NEED_CHECK | FEATURE_BACKING_FORMAT_NO_PROBE;
+
+// Table entry of a cluster reading as zeroes, whatever the backing file
+// holds.
+const ZERO_CLUSTER: u64 = 1;
+
+// Number of L2 tables kept in memory.
+const L2_CACHE_SIZE: usize = 64;
+
+// Maximum depth of the backing chain.
+const MAX_NESTING_DEPTH: u32 = 10;
+
+#[sorted]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Backing file chain contains a cycle")]
+    BackingFileCycle,
+    #[error("Backing file io error: {0}")]
+    BackingFileIo(#[source] io::Error),
+    #[error("Backing file name is not within the header")]
+    BackingFileNameOffHeader,
+    #[error("Backing file open error: {0}")]
+    BackingFileOpen(#[source] Box<crate::Error>),
+    #[error("Failed getting the file metadata: {0}")]
+    GettingFileMetadata(#[source] io::Error),
+    #[error("Invalid backing file name: {0}")]
+    InvalidBackingFileName(#[source] str::Utf8Error),
+    #[error("Invalid cluster size: {0}")]
+    InvalidClusterSize(u32),
+    #[error("Invalid header size: {0} clusters")]
+    InvalidHeaderSize(u32),
+    #[error("Invalid image size: {0}")]
+    InvalidImageSize(u64),
+    #[error("Invalid L1 table offset: {0:#x}")]
+    InvalidL1TableOffset(u64),
+    #[error("Invalid magic: {0:#x}")]
+    InvalidMagic(u32),
+    #[error("Invalid table size: {0} clusters")]
+    InvalidTableSize(u32),
+    #[error("Maximum backing file nesting depth exceeded")]
+    MaxNestingDepthExceeded,
+    #[error("Failed reading the header: {0}")]
+    ReadingHeader(#[source] io::Error),
+    #[error("Failed reading the L1 table: {0}")]
+    ReadingL1Table(#[source] io::Error),
+    #[error("Unsupported incompatible features: {0:#x}")]
+    UnsupportedFeatures(u64),
+    #[error("Failed writing the header: {0}")]
+    WritingHeader(#[source] io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QedHeader {
+    pub magic: u32,
+    /// Size of a cluster in bytes.
+    pub cluster_size: u32,
+    /// Size of a table in clusters.
+    pub table_size: u32,
+    /// Size of the header in clusters.
+    pub header_size: u32,
+    /// Incompatible features, the image can't be used without support.
+    pub features: u64,
+    /// Compatible features, safe to ignore.
+    pub compat_features: u64,
+    /// Features to clear when the image is modified without support.
+    pub autoclear_features: u64,
+    pub l1_table_offset: u64,
+    /// Virtual size of the image in bytes.
+    pub image_size: u64,
+    pub backing_filename_offset: u32,
+    pub backing_filename_size: u32,
+}
+
+impl QedHeader {
+    fn from_bytes(buf: &[u8; HEADER_SIZE]) -> Self {
+        QedHeader {
+            magic: LittleEndian::read_u32(&buf[0..4]),
+            cluster_size: LittleEndian::read_u32(&buf[4..8]),
+            table_size: LittleEndian::read_u32(&buf[8..12]),
+            header_size: LittleEndian::read_u32(&buf[12..16]),
+            features: LittleEndian::read_u64(&buf[16..24]),
+            compat_features: LittleEndian::read_u64(&buf[24..32]),
+            autoclear_features: LittleEndian::read_u64(&buf[32..40]),
+            l1_table_offset: LittleEndian::read_u64(&buf[40..48]),
+            image_size: LittleEndian::read_u64(&buf[48..56]),
+            backing_filename_offset: LittleEndian::read_u32(&buf[56..60]),
+            backing_filename_size: LittleEndian::read_u32(&buf[60..64]),
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut buf = [0u8; HEADER_SIZE];
+        LittleEndian::write_u32(&mut buf[0..4], self.magic);
+        LittleEndian::write_u32(&mut buf[4..8], self.cluster_size);
+        LittleEndian::write_u32(&mut buf[8..12], self.table_size);
+        LittleEndian::write_u32(&mut buf[12..16], self.header_size);
+        LittleEndian::write_u64(&mut buf[16..24], self.features);
+        LittleEndian::write_u64(&mut buf[24..32], self.compat_features);
+        LittleEndian::write_u64(&mut buf[32..40], self.autoclear_features);
+        LittleEndian::write_u64(&mut buf[40..48], self.l1_table_offset);
+        LittleEndian::write_u64(&mut buf[48..56], self.image_size);
+        LittleEndian::write_u32(&mut buf[56..60], self.backing_filename_offset);
+        LittleEndian::write_u32(&mut buf[60..64], self.backing_filename_size);
+        buf
+    }
+
+    /// Reads the header from the start of `f`, without validating it.
+    pub fn read<F: Read + Seek>(f: &mut F) -> Result<Self> {
+        let mut buf = [0u8; HEADER_SIZE];
+        f.seek(SeekFrom::Start(0)).map_err(Error::ReadingHeader)?;
+        f.read_exact(&mut buf).map_err(Error::ReadingHeader)?;
+        Ok(Self::from_bytes(&buf))
+    }
+
+    fn write<F: Write + Seek>(&self, f: &mut F) -> Result<()> {
+        f.seek(SeekFrom::Start(0)).map_err(Error::WritingHeader)?;
+        f.write_all(&self.to_bytes()).map_err(Error::WritingHeader)
+    }
+
+    // Number of entries of a L1 or L2 table.
+    fn table_entries(&self) -> u64 {
+        u64::from(self.table_size) * u64::from(self.cluster_size) / 8
+    }
+
+    // Size of the header area, holding the backing file name.
+    fn header_bytes(&self) -> u64 {
+        u64::from(self.header_size) * u64::from(self.cluster_size)
+    }
+
+    fn max_image_size(&self) -> u64 {
+        self.table_entries()
+            .checked_mul(self.table_entries())
+            .and_then(|clusters| clusters.checked_mul(u64::from(self.cluster_size)))
+            .unwrap_or(u64::MAX)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.magic != QED_MAGIC {
+            return Err(Error::InvalidMagic(self.magic));
+        }
+        if !self.cluster_size.is_power_of_two()
+            || !(MIN_CLUSTER_SIZE..=MAX_CLUSTER_SIZE).contains(&self.cluster_size)
+        {
+            return Err(Error::InvalidClusterSize(self.cluster_size));
+        }
+        if !self.table_size.is_power_of_two()
+            || !(MIN_TABLE_SIZE..=MAX_TABLE_SIZE).contains(&self.table_size)
+        {
+            return Err(Error::InvalidTableSize(self.table_size));
+        }
+        if self.header_size == 0 || self.header_bytes() > u64::from(u32::MAX) {
+            return Err(Error::InvalidHeaderSize(self.header_size));
+        }
+
+        // Using an image with unknown incompatible features would corrupt it.
+        let unsupported = self.features & !SUPPORTED_FEATURES;
+        if unsupported != 0 {
+            return Err(Error::UnsupportedFeatures(unsupported));
+        }
+
+        if self.image_size % SECTOR_SIZE != 0 || self.image_size > self.max_image_size() {
+            return Err(Error::InvalidImageSize(self.image_size));
+        }
+        if self.l1_table_offset == 0
+            || self.l1_table_offset % u64::from(self.cluster_size) != 0
+            || self.l1_table_offset < self.header_bytes()
+        {
+            return Err(Error::InvalidL1TableOffset(self.l1_table_offset));
+        }
+        if self.features & FEATURE_BACKING_FILE != 0
+            && u64::from(self.backing_filename_offset) + u64::from(self.backing_filename_size)
+                > self.header_bytes()
+        {
+            return Err(Error::BackingFileNameOffHeader);
+        }
+
+        Ok(())
+    }
+}
+
+// Mapping of a guest cluster.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Cluster {
+    Unallocated,
+    Zero,
+    Allocated(u64),
+}
+
+#[derive(Debug)]
+pub struct QedFile {
+    raw_file: RawFile,
+    header: QedHeader,
+    l1_table: Vec<u64>,
+    // L2 tables indexed by their offset in the file.
+    l2_cache: HashMap<u64, Vec<u64>>,
+    backing_file: Option<Box<dyn BlockBackend>>,
+    backing_size: u64,
+    // Offset of the next cluster to allocate, at the end of the file.
+    next_cluster: u64,
+    current_offset: u64,
+    first_write: bool,
+}
+
+impl QedFile {
+    /// Opens the QED image stored in `file`.
+    pub fn from(file: RawFile) -> Result<QedFile> {
+        Self::from_with_nesting_depth(file, MAX_NESTING_DEPTH)
+    }
+
+    /// Opens the QED image stored in `file`, following at most
+    /// `max_nesting_depth` backing files.
+    pub fn from_with_nesting_depth(file: RawFile, max_nesting_depth: u32) -> Result<QedFile> {
+        Self::from_with_chain(file, max_nesting_depth, &mut Vec::new())
+    }
+
+    // `chain` holds the device and inode numbers of the images already opened
+    // down the backing chain, which is how cycles are caught.
+    fn from_with_chain(
+        mut file: RawFile,
+        max_nesting_depth: u32,
+        chain: &mut Vec<(u64, u64)>,
+    ) -> Result<QedFile> {
+        let metadata = file.metadata().map_err(Error::GettingFileMetadata)?;
+        let id = (metadata.dev(), metadata.ino());
+        if chain.contains(&id) {
+            return Err(Error::BackingFileCycle);
+        }
+        chain.push(id);
+
+        let header = QedHeader::read(&mut file)?;
+        header.validate()?;
+        if header.features & FEATURE_NEED_CHECK != 0 {
+            warn!("QED image was not closed cleanly, some of its clusters may be leaked");
+        }
+
+        let backing_file = if header.features & FEATURE_BACKING_FILE != 0 {
+            if max_nesting_depth == 0 {
+                return Err(Error::MaxNestingDepthExceeded);
+            }
+            Some(Self::open_backing_file(
+                &mut file,
+                &header,
+                max_nesting_depth - 1,
+                chain,
+            )?)
+        } else {
+            None
+        };
+        let backing_size = match backing_file.as_ref() {
+            Some(backing_file) => backing_file
+                .size()
+                .map_err(|e| Error::BackingFileOpen(Box::new(e)))?,
+            None => 0,
+        };
+
+        let mut l1_table = vec![0u64; header.table_entries() as usize];
+        read_table(&mut file, header.l1_table_offset, &mut l1_table)
+            .map_err(Error::ReadingL1Table)?;
+
+        let cluster_size = u64::from(header.cluster_size);
+        let next_cluster = metadata.len().div_ceil(cluster_size) * cluster_size;
+
+        Ok(QedFile {
+            raw_file: file,
+            header,
+            l1_table,
+            l2_cache: HashMap::new(),
+            backing_file,
+            backing_size,
+            next_cluster: next_cluster.max(cluster_size),
+            current_offset: 0,
+            first_write: true,
+        })
+    }
+
+    fn open_backing_file(
+        file: &mut RawFile,
+        header: &QedHeader,
+        max_nesting_depth: u32,
+        chain: &mut Vec<(u64, u64)>,
+    ) -> Result<Box<dyn BlockBackend>> {
+        let mut name = vec![0u8; header.backing_filename_size as usize];
+        file.seek(SeekFrom::Start(u64::from(header.backing_filename_offset)))
+            .map_err(Error::ReadingHeader)?;
+        file.read_exact(&mut name).map_err(Error::ReadingHeader)?;
+        let name = str::from_utf8(&name).map_err(Error::InvalidBackingFileName)?;
+
+        let path = resolve_backing_file_path(&*file, name);
+        let direct_io = file.is_direct();
+        let mut backing_file = OpenOptions::new()
+            .read(true)
+            .open(path)
+            .map_err(Error::BackingFileIo)?;
+
+        if header.features & FEATURE_BACKING_FORMAT_NO_PROBE != 0 {
+            return Ok(Box::new(RawFile::new(backing_file, direct_io)));
+        }
+
+        let image_type = detect_image_type(&mut backing_file).map_err(Error::BackingFileIo)?;
+        // The detection leaves the file offset past the header.
+        backing_file.rewind().map_err(Error::BackingFileIo)?;
+
+        Ok(match image_type {
+            ImageType::Qed => Box::new(Self::from_with_chain(
+                RawFile::new(backing_file, direct_io),
+                max_nesting_depth,
+                chain,
+            )?),
+            _ => create_disk_file(backing_file, direct_io)
+                .map_err(|e| Error::BackingFileOpen(Box::new(e)))?,
+        })
+    }
+
+    /// Creates a QED image of `virtual_size` bytes in `file`, optionally
+    /// backed by the image at `backing_file`.
+    pub fn new(mut file: RawFile, virtual_size: u64, backing_file: Option<&str>) -> Result<Self> {
+        let cluster_size = u64::from(DEFAULT_CLUSTER_SIZE);
+        let mut header = QedHeader {
+            magic: QED_MAGIC,
+            cluster_size: DEFAULT_CLUSTER_SIZE,
+            table_size: DEFAULT_TABLE_SIZE,
+            header_size: 1,
+            features: 0,
+            compat_features: 0,
+            autoclear_features: 0,
+            l1_table_offset: cluster_size,
+            image_size: virtual_size.div_ceil(SECTOR_SIZE) * SECTOR_SIZE,
+            backing_filename_offset: 0,
+            backing_filename_size: 0,
+        };
+        if let Some(backing_file) = backing_file {
+            header.features |= FEATURE_BACKING_FILE;
+            header.backing_filename_offset = HEADER_SIZE as u32;
+            header.backing_filename_size = backing_file.len() as u32;
+        }
+        header.validate()?;
+
+        header.write(&mut file)?;
+        if let Some(backing_file) = backing_file {
+            file.write_all(backing_file.as_bytes())
+                .map_err(Error::WritingHeader)?;
+        }
+        let l1_table_size = u64::from(header.table_size) * cluster_size;
+        file.set_len(header.l1_table_offset + l1_table_size)
+            .map_err(Error::WritingHeader)?;
+
+        Self::from(file)
+    }
+
+    pub fn virtual_size(&self) -> u64 {
+        self.header.image_size
+    }
+
+    fn cluster_size(&self) -> u64 {
+        u64::from(self.header.cluster_size)
+    }
+
+    fn table_bytes(&self) -> u64 {
+        u64::from(self.header.table_size) * self.cluster_size()
+    }
+
+    // Returns the indexes in the L1 and L2 tables of the cluster holding
+    // `address`.
+    fn table_indexes(&self, address: u64) -> (usize, usize) {
+        let cluster = address / self.cluster_size();
+        let entries = self.header.table_entries();
+        ((cluster / entries) as usize, (cluster % entries) as usize)
+    }
+
+    // Table entries pointing outside of the file would otherwise let a
+    // corrupted image read or overwrite unrelated data.
+    fn check_offset(&self, offset: u64) -> io::Result<()> {
+        if offset % self.cluster_size() != 0 || offset >= self.next_cluster {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid QED table entry {offset:#x}"),
+            ));
+        }
+        Ok(())
+    }
+
+    fn l2_table(&mut self, l2_offset: u64) -> io::Result<&mut Vec<u64>> {
+        if !self.l2_cache.contains_key(&l2_offset) {
+            self.check_offset(l2_offset)?;
+            let mut table = vec![0u64; self.header.table_entries() as usize];
+            read_table(&mut self.raw_file, l2_offset, &mut table)?;
+            // Tables are written through, any of them can be evicted.
+            if self.l2_cache.len() >= L2_CACHE_SIZE {
+                let evicted = *self.l2_cache.keys().next().unwrap();
+                self.l2_cache.remove(&evicted);
+            }
+            self.l2_cache.insert(l2_offset, table);
+        }
+
+        Ok(self.l2_cache.get_mut(&l2_offset).unwrap())
+    }
+
+    fn cluster(&mut self, address: u64) -> io::Result<Cluster> {
+        let (l1_index, l2_index) = self.table_indexes(address);
+        let l2_offset = self.l1_table[l1_index];
+        if l2_offset == 0 {
+            return Ok(Cluster::Unallocated);
+        }
+
+        let entry = self.l2_table(l2_offset)?[l2_index];
+        Ok(match entry {
+            0 => Cluster::Unallocated,
+            ZERO_CLUSTER => Cluster::Zero,
+            offset => {
+                self.check_offset(offset)?;
+                Cluster::Allocated(offset)
+            }
+        })
+    }
+
+    // Reads the content of unallocated clusters from the backing file, past
+    // which they read as zeroes.
+    fn read_backing(&mut self, address: u64, buf: &mut [u8]) -> io::Result<()> {
+        let count = if address < self.backing_size {
+            min(buf.len() as u64, self.backing_size - address) as usize
+        } else {
+            0
+        };
+        if let Some(backing_file) = self.backing_file.as_mut() {
+            if count > 0 {
+                backing_file.seek(SeekFrom::Start(address))?;
+                backing_file.read_exact(&mut buf[..count])?;
+            }
+        }
+        buf[count..].fill(0);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        self.raw_file.seek(SeekFrom::Start(offset))?;
+        self.raw_file.write_all(buf)
+    }
+
+    // Points the L2 entry of the cluster holding `address` to `offset`,
+    // allocating the L2 table if needed.
+    fn set_cluster(&mut self, address: u64, offset: u64) -> io::Result<()> {
+        let (l1_index, l2_index) = self.table_indexes(address);
+        let l2_offset = self.l1_table[l1_index];
+        if l2_offset != 0 {
+            self.l2_table(l2_offset)?[l2_index] = offset;
+            let mut entry = [0u8; 8];
+            LittleEndian::write_u64(&mut entry, offset);
+            return self.write_at(l2_offset + l2_index as u64 * 8, &entry);
+        }
+
+        // The new table is complete before the L1 table references it.
+        let l2_offset = self.next_cluster;
+        self.next_cluster += self.table_bytes();
+        let mut table = vec![0u64; self.header.table_entries() as usize];
+        table[l2_index] = offset;
+        let mut bytes = vec![0u8; self.table_bytes() as usize];
+        LittleEndian::write_u64_into(&table, &mut bytes);
+        self.write_at(l2_offset, &bytes)?;
+
+        let mut entry = [0u8; 8];
+        LittleEndian::write_u64(&mut entry, l2_offset);
+        self.write_at(self.header.l1_table_offset + l1_index as u64 * 8, &entry)?;
+        self.l1_table[l1_index] = l2_offset;
+        self.l2_cache.insert(l2_offset, table);
+
+        Ok(())
+    }
+
+    // Writes `buf` at `address` of a cluster not allocated yet, filling the
+    // rest of the new cluster with what the guest used to read from it.
+    fn allocate_cluster(&mut self, address: u64, cluster: Cluster, buf: &[u8]) -> io::Result<()> {
+        let cluster_size = self.cluster_size();
+        let cluster_start = address / cluster_size * cluster_size;
+        let start = (address - cluster_start) as usize;
+
+        let mut data = vec![0u8; cluster_size as usize];
+        if buf.len() != data.len() && cluster == Cluster::Unallocated {
+            self.read_backing(cluster_start, &mut data)?;
+        }
+        data[start..start + buf.len()].copy_from_slice(buf);
+
+        let offset = self.next_cluster;
+        self.next_cluster += cluster_size;
+        self.write_at(offset, &data)?;
+        self.set_cluster(cluster_start, offset)
+    }
+
+    // Clear the features not supported by this implementation before the
+    // image gets modified.
+    fn update_header(&mut self) -> io::Result<()> {
+        if self.header.autoclear_features != 0 {
+            self.header.autoclear_features = 0;
+            self.header
+                .write(&mut self.raw_file)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        }
+        Ok(())
+    }
+
+    // Limits the number of bytes from `address` to the end of the disk.
+    fn limit_range_file(&self, address: u64, count: usize) -> usize {
+        min(count as u64, self.virtual_size().saturating_sub(address)) as usize
+    }
+
+    // Limits the number of bytes from `address` to the end of its cluster.
+    fn limit_range_cluster(&self, address: u64, count: usize) -> usize {
+        let cluster_size = self.cluster_size();
+        min(count as u64, cluster_size - address % cluster_size) as usize
+    }
+}
+
+fn read_table<F: Read + Seek>(f: &mut F, offset: u64, table: &mut [u64]) -> io::Result<()> {
+    let mut bytes = vec![0u8; table.len() * 8];
+    f.seek(SeekFrom::Start(offset))?;
+    f.read_exact(&mut bytes)?;
+    LittleEndian::read_u64_into(&bytes, table);
+    Ok(())
+}
+
+impl Read for QedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let address = self.current_offset;
+        let read_count = self.limit_range_file(address, buf.len());
+
+        let mut nread = 0;
+        while nread < read_count {
+            let curr_addr = address + nread as u64;
+            let count = self.limit_range_cluster(curr_addr, read_count - nread);
+            let chunk = &mut buf[nread..nread + count];
+
+            match self.cluster(curr_addr)? {
+                Cluster::Allocated(offset) => {
+                    self.raw_file
+                        .seek(SeekFrom::Start(offset + curr_addr % self.cluster_size()))?;
+                    self.raw_file.read_exact(chunk)?;
+                }
+                Cluster::Zero => chunk.fill(0),
+                Cluster::Unallocated => self.read_backing(curr_addr, chunk)?,
+            }
+
+            nread += count;
+        }
+        self.current_offset += read_count as u64;
+        Ok(read_count)
+    }
+}
+
+impl Write for QedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.first_write {
+            self.update_header()?;
+            self.first_write = false;
+        }
+
+        let address = self.current_offset;
+        let write_count = self.limit_range_file(address, buf.len());
+
+        let mut nwritten = 0;
+        while nwritten < write_count {
+            let curr_addr = address + nwritten as u64;
+            let count = self.limit_range_cluster(curr_addr, write_count - nwritten);
+            let chunk = &buf[nwritten..nwritten + count];
+
+            match self.cluster(curr_addr)? {
+                Cluster::Allocated(offset) => {
+                    self.write_at(offset + curr_addr % self.cluster_size(), chunk)?
+                }
+                cluster => self.allocate_cluster(curr_addr, cluster, chunk)?,
+            }
+
+            nwritten += count;
+        }
+        self.current_offset += write_count as u64;
+        Ok(write_count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.raw_file.flush()
+    }
+}
+
+impl Seek for QedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_offset: Option<u64> = match pos {
+            SeekFrom::Start(off) => Some(off),
+            SeekFrom::End(off) => {
+                if off < 0 {
+                    0i64.checked_sub(off)
+                        .and_then(|increment| self.virtual_size().checked_sub(increment as u64))
+                } else {
+                    self.virtual_size().checked_add(off as u64)
+                }
+            }
+            SeekFrom::Current(off) => {
+                if off < 0 {
+                    0i64.checked_sub(off)
+                        .and_then(|increment| self.current_offset.checked_sub(increment as u64))
+                } else {
+                    self.current_offset.checked_add(off as u64)
+                }
+            }
+        };
+
+        if let Some(o) = new_offset {
+            if o <= self.virtual_size() {
+                self.current_offset = o;
+                return Ok(o);
+            }
+        }
+        Err(io::Error::from_raw_os_error(libc::EINVAL))
+    }
+}
+
+impl BlockBackend for QedFile {
+    fn size(&self) -> std::result::Result<u64, crate::Error> {
+        Ok(self.virtual_size())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const CLUSTER_SIZE: usize = DEFAULT_CLUSTER_SIZE as usize;
+
+    fn raw_file(file: &TempFile) -> RawFile {
+        RawFile::new(file.as_file().try_clone().unwrap(), false)
+    }
+
+    fn reopen(file: &TempFile) -> QedFile {
+        QedFile::from(raw_file(file)).unwrap()
+    }
+
+    fn read_at(qed: &mut QedFile, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0xaau8; len];
+        qed.seek(SeekFrom::Start(offset)).unwrap();
+        qed.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    fn write_at(qed: &mut QedFile, offset: u64, buf: &[u8]) {
+        qed.seek(SeekFrom::Start(offset)).unwrap();
+        qed.write_all(buf).unwrap();
+    }
+
+    #[test]
+    fn qed_read_write() {
+        let file = TempFile::new().unwrap();
+        let mut qed = QedFile::new(raw_file(&file), 16 << 20, None).unwrap();
+        assert_eq!(qed.seek(SeekFrom::End(0)).unwrap(), 16 << 20);
+
+        // Unallocated clusters read as zeroes.
+        assert!(read_at(&mut qed, 0, 4096).iter().all(|b| *b == 0));
+
+        // The write spans two clusters, partially.
+        let data = vec![0x55u8; CLUSTER_SIZE];
+        let offset = (CLUSTER_SIZE + CLUSTER_SIZE / 2) as u64;
+        write_at(&mut qed, offset, &data);
+        qed.flush().unwrap();
+
+        let mut qed = reopen(&file);
+        let buf = read_at(&mut qed, CLUSTER_SIZE as u64, 3 * CLUSTER_SIZE);
+        assert!(buf[..CLUSTER_SIZE / 2].iter().all(|b| *b == 0));
+        assert!(buf[CLUSTER_SIZE / 2..CLUSTER_SIZE * 3 / 2]
+            .iter()
+            .all(|b| *b == 0x55));
+        assert!(buf[CLUSTER_SIZE * 3 / 2..].iter().all(|b| *b == 0));
+
+        // Overwriting an allocated cluster doesn't allocate a new one.
+        let len = file.as_file().metadata().unwrap().len();
+        write_at(&mut qed, offset, &[0x66u8; 512]);
+        assert_eq!(file.as_file().metadata().unwrap().len(), len);
+        assert_eq!(read_at(&mut qed, offset, 512), vec![0x66u8; 512]);
+
+        // Writes past the end of the disk are truncated.
+        qed.seek(SeekFrom::Start((16 << 20) - 512)).unwrap();
+        assert_eq!(qed.write(&[1u8; 1024]).unwrap(), 512);
+    }
+
+    #[test]
+    fn qed_backing_file() {
+        let backing = TempFile::new().unwrap();
+        let backing_data: Vec<u8> = (0..2 * CLUSTER_SIZE).map(|i| (i % 251) as u8).collect();
+        backing.as_file().write_all(&backing_data).unwrap();
+
+        let file = TempFile::new().unwrap();
+        let backing_path = backing.as_path().to_str().unwrap();
+        let mut qed =
+            QedFile::new(raw_file(&file), 4 * CLUSTER_SIZE as u64, Some(backing_path)).unwrap();
+
+        // Reads go to the backing file, and read zeroes past its end.
+        let buf = read_at(&mut qed, 0, 4 * CLUSTER_SIZE);
+        assert_eq!(buf[..2 * CLUSTER_SIZE], backing_data[..]);
+        assert!(buf[2 * CLUSTER_SIZE..].iter().all(|b| *b == 0));
+
+        // A partial write copies the rest of the cluster from the backing
+        // file, which is left untouched.
+        write_at(&mut qed, 512, &[0xffu8; 512]);
+        let mut qed = reopen(&file);
+        let buf = read_at(&mut qed, 0, CLUSTER_SIZE);
+        assert_eq!(buf[..512], backing_data[..512]);
+        assert!(buf[512..1024].iter().all(|b| *b == 0xff));
+        assert_eq!(buf[1024..], backing_data[1024..CLUSTER_SIZE]);
+
+        let mut backing_buf = vec![0u8; 1024];
+        File::open(backing.as_path())
+            .unwrap()
+            .read_exact(&mut backing_buf)
+            .unwrap();
+        assert_eq!(backing_buf, backing_data[..1024]);
+    }
+
+    #[test]
+    fn qed_invalid_header() {
+        let file = TempFile::new().unwrap();
+        QedFile::new(raw_file(&file), 1 << 20, None).unwrap();
+
+        let mut header = QedHeader::read(&mut raw_file(&file)).unwrap();
+        header.features |= 1 << 8;
+        header.write(&mut raw_file(&file)).unwrap();
+        assert!(matches!(
+            QedFile::from(raw_file(&file)),
+            Err(Error::UnsupportedFeatures(0x100))
+        ));
+
+        header.features = 0;
+        header.cluster_size = 1000;
+        header.write(&mut raw_file(&file)).unwrap();
+        assert!(matches!(
+            QedFile::from(raw_file(&file)),
+            Err(Error::InvalidClusterSize(1000))
+        ));
+
+        header.cluster_size = DEFAULT_CLUSTER_SIZE;
+        header.magic = 0;
+        header.write(&mut raw_file(&file)).unwrap();
+        assert!(matches!(
+            QedFile::from(raw_file(&file)),
+            Err(Error::InvalidMagic(0))
+        ));
+    }
+
+    #[test]
+    fn qed_invalid_table_entry() {
+        let file = TempFile::new().unwrap();
+        let mut qed = QedFile::new(raw_file(&file), 1 << 20, None).unwrap();
+        write_at(&mut qed, 0, &[1u8; 512]);
+
+        // Point the L1 entry past the end of the file.
+        let header = QedHeader::read(&mut raw_file(&file)).unwrap();
+        let mut entry = [0u8; 8];
+        LittleEndian::write_u64(&mut entry, 1 << 40);
+        let mut raw = raw_file(&file);
+        raw.seek(SeekFrom::Start(header.l1_table_offset)).unwrap();
+        raw.write_all(&entry).unwrap();
+
+        let mut qed = reopen(&file);
+        let mut buf = [0u8; 512];
+        assert!(qed.read_exact(&mut buf).is_err());
+        qed.seek(SeekFrom::Start(0)).unwrap();
+        assert!(qed.write(&buf).is_err());
+    }
+}
@@ -0,0 +1,110 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::async_io::{
+    completion_room, AsyncIo, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult,
+};
+use crate::qcow::RawFile;
+use crate::qed::{QedFile, Result as QedResult};
+use crate::AsyncAdaptor;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::sync::{Arc, Mutex, MutexGuard};
+use vmm_sys_util::eventfd::EventFd;
+
+pub struct QedDiskSync {
+    qed_file: Arc<Mutex<QedFile>>,
+}
+
+impl QedDiskSync {
+    pub fn new(file: File, direct_io: bool) -> QedResult<Self> {
+        Ok(QedDiskSync {
+            qed_file: Arc::new(Mutex::new(QedFile::from(RawFile::new(file, direct_io))?)),
+        })
+    }
+}
+
+impl DiskFile for QedDiskSync {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        let mut file = self.qed_file.lock().unwrap();
+
+        file.seek(SeekFrom::End(0)).map_err(DiskFileError::Size)
+    }
+
+    fn new_async_io(&self, _ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        Ok(Box::new(QedSync::new(self.qed_file.clone())) as Box<dyn AsyncIo>)
+    }
+}
+
+pub struct QedSync {
+    qed_file: Arc<Mutex<QedFile>>,
+    eventfd: EventFd,
+    completion_list: VecDeque<(u64, i32)>,
+}
+
+impl QedSync {
+    pub fn new(qed_file: Arc<Mutex<QedFile>>) -> Self {
+        QedSync {
+            qed_file,
+            eventfd: EventFd::new(libc::EFD_NONBLOCK).expect("Failed creating EventFd for QedSync"),
+            completion_list: VecDeque::new(),
+        }
+    }
+}
+
+impl AsyncAdaptor<QedFile> for Arc<Mutex<QedFile>> {
+    fn file(&mut self) -> MutexGuard<QedFile> {
+        self.lock().unwrap()
+    }
+}
+
+impl AsyncIo for QedSync {
+    fn notifier(&self) -> &EventFd {
+        &self.eventfd
+    }
+
+    fn submission_room(&self) -> usize {
+        completion_room(self.completion_list.len())
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.qed_file.read_vectored_sync(
+            offset,
+            iovecs,
+            user_data,
+            &self.eventfd,
+            &mut self.completion_list,
+        )
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.qed_file.write_vectored_sync(
+            offset,
+            iovecs,
+            user_data,
+            &self.eventfd,
+            &mut self.completion_list,
+        )
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        self.qed_file
+            .fsync_sync(user_data, &self.eventfd, &mut self.completion_list)
+    }
+
+    fn next_completed_request(&mut self) -> Option<(u64, i32)> {
+        self.completion_list.pop_front()
+    }
+}
@@ -3,37 +3,111 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
 use crate::async_io::{
-    AsyncIo, AsyncIoError, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult,
+    AsyncIo, AsyncIoError, AsyncIoResult, DiskExtent, DiskFile, DiskFileError, DiskFileResult,
+    IoAlignment, RequestPriority,
 };
-use crate::DiskTopology;
-use io_uring::{opcode, squeue, types, IoUring};
+use crate::raw_sync::RawFileSync;
+use crate::{
+    disk_file_size, filesystem_available_space, seek_extents, AsyncIoBackend, CacheMode,
+    DiskTopology,
+};
+use io_uring::{opcode, squeue, types, IoUring, Submitter};
+use std::collections::{HashMap, VecDeque};
 use std::fs::File;
-use std::io::{Seek, SeekFrom};
+use std::io;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
 use vmm_sys_util::eventfd::EventFd;
 
+// Largest buffer io_uring accepts to register.
+const MAX_FIXED_BUFFER_SIZE: usize = 1 << 30;
+
+// How long the submission queue polling thread spins without any request
+// before going to sleep, until woken up by the next submission.
+const SQPOLL_IDLE_MS: u32 = 100;
+
+// User data of the cancellation entries, whose completions aren't returned.
+// It lies past both the descriptor heads and the user data the device
+// reserves for its own flushes.
+const CANCEL_USER_DATA: u64 = u64::MAX - 2;
+
+const IOPRIO_CLASS_SHIFT: u16 = 13;
+const IOPRIO_CLASS_BE: u16 = 2;
+
+// I/O priority of the submission queue entries. The high priority requests
+// get the highest level of the best-effort class, which requires no
+// privilege unlike the real-time one, the others the priority of the thread.
+fn ioprio(priority: RequestPriority) -> u16 {
+    match priority {
+        RequestPriority::Normal => 0,
+        RequestPriority::High => IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT,
+    }
+}
+
 pub struct RawFileDisk {
     file: File,
+    // Whether a queue had to fall back on the synchronous backend.
+    sync_fallback: AtomicBool,
+    sqpoll_cpu: Option<u32>,
 }
 
 impl RawFileDisk {
     pub fn new(file: File) -> Self {
-        RawFileDisk { file }
+        RawFileDisk {
+            file,
+            sync_fallback: AtomicBool::new(false),
+            sqpoll_cpu: None,
+        }
+    }
+
+    /// Has the kernel poll the submission queue of each ring from a thread
+    /// running on the host CPU `cpu`, sparing the queue threads the system
+    /// calls submitting the requests.
+    pub fn set_sqpoll_cpu(&mut self, cpu: u32) {
+        self.sqpoll_cpu = Some(cpu);
     }
 }
 
 impl DiskFile for RawFileDisk {
     fn size(&mut self) -> DiskFileResult<u64> {
-        self.file
-            .seek(SeekFrom::End(0))
-            .map_err(DiskFileError::Size)
+        disk_file_size(&self.file).map_err(DiskFileError::Size)
     }
 
     fn new_async_io(&self, ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
-        Ok(Box::new(
-            RawFileAsync::new(self.file.as_raw_fd(), ring_depth)
-                .map_err(DiskFileError::NewAsyncIo)?,
-        ) as Box<dyn AsyncIo>)
+        let fd = self.file.as_raw_fd();
+        let raw_file_async = match RawFileAsync::new(fd, ring_depth, self.sqpoll_cpu) {
+            // Polling the submission queue may require privileges.
+            Err(e) if self.sqpoll_cpu.is_some() => {
+                warn!(
+                    "Failed setting up io_uring submission queue polling ({}), submitting the requests from the queue thread",
+                    e
+                );
+                RawFileAsync::new(fd, ring_depth, None)
+            }
+            r => r,
+        };
+        match raw_file_async {
+            Ok(raw_file_async) => Ok(Box::new(raw_file_async) as Box<dyn AsyncIo>),
+            Err(e) => {
+                // The kernel may refuse to set up a ring even though io_uring
+                // was detected, e.g. because of locked memory limits.
+                warn!(
+                    "Failed setting up io_uring ({}), using synchronous RAW disk file instead",
+                    e
+                );
+                self.sync_fallback.store(true, Ordering::Relaxed);
+                Ok(Box::new(
+                    RawFileSync::new(
+                        self.file.as_raw_fd(),
+                        None,
+                        None,
+                        false,
+                        CacheMode::Writeback,
+                    )
+                    .map_err(DiskFileError::NewAsyncIo)?,
+                ) as Box<dyn AsyncIo>)
+            }
+        }
     }
 
     fn topology(&mut self) -> DiskTopology {
@@ -44,17 +118,54 @@ impl DiskFile for RawFileDisk {
             DiskTopology::default()
         }
     }
+
+    fn backend(&self) -> AsyncIoBackend {
+        if self.sync_fallback.load(Ordering::Relaxed) {
+            AsyncIoBackend::Sync
+        } else {
+            AsyncIoBackend::IoUring
+        }
+    }
+
+    fn extents(&mut self) -> DiskFileResult<Vec<DiskExtent>> {
+        let size = self.size()?;
+        seek_extents(&mut self.file, size).map_err(DiskFileError::Extents)
+    }
+
+    fn available_space(&self) -> DiskFileResult<Option<u64>> {
+        filesystem_available_space(&self.file).map_err(DiskFileError::AvailableSpace)
+    }
 }
 
 pub struct RawFileAsync {
     fd: RawFd,
     io_uring: IoUring,
     eventfd: EventFd,
+    // The iovecs referenced by the in-flight requests, keyed by user_data.
+    // They must outlive the submission queue entries pointing at them.
+    iovecs: HashMap<u64, Vec<libc::iovec>>,
+    // Buffers registered with the ring, in the order of their indexes.
+    fixed_buffers: Vec<libc::iovec>,
+    // Entries held back while the submission queue is full, in submission
+    // order, until completions make room for them. They can't outnumber
+    // the requests the caller has in flight.
+    overflow: VecDeque<squeue::Entry>,
+    alignment: IoAlignment,
 }
 
+// SAFETY: the raw pointers held by the iovecs are only handed over to the
+// kernel, they are never dereferenced from another thread.
+unsafe impl Send for RawFileAsync {}
+
 impl RawFileAsync {
-    pub fn new(fd: RawFd, ring_depth: u32) -> std::io::Result<Self> {
-        let io_uring = IoUring::new(ring_depth)?;
+    /// Sets up a ring of `ring_depth` entries, whose submission queue is
+    /// polled from a kernel thread running on `sqpoll_cpu` if given.
+    pub fn new(fd: RawFd, ring_depth: u32, sqpoll_cpu: Option<u32>) -> std::io::Result<Self> {
+        let mut builder = IoUring::builder();
+        if let Some(cpu) = sqpoll_cpu {
+            builder.setup_sqpoll(SQPOLL_IDLE_MS).setup_sqpoll_cpu(cpu);
+        }
+        let io_uring = builder.build(ring_depth)?;
         let eventfd = EventFd::new(libc::EFD_NONBLOCK)?;
 
         // Register the io_uring eventfd that will notify when something in
@@ -65,8 +176,121 @@ impl RawFileAsync {
             fd,
             io_uring,
             eventfd,
+            iovecs: HashMap::with_capacity(ring_depth as usize),
+            fixed_buffers: Vec::new(),
+            overflow: VecDeque::new(),
+            alignment: IoAlignment::probe(fd)?,
         })
     }
+
+    // Returns the index of the registered buffer holding the whole request
+    // buffer, given its only iovec.
+    fn fixed_buffer_index(&self, iovecs: &[libc::iovec]) -> Option<u16> {
+        let [iovec] = iovecs else {
+            return None;
+        };
+        let start = iovec.iov_base as usize;
+        let end = start.checked_add(iovec.iov_len)?;
+        self.fixed_buffers
+            .iter()
+            .position(|buf| {
+                let buf_start = buf.iov_base as usize;
+                start >= buf_start && end <= buf_start + buf.iov_len
+            })
+            .map(|index| index as u16)
+    }
+
+    // Queue a read or write of a registered buffer, which doesn't need any
+    // iovec to be kept around.
+    fn push_fixed(&mut self, entry: squeue::Entry, user_data: u64) -> io::Result<()> {
+        // SAFETY: the buffer lies within memory registered with the ring, and
+        // we relied on vm-memory to provide the buffer address.
+        unsafe { self.push(&entry.flags(squeue::Flags::ASYNC).user_data(user_data)) }
+    }
+
+    // Queue an entry on the submission queue. The entries are only handed
+    // over to the kernel from submit(), unless the submission queue is full,
+    // in which case the pending entries are submitted to make room. Should
+    // the kernel not take them yet, the entry is held back until the next
+    // completions are reaped, rather than failed.
+    //
+    // SAFETY: the caller must guarantee the resources referenced by the entry
+    // remain valid until its completion has been reaped.
+    unsafe fn push(&mut self, entry: &squeue::Entry) -> io::Result<()> {
+        // Nothing overtakes the entries held back.
+        if !self.overflow.is_empty() {
+            self.overflow.push_back(entry.clone());
+            return Ok(());
+        }
+
+        let (submitter, mut sq, _) = self.io_uring.split();
+
+        if sq.push(entry).is_err() {
+            sq.sync();
+            submit_ring(&submitter)?;
+            sq.sync();
+            if sq.push(entry).is_err() {
+                self.overflow.push_back(entry.clone());
+            }
+        }
+        sq.sync();
+
+        Ok(())
+    }
+
+    // Moves the entries held back over to the submission queue, for as long
+    // as the kernel makes room for them, and submits the submission queue.
+    fn flush_overflow(&mut self) -> io::Result<()> {
+        let (submitter, mut sq, _) = self.io_uring.split();
+
+        loop {
+            sq.sync();
+            let mut moved = false;
+            while let Some(entry) = self.overflow.front() {
+                // SAFETY: the resources referenced by the entry were
+                // guaranteed valid by the caller of push() queuing it.
+                if unsafe { sq.push(entry) }.is_err() {
+                    break;
+                }
+                self.overflow.pop_front();
+                moved = true;
+            }
+            sq.sync();
+
+            if !submit_ring(&submitter)? || self.overflow.is_empty() || !moved {
+                return Ok(());
+            }
+        }
+    }
+
+    fn push_vectored(
+        &mut self,
+        entry: squeue::Entry,
+        iovecs: Vec<libc::iovec>,
+        user_data: u64,
+    ) -> io::Result<()> {
+        // Moving the vector doesn't move its heap allocation, which the entry
+        // points to.
+        self.iovecs.insert(user_data, iovecs);
+
+        // SAFETY: the iovecs are kept around until the request completes,
+        // and we relied on vm-memory to provide the buffer addresses.
+        unsafe { self.push(&entry.flags(squeue::Flags::ASYNC).user_data(user_data)) }.map_err(|e| {
+            self.iovecs.remove(&user_data);
+            e
+        })
+    }
+}
+
+// Submits the submission queue, returning whether the kernel took the
+// entries. It doesn't while the completions exceed the completion queue,
+// until some are reaped.
+fn submit_ring(submitter: &Submitter) -> io::Result<bool> {
+    match submitter.submit() {
+        Ok(_) => Ok(true),
+        Err(e) if matches!(e.raw_os_error(), Some(libc::EBUSY) | Some(libc::EAGAIN)) => Ok(false),
+        Err(e) => Err(e),
+    }
 }
 
 impl AsyncIo for RawFileAsync {
@@ -74,92 +298,461 @@ impl AsyncIo for RawFileAsync {
         &self.eventfd
     }
 
+    fn required_alignment(&self) -> IoAlignment {
+        self.alignment
+    }
+
     fn read_vectored(
         &mut self,
         offset: libc::off_t,
         iovecs: &[libc::iovec],
         user_data: u64,
     ) -> AsyncIoResult<()> {
-        let (submitter, mut sq, _) = self.io_uring.split();
+        self.read_vectored_with_priority(offset, iovecs, user_data, RequestPriority::Normal)
+    }
 
-        // SAFETY: we know the file descriptor is valid and we
-        // relied on vm-memory to provide the buffer address.
-        let _ = unsafe {
-            sq.push(
-                &opcode::Readv::new(types::Fd(self.fd), iovecs.as_ptr(), iovecs.len() as u32)
-                    .offset(offset.try_into().unwrap())
-                    .build()
-                    .flags(squeue::Flags::ASYNC)
-                    .user_data(user_data),
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.write_vectored_with_priority(offset, iovecs, user_data, RequestPriority::Normal)
+    }
+
+    fn read_vectored_with_priority(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+        priority: RequestPriority,
+    ) -> AsyncIoResult<()> {
+        if let Some(index) = self.fixed_buffer_index(iovecs) {
+            let entry = opcode::ReadFixed::new(
+                types::Fd(self.fd),
+                iovecs[0].iov_base as *mut u8,
+                iovecs[0].iov_len as u32,
+                index,
             )
-        };
+            .offset(offset.try_into().unwrap())
+            .ioprio(ioprio(priority))
+            .build();
+            return self
+                .push_fixed(entry, user_data)
+                .map_err(AsyncIoError::ReadVectored);
+        }
 
-        // Update the submission queue and submit new operations to the
-        // io_uring instance.
-        sq.sync();
-        submitter.submit().map_err(AsyncIoError::ReadVectored)?;
+        let iovecs = iovecs.to_vec();
+        let entry = opcode::Readv::new(types::Fd(self.fd), iovecs.as_ptr(), iovecs.len() as u32)
+            .offset(offset.try_into().unwrap())
+            .ioprio(ioprio(priority))
+            .build();
 
-        Ok(())
+        self.push_vectored(entry, iovecs, user_data)
+            .map_err(AsyncIoError::ReadVectored)
     }
 
-    fn write_vectored(
+    fn write_vectored_with_priority(
         &mut self,
         offset: libc::off_t,
         iovecs: &[libc::iovec],
         user_data: u64,
+        priority: RequestPriority,
     ) -> AsyncIoResult<()> {
-        let (submitter, mut sq, _) = self.io_uring.split();
-
-        // SAFETY: we know the file descriptor is valid and we
-        // relied on vm-memory to provide the buffer address.
-        let _ = unsafe {
-            sq.push(
-                &opcode::Writev::new(types::Fd(self.fd), iovecs.as_ptr(), iovecs.len() as u32)
-                    .offset(offset.try_into().unwrap())
-                    .build()
-                    .flags(squeue::Flags::ASYNC)
-                    .user_data(user_data),
+        if let Some(index) = self.fixed_buffer_index(iovecs) {
+            let entry = opcode::WriteFixed::new(
+                types::Fd(self.fd),
+                iovecs[0].iov_base as *const u8,
+                iovecs[0].iov_len as u32,
+                index,
             )
-        };
+            .offset(offset.try_into().unwrap())
+            .ioprio(ioprio(priority))
+            .build();
+            return self
+                .push_fixed(entry, user_data)
+                .map_err(AsyncIoError::WriteVectored);
+        }
 
-        // Update the submission queue and submit new operations to the
-        // io_uring instance.
-        sq.sync();
-        submitter.submit().map_err(AsyncIoError::WriteVectored)?;
+        let iovecs = iovecs.to_vec();
+        let entry = opcode::Writev::new(types::Fd(self.fd), iovecs.as_ptr(), iovecs.len() as u32)
+            .offset(offset.try_into().unwrap())
+            .ioprio(ioprio(priority))
+            .build();
 
-        Ok(())
+        self.push_vectored(entry, iovecs, user_data)
+            .map_err(AsyncIoError::WriteVectored)
     }
 
     fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
         if let Some(user_data) = user_data {
-            let (submitter, mut sq, _) = self.io_uring.split();
+            let entry = opcode::Fsync::new(types::Fd(self.fd))
+                .build()
+                .flags(squeue::Flags::ASYNC)
+                .user_data(user_data);
 
             // SAFETY: we know the file descriptor is valid.
-            let _ = unsafe {
-                sq.push(
-                    &opcode::Fsync::new(types::Fd(self.fd))
-                        .build()
-                        .flags(squeue::Flags::ASYNC)
-                        .user_data(user_data),
-                )
-            };
-
-            // Update the submission queue and submit new operations to the
-            // io_uring instance.
-            sq.sync();
-            submitter.submit().map_err(AsyncIoError::Fsync)?;
+            unsafe { self.push(&entry) }.map_err(AsyncIoError::Fsync)?;
         } else {
             // SAFETY: FFI call with a valid fd
-            unsafe { libc::fsync(self.fd) };
+            if unsafe { libc::fsync(self.fd) } < 0 {
+                return Err(AsyncIoError::Fsync(std::io::Error::last_os_error()));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn register_buffers(&mut self, regions: &[libc::iovec]) -> AsyncIoResult<bool> {
+        // Large regions are registered as several buffers.
+        let mut buffers = Vec::new();
+        for region in regions {
+            let mut offset = 0;
+            while offset < region.iov_len {
+                let len = (region.iov_len - offset).min(MAX_FIXED_BUFFER_SIZE);
+                buffers.push(libc::iovec {
+                    // SAFETY: the offset is within the region
+                    iov_base: unsafe { (region.iov_base as *mut u8).add(offset) }
+                        as *mut libc::c_void,
+                    iov_len: len,
+                });
+                offset += len;
+            }
+        }
+        if buffers.is_empty() || buffers.len() > u16::MAX as usize {
+            return Ok(false);
         }
 
+        // SAFETY: the regions are mappings of the guest memory, which remain
+        // valid for as long as the ring.
+        unsafe { self.io_uring.submitter().register_buffers(&buffers) }
+            .map_err(AsyncIoError::RegisterBuffers)?;
+        self.fixed_buffers = buffers;
+
+        Ok(true)
+    }
+
+    fn cancel(&mut self, user_data: u64) -> AsyncIoResult<bool> {
+        let entry = opcode::AsyncCancel::new(user_data)
+            .build()
+            .user_data(CANCEL_USER_DATA);
+        // SAFETY: the cancellation doesn't reference any memory.
+        unsafe { self.push(&entry) }.map_err(AsyncIoError::Cancel)?;
+        // Held back behind the request, the cancellation can't miss it.
+        self.flush_overflow().map_err(AsyncIoError::Cancel)?;
+
+        Ok(true)
+    }
+
+    fn submit(&mut self) -> AsyncIoResult<()> {
+        // Submit all the entries queued since the last call at once, which
+        // costs a single io_uring_enter() syscall.
+        self.flush_overflow().map_err(AsyncIoError::Submit)?;
+
         Ok(())
     }
 
     fn next_completed_request(&mut self) -> Option<(u64, i32)> {
-        self.io_uring
-            .completion()
-            .next()
-            .map(|entry| (entry.user_data(), entry.result()))
+        loop {
+            let next = self.io_uring.completion().next();
+            let entry = match next {
+                Some(entry) => entry,
+                // The completions exceeding the completion queue are moved over
+                // to it by the kernel on the next submission.
+                None if self.io_uring.submission().cq_overflow() => {
+                    if let Err(e) = self.flush_overflow() {
+                        warn!("Failed flushing the io_uring completions: {}", e);
+                    }
+                    self.io_uring.completion().next()?
+                }
+                None => return None,
+            };
+            self.iovecs.remove(&entry.user_data());
+
+            // The completion made room for the entries held back, the others
+            // waiting for the next submit(), which also reports a failed
+            // submission.
+            if !self.overflow.is_empty() {
+                if let Err(e) = self.flush_overflow() {
+                    warn!("Failed submitting the held back io_uring entries: {}", e);
+                }
+            }
+
+            // The outcome of a cancellation shows in the completion of the
+            // request it targeted.
+            if entry.user_data() == CANCEL_USER_DATA {
+                continue;
+            }
+
+            return Some((entry.user_data(), entry.result()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_io_uring_is_supported;
+    use std::collections::HashSet;
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::io::FromRawFd;
+    use vmm_sys_util::tempfile::TempFile;
+
+    // Far fewer entries than requests in flight in the tests.
+    const RING_DEPTH: u32 = 4;
+    const REQUESTS: usize = 256;
+    const BLOCK_SIZE: usize = 4096;
+
+    // Queues a request per buffer, each on its own block, and returns once
+    // they all completed, checking none of them completed twice.
+    fn run_requests(io: &mut RawFileAsync, buffers: &mut [Vec<u8>], write: bool) {
+        for (i, buf) in buffers.iter_mut().enumerate() {
+            let iovecs = [libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            }];
+            let offset = (i * BLOCK_SIZE) as libc::off_t;
+            if write {
+                io.write_vectored(offset, &iovecs, i as u64).unwrap();
+            } else {
+                io.read_vectored(offset, &iovecs, i as u64).unwrap();
+            }
+        }
+        io.submit().unwrap();
+        wait_requests(io, buffers.len());
+    }
+
+    // Returns once the requests numbered from 0 to `count` completed, each
+    // of a whole block and only once.
+    fn wait_requests(io: &mut RawFileAsync, count: usize) {
+        let mut completed = HashSet::new();
+        while completed.len() < count {
+            if let Some((user_data, result)) = io.next_completed_request() {
+                assert_eq!(result, BLOCK_SIZE as i32);
+                assert!(completed.insert(user_data), "{user_data} completed twice");
+                continue;
+            }
+
+            let mut pollfd = libc::pollfd {
+                fd: io.notifier().as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // SAFETY: FFI call with a valid pollfd
+            let ready = unsafe { libc::poll(&mut pollfd, 1, 5000) };
+            assert!(ready > 0, "{} requests lost", count - completed.len());
+            let _ = io.notifier().read();
+        }
+        assert!((0..count as u64).all(|i| completed.contains(&i)));
+    }
+
+    #[test]
+    fn test_submission_queue_overflow() {
+        if !block_io_uring_is_supported() {
+            return;
+        }
+
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len((REQUESTS * BLOCK_SIZE) as u64).unwrap();
+        let mut io = RawFileAsync::new(file.as_raw_fd(), RING_DEPTH, None).unwrap();
+
+        let mut written: Vec<Vec<u8>> = (0..REQUESTS)
+            .map(|i| vec![(i % 255) as u8 + 1; BLOCK_SIZE])
+            .collect();
+        run_requests(&mut io, &mut written, true);
+        for (i, expected) in written.iter().enumerate() {
+            let mut buf = vec![0u8; BLOCK_SIZE];
+            file.read_exact_at(&mut buf, (i * BLOCK_SIZE) as u64)
+                .unwrap();
+            assert_eq!(&buf, expected);
+        }
+
+        let mut read = vec![vec![0u8; BLOCK_SIZE]; REQUESTS];
+        run_requests(&mut io, &mut read, false);
+        assert_eq!(read, written);
+    }
+
+    #[test]
+    fn test_batched_submission() {
+        if !block_io_uring_is_supported() {
+            return;
+        }
+
+        let file = TempFile::new().unwrap().into_file();
+        let mut io = RawFileAsync::new(file.as_raw_fd(), RING_DEPTH, None).unwrap();
+
+        // The entries only reach the kernel on submit(), the iovecs they
+        // point to being kept until then.
+        let mut buf = vec![0xa5u8; BLOCK_SIZE];
+        for i in 0..2 {
+            let iovecs = vec![libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            }];
+            io.write_vectored((i * BLOCK_SIZE) as libc::off_t, &iovecs, i as u64)
+                .unwrap();
+        }
+        assert!(io.next_completed_request().is_none());
+        assert_eq!(file.metadata().unwrap().len(), 0);
+
+        io.submit().unwrap();
+        let mut completed = HashSet::new();
+        while completed.len() < 2 {
+            if let Some((user_data, result)) = io.next_completed_request() {
+                assert_eq!(result, BLOCK_SIZE as i32);
+                completed.insert(user_data);
+                continue;
+            }
+
+            let mut pollfd = libc::pollfd {
+                fd: io.notifier().as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // SAFETY: FFI call with a valid pollfd
+            assert!(unsafe { libc::poll(&mut pollfd, 1, 5000) } > 0);
+            let _ = io.notifier().read();
+        }
+        assert_eq!(file.metadata().unwrap().len(), 2 * BLOCK_SIZE as u64);
+    }
+
+    #[test]
+    fn test_sync_fallback() {
+        let file = TempFile::new().unwrap().into_file();
+        let disk = RawFileDisk::new(file.try_clone().unwrap());
+
+        // No ring can be set up without entries, leaving the disk to the
+        // synchronous backend.
+        let mut io = disk.new_async_io(0).unwrap();
+        assert_eq!(disk.backend(), AsyncIoBackend::Sync);
+
+        let mut buf = vec![0xa5u8; BLOCK_SIZE];
+        let iovecs = [libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }];
+        io.write_vectored(0, &iovecs, 1).unwrap();
+        assert_eq!(io.next_completed_request(), Some((1, BLOCK_SIZE as i32)));
+        let mut read = vec![0u8; BLOCK_SIZE];
+        file.read_exact_at(&mut read, 0).unwrap();
+        assert_eq!(read, buf);
+    }
+
+    #[test]
+    fn test_cancel() {
+        if !block_io_uring_is_supported() {
+            return;
+        }
+
+        let mut fds = [0; 2];
+        // SAFETY: FFI call with a valid array
+        assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }, 0);
+        // SAFETY: the descriptors were just created, and are owned by nothing else
+        let (read_end, _write_end) =
+            unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        let mut io = RawFileAsync::new(read_end.as_raw_fd(), RING_DEPTH, None).unwrap();
+
+        // Nothing is ever written to the pipe, so only the cancellation can
+        // complete the read.
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        let iovecs = [libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }];
+        io.read_vectored(0, &iovecs, 1).unwrap();
+        io.submit().unwrap();
+        assert!(io.cancel(1).unwrap());
+
+        loop {
+            if let Some((user_data, result)) = io.next_completed_request() {
+                assert_eq!(user_data, 1);
+                assert!(
+                    result == -libc::ECANCELED || result == -libc::EINTR,
+                    "unexpected result {result}"
+                );
+                break;
+            }
+
+            let mut pollfd = libc::pollfd {
+                fd: io.notifier().as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // SAFETY: FFI call with a valid pollfd
+            assert!(
+                unsafe { libc::poll(&mut pollfd, 1, 5000) } > 0,
+                "read not canceled"
+            );
+            let _ = io.notifier().read();
+        }
+        assert!(io.next_completed_request().is_none());
+    }
+
+    #[test]
+    fn test_fixed_buffers() {
+        if !block_io_uring_is_supported() {
+            return;
+        }
+
+        let file = TempFile::new().unwrap().into_file();
+        let mut io = RawFileAsync::new(file.as_raw_fd(), RING_DEPTH, None).unwrap();
+        let mut region = vec![0u8; 8 * BLOCK_SIZE];
+        let base = region.as_mut_ptr();
+        let iovec = |offset: usize, len: usize| libc::iovec {
+            // SAFETY: the tests only build iovecs within the region
+            iov_base: unsafe { base.add(offset) } as *mut libc::c_void,
+            iov_len: len,
+        };
+        assert!(io.register_buffers(&[iovec(0, region.len())]).unwrap());
+
+        // Only requests of a single iovec within the region use it.
+        assert_eq!(
+            io.fixed_buffer_index(&[iovec(BLOCK_SIZE, BLOCK_SIZE)]),
+            Some(0)
+        );
+        assert_eq!(
+            io.fixed_buffer_index(&[iovec(7 * BLOCK_SIZE, 2 * BLOCK_SIZE)]),
+            None
+        );
+        assert_eq!(
+            io.fixed_buffer_index(&[iovec(0, BLOCK_SIZE), iovec(BLOCK_SIZE, BLOCK_SIZE)]),
+            None
+        );
+        let mut other = vec![0u8; BLOCK_SIZE];
+        let other_iovec = libc::iovec {
+            iov_base: other.as_mut_ptr() as *mut libc::c_void,
+            iov_len: other.len(),
+        };
+        assert_eq!(io.fixed_buffer_index(&[other_iovec]), None);
+
+        // The data go through the registered buffer both ways.
+        for (i, block) in region.chunks_mut(BLOCK_SIZE).enumerate() {
+            block.fill(i as u8 + 1);
+        }
+        for i in 0..8 {
+            let offset = (i * BLOCK_SIZE) as libc::off_t;
+            io.write_vectored(offset, &[iovec(i * BLOCK_SIZE, BLOCK_SIZE)], i as u64)
+                .unwrap();
+        }
+        io.submit().unwrap();
+        wait_requests(&mut io, 8);
+        for i in 0..8 {
+            let mut buf = vec![0u8; BLOCK_SIZE];
+            file.read_exact_at(&mut buf, (i * BLOCK_SIZE) as u64)
+                .unwrap();
+            assert!(buf.iter().all(|b| *b == i as u8 + 1));
+        }
+
+        region.fill(0);
+        for i in 0..8 {
+            let offset = (i * BLOCK_SIZE) as libc::off_t;
+            io.read_vectored(offset, &[iovec(i * BLOCK_SIZE, BLOCK_SIZE)], i as u64)
+                .unwrap();
+        }
+        io.submit().unwrap();
+        wait_requests(&mut io, 8);
+        for (i, block) in region.chunks(BLOCK_SIZE).enumerate() {
+            assert!(block.iter().all(|b| *b == i as u8 + 1));
+        }
     }
 }
@@ -6,11 +6,13 @@
 //
 
 use crate::async_io::{
-    AsyncIo, AsyncIoError, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult,
+    AsyncIo, AsyncIoError, AsyncIoResult, DiskExtent, DiskFile, DiskFileError, DiskFileResult,
+    IoAlignment,
+};
+use crate::{
+    disk_file_size, filesystem_available_space, seek_extents, AsyncIoBackend, DiskTopology,
 };
-use crate::DiskTopology;
 use std::fs::File;
-use std::io::{Seek, SeekFrom};
 use std::os::unix::io::{AsRawFd, RawFd};
 use vmm_sys_util::aio;
 use vmm_sys_util::eventfd::EventFd;
@@ -27,9 +29,7 @@ impl RawFileDiskAio {
 
 impl DiskFile for RawFileDiskAio {
     fn size(&mut self) -> DiskFileResult<u64> {
-        self.file
-            .seek(SeekFrom::End(0))
-            .map_err(DiskFileError::Size)
+        disk_file_size(&self.file).map_err(DiskFileError::Size)
     }
 
     fn new_async_io(&self, ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
@@ -39,6 +39,10 @@ impl DiskFile for RawFileDiskAio {
         ) as Box<dyn AsyncIo>)
     }
 
+    fn backend(&self) -> AsyncIoBackend {
+        AsyncIoBackend::Aio
+    }
+
     fn topology(&mut self) -> DiskTopology {
         if let Ok(topology) = DiskTopology::probe(&self.file) {
             topology
@@ -47,12 +51,22 @@ impl DiskFile for RawFileDiskAio {
             DiskTopology::default()
         }
     }
+
+    fn extents(&mut self) -> DiskFileResult<Vec<DiskExtent>> {
+        let size = self.size()?;
+        seek_extents(&mut self.file, size).map_err(DiskFileError::Extents)
+    }
+
+    fn available_space(&self) -> DiskFileResult<Option<u64>> {
+        filesystem_available_space(&self.file).map_err(DiskFileError::AvailableSpace)
+    }
 }
 
 pub struct RawFileAsyncAio {
     fd: RawFd,
     ctx: aio::IoContext,
     eventfd: EventFd,
+    alignment: IoAlignment,
 }
 
 impl RawFileAsyncAio {
@@ -60,7 +74,12 @@ impl RawFileAsyncAio {
         let eventfd = EventFd::new(libc::EFD_NONBLOCK)?;
         let ctx = aio::IoContext::new(queue_depth)?;
 
-        Ok(RawFileAsyncAio { fd, ctx, eventfd })
+        Ok(RawFileAsyncAio {
+            fd,
+            ctx,
+            eventfd,
+            alignment: IoAlignment::probe(fd)?,
+        })
     }
 }
 
@@ -69,6 +88,10 @@ impl AsyncIo for RawFileAsyncAio {
         &self.eventfd
     }
 
+    fn required_alignment(&self) -> IoAlignment {
+        self.alignment
+    }
+
     fn read_vectored(
         &mut self,
         offset: libc::off_t,
@@ -132,7 +155,9 @@ impl AsyncIo for RawFileAsyncAio {
             let _ = self.ctx.submit(&iocbs[..]).map_err(AsyncIoError::Fsync)?;
         } else {
             // SAFETY: FFI call with a valid fd
-            unsafe { libc::fsync(self.fd) };
+            if unsafe { libc::fsync(self.fd) } < 0 {
+                return Err(AsyncIoError::Fsync(std::io::Error::last_os_error()));
+            }
         }
 
         Ok(())
@@ -3,59 +3,455 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
 use crate::async_io::{
-    AsyncIo, AsyncIoError, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult,
+    check_completion_room, completion_room, AsyncIo, AsyncIoError, AsyncIoResult, DiskExtent,
+    DiskFile, DiskFileError, DiskFileResult, IoAlignment, RequestPriority,
 };
-use crate::DiskTopology;
+use crate::lifetime::{block_device_lifetime, DiskLifetime};
+use crate::readahead::{fadvise, FadviseMode, Readahead, ReadaheadStats};
+use crate::scsi::{self, ScsiCommand, ScsiResponse};
+use crate::zoned::{self, BlkZone, ZoneOperation, BLK_ZONE_TYPE_SEQWRITE_REQ};
+use crate::{
+    block_device_rotational, block_device_serial, disk_file_size, error_result,
+    filesystem_available_space, seek_extents, CacheMode, DiskTopology, SECTOR_SIZE,
+};
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::cmp;
 use std::collections::VecDeque;
 use std::fs::File;
-use std::io::{Seek, SeekFrom};
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::mem::ManuallyDrop;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 use vmm_sys_util::eventfd::EventFd;
 
+// Backoff before retrying a request failing with EAGAIN, doubled on every
+// attempt up to the maximum.
+const RETRY_INITIAL_BACKOFF: Duration = Duration::from_millis(1);
+const RETRY_MAX_BACKOFF: Duration = Duration::from_millis(100);
+
+// Logical blocks each queue keeps around after writing only part of them.
+const BLOCK_CACHE_BLOCKS: usize = 16;
+
+/// Shared by the instances writing to the same file, updating the blocks
+/// they only partially write one at a time, and telling whether the blocks
+/// cached by one of them may have been written by another.
+#[derive(Default)]
+pub struct PartialBlockLock {
+    lock: Mutex<()>,
+    // Requests modifying the file, from any instance.
+    writes: AtomicU64,
+}
+
 pub struct RawFileDiskSync {
     file: File,
+    logical_block_size: Option<u64>,
+    // Block sizes reported to the guest instead of the ones of the host.
+    guest_logical_block_size: Option<u64>,
+    guest_physical_block_size: Option<u64>,
+    zone_size: Option<u64>,
+    read_only: bool,
+    cache_mode: CacheMode,
+    fadvise: FadviseMode,
+    readahead: Option<u64>,
+    readahead_stats: Arc<ReadaheadStats>,
+    io_retries: u32,
+    completion_batch: u32,
+    // Shared by the queues, see RawFileSync.
+    partial_block_lock: Arc<PartialBlockLock>,
+    // Whether the file is a SCSI device, which commands can be passed
+    // through to.
+    scsi: bool,
 }
 
 impl RawFileDiskSync {
-    pub fn new(file: File) -> Self {
-        RawFileDiskSync { file }
+    /// Creates a disk from `file`, which must have been opened with the
+    /// flags required by `cache_mode`.
+    ///
+    /// With O_DIRECT, requests are realigned on `logical_block_size`, or on
+    /// the alignment detected from the file, failing if it can't be found
+    /// out. A `logical_block_size` is also reported to the guest.
+    pub fn new(
+        file: File,
+        read_only: bool,
+        cache_mode: CacheMode,
+        logical_block_size: Option<u64>,
+    ) -> DiskFileResult<Self> {
+        let guest_logical_block_size = logical_block_size;
+        let topology = match DiskTopology::is_block_device(&file) {
+            Ok(true) => DiskTopology::probe(&file).ok(),
+            _ => None,
+        };
+        let zone_size = topology
+            .as_ref()
+            .map(|t| t.zone_size)
+            .filter(|zone_size| *zone_size != 0);
+
+        // SAFETY: FFI call with a valid fd
+        let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+        if flags < 0 {
+            return Err(DiskFileError::DirectIoAlignment(
+                std::io::Error::last_os_error(),
+            ));
+        }
+
+        // Block devices only accept ranges aligned on their logical block
+        // size, and so do regular files opened with O_DIRECT, while other
+        // regular files can deal with any range.
+        let logical_block_size = match logical_block_size {
+            Some(size) => Some(size),
+            None if flags & libc::O_DIRECT != 0 => {
+                let alignment = DiskTopology::direct_io_alignment(&file)
+                    .and_then(|alignment| {
+                        alignment.ok_or_else(|| {
+                            std::io::Error::new(
+                                std::io::ErrorKind::Unsupported,
+                                "the kernel or the filesystem doesn't report it",
+                            )
+                        })
+                    })
+                    .map_err(DiskFileError::DirectIoAlignment)?;
+                info!("Detected O_DIRECT alignment of {} bytes", alignment);
+                Some(alignment)
+            }
+            None => topology.as_ref().map(|t| t.logical_block_size),
+        };
+
+        let scsi = scsi::is_scsi_device(file.as_raw_fd());
+
+        Ok(RawFileDiskSync {
+            file,
+            logical_block_size,
+            guest_logical_block_size,
+            guest_physical_block_size: None,
+            zone_size,
+            read_only,
+            cache_mode,
+            fadvise: FadviseMode::default(),
+            readahead: None,
+            readahead_stats: Arc::default(),
+            io_retries: 0,
+            completion_batch: 1,
+            partial_block_lock: Arc::new(PartialBlockLock::default()),
+            scsi,
+        })
+    }
+
+    /// Creates a disk from `fd`, opened by another process, without needing
+    /// access to its path. The descriptor is duplicated, the caller keeping
+    /// ownership of it, and must have been opened with the flags required by
+    /// `cache_mode`. The O_DIRECT alignment is detected as with `new()`.
+    pub fn from_raw_fd(
+        fd: RawFd,
+        read_only: bool,
+        cache_mode: CacheMode,
+        logical_block_size: Option<u64>,
+    ) -> DiskFileResult<Self> {
+        let file = crate::disk_file_from_fd(fd, cache_mode.open_flags(), !read_only)
+            .map_err(DiskFileError::InvalidFd)?;
+        Self::new(file, read_only, cache_mode, logical_block_size)
+    }
+
+    /// Reports `physical_block_size` to the guest rather than the physical
+    /// block size of the host.
+    pub fn set_physical_block_size(&mut self, physical_block_size: Option<u64>) {
+        self.guest_physical_block_size = physical_block_size;
+    }
+
+    /// Retries the reads and writes failing with EAGAIN or EINTR up to
+    /// `io_retries` times, resuming the short writes, rather than failing
+    /// them right away.
+    pub fn set_io_retries(&mut self, io_retries: u32) {
+        self.io_retries = io_retries;
+    }
+
+    /// Signals the completions by batches of up to `completion_batch`,
+    /// sparing the wakeups of the queues at high IOPS. The completions of
+    /// a smaller batch are signaled at the end of each submission round.
+    pub fn set_completion_batch(&mut self, completion_batch: u32) {
+        self.completion_batch = completion_batch;
+    }
+
+    /// Hints the host page cache about how the file is going to be accessed,
+    /// optionally reading `readahead` bytes ahead of the sequential reads of
+    /// each queue.
+    pub fn set_page_cache_hints(&mut self, fadvise: FadviseMode, readahead: Option<u64>) {
+        // The hint is only an optimization, the disk works without it.
+        if let Err(e) = fadvise.apply(&self.file) {
+            warn!("Failed applying the {:?} hint to the disk: {}", fadvise, e);
+        }
+        self.fadvise = fadvise;
+        self.readahead = readahead;
+    }
+
+    /// Bytes read ahead of the queues, counted for as long as the disk.
+    pub fn readahead_stats(&self) -> Arc<ReadaheadStats> {
+        self.readahead_stats.clone()
     }
 }
 
 impl DiskFile for RawFileDiskSync {
     fn size(&mut self) -> DiskFileResult<u64> {
-        self.file
-            .seek(SeekFrom::End(0))
-            .map_err(DiskFileError::Size)
+        disk_file_size(&self.file).map_err(DiskFileError::Size)
     }
 
     fn new_async_io(&self, _ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
-        Ok(Box::new(RawFileSync::new(self.file.as_raw_fd())) as Box<dyn AsyncIo>)
+        let mut raw_file_sync = RawFileSync::new(
+            self.file.as_raw_fd(),
+            self.logical_block_size,
+            self.zone_size,
+            self.read_only,
+            self.cache_mode,
+        )
+        .map_err(DiskFileError::NewAsyncIo)?;
+        raw_file_sync.set_page_cache_hints(
+            self.fadvise,
+            self.readahead
+                .map(|window| Readahead::new(window, self.readahead_stats.clone())),
+        );
+        raw_file_sync.set_io_retries(self.io_retries);
+        raw_file_sync.set_completion_batch(self.completion_batch);
+        raw_file_sync.set_partial_block_lock(self.partial_block_lock.clone());
+
+        Ok(Box::new(raw_file_sync) as Box<dyn AsyncIo>)
+    }
+
+    fn serial(&mut self) -> Option<String> {
+        block_device_serial(&self.file)
+    }
+
+    fn rotational(&mut self) -> bool {
+        block_device_rotational(&self.file).unwrap_or(false)
+    }
+
+    fn get_lifetime(&mut self) -> DiskLifetime {
+        block_device_lifetime(&self.file).unwrap_or_default()
+    }
+
+    fn available_space(&self) -> DiskFileResult<Option<u64>> {
+        filesystem_available_space(&self.file).map_err(DiskFileError::AvailableSpace)
     }
 
     fn topology(&mut self) -> DiskTopology {
-        if let Ok(topology) = DiskTopology::probe(&self.file) {
+        let mut topology = if let Ok(topology) = DiskTopology::probe(&self.file) {
             topology
         } else {
             warn!("Unable to get device topology. Using default topology");
             DiskTopology::default()
+        };
+
+        if let Some(size) = self.guest_logical_block_size {
+            topology.logical_block_size = size;
+        }
+        if let Some(size) = self.guest_physical_block_size {
+            topology.physical_block_size = size;
+        }
+        // A physical block holds whole logical blocks, and the guest can't
+        // do its I/O in less than one of them.
+        topology.physical_block_size = topology
+            .physical_block_size
+            .max(topology.logical_block_size);
+        topology.minimum_io_size = topology.minimum_io_size.max(topology.physical_block_size);
+
+        topology
+    }
+
+    fn supports_discard(&self) -> bool {
+        true
+    }
+
+    fn supports_write_zeroes(&self) -> bool {
+        true
+    }
+
+    fn discard_zeroes(&self) -> bool {
+        // The holes punched read back as zeroes, but the logical blocks
+        // only partially covered by a discard are left as they are.
+        self.logical_block_size.is_none()
+    }
+
+    fn send_scsi_command(&mut self, command: &ScsiCommand) -> DiskFileResult<ScsiResponse> {
+        // A command may write to the device, whatever its opcode says.
+        if !self.scsi || self.read_only {
+            return Err(DiskFileError::ScsiNotSupported);
+        }
+        scsi::send_command(self.file.as_raw_fd(), command).map_err(DiskFileError::ScsiCommand)
+    }
+
+    fn resize(&mut self, current_size: u64) -> DiskFileResult<u64> {
+        // A block device might have been moved onto different storage while
+        // being resized, its logical block size must be looked up again.
+        if self.logical_block_size.is_some() {
+            if let Ok(topology) = DiskTopology::probe(&self.file) {
+                if Some(topology.logical_block_size) != self.logical_block_size {
+                    warn!(
+                        "Logical block size changed from {} to {}, only applied to new queues",
+                        self.logical_block_size.unwrap(),
+                        topology.logical_block_size
+                    );
+                    self.logical_block_size = Some(topology.logical_block_size);
+                }
+            }
+        }
+
+        let size = self.size()?;
+        if size < current_size {
+            return Err(DiskFileError::Shrunk(current_size, size));
+        }
+        Ok(size)
+    }
+
+    fn extents(&mut self) -> DiskFileResult<Vec<DiskExtent>> {
+        let size = self.size()?;
+        seek_extents(&mut self.file, size).map_err(DiskFileError::Extents)
+    }
+}
+
+impl Drop for RawFileDiskSync {
+    // Make sure the writes acknowledged to the guest reach the storage
+    // before the file gets closed, unless flushes are ignored on purpose.
+    fn drop(&mut self) {
+        if self.read_only || self.cache_mode == CacheMode::None {
+            return;
+        }
+
+        if let Err(e) = self.file.sync_all() {
+            if e.raw_os_error() == Some(libc::EIO) {
+                error!(
+                    "Failed to flush the disk on close, data written by the guest might be lost: {}",
+                    e
+                );
+            } else {
+                error!("Failed to flush the disk on close: {}", e);
+            }
         }
     }
 }
 
 pub struct RawFileSync {
     fd: RawFd,
+    logical_block_size: Option<u64>,
+    // Size of the zones in bytes, for zoned block devices.
+    zone_size: Option<u64>,
+    // Refuse any request modifying the file.
+    read_only: bool,
+    cache_mode: CacheMode,
+    // Whether preadv2() accepts RWF_NOWAIT for this file.
+    rwf_nowait: bool,
+    // Whether pwritev2() accepts RWF_DSYNC, until it's found out otherwise.
+    rwf_dsync: bool,
+    // Whether the file was opened with O_DIRECT, requiring requests to be
+    // aligned on the logical block size.
+    direct: bool,
+    // Drop the range of every request from the page cache once completed.
+    dontneed: bool,
+    // Detection of the sequential reads to read ahead of.
+    readahead: Option<Readahead>,
+    // Retries of the reads and writes failing with EAGAIN or EINTR.
+    io_retries: u32,
+    // Written once per batch of completions without EFD_SEMAPHORE, a single
+    // read clearing it however many completions are pending.
     eventfd: EventFd,
+    // Completions the eventfd is written once for, the last batch of a
+    // submission round being notified by submit().
+    completion_batch: u32,
+    unnotified_completions: u32,
+    // Held while partial blocks are read back, modified and written. The
+    // bytes written by another queue to the same block in the meantime
+    // would otherwise be overwritten with the ones read before.
+    partial_block_lock: Arc<PartialBlockLock>,
+    // Blocks partially written last, sparing the reads of the blocks at
+    // both ends of the next partial writes within them. They are only
+    // valid while the shared lock counts no other write than the ones of
+    // this instance, up to `writes`.
+    block_cache: BlockCache,
+    writes: u64,
     completion_list: VecDeque<(u64, i32)>,
+    // Completions of the high priority requests, returned ahead of the
+    // others.
+    high_priority_completions: VecDeque<(u64, i32)>,
 }
 
 impl RawFileSync {
-    pub fn new(fd: RawFd) -> Self {
-        RawFileSync {
+    pub fn new(
+        fd: RawFd,
+        logical_block_size: Option<u64>,
+        zone_size: Option<u64>,
+        read_only: bool,
+        cache_mode: CacheMode,
+    ) -> std::io::Result<Self> {
+        // SAFETY: FFI call
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let direct = flags & libc::O_DIRECT != 0;
+
+        // Realigning the requests of the guest requires knowing the logical
+        // block size, failing to find it out would only make every unaligned
+        // request fail later on.
+        let logical_block_size = match logical_block_size {
+            None if direct => {
+                // SAFETY: fd is valid for the lifetime of RawFileSync, and
+                // wrapping the File with ManuallyDrop prevents it from being
+                // closed.
+                let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+                Some(DiskTopology::probe(&file)?.logical_block_size)
+            }
+            logical_block_size => logical_block_size,
+        };
+
+        Ok(RawFileSync {
             fd,
+            logical_block_size,
+            zone_size,
+            read_only,
+            cache_mode,
+            rwf_nowait: Self::probe_rwf_nowait(fd),
+            rwf_dsync: true,
+            direct,
+            dontneed: false,
+            readahead: None,
+            io_retries: 0,
             eventfd: EventFd::new(libc::EFD_NONBLOCK).expect("Failed creating EventFd for RawFile"),
             completion_list: VecDeque::new(),
-        }
+            high_priority_completions: VecDeque::new(),
+            completion_batch: 1,
+            unnotified_completions: 0,
+            partial_block_lock: Arc::new(PartialBlockLock::default()),
+            block_cache: BlockCache::default(),
+            writes: 0,
+        })
+    }
+
+    /// Enables the page cache hints given on each request, the ones given
+    /// for the whole file being the caller's business.
+    pub fn set_page_cache_hints(&mut self, fadvise: FadviseMode, readahead: Option<Readahead>) {
+        self.dontneed = fadvise == FadviseMode::Dontneed;
+        self.readahead = readahead;
+    }
+
+    /// Retries the reads and writes failing with EAGAIN or EINTR up to
+    /// `io_retries` times, resuming the short writes.
+    pub fn set_io_retries(&mut self, io_retries: u32) {
+        self.io_retries = io_retries;
+    }
+
+    /// Writes the eventfd once per `completion_batch` completions, or once
+    /// the requests have been submitted if fewer completed, rather than on
+    /// every completion.
+    pub fn set_completion_batch(&mut self, completion_batch: u32) {
+        self.completion_batch = completion_batch.max(1);
+    }
+
+    /// Serializes the updates of partial blocks with the other instances
+    /// sharing `partial_block_lock`, which must be all the ones writing to
+    /// the same file.
+    pub fn set_partial_block_lock(&mut self, partial_block_lock: Arc<PartialBlockLock>) {
+        self.partial_block_lock = partial_block_lock;
+        self.block_cache.clear();
     }
 }
 
@@ -64,72 +460,2065 @@ impl AsyncIo for RawFileSync {
         &self.eventfd
     }
 
+    fn required_alignment(&self) -> IoAlignment {
+        // The requests are realigned before reaching a file opened with
+        // O_DIRECT.
+        IoAlignment::NONE
+    }
+
+    fn submission_room(&self) -> usize {
+        completion_room(self.pending_completions())
+    }
+
     fn read_vectored(
         &mut self,
         offset: libc::off_t,
         iovecs: &[libc::iovec],
         user_data: u64,
     ) -> AsyncIoResult<()> {
-        // SAFETY: FFI call with valid arguments
-        let result = unsafe {
-            libc::preadv(
-                self.fd as libc::c_int,
-                iovecs.as_ptr(),
-                iovecs.len() as libc::c_int,
-                offset,
-            )
-        };
-        if result < 0 {
-            return Err(AsyncIoError::ReadVectored(std::io::Error::last_os_error()));
+        self.read_vectored_with_priority(offset, iovecs, user_data, RequestPriority::Normal)
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.write(offset, iovecs, user_data, false, RequestPriority::Normal)
+    }
+
+    fn write_vectored_fua(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.write(offset, iovecs, user_data, true, RequestPriority::Normal)
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        self.fsync_with_priority(user_data, RequestPriority::Normal)
+    }
+
+    fn read_vectored_with_priority(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+        priority: RequestPriority,
+    ) -> AsyncIoResult<()> {
+        check_completion_room(self.pending_completions())?;
+
+        let result = self
+            .read_full(offset as u64, iovecs)
+            .map_or_else(|e| error_result(&e), |count| count as i32);
+
+        let length = iovecs.iter().map(|iovec| iovec.iov_len as u64).sum();
+        if let Some((start, length)) = self
+            .readahead
+            .as_mut()
+            .and_then(|readahead| readahead.read(offset as u64, length))
+        {
+            self.advise(start, length, libc::POSIX_FADV_WILLNEED);
+        }
+        if self.dontneed && result > 0 {
+            self.advise(offset as u64, result as u64, libc::POSIX_FADV_DONTNEED);
         }
 
-        self.completion_list.push_back((user_data, result as i32));
-        self.eventfd.write(1).unwrap();
+        self.complete(user_data, result, priority);
 
         Ok(())
     }
 
-    fn write_vectored(
+    fn write_vectored_with_priority(
         &mut self,
         offset: libc::off_t,
         iovecs: &[libc::iovec],
         user_data: u64,
+        priority: RequestPriority,
     ) -> AsyncIoResult<()> {
-        // SAFETY: FFI call with valid arguments
-        let result = unsafe {
-            libc::pwritev(
-                self.fd as libc::c_int,
-                iovecs.as_ptr(),
-                iovecs.len() as libc::c_int,
+        self.write(offset, iovecs, user_data, false, priority)
+    }
+
+    fn fsync_with_priority(
+        &mut self,
+        user_data: Option<u64>,
+        priority: RequestPriority,
+    ) -> AsyncIoResult<()> {
+        if user_data.is_some() {
+            check_completion_room(self.pending_completions())?;
+        }
+
+        // Nothing can be dirty on a read-only file, but guests flushing a
+        // read-only mount must not see an error. Flushes are also ignored
+        // on purpose without caching policy.
+        let result = if self.read_only || self.cache_mode == CacheMode::None {
+            0
+        } else {
+            // SAFETY: FFI call
+            unsafe { libc::fsync(self.fd as libc::c_int) }
+        };
+        let result = if result < 0 {
+            let e = std::io::Error::last_os_error();
+            // Without a request to complete, nobody else can be told about
+            // the failure.
+            if user_data.is_none() {
+                return Err(AsyncIoError::Fsync(e));
+            }
+            error_result(&e)
+        } else {
+            result
+        };
+
+        if let Some(user_data) = user_data {
+            self.complete(user_data, result, priority);
+        }
+
+        Ok(())
+    }
+
+    fn discard(&mut self, offset: libc::off_t, length: u64, user_data: u64) -> AsyncIoResult<()> {
+        if self.read_only {
+            return Err(AsyncIoError::ReadOnly);
+        }
+        check_completion_room(self.pending_completions())?;
+
+        self.invalidate_cached_blocks(offset as u64, length);
+
+        let (offset, length) = if let Some(block_size) = self.logical_block_size {
+            // Only the blocks entirely covered by the range can be punched,
+            // the partial ones at both ends still hold data the guest did
+            // not ask to discard.
+            let start = (offset as u64).div_ceil(block_size) * block_size;
+            let end = (offset as u64 + length) / block_size * block_size;
+            (start, end.saturating_sub(start))
+        } else {
+            (offset as u64, length)
+        };
+
+        if length > 0 {
+            self.fallocate(
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
                 offset,
+                length,
             )
-        };
-        if result < 0 {
-            return Err(AsyncIoError::WriteVectored(std::io::Error::last_os_error()));
+            .map_err(|e| {
+                if e.raw_os_error() == Some(libc::EOPNOTSUPP) {
+                    AsyncIoError::DiscardNotSupported
+                } else {
+                    AsyncIoError::Discard(e)
+                }
+            })?;
         }
 
-        self.completion_list.push_back((user_data, result as i32));
-        self.eventfd.write(1).unwrap();
+        self.complete(user_data, 0, RequestPriority::Normal);
 
         Ok(())
     }
 
-    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
-        // SAFETY: FFI call
-        let result = unsafe { libc::fsync(self.fd as libc::c_int) };
-        if result < 0 {
-            return Err(AsyncIoError::Fsync(std::io::Error::last_os_error()));
+    fn write_zeroes(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        unmap: bool,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        if self.read_only {
+            return Err(AsyncIoError::ReadOnly);
         }
+        check_completion_room(self.pending_completions())?;
+        let result = i32::try_from(length).map_err(|_| {
+            AsyncIoError::WriteZeroes(std::io::Error::from_raw_os_error(libc::EOVERFLOW))
+        })?;
 
-        if let Some(user_data) = user_data {
-            self.completion_list.push_back((user_data, result));
-            self.eventfd.write(1).unwrap();
+        let start = offset as u64;
+        let end = start + length;
+        self.invalidate_cached_blocks(start, length);
+
+        if let Some(block_size) = self.logical_block_size {
+            let aligned_start = start.div_ceil(block_size) * block_size;
+            let aligned_end = end / block_size * block_size;
+            if aligned_start < aligned_end {
+                self.zero_range(aligned_start, aligned_end - aligned_start, unmap)
+                    .map_err(AsyncIoError::WriteZeroes)?;
+                if start < aligned_start {
+                    self.zero_partial_blocks(block_size, start, aligned_start)
+                        .map_err(AsyncIoError::WriteZeroes)?;
+                }
+                if aligned_end < end {
+                    self.zero_partial_blocks(block_size, aligned_end, end)
+                        .map_err(AsyncIoError::WriteZeroes)?;
+                }
+            } else if start < end {
+                self.zero_partial_blocks(block_size, start, end)
+                    .map_err(AsyncIoError::WriteZeroes)?;
+            }
+        } else if length > 0 {
+            self.zero_range(start, length, unmap)
+                .map_err(AsyncIoError::WriteZeroes)?;
+        }
+
+        self.complete(user_data, result, RequestPriority::Normal);
+
+        Ok(())
+    }
+
+    fn zone_report(
+        &mut self,
+        offset: libc::off_t,
+        zones: &mut [BlkZone],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        if self.zone_size.is_none() {
+            return Err(AsyncIoError::ZonesNotSupported);
         }
+        check_completion_room(self.pending_completions())?;
+
+        let nr_zones = zoned::report_zones(self.fd, offset as u64 / SECTOR_SIZE, zones)
+            .map_err(AsyncIoError::ZoneReport)?;
+
+        self.complete(user_data, nr_zones as i32, RequestPriority::Normal);
+
+        Ok(())
+    }
+
+    fn zone_open(&mut self, offset: libc::off_t, user_data: u64) -> AsyncIoResult<()> {
+        self.manage_zone(ZoneOperation::Open, offset, user_data)
+    }
+
+    fn zone_close(&mut self, offset: libc::off_t, user_data: u64) -> AsyncIoResult<()> {
+        self.manage_zone(ZoneOperation::Close, offset, user_data)
+    }
+
+    fn zone_finish(&mut self, offset: libc::off_t, user_data: u64) -> AsyncIoResult<()> {
+        self.manage_zone(ZoneOperation::Finish, offset, user_data)
+    }
+
+    fn zone_reset(&mut self, offset: libc::off_t, user_data: u64) -> AsyncIoResult<()> {
+        self.manage_zone(ZoneOperation::Reset, offset, user_data)
+    }
+
+    fn submit(&mut self) -> AsyncIoResult<()> {
+        // The requests completed as they were queued, the ones of the last
+        // batch are only notified now.
+        self.notify_completions();
 
         Ok(())
     }
 
     fn next_completed_request(&mut self) -> Option<(u64, i32)> {
-        self.completion_list.pop_front()
+        self.high_priority_completions
+            .pop_front()
+            .or_else(|| self.completion_list.pop_front())
+    }
+}
+
+impl RawFileSync {
+    // Completions waiting to be reaped, whatever their priority.
+    fn pending_completions(&self) -> usize {
+        self.completion_list.len() + self.high_priority_completions.len()
+    }
+
+    fn complete(&mut self, user_data: u64, result: i32, priority: RequestPriority) {
+        match priority {
+            RequestPriority::Normal => self.completion_list.push_back((user_data, result)),
+            RequestPriority::High => self
+                .high_priority_completions
+                .push_back((user_data, result)),
+        }
+
+        self.unnotified_completions += 1;
+        if self.unnotified_completions >= self.completion_batch {
+            self.notify_completions();
+        }
+    }
+
+    fn notify_completions(&mut self) {
+        if self.unnotified_completions > 0 {
+            self.eventfd.write(1).unwrap();
+            self.unnotified_completions = 0;
+        }
+    }
+
+    fn write(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+        fua: bool,
+        priority: RequestPriority,
+    ) -> AsyncIoResult<()> {
+        if self.read_only {
+            return Err(AsyncIoError::ReadOnly);
+        }
+        check_completion_room(self.pending_completions())?;
+
+        if self.zone_size.is_some() {
+            self.check_zone_write_pointer(offset as u64)?;
+        }
+
+        // Like flushes, FUA is ignored on purpose without caching policy.
+        let fua = fua && self.cache_mode != CacheMode::None;
+        // Only this write is made durable by RWF_DSYNC, sparing a sync of
+        // the whole file.
+        let mut dsync = fua && self.rwf_dsync;
+        let mut result = self.pwritev_any(offset, iovecs, if dsync { libc::RWF_DSYNC } else { 0 });
+        if dsync {
+            if let Err(e) = &result {
+                if matches!(e.raw_os_error(), Some(libc::EOPNOTSUPP | libc::ENOSYS)) {
+                    warn!("RWF_DSYNC is not supported, syncing the file after FUA writes");
+                    self.rwf_dsync = false;
+                    dsync = false;
+                    result = self.pwritev_any(offset, iovecs, 0);
+                }
+            }
+        }
+        let mut result = result.map_or_else(|e| error_result(&e), |count| count as i32);
+
+        // Without RWF_DSYNC, the range written can't be made durable on its
+        // own, the whole file is synced instead.
+        if result >= 0 && !dsync && (fua || self.cache_mode == CacheMode::Writethrough) {
+            // SAFETY: FFI call
+            if unsafe { libc::fdatasync(self.fd as libc::c_int) } < 0 {
+                result = error_result(&std::io::Error::last_os_error());
+            }
+        }
+
+        // Only the pages already written back can be dropped, the others
+        // are left to the host to reclaim.
+        if self.dontneed && result > 0 {
+            self.advise(offset as u64, result as u64, libc::POSIX_FADV_DONTNEED);
+        }
+
+        self.complete(user_data, result, priority);
+
+        Ok(())
+    }
+
+    fn pwritev_any(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        flags: libc::c_int,
+    ) -> std::io::Result<usize> {
+        match self.unaligned_block_size(offset, iovecs) {
+            Some(block_size) => self.pwritev_unaligned(block_size, offset as u64, iovecs, flags),
+            None => {
+                let len = iovecs.iter().map(|iovec| iovec.iov_len as u64).sum();
+                self.invalidate_cached_blocks(offset as u64, len);
+                self.pwritev(offset, iovecs, flags)
+            }
+        }
+    }
+
+    // Accounts for a request about to modify `length` bytes at `offset`,
+    // dropping the cached blocks it covers, or all of them if another
+    // instance modified the file since the last request of this one. The
+    // partial writes must call it with the partial block lock held, before
+    // looking their blocks up.
+    fn invalidate_cached_blocks(&mut self, offset: u64, length: u64) {
+        // Only the partial writes realigned for O_DIRECT fill the cache.
+        if !self.direct || self.logical_block_size.is_none() {
+            return;
+        }
+
+        let writes = self
+            .partial_block_lock
+            .writes
+            .fetch_add(1, Ordering::AcqRel);
+        if writes == self.writes {
+            self.block_cache.invalidate(offset, offset + length);
+        } else {
+            self.block_cache.clear();
+        }
+        self.writes = writes + 1;
+    }
+
+    // Page cache hints are only an optimization, their failure doesn't
+    // affect the request.
+    fn advise(&self, offset: u64, length: u64, advice: libc::c_int) {
+        if let Err(e) = fadvise(self.fd, offset, length, advice) {
+            debug!("Failed giving page cache hint {}: {}", advice, e);
+        }
+    }
+
+    // Find out whether RWF_NOWAIT reads are supported, which depends on both
+    // the kernel version and the filesystem holding the file. A zero sized
+    // read would return early without validating the flags.
+    fn probe_rwf_nowait(fd: RawFd) -> bool {
+        let mut byte = 0u8;
+        let iovec = libc::iovec {
+            iov_base: &mut byte as *mut u8 as *mut libc::c_void,
+            iov_len: 1,
+        };
+        // SAFETY: FFI call with a valid iovec
+        let result = unsafe { libc::preadv2(fd, &iovec, 1, 0, libc::RWF_NOWAIT) };
+
+        result >= 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EAGAIN)
+    }
+
+    // Apply the operation to the zone containing offset.
+    fn manage_zone(
+        &mut self,
+        op: ZoneOperation,
+        offset: libc::off_t,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let zone_size = self.zone_size.ok_or(AsyncIoError::ZonesNotSupported)?;
+        if self.read_only {
+            return Err(AsyncIoError::ReadOnly);
+        }
+        check_completion_room(self.pending_completions())?;
+
+        let zone_start = offset as u64 / zone_size * zone_size;
+        zoned::manage_zones(
+            self.fd,
+            op,
+            zone_start / SECTOR_SIZE,
+            zone_size / SECTOR_SIZE,
+        )
+        .map_err(AsyncIoError::ZoneManagement)?;
+
+        self.complete(user_data, 0, RequestPriority::Normal);
+
+        Ok(())
+    }
+
+    // Sequential write required zones must be written at their write
+    // pointer, anything else would be failed by the device.
+    fn check_zone_write_pointer(&self, offset: u64) -> AsyncIoResult<()> {
+        let mut zone = [BlkZone::default()];
+        let nr_zones = zoned::report_zones(self.fd, offset / SECTOR_SIZE, &mut zone)
+            .map_err(AsyncIoError::ZoneReport)?;
+        if nr_zones == 1 && zone[0].type_ == BLK_ZONE_TYPE_SEQWRITE_REQ {
+            let write_pointer = zone[0].wp * SECTOR_SIZE;
+            if offset != write_pointer {
+                return Err(AsyncIoError::UnalignedZoneWrite(offset, write_pointer));
+            }
+        }
+
+        Ok(())
+    }
+
+    // preadv() and pwritev() fail with EINVAL past UIO_MAXIOV iovecs, which
+    // a guest request can exceed once realigned. Such a request is served by
+    // chunks at segment boundaries, stopping at the first short one.
+    //
+    // With O_DIRECT, every request reaching them must have been realigned
+    // first, including the reads of the partial blocks of a write, which
+    // would otherwise fail with EINVAL.
+    fn preadv(&self, offset: libc::off_t, iovecs: &[libc::iovec]) -> std::io::Result<usize> {
+        debug_assert!(
+            self.unaligned_block_size(offset, iovecs).is_none(),
+            "Unaligned O_DIRECT read at {offset}"
+        );
+        let mut count = 0;
+        for chunk in iovecs.chunks(libc::UIO_MAXIOV as usize) {
+            let len: usize = chunk.iter().map(|iovec| iovec.iov_len).sum();
+            let read = self.preadv_chunk(offset + count as libc::off_t, chunk)?;
+            count += read;
+            if read < len {
+                break;
+            }
+        }
+
+        Ok(count)
+    }
+
+    fn preadv_chunk(&self, offset: libc::off_t, iovecs: &[libc::iovec]) -> std::io::Result<usize> {
+        if self.rwf_nowait {
+            // Try to serve the read from the page cache first, which never
+            // blocks. EAGAIN means part of the data has to be fetched from
+            // the backing storage, done below through a regular read.
+            // SAFETY: FFI call with valid arguments
+            let result = unsafe {
+                libc::preadv2(
+                    self.fd as libc::c_int,
+                    iovecs.as_ptr(),
+                    iovecs.len() as libc::c_int,
+                    offset,
+                    libc::RWF_NOWAIT,
+                )
+            };
+            if result >= 0 {
+                let expected: usize = iovecs.iter().map(|iovec| iovec.iov_len).sum();
+                // A short read can be caused by some of the data missing from
+                // the page cache, so it can't be told apart from reaching
+                // the end of the file.
+                if result as usize == expected {
+                    return Ok(result as usize);
+                }
+            } else {
+                let e = std::io::Error::last_os_error();
+                if e.raw_os_error() != Some(libc::EAGAIN) {
+                    return Err(e);
+                }
+            }
+        }
+
+        self.retry(|| {
+            // SAFETY: FFI call with valid arguments
+            unsafe {
+                libc::preadv(
+                    self.fd as libc::c_int,
+                    iovecs.as_ptr(),
+                    iovecs.len() as libc::c_int,
+                    offset,
+                )
+            }
+        })
+    }
+
+    fn pwritev(
+        &self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        flags: libc::c_int,
+    ) -> std::io::Result<usize> {
+        debug_assert!(
+            self.unaligned_block_size(offset, iovecs).is_none(),
+            "Unaligned O_DIRECT write at {offset}"
+        );
+        let mut count = 0;
+        for chunk in iovecs.chunks(libc::UIO_MAXIOV as usize) {
+            let len: usize = chunk.iter().map(|iovec| iovec.iov_len).sum();
+            let written = self.pwritev_chunk(offset + count as libc::off_t, chunk, flags)?;
+            count += written;
+            if written < len {
+                break;
+            }
+        }
+
+        Ok(count)
+    }
+
+    fn pwritev_chunk(
+        &self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        flags: libc::c_int,
+    ) -> std::io::Result<usize> {
+        let len: usize = iovecs.iter().map(|iovec| iovec.iov_len).sum();
+        let mut written = self.pwritev_once(offset, iovecs, flags)?;
+        if written == len || self.io_retries == 0 {
+            return Ok(written);
+        }
+
+        // Resume a short write from where it stopped rather than restarting
+        // it, until nothing more gets written.
+        let mut remaining = iovecs.to_vec();
+        let mut count = written;
+        while written < len && count > 0 {
+            advance_iovecs(&mut remaining, count);
+            count = self.pwritev_once(offset + written as libc::off_t, &remaining, flags)?;
+            written += count;
+        }
+
+        Ok(written)
+    }
+
+    fn pwritev_once(
+        &self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        flags: libc::c_int,
+    ) -> std::io::Result<usize> {
+        self.retry(|| {
+            // Plain writes don't depend on pwritev2() being available.
+            // SAFETY: FFI calls with valid arguments
+            unsafe {
+                if flags == 0 {
+                    libc::pwritev(
+                        self.fd as libc::c_int,
+                        iovecs.as_ptr(),
+                        iovecs.len() as libc::c_int,
+                        offset,
+                    )
+                } else {
+                    libc::pwritev2(
+                        self.fd as libc::c_int,
+                        iovecs.as_ptr(),
+                        iovecs.len() as libc::c_int,
+                        offset,
+                        flags,
+                    )
+                }
+            }
+        })
+    }
+
+    // Run the syscall again while it fails with EINTR or EAGAIN, which
+    // network filesystems may return transiently, until the retries are
+    // exhausted. EINTR is retried right away, EAGAIN after a backoff.
+    fn retry<F: FnMut() -> isize>(&self, mut syscall: F) -> std::io::Result<usize> {
+        let mut backoff = RETRY_INITIAL_BACKOFF;
+        let mut retries = 0;
+        loop {
+            let result = syscall();
+            if result >= 0 {
+                return Ok(result as usize);
+            }
+
+            let e = std::io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::EINTR) if retries < self.io_retries => {}
+                Some(libc::EAGAIN) if retries < self.io_retries => {
+                    thread::sleep(backoff);
+                    backoff = cmp::min(backoff * 2, RETRY_MAX_BACKOFF);
+                }
+                _ => return Err(e),
+            }
+            retries += 1;
+        }
+    }
+
+    // Returns the logical block size if the request must be realigned on it
+    // before reaching a file opened with O_DIRECT, which refuses any offset,
+    // length or buffer address that isn't a multiple of it.
+    fn unaligned_block_size(&self, offset: libc::off_t, iovecs: &[libc::iovec]) -> Option<u64> {
+        let block_size = self.logical_block_size.filter(|_| self.direct)?;
+        let aligned = offset as u64 % block_size == 0
+            && iovecs.iter().all(|iovec| {
+                iovec.iov_base as u64 % block_size == 0 && iovec.iov_len as u64 % block_size == 0
+            });
+
+        (!aligned).then_some(block_size)
+    }
+
+    // Size of the file, or capacity of the block device, which fstat()
+    // doesn't report.
+    fn file_size(&self) -> std::io::Result<u64> {
+        // SAFETY: fd is a valid file descriptor, and wrapping the File with
+        // ManuallyDrop prevents it from being closed.
+        let file = ManuallyDrop::new(unsafe { File::from_raw_fd(self.fd) });
+        disk_file_size(&file)
+    }
+
+    fn read(&self, offset: u64, iovecs: &[libc::iovec]) -> std::io::Result<usize> {
+        match self.unaligned_block_size(offset as libc::off_t, iovecs) {
+            Some(block_size) => self.preadv_unaligned(block_size, offset, iovecs),
+            None => self.preadv(offset as libc::off_t, iovecs),
+        }
+    }
+
+    // Read up to the end of the file, the rest of the request reading as
+    // zeroes. The disk may be larger than the file, after it shrank, and a
+    // block device has no holes within its capacity.
+    fn read_full(&self, offset: u64, iovecs: &[libc::iovec]) -> std::io::Result<usize> {
+        let len: usize = iovecs.iter().map(|iovec| iovec.iov_len).sum();
+        let mut count = self.read(offset, iovecs)?;
+        if count == len {
+            return Ok(len);
+        }
+
+        let mut remaining = iovecs.to_vec();
+        advance_iovecs(&mut remaining, count);
+        while !remaining.is_empty() {
+            let read = self.read(offset + count as u64, &remaining)?;
+            if read == 0 {
+                break;
+            }
+            count += read;
+            advance_iovecs(&mut remaining, read);
+        }
+        for iovec in remaining {
+            // SAFETY: the iovecs point to memory valid for the duration of
+            // the request.
+            unsafe { std::ptr::write_bytes(iovec.iov_base as *mut u8, 0, iovec.iov_len) };
+        }
+
+        Ok(len)
+    }
+
+    // Read the blocks covering the request into an aligned buffer, copying
+    // the requested bytes back to the iovecs.
+    fn preadv_unaligned(
+        &self,
+        block_size: u64,
+        offset: u64,
+        iovecs: &[libc::iovec],
+    ) -> std::io::Result<usize> {
+        let len: usize = iovecs.iter().map(|iovec| iovec.iov_len).sum();
+        if len == 0 {
+            return Ok(0);
+        }
+        let start = offset / block_size * block_size;
+        let end = (offset + len as u64).div_ceil(block_size) * block_size;
+        let head = (offset - start) as usize;
+
+        let mut buffer = AlignedBuffer::new((end - start) as usize, block_size as usize)?;
+        let read = self.preadv(start as libc::off_t, &[buffer.iovec(0, buffer.len())])?;
+        // A read short of the head block is only expected with the file
+        // ending before the request, which then reads as zeroes. Anything
+        // else would have the request read the blocks following the ones
+        // which were read as zeroes.
+        if read < head && start + (read as u64) < self.file_size()? {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("Short read of {read} bytes ahead of the request at {offset}"),
+            ));
+        }
+        // Only the bytes of the request actually read are accounted for,
+        // not the ones of the head and tail blocks.
+        let count = read.saturating_sub(head).min(len);
+
+        let data = &buffer.as_mut_slice()[head..head + count];
+        let mut copied = 0;
+        for iovec in iovecs {
+            if copied == count {
+                break;
+            }
+            let n = iovec.iov_len.min(count - copied);
+            // SAFETY: the iovecs point to memory valid for the duration of
+            // the request.
+            unsafe {
+                std::ptr::copy_nonoverlapping(data[copied..].as_ptr(), iovec.iov_base as *mut u8, n)
+            };
+            copied += n;
+        }
+
+        Ok(count)
+    }
+
+    // Write the blocks covering the request from an aligned buffer, after
+    // reading back the partial blocks at both ends so that the bytes outside
+    // of the request are preserved. Those are then cached, the next partial
+    // writes within them not having to read them again.
+    fn pwritev_unaligned(
+        &mut self,
+        block_size: u64,
+        offset: u64,
+        iovecs: &[libc::iovec],
+        flags: libc::c_int,
+    ) -> std::io::Result<usize> {
+        let len: usize = iovecs.iter().map(|iovec| iovec.iov_len).sum();
+        if len == 0 {
+            return Ok(0);
+        }
+        let start = offset / block_size * block_size;
+        let end = (offset + len as u64).div_ceil(block_size) * block_size;
+        let head = (offset - start) as usize;
+        let bs = block_size as usize;
+
+        let mut buffer = AlignedBuffer::new((end - start) as usize, bs)?;
+        let partial_block_lock = self.partial_block_lock.clone();
+        let _guard = partial_block_lock.lock.lock().unwrap();
+
+        // The partial blocks at either end are read back, so that the bytes
+        // the write doesn't cover are preserved. When the write fits within
+        // a single block, both ends are in that block, which is read once.
+        let head_partial = head != 0;
+        let tail_partial = (offset + len as u64) % block_size != 0;
+        let tail_block = buffer.len() - bs;
+        let read_head = head_partial || (tail_partial && tail_block == 0);
+        let read_tail = tail_partial && tail_block != 0;
+        // The blocks in between are entirely overwritten.
+        self.invalidate_cached_blocks(
+            start + block_size,
+            (end - start).saturating_sub(2 * block_size),
+        );
+        if read_head {
+            match self.block_cache.get(start) {
+                Some(block) => buffer.as_mut_slice()[..bs].copy_from_slice(block),
+                None => {
+                    self.preadv(start as libc::off_t, &[buffer.iovec(0, bs)])?;
+                }
+            }
+        }
+        if read_tail {
+            match self.block_cache.get(end - block_size) {
+                Some(block) => buffer.as_mut_slice()[tail_block..].copy_from_slice(block),
+                None => {
+                    self.preadv(
+                        (end - block_size) as libc::off_t,
+                        &[buffer.iovec(tail_block, bs)],
+                    )?;
+                }
+            }
+        }
+
+        let data = &mut buffer.as_mut_slice()[head..head + len];
+        let mut copied = 0;
+        for iovec in iovecs {
+            // SAFETY: the iovecs point to memory valid for the duration of
+            // the request.
+            let src =
+                unsafe { std::slice::from_raw_parts(iovec.iov_base as *const u8, iovec.iov_len) };
+            data[copied..copied + src.len()].copy_from_slice(src);
+            copied += src.len();
+        }
+
+        // The blocks at both ends now hold what was written, unless the
+        // write failed or stopped short, leaving them in an unknown state.
+        let result = self.pwritev(
+            start as libc::off_t,
+            &[buffer.iovec(0, buffer.len())],
+            flags,
+        );
+        let written = match result {
+            Ok(written) if written == buffer.len() => {
+                let data = buffer.as_mut_slice();
+                if read_head {
+                    self.block_cache.insert(start, &data[..bs]);
+                }
+                if read_tail {
+                    self.block_cache
+                        .insert(end - block_size, &data[tail_block..]);
+                }
+                written
+            }
+            result => {
+                self.block_cache.invalidate(start, end);
+                result?
+            }
+        };
+
+        Ok(written.saturating_sub(head).min(len))
+    }
+
+    fn fallocate(&self, mode: libc::c_int, offset: u64, length: u64) -> std::io::Result<()> {
+        // SAFETY: FFI call with valid arguments
+        let result = unsafe {
+            libc::fallocate(
+                self.fd as libc::c_int,
+                mode,
+                offset as libc::off_t,
+                length as libc::off_t,
+            )
+        };
+        if result < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    fn zero_range(&self, offset: u64, length: u64, unmap: bool) -> std::io::Result<()> {
+        // Punching a hole both zeroes and deallocates the range, but not
+        // every file supports it, in which case the range is only zeroed.
+        if unmap {
+            match self.fallocate(
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset,
+                length,
+            ) {
+                Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {}
+                r => return r,
+            }
+        }
+
+        self.fallocate(
+            libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE,
+            offset,
+            length,
+        )
+    }
+
+    // Zero a range which doesn't cover any logical block entirely, by
+    // reading back each block it touches, clearing the requested bytes and
+    // writing the block back. The part of a block past the end of the file
+    // reads as zeroes, and the file is only extended up to the end of the
+    // range.
+    fn zero_partial_blocks(&self, block_size: u64, start: u64, end: u64) -> std::io::Result<()> {
+        let layout = Layout::from_size_align(block_size as usize, block_size as usize)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        // SAFETY: layout has non-zero size
+        let ptr = unsafe { alloc_zeroed(layout) };
+        if ptr.is_null() {
+            return Err(std::io::Error::last_os_error());
+        }
+        // SAFETY: ptr has been allocated with block_size bytes
+        let block = unsafe { std::slice::from_raw_parts_mut(ptr, block_size as usize) };
+        // SAFETY: fd is valid for the lifetime of RawFileSync, and wrapping
+        // the File with ManuallyDrop prevents it from being closed.
+        let file = ManuallyDrop::new(unsafe { File::from_raw_fd(self.fd) });
+
+        let _guard = self.partial_block_lock.lock.lock().unwrap();
+        let mut result = Ok(());
+        let mut block_start = start / block_size * block_size;
+        while block_start < end {
+            let zero_start = (cmp::max(start, block_start) - block_start) as usize;
+            let zero_end = (cmp::min(end, block_start + block_size) - block_start) as usize;
+            result = read_up_to_eof(&file, block, block_start).and_then(|read| {
+                block[read..].fill(0);
+                block[zero_start..zero_end].fill(0);
+                file.write_all_at(block, block_start)?;
+                if read < block.len() {
+                    file.set_len(block_start + cmp::max(read, zero_end) as u64)?;
+                }
+                Ok(())
+            });
+            if result.is_err() {
+                break;
+            }
+            block_start += block_size;
+        }
+
+        // SAFETY: ptr has been allocated by alloc_zeroed with the same layout
+        unsafe { dealloc(ptr, layout) };
+
+        result
+    }
+}
+
+// Read into `buf` until it's full or the end of the file is reached,
+// returning the number of bytes read.
+fn read_up_to_eof(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read_at(&mut buf[read..], offset + read as u64) {
+            Ok(0) => break,
+            Ok(count) => read += count,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(read)
+}
+
+// Drop the first `count` bytes of the iovecs.
+fn advance_iovecs(iovecs: &mut Vec<libc::iovec>, mut count: usize) {
+    let consumed = iovecs
+        .iter()
+        .take_while(|iovec| {
+            let whole = iovec.iov_len <= count;
+            if whole {
+                count -= iovec.iov_len;
+            }
+            whole
+        })
+        .count();
+    iovecs.drain(..consumed);
+    if let Some(iovec) = iovecs.first_mut() {
+        // SAFETY: count is smaller than the length of the iovec
+        iovec.iov_base = unsafe { (iovec.iov_base as *mut u8).add(count) } as *mut libc::c_void;
+        iovec.iov_len -= count;
+    }
+}
+
+// Least recently used logical blocks, each at the offset of the block in
+// the file, the most recently used first.
+#[derive(Default)]
+struct BlockCache {
+    blocks: VecDeque<(u64, Vec<u8>)>,
+}
+
+impl BlockCache {
+    fn get(&mut self, offset: u64) -> Option<&[u8]> {
+        let index = self.blocks.iter().position(|(o, _)| *o == offset)?;
+        let block = self.blocks.remove(index)?;
+        self.blocks.push_front(block);
+        self.blocks.front().map(|(_, data)| data.as_slice())
+    }
+
+    fn insert(&mut self, offset: u64, data: &[u8]) {
+        let block = match self.blocks.iter().position(|(o, _)| *o == offset) {
+            Some(index) => self.blocks.remove(index),
+            None if self.blocks.len() == BLOCK_CACHE_BLOCKS => self.blocks.pop_back(),
+            None => None,
+        };
+        let mut block = block.map_or_else(Vec::new, |(_, data)| data);
+        block.clear();
+        block.extend_from_slice(data);
+        self.blocks.push_front((offset, block));
+    }
+
+    // Drop the blocks overlapping with the range from `start` to `end`.
+    fn invalidate(&mut self, start: u64, end: u64) {
+        self.blocks
+            .retain(|(offset, data)| offset + data.len() as u64 <= start || *offset >= end);
+    }
+
+    fn clear(&mut self) {
+        self.blocks.clear();
+    }
+}
+
+// Zeroed heap buffer aligned on the logical block size, suitable for
+// O_DIRECT.
+struct AlignedBuffer {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+impl AlignedBuffer {
+    fn new(len: usize, align: usize) -> std::io::Result<Self> {
+        let layout = Layout::from_size_align(len, align)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        if layout.size() == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput));
+        }
+        // SAFETY: layout has non-zero size
+        let ptr = unsafe { alloc_zeroed(layout) };
+        if ptr.is_null() {
+            return Err(std::io::Error::from(std::io::ErrorKind::OutOfMemory));
+        }
+
+        Ok(AlignedBuffer { ptr, layout })
+    }
+
+    fn len(&self) -> usize {
+        self.layout.size()
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: ptr has been allocated with layout.size() bytes
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+
+    fn iovec(&self, offset: usize, len: usize) -> libc::iovec {
+        libc::iovec {
+            // SAFETY: callers stay within the allocated size
+            iov_base: unsafe { self.ptr.add(offset) } as *mut libc::c_void,
+            iov_len: len,
+        }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        // SAFETY: ptr has been allocated by alloc_zeroed with the same layout
+        unsafe { dealloc(self.ptr, self.layout) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::os::unix::fs::OpenOptionsExt;
+    use vmm_sys_util::tempfile::TempFile;
+
+    // Realigns the requests on 4 KiB as with O_DIRECT, the file being
+    // opened without it for the test to run on any filesystem.
+    fn realigning_io(file: &TempFile) -> RawFileSync {
+        let mut io = RawFileSync::new(
+            file.as_file().as_raw_fd(),
+            Some(4096),
+            None,
+            false,
+            CacheMode::Writeback,
+        )
+        .unwrap();
+        io.direct = true;
+        io
+    }
+
+    #[test]
+    fn test_more_iovecs_than_uio_maxiov() {
+        let file = TempFile::new().unwrap();
+        let mut io = RawFileSync::new(
+            file.as_file().as_raw_fd(),
+            None,
+            None,
+            false,
+            CacheMode::Writeback,
+        )
+        .unwrap();
+
+        // One more segment than a single pwritev() takes, each of a
+        // distinct pattern.
+        let segments = libc::UIO_MAXIOV as usize + 1;
+        let mut buf: Vec<u8> = (0..segments).flat_map(|i| [i as u8; 512]).collect();
+        let iovecs: Vec<libc::iovec> = buf
+            .chunks_mut(512)
+            .map(|chunk| libc::iovec {
+                iov_base: chunk.as_mut_ptr() as *mut libc::c_void,
+                iov_len: chunk.len(),
+            })
+            .collect();
+        io.write_vectored(0, &iovecs, 1).unwrap();
+        assert_eq!(io.next_completed_request(), Some((1, buf.len() as i32)));
+        assert_eq!(io.next_completed_request(), None);
+
+        let mut data = vec![0u8; buf.len()];
+        file.as_file().read_exact_at(&mut data, 0).unwrap();
+        assert_eq!(data, buf);
+
+        let mut read = vec![0u8; buf.len()];
+        let iovecs: Vec<libc::iovec> = read
+            .chunks_mut(512)
+            .map(|chunk| libc::iovec {
+                iov_base: chunk.as_mut_ptr() as *mut libc::c_void,
+                iov_len: chunk.len(),
+            })
+            .collect();
+        io.read_vectored(0, &iovecs, 2).unwrap();
+        assert_eq!(io.next_completed_request(), Some((2, buf.len() as i32)));
+        assert_eq!(io.next_completed_request(), None);
+        assert_eq!(read, buf);
+    }
+
+    #[test]
+    fn test_write_within_block() {
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(&[0x11u8; 3 * 4096]).unwrap();
+        let mut io = realigning_io(&file);
+
+        // Neither end of the write is on a block boundary, and both are in
+        // the same block.
+        let mut buf = vec![0xa5u8; 100];
+        let iovec = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        io.write_vectored(4096 + 1000, &[iovec], 1).unwrap();
+        assert_eq!(io.next_completed_request(), Some((1, 100)));
+
+        // And across a block boundary.
+        io.write_vectored(2 * 4096 - 50, &[iovec], 2).unwrap();
+        assert_eq!(io.next_completed_request(), Some((2, 100)));
+
+        let mut data = vec![0u8; 3 * 4096];
+        file.as_file().read_exact_at(&mut data, 0).unwrap();
+        for (i, byte) in data.iter().enumerate() {
+            let written = (4096 + 1000..4096 + 1100).contains(&i)
+                || (2 * 4096 - 50..2 * 4096 + 50).contains(&i);
+            assert_eq!(*byte, if written { 0xa5 } else { 0x11 }, "byte {i}");
+        }
+    }
+
+    #[test]
+    fn test_discard_partial_blocks() {
+        use std::os::unix::fs::MetadataExt;
+
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(&[0x11u8; 4 * 4096]).unwrap();
+        file.as_file().sync_all().unwrap();
+        let allocated = file.as_file().metadata().unwrap().blocks();
+        let mut io = realigning_io(&file);
+
+        // The range is rounded in on both ends, only the blocks it covers
+        // entirely being punched.
+        io.discard(1000, 3 * 4096 - 500, 1).unwrap();
+        assert_eq!(io.next_completed_request(), Some((1, 0)));
+        let mut data = vec![0u8; 4 * 4096];
+        file.as_file().read_exact_at(&mut data, 0).unwrap();
+        for (i, byte) in data.iter().enumerate() {
+            let punched = (4096..3 * 4096).contains(&i);
+            assert_eq!(*byte, if punched { 0 } else { 0x11 }, "byte {i}");
+        }
+        assert_eq!(
+            file.as_file().metadata().unwrap().blocks(),
+            allocated - 2 * 4096 / 512
+        );
+
+        // A range within a single block is left untouched.
+        io.discard(3 * 4096 + 100, 1000, 2).unwrap();
+        assert_eq!(io.next_completed_request(), Some((2, 0)));
+        file.as_file().read_exact_at(&mut data, 0).unwrap();
+        assert!(data[3 * 4096..].iter().all(|b| *b == 0x11));
+    }
+
+    #[test]
+    fn test_partial_block_reads_aligned() {
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(&[0x11u8; 4 * 4096]).unwrap();
+        let mut expected = vec![0x11u8; 4 * 4096];
+
+        // The buffer of each write is misaligned as well, the partial blocks
+        // being read back and written from aligned buffers of whole blocks
+        // only, which preadv() and pwritev() assert. A new instance is used
+        // each time, for the partial blocks not to be served from the cache.
+        let mut buf = vec![0xa5u8; 2 * 4096 + 1];
+        for (user_data, (offset, len)) in [(1, 1), (4095, 2), (100, 4096), (4096 + 10, 2 * 4096)]
+            .into_iter()
+            .enumerate()
+        {
+            let mut io = realigning_io(&file);
+            let iovec = libc::iovec {
+                iov_base: buf[1..].as_mut_ptr() as *mut libc::c_void,
+                iov_len: len,
+            };
+            io.write_vectored(offset as libc::off_t, &[iovec], user_data as u64)
+                .unwrap();
+            assert_eq!(
+                io.next_completed_request(),
+                Some((user_data as u64, len as i32))
+            );
+            expected[offset..offset + len].fill(0xa5);
+        }
+
+        let mut data = vec![0u8; 4 * 4096];
+        file.as_file().read_exact_at(&mut data, 0).unwrap();
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn test_concurrent_writes_within_block() {
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(&[0u8; 4096]).unwrap();
+        let lock = Arc::new(PartialBlockLock::default());
+
+        // Each queue writes its own half of the same block, the other half
+        // being preserved whatever the interleaving.
+        let threads: Vec<_> = [(0u64, 0x11u8), (2048, 0x22)]
+            .into_iter()
+            .map(|(offset, pattern)| {
+                let mut io = realigning_io(&file);
+                io.set_partial_block_lock(lock.clone());
+                thread::spawn(move || {
+                    let mut buf = vec![pattern; 2048];
+                    let iovec = libc::iovec {
+                        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                        iov_len: buf.len(),
+                    };
+                    for user_data in 0..200 {
+                        io.write_vectored(offset as libc::off_t, &[iovec], user_data)
+                            .unwrap();
+                        assert_eq!(io.next_completed_request(), Some((user_data, 2048)));
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let mut data = vec![0u8; 4096];
+        file.as_file().read_exact_at(&mut data, 0).unwrap();
+        assert!(data[..2048].iter().all(|b| *b == 0x11));
+        assert!(data[2048..].iter().all(|b| *b == 0x22));
+    }
+
+    #[test]
+    fn test_cached_partial_blocks() {
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(&[0x11u8; 4096]).unwrap();
+        let mut io = realigning_io(&file);
+
+        let mut buf = vec![0xa5u8; 512];
+        let iovec = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        io.write_vectored(0, &[iovec], 1).unwrap();
+        assert_eq!(io.next_completed_request(), Some((1, 512)));
+
+        // Modified behind the back of the disk, the block isn't read again
+        // by the next partial write within it.
+        file.as_file().write_all_at(&[0x22u8; 4096], 0).unwrap();
+        io.write_vectored(512, &[iovec], 2).unwrap();
+        assert_eq!(io.next_completed_request(), Some((2, 512)));
+
+        let mut data = vec![0u8; 4096];
+        file.as_file().read_exact_at(&mut data, 0).unwrap();
+        assert!(data[..1024].iter().all(|b| *b == 0xa5));
+        assert!(data[1024..].iter().all(|b| *b == 0x11));
+    }
+
+    #[test]
+    fn test_cached_partial_blocks_invalidation() {
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(&[0x11u8; 4096]).unwrap();
+        let lock = Arc::new(PartialBlockLock::default());
+        let mut io = realigning_io(&file);
+        io.set_partial_block_lock(lock.clone());
+        let mut other = realigning_io(&file);
+        other.set_partial_block_lock(lock);
+
+        let mut buf = vec![0xa5u8; 512];
+        let iovec = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut block = AlignedBuffer::new(4096, 4096).unwrap();
+        block.as_mut_slice().fill(0x22);
+        let block_iovec = block.iovec(0, block.len());
+
+        // Written by the same queue.
+        io.write_vectored(0, &[iovec], 1).unwrap();
+        assert_eq!(io.next_completed_request(), Some((1, 512)));
+        io.write_vectored(0, &[block_iovec], 2).unwrap();
+        assert_eq!(io.next_completed_request(), Some((2, 4096)));
+        io.write_vectored(512, &[iovec], 3).unwrap();
+        assert_eq!(io.next_completed_request(), Some((3, 512)));
+
+        let mut data = vec![0u8; 4096];
+        file.as_file().read_exact_at(&mut data, 0).unwrap();
+        assert!(data[..512].iter().all(|b| *b == 0x22));
+        assert!(data[512..1024].iter().all(|b| *b == 0xa5));
+        assert!(data[1024..].iter().all(|b| *b == 0x22));
+
+        // And by another one.
+        block.as_mut_slice().fill(0x33);
+        other.write_vectored(0, &[block_iovec], 4).unwrap();
+        assert_eq!(other.next_completed_request(), Some((4, 4096)));
+        io.write_vectored(1024, &[iovec], 5).unwrap();
+        assert_eq!(io.next_completed_request(), Some((5, 512)));
+
+        file.as_file().read_exact_at(&mut data, 0).unwrap();
+        assert!(data[..1024].iter().all(|b| *b == 0x33));
+        assert!(data[1024..1536].iter().all(|b| *b == 0xa5));
+        assert!(data[1536..].iter().all(|b| *b == 0x33));
+    }
+
+    #[test]
+    fn test_completion_burst() {
+        const BURST: u64 = 64;
+
+        let file = TempFile::new().unwrap();
+        file.as_file().set_len(BURST * 512).unwrap();
+        let mut io = RawFileSync::new(
+            file.as_file().as_raw_fd(),
+            None,
+            None,
+            false,
+            CacheMode::Writeback,
+        )
+        .unwrap();
+
+        let mut buf = vec![0xa5u8; 512];
+        let iovec = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        for user_data in 0..BURST {
+            io.write_vectored((user_data * 512) as libc::off_t, &[iovec], user_data)
+                .unwrap();
+        }
+        io.fsync(Some(BURST)).unwrap();
+
+        // A single read of the notifier accounts for the whole burst.
+        assert_eq!(io.notifier().read().unwrap(), BURST + 1);
+        let mut completed = HashSet::new();
+        while let Some((user_data, result)) = io.next_completed_request() {
+            assert!(completed.insert(user_data));
+            assert_eq!(result, if user_data == BURST { 0 } else { 512 });
+        }
+        assert_eq!(completed.len() as u64, BURST + 1);
+
+        // Nothing is left to be signaled.
+        assert!(io.notifier().read().is_err());
+        assert_eq!(io.next_completed_request(), None);
+    }
+
+    #[test]
+    fn test_completion_batch() {
+        let file = TempFile::new().unwrap();
+        file.as_file().set_len(8 * 512).unwrap();
+        let mut io = RawFileSync::new(
+            file.as_file().as_raw_fd(),
+            None,
+            None,
+            false,
+            CacheMode::Writeback,
+        )
+        .unwrap();
+        io.set_completion_batch(4);
+
+        let mut buf = vec![0xa5u8; 512];
+        let iovec = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        for user_data in 0..6 {
+            io.write_vectored((user_data * 512) as libc::off_t, &[iovec], user_data)
+                .unwrap();
+        }
+
+        // The first batch is notified once full, the rest once submitted.
+        assert_eq!(io.notifier().read().unwrap(), 1);
+        assert!(io.notifier().read().is_err());
+        io.submit().unwrap();
+        assert_eq!(io.notifier().read().unwrap(), 1);
+        for user_data in 0..6 {
+            assert_eq!(io.next_completed_request(), Some((user_data, 512)));
+        }
+
+        // Nothing is left to notify.
+        io.submit().unwrap();
+        assert!(io.notifier().read().is_err());
+    }
+
+    #[test]
+    fn test_request_priority() {
+        let file = TempFile::new().unwrap();
+        file.as_file().set_len(4096).unwrap();
+        let mut io = RawFileSync::new(
+            file.as_file().as_raw_fd(),
+            None,
+            None,
+            false,
+            CacheMode::Writeback,
+        )
+        .unwrap();
+
+        let mut buf = vec![0xa5u8; 512];
+        let iovec = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        io.write_vectored(0, &[iovec], 1).unwrap();
+        io.read_vectored_with_priority(512, &[iovec], 2, RequestPriority::Normal)
+            .unwrap();
+        io.fsync_with_priority(Some(3), RequestPriority::High)
+            .unwrap();
+        io.write_vectored_with_priority(1024, &[iovec], 4, RequestPriority::High)
+            .unwrap();
+
+        // The high priority completions are returned first, each level in
+        // order.
+        assert_eq!(io.next_completed_request(), Some((3, 0)));
+        assert_eq!(io.next_completed_request(), Some((4, 512)));
+        assert_eq!(io.next_completed_request(), Some((1, 512)));
+        assert_eq!(io.next_completed_request(), Some((2, 512)));
+        assert_eq!(io.next_completed_request(), None);
+        assert_eq!(io.notifier().read().unwrap(), 4);
+    }
+
+    #[test]
+    fn test_rwf_nowait_reads() {
+        // A pipe can't be read at an offset, with RWF_NOWAIT or not.
+        let mut fds = [0; 2];
+        // SAFETY: FFI call with a valid array
+        assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }, 0);
+        // SAFETY: the descriptors were just created, and are owned by nothing else
+        let _pipe = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        assert!(!RawFileSync::probe_rwf_nowait(fds[0]));
+
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(&[0xa5u8; 8192]).unwrap();
+        let mut io = RawFileSync::new(
+            file.as_file().as_raw_fd(),
+            None,
+            None,
+            false,
+            CacheMode::Writeback,
+        )
+        .unwrap();
+        // Whether RWF_NOWAIT is supported depends on the filesystem holding
+        // the test file.
+        if !io.rwf_nowait {
+            return;
+        }
+
+        // The data just written is in the page cache.
+        let mut buf = vec![0u8; 8192];
+        let iovecs = [libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: 4096,
+        }];
+        io.read_vectored(0, &iovecs, 1).unwrap();
+        assert_eq!(io.next_completed_request(), Some((1, 4096)));
+        assert!(buf[..4096].iter().all(|b| *b == 0xa5));
+
+        // A short read is retried without RWF_NOWAIT, reaching the end of
+        // the file the same way.
+        buf.fill(0);
+        let iovecs = [libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: 8192,
+        }];
+        io.read_vectored(4096, &iovecs, 2).unwrap();
+        assert_eq!(io.next_completed_request(), Some((2, 8192)));
+        assert!(buf[..4096].iter().all(|b| *b == 0xa5));
+        assert!(buf[4096..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_read_past_eof() {
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(&[0xa5u8; 1024]).unwrap();
+        let mut io = RawFileSync::new(
+            file.as_file().as_raw_fd(),
+            None,
+            None,
+            false,
+            CacheMode::Writeback,
+        )
+        .unwrap();
+
+        // The part of the request past the end of the file reads as zeroes.
+        let mut buf = vec![0xffu8; 2048];
+        let iovecs = [
+            libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: 1024,
+            },
+            libc::iovec {
+                iov_base: buf[1024..].as_mut_ptr() as *mut libc::c_void,
+                iov_len: 1024,
+            },
+        ];
+        io.read_vectored(512, &iovecs, 1).unwrap();
+        assert_eq!(io.next_completed_request(), Some((1, 2048)));
+        assert!(buf[..512].iter().all(|b| *b == 0xa5));
+        assert!(buf[512..].iter().all(|b| *b == 0));
+
+        buf.fill(0xff);
+        io.read_vectored(4096, &iovecs[..1], 2).unwrap();
+        assert_eq!(io.next_completed_request(), Some((2, 1024)));
+        assert!(buf[..1024].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_short_realigned_read() {
+        // The file ends within the second block, so that the realigned reads
+        // of it are short.
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(&[0xa5u8; 4096 + 1000]).unwrap();
+        let mut io = realigning_io(&file);
+
+        // Only the bytes of the request read from the file are accounted
+        // for, the rest reading as zeroes.
+        let mut buf = vec![0xffu8; 1024];
+        let iovec = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        io.read_vectored(4096 + 512, &[iovec], 1).unwrap();
+        assert_eq!(io.next_completed_request(), Some((1, 1024)));
+        assert!(buf[..488].iter().all(|b| *b == 0xa5));
+        assert!(buf[488..].iter().all(|b| *b == 0));
+
+        // The read doesn't even reach the head of the request, the file
+        // ending before it.
+        buf.fill(0xff);
+        io.read_vectored(4096 + 2048, &[iovec], 2).unwrap();
+        assert_eq!(io.next_completed_request(), Some((2, 1024)));
+        assert!(buf.iter().all(|b| *b == 0));
+        assert_eq!(io.preadv_unaligned(4096, 4096 + 2048, &[iovec]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_guest_block_sizes() {
+        let file = TempFile::new().unwrap();
+        file.as_file().set_len(1 << 20).unwrap();
+        let open = |logical_block_size| {
+            RawFileDiskSync::new(
+                file.as_file().try_clone().unwrap(),
+                false,
+                CacheMode::Writeback,
+                logical_block_size,
+            )
+            .unwrap()
+        };
+
+        let topology = open(None).topology();
+        assert_eq!(topology.logical_block_size, 512);
+        assert_eq!(topology.physical_block_size, 512);
+
+        // The physical block size follows a larger logical block size.
+        let topology = open(Some(4096)).topology();
+        assert_eq!(topology.logical_block_size, 4096);
+        assert_eq!(topology.physical_block_size, 4096);
+        assert_eq!(topology.minimum_io_size, 4096);
+
+        let mut disk = open(None);
+        disk.set_physical_block_size(Some(4096));
+        let topology = disk.topology();
+        assert_eq!(topology.logical_block_size, 512);
+        assert_eq!(topology.physical_block_size, 4096);
+        assert_eq!(topology.minimum_io_size, 4096);
+    }
+
+    #[test]
+    fn test_available_space() {
+        let file = TempFile::new().unwrap();
+        let disk = RawFileDiskSync::new(
+            file.as_file().try_clone().unwrap(),
+            false,
+            CacheMode::Writeback,
+            None,
+        )
+        .unwrap();
+        // Regular files report the space left on their filesystem.
+        assert!(disk.available_space().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_size_leaves_file_offset() {
+        let file = TempFile::new().unwrap();
+        for block in 0..4u8 {
+            file.as_file().write_all(&[block; 4096]).unwrap();
+        }
+        file.as_file().seek(SeekFrom::Start(100)).unwrap();
+        let mut disk = RawFileDiskSync::new(
+            file.as_file().try_clone().unwrap(),
+            false,
+            CacheMode::Writeback,
+            None,
+        )
+        .unwrap();
+        let mut io = disk.new_async_io(1).unwrap();
+
+        // Each read lands at its own offset wherever the size was last
+        // queried from, the offset the handles share being left alone.
+        for (user_data, block) in [3u8, 0, 2, 1].into_iter().enumerate() {
+            assert_eq!(disk.size().unwrap(), 4 * 4096);
+            let mut buf = vec![0xffu8; 4096];
+            let iovec = libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            };
+            io.read_vectored(i64::from(block) * 4096, &[iovec], user_data as u64)
+                .unwrap();
+            assert_eq!(io.next_completed_request(), Some((user_data as u64, 4096)));
+            assert!(buf.iter().all(|b| *b == block), "block {block}");
+        }
+        assert_eq!(file.as_file().stream_position().unwrap(), 100);
+    }
+
+    #[test]
+    fn test_write_zeroes_partial_blocks() {
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(&[0xa5u8; 9192]).unwrap();
+        let mut io = realigning_io(&file);
+
+        // Only the requested bytes of the block are cleared.
+        io.write_zeroes(1000, 2000, false, 1).unwrap();
+        assert_eq!(io.next_completed_request(), Some((1, 2000)));
+        let mut buf = vec![0u8; 4096];
+        file.as_file().read_exact_at(&mut buf, 0).unwrap();
+        assert!(buf[..1000].iter().all(|b| *b == 0xa5));
+        assert!(buf[1000..3000].iter().all(|b| *b == 0));
+        assert!(buf[3000..].iter().all(|b| *b == 0xa5));
+
+        // The last block is read back up to the end of the file, which is
+        // extended up to the end of the range.
+        io.write_zeroes(8692, 1000, false, 2).unwrap();
+        assert_eq!(io.next_completed_request(), Some((2, 1000)));
+        assert_eq!(file.as_file().metadata().unwrap().len(), 9692);
+        let mut buf = vec![0u8; 1500];
+        file.as_file().read_exact_at(&mut buf, 8192).unwrap();
+        assert!(buf[..500].iter().all(|b| *b == 0xa5));
+        assert!(buf[500..].iter().all(|b| *b == 0));
+
+        // A length the completion can't report fails the request.
+        assert!(matches!(
+            io.write_zeroes(0, 1 << 31, false, 3),
+            Err(AsyncIoError::WriteZeroes(_))
+        ));
+        assert_eq!(io.next_completed_request(), None);
+    }
+
+    #[test]
+    fn test_discard_zeroes() {
+        let file = TempFile::new().unwrap();
+        let disk = RawFileDiskSync::new(
+            file.as_file().try_clone().unwrap(),
+            false,
+            CacheMode::Writeback,
+            None,
+        )
+        .unwrap();
+        assert!(disk.discard_zeroes());
+
+        // The partial blocks at both ends of a discard are left untouched.
+        let disk = RawFileDiskSync::new(
+            file.as_file().try_clone().unwrap(),
+            false,
+            CacheMode::Writeback,
+            Some(4096),
+        )
+        .unwrap();
+        assert!(!disk.discard_zeroes());
+    }
+
+    #[test]
+    fn test_from_raw_fd() {
+        let file = TempFile::new().unwrap();
+        file.as_file().set_len(8192).unwrap();
+        let fd = file.as_file().as_raw_fd();
+
+        let mut disk = RawFileDiskSync::from_raw_fd(fd, false, CacheMode::Writeback, None).unwrap();
+        assert_eq!(disk.size().unwrap(), 8192);
+        drop(disk);
+        // The descriptor of the caller is left open.
+        assert_eq!(file.as_file().metadata().unwrap().len(), 8192);
+
+        // The flags of the descriptor must match the disk configuration.
+        assert!(matches!(
+            RawFileDiskSync::from_raw_fd(fd, false, CacheMode::DirectSync, None),
+            Err(DiskFileError::InvalidFd(_))
+        ));
+        let read_only = File::open(file.as_path()).unwrap();
+        assert!(matches!(
+            RawFileDiskSync::from_raw_fd(read_only.as_raw_fd(), false, CacheMode::Writeback, None),
+            Err(DiskFileError::InvalidFd(_))
+        ));
+        RawFileDiskSync::from_raw_fd(read_only.as_raw_fd(), true, CacheMode::Writeback, None)
+            .unwrap();
+
+        // Only files and block devices back disks.
+        let mut pipe = [0; 2];
+        // SAFETY: FFI call with a valid buffer
+        assert_eq!(unsafe { libc::pipe(pipe.as_mut_ptr()) }, 0);
+        assert!(matches!(
+            RawFileDiskSync::from_raw_fd(pipe[0], true, CacheMode::Writeback, None),
+            Err(DiskFileError::InvalidFd(_))
+        ));
+        // SAFETY: the descriptors were just created
+        unsafe {
+            libc::close(pipe[0]);
+            libc::close(pipe[1]);
+        }
+    }
+
+    #[test]
+    fn test_read_only() {
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(&[0x11u8; 4096]).unwrap();
+        let mut io = RawFileSync::new(
+            file.as_file().as_raw_fd(),
+            None,
+            None,
+            true,
+            CacheMode::Writeback,
+        )
+        .unwrap();
+
+        let mut buf = [0x22u8; 512];
+        let iovecs = [libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }];
+        assert!(matches!(
+            io.write_vectored(0, &iovecs, 1),
+            Err(AsyncIoError::ReadOnly)
+        ));
+        assert!(matches!(
+            io.discard(0, 4096, 2),
+            Err(AsyncIoError::ReadOnly)
+        ));
+        assert!(matches!(
+            io.write_zeroes(0, 4096, false, 3),
+            Err(AsyncIoError::ReadOnly)
+        ));
+        // None of them reached the file, or completed.
+        assert_eq!(io.next_completed_request(), None);
+        let mut data = [0u8; 4096];
+        file.as_file().read_exact_at(&mut data, 0).unwrap();
+        assert_eq!(data, [0x11u8; 4096]);
+
+        // Flushes succeed, with or without a request to complete.
+        io.fsync(Some(4)).unwrap();
+        assert_eq!(io.next_completed_request(), Some((4, 0)));
+        io.fsync(None).unwrap();
+        assert_eq!(io.next_completed_request(), None);
+
+        // Reads are still served.
+        io.read_vectored(0, &iovecs, 5).unwrap();
+        assert_eq!(io.next_completed_request(), Some((5, 512)));
+        assert_eq!(buf, [0x11u8; 512]);
+    }
+
+    #[test]
+    fn test_zones_not_supported() {
+        let file = TempFile::new().unwrap();
+        file.as_file().set_len(8192).unwrap();
+        let mut io = RawFileSync::new(
+            file.as_file().as_raw_fd(),
+            None,
+            None,
+            false,
+            CacheMode::Writeback,
+        )
+        .unwrap();
+
+        let mut zones = [BlkZone::default()];
+        assert!(matches!(
+            io.zone_report(0, &mut zones, 1),
+            Err(AsyncIoError::ZonesNotSupported)
+        ));
+        assert!(matches!(
+            io.zone_open(0, 2),
+            Err(AsyncIoError::ZonesNotSupported)
+        ));
+        assert!(matches!(
+            io.zone_close(0, 3),
+            Err(AsyncIoError::ZonesNotSupported)
+        ));
+        assert!(matches!(
+            io.zone_finish(0, 4),
+            Err(AsyncIoError::ZonesNotSupported)
+        ));
+        assert!(matches!(
+            io.zone_reset(0, 5),
+            Err(AsyncIoError::ZonesNotSupported)
+        ));
+        assert_eq!(io.next_completed_request(), None);
+
+        // A write to a zoned disk whose write pointer can't be checked
+        // doesn't reach the file.
+        let mut io = RawFileSync::new(
+            file.as_file().as_raw_fd(),
+            None,
+            Some(4096),
+            false,
+            CacheMode::Writeback,
+        )
+        .unwrap();
+        let mut buf = [0x11u8; 512];
+        let iovecs = [libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }];
+        assert!(matches!(
+            io.write_vectored(0, &iovecs, 6),
+            Err(AsyncIoError::ZoneReport(_))
+        ));
+        assert_eq!(io.next_completed_request(), None);
+        let mut data = [0u8; 512];
+        file.as_file().read_exact_at(&mut data, 0).unwrap();
+        assert_eq!(data, [0u8; 512]);
+    }
+
+    #[test]
+    fn test_cache_modes() {
+        // Writes to /dev/null succeed while syncing it fails, which tells
+        // whether the data were synced.
+        let null = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/null")
+            .unwrap();
+        let mut buf = [0x11u8; 512];
+        let iovecs = [libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }];
+        let einval = -libc::EINVAL;
+        for (cache_mode, write, flush) in [
+            (CacheMode::None, 512, 0),
+            (CacheMode::Writeback, 512, einval),
+            (CacheMode::Writethrough, einval, einval),
+        ] {
+            let mut io = RawFileSync::new(null.as_raw_fd(), None, None, false, cache_mode).unwrap();
+            io.write_vectored(0, &iovecs, 1).unwrap();
+            assert_eq!(io.next_completed_request(), Some((1, write)));
+            io.fsync(Some(2)).unwrap();
+            assert_eq!(io.next_completed_request(), Some((2, flush)));
+        }
+
+        // Only directsync changes the flags of the file.
+        assert_eq!(CacheMode::None.open_flags(), 0);
+        assert_eq!(CacheMode::Writeback.open_flags(), 0);
+        assert_eq!(CacheMode::Writethrough.open_flags(), 0);
+        assert_eq!(
+            CacheMode::DirectSync.open_flags(),
+            libc::O_DIRECT | libc::O_DSYNC
+        );
+        assert_eq!(
+            "DirectSync".parse::<CacheMode>().unwrap(),
+            CacheMode::DirectSync
+        );
+        assert!("unsafe".parse::<CacheMode>().is_err());
+    }
+
+    #[test]
+    fn test_direct_alignment_detected() {
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(&[0xa5u8; 4096]).unwrap();
+        // Whether O_DIRECT is supported depends on the filesystem holding the
+        // test file.
+        let direct = match std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(file.as_path())
+        {
+            Ok(direct) => direct,
+            Err(_) => return,
+        };
+        let mut io =
+            RawFileSync::new(direct.as_raw_fd(), None, None, false, CacheMode::Writeback).unwrap();
+        assert!(io.direct);
+        assert_eq!(io.logical_block_size, Some(512));
+
+        // Buffers misaligned in memory are bounced rather than failing with
+        // EINVAL.
+        let mut buf = vec![0u8; 1025];
+        let iovecs = [libc::iovec {
+            iov_base: buf[1..].as_mut_ptr() as *mut libc::c_void,
+            iov_len: 1024,
+        }];
+        io.read_vectored(512, &iovecs, 1).unwrap();
+        assert_eq!(io.next_completed_request(), Some((1, 1024)));
+        assert!(buf[1..].iter().all(|b| *b == 0xa5));
+
+        buf.fill(0x5a);
+        io.write_vectored(1024, &iovecs, 2).unwrap();
+        assert_eq!(io.next_completed_request(), Some((2, 1024)));
+        let mut data = vec![0u8; 4096];
+        file.as_file().read_exact_at(&mut data, 0).unwrap();
+        assert!(data[..1024].iter().all(|b| *b == 0xa5));
+        assert!(data[1024..2048].iter().all(|b| *b == 0x5a));
+        assert!(data[2048..].iter().all(|b| *b == 0xa5));
+    }
+
+    #[test]
+    fn test_failed_requests_complete() {
+        // Reads of a write only file fail with EBADF.
+        let file = TempFile::new().unwrap();
+        let write_only = std::fs::OpenOptions::new()
+            .write(true)
+            .open(file.as_path())
+            .unwrap();
+        let mut io = RawFileSync::new(
+            write_only.as_raw_fd(),
+            None,
+            None,
+            false,
+            CacheMode::Writeback,
+        )
+        .unwrap();
+
+        // The failure is handed over to the guest, rather than failing the
+        // submission.
+        let mut buf = [0u8; 512];
+        let iovecs = [libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }];
+        io.read_vectored(0, &iovecs, 1).unwrap();
+        assert_eq!(io.next_completed_request(), Some((1, -libc::EBADF)));
+        io.write_vectored(0, &iovecs, 2).unwrap();
+        assert_eq!(io.next_completed_request(), Some((2, 512)));
+    }
+
+    #[test]
+    fn test_fua_writes() {
+        let file = TempFile::new().unwrap();
+        let mut io = RawFileSync::new(
+            file.as_file().as_raw_fd(),
+            None,
+            None,
+            false,
+            CacheMode::Writeback,
+        )
+        .unwrap();
+        let mut buf = [0x11u8; 512];
+        let iovecs = [libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }];
+        io.write_vectored_fua(512, &iovecs, 1).unwrap();
+        assert_eq!(io.next_completed_request(), Some((1, 512)));
+        assert!(io.rwf_dsync);
+        let mut data = [0u8; 512];
+        file.as_file().read_exact_at(&mut data, 512).unwrap();
+        assert_eq!(data, buf);
+
+        // RWF_DSYNC has no effect on /dev/null, while syncing it fails,
+        // which tells whether the file was synced after the write.
+        let null = std::fs::OpenOptions::new()
+            .write(true)
+            .open("/dev/null")
+            .unwrap();
+        let mut io =
+            RawFileSync::new(null.as_raw_fd(), None, None, false, CacheMode::Writeback).unwrap();
+        io.write_vectored_fua(0, &iovecs, 2).unwrap();
+        assert_eq!(io.next_completed_request(), Some((2, 512)));
+        // Without RWF_DSYNC, the whole file is synced instead.
+        io.rwf_dsync = false;
+        io.write_vectored_fua(0, &iovecs, 3).unwrap();
+        assert_eq!(io.next_completed_request(), Some((3, -libc::EINVAL)));
+        // Unless FUA is ignored along with the flushes.
+        io.cache_mode = CacheMode::None;
+        io.write_vectored_fua(0, &iovecs, 4).unwrap();
+        assert_eq!(io.next_completed_request(), Some((4, 512)));
+    }
+
+    #[test]
+    fn test_direct_alignment_detection() {
+        let file = TempFile::new().unwrap();
+        let open_direct = || {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(libc::O_DIRECT)
+                .open(file.as_path())
+        };
+        // Whether O_DIRECT is supported depends on the filesystem holding the
+        // test file.
+        let Ok(direct) = open_direct() else {
+            return;
+        };
+
+        // The alignment is detected, or the disk refused, depending on
+        // what the kernel and the filesystem report.
+        let disk = RawFileDiskSync::new(direct, false, CacheMode::Writeback, None);
+        match DiskTopology::direct_io_alignment(&open_direct().unwrap()).unwrap() {
+            Some(alignment) => {
+                assert!(alignment.is_power_of_two());
+                assert_eq!(disk.unwrap().logical_block_size, Some(alignment));
+            }
+            None => assert!(matches!(disk, Err(DiskFileError::DirectIoAlignment(_)))),
+        }
+
+        // A configured block size is used as is.
+        let disk = RawFileDiskSync::new(
+            open_direct().unwrap(),
+            false,
+            CacheMode::Writeback,
+            Some(4096),
+        )
+        .unwrap();
+        assert_eq!(disk.logical_block_size, Some(4096));
+
+        // Without O_DIRECT, files can be accessed at any offset.
+        let disk = RawFileDiskSync::new(
+            file.as_file().try_clone().unwrap(),
+            false,
+            CacheMode::Writeback,
+            None,
+        )
+        .unwrap();
+        assert_eq!(disk.logical_block_size, None);
+    }
+
+    #[test]
+    fn test_io_retries() {
+        let file = TempFile::new().unwrap();
+        let mut io = RawFileSync::new(
+            file.as_file().as_raw_fd(),
+            None,
+            None,
+            false,
+            CacheMode::Writeback,
+        )
+        .unwrap();
+
+        // Fails with each of `errors` in turn, succeeding afterwards.
+        let syscall = |errors: Vec<i32>| {
+            let mut errors = errors.into_iter();
+            move || match errors.next() {
+                Some(errno) => {
+                    // SAFETY: errno is thread local
+                    unsafe { *libc::__errno_location() = errno };
+                    -1
+                }
+                None => 512,
+            }
+        };
+        let errno = |result: std::io::Result<usize>| result.unwrap_err().raw_os_error();
+
+        // Failing fast by default.
+        assert_eq!(
+            errno(io.retry(syscall(vec![libc::EAGAIN]))),
+            Some(libc::EAGAIN)
+        );
+
+        io.set_io_retries(3);
+        assert_eq!(
+            io.retry(syscall(vec![libc::EINTR, libc::EAGAIN, libc::EAGAIN]))
+                .unwrap(),
+            512
+        );
+        // Until the retries are exhausted.
+        assert_eq!(
+            errno(io.retry(syscall(vec![libc::EAGAIN; 4]))),
+            Some(libc::EAGAIN)
+        );
+        // Other errors are never retried.
+        assert_eq!(
+            errno(io.retry(syscall(vec![libc::EIO, libc::EAGAIN]))),
+            Some(libc::EIO)
+        );
     }
 }
@@ -0,0 +1,215 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Page cache hints for RAW disk files, either given once for the whole
+//! file or, for the readahead, issued ahead of the sequential reads of the
+//! guest as they are detected.
+
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// Number of contiguous reads after which the access is deemed sequential.
+const SEQUENTIAL_READS: u32 = 2;
+
+/// Access pattern advertised to the host page cache for a disk file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum FadviseMode {
+    /// No hint, the default readahead of the host applies.
+    #[default]
+    Normal,
+    /// POSIX_FADV_SEQUENTIAL, doubling the readahead window of the host.
+    Sequential,
+    /// POSIX_FADV_WILLNEED on the whole file, populating the page cache
+    /// in the background.
+    Willneed,
+    /// POSIX_FADV_DONTNEED on the range of each request once completed,
+    /// keeping jobs such as backups from polluting the page cache.
+    Dontneed,
+}
+
+impl FadviseMode {
+    /// Applies the hint given once for the whole file.
+    pub fn apply(&self, file: &File) -> io::Result<()> {
+        let advice = match self {
+            FadviseMode::Normal | FadviseMode::Dontneed => return Ok(()),
+            FadviseMode::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+            FadviseMode::Willneed => libc::POSIX_FADV_WILLNEED,
+        };
+        fadvise(file.as_raw_fd(), 0, 0, advice)
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseFadviseModeError {
+    InvalidValue(String),
+}
+
+impl FromStr for FadviseMode {
+    type Err = ParseFadviseModeError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "normal" => Ok(FadviseMode::Normal),
+            "sequential" => Ok(FadviseMode::Sequential),
+            "willneed" => Ok(FadviseMode::Willneed),
+            "dontneed" => Ok(FadviseMode::Dontneed),
+            _ => Err(ParseFadviseModeError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+/// Calls posix_fadvise() on the range, a length of 0 meaning up to the end
+/// of the file.
+pub fn fadvise(fd: RawFd, offset: u64, length: u64, advice: libc::c_int) -> io::Result<()> {
+    // SAFETY: FFI call with valid arguments
+    let ret =
+        unsafe { libc::posix_fadvise(fd, offset as libc::off_t, length as libc::off_t, advice) };
+    // posix_fadvise() returns the error rather than setting errno.
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+
+    Ok(())
+}
+
+/// Bytes read ahead of the queues of a disk, telling how well the window
+/// suits the workload of the guest.
+#[derive(Default)]
+pub struct ReadaheadStats {
+    /// Bytes hinted to the page cache.
+    pub prefetched_bytes: AtomicU64,
+    /// Bytes of the sequential reads found within the range read ahead.
+    pub hit_bytes: AtomicU64,
+    /// Bytes read ahead past the last read of a sequential run, once the
+    /// run stopped.
+    pub wasted_bytes: AtomicU64,
+}
+
+/// Detects the sequential reads of a queue, telling which range to read
+/// ahead so that `window` bytes following the last read are always being
+/// fetched. Any read not following the previous one resets the detection.
+pub struct Readahead {
+    window: u64,
+    stats: Arc<ReadaheadStats>,
+    // End of the previous read.
+    next_offset: u64,
+    // Number of contiguous reads so far.
+    contiguous: u32,
+    // End of the range already read ahead.
+    prefetched: u64,
+}
+
+impl Readahead {
+    pub fn new(window: u64, stats: Arc<ReadaheadStats>) -> Self {
+        Readahead {
+            window,
+            stats,
+            next_offset: 0,
+            contiguous: 0,
+            prefetched: 0,
+        }
+    }
+
+    /// Records a read, returning the range to read ahead if any.
+    pub fn read(&mut self, offset: u64, length: u64) -> Option<(u64, u64)> {
+        let end = offset + length;
+        if offset == self.next_offset && self.contiguous > 0 {
+            self.contiguous = self.contiguous.saturating_add(1);
+            // The range read ahead starts at the end of a read of the run,
+            // so whatever of the next ones lies below its end is within it.
+            let hit = end.min(self.prefetched).saturating_sub(offset);
+            self.stats.hit_bytes.fetch_add(hit, Ordering::Relaxed);
+        } else {
+            let wasted = self.prefetched.saturating_sub(self.next_offset);
+            self.stats.wasted_bytes.fetch_add(wasted, Ordering::Relaxed);
+            self.contiguous = 1;
+            self.prefetched = 0;
+        }
+        self.next_offset = end;
+
+        // Only extend the range once half of the window was consumed, not
+        // to issue a hint for every read.
+        if self.contiguous < SEQUENTIAL_READS
+            || self.prefetched >= self.next_offset + self.window / 2
+        {
+            return None;
+        }
+        let start = self.prefetched.max(self.next_offset);
+        let end = self.next_offset + self.window;
+        self.prefetched = end;
+        self.stats
+            .prefetched_bytes
+            .fetch_add(end - start, Ordering::Relaxed);
+
+        Some((start, end - start))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_readahead() {
+        let mut readahead = Readahead::new(1 << 20, Arc::default());
+
+        // The first read can't tell anything about the pattern.
+        assert_eq!(readahead.read(0, 4096), None);
+        assert_eq!(readahead.read(4096, 4096), Some((8192, 1 << 20)));
+        // Nothing more until half of the window was read.
+        assert_eq!(readahead.read(8192, 4096), None);
+        assert_eq!(readahead.read(12288, (1 << 19) - 12288), None);
+        assert_eq!(readahead.read(1 << 19, 4096), None);
+        assert_eq!(
+            readahead.read((1 << 19) + 4096, 8192),
+            Some((8192 + (1 << 20), (1 << 19) + 4096))
+        );
+
+        // A random read resets the detection.
+        assert_eq!(readahead.read(100 << 20, 4096), None);
+        assert_eq!(readahead.read(0, 4096), None);
+        assert_eq!(readahead.read(4096, 4096), Some((8192, 1 << 20)));
+    }
+
+    #[test]
+    fn test_readahead_stats() {
+        let stats = Arc::new(ReadaheadStats::default());
+        let mut readahead = Readahead::new(1 << 20, stats.clone());
+
+        assert_eq!(readahead.read(0, 4096), None);
+        assert_eq!(readahead.read(4096, 4096), Some((8192, 1 << 20)));
+        assert_eq!(readahead.read(8192, 1 << 19), None);
+        assert_eq!(
+            readahead.read(8192 + (1 << 19), 4096),
+            Some((8192 + (1 << 20), 4096 + (1 << 19)))
+        );
+        // The random read leaves the rest of the range unread.
+        assert_eq!(readahead.read(100 << 20, 4096), None);
+
+        assert_eq!(
+            stats.prefetched_bytes.load(Ordering::Relaxed),
+            (1 << 20) + (1 << 19) + 4096
+        );
+        assert_eq!(stats.hit_bytes.load(Ordering::Relaxed), (1 << 19) + 4096);
+        assert_eq!(stats.wasted_bytes.load(Ordering::Relaxed), 1 << 20);
+    }
+
+    #[test]
+    fn test_parse_fadvise_mode() {
+        assert_eq!(
+            "sequential".parse::<FadviseMode>().unwrap(),
+            FadviseMode::Sequential
+        );
+        assert_eq!(
+            "DontNeed".parse::<FadviseMode>().unwrap(),
+            FadviseMode::Dontneed
+        );
+        assert!("random".parse::<FadviseMode>().is_err());
+    }
+}
@@ -0,0 +1,360 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Background scan of a disk image, reading it sequentially while the guest
+//! is not using the disk. This warms the host page cache before the guest
+//! first touches the data and, given a manifest of checksums, detects the
+//! blocks whose content changed behind our back.
+
+use crc_any::CRCu32;
+use std::cmp;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use thiserror::Error;
+
+// How long to wait before checking again whether the guest is still busy.
+const YIELD_DELAY: Duration = Duration::from_millis(50);
+
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed opening the manifest: {0}")]
+    OpenManifest(#[source] io::Error),
+    #[error("Failed reading the manifest: {0}")]
+    ReadManifest(#[source] io::Error),
+    #[error("Invalid manifest block size: {0}")]
+    InvalidBlockSize(String),
+    #[error("Invalid checksum on line {0} of the manifest")]
+    InvalidChecksum(usize),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Expected CRC32C of every block of an image.
+///
+/// The manifest is a text file whose first line is `block_size=<bytes>`,
+/// followed by one line per block holding its checksum as hexadecimal. The
+/// last block of the image may be shorter than the block size.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ScrubManifest {
+    block_size: u64,
+    checksums: Vec<u32>,
+}
+
+impl ScrubManifest {
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path).map_err(Error::OpenManifest)?;
+        Self::parse(BufReader::new(file))
+    }
+
+    fn parse<R: BufRead>(reader: R) -> Result<Self> {
+        let mut lines = reader.lines();
+
+        let first = lines
+            .next()
+            .transpose()
+            .map_err(Error::ReadManifest)?
+            .unwrap_or_default();
+        let block_size = first
+            .strip_prefix("block_size=")
+            .and_then(|size| size.trim().parse::<u64>().ok())
+            .filter(|size| *size > 0)
+            .ok_or_else(|| Error::InvalidBlockSize(first.clone()))?;
+
+        let mut checksums = Vec::new();
+        for (i, line) in lines.enumerate() {
+            let line = line.map_err(Error::ReadManifest)?;
+            // Lines are numbered from 1, the block size being on the first.
+            let checksum =
+                u32::from_str_radix(line.trim(), 16).map_err(|_| Error::InvalidChecksum(i + 2))?;
+            checksums.push(checksum);
+        }
+
+        Ok(ScrubManifest {
+            block_size,
+            checksums,
+        })
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+}
+
+/// Returns the CRC32C of `data`, as stored in a [`ScrubManifest`].
+pub fn checksum(data: &[u8]) -> u32 {
+    let mut crc = CRCu32::crc32c();
+    crc.digest(data);
+    crc.get_crc()
+}
+
+struct Progress {
+    total_bytes: u64,
+    scanned_bytes: AtomicU64,
+    mismatches: AtomicU64,
+}
+
+pub struct Scrubber {
+    progress: Arc<Progress>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Scrubber {
+    /// Starts reading the first `size` bytes of `file` from a new thread, by
+    /// chunks of `chunk_size` bytes, or of the manifest block size when
+    /// verifying the content against `manifest`.
+    ///
+    /// `activity` must return a value changing whenever the guest uses the
+    /// disk, the scan waiting for it to settle before reading any chunk.
+    pub fn start<F>(
+        name: String,
+        file: File,
+        size: u64,
+        chunk_size: u64,
+        manifest: Option<ScrubManifest>,
+        activity: F,
+    ) -> io::Result<Self>
+    where
+        F: Fn() -> u64 + Send + 'static,
+    {
+        let progress = Arc::new(Progress {
+            total_bytes: size,
+            scanned_bytes: AtomicU64::new(0),
+            mismatches: AtomicU64::new(0),
+        });
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let progress = progress.clone();
+            let stop = stop.clone();
+            let chunk_size = manifest.as_ref().map_or(chunk_size, |m| m.block_size);
+            thread::Builder::new()
+                .name(name)
+                .spawn(move || scrub(&file, chunk_size, manifest, &progress, &stop, activity))?
+        };
+
+        Ok(Scrubber {
+            progress,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.progress.total_bytes
+    }
+
+    /// Number of bytes read so far.
+    pub fn scanned_bytes(&self) -> u64 {
+        self.progress.scanned_bytes.load(Ordering::Acquire)
+    }
+
+    /// Number of blocks whose checksum didn't match the manifest.
+    pub fn mismatches(&self) -> u64 {
+        self.progress.mismatches.load(Ordering::Acquire)
+    }
+
+    /// Whether the whole image has been read.
+    pub fn is_done(&self) -> bool {
+        self.scanned_bytes() >= self.total_bytes()
+    }
+}
+
+impl Drop for Scrubber {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Disk scrubber thread panicked");
+            }
+        }
+    }
+}
+
+// Only let the scan reach the storage when nothing else is using it.
+fn set_idle_io_priority() {
+    // SAFETY: FFI call with valid arguments, 0 meaning the calling thread
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        )
+    };
+    if ret < 0 {
+        warn!(
+            "Failed lowering the I/O priority of the disk scrubber: {}",
+            io::Error::last_os_error()
+        );
+    }
+}
+
+fn scrub<F: Fn() -> u64>(
+    file: &File,
+    chunk_size: u64,
+    manifest: Option<ScrubManifest>,
+    progress: &Progress,
+    stop: &AtomicBool,
+    activity: F,
+) {
+    set_idle_io_priority();
+
+    let mut buf = vec![0u8; chunk_size as usize];
+    let mut last_activity = activity();
+    let mut offset = 0;
+    while offset < progress.total_bytes && !stop.load(Ordering::Acquire) {
+        // Back off as long as the guest keeps doing I/O.
+        let current_activity = activity();
+        if current_activity != last_activity {
+            last_activity = current_activity;
+            thread::sleep(YIELD_DELAY);
+            continue;
+        }
+
+        let len = cmp::min(chunk_size, progress.total_bytes - offset) as usize;
+        if let Err(e) = file.read_exact_at(&mut buf[..len], offset) {
+            warn!("Disk scrubbing stopped at offset {:#x}: {}", offset, e);
+            return;
+        }
+
+        if let Some(manifest) = manifest.as_ref() {
+            let block = (offset / chunk_size) as usize;
+            if let Some(expected) = manifest.checksums.get(block) {
+                let actual = checksum(&buf[..len]);
+                if actual != *expected {
+                    error!(
+                        "Disk block at offset {:#x} doesn't match the manifest: \
+                         checksum {:08x}, expected {:08x}",
+                        offset, actual, expected
+                    );
+                    progress.mismatches.fetch_add(1, Ordering::AcqRel);
+                }
+            }
+        }
+
+        offset += len as u64;
+        progress.scanned_bytes.store(offset, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use vmm_sys_util::tempfile::TempFile;
+
+    fn wait_done(scrubber: &Scrubber) {
+        for _ in 0..500 {
+            if scrubber.is_done() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("Scrubbing didn't complete");
+    }
+
+    #[test]
+    fn test_manifest_parsing() {
+        let manifest =
+            ScrubManifest::parse("block_size=4096\n0000abcd\nffffffff\n".as_bytes()).unwrap();
+        assert_eq!(
+            manifest,
+            ScrubManifest {
+                block_size: 4096,
+                checksums: vec![0xabcd, 0xffff_ffff],
+            }
+        );
+
+        assert!(matches!(
+            ScrubManifest::parse("block_size=0\n".as_bytes()),
+            Err(Error::InvalidBlockSize(_))
+        ));
+        assert!(matches!(
+            ScrubManifest::parse("0000abcd\n".as_bytes()),
+            Err(Error::InvalidBlockSize(_))
+        ));
+        assert!(matches!(
+            ScrubManifest::parse("block_size=512\n0000abcd\nbogus\n".as_bytes()),
+            Err(Error::InvalidChecksum(3))
+        ));
+    }
+
+    #[test]
+    fn test_scrub_verify() {
+        let file = TempFile::new().unwrap();
+        let data: Vec<u8> = (0..10000).map(|i| (i % 251) as u8).collect();
+        file.as_file().write_all(&data).unwrap();
+
+        // The last block is short, and the third one is corrupted.
+        let mut checksums: Vec<u32> = data.chunks(4096).map(checksum).collect();
+        checksums[2] ^= 1;
+        let manifest = ScrubManifest {
+            block_size: 4096,
+            checksums,
+        };
+
+        let scrubber = Scrubber::start(
+            "scrub_test".to_owned(),
+            file.as_file().try_clone().unwrap(),
+            data.len() as u64,
+            512,
+            Some(manifest),
+            || 0,
+        )
+        .unwrap();
+        wait_done(&scrubber);
+        assert_eq!(scrubber.scanned_bytes(), data.len() as u64);
+        assert_eq!(scrubber.mismatches(), 1);
+    }
+
+    #[test]
+    fn test_scrub_yield() {
+        let file = TempFile::new().unwrap();
+        file.as_file().set_len(1 << 20).unwrap();
+
+        // The scan doesn't make progress while the guest is busy.
+        let activity = Arc::new(AtomicU64::new(0));
+        let scrubber = {
+            let activity = activity.clone();
+            Scrubber::start(
+                "scrub_test".to_owned(),
+                file.as_file().try_clone().unwrap(),
+                1 << 20,
+                4096,
+                None,
+                move || activity.fetch_add(1, Ordering::AcqRel),
+            )
+            .unwrap()
+        };
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(scrubber.scanned_bytes(), 0);
+        assert!(!scrubber.is_done());
+
+        // Dropping the scrubber stops the scan.
+        drop(scrubber);
+
+        let scrubber = Scrubber::start(
+            "scrub_test".to_owned(),
+            file.as_file().try_clone().unwrap(),
+            1 << 20,
+            4096,
+            None,
+            || 0,
+        )
+        .unwrap();
+        wait_done(&scrubber);
+        assert_eq!(scrubber.mismatches(), 0);
+    }
+}
@@ -0,0 +1,193 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! SCSI pass-through to the host device backing a disk, relying on the
+//! SG_IO ioctl of the Linux SCSI generic driver, which is served both by the
+//! `/dev/sg*` character devices and by the SCSI disks at `/dev/sd*`.
+
+use libc::ioctl;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+// See include/scsi/sg.h in the kernel code.
+const SG_GET_VERSION_NUM: libc::c_ulong = 0x2282;
+const SG_IO: libc::c_ulong = 0x2285;
+
+const SG_DXFER_NONE: i32 = -1;
+const SG_DXFER_TO_DEV: i32 = -2;
+const SG_DXFER_FROM_DEV: i32 = -3;
+
+/// Largest command descriptor block accepted by SG_IO.
+pub const SCSI_MAX_CDB_SIZE: usize = 255;
+/// Size of the buffer the sense data of a failed command is returned into.
+pub const SCSI_SENSE_BUFFER_SIZE: usize = 96;
+
+// Header of the SG_IO argument, as defined by `struct sg_io_hdr`.
+#[repr(C)]
+struct SgIoHdr {
+    interface_id: i32,
+    dxfer_direction: i32,
+    cmd_len: u8,
+    mx_sb_len: u8,
+    iovec_count: u16,
+    dxfer_len: u32,
+    dxferp: *mut libc::c_void,
+    cmdp: *const u8,
+    sbp: *mut u8,
+    timeout: u32,
+    flags: u32,
+    pack_id: i32,
+    usr_ptr: *mut libc::c_void,
+    status: u8,
+    masked_status: u8,
+    msg_status: u8,
+    sb_len_wr: u8,
+    host_status: u16,
+    driver_status: u16,
+    resid: i32,
+    duration: u32,
+    info: u32,
+}
+
+/// Direction of the data transferred by a SCSI command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScsiDataDirection {
+    None,
+    ToDevice,
+    FromDevice,
+}
+
+/// SCSI command forwarded to the host device.
+pub struct ScsiCommand<'a> {
+    /// Command descriptor block.
+    pub cdb: &'a [u8],
+    pub direction: ScsiDataDirection,
+    /// Buffers the data is transferred from or to, which must stay valid
+    /// until the command completes.
+    pub iovecs: &'a [libc::iovec],
+    /// How long the host waits for the device before aborting the command.
+    pub timeout: Duration,
+}
+
+/// Outcome of a SCSI command completed by the host device.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScsiResponse {
+    /// SCSI status returned by the device.
+    pub status: u8,
+    /// Status of the host adapter, non-zero if the command couldn't be
+    /// delivered to the device.
+    pub host_status: u16,
+    /// Status of the host driver.
+    pub driver_status: u16,
+    /// Sense data returned along with a failure.
+    pub sense: Vec<u8>,
+    /// Number of bytes which haven't been transferred.
+    pub residual: u32,
+}
+
+/// Returns whether `fd` refers to a device taking SCSI commands through
+/// SG_IO.
+pub fn is_scsi_device(fd: RawFd) -> bool {
+    let mut version: i32 = 0;
+    // SAFETY: FFI call with a valid pointer to an int
+    let ret = unsafe { ioctl(fd, SG_GET_VERSION_NUM as _, &mut version) };
+    ret == 0
+}
+
+/// Sends `command` to the device behind `fd`, returning once it has
+/// completed. The failures reported by the device are part of the response,
+/// only the command failing to be sent is an error.
+pub fn send_command(fd: RawFd, command: &ScsiCommand) -> io::Result<ScsiResponse> {
+    if command.cdb.is_empty() || command.cdb.len() > SCSI_MAX_CDB_SIZE {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+    let dxfer_direction = match command.direction {
+        _ if command.iovecs.is_empty() => SG_DXFER_NONE,
+        ScsiDataDirection::None => SG_DXFER_NONE,
+        ScsiDataDirection::ToDevice => SG_DXFER_TO_DEV,
+        ScsiDataDirection::FromDevice => SG_DXFER_FROM_DEV,
+    };
+    let dxfer_len: usize = command.iovecs.iter().map(|iovec| iovec.iov_len).sum();
+    let iovec_count = u16::try_from(command.iovecs.len())
+        .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+    let dxfer_len =
+        u32::try_from(dxfer_len).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+
+    let mut sense = vec![0u8; SCSI_SENSE_BUFFER_SIZE];
+    let mut hdr = SgIoHdr {
+        interface_id: 'S' as i32,
+        dxfer_direction,
+        cmd_len: command.cdb.len() as u8,
+        mx_sb_len: sense.len() as u8,
+        iovec_count,
+        dxfer_len,
+        dxferp: command.iovecs.as_ptr() as *mut libc::c_void,
+        cmdp: command.cdb.as_ptr(),
+        sbp: sense.as_mut_ptr(),
+        timeout: u32::try_from(command.timeout.as_millis()).unwrap_or(u32::MAX),
+        flags: 0,
+        pack_id: 0,
+        usr_ptr: std::ptr::null_mut(),
+        status: 0,
+        masked_status: 0,
+        msg_status: 0,
+        sb_len_wr: 0,
+        host_status: 0,
+        driver_status: 0,
+        resid: 0,
+        duration: 0,
+        info: 0,
+    };
+    if dxfer_direction == SG_DXFER_NONE {
+        hdr.iovec_count = 0;
+        hdr.dxfer_len = 0;
+        hdr.dxferp = std::ptr::null_mut();
+    }
+
+    // SAFETY: FFI call with a valid header, pointing to the command, the
+    // sense buffer and the iovecs, all outliving the call.
+    let ret = unsafe { ioctl(fd, SG_IO as _, &mut hdr) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    sense.truncate(hdr.sb_len_wr as usize);
+    Ok(ScsiResponse {
+        status: hdr.status,
+        host_status: hdr.host_status,
+        driver_status: hdr.driver_status,
+        sense,
+        residual: hdr.resid.max(0) as u32,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_send_command_on_file() {
+        let file = TempFile::new().unwrap();
+        let fd = file.as_file().as_raw_fd();
+        assert!(!is_scsi_device(fd));
+
+        // TEST UNIT READY
+        let cdb = [0u8; 6];
+        let mut command = ScsiCommand {
+            cdb: &cdb,
+            direction: ScsiDataDirection::None,
+            iovecs: &[],
+            timeout: Duration::from_secs(1),
+        };
+        let err = send_command(fd, &command).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTTY));
+
+        command.cdb = &[];
+        let err = send_command(fd, &command).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    }
+}
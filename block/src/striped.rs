@@ -0,0 +1,767 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! RAID0 stripe of several [`DiskFile`]s of the same size.
+//!
+//! The disk is divided in chunks of the stripe size, distributed in turn to
+//! each child. A request is split in one segment per child it covers, made of
+//! the chunks of that child, which are contiguous on the child. The request
+//! completes once every segment did.
+
+use crate::async_io::{
+    check_completion_room, completion_room, AsyncIo, AsyncIoError, AsyncIoResult, DiskFile,
+    DiskFileError, DiskFileResult, IoAlignment,
+};
+use crate::overlay::sub_iovecs;
+use crate::{AsyncIoBackend, DiskTopology};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::thread;
+use vmm_sys_util::eventfd::EventFd;
+
+pub struct StripedDiskFile {
+    children: Vec<Box<dyn DiskFile>>,
+    stripe_size: u64,
+    size: u64,
+    topology: DiskTopology,
+}
+
+impl StripedDiskFile {
+    /// Creates a stripe of `children`, which must all have the same size,
+    /// a multiple of `stripe_size`.
+    pub fn new(mut children: Vec<Box<dyn DiskFile>>, stripe_size: u64) -> DiskFileResult<Self> {
+        if children.is_empty() {
+            return Err(DiskFileError::NoStripeChildren);
+        }
+
+        let child_size = children[0].size()?;
+        for child in children.iter_mut().skip(1) {
+            let size = child.size()?;
+            if size != child_size {
+                return Err(DiskFileError::StripeChildSizeMismatch(child_size, size));
+            }
+        }
+
+        let topologies: Vec<DiskTopology> =
+            children.iter_mut().map(|child| child.topology()).collect();
+        let logical_block_size = topologies
+            .iter()
+            .map(|topology| topology.logical_block_size)
+            .max()
+            .unwrap();
+        if stripe_size == 0
+            || stripe_size % logical_block_size != 0
+            || child_size % stripe_size != 0
+        {
+            return Err(DiskFileError::InvalidStripeSize(stripe_size));
+        }
+
+        let count = children.len() as u64;
+        Ok(StripedDiskFile {
+            topology: stripe_topology(&topologies, stripe_size),
+            children,
+            stripe_size,
+            size: child_size * count,
+        })
+    }
+}
+
+// Topology of the stripe, keeping the segments sent to each child within its
+// limits: a child segment only has the buffers of the request, split at the
+// boundaries of the chunks of the child.
+fn stripe_topology(topologies: &[DiskTopology], stripe_size: u64) -> DiskTopology {
+    let count = topologies.len() as u64;
+    let max_segments = topologies
+        .iter()
+        .map(|topology| topology.max_segments)
+        .min()
+        .unwrap();
+    // Half of the buffers of a segment come from the request, the other half
+    // is left for the splits, one per chunk.
+    let max_request_segments = (max_segments / 2).max(1);
+    let max_chunks = u64::from(max_request_segments.saturating_sub(1).max(1));
+    let max_transfer_size = topologies
+        .iter()
+        .map(|topology| topology.max_transfer_size)
+        .filter(|size| *size != 0)
+        .chain(std::iter::once(max_chunks * stripe_size * count))
+        .min()
+        .unwrap();
+
+    DiskTopology {
+        logical_block_size: topologies
+            .iter()
+            .map(|topology| topology.logical_block_size)
+            .max()
+            .unwrap(),
+        physical_block_size: topologies
+            .iter()
+            .map(|topology| topology.physical_block_size)
+            .max()
+            .unwrap(),
+        minimum_io_size: stripe_size,
+        optimal_io_size: stripe_size * count,
+        max_transfer_size,
+        max_segments: max_request_segments,
+        // Each child gets at most the whole range of a request.
+        max_discard_size: topologies
+            .iter()
+            .map(|topology| topology.max_discard_size)
+            .min()
+            .unwrap(),
+        max_write_zeroes_size: topologies
+            .iter()
+            .map(|topology| topology.max_write_zeroes_size)
+            .min()
+            .unwrap(),
+        ..Default::default()
+    }
+}
+
+impl DiskFile for StripedDiskFile {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        Ok(self.size)
+    }
+
+    fn new_async_io(&self, ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        let children = self
+            .children
+            .iter()
+            .map(|child| child.new_async_io(ring_depth))
+            .collect::<DiskFileResult<Vec<Box<dyn AsyncIo>>>>()?;
+
+        Ok(Box::new(StripedAsyncIo {
+            io: ChildrenIo::new(children).map_err(DiskFileError::NewAsyncIo)?,
+            stripe_size: self.stripe_size,
+        }) as Box<dyn AsyncIo>)
+    }
+
+    fn topology(&mut self) -> DiskTopology {
+        self.topology.clone()
+    }
+
+    fn supports_discard(&self) -> bool {
+        self.children.iter().all(|child| child.supports_discard())
+    }
+
+    fn backend(&self) -> AsyncIoBackend {
+        // The children are usually opened alike.
+        self.children[0].backend()
+    }
+
+    fn supports_write_zeroes(&self) -> bool {
+        self.children
+            .iter()
+            .all(|child| child.supports_write_zeroes())
+    }
+
+    fn discard_zeroes(&self) -> bool {
+        self.children.iter().all(|child| child.discard_zeroes())
+    }
+
+    fn resize(&mut self, current_size: u64) -> DiskFileResult<u64> {
+        // The chunks are laid out after the size of the children, which
+        // can't change without moving the data around.
+        if self.size < current_size {
+            return Err(DiskFileError::Shrunk(current_size, self.size));
+        }
+
+        Ok(self.size)
+    }
+}
+
+// Part of a request served by a child.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct ChildSegment {
+    pub(crate) child: usize,
+    // Offset on the child.
+    pub(crate) offset: u64,
+    pub(crate) length: u64,
+    // Ranges of the request, as position and length, laid out contiguously
+    // on the child.
+    pub(crate) pieces: Vec<(u64, u64)>,
+}
+
+pub(crate) fn iovecs_len(iovecs: &[libc::iovec]) -> u64 {
+    iovecs.iter().map(|iovec| iovec.iov_len as u64).sum()
+}
+
+// Splits the `length` bytes at `offset` into one segment per child.
+fn split(stripe_size: u64, count: usize, offset: u64, length: u64) -> Vec<ChildSegment> {
+    let mut segments: Vec<ChildSegment> = Vec::new();
+    let mut position = 0;
+
+    while position < length {
+        let chunk = (offset + position) / stripe_size;
+        let within = (offset + position) % stripe_size;
+        let piece = (stripe_size - within).min(length - position);
+        let child = (chunk % count as u64) as usize;
+
+        // The next chunk of a child always follows its previous one.
+        match segments.iter_mut().find(|segment| segment.child == child) {
+            Some(segment) => {
+                segment.length += piece;
+                segment.pieces.push((position, piece));
+            }
+            None => segments.push(ChildSegment {
+                child,
+                offset: chunk / count as u64 * stripe_size + within,
+                length: piece,
+                pieces: vec![(position, piece)],
+            }),
+        }
+        position += piece;
+    }
+
+    segments
+}
+
+// Forwards the completion events of the children to the single notifier the
+// consumer waits on, from a dedicated thread.
+struct NotifierRelay {
+    notifier: EventFd,
+    stop_evt: EventFd,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl NotifierRelay {
+    fn new(children: &[Box<dyn AsyncIo>]) -> io::Result<Self> {
+        let notifier = EventFd::new(libc::EFD_NONBLOCK)?;
+        let stop_evt = EventFd::new(libc::EFD_NONBLOCK)?;
+        let child_notifiers = children
+            .iter()
+            .map(|child| child.notifier().try_clone())
+            .collect::<io::Result<Vec<EventFd>>>()?;
+        let relay_notifier = notifier.try_clone()?;
+        let relay_stop_evt = stop_evt.try_clone()?;
+
+        let thread = thread::Builder::new()
+            .name("disk_relay".to_string())
+            .spawn(move || relay(&child_notifiers, &relay_notifier, &relay_stop_evt))?;
+
+        Ok(NotifierRelay {
+            notifier,
+            stop_evt,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for NotifierRelay {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop_evt.write(1) {
+            error!("Failed to stop the disk children notifier relay: {}", e);
+            return;
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn relay(children: &[EventFd], notifier: &EventFd, stop_evt: &EventFd) {
+    let mut pollfds: Vec<libc::pollfd> = children
+        .iter()
+        .chain(std::iter::once(stop_evt))
+        .map(|evt| libc::pollfd {
+            fd: evt.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+
+    loop {
+        // SAFETY: FFI call with valid pollfds
+        let ret = unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, -1) };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            error!("Failed to poll the disk children: {}", e);
+            return;
+        }
+        if pollfds[children.len()].revents != 0 {
+            return;
+        }
+
+        for (child, pollfd) in children.iter().zip(pollfds.iter()) {
+            if pollfd.revents & libc::POLLIN == 0 {
+                continue;
+            }
+            // The event is consumed before being forwarded, so that the
+            // completions signaled afterwards are forwarded again.
+            let _ = child.read();
+            if let Err(e) = notifier.write(1) {
+                error!("Failed to notify the disk children completions: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+// Request waiting for the completion of its segments.
+struct Pending {
+    remaining: usize,
+    result: i32,
+}
+
+impl Pending {
+    // Accounts for the completion of a segment, the request completing with
+    // the number of bytes of every segment or the first error.
+    fn complete(&mut self, result: i32) {
+        self.remaining -= 1;
+        if self.result >= 0 {
+            self.result = if result < 0 {
+                result
+            } else {
+                self.result + result
+            };
+        }
+    }
+}
+
+// Backends of the children of a disk made of several ones, serving each
+// request through the segments sent to the children it covers.
+pub(crate) struct ChildrenIo {
+    children: Vec<Box<dyn AsyncIo>>,
+    relay: NotifierRelay,
+    pending: HashMap<u64, Pending>,
+    // Requests completed without being sent to any child.
+    completion_list: VecDeque<(u64, i32)>,
+}
+
+impl ChildrenIo {
+    pub(crate) fn new(children: Vec<Box<dyn AsyncIo>>) -> io::Result<Self> {
+        Ok(ChildrenIo {
+            relay: NotifierRelay::new(&children)?,
+            children,
+            pending: HashMap::new(),
+            completion_list: VecDeque::new(),
+        })
+    }
+
+    pub(crate) fn count(&self) -> usize {
+        self.children.len()
+    }
+
+    // Sends each segment to its child, the segments of a request having the
+    // user data of the request.
+    pub(crate) fn submit_segments<F>(
+        &mut self,
+        segments: &[ChildSegment],
+        user_data: u64,
+        mut submit: F,
+    ) -> AsyncIoResult<()>
+    where
+        F: FnMut(&mut dyn AsyncIo, &ChildSegment) -> AsyncIoResult<()>,
+    {
+        if segments.is_empty() {
+            check_completion_room(self.completion_list.len())?;
+            self.completion_list.push_back((user_data, 0));
+            self.relay.notifier.write(1).unwrap();
+            return Ok(());
+        }
+
+        // The request is refused as a whole, as the segments already
+        // submitted couldn't be withdrawn.
+        if segments
+            .iter()
+            .any(|segment| self.children[segment.child].submission_room() == 0)
+        {
+            return Err(AsyncIoError::CompletionsFull);
+        }
+
+        let mut pending = Pending {
+            remaining: 0,
+            result: 0,
+        };
+        for segment in segments {
+            if let Err(e) = submit(self.children[segment.child].as_mut(), segment) {
+                if pending.remaining == 0 {
+                    return Err(e);
+                }
+                // The segments already submitted can't be withdrawn, the
+                // request fails once they complete.
+                error!("Failed to submit a disk segment: {}", e);
+                pending.result = -libc::EIO;
+                break;
+            }
+            pending.remaining += 1;
+        }
+        self.pending.insert(user_data, pending);
+
+        Ok(())
+    }
+
+    // Sends the buffers of the request covered by each segment to its child.
+    pub(crate) fn submit_vectored<F>(
+        &mut self,
+        segments: &[ChildSegment],
+        iovecs: &[libc::iovec],
+        user_data: u64,
+        mut submit: F,
+    ) -> AsyncIoResult<()>
+    where
+        F: FnMut(&mut dyn AsyncIo, libc::off_t, &[libc::iovec]) -> AsyncIoResult<()>,
+    {
+        self.submit_segments(segments, user_data, |child, segment| {
+            let segment_iovecs: Vec<libc::iovec> = segment
+                .pieces
+                .iter()
+                .flat_map(|(position, length)| {
+                    sub_iovecs(iovecs, *position as usize, *length as usize)
+                })
+                .collect();
+            submit(child, segment.offset as libc::off_t, &segment_iovecs)
+        })
+    }
+
+    // Flushes every child, the request completing once all of them did.
+    pub(crate) fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        let Some(user_data) = user_data else {
+            for child in self.children.iter_mut() {
+                child.fsync(None)?;
+            }
+            return Ok(());
+        };
+
+        let segments: Vec<ChildSegment> = (0..self.children.len())
+            .map(|child| ChildSegment {
+                child,
+                offset: 0,
+                length: 0,
+                pieces: Vec::new(),
+            })
+            .collect();
+        self.submit_segments(&segments, user_data, |child, _| {
+            child.fsync(Some(user_data))
+        })
+    }
+
+    pub(crate) fn notifier(&self) -> &EventFd {
+        &self.relay.notifier
+    }
+
+    pub(crate) fn required_alignment(&self) -> IoAlignment {
+        self.children
+            .iter()
+            .fold(IoAlignment::NONE, |alignment, child| {
+                alignment.max(child.required_alignment())
+            })
+    }
+
+    // A request sends at most one segment to each child.
+    pub(crate) fn submission_room(&self) -> usize {
+        self.children
+            .iter()
+            .map(|child| child.submission_room())
+            .fold(completion_room(self.completion_list.len()), usize::min)
+    }
+
+    pub(crate) fn register_buffers(&mut self, regions: &[libc::iovec]) -> AsyncIoResult<bool> {
+        let mut registered = false;
+        for child in self.children.iter_mut() {
+            registered |= child.register_buffers(regions)?;
+        }
+
+        Ok(registered)
+    }
+
+    pub(crate) fn submit(&mut self) -> AsyncIoResult<()> {
+        for child in self.children.iter_mut() {
+            child.submit()?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn next_completed_request(&mut self) -> Option<(u64, i32)> {
+        if let Some(completion) = self.completion_list.pop_front() {
+            return Some(completion);
+        }
+
+        for child in self.children.iter_mut() {
+            while let Some((user_data, result)) = child.next_completed_request() {
+                let Some(pending) = self.pending.get_mut(&user_data) else {
+                    warn!("Unexpected disk segment completion: {}", user_data);
+                    continue;
+                };
+                pending.complete(result);
+                if pending.remaining == 0 {
+                    let result = pending.result;
+                    self.pending.remove(&user_data);
+                    return Some((user_data, result));
+                }
+            }
+        }
+
+        None
+    }
+}
+
+pub struct StripedAsyncIo {
+    io: ChildrenIo,
+    stripe_size: u64,
+}
+
+// SAFETY: the iovecs point to the guest memory of in flight requests, which
+// stays valid until the requests complete.
+unsafe impl Send for StripedAsyncIo {}
+
+impl StripedAsyncIo {
+    fn split(&self, offset: libc::off_t, length: u64) -> Vec<ChildSegment> {
+        split(self.stripe_size, self.io.count(), offset as u64, length)
+    }
+}
+
+impl AsyncIo for StripedAsyncIo {
+    fn notifier(&self) -> &EventFd {
+        self.io.notifier()
+    }
+
+    fn required_alignment(&self) -> IoAlignment {
+        self.io.required_alignment()
+    }
+
+    fn submission_room(&self) -> usize {
+        self.io.submission_room()
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let segments = self.split(offset, iovecs_len(iovecs));
+        self.io
+            .submit_vectored(&segments, iovecs, user_data, |child, offset, iovecs| {
+                child.read_vectored(offset, iovecs, user_data)
+            })
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let segments = self.split(offset, iovecs_len(iovecs));
+        self.io
+            .submit_vectored(&segments, iovecs, user_data, |child, offset, iovecs| {
+                child.write_vectored(offset, iovecs, user_data)
+            })
+    }
+
+    fn write_vectored_fua(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let segments = self.split(offset, iovecs_len(iovecs));
+        self.io
+            .submit_vectored(&segments, iovecs, user_data, |child, offset, iovecs| {
+                child.write_vectored_fua(offset, iovecs, user_data)
+            })
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        self.io.fsync(user_data)
+    }
+
+    fn register_buffers(&mut self, regions: &[libc::iovec]) -> AsyncIoResult<bool> {
+        self.io.register_buffers(regions)
+    }
+
+    fn discard(&mut self, offset: libc::off_t, length: u64, user_data: u64) -> AsyncIoResult<()> {
+        let segments = self.split(offset, length);
+        self.io
+            .submit_segments(&segments, user_data, |child, segment| {
+                child.discard(segment.offset as libc::off_t, segment.length, user_data)
+            })
+    }
+
+    fn write_zeroes(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        unmap: bool,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let segments = self.split(offset, length);
+        self.io
+            .submit_segments(&segments, user_data, |child, segment| {
+                child.write_zeroes(
+                    segment.offset as libc::off_t,
+                    segment.length,
+                    unmap,
+                    user_data,
+                )
+            })
+    }
+
+    fn submit(&mut self) -> AsyncIoResult<()> {
+        self.io.submit()
+    }
+
+    fn next_completed_request(&mut self) -> Option<(u64, i32)> {
+        self.io.next_completed_request()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_io::wait_completion;
+    use crate::raw_sync::RawFileDiskSync;
+    use crate::CacheMode;
+    use std::os::unix::fs::FileExt;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const STRIPE_SIZE: u64 = 4096;
+
+    fn child_disk(size: u64) -> (TempFile, Box<dyn DiskFile>) {
+        let file = TempFile::new().unwrap();
+        file.as_file().set_len(size).unwrap();
+        let disk = Box::new(
+            RawFileDiskSync::new(
+                file.as_file().try_clone().unwrap(),
+                false,
+                CacheMode::Writeback,
+                None,
+            )
+            .unwrap(),
+        );
+        (file, disk)
+    }
+
+    fn iovec(buf: &mut [u8]) -> libc::iovec {
+        libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }
+    }
+
+    #[test]
+    fn test_split() {
+        // Three stripes from the middle of the first child chunk of the
+        // second row.
+        let segments = split(STRIPE_SIZE, 2, 2 * STRIPE_SIZE + 512, 3 * STRIPE_SIZE);
+        assert_eq!(
+            segments,
+            vec![
+                ChildSegment {
+                    child: 0,
+                    offset: STRIPE_SIZE + 512,
+                    length: 2 * STRIPE_SIZE - 512,
+                    pieces: vec![(0, STRIPE_SIZE - 512), (2 * STRIPE_SIZE - 512, STRIPE_SIZE)],
+                },
+                ChildSegment {
+                    child: 1,
+                    offset: STRIPE_SIZE,
+                    length: STRIPE_SIZE + 512,
+                    pieces: vec![
+                        (STRIPE_SIZE - 512, STRIPE_SIZE),
+                        (3 * STRIPE_SIZE - 512, 512)
+                    ],
+                },
+            ]
+        );
+
+        assert!(split(STRIPE_SIZE, 2, 0, 0).is_empty());
+    }
+
+    #[test]
+    fn test_stripe_topology() {
+        let topologies = [
+            DiskTopology::default(),
+            DiskTopology {
+                max_discard_size: 1 << 20,
+                ..Default::default()
+            },
+        ];
+        let topology = stripe_topology(&topologies, STRIPE_SIZE);
+        assert_eq!(topology.minimum_io_size, STRIPE_SIZE);
+        assert_eq!(topology.optimal_io_size, 2 * STRIPE_SIZE);
+        // The smallest limit of the children applies to the stripe.
+        assert_eq!(topology.max_discard_size, 1 << 20);
+        assert_eq!(
+            topology.max_write_zeroes_size,
+            DiskTopology::default().max_write_zeroes_size
+        );
+    }
+
+    #[test]
+    fn test_striped_read_write() {
+        let (file0, child0) = child_disk(4 * STRIPE_SIZE);
+        let (file1, child1) = child_disk(4 * STRIPE_SIZE);
+        let mut disk = StripedDiskFile::new(vec![child0, child1], STRIPE_SIZE).unwrap();
+        assert_eq!(disk.size().unwrap(), 8 * STRIPE_SIZE);
+        let mut io = disk.new_async_io(1).unwrap();
+
+        // The write spans the two children, with a buffer crossing a stripe
+        // boundary.
+        let mut head = vec![1u8; STRIPE_SIZE as usize];
+        let mut tail = vec![2u8; STRIPE_SIZE as usize];
+        io.write_vectored(
+            (STRIPE_SIZE / 2) as libc::off_t,
+            &[iovec(&mut head), iovec(&mut tail)],
+            1,
+        )
+        .unwrap();
+        io.submit().unwrap();
+        assert_eq!(wait_completion(io.as_mut()), (1, 2 * STRIPE_SIZE as i32));
+        assert_eq!(io.next_completed_request(), None);
+
+        let mut chunk = vec![0u8; STRIPE_SIZE as usize];
+        file0.as_file().read_exact_at(&mut chunk, 0).unwrap();
+        assert!(chunk[..STRIPE_SIZE as usize / 2].iter().all(|b| *b == 0));
+        assert!(chunk[STRIPE_SIZE as usize / 2..].iter().all(|b| *b == 1));
+        file1.as_file().read_exact_at(&mut chunk, 0).unwrap();
+        assert!(chunk[..STRIPE_SIZE as usize / 2].iter().all(|b| *b == 1));
+        assert!(chunk[STRIPE_SIZE as usize / 2..].iter().all(|b| *b == 2));
+        file0
+            .as_file()
+            .read_exact_at(&mut chunk, STRIPE_SIZE)
+            .unwrap();
+        assert!(chunk[..STRIPE_SIZE as usize / 2].iter().all(|b| *b == 2));
+
+        let mut buf = vec![0u8; 3 * STRIPE_SIZE as usize];
+        io.read_vectored(0, &[iovec(&mut buf)], 2).unwrap();
+        io.submit().unwrap();
+        assert_eq!(wait_completion(io.as_mut()), (2, 3 * STRIPE_SIZE as i32));
+        let half = STRIPE_SIZE as usize / 2;
+        assert!(buf[..half].iter().all(|b| *b == 0));
+        assert!(buf[half..3 * half].iter().all(|b| *b == 1));
+        assert!(buf[3 * half..5 * half].iter().all(|b| *b == 2));
+        assert!(buf[5 * half..].iter().all(|b| *b == 0));
+
+        io.fsync(Some(3)).unwrap();
+        assert_eq!(wait_completion(io.as_mut()), (3, 0));
+    }
+
+    #[test]
+    fn test_striped_invalid() {
+        let (_file0, child0) = child_disk(4 * STRIPE_SIZE);
+        let (_file1, child1) = child_disk(2 * STRIPE_SIZE);
+        assert!(matches!(
+            StripedDiskFile::new(vec![child0, child1], STRIPE_SIZE),
+            Err(DiskFileError::StripeChildSizeMismatch(..))
+        ));
+
+        let (_file0, child0) = child_disk(4 * STRIPE_SIZE);
+        assert!(matches!(
+            StripedDiskFile::new(vec![child0], 3 * STRIPE_SIZE),
+            Err(DiskFileError::InvalidStripeSize(..))
+        ));
+
+        assert!(matches!(
+            StripedDiskFile::new(Vec::new(), STRIPE_SIZE),
+            Err(DiskFileError::NoStripeChildren)
+        ));
+    }
+}
@@ -0,0 +1,525 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! [`DiskFile`] checking the data read from another [`DiskFile`] against a
+//! manifest of SHA-256 digests of its blocks, detecting an image modified
+//! behind our back. Unlike the checksums of qcow2, the manifest is kept
+//! beside the image, working over any RAW image and still applying once the
+//! image has been imported again.
+
+use crate::async_io::{
+    AsyncIo, AsyncIoError, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult, IoAlignment,
+};
+use crate::lifetime::DiskLifetime;
+use crate::{AsyncIoBackend, DiskTopology};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use vmm_sys_util::eventfd::EventFd;
+
+const DIGEST_SIZE: usize = 32;
+
+type BlockDigest = [u8; DIGEST_SIZE];
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed opening the manifest: {0}")]
+    OpenManifest(#[source] io::Error),
+    #[error("Failed reading the manifest: {0}")]
+    ReadManifest(#[source] io::Error),
+    #[error("Failed writing the manifest: {0}")]
+    WriteManifest(#[source] io::Error),
+    #[error("Invalid manifest block size: {0}")]
+    InvalidBlockSize(String),
+    #[error("Invalid digest on line {0} of the manifest")]
+    InvalidDigest(usize),
+    #[error("Failed reading the image: {0}")]
+    ReadImage(#[source] io::Error),
+    #[error("The image size {0} is not a multiple of the block size")]
+    UnalignedImage(u64),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// SHA-256 digest of every block of an image.
+///
+/// The manifest is a text file whose first line is `block_size=<bytes>`,
+/// followed by one line per block holding its digest as hexadecimal. The
+/// size of the image must be a multiple of the block size.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DigestManifest {
+    block_size: u64,
+    digests: Vec<BlockDigest>,
+}
+
+impl DigestManifest {
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path).map_err(Error::OpenManifest)?;
+        Self::parse(BufReader::new(file))
+    }
+
+    fn parse<R: BufRead>(reader: R) -> Result<Self> {
+        let mut lines = reader.lines();
+
+        let first = lines
+            .next()
+            .transpose()
+            .map_err(Error::ReadManifest)?
+            .unwrap_or_default();
+        let block_size = first
+            .strip_prefix("block_size=")
+            .and_then(|size| size.trim().parse::<u64>().ok())
+            .filter(|size| *size > 0)
+            .ok_or_else(|| Error::InvalidBlockSize(first.clone()))?;
+
+        let mut digests = Vec::new();
+        for (i, line) in lines.enumerate() {
+            let line = line.map_err(Error::ReadManifest)?;
+            // Lines are numbered from 1, the block size being on the first.
+            let digest = parse_digest(line.trim()).ok_or(Error::InvalidDigest(i + 2))?;
+            digests.push(digest);
+        }
+
+        Ok(DigestManifest {
+            block_size,
+            digests,
+        })
+    }
+
+    /// Computes the digests of the blocks of the image stored in `file`.
+    pub fn generate(file: &File, block_size: u64) -> Result<Self> {
+        if block_size == 0 {
+            return Err(Error::InvalidBlockSize(block_size.to_string()));
+        }
+        let size = file.metadata().map_err(Error::ReadImage)?.len();
+        if size % block_size != 0 {
+            return Err(Error::UnalignedImage(size));
+        }
+
+        let mut block = vec![0u8; block_size as usize];
+        let mut digests = Vec::with_capacity((size / block_size) as usize);
+        for offset in (0..size).step_by(block_size as usize) {
+            file.read_exact_at(&mut block, offset)
+                .map_err(Error::ReadImage)?;
+            digests.push(Sha256::digest(&block).into());
+        }
+
+        Ok(DigestManifest {
+            block_size,
+            digests,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file = File::create(path).map_err(Error::WriteManifest)?;
+        let mut writer = BufWriter::new(file);
+        writeln!(writer, "block_size={}", self.block_size).map_err(Error::WriteManifest)?;
+        for digest in &self.digests {
+            for byte in digest {
+                write!(writer, "{byte:02x}").map_err(Error::WriteManifest)?;
+            }
+            writeln!(writer).map_err(Error::WriteManifest)?;
+        }
+        writer.flush().map_err(Error::WriteManifest)
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    fn size(&self) -> u64 {
+        self.digests.len() as u64 * self.block_size
+    }
+}
+
+fn parse_digest(hex: &str) -> Option<BlockDigest> {
+    if hex.len() != DIGEST_SIZE * 2 || !hex.is_ascii() {
+        return None;
+    }
+    let mut digest = [0u8; DIGEST_SIZE];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(digest)
+}
+
+// Returns the digests of the blocks held by the first `len` bytes of
+// `iovecs`, `len` being a multiple of `block_size`.
+fn block_digests(iovecs: &[libc::iovec], len: usize, block_size: usize) -> Vec<BlockDigest> {
+    let mut digests = Vec::with_capacity(len / block_size);
+    let mut hasher = Sha256::new();
+    let mut hashed = 0;
+    for iovec in iovecs {
+        // SAFETY: the iovecs point to guest memory valid for the duration
+        // of the request.
+        let mut data =
+            unsafe { std::slice::from_raw_parts(iovec.iov_base as *const u8, iovec.iov_len) };
+        while !data.is_empty() && digests.len() * block_size < len {
+            let count = data.len().min(block_size - hashed);
+            hasher.update(&data[..count]);
+            data = &data[count..];
+            hashed += count;
+            if hashed == block_size {
+                digests.push(hasher.finalize_reset().into());
+                hashed = 0;
+            }
+        }
+    }
+    digests
+}
+
+pub struct VerifiedDiskFile {
+    inner: Box<dyn DiskFile>,
+    manifest: Arc<Mutex<DigestManifest>>,
+}
+
+impl VerifiedDiskFile {
+    /// Checks the data read from `inner` against `manifest`, which must
+    /// cover the whole disk. The digests of the blocks written by the guest
+    /// are updated as the writes complete.
+    pub fn new(mut inner: Box<dyn DiskFile>, manifest: DigestManifest) -> DiskFileResult<Self> {
+        let size = inner.size()?;
+        if size != manifest.size() {
+            return Err(DiskFileError::ManifestSize(manifest.size(), size));
+        }
+
+        Ok(VerifiedDiskFile {
+            inner,
+            manifest: Arc::new(Mutex::new(manifest)),
+        })
+    }
+
+    /// Returns the manifest matching the current content of the disk, to be
+    /// saved once the guest is done writing to it.
+    pub fn manifest(&self) -> DigestManifest {
+        self.manifest.lock().unwrap().clone()
+    }
+}
+
+impl DiskFile for VerifiedDiskFile {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        self.inner.size()
+    }
+
+    fn new_async_io(&self, ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        Ok(Box::new(VerifiedAsyncIo::new(
+            self.inner.new_async_io(ring_depth)?,
+            self.manifest.clone(),
+        )) as Box<dyn AsyncIo>)
+    }
+
+    fn serial(&mut self) -> Option<String> {
+        self.inner.serial()
+    }
+
+    fn rotational(&mut self) -> bool {
+        self.inner.rotational()
+    }
+
+    fn get_lifetime(&mut self) -> DiskLifetime {
+        self.inner.get_lifetime()
+    }
+
+    fn available_space(&self) -> DiskFileResult<Option<u64>> {
+        self.inner.available_space()
+    }
+
+    fn backend(&self) -> AsyncIoBackend {
+        self.inner.backend()
+    }
+
+    fn topology(&mut self) -> DiskTopology {
+        let block_size = self.manifest.lock().unwrap().block_size;
+        let mut topology = self.inner.topology();
+        // Requests must cover whole blocks of the manifest.
+        topology.logical_block_size = topology.logical_block_size.max(block_size);
+        topology.physical_block_size = topology
+            .physical_block_size
+            .max(topology.logical_block_size);
+        topology
+    }
+
+    fn resize(&mut self, current_size: u64) -> DiskFileResult<u64> {
+        // The manifest has no digest for the blocks the disk would grow by.
+        let size = self.inner.size()?;
+        if size != current_size {
+            return Err(DiskFileError::ManifestSize(current_size, size));
+        }
+        Ok(size)
+    }
+}
+
+struct PendingRead {
+    first_block: u64,
+    iovecs: Vec<libc::iovec>,
+}
+
+struct PendingWrite {
+    first_block: u64,
+    digests: Vec<BlockDigest>,
+}
+
+pub struct VerifiedAsyncIo {
+    inner: Box<dyn AsyncIo>,
+    manifest: Arc<Mutex<DigestManifest>>,
+    block_size: u64,
+    reads: HashMap<u64, PendingRead>,
+    writes: HashMap<u64, PendingWrite>,
+}
+
+// SAFETY: the raw pointers are only the guest memory iovecs of in flight
+// reads, which stay valid until the requests complete.
+unsafe impl Send for VerifiedAsyncIo {}
+
+impl VerifiedAsyncIo {
+    fn new(inner: Box<dyn AsyncIo>, manifest: Arc<Mutex<DigestManifest>>) -> Self {
+        let block_size = manifest.lock().unwrap().block_size;
+        VerifiedAsyncIo {
+            inner,
+            manifest,
+            block_size,
+            reads: HashMap::new(),
+            writes: HashMap::new(),
+        }
+    }
+
+    // Checks the request covers whole blocks, returning its length and the
+    // index of its first block.
+    fn blocks(&self, offset: libc::off_t, iovecs: &[libc::iovec]) -> io::Result<(usize, u64)> {
+        let len: usize = iovecs.iter().map(|iovec| iovec.iov_len).sum();
+        if offset < 0 || offset as u64 % self.block_size != 0 || len as u64 % self.block_size != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "request not aligned on the manifest block size",
+            ));
+        }
+
+        Ok((len, offset as u64 / self.block_size))
+    }
+
+    // Compares the blocks read with their digests, returning the first one
+    // which doesn't match.
+    fn verify(&self, read: &PendingRead, len: usize) -> Option<u64> {
+        let manifest = self.manifest.lock().unwrap();
+        block_digests(&read.iovecs, len, self.block_size as usize)
+            .iter()
+            .zip(read.first_block..)
+            .find(|(digest, block)| manifest.digests.get(*block as usize) != Some(*digest))
+            .map(|(_, block)| block)
+    }
+}
+
+impl AsyncIo for VerifiedAsyncIo {
+    fn notifier(&self) -> &EventFd {
+        self.inner.notifier()
+    }
+
+    fn required_alignment(&self) -> IoAlignment {
+        let inner = self.inner.required_alignment();
+        IoAlignment {
+            offset: inner.offset.max(self.block_size),
+            memory: inner.memory,
+        }
+    }
+
+    fn submission_room(&self) -> usize {
+        self.inner.submission_room()
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let (_, first_block) = self
+            .blocks(offset, iovecs)
+            .map_err(AsyncIoError::ReadVectored)?;
+
+        self.inner.read_vectored(offset, iovecs, user_data)?;
+        self.reads.insert(
+            user_data,
+            PendingRead {
+                first_block,
+                iovecs: iovecs.to_vec(),
+            },
+        );
+
+        Ok(())
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let (len, first_block) = self
+            .blocks(offset, iovecs)
+            .map_err(AsyncIoError::WriteVectored)?;
+        // The digests are taken before the guest gets a chance to modify
+        // the buffers again.
+        let digests = block_digests(iovecs, len, self.block_size as usize);
+
+        self.inner.write_vectored(offset, iovecs, user_data)?;
+        self.writes.insert(
+            user_data,
+            PendingWrite {
+                first_block,
+                digests,
+            },
+        );
+
+        Ok(())
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        self.inner.fsync(user_data)
+    }
+
+    fn cancel(&mut self, user_data: u64) -> AsyncIoResult<bool> {
+        self.inner.cancel(user_data)
+    }
+
+    fn submit(&mut self) -> AsyncIoResult<()> {
+        self.inner.submit()
+    }
+
+    fn next_completed_request(&mut self) -> Option<(u64, i32)> {
+        let (user_data, mut result) = self.inner.next_completed_request()?;
+
+        if let Some(read) = self.reads.remove(&user_data) {
+            if result > 0 {
+                // Only whole blocks can be verified.
+                let len = result as u64 / self.block_size * self.block_size;
+                if let Some(block) = self.verify(&read, len as usize) {
+                    error!("{}", AsyncIoError::Corrupt(block));
+                    result = -libc::EIO;
+                } else {
+                    result = len as i32;
+                }
+            }
+        } else if let Some(write) = self.writes.remove(&user_data) {
+            // The blocks a failed write may have modified are left with
+            // their previous digest, reading them failing until they are
+            // written again.
+            if result > 0 {
+                let written = (result as u64 / self.block_size) as usize;
+                let mut manifest = self.manifest.lock().unwrap();
+                let first = write.first_block as usize;
+                manifest.digests[first..first + written].copy_from_slice(&write.digests[..written]);
+            }
+        }
+
+        Some((user_data, result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_disk::MemoryDiskFile;
+    use vmm_sys_util::tempfile::TempFile;
+
+    fn iovec(buf: &mut [u8]) -> libc::iovec {
+        libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }
+    }
+
+    #[test]
+    fn test_manifest_generate_and_load() {
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(&[0xa5u8; 8192]).unwrap();
+        let manifest = DigestManifest::generate(file.as_file(), 4096).unwrap();
+        assert_eq!(manifest.digests.len(), 2);
+        assert_eq!(manifest.digests[0], manifest.digests[1]);
+        assert_eq!(
+            manifest.digests[0],
+            <BlockDigest>::from(Sha256::digest([0xa5u8; 4096]))
+        );
+
+        let path = TempFile::new().unwrap();
+        manifest.save(path.as_path()).unwrap();
+        assert_eq!(DigestManifest::load(path.as_path()).unwrap(), manifest);
+
+        assert!(matches!(
+            DigestManifest::generate(file.as_file(), 3000),
+            Err(Error::UnalignedImage(8192))
+        ));
+        assert!(matches!(
+            DigestManifest::parse("block_size=512\nbogus\n".as_bytes()),
+            Err(Error::InvalidDigest(2))
+        ));
+        assert!(matches!(
+            DigestManifest::parse("0000abcd\n".as_bytes()),
+            Err(Error::InvalidBlockSize(_))
+        ));
+    }
+
+    #[test]
+    fn test_verified_reads() {
+        let memory_disk = MemoryDiskFile::with_data(vec![0xa5u8; 8192], 512);
+        let manifest = DigestManifest {
+            block_size: 4096,
+            digests: vec![Sha256::digest([0xa5u8; 4096]).into(); 2],
+        };
+        let mut disk = VerifiedDiskFile::new(Box::new(memory_disk.clone()), manifest).unwrap();
+        assert_eq!(disk.topology().logical_block_size, 4096);
+        let mut io = disk.new_async_io(1).unwrap();
+
+        // The blocks may be split across the buffers.
+        let mut buf = vec![0u8; 8192];
+        let (first, second) = buf.split_at_mut(1000);
+        io.read_vectored(0, &[iovec(first), iovec(second)], 1)
+            .unwrap();
+        assert_eq!(io.next_completed_request(), Some((1, 8192)));
+
+        // Written blocks are read back fine.
+        let mut data = vec![0x5au8; 4096];
+        io.write_vectored(4096, &[iovec(&mut data)], 2).unwrap();
+        assert_eq!(io.next_completed_request(), Some((2, 4096)));
+        io.read_vectored(0, &[iovec(&mut buf)], 3).unwrap();
+        assert_eq!(io.next_completed_request(), Some((3, 8192)));
+        assert!(buf[4096..].iter().all(|b| *b == 0x5a));
+        assert_eq!(
+            disk.manifest().digests[1],
+            <BlockDigest>::from(Sha256::digest([0x5au8; 4096]))
+        );
+
+        // Blocks modified behind our back fail to be read.
+        let mut other = memory_disk.new_async_io(1).unwrap();
+        let mut tampered = vec![0u8; 512];
+        other.write_vectored(0, &[iovec(&mut tampered)], 4).unwrap();
+        io.read_vectored(0, &[iovec(&mut buf[..4096])], 5).unwrap();
+        assert_eq!(io.next_completed_request(), Some((5, -libc::EIO)));
+        io.read_vectored(4096, &[iovec(&mut buf[..4096])], 6)
+            .unwrap();
+        assert_eq!(io.next_completed_request(), Some((6, 4096)));
+
+        // Requests must cover whole blocks.
+        assert!(io
+            .read_vectored(512, &[iovec(&mut buf[..4096])], 7)
+            .is_err());
+    }
+
+    #[test]
+    fn test_manifest_size_mismatch() {
+        let memory_disk = MemoryDiskFile::new(8192, 512);
+        let manifest = DigestManifest {
+            block_size: 4096,
+            digests: vec![[0u8; DIGEST_SIZE]],
+        };
+        assert!(matches!(
+            VerifiedDiskFile::new(Box::new(memory_disk), manifest),
+            Err(DiskFileError::ManifestSize(4096, 8192))
+        ));
+    }
+}
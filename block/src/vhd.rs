@@ -4,7 +4,91 @@
 
 use crate::{read_aligned_block_size, DiskTopology};
 use std::fs::File;
-use std::io::{Seek, SeekFrom};
+use std::io::{self, Seek, SeekFrom};
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+pub const VHD_FOOTER_SIZE: u64 = 512;
+// "conectix"
+const VHD_COOKIE: u64 = 0x636f_6e65_6374_6978;
+// Reserved features bit, always set.
+const VHD_FEATURES: u32 = 0x0000_0002;
+const VHD_FILE_FORMAT_VERSION: u32 = 0x0001_0000;
+const VHD_FIXED_DATA_OFFSET: u64 = 0xffff_ffff_ffff_ffff;
+const VHD_DISK_TYPE_FIXED: u32 = 0x2;
+// "clhv"
+const VHD_CREATOR_APPLICATION: u32 = 0x636c_6876;
+const VHD_CREATOR_VERSION: u32 = 0x0001_0000;
+// "Wi2k", as expected by Hyper-V.
+const VHD_CREATOR_HOST_OS: u32 = 0x5769_326b;
+// VHD time stamps count the seconds since January 1, 2000 UTC.
+const VHD_EPOCH: u64 = 946_684_800;
+const CHECKSUM_RANGE: std::ops::Range<usize> = 64..68;
+
+#[derive(Error, Debug)]
+pub enum VhdError {
+    #[error("Failed to read the VHD footer: {0}")]
+    ReadFooter(#[source] io::Error),
+    #[error("Failed to write the VHD footer: {0}")]
+    WriteFooter(#[source] io::Error),
+    #[error("Invalid VHD footer cookie: {0:#x}")]
+    InvalidCookie(u64),
+    #[error("Unsupported VHD file format version: {0:#x}")]
+    UnsupportedVersion(u32),
+    #[error("Not a fixed VHD, disk type {0}")]
+    NotFixed(u32),
+    #[error("VHD footer checksum mismatch: expected {expected:#x}, computed {computed:#x}")]
+    ChecksumMismatch { expected: u32, computed: u32 },
+    #[error("VHD footer size {footer_size} doesn't match the file length {file_len}")]
+    SizeMismatch { footer_size: u64, file_len: u64 },
+}
+
+impl From<VhdError> for io::Error {
+    fn from(e: VhdError) -> Self {
+        match e {
+            VhdError::ReadFooter(e) | VhdError::WriteFooter(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+// Returns the one's complement of the sum of the footer bytes, the checksum
+// field excluded.
+fn footer_checksum(sector: &[u8]) -> u32 {
+    let sum = sector[..VHD_FOOTER_SIZE as usize]
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !CHECKSUM_RANGE.contains(i))
+        .fold(0u32, |sum, (_, b)| sum.wrapping_add(u32::from(*b)));
+    !sum
+}
+
+// Cylinders, heads and sectors per track describing a disk of `size` bytes,
+// computed as defined by the VHD specification.
+fn disk_geometry(size: u64) -> u32 {
+    let total_sectors = (size / 512).min(65535 * 16 * 255);
+    let (sectors_per_track, heads, cylinder_times_heads) = if total_sectors >= 65535 * 16 * 63 {
+        (255, 16, total_sectors / 255)
+    } else {
+        let mut sectors_per_track = 17;
+        let mut cylinder_times_heads = total_sectors / sectors_per_track;
+        let mut heads = cylinder_times_heads.div_ceil(1024).max(4);
+        if cylinder_times_heads >= heads * 1024 || heads > 16 {
+            sectors_per_track = 31;
+            heads = 16;
+            cylinder_times_heads = total_sectors / sectors_per_track;
+        }
+        if cylinder_times_heads >= heads * 1024 {
+            sectors_per_track = 63;
+            heads = 16;
+            cylinder_times_heads = total_sectors / sectors_per_track;
+        }
+        (sectors_per_track, heads, cylinder_times_heads)
+    };
+    let cylinders = cylinder_times_heads / heads;
+
+    ((cylinders as u32) << 16) | ((heads as u32) << 8) | sectors_per_track as u32
+}
 
 #[derive(Clone, Copy)]
 pub struct VhdFooter {
@@ -23,6 +107,8 @@ pub struct VhdFooter {
     checksum: u32,
     unique_id: u128,
     saved_state: u8,
+    // Checksum of the footer as read, reserved bytes included.
+    computed_checksum: u32,
 }
 
 impl VhdFooter {
@@ -36,9 +122,42 @@ impl VhdFooter {
 
         // We only care about the last sector
         let offset = blocksize - 512;
-        let sector = &data[offset..];
 
-        Ok(VhdFooter {
+        Ok(Self::parse(&data[offset..]))
+    }
+
+    /// Footer of a new fixed VHD of `size` bytes.
+    pub fn new_fixed(size: u64) -> VhdFooter {
+        let time_stamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs().saturating_sub(VHD_EPOCH) as u32)
+            .unwrap_or(0);
+
+        let mut footer = VhdFooter {
+            cookie: VHD_COOKIE,
+            features: VHD_FEATURES,
+            file_format_version: VHD_FILE_FORMAT_VERSION,
+            data_offset: VHD_FIXED_DATA_OFFSET,
+            time_stamp,
+            creator_application: VHD_CREATOR_APPLICATION,
+            creator_version: VHD_CREATOR_VERSION,
+            creator_host_os: VHD_CREATOR_HOST_OS,
+            original_size: size,
+            current_size: size,
+            disk_geometry: disk_geometry(size),
+            disk_type: VHD_DISK_TYPE_FIXED,
+            checksum: 0,
+            unique_id: uuid::Uuid::new_v4().as_u128(),
+            saved_state: 0,
+            computed_checksum: 0,
+        };
+        footer.checksum = footer_checksum(&footer.to_bytes());
+        footer.computed_checksum = footer.checksum;
+        footer
+    }
+
+    fn parse(sector: &[u8]) -> VhdFooter {
+        VhdFooter {
             cookie: u64::from_be_bytes(sector[0..8].try_into().unwrap()),
             features: u32::from_be_bytes(sector[8..12].try_into().unwrap()),
             file_format_version: u32::from_be_bytes(sector[12..16].try_into().unwrap()),
@@ -54,7 +173,60 @@ impl VhdFooter {
             checksum: u32::from_be_bytes(sector[64..68].try_into().unwrap()),
             unique_id: u128::from_be_bytes(sector[68..84].try_into().unwrap()),
             saved_state: u8::from_be_bytes(sector[84..85].try_into().unwrap()),
-        })
+            computed_checksum: footer_checksum(sector),
+        }
+    }
+
+    /// Serializes the footer, the remaining bytes being reserved and zeroed.
+    pub fn to_bytes(&self) -> [u8; VHD_FOOTER_SIZE as usize] {
+        let mut sector = [0u8; VHD_FOOTER_SIZE as usize];
+        sector[0..8].copy_from_slice(&self.cookie.to_be_bytes());
+        sector[8..12].copy_from_slice(&self.features.to_be_bytes());
+        sector[12..16].copy_from_slice(&self.file_format_version.to_be_bytes());
+        sector[16..24].copy_from_slice(&self.data_offset.to_be_bytes());
+        sector[24..28].copy_from_slice(&self.time_stamp.to_be_bytes());
+        sector[28..32].copy_from_slice(&self.creator_application.to_be_bytes());
+        sector[32..36].copy_from_slice(&self.creator_version.to_be_bytes());
+        sector[36..40].copy_from_slice(&self.creator_host_os.to_be_bytes());
+        sector[40..48].copy_from_slice(&self.original_size.to_be_bytes());
+        sector[48..56].copy_from_slice(&self.current_size.to_be_bytes());
+        sector[56..60].copy_from_slice(&self.disk_geometry.to_be_bytes());
+        sector[60..64].copy_from_slice(&self.disk_type.to_be_bytes());
+        sector[64..68].copy_from_slice(&self.checksum.to_be_bytes());
+        sector[68..84].copy_from_slice(&self.unique_id.to_be_bytes());
+        sector[84] = self.saved_state;
+        sector
+    }
+
+    /// Checks the footer describes a fixed VHD stored in a file of
+    /// `file_len` bytes.
+    pub fn validate_fixed(&self, file_len: u64) -> Result<(), VhdError> {
+        if self.cookie != VHD_COOKIE {
+            return Err(VhdError::InvalidCookie(self.cookie));
+        }
+        if self.file_format_version != VHD_FILE_FORMAT_VERSION {
+            return Err(VhdError::UnsupportedVersion(self.file_format_version));
+        }
+        if self.disk_type != VHD_DISK_TYPE_FIXED || self.data_offset != VHD_FIXED_DATA_OFFSET {
+            return Err(VhdError::NotFixed(self.disk_type));
+        }
+
+        if self.computed_checksum != self.checksum {
+            return Err(VhdError::ChecksumMismatch {
+                expected: self.checksum,
+                computed: self.computed_checksum,
+            });
+        }
+
+        // The data is directly followed by the footer.
+        if self.current_size.checked_add(VHD_FOOTER_SIZE) != Some(file_len) {
+            return Err(VhdError::SizeMismatch {
+                footer_size: self.current_size,
+                file_len,
+            });
+        }
+
+        Ok(())
     }
 
     pub fn cookie(&self) -> u64 {
@@ -108,16 +280,17 @@ impl VhdFooter {
 pub fn is_fixed_vhd(f: &mut File) -> std::io::Result<bool> {
     let footer = VhdFooter::new(f)?;
 
-    // "conectix" => 0x636f6e6563746978
-    Ok(footer.cookie() == 0x636f6e6563746978
-        && footer.file_format_version() == 0x0001_0000
-        && footer.data_offset() == 0xffff_ffff_ffff_ffff
-        && footer.disk_type() == 0x2)
+    Ok(footer.cookie() == VHD_COOKIE
+        && footer.file_format_version() == VHD_FILE_FORMAT_VERSION
+        && footer.data_offset() == VHD_FIXED_DATA_OFFSET
+        && footer.disk_type() == VHD_DISK_TYPE_FIXED)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{is_fixed_vhd, VhdFooter};
+    use super::{footer_checksum, is_fixed_vhd, VhdError, VhdFooter};
+    use crate::fixed_vhd::FixedVhd;
+    use crate::BlockBackend;
     use std::fs::File;
     use std::io::{Seek, SeekFrom, Write};
     use vmm_sys_util::tempfile::TempFile;
@@ -214,4 +387,68 @@ mod tests {
             assert!(!(is_fixed_vhd(&mut file).unwrap()));
         });
     }
+
+    // The fixture footer with its checksum set.
+    fn checksummed_fixed_vhd_footer() -> Vec<u8> {
+        let mut footer = valid_fixed_vhd_footer();
+        footer.resize(512, 0);
+        let checksum = footer_checksum(&footer);
+        footer[64..68].copy_from_slice(&checksum.to_be_bytes());
+        footer
+    }
+
+    #[test]
+    fn test_validate_fixed_vhd_footer() {
+        with_file(&checksummed_fixed_vhd_footer(), |mut file: File| {
+            let vhd_footer = VhdFooter::new(&mut file).unwrap();
+            vhd_footer.validate_fixed(0x1000_0200).unwrap();
+            assert!(matches!(
+                vhd_footer.validate_fixed(0x1000_0400),
+                Err(VhdError::SizeMismatch {
+                    footer_size: 0x1000_0000,
+                    file_len: 0x1000_0400
+                })
+            ));
+        });
+
+        // The fixture footer has no valid checksum.
+        with_file(&valid_fixed_vhd_footer(), |mut file: File| {
+            let vhd_footer = VhdFooter::new(&mut file).unwrap();
+            assert!(matches!(
+                vhd_footer.validate_fixed(0x1000_0200),
+                Err(VhdError::ChecksumMismatch { expected: 0, .. })
+            ));
+            assert!(FixedVhd::new(file).is_err());
+        });
+
+        with_file(&valid_dynamic_vhd_footer(), |mut file: File| {
+            let vhd_footer = VhdFooter::new(&mut file).unwrap();
+            assert!(matches!(
+                vhd_footer.validate_fixed(0x1000_0200),
+                Err(VhdError::NotFixed(3))
+            ));
+        });
+    }
+
+    #[test]
+    fn test_create_fixed_vhd() {
+        let file: File = TempFile::new().unwrap().into_file();
+        FixedVhd::create(file.try_clone().unwrap(), 0x1000_0000).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 0x1000_0200);
+
+        let mut file = file;
+        assert!(is_fixed_vhd(&mut file).unwrap());
+        let vhd_footer = VhdFooter::new(&mut file).unwrap();
+        assert_eq!(vhd_footer.current_size(), 0x1000_0000);
+        assert_eq!(vhd_footer.original_size(), 0x1000_0000);
+        // 520 cylinders, 16 heads, 63 sectors per track.
+        assert_eq!(vhd_footer.disk_geometry(), 0x0208_103f);
+        assert_eq!(
+            VhdFooter::parse(&vhd_footer.to_bytes()).checksum(),
+            vhd_footer.checksum()
+        );
+
+        let fixed_vhd = FixedVhd::new(file).unwrap();
+        assert_eq!(fixed_vhd.size().unwrap(), 0x1000_0000);
+    }
 }
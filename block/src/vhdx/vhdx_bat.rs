@@ -26,8 +26,6 @@ pub const BAT_FILE_OFF_MASK: u64 = 0xFFFFFFFFFFF00000;
 #[sorted]
 #[derive(Error, Debug)]
 pub enum VhdxBatError {
-    #[error("Invalid BAT entry")]
-    InvalidBatEntry,
     #[error("Invalid BAT entry count")]
     InvalidEntryCount,
     #[error("Failed to read BAT entry {0}")]
@@ -79,25 +77,13 @@ impl BatEntry {
         data_blocks_count + (data_blocks_count - 1) / chunk_ratio
     }
 
-    // Routine for writing BAT entries to the disk
-    pub fn write_bat_entries(
-        f: &mut File,
-        bat_offset: u64,
-        bat_entries: &[BatEntry],
-    ) -> Result<()> {
-        for i in 0..bat_entries.len() as u64 {
-            f.seek(SeekFrom::Start(bat_offset + i * size_of::<u64>() as u64))
-                .map_err(VhdxBatError::WriteBat)?;
-            let bat_entry = match bat_entries.get(i as usize) {
-                Some(entry) => entry.0,
-                None => {
-                    return Err(VhdxBatError::InvalidBatEntry);
-                }
-            };
-
-            f.write_u64::<LittleEndian>(bat_entry)
-                .map_err(VhdxBatError::WriteBat)?;
-        }
-        Ok(())
+    // Routine for writing a single BAT entry to the disk
+    pub fn write_bat_entry(f: &mut File, bat_offset: u64, index: u64, entry: u64) -> Result<()> {
+        f.seek(SeekFrom::Start(
+            bat_offset + index * size_of::<u64>() as u64,
+        ))
+        .map_err(VhdxBatError::WriteBat)?;
+        f.write_u64::<LittleEndian>(entry)
+            .map_err(VhdxBatError::WriteBat)
     }
 }
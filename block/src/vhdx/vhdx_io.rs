@@ -7,12 +7,11 @@ use crate::vhdx::{
     vhdx_metadata::{self, DiskSpec},
 };
 use remain::sorted;
+use std::cmp;
 use std::fs::File;
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use thiserror::Error;
 
-const SECTOR_SIZE: u64 = 512;
-
 #[sorted]
 #[derive(Error, Debug)]
 pub enum VhdxIoError {
@@ -60,7 +59,10 @@ impl Sector {
     ) -> Result<Sector> {
         let mut sector = Sector::default();
 
-        sector.bat_index = sector_index / disk_spec.sectors_per_block as u64;
+        // Every chunk_ratio payload blocks, the BAT holds the entry of the
+        // sector bitmap block describing them, which must be skipped.
+        let block_index = sector_index / disk_spec.sectors_per_block as u64;
+        sector.bat_index = block_index + block_index / disk_spec.chunk_ratio;
         sector.block_offset = sector_index % disk_spec.sectors_per_block as u64;
         sector.free_sectors = disk_spec.sectors_per_block as u64 - sector.block_offset;
         if sector.free_sectors > sector_count {
@@ -102,6 +104,8 @@ pub fn read(
             return Err(VhdxIoError::UnsupportedMode);
         } else {
             let sector = Sector::new(disk_spec, bat, sector_index, sector_count)?;
+            // The last sector may only be partially requested.
+            let read_end = cmp::min(read_count + sector.free_bytes as usize, buf.len());
 
             let bat_entry = match bat.get(sector.bat_index as usize) {
                 Some(entry) => entry.0,
@@ -114,15 +118,14 @@ pub fn read(
                 vhdx_bat::PAYLOAD_BLOCK_NOT_PRESENT
                 | vhdx_bat::PAYLOAD_BLOCK_UNDEFINED
                 | vhdx_bat::PAYLOAD_BLOCK_UNMAPPED
-                | vhdx_bat::PAYLOAD_BLOCK_ZERO => {}
+                | vhdx_bat::PAYLOAD_BLOCK_ZERO => {
+                    buf[read_count..read_end].fill(0);
+                }
                 vhdx_bat::PAYLOAD_BLOCK_FULLY_PRESENT => {
                     f.seek(SeekFrom::Start(sector.file_offset))
                         .map_err(VhdxIoError::ReadSectorBlock)?;
-                    f.read_exact(
-                        &mut buf[read_count
-                            ..(read_count + (sector.free_sectors * SECTOR_SIZE) as usize)],
-                    )
-                    .map_err(VhdxIoError::ReadSectorBlock)?;
+                    f.read_exact(&mut buf[read_count..read_end])
+                        .map_err(VhdxIoError::ReadSectorBlock)?;
                 }
                 vhdx_bat::PAYLOAD_BLOCK_PARTIALLY_PRESENT => {
                     return Err(VhdxIoError::UnsupportedMode);
@@ -133,7 +136,7 @@ pub fn read(
             };
             sector_count -= sector.free_sectors;
             sector_index += sector.free_sectors;
-            read_count = sector.free_bytes as usize;
+            read_count = read_end;
         };
     }
     Ok(read_count)
@@ -157,6 +160,8 @@ pub fn write(
             return Err(VhdxIoError::UnsupportedMode);
         } else {
             let sector = Sector::new(disk_spec, bat, sector_index, sector_count)?;
+            // The last sector may only be partially provided.
+            let write_end = cmp::min(write_count + sector.free_bytes as usize, buf.len());
 
             let bat_entry = match bat.get(sector.bat_index as usize) {
                 Some(entry) => entry.0,
@@ -170,6 +175,8 @@ pub fn write(
                 | vhdx_bat::PAYLOAD_BLOCK_UNDEFINED
                 | vhdx_bat::PAYLOAD_BLOCK_UNMAPPED
                 | vhdx_bat::PAYLOAD_BLOCK_ZERO => {
+                    // Append a new block to the file, reading as zeroes
+                    // until written.
                     let file_offset =
                         align!(disk_spec.image_size, vhdx_metadata::BLOCK_SIZE_MIN as u64);
                     let new_size = file_offset
@@ -181,21 +188,20 @@ pub fn write(
 
                     let new_bat_entry = file_offset
                         | (vhdx_bat::PAYLOAD_BLOCK_FULLY_PRESENT & vhdx_bat::BAT_STATE_BIT_MASK);
-                    bat[sector.bat_index as usize] = BatEntry(new_bat_entry);
-                    BatEntry::write_bat_entries(f, bat_offset, bat)
-                        .map_err(VhdxIoError::WriteBat)?;
-
                     if file_offset < vhdx_metadata::BLOCK_SIZE_MIN as u64 {
                         break;
                     }
 
-                    f.seek(SeekFrom::Start(file_offset))
+                    // Write the data before pointing the BAT at it, so that a
+                    // crash in between doesn't expose stale content.
+                    f.seek(SeekFrom::Start(file_offset + sector.block_offset))
+                        .map_err(VhdxIoError::ReadSectorBlock)?;
+                    f.write_all(&buf[write_count..write_end])
                         .map_err(VhdxIoError::ReadSectorBlock)?;
-                    f.write_all(
-                        &buf[write_count
-                            ..(write_count + (sector.free_sectors * SECTOR_SIZE) as usize)],
-                    )
-                    .map_err(VhdxIoError::ReadSectorBlock)?;
+
+                    bat[sector.bat_index as usize] = BatEntry(new_bat_entry);
+                    BatEntry::write_bat_entry(f, bat_offset, sector.bat_index, new_bat_entry)
+                        .map_err(VhdxIoError::WriteBat)?;
                 }
                 vhdx_bat::PAYLOAD_BLOCK_FULLY_PRESENT => {
                     if sector.file_offset < vhdx_metadata::BLOCK_SIZE_MIN as u64 {
@@ -204,11 +210,8 @@ pub fn write(
 
                     f.seek(SeekFrom::Start(sector.file_offset))
                         .map_err(VhdxIoError::ReadSectorBlock)?;
-                    f.write_all(
-                        &buf[write_count
-                            ..(write_count + (sector.free_sectors * SECTOR_SIZE) as usize)],
-                    )
-                    .map_err(VhdxIoError::ReadSectorBlock)?;
+                    f.write_all(&buf[write_count..write_end])
+                        .map_err(VhdxIoError::ReadSectorBlock)?;
                 }
                 vhdx_bat::PAYLOAD_BLOCK_PARTIALLY_PRESENT => {
                     return Err(VhdxIoError::UnsupportedMode);
@@ -219,8 +222,44 @@ pub fn write(
             };
             sector_count -= sector.free_sectors;
             sector_index += sector.free_sectors;
-            write_count = sector.free_bytes as usize;
+            write_count = write_end;
         };
     }
     Ok(write_count)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sector_skips_sector_bitmap_entries() {
+        // 1 MiB blocks of 512 bytes sectors, a sector bitmap entry following
+        // every 4096 payload entries.
+        let disk_spec = DiskSpec {
+            block_size: 1 << 20,
+            logical_sector_size: 512,
+            sectors_per_block: 2048,
+            chunk_ratio: 4096,
+            ..Default::default()
+        };
+        let mut bat = vec![BatEntry::default(); 4098];
+        bat[4095] = BatEntry((1 << 20) | vhdx_bat::PAYLOAD_BLOCK_FULLY_PRESENT);
+        bat[4097] = BatEntry((2 << 20) | vhdx_bat::PAYLOAD_BLOCK_FULLY_PRESENT);
+
+        let sector = Sector::new(&disk_spec, &bat, 4095 * 2048 + 1, 4096).unwrap();
+        assert_eq!(sector.bat_index, 4095);
+        assert_eq!(sector.free_sectors, 2047);
+        assert_eq!(sector.file_offset, (1 << 20) + 512);
+
+        let sector = Sector::new(&disk_spec, &bat, 4096 * 2048, 1).unwrap();
+        assert_eq!(sector.bat_index, 4097);
+        assert_eq!(sector.free_bytes, 512);
+        assert_eq!(sector.file_offset, 2 << 20);
+
+        assert!(matches!(
+            Sector::new(&disk_spec, &bat, 4097 * 2048, 1),
+            Err(VhdxIoError::InvalidBatIndex)
+        ));
+    }
+}
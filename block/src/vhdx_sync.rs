@@ -2,7 +2,9 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::async_io::{AsyncIo, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult};
+use crate::async_io::{
+    completion_room, AsyncIo, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult,
+};
 use crate::vhdx::{Result as VhdxResult, Vhdx};
 use crate::AsyncAdaptor;
 use std::collections::VecDeque;
@@ -62,6 +64,10 @@ impl AsyncIo for VhdxSync {
         &self.eventfd
     }
 
+    fn submission_room(&self) -> usize {
+        completion_room(self.completion_list.len())
+    }
+
     fn read_vectored(
         &mut self,
         offset: libc::off_t,
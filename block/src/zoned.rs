@@ -0,0 +1,205 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Zoned block device support, relying on the Linux zoned block device
+//! ioctls. Offsets and lengths used by the kernel interface are expressed in
+//! 512 bytes sectors, whatever the logical block size of the device.
+
+use libc::ioctl;
+use std::io;
+use std::mem::{size_of, size_of_val};
+use std::os::unix::io::RawFd;
+use vmm_sys_util::{ioctl_ioc_nr, ioctl_ior_nr, ioctl_iow_nr, ioctl_iowr_nr};
+
+/// Conventional zone, which can be written randomly.
+pub const BLK_ZONE_TYPE_CONVENTIONAL: u8 = 0x1;
+/// Sequential write required zone, which can only be written at its write
+/// pointer.
+pub const BLK_ZONE_TYPE_SEQWRITE_REQ: u8 = 0x2;
+/// Sequential write preferred zone.
+pub const BLK_ZONE_TYPE_SEQWRITE_PREF: u8 = 0x3;
+
+/// Zone descriptor, as defined by `struct blk_zone` from the Linux UAPI.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BlkZone {
+    /// Zone start sector.
+    pub start: u64,
+    /// Zone length in sectors.
+    pub len: u64,
+    /// Zone write pointer position, as a sector.
+    pub wp: u64,
+    /// Zone type, one of the `BLK_ZONE_TYPE_*` values.
+    pub type_: u8,
+    /// Zone condition.
+    pub cond: u8,
+    pub non_seq: u8,
+    pub reset: u8,
+    pub resv: [u8; 4],
+    /// Zone capacity in sectors.
+    pub capacity: u64,
+    pub reserved: [u8; 24],
+}
+
+// Header of the BLKREPORTZONE argument, followed by nr_zones descriptors.
+#[repr(C)]
+#[derive(Default)]
+struct BlkZoneReport {
+    sector: u64,
+    nr_zones: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct BlkZoneRange {
+    sector: u64,
+    nr_sectors: u64,
+}
+
+ioctl_iowr_nr!(BLKREPORTZONE, 0x12, 130, BlkZoneReport);
+ioctl_iow_nr!(BLKRESETZONE, 0x12, 131, BlkZoneRange);
+ioctl_ior_nr!(BLKGETZONESZ, 0x12, 132, u32);
+ioctl_ior_nr!(BLKGETNRZONES, 0x12, 133, u32);
+ioctl_iow_nr!(BLKOPENZONE, 0x12, 134, BlkZoneRange);
+ioctl_iow_nr!(BLKCLOSEZONE, 0x12, 135, BlkZoneRange);
+ioctl_iow_nr!(BLKFINISHZONE, 0x12, 136, BlkZoneRange);
+
+/// Zone management operations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ZoneOperation {
+    Open,
+    Close,
+    Finish,
+    Reset,
+}
+
+fn query_u32(fd: RawFd, request: libc::c_ulong) -> io::Result<u32> {
+    let mut value: u32 = 0;
+    // SAFETY: FFI call with a valid pointer to a u32
+    let ret = unsafe { ioctl(fd, request as _, &mut value) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(value)
+}
+
+/// Returns the size of the zones in sectors, 0 meaning the device is not
+/// zoned.
+pub fn zone_sectors(fd: RawFd) -> io::Result<u32> {
+    match query_u32(fd, BLKGETZONESZ()) {
+        // Kernels predating zoned block devices support.
+        Err(e) if e.raw_os_error() == Some(libc::ENOTTY) => Ok(0),
+        r => r,
+    }
+}
+
+/// Returns the number of zones of the device.
+pub fn nr_zones(fd: RawFd) -> io::Result<u32> {
+    match query_u32(fd, BLKGETNRZONES()) {
+        Err(e) if e.raw_os_error() == Some(libc::ENOTTY) => Ok(0),
+        r => r,
+    }
+}
+
+/// Fills `zones` with the descriptors of the zones starting from the one
+/// containing `sector`, returning how many have been reported.
+pub fn report_zones(fd: RawFd, sector: u64, zones: &mut [BlkZone]) -> io::Result<usize> {
+    let header_len = size_of::<BlkZoneReport>();
+    let len = header_len + size_of_val(zones);
+    // Use a u64 buffer for the alignment of the structures.
+    let mut buf = vec![0u64; len.div_ceil(size_of::<u64>())];
+    let report = buf.as_mut_ptr() as *mut BlkZoneReport;
+
+    // SAFETY: the buffer is large enough to hold the header
+    unsafe {
+        (*report).sector = sector;
+        (*report).nr_zones = zones.len() as u32;
+    }
+
+    // SAFETY: FFI call with a buffer large enough for nr_zones descriptors
+    let ret = unsafe { ioctl(fd, BLKREPORTZONE() as _, report) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: the kernel updated nr_zones with the number of descriptors
+    // it wrote, which can't be more than requested.
+    let nr_zones = unsafe { (*report).nr_zones } as usize;
+    // SAFETY: the descriptors directly follow the header in the buffer
+    let reported = unsafe {
+        std::slice::from_raw_parts(
+            (buf.as_ptr() as *const u8).add(header_len) as *const BlkZone,
+            nr_zones,
+        )
+    };
+    zones[..nr_zones].copy_from_slice(reported);
+
+    Ok(nr_zones)
+}
+
+/// Applies `op` to the `nr_sectors` long range of zones starting at `sector`.
+pub fn manage_zones(fd: RawFd, op: ZoneOperation, sector: u64, nr_sectors: u64) -> io::Result<()> {
+    let range = BlkZoneRange { sector, nr_sectors };
+    let request = match op {
+        ZoneOperation::Open => BLKOPENZONE(),
+        ZoneOperation::Close => BLKCLOSEZONE(),
+        ZoneOperation::Finish => BLKFINISHZONE(),
+        ZoneOperation::Reset => BLKRESETZONE(),
+    };
+
+    // SAFETY: FFI call with a valid zone range
+    let ret = unsafe { ioctl(fd, request as _, &range) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_uapi_layout() {
+        assert_eq!(size_of::<BlkZone>(), 64);
+        assert_eq!(size_of::<BlkZoneReport>(), 16);
+        assert_eq!(size_of::<BlkZoneRange>(), 16);
+
+        // Values of the ioctls from linux/blkzoned.h.
+        assert_eq!(BLKREPORTZONE(), 0xc010_1282);
+        assert_eq!(BLKRESETZONE(), 0x4010_1283);
+        assert_eq!(BLKGETZONESZ(), 0x8004_1284);
+        assert_eq!(BLKGETNRZONES(), 0x8004_1285);
+        assert_eq!(BLKOPENZONE(), 0x4010_1286);
+        assert_eq!(BLKCLOSEZONE(), 0x4010_1287);
+        assert_eq!(BLKFINISHZONE(), 0x4010_1288);
+    }
+
+    #[test]
+    fn test_not_zoned() {
+        let file = TempFile::new().unwrap();
+        let fd = file.as_file().as_raw_fd();
+
+        // Anything but a zoned block device has no zones.
+        assert_eq!(zone_sectors(fd).unwrap(), 0);
+        assert_eq!(nr_zones(fd).unwrap(), 0);
+
+        // While the zones can't be reported or managed.
+        let mut zones = [BlkZone::default(); 2];
+        assert_eq!(
+            report_zones(fd, 0, &mut zones).unwrap_err().raw_os_error(),
+            Some(libc::ENOTTY)
+        );
+        assert_eq!(
+            manage_zones(fd, ZoneOperation::Reset, 0, 8)
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::ENOTTY)
+        );
+    }
+}
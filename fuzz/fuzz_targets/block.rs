@@ -8,9 +8,10 @@
 
 #![no_main]
 
-use block::{async_io::DiskFile, raw_sync::RawFileDiskSync};
+use block::{async_io::DiskFile, raw_sync::RawFileDiskSync, CacheMode};
 use libfuzzer_sys::fuzz_target;
 use seccompiler::SeccompAction;
+use std::collections::BTreeMap;
 use std::ffi;
 use std::fs::File;
 use std::io;
@@ -21,7 +22,6 @@ use virtio_devices::{Block, VirtioDevice, VirtioInterrupt, VirtioInterruptType};
 use virtio_queue::{Queue, QueueT};
 use vm_memory::{bitmap::AtomicBitmap, Bytes, GuestAddress, GuestMemoryAtomic};
 use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
-use std::collections::BTreeMap;
 
 type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
 
@@ -49,7 +49,9 @@ fuzz_target!(|bytes| {
     // Create a virtio-block device backed by a synchronous raw file
     let shm = memfd_create(&ffi::CString::new("fuzz").unwrap(), 0).unwrap();
     let disk_file: File = unsafe { File::from_raw_fd(shm) };
-    let qcow_disk = Box::new(RawFileDiskSync::new(disk_file)) as Box<dyn DiskFile>;
+    let qcow_disk =
+        Box::new(RawFileDiskSync::new(disk_file, false, CacheMode::Writeback, None).unwrap())
+            as Box<dyn DiskFile>;
     let queue_affinity = BTreeMap::new();
     let mut block = Block::new(
         "tmp".to_owned(),
@@ -62,6 +64,8 @@ fuzz_target!(|bytes| {
         None,
         SeccompAction::Allow,
         None,
+        None,
+        None,
         EventFd::new(EFD_NONBLOCK).unwrap(),
         None,
         queue_affinity,
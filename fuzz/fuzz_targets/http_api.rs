@@ -19,7 +19,7 @@ use vmm::api::{
 use vmm::config::RestoreConfig;
 use vmm::vm::{Error as VmError, VmState};
 use vmm::vm_config::*;
-use vmm::{EpollContext, EpollDispatch};
+use vmm::{DiskRateLimits, EpollContext, EpollDispatch};
 use vmm_sys_util::eventfd::EventFd;
 
 // Need to be ordered for test case reproducibility
@@ -262,6 +262,10 @@ impl RequestHandler for StubApiRequestHandler {
         Ok(None)
     }
 
+    fn vm_disks(&mut self) -> Result<Option<Vec<u8>>, VmError> {
+        Ok(None)
+    }
+
     fn vm_power_button(&mut self) -> Result<(), VmError> {
         Ok(())
     }
@@ -277,6 +281,30 @@ impl RequestHandler for StubApiRequestHandler {
     fn vm_nmi(&mut self) -> Result<(), VmError> {
         Ok(())
     }
+
+    fn vm_flush_disks(&mut self) -> Result<(), VmError> {
+        Ok(())
+    }
+
+    fn vm_resume_disk(&mut self, _: String) -> Result<(), VmError> {
+        Ok(())
+    }
+
+    fn vm_change_medium(&mut self, _: String, _: Option<PathBuf>) -> Result<(), VmError> {
+        Ok(())
+    }
+
+    fn vm_update_disk_rate_limits(
+        &mut self,
+        _: String,
+        _: DiskRateLimits,
+    ) -> Result<Option<Vec<u8>>, VmError> {
+        Ok(None)
+    }
+
+    fn vm_rotate_disk_key(&mut self, _: String, _: PathBuf) -> Result<(), VmError> {
+        Ok(())
+    }
 }
 
 fn http_receiver_stub(exit_evt: EventFd, api_evt: EventFd, api_receiver: Receiver<ApiRequest>) {
@@ -134,6 +134,8 @@ pub struct PerformanceTestControl {
     queue_size: Option<u32>,
     net_control: Option<(bool, bool)>, // First bool is for RX(true)/TX(false), second bool is for bandwidth or PPS
     fio_control: Option<(FioOps, bool)>, // Second parameter controls whether we want bandwidth or IOPS
+    fio_block_size: Option<u32>,         // 4 KiB by default
+    disk_options: Option<&'static str>,  // Appended to the options of the tested disk
     num_boot_vcpus: Option<u8>,
 }
 
@@ -157,6 +159,12 @@ impl fmt::Display for PerformanceTestControl {
             let (ops, bw) = o;
             output = format!("{output}, fio_ops = {ops}, bandwidth = {bw}");
         }
+        if let Some(o) = self.fio_block_size {
+            output = format!("{output}, fio_block_size = {o}");
+        }
+        if let Some(o) = self.disk_options {
+            output = format!("{output}, disk_options = {o}");
+        }
 
         write!(f, "{output}")
     }
@@ -171,6 +179,8 @@ impl PerformanceTestControl {
             queue_size: None,
             net_control: None,
             fio_control: None,
+            fio_block_size: None,
+            disk_options: None,
             num_boot_vcpus: Some(1),
         }
     }
@@ -274,7 +284,7 @@ mod adjuster {
     }
 }
 
-const TEST_LIST: [PerformanceTest; 29] = [
+const TEST_LIST: [PerformanceTest; 30] = [
     PerformanceTest {
         name: "boot_time_ms",
         func_ptr: performance_boot_time,
@@ -591,6 +601,22 @@ const TEST_LIST: [PerformanceTest; 29] = [
         },
         unit_adjuster: adjuster::identity,
     },
+    // Realigned on the O_DIRECT alignment of the filesystem holding the test
+    // image, each write being a read-modify-write of a 4 KiB block on 4Kn
+    // storage.
+    PerformanceTest {
+        name: "block_sub_block_random_write_IOPS",
+        func_ptr: performance_block_io,
+        control: PerformanceTestControl {
+            num_queues: Some(1),
+            queue_size: Some(128),
+            fio_control: Some((FioOps::RandomWrite, false)),
+            fio_block_size: Some(512),
+            disk_options: Some("direct=on,backend=sync"),
+            ..PerformanceTestControl::default()
+        },
+        unit_adjuster: adjuster::identity,
+    },
 ];
 
 fn run_test_with_timeout(
@@ -335,6 +335,10 @@ pub fn performance_block_io(control: &PerformanceTestControl) -> f64 {
     let test_timeout = control.test_timeout;
     let num_queues = control.num_queues.unwrap();
     let (fio_ops, bandwidth) = control.fio_control.as_ref().unwrap();
+    let fio_block_size = control.fio_block_size.unwrap_or(4096);
+    let disk_options = control
+        .disk_options
+        .map_or_else(String::new, |options| format!(",{options}"));
 
     let focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
     let guest = performance_test_new_guest(Box::new(focal));
@@ -363,7 +367,7 @@ pub fn performance_block_io(control: &PerformanceTestControl) -> f64 {
                 guest.disk_config.disk(DiskType::CloudInit).unwrap()
             )
             .as_str(),
-            format!("path={BLK_IO_TEST_IMG}").as_str(),
+            format!("path={BLK_IO_TEST_IMG}{disk_options}").as_str(),
         ])
         .default_net()
         .args(["--api-socket", &api_socket])
@@ -378,7 +382,7 @@ pub fn performance_block_io(control: &PerformanceTestControl) -> f64 {
 
         let fio_command = format!(
             "sudo fio --filename=/dev/vdc --name=test --output-format=json \
-            --direct=1 --bs=4k --ioengine=io_uring --iodepth=64 \
+            --direct=1 --bs={fio_block_size} --ioengine=io_uring --iodepth=64 \
             --rw={fio_ops} --runtime={test_timeout} --numjobs={num_queues}"
         );
         let output = guest
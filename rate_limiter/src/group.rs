@@ -4,7 +4,7 @@
 // Copyright 2023 Crusoe Energy Systems LLC
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::{RateLimiter, TokenType};
+use crate::{BucketUpdate, RateLimiter, TokenBucket, TokenType};
 use core::panic::AssertUnwindSafe;
 use std::fs::File;
 use std::io;
@@ -212,6 +212,17 @@ impl RateLimiterGroup {
         RateLimiterGroupHandle::new(self.inner.clone())
     }
 
+    /// Replaces the token buckets of the group, the new ones keeping the
+    /// budget left as with `RateLimiter::adjust_buckets()`.
+    pub fn adjust_buckets(&self, bytes: BucketUpdate, ops: BucketUpdate) {
+        self.inner.rate_limiter.adjust_buckets(bytes, ops)
+    }
+
+    /// Returns the bytes and ops token buckets of the group.
+    pub fn buckets(&self) -> (Option<TokenBucket>, Option<TokenBucket>) {
+        self.inner.rate_limiter.buckets()
+    }
+
     /// Start a worker thread to broadcast an event to each RateLimiterGroupHandle
     /// when the RateLimiter becomes unblocked.
     pub fn start_thread(&mut self, exit_evt: EventFd) -> result::Result<(), Error> {
@@ -236,6 +236,14 @@ impl TokenBucket {
         self.refill_time
     }
 
+    // Takes over the state of the bucket `self` replaces, so that the tokens
+    // consumed before are not handed back, the budget left being capped to
+    // the new capacity and refilled at the new rate from then on.
+    fn carry_over(&mut self, replaced: &TokenBucket) {
+        self.budget = std::cmp::min(replaced.budget, self.size);
+        self.last_update = replaced.last_update;
+    }
+
     /// Returns the current budget (one time burst allowance notwithstanding).
     pub fn budget(&self) -> u64 {
         self.budget
@@ -494,6 +502,33 @@ impl RateLimiter {
             BucketUpdate::None => (),
         };
     }
+
+    /// Replaces the token buckets associated with this RateLimiter, the new
+    /// ones keeping the budget left in those they replace up to their
+    /// capacity. Unlike `update_buckets()`, the new limits therefore take
+    /// effect as the buckets refill, without a burst of a full budget.
+    pub fn adjust_buckets(&self, bytes: BucketUpdate, ops: BucketUpdate) {
+        let mut guard = self.inner.lock().unwrap();
+        let adjust = |bucket: &mut Option<TokenBucket>, update: BucketUpdate| match update {
+            BucketUpdate::Disabled => *bucket = None,
+            BucketUpdate::Update(mut tb) => {
+                if let Some(replaced) = bucket.as_ref() {
+                    tb.carry_over(replaced);
+                }
+                *bucket = Some(tb);
+            }
+            BucketUpdate::None => (),
+        };
+        adjust(&mut guard.bandwidth, bytes);
+        adjust(&mut guard.ops, ops);
+    }
+
+    /// Returns the bytes and ops token buckets of this RateLimiter, unset
+    /// for the token types not limited.
+    pub fn buckets(&self) -> (Option<TokenBucket>, Option<TokenBucket>) {
+        let guard = self.inner.lock().unwrap();
+        (guard.bandwidth.clone(), guard.ops.clone())
+    }
 }
 
 impl AsRawFd for RateLimiter {
@@ -904,6 +939,37 @@ pub(crate) mod tests {
         assert_eq!(x.ops(), None);
     }
 
+    #[test]
+    fn test_adjust_buckets() {
+        let x = RateLimiter::new(1000, 0, 1000, 10, 0, 1000).unwrap();
+        assert!(x.consume(900, TokenType::Bytes));
+        assert!(x.consume(2, TokenType::Ops));
+
+        // The budget left is kept, up to the capacity of the new buckets.
+        x.adjust_buckets(
+            BucketUpdate::Update(TokenBucket::new(2000, 0, 1000).unwrap()),
+            BucketUpdate::Update(TokenBucket::new(5, 0, 1000).unwrap()),
+        );
+        let (bandwidth, ops) = x.buckets();
+        let (bandwidth, ops) = (bandwidth.unwrap(), ops.unwrap());
+        assert_eq!(bandwidth.capacity(), 2000);
+        assert_eq!(bandwidth.budget(), 100);
+        assert_eq!(ops.capacity(), 5);
+        assert_eq!(ops.budget(), 5);
+
+        x.adjust_buckets(BucketUpdate::None, BucketUpdate::Disabled);
+        let (bandwidth, ops) = x.buckets();
+        assert_eq!(bandwidth.unwrap().capacity(), 2000);
+        assert!(ops.is_none());
+
+        // A bucket enabling the limiting starts full.
+        x.adjust_buckets(
+            BucketUpdate::None,
+            BucketUpdate::Update(TokenBucket::new(7, 0, 1000).unwrap()),
+        );
+        assert_eq!(x.buckets().1.unwrap().budget(), 7);
+    }
+
     #[test]
     fn test_rate_limiter_debug() {
         let l = RateLimiter::new(1, 2, 3, 4, 5, 6).unwrap();
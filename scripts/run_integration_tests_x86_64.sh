@@ -12,10 +12,10 @@ mkdir -p "$WORKLOADS_DIR"
 process_common_args "$@"
 
 # For now these values are default for kvm
-test_features=""
+test_features="--features qcow_compression"
 
 if [ "$hypervisor" = "mshv" ]; then
-    test_features="--features mshv"
+    test_features="--features mshv,qcow_compression"
 fi
 
 cp scripts/sha1sums-x86_64 "$WORKLOADS_DIR"
@@ -148,7 +148,7 @@ cp "$FOCAL_OS_RAW_IMAGE" "$VFIO_DIR"
 cp "$FW" "$VFIO_DIR"
 cp "$VMLINUX_IMAGE" "$VFIO_DIR" || exit 1
 
-cargo build --features mshv --all --release --target "$BUILD_TARGET"
+cargo build --features "mshv,qcow_compression" --all --release --target "$BUILD_TARGET"
 
 # We always copy a fresh version of our binary for our L2 guest.
 cp target/"$BUILD_TARGET"/release/cloud-hypervisor "$VFIO_DIR"
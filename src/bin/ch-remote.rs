@@ -13,6 +13,7 @@ use std::fmt;
 use std::io::Read;
 use std::marker::PhantomData;
 use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
 use std::process;
 #[cfg(feature = "dbus_api")]
 use zbus::{proxy, zvariant::Optional};
@@ -35,6 +36,7 @@ enum Error {
     AddUserDeviceConfig(vmm::config::Error),
     AddVdpaConfig(vmm::config::Error),
     AddVsockConfig(vmm::config::Error),
+    UpdateDiskRateLimitsConfig(vmm::config::Error),
     Restore(vmm::config::Error),
     ReadingStdin(std::io::Error),
     ReadingFile(std::io::Error),
@@ -58,6 +60,7 @@ impl fmt::Display for Error {
             AddUserDeviceConfig(e) => write!(f, "Error parsing user device syntax: {e}"),
             AddVdpaConfig(e) => write!(f, "Error parsing vDPA device syntax: {e}"),
             AddVsockConfig(e) => write!(f, "Error parsing vsock syntax: {e}"),
+            UpdateDiskRateLimitsConfig(e) => write!(f, "Error parsing rate limits syntax: {e}"),
             Restore(e) => write!(f, "Error parsing restore syntax: {e}"),
             ReadingStdin(e) => write!(f, "Error reading from stdin: {e}"),
             ReadingFile(e) => write!(f, "Error reading from file: {e}"),
@@ -291,6 +294,9 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
         Some("counters") => {
             simple_api_command(socket, "GET", "counters", None).map_err(Error::HttpApiClient)
         }
+        Some("disks") => {
+            simple_api_command(socket, "GET", "disks", None).map_err(Error::HttpApiClient)
+        }
         Some("ping") => {
             simple_api_full_command(socket, "GET", "vmm.ping", None).map_err(Error::HttpApiClient)
         }
@@ -298,6 +304,51 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
             simple_api_command(socket, "PUT", "shutdown", None).map_err(Error::HttpApiClient)
         }
         Some("nmi") => simple_api_command(socket, "PUT", "nmi", None).map_err(Error::HttpApiClient),
+        Some("flush-disks") => {
+            simple_api_command(socket, "PUT", "flush-disks", None).map_err(Error::HttpApiClient)
+        }
+        Some("resume-disk") => {
+            let resume_disk_data = resume_disk_config(
+                matches
+                    .subcommand_matches("resume-disk")
+                    .unwrap()
+                    .get_one::<String>("id")
+                    .unwrap(),
+            );
+            simple_api_command(socket, "PUT", "resume-disk", Some(&resume_disk_data))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("change-medium") => {
+            let change_medium_matches = matches.subcommand_matches("change-medium").unwrap();
+            let change_medium_data = change_medium_config(
+                change_medium_matches.get_one::<String>("id").unwrap(),
+                change_medium_matches
+                    .get_one::<String>("path")
+                    .map(|path| path.as_str()),
+            );
+            simple_api_command(socket, "PUT", "change-medium", Some(&change_medium_data))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("update-disk-rate-limits") => {
+            let update_matches = matches
+                .subcommand_matches("update-disk-rate-limits")
+                .unwrap();
+            let update_data = update_disk_rate_limits_config(
+                update_matches.get_one::<String>("id").unwrap(),
+                update_matches.get_one::<String>("limits").unwrap(),
+            )?;
+            simple_api_command(socket, "PUT", "update-disk-rate-limits", Some(&update_data))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("rotate-disk-key") => {
+            let rotate_matches = matches.subcommand_matches("rotate-disk-key").unwrap();
+            let rotate_data = rotate_disk_key_config(
+                rotate_matches.get_one::<String>("id").unwrap(),
+                rotate_matches.get_one::<String>("passphrase_file").unwrap(),
+            );
+            simple_api_command(socket, "PUT", "rotate-disk-key", Some(&rotate_data))
+                .map_err(Error::HttpApiClient)
+        }
         Some("resize") => {
             let resize = resize_config(
                 matches
@@ -357,14 +408,14 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
                 .map_err(Error::HttpApiClient)
         }
         Some("add-disk") => {
-            let disk_config = add_disk_config(
+            let (disk_config, fds) = add_disk_config(
                 matches
                     .subcommand_matches("add-disk")
                     .unwrap()
                     .get_one::<String>("disk_config")
                     .unwrap(),
             )?;
-            simple_api_command(socket, "PUT", "add-disk", Some(&disk_config))
+            simple_api_command_with_fds(socket, "PUT", "add-disk", Some(&disk_config), fds)
                 .map_err(Error::HttpApiClient)
         }
         Some("add-fs") => {
@@ -581,7 +632,7 @@ fn dbus_api_do_command(matches: &ArgMatches, proxy: &DBusApi1ProxyBlocking<'_>)
             proxy.api_vm_remove_device(&remove_device_data)
         }
         Some("add-disk") => {
-            let disk_config = add_disk_config(
+            let (disk_config, _fds) = add_disk_config(
                 matches
                     .subcommand_matches("add-disk")
                     .unwrap()
@@ -793,11 +844,54 @@ fn remove_device_config(id: &str) -> String {
     serde_json::to_string(&remove_device_data).unwrap()
 }
 
-fn add_disk_config(config: &str) -> Result<String, Error> {
-    let disk_config = vmm::config::DiskConfig::parse(config).map_err(Error::AddDiskConfig)?;
+fn resume_disk_config(id: &str) -> String {
+    let resume_disk_data = vmm::api::VmResumeDiskData { id: id.to_owned() };
+
+    serde_json::to_string(&resume_disk_data).unwrap()
+}
+
+fn change_medium_config(id: &str, path: Option<&str>) -> String {
+    let change_medium_data = vmm::api::VmChangeMediumData {
+        id: id.to_owned(),
+        path: path.map(PathBuf::from),
+    };
+
+    serde_json::to_string(&change_medium_data).unwrap()
+}
+
+// The limits are given with the options of the disk syntax, only the rate
+// limiters some options are given of being updated.
+fn update_disk_rate_limits_config(id: &str, limits: &str) -> Result<String, Error> {
+    let disk_config =
+        vmm::config::DiskConfig::parse(limits).map_err(Error::UpdateDiskRateLimitsConfig)?;
+    let update_data = vmm::api::VmUpdateDiskRateLimitsData {
+        id: id.to_owned(),
+        rate_limiter_config: disk_config.rate_limiter_config,
+        read_rate_limiter_config: disk_config.read_rate_limiter_config,
+        write_rate_limiter_config: disk_config.write_rate_limiter_config,
+    };
+
+    Ok(serde_json::to_string(&update_data).unwrap())
+}
+
+fn rotate_disk_key_config(id: &str, passphrase_file: &str) -> String {
+    let rotate_data = vmm::api::VmRotateDiskKeyData {
+        id: id.to_owned(),
+        passphrase_file: PathBuf::from(passphrase_file),
+    };
+
+    serde_json::to_string(&rotate_data).unwrap()
+}
+
+fn add_disk_config(config: &str) -> Result<(String, Vec<i32>), Error> {
+    let mut disk_config = vmm::config::DiskConfig::parse(config).map_err(Error::AddDiskConfig)?;
+
+    // As with the network devices, the file descriptor is taken out so that
+    // it is sent alongside the request rather than as a meaningless value.
+    let fds = disk_config.fd.take().into_iter().collect();
     let disk_config = serde_json::to_string(&disk_config).unwrap();
 
-    Ok(disk_config)
+    Ok((disk_config, fds))
 }
 
 fn add_fs_config(config: &str) -> Result<String, Error> {
@@ -1001,6 +1095,7 @@ fn main() {
         )
         .subcommand(Command::new("info").about("Info on the VM"))
         .subcommand(Command::new("counters").about("Counters from the VM"))
+        .subcommand(Command::new("disks").about("Disks of the VM and their statistics"))
         .subcommand(Command::new("pause").about("Pause the VM"))
         .subcommand(Command::new("reboot").about("Reboot the VM"))
         .subcommand(Command::new("power-button").about("Trigger a power button in the VM"))
@@ -1100,7 +1195,43 @@ fn main() {
         )
         .subcommand(Command::new("ping").about("Ping the VMM to check for API server availability"))
         .subcommand(Command::new("shutdown-vmm").about("Shutdown the VMM"))
-        .subcommand(Command::new("nmi").about("Trigger NMI"));
+        .subcommand(Command::new("nmi").about("Trigger NMI"))
+        .subcommand(Command::new("flush-disks").about("Flush every disk of the VM"))
+        .subcommand(
+            Command::new("resume-disk")
+                .about("Retry the writes to a disk paused with the host storage full")
+                .arg(Arg::new("id").index(1).help("<disk_id>")),
+        )
+        .subcommand(
+            Command::new("change-medium")
+                .about("Insert an image into a removable disk, or eject its medium")
+                .arg(Arg::new("id").index(1).help("<disk_id>"))
+                .arg(
+                    Arg::new("path")
+                        .index(2)
+                        .help("<image_path>, the medium being ejected if omitted"),
+                ),
+        )
+        .subcommand(
+            Command::new("update-disk-rate-limits")
+                .about("Change the rate limits of a disk")
+                .arg(Arg::new("id").index(1).help("<disk_id>"))
+                .arg(Arg::new("limits").index(2).help(
+                    "bw_size=<bytes>,bw_one_time_burst=<bytes>,bw_refill_time=<ms>,\
+                     ops_size=<io_ops>,ops_one_time_burst=<io_ops>,ops_refill_time=<ms>, \
+                     and the same prefixed with read_ or write_",
+                )),
+        )
+        .subcommand(
+            Command::new("rotate-disk-key")
+                .about("Replace the passphrase of a LUKS encrypted disk")
+                .arg(Arg::new("id").index(1).help("<disk_id>"))
+                .arg(
+                    Arg::new("passphrase_file")
+                        .index(2)
+                        .help("<passphrase_file_path>"),
+                ),
+        );
 
     let matches = app.get_matches();
 
@@ -0,0 +1,98 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use block::qcow::CheckReport;
+use block::qcow_sync::QcowDiskSync;
+use clap::{Arg, ArgAction, Command};
+use std::fs::OpenOptions;
+use std::process;
+
+// Exit codes, matching the ones of `qemu-img check`.
+const EXIT_CLEAN: i32 = 0;
+const EXIT_FAILED: i32 = 1;
+const EXIT_CORRUPTED: i32 = 2;
+const EXIT_LEAKS: i32 = 3;
+
+fn print_report(report: &CheckReport) {
+    for error in &report.errors {
+        println!("ERROR: {error}");
+    }
+    for leak in &report.leaks {
+        println!("Leaked cluster at {leak:#x}");
+    }
+    if report.leaks_fixed > 0 {
+        println!("Repaired {} leaked clusters", report.leaks_fixed);
+    }
+
+    if report.is_clean() {
+        println!("No errors were found on the image.");
+    } else {
+        println!(
+            "{} errors and {} leaked clusters were found on the image.",
+            report.errors.len(),
+            report.leaks.len()
+        );
+    }
+}
+
+fn main() {
+    let cmd_arguments = Command::new("qcow-check")
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .version(env!("BUILD_VERSION"))
+        .about("Check the consistency of a qcow2 disk image.")
+        .arg_required_else_help(true)
+        .args([
+            Arg::new("image")
+                .index(1)
+                .required(true)
+                .help("Path to the qcow2 image"),
+            Arg::new("repair")
+                .long("repair")
+                .action(ArgAction::SetTrue)
+                .num_args(0)
+                .help("Free the leaked clusters, if no other error was found"),
+        ])
+        .get_matches();
+
+    let path = cmd_arguments.get_one::<String>("image").unwrap();
+    let repair = cmd_arguments.get_flag("repair");
+
+    let file = match OpenOptions::new().read(true).write(repair).open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Error opening {path}: {e}");
+            process::exit(EXIT_FAILED);
+        }
+    };
+    let disk = match QcowDiskSync::new(file, false, None) {
+        Ok(disk) => disk,
+        Err(e) => {
+            eprintln!("Error opening {path} as a qcow2 image: {e}");
+            process::exit(EXIT_FAILED);
+        }
+    };
+
+    let result = if repair {
+        disk.repair_leaks()
+    } else {
+        disk.check()
+    };
+    let report = match result {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("Error checking {path}: {e}");
+            process::exit(EXIT_FAILED);
+        }
+    };
+
+    print_report(&report);
+    process::exit(if !report.errors.is_empty() {
+        EXIT_CORRUPTED
+    } else if !report.leaks.is_empty() {
+        EXIT_LEAKS
+    } else {
+        EXIT_CLEAN
+    });
+}
@@ -0,0 +1,73 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use block::qcow::{QcowFile, RawFile, RebaseMode};
+use clap::{Arg, ArgAction, Command};
+use std::fs::OpenOptions;
+use std::process;
+
+fn main() {
+    let cmd_arguments = Command::new("qcow-rebase")
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .version(env!("BUILD_VERSION"))
+        .about("Change the backing file of a qcow2 disk image.")
+        .arg_required_else_help(true)
+        .args([
+            Arg::new("image")
+                .index(1)
+                .required(true)
+                .help("Path to the qcow2 image"),
+            Arg::new("backing")
+                .long("backing")
+                .short('b')
+                .required(true)
+                .num_args(1)
+                .help(
+                    "Path to the new qcow2 backing file, relative to the image, \
+                    or \"\" for none",
+                ),
+            Arg::new("unsafe")
+                .long("unsafe")
+                .short('u')
+                .action(ArgAction::SetTrue)
+                .num_args(0)
+                .help("Only change the header, the backing files holding the same data"),
+        ])
+        .get_matches();
+
+    let path = cmd_arguments.get_one::<String>("image").unwrap();
+    let backing = cmd_arguments
+        .get_one::<String>("backing")
+        .map(String::as_str)
+        .filter(|backing| !backing.is_empty());
+    let mode = if cmd_arguments.get_flag("unsafe") {
+        RebaseMode::Unsafe
+    } else {
+        RebaseMode::Safe
+    };
+
+    let file = match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Error opening {path}: {e}");
+            process::exit(1);
+        }
+    };
+    let mut image = match QcowFile::from(RawFile::new(file, false)) {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("Error opening {path} as a qcow2 image: {e}");
+            process::exit(1);
+        }
+    };
+
+    match image.rebase(backing, mode) {
+        Ok(copied) => println!("Copied {copied} clusters into the image."),
+        Err(e) => {
+            eprintln!("Error rebasing {path}: {e}");
+            process::exit(1);
+        }
+    }
+}
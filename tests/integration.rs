@@ -40,6 +40,12 @@ mod x86_64 {
     pub const FOCAL_IMAGE_NAME_QCOW2: &str = "focal-server-cloudimg-amd64-custom-20210609-0.qcow2";
     pub const FOCAL_IMAGE_NAME_QCOW2_BACKING_FILE: &str =
         "focal-server-cloudimg-amd64-custom-20210609-0-backing.qcow2";
+    #[cfg(feature = "qcow_compression")]
+    pub const FOCAL_IMAGE_NAME_QCOW2_ZLIB: &str =
+        "focal-server-cloudimg-amd64-custom-20210609-0-zlib.qcow2";
+    #[cfg(feature = "qcow_compression")]
+    pub const FOCAL_IMAGE_NAME_QCOW2_ZSTD: &str =
+        "focal-server-cloudimg-amd64-custom-20210609-0-zstd.qcow2";
     pub const FOCAL_IMAGE_NAME_VHD: &str = "focal-server-cloudimg-amd64-custom-20210609-0.vhd";
     pub const FOCAL_IMAGE_NAME_VHDX: &str = "focal-server-cloudimg-amd64-custom-20210609-0.vhdx";
     pub const JAMMY_IMAGE_NAME: &str = "jammy-server-cloudimg-amd64-custom-20230119-0.raw";
@@ -59,6 +65,12 @@ mod aarch64 {
     pub const FOCAL_IMAGE_NAME_QCOW2: &str = "focal-server-cloudimg-arm64-custom-20210929-0.qcow2";
     pub const FOCAL_IMAGE_NAME_QCOW2_BACKING_FILE: &str =
         "focal-server-cloudimg-arm64-custom-20210929-0-backing.qcow2";
+    #[cfg(feature = "qcow_compression")]
+    pub const FOCAL_IMAGE_NAME_QCOW2_ZLIB: &str =
+        "focal-server-cloudimg-arm64-custom-20210929-0-zlib.qcow2";
+    #[cfg(feature = "qcow_compression")]
+    pub const FOCAL_IMAGE_NAME_QCOW2_ZSTD: &str =
+        "focal-server-cloudimg-arm64-custom-20210929-0-zstd.qcow2";
     pub const FOCAL_IMAGE_NAME_VHD: &str = "focal-server-cloudimg-arm64-custom-20210929-0.vhd";
     pub const FOCAL_IMAGE_NAME_VHDX: &str = "focal-server-cloudimg-arm64-custom-20210929-0.vhdx";
     pub const JAMMY_IMAGE_NAME: &str = "jammy-server-cloudimg-arm64-custom-20220329-0.raw";
@@ -3334,6 +3346,44 @@ mod common_parallel {
         _test_virtio_block(FOCAL_IMAGE_NAME_QCOW2_BACKING_FILE, false, false)
     }
 
+    #[cfg(feature = "qcow_compression")]
+    fn _test_virtio_block_qcow2_compressed(image_name: &str, compression_type: &str) {
+        let mut workload_path = dirs::home_dir().unwrap();
+        workload_path.push("workloads");
+
+        let mut raw_file_path = workload_path.clone();
+        let mut qcow2_file_path = workload_path;
+        raw_file_path.push(FOCAL_IMAGE_NAME);
+        qcow2_file_path.push(image_name);
+
+        // Generate compressed QCOW2 file from RAW file
+        std::process::Command::new("qemu-img")
+            .arg("convert")
+            .arg("-p")
+            .arg("-c")
+            .args(["-f", "raw"])
+            .args(["-O", "qcow2"])
+            .args(["-o", &format!("compression_type={compression_type}")])
+            .arg(raw_file_path.to_str().unwrap())
+            .arg(qcow2_file_path.to_str().unwrap())
+            .output()
+            .expect("Expect generating compressed QCOW2 image from RAW image");
+
+        _test_virtio_block(image_name, false, false)
+    }
+
+    #[test]
+    #[cfg(feature = "qcow_compression")]
+    fn test_virtio_block_qcow2_zlib() {
+        _test_virtio_block_qcow2_compressed(FOCAL_IMAGE_NAME_QCOW2_ZLIB, "zlib")
+    }
+
+    #[test]
+    #[cfg(feature = "qcow_compression")]
+    fn test_virtio_block_qcow2_zstd() {
+        _test_virtio_block_qcow2_compressed(FOCAL_IMAGE_NAME_QCOW2_ZSTD, "zstd")
+    }
+
     #[test]
     fn test_virtio_block_vhd() {
         let mut workload_path = dirs::home_dir().unwrap();
@@ -3591,6 +3641,195 @@ mod common_parallel {
         test_vhost_user_blk(1, false, true, Some(&prepare_vubd))
     }
 
+    #[test]
+    #[cfg(not(target_arch = "aarch64"))]
+    fn test_vhost_user_blk_auth() {
+        let focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
+        let guest = Guest::new(Box::new(focal));
+        let kernel_path = direct_kernel_boot_path();
+
+        let mut blk_file_path = dirs::home_dir().unwrap();
+        blk_file_path.push("workloads");
+        blk_file_path.push("blk.img");
+        let blk_file_path = blk_file_path.to_str().unwrap();
+        let key_path = guest.tmp_dir.as_path().join("vub.key");
+        std::fs::write(&key_path, b"vhost-user-blk shared key").unwrap();
+        let key_path = key_path.to_str().unwrap();
+        let vubd_socket_path =
+            String::from(guest.tmp_dir.as_path().join("vub.sock").to_str().unwrap());
+
+        // Start the daemon, authenticating the frontend from the key
+        let mut daemon_child = Command::new(clh_command("vhost_user_block"))
+            .args([
+                "--block-backend",
+                format!(
+                    "path={blk_file_path},socket={vubd_socket_path},num_queues=1,readonly=true,auth_key={key_path}"
+                )
+                .as_str(),
+            ])
+            .spawn()
+            .unwrap();
+
+        thread::sleep(std::time::Duration::new(10, 0));
+
+        let mut child = GuestCommand::new(&guest)
+            .args(["--cpus", "boot=1"])
+            .args(["--memory", "size=512M,shared=on"])
+            .args(["--kernel", kernel_path.to_str().unwrap()])
+            .args(["--cmdline", DIRECT_KERNEL_BOOT_CMDLINE])
+            .args([
+                "--disk",
+                format!(
+                    "path={}",
+                    guest.disk_config.disk(DiskType::OperatingSystem).unwrap()
+                )
+                .as_str(),
+                format!(
+                    "path={}",
+                    guest.disk_config.disk(DiskType::CloudInit).unwrap()
+                )
+                .as_str(),
+                format!(
+                    "vhost_user=true,socket={vubd_socket_path},num_queues=1,queue_size=128,vhost_auth_key={key_path}"
+                )
+                .as_str(),
+            ])
+            .default_net()
+            .capture_output()
+            .spawn()
+            .unwrap();
+
+        let r = std::panic::catch_unwind(|| {
+            guest.wait_vm_boot(None).unwrap();
+
+            // Check both if /dev/vdc exists and if the block size is 16M.
+            assert_eq!(
+                guest
+                    .ssh_command("lsblk | grep vdc | grep -c 16M")
+                    .unwrap()
+                    .trim()
+                    .parse::<u32>()
+                    .unwrap_or_default(),
+                1
+            );
+
+            // Check the content of the block device, read through the
+            // authenticated connection. The file "foo" should contain "bar".
+            guest.ssh_command("mkdir mount_image").unwrap();
+            guest
+                .ssh_command("sudo mount -o ro,noload -t ext4 /dev/vdc mount_image/")
+                .unwrap();
+            assert_eq!(
+                guest.ssh_command("cat mount_image/foo").unwrap().trim(),
+                "bar"
+            );
+        });
+
+        kill_child(&mut child);
+        let output = child.wait_with_output().unwrap();
+
+        let _ = daemon_child.kill();
+        let _ = daemon_child.wait();
+
+        handle_child_output(r, &output);
+    }
+
+    #[test]
+    #[cfg(not(target_arch = "aarch64"))]
+    fn test_vhost_user_blk_reconnect() {
+        let focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
+        let guest = Guest::new(Box::new(focal));
+        let kernel_path = direct_kernel_boot_path();
+
+        let blk_img = String::from(guest.tmp_dir.as_path().join("vub.img").to_str().unwrap());
+        assert!(exec_host_command_status(&format!("truncate -s 64M {blk_img}")).success());
+        let vubd_socket_path =
+            String::from(guest.tmp_dir.as_path().join("vub.sock").to_str().unwrap());
+        let spawn_daemon = || {
+            let child = Command::new(clh_command("vhost_user_block"))
+                .args([
+                    "--block-backend",
+                    format!("path={blk_img},socket={vubd_socket_path},num_queues=1").as_str(),
+                ])
+                .spawn()
+                .unwrap();
+            thread::sleep(std::time::Duration::new(5, 0));
+            child
+        };
+        let daemon_child = Mutex::new(spawn_daemon());
+
+        let mut child = GuestCommand::new(&guest)
+            .args(["--cpus", "boot=1"])
+            .args(["--memory", "size=512M,shared=on"])
+            .args(["--kernel", kernel_path.to_str().unwrap()])
+            .args(["--cmdline", DIRECT_KERNEL_BOOT_CMDLINE])
+            .default_disks()
+            .args([
+                "--disk",
+                format!("vhost_user=true,socket={vubd_socket_path},num_queues=1").as_str(),
+            ])
+            .default_net()
+            .capture_output()
+            .spawn()
+            .unwrap();
+
+        let r = std::panic::catch_unwind(|| {
+            guest.wait_vm_boot(None).unwrap();
+
+            guest
+                .ssh_command("head -c 64M /dev/urandom > pattern")
+                .unwrap();
+            let expected = guest.ssh_command("md5sum < pattern").unwrap();
+
+            // Small synchronous writes, so that the backend goes away in the
+            // middle of the copy.
+            guest
+                .ssh_command(
+                    "nohup bash -c 'sudo dd if=pattern of=/dev/vdc bs=4k oflag=direct,dsync && touch copied' > /dev/null 2>&1 &",
+                )
+                .unwrap();
+            thread::sleep(std::time::Duration::new(2, 0));
+            {
+                let mut daemon_child = daemon_child.lock().unwrap();
+                let _ = daemon_child.kill();
+                let _ = daemon_child.wait();
+                thread::sleep(std::time::Duration::new(2, 0));
+                *daemon_child = spawn_daemon();
+            }
+
+            // The copy completes once the backend is back.
+            let mut copied = false;
+            for _ in 0..60 {
+                if guest
+                    .ssh_command("test -f copied && echo yes || echo no")
+                    .unwrap()
+                    .trim()
+                    == "yes"
+                {
+                    copied = true;
+                    break;
+                }
+                thread::sleep(std::time::Duration::new(2, 0));
+            }
+            assert!(copied);
+            assert_eq!(
+                guest
+                    .ssh_command("sudo dd if=/dev/vdc bs=1M count=64 iflag=direct | md5sum")
+                    .unwrap(),
+                expected
+            );
+        });
+
+        kill_child(&mut child);
+        let output = child.wait_with_output().unwrap();
+
+        let mut daemon_child = daemon_child.into_inner().unwrap_or_else(|e| e.into_inner());
+        let _ = daemon_child.kill();
+        let _ = daemon_child.wait();
+
+        handle_child_output(r, &output);
+    }
+
     #[test]
     fn test_boot_from_vhost_user_blk_default() {
         test_boot_from_vhost_user_blk(1, false, false, Some(&prepare_vubd))
@@ -5431,6 +5670,112 @@ mod common_parallel {
         loop_device_path
     }
 
+    #[test]
+    fn test_virtio_block_writeback_toggle() {
+        let focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
+        let guest = Guest::new(Box::new(focal));
+
+        let kernel_path = direct_kernel_boot_path();
+        let api_socket = temp_api_path(&guest.tmp_dir);
+        let writeback_disk_path = guest.tmp_dir.as_path().join("writeback.img");
+        let writethrough_disk_path = guest.tmp_dir.as_path().join("writethrough.img");
+        for path in [&writeback_disk_path, &writethrough_disk_path] {
+            let file = std::fs::File::create(path).unwrap();
+            file.set_len(16 << 20).unwrap();
+        }
+
+        let mut child = GuestCommand::new(&guest)
+            .args(["--api-socket", &api_socket])
+            .args(["--cpus", "boot=1"])
+            .args(["--memory", "size=512M"])
+            .args(["--kernel", kernel_path.to_str().unwrap()])
+            .args(["--cmdline", DIRECT_KERNEL_BOOT_CMDLINE])
+            .args([
+                "--disk",
+                format!(
+                    "path={}",
+                    guest.disk_config.disk(DiskType::OperatingSystem).unwrap()
+                )
+                .as_str(),
+                format!(
+                    "path={}",
+                    guest.disk_config.disk(DiskType::CloudInit).unwrap()
+                )
+                .as_str(),
+                format!("path={}", writeback_disk_path.to_str().unwrap()).as_str(),
+                format!(
+                    "path={},cache=writethrough",
+                    writethrough_disk_path.to_str().unwrap()
+                )
+                .as_str(),
+            ])
+            .default_net()
+            .capture_output()
+            .spawn()
+            .unwrap();
+
+        let r = std::panic::catch_unwind(|| {
+            guest.wait_vm_boot(None).unwrap();
+
+            let cache_type = |disk: &str| {
+                guest
+                    .ssh_command(&format!("cat /sys/block/{disk}/cache_type"))
+                    .unwrap()
+                    .trim()
+                    .to_string()
+            };
+            let flush_ops = || {
+                let (cmd_success, cmd_output) =
+                    remote_command_w_output(&api_socket, "counters", None);
+                assert!(cmd_success);
+                let counters: HashMap<&str, HashMap<&str, u64>> =
+                    serde_json::from_slice(&cmd_output).unwrap_or_default();
+                *counters.get("_disk2").unwrap().get("flush_ops").unwrap()
+            };
+            // Writes synced by the guest, which flushes the disk only with
+            // the write cache on.
+            let synced_write = || {
+                guest
+                    .ssh_command(
+                        "sudo dd if=/dev/urandom of=/dev/vdc bs=1M count=4 oflag=direct conv=fsync",
+                    )
+                    .unwrap();
+            };
+
+            // The host making every write durable, the guest sees no write
+            // cache.
+            assert_eq!(cache_type("vdc"), "write back");
+            assert_eq!(cache_type("vdd"), "write through");
+
+            let flushes = flush_ops();
+            synced_write();
+            assert!(flush_ops() > flushes);
+
+            // With the write cache off, the guest stops flushing, each write
+            // being synced by the host instead.
+            guest
+                .ssh_command("echo 'write through' | sudo tee /sys/block/vdc/cache_type")
+                .unwrap();
+            assert_eq!(cache_type("vdc"), "write through");
+            let flushes = flush_ops();
+            synced_write();
+            assert_eq!(flush_ops(), flushes);
+
+            guest
+                .ssh_command("echo 'write back' | sudo tee /sys/block/vdc/cache_type")
+                .unwrap();
+            assert_eq!(cache_type("vdc"), "write back");
+            let flushes = flush_ops();
+            synced_write();
+            assert!(flush_ops() > flushes);
+        });
+
+        kill_child(&mut child);
+        let output = child.wait_with_output().unwrap();
+
+        handle_child_output(r, &output);
+    }
+
     #[test]
     fn test_virtio_block_topology() {
         let focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
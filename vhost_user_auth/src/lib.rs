@@ -0,0 +1,512 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Authentication of the two ends of a vhost-user socket from a shared key.
+//!
+//! Once connected, and before any vhost-user message, the frontend sends
+//! `MAGIC` followed by a random nonce. The backend answers with its own
+//! nonce and the HMAC-SHA256 of `"backend"` and both nonces, the frontend
+//! nonce first. Once checked, the frontend proves it holds the key as well
+//! with the HMAC of `"frontend"` and both nonces, the backend nonce first, to
+//! which the backend answers with a single byte, `ACCEPTED` if the proof is
+//! valid. A backend refusing the frontend may close the connection instead.
+//!
+//! Both ends then derive a session key per direction from the shared key and
+//! the nonces, and every vhost-user message is carried in a frame holding
+//! its length, the number of file descriptors passed along, the message and
+//! the HMAC of a sequence number, the length, the number of file descriptors
+//! and the message, keyed with the session key of the direction. A message
+//! forged, altered, replayed, reordered or dropped on the way thus closes the
+//! connection rather than reaching the other end.
+//!
+//! The file descriptors passed along with the vhost-user messages can't go
+//! through a TLS session, hence the shared key rather than certificates, and
+//! only their number is authenticated.
+//!
+//! [`Session::relay`] relays the messages between the authenticated
+//! connection and a local socket, which the vhost-user implementation uses
+//! as it would the connection itself.
+
+#[macro_use]
+extern crate log;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::thread;
+use std::time::Duration;
+use vmm_sys_util::sock_ctrl_msg::ScmSocket;
+
+/// Sent by the frontend to open the handshake.
+pub const MAGIC: &[u8; 8] = b"CHVUAUTH";
+/// Sent by the backend once the frontend is authenticated.
+pub const ACCEPTED: u8 = 0;
+
+const NONCE_SIZE: usize = 32;
+const MAC_SIZE: usize = 32;
+// Time left to the peer for each step of the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A vhost-user message header holds the request, the flags and the size of
+// the payload, as 32 bits values.
+const MESSAGE_HEADER_SIZE: usize = 12;
+const MESSAGE_SIZE_OFFSET: usize = 8;
+// Largest vhost-user payload, and number of file descriptors passed along.
+const MAX_PAYLOAD_SIZE: usize = 0x1000;
+const MAX_FDS: usize = 32;
+// A frame header holds the length of the message and the number of file
+// descriptors passed along, as 32 bits values.
+const FRAME_HEADER_SIZE: usize = 8;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn mac(key: &[u8], label: &[u8], first: &[u8], second: &[u8]) -> HmacSha256 {
+    // HMAC takes keys of any length.
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    mac.update(label);
+    mac.update(first);
+    mac.update(second);
+    mac
+}
+
+fn nonce() -> io::Result<[u8; NONCE_SIZE]> {
+    let mut nonce = [0u8; NONCE_SIZE];
+    getrandom::getrandom(&mut nonce)?;
+    Ok(nonce)
+}
+
+fn denied(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, message)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn with_timeout<F>(stream: &mut UnixStream, handshake: F) -> io::Result<()>
+where
+    F: FnOnce(&mut UnixStream) -> io::Result<()>,
+{
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    handshake(stream)?;
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(None)
+}
+
+/// Authenticates the backend at the other end of `stream` from `key`,
+/// proving to it that the frontend holds the key as well.
+pub fn authenticate_backend(mut stream: UnixStream, key: &[u8]) -> io::Result<Session> {
+    let frontend_nonce = nonce()?;
+    let mut backend_nonce = [0u8; NONCE_SIZE];
+
+    with_timeout(&mut stream, |stream| {
+        stream.write_all(MAGIC)?;
+        stream.write_all(&frontend_nonce)?;
+
+        let mut backend_mac = [0u8; MAC_SIZE];
+        stream.read_exact(&mut backend_nonce)?;
+        stream.read_exact(&mut backend_mac)?;
+        mac(key, b"backend", &frontend_nonce, &backend_nonce)
+            .verify_slice(&backend_mac)
+            .map_err(|_| denied("The backend doesn't hold the authentication key"))?;
+
+        let frontend_mac = mac(key, b"frontend", &backend_nonce, &frontend_nonce);
+        stream.write_all(&frontend_mac.finalize().into_bytes())?;
+
+        let mut status = [0u8; 1];
+        stream.read_exact(&mut status)?;
+        if status[0] != ACCEPTED {
+            return Err(denied("The backend refused the authentication"));
+        }
+
+        Ok(())
+    })?;
+
+    Ok(Session {
+        stream,
+        send_key: session_key(key, b"to backend", &frontend_nonce, &backend_nonce),
+        receive_key: session_key(key, b"to frontend", &frontend_nonce, &backend_nonce),
+    })
+}
+
+/// Authenticates the frontend at the other end of `stream` from `key`, as
+/// a backend does.
+pub fn authenticate_frontend(mut stream: UnixStream, key: &[u8]) -> io::Result<Session> {
+    let backend_nonce = nonce()?;
+    let mut frontend_nonce = [0u8; NONCE_SIZE];
+
+    with_timeout(&mut stream, |stream| {
+        let mut magic = [0u8; MAGIC.len()];
+        stream.read_exact(&mut magic)?;
+        if magic != *MAGIC {
+            return Err(denied("The frontend didn't open an authentication"));
+        }
+        stream.read_exact(&mut frontend_nonce)?;
+
+        let backend_mac = mac(key, b"backend", &frontend_nonce, &backend_nonce);
+        stream.write_all(&backend_nonce)?;
+        stream.write_all(&backend_mac.finalize().into_bytes())?;
+
+        let mut frontend_mac = [0u8; MAC_SIZE];
+        stream.read_exact(&mut frontend_mac)?;
+        mac(key, b"frontend", &backend_nonce, &frontend_nonce)
+            .verify_slice(&frontend_mac)
+            .map_err(|_| denied("The frontend doesn't hold the authentication key"))?;
+        stream.write_all(&[ACCEPTED])
+    })?;
+
+    Ok(Session {
+        stream,
+        send_key: session_key(key, b"to frontend", &frontend_nonce, &backend_nonce),
+        receive_key: session_key(key, b"to backend", &frontend_nonce, &backend_nonce),
+    })
+}
+
+fn session_key(
+    key: &[u8],
+    direction: &[u8],
+    frontend_nonce: &[u8],
+    backend_nonce: &[u8],
+) -> Vec<u8> {
+    mac(key, direction, frontend_nonce, backend_nonce)
+        .finalize()
+        .into_bytes()
+        .to_vec()
+}
+
+/// An authenticated connection, whose messages go through [`Session::relay`].
+pub struct Session {
+    stream: UnixStream,
+    send_key: Vec<u8>,
+    receive_key: Vec<u8>,
+}
+
+impl Session {
+    /// Relays the vhost-user messages between `local` and the other end of
+    /// the session, authenticating them, until either connection closes.
+    ///
+    /// Both directions are relayed from threads of their own, and a message
+    /// failing its authentication closes both connections.
+    pub fn relay(self, local: UnixStream) -> io::Result<()> {
+        let peer = self.stream;
+        let (local_sender, peer_receiver) = (local.try_clone()?, peer.try_clone()?);
+
+        // The threads are left unnamed, naming them being one more system
+        // call for the seccomp filters of the threads connecting the backend.
+        thread::Builder::new().spawn(move || {
+            let result = seal(&local, &peer, &self.send_key);
+            close(&local, &peer, result);
+        })?;
+        thread::Builder::new().spawn(move || {
+            let result = open(&peer_receiver, &local_sender, &self.receive_key);
+            close(&local_sender, &peer_receiver, result);
+        })?;
+
+        Ok(())
+    }
+
+    /// Relays the session as [`Session::relay`] does, to the returned end of
+    /// a new socket pair.
+    pub fn into_stream(self) -> io::Result<UnixStream> {
+        let (local, stream) = UnixStream::pair()?;
+        self.relay(local)?;
+        Ok(stream)
+    }
+}
+
+fn close(local: &UnixStream, peer: &UnixStream, result: io::Result<()>) {
+    if let Err(e) = result {
+        error!("Closing the authenticated vhost-user connection: {}", e);
+    }
+    // Wakes the other direction up, and lets both ends know.
+    let _ = local.shutdown(Shutdown::Both);
+    let _ = peer.shutdown(Shutdown::Both);
+}
+
+fn frame_mac(key: &[u8], sequence: u64, header: &[u8], message: &[u8]) -> HmacSha256 {
+    // Session keys are HMAC outputs.
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    mac.update(&sequence.to_le_bytes());
+    mac.update(header);
+    mac.update(message);
+    mac
+}
+
+// Reads the vhost-user messages written to `local`, and sends them to
+// `peer` in authenticated frames.
+fn seal(local: &UnixStream, peer: &UnixStream, key: &[u8]) -> io::Result<()> {
+    for sequence in 0u64.. {
+        let mut files = Vec::new();
+        let mut message = vec![0u8; MESSAGE_HEADER_SIZE];
+        if !receive(local, &mut message, &mut files)? {
+            return Ok(());
+        }
+
+        let size = read_u32(&message, MESSAGE_SIZE_OFFSET) as usize;
+        if size > MAX_PAYLOAD_SIZE {
+            return Err(invalid("The vhost-user message is too large"));
+        }
+        message.resize(MESSAGE_HEADER_SIZE + size, 0);
+        if !receive(local, &mut message[MESSAGE_HEADER_SIZE..], &mut files)? {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+        header[..4].copy_from_slice(&(message.len() as u32).to_le_bytes());
+        header[4..].copy_from_slice(&(files.len() as u32).to_le_bytes());
+        let mac = frame_mac(key, sequence, &header, &message).finalize();
+
+        send(
+            peer,
+            &[&header[..], &message, &mac.into_bytes()].concat(),
+            &files,
+        )?;
+    }
+
+    Ok(())
+}
+
+// Reads the authenticated frames sent to `peer`, and writes their messages
+// to `local` once checked.
+fn open(peer: &UnixStream, local: &UnixStream, key: &[u8]) -> io::Result<()> {
+    for sequence in 0u64.. {
+        let mut files = Vec::new();
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+        if !receive(peer, &mut header, &mut files)? {
+            return Ok(());
+        }
+
+        let length = read_u32(&header, 0) as usize;
+        let fd_count = read_u32(&header, 4) as usize;
+        if length > MESSAGE_HEADER_SIZE + MAX_PAYLOAD_SIZE {
+            return Err(invalid("The authenticated frame is too large"));
+        }
+        let mut frame = vec![0u8; length + MAC_SIZE];
+        if !receive(peer, &mut frame, &mut files)? {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let (message, message_mac) = frame.split_at(length);
+        frame_mac(key, sequence, &header, message)
+            .verify_slice(message_mac)
+            .map_err(|_| denied("A vhost-user message failed its authentication"))?;
+        if files.len() != fd_count {
+            return Err(denied(
+                "A vhost-user message came with unexpected file descriptors",
+            ));
+        }
+
+        send(local, message, &files)?;
+    }
+
+    Ok(())
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+// Fills `buf` from `stream`, keeping the file descriptors received along in
+// `files`. Returns false if the connection closed before the first byte.
+fn receive(stream: &UnixStream, buf: &mut [u8], files: &mut Vec<File>) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let mut fds: [RawFd; MAX_FDS] = [0; MAX_FDS];
+        let mut iovecs = [libc::iovec {
+            iov_base: buf[filled..].as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len() - filled,
+        }];
+        // SAFETY: the iovec covers the part of buf left to fill.
+        let (count, fd_count) = match unsafe { stream.recv_with_fds(&mut iovecs, &mut fds) } {
+            Ok(received) => received,
+            Err(e) if e.errno() == libc::EINTR => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for fd in &fds[..fd_count] {
+            // SAFETY: the file descriptors received are ours.
+            files.push(unsafe { File::from_raw_fd(*fd) });
+        }
+        if files.len() > MAX_FDS {
+            return Err(invalid(
+                "Too many file descriptors for a vhost-user message",
+            ));
+        }
+
+        if count == 0 {
+            if filled == 0 {
+                return Ok(false);
+            }
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        filled += count;
+    }
+
+    Ok(true)
+}
+
+// Writes `buf` to `stream`, passing `files` along with its first bytes.
+fn send(stream: &UnixStream, buf: &[u8], files: &[File]) -> io::Result<()> {
+    let fds: Vec<RawFd> = files.iter().map(|f| f.as_raw_fd()).collect();
+    let sent = loop {
+        match stream.send_with_fds(&[buf], &fds) {
+            Ok(sent) => break sent,
+            Err(e) if e.errno() == libc::EINTR => continue,
+            Err(e) => return Err(e.into()),
+        }
+    };
+
+    let mut stream = stream;
+    stream.write_all(&buf[sent..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::eventfd::EventFd;
+
+    fn handshake(
+        frontend_key: &[u8],
+        backend_key: &'static [u8],
+    ) -> (io::Result<Session>, io::Result<Session>) {
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        let backend = thread::spawn(move || authenticate_frontend(backend, backend_key));
+        let frontend = authenticate_backend(frontend, frontend_key);
+
+        (frontend, backend.join().unwrap())
+    }
+
+    // Builds a vhost-user message of the request `request` with `payload`.
+    fn message(request: u32, payload: &[u8]) -> Vec<u8> {
+        let mut message = request.to_le_bytes().to_vec();
+        message.extend_from_slice(&1u32.to_le_bytes());
+        message.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        message.extend_from_slice(payload);
+        message
+    }
+
+    fn receive_message(stream: &UnixStream) -> (Vec<u8>, Vec<File>) {
+        let mut files = Vec::new();
+        let mut message = vec![0u8; MESSAGE_HEADER_SIZE];
+        assert!(receive(stream, &mut message, &mut files).unwrap());
+        let size = read_u32(&message, MESSAGE_SIZE_OFFSET) as usize;
+        message.resize(MESSAGE_HEADER_SIZE + size, 0);
+        assert!(receive(stream, &mut message[MESSAGE_HEADER_SIZE..], &mut files).unwrap());
+        (message, files)
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let (frontend, backend) = handshake(b"shared key", b"shared key");
+        let (frontend, backend) = (frontend.unwrap(), backend.unwrap());
+        assert_eq!(frontend.send_key, backend.receive_key);
+        assert_eq!(frontend.receive_key, backend.send_key);
+        assert_ne!(frontend.send_key, frontend.receive_key);
+    }
+
+    #[test]
+    fn test_authenticate_wrong_key() {
+        // The frontend refuses the backend before proving anything, and
+        // closes the connection dropping it.
+        let (frontend, backend) = handshake(b"shared key", b"other key");
+        assert_eq!(
+            frontend.err().unwrap().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        assert!(backend.is_err());
+    }
+
+    #[test]
+    fn test_authenticate_no_handshake() {
+        let (mut frontend, backend) = UnixStream::pair().unwrap();
+        frontend.write_all(&[0u8; 64]).unwrap();
+        assert_eq!(
+            authenticate_frontend(backend, b"shared key")
+                .err()
+                .unwrap()
+                .kind(),
+            io::ErrorKind::PermissionDenied
+        );
+    }
+
+    #[test]
+    fn test_relay() {
+        let (frontend, backend) = handshake(b"shared key", b"shared key");
+        let frontend = frontend.unwrap().into_stream().unwrap();
+        let backend = backend.unwrap().into_stream().unwrap();
+
+        // A request passing a file descriptor, and its reply.
+        let evt = EventFd::new(0).unwrap();
+        // SAFETY: dup() returns a new file descriptor, owned by the file.
+        let file = unsafe { File::from_raw_fd(libc::dup(evt.as_raw_fd())) };
+        let request = message(5, &[1, 2, 3, 4]);
+        send(&frontend, &request, &[file]).unwrap();
+        let (received, files) = receive_message(&backend);
+        assert_eq!(received, request);
+        assert_eq!(files.len(), 1);
+        (&files[0]).write_all(&7u64.to_le_bytes()).unwrap();
+        assert_eq!(evt.read().unwrap(), 7);
+
+        let reply = message(5, &[0; MAX_PAYLOAD_SIZE]);
+        send(&backend, &reply, &[]).unwrap();
+        let (received, files) = receive_message(&frontend);
+        assert_eq!(received, reply);
+        assert!(files.is_empty());
+
+        // Closing one end closes the other.
+        drop(frontend);
+        let mut buf = [0u8; 1];
+        assert_eq!((&backend).read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_relay_rejects_altered_message() {
+        let (frontend, proxy) = UnixStream::pair().unwrap();
+        let (proxied, backend) = UnixStream::pair().unwrap();
+
+        // Authenticates the backend through a proxy altering the first byte
+        // of the first message following the handshake.
+        let forward = thread::spawn(move || {
+            let read = |mut from: &UnixStream, len: usize| {
+                let mut buf = vec![0u8; len];
+                from.read_exact(&mut buf).unwrap();
+                buf
+            };
+            for (to_backend, len) in [
+                (true, MAGIC.len() + NONCE_SIZE),
+                (false, NONCE_SIZE + MAC_SIZE),
+                (true, MAC_SIZE),
+                (false, 1),
+            ] {
+                let (from, mut to) = if to_backend {
+                    (&proxy, &proxied)
+                } else {
+                    (&proxied, &proxy)
+                };
+                to.write_all(&read(from, len)).unwrap();
+            }
+
+            let mut buf = read(&proxy, FRAME_HEADER_SIZE + MESSAGE_HEADER_SIZE + MAC_SIZE);
+            buf[FRAME_HEADER_SIZE] ^= 1;
+            (&proxied).write_all(&buf).unwrap();
+        });
+        let backend = thread::spawn(move || authenticate_frontend(backend, b"shared key"));
+        let frontend = authenticate_backend(frontend, b"shared key")
+            .unwrap()
+            .into_stream()
+            .unwrap();
+        let backend = backend.join().unwrap().unwrap().into_stream().unwrap();
+
+        send(&frontend, &message(1, &[]), &[]).unwrap();
+        forward.join().unwrap();
+
+        // The backend connection closes rather than getting the message.
+        let mut buf = [0u8; 1];
+        assert_eq!((&backend).read(&mut buf).unwrap(), 0);
+    }
+}
@@ -17,12 +17,14 @@ use libc::EFD_NONBLOCK;
 use log::*;
 use option_parser::{OptionParser, OptionParserError, Toggle};
 use std::fs::File;
-use std::fs::OpenOptions;
+use std::fs::{DirBuilder, OpenOptions};
 use std::io::Read;
 use std::io::{Seek, SeekFrom, Write};
 use std::ops::Deref;
 use std::ops::DerefMut;
-use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::PathBuf;
 use std::process;
 use std::result;
@@ -74,12 +76,16 @@ enum Error {
     PathParameterMissing,
     /// No socket provided
     SocketParameterMissing,
+    /// Failed to read the authentication key
+    ReadAuthKey(io::Error),
+    /// Failed to authenticate the frontend
+    AuthenticateFrontend(io::Error),
 }
 
 pub const SYNTAX: &str = "vhost-user-block backend parameters \
  \"path=<image_path>,socket=<socket_path>,num_queues=<number_of_queues>,\
  queue_size=<size_of_each_queue>,readonly=true|false,direct=true|false,\
- poll_queue=true|false\"";
+ poll_queue=true|false,auth_key=<key_path>\"";
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -452,6 +458,7 @@ struct VhostUserBlkBackendConfig {
     readonly: bool,
     direct: bool,
     poll_queue: bool,
+    auth_key: Option<Vec<u8>>,
 }
 
 impl VhostUserBlkBackendConfig {
@@ -464,7 +471,8 @@ impl VhostUserBlkBackendConfig {
             .add("num_queues")
             .add("queue_size")
             .add("socket")
-            .add("poll_queue");
+            .add("poll_queue")
+            .add("auth_key");
         parser.parse(backend).map_err(Error::FailedConfigParse)?;
 
         let path = parser.get("path").ok_or(Error::PathParameterMissing)?;
@@ -492,6 +500,11 @@ impl VhostUserBlkBackendConfig {
             .convert("queue_size")
             .map_err(Error::FailedConfigParse)?
             .unwrap_or(1024);
+        let auth_key = parser
+            .get("auth_key")
+            .map(std::fs::read)
+            .transpose()
+            .map_err(Error::ReadAuthKey)?;
 
         Ok(VhostUserBlkBackendConfig {
             path,
@@ -501,10 +514,39 @@ impl VhostUserBlkBackendConfig {
             readonly,
             direct,
             poll_queue,
+            auth_key,
         })
     }
 }
 
+// Accepts the frontend connecting to `socket` and authenticates it from
+// `key`. The daemon only takes connections from a listener, so the relayed
+// connection is queued on a listener bound in a directory private to the
+// backend, both unlinked before the listener is returned.
+fn accept_authenticated(socket: &str, key: &[u8]) -> Result<Listener> {
+    let _ = std::fs::remove_file(socket);
+    let listener = UnixListener::bind(socket).map_err(Error::AuthenticateFrontend)?;
+    let (stream, _) = listener.accept().map_err(Error::AuthenticateFrontend)?;
+    let _ = std::fs::remove_file(socket);
+    let session =
+        vhost_user_auth::authenticate_frontend(stream, key).map_err(Error::AuthenticateFrontend)?;
+
+    let dir = std::env::temp_dir().join(format!("vhost_user_block-{}", process::id()));
+    let path = dir.join("socket");
+    let relayed = DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .and_then(|_| UnixListener::bind(&path))
+        .and_then(|listener| Ok((listener, UnixStream::connect(&path)?)));
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_dir(&dir);
+    let (listener, local) = relayed.map_err(Error::AuthenticateFrontend)?;
+    session.relay(local).map_err(Error::AuthenticateFrontend)?;
+
+    // SAFETY: the file descriptor of the listener is handed over.
+    Ok(unsafe { Listener::from_raw_fd(listener.into_raw_fd()) })
+}
+
 pub fn start_block_backend(backend_command: &str) {
     let backend_config = match VhostUserBlkBackendConfig::parse(backend_command) {
         Ok(config) => config,
@@ -531,7 +573,16 @@ pub fn start_block_backend(backend_command: &str) {
 
     debug!("blk_backend is created!\n");
 
-    let listener = Listener::new(&backend_config.socket, true).unwrap();
+    let listener = match &backend_config.auth_key {
+        Some(key) => match accept_authenticated(&backend_config.socket, key) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Failed to authenticate the frontend: {:?}", e);
+                process::exit(1);
+            }
+        },
+        None => Listener::new(&backend_config.socket, true).unwrap(),
+    };
 
     let name = "vhost-user-blk-backend";
     let mut blk_daemon = VhostUserDaemon::new(name.to_string(), blk_backend.clone(), mem).unwrap();
@@ -10,8 +10,9 @@
 
 use super::Error as DeviceError;
 use super::{
-    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, VirtioCommon,
-    VirtioDevice, VirtioDeviceType, VirtioInterruptType, EPOLL_HELPER_EVENT_LAST,
+    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler,
+    RateLimiterConfig, TokenBucketConfig, VirtioCommon, VirtioDevice, VirtioDeviceType,
+    VirtioInterruptType, EPOLL_HELPER_EVENT_LAST,
 };
 use crate::seccomp_filters::Thread;
 use crate::thread_helper::spawn_virtio_thread;
@@ -19,8 +20,21 @@ use crate::GuestMemoryMmap;
 use crate::VirtioInterrupt;
 use anyhow::anyhow;
 use block::{
-    async_io::AsyncIo, async_io::AsyncIoError, async_io::DiskFile, build_serial, Request,
-    RequestType, VirtioBlockConfig,
+    async_io::AsyncIo,
+    async_io::AsyncIoError,
+    async_io::DiskExtent,
+    async_io::DiskFile,
+    async_io::DiskFileError,
+    build_serial, completion_status,
+    dm_thin::{ThinPool, ThinPoolStatus},
+    latency::{BlockLatencySnapshot, LatencyCollector, MeteredAsyncIo},
+    lifetime::{DiskLifetime, VIRTIO_BLK_F_LIFETIME},
+    null_disk::NullDiskFile,
+    numa,
+    readahead::ReadaheadStats,
+    scrubber::{ScrubManifest, Scrubber},
+    serial_bytes, AsyncIoBackend, CacheMode, EnospcPolicy, ExecuteError, Request, RequestType,
+    TimeoutPolicy, VirtioBlockConfig,
 };
 use rate_limiter::group::{RateLimiterGroup, RateLimiterGroupHandle};
 use rate_limiter::TokenType;
@@ -28,7 +42,9 @@ use seccompiler::SeccompAction;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::collections::VecDeque;
+use std::fs::File;
 use std::io;
 use std::num::Wrapping;
 use std::ops::Deref;
@@ -36,15 +52,20 @@ use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
 use std::result;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::{Arc, Barrier};
+use std::sync::{Arc, Barrier, Condvar, Mutex};
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use virtio_bindings::virtio_blk::*;
 use virtio_bindings::virtio_config::*;
 use virtio_queue::{Queue, QueueOwnedT, QueueT};
-use vm_memory::{ByteValued, Bytes, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryError};
+use vm_memory::{
+    ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryAtomic,
+    GuestMemoryError, GuestMemoryRegion,
+};
 use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
 use vm_virtio::AccessPlatform;
-use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+use vmm_sys_util::timerfd::TimerFd;
 
 const SECTOR_SHIFT: u8 = 9;
 pub const SECTOR_SIZE: u64 = 0x01 << SECTOR_SHIFT;
@@ -55,10 +76,40 @@ const QUEUE_AVAIL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
 const COMPLETION_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
 // New 'wake up' event from the rate limiter
 const RATE_LIMITER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
+// New 'wake up' event from the read rate limiter
+const READ_RATE_LIMITER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 4;
+// New 'wake up' event from the write rate limiter
+const WRITE_RATE_LIMITER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 5;
+// The flush coalescing window expired.
+const FLUSH_TIMER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 6;
+// The write barrier has been raised or lifted.
+const WRITE_BARRIER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 7;
+// The writes have been held for as long as the write barrier allows.
+const WRITE_BARRIER_TIMER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 8;
+// No write may have been received for the idle flush interval.
+const IDLE_FLUSH_TIMER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 9;
+// The writes paused with the host storage full may be retried.
+const OUT_OF_SPACE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 10;
+// The requests in flight may have exceeded the request timeout.
+const REQUEST_TIMEOUT_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 11;
+// A new medium has been inserted into the removable device.
+const MEDIUM_CHANGE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 12;
 
 // latency scale, for reduce precision loss in calculate.
 const LATENCY_SCALE: u64 = 10000;
 
+// Number of logical blocks read at once by the disk scrubber.
+const SCRUB_CHUNK_BLOCKS: u64 = 256;
+
+// Maximum number of buffers handed over to the backend by a merged request.
+const MAX_MERGED_IOVECS: usize = libc::UIO_MAXIOV as usize;
+
+// User data of the flush submitted when quiescing the queue, which can't be
+// mistaken for a descriptor head.
+const QUIESCE_FLUSH_USER_DATA: u64 = u64::MAX;
+// User data of the flush submitted once the guest stopped writing.
+const IDLE_FLUSH_USER_DATA: u64 = u64::MAX - 1;
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Failed to parse the request: {0}")]
@@ -69,16 +120,52 @@ pub enum Error {
     RequestCompleting(block::Error),
     #[error("Missing the expected entry in the list of requests")]
     MissingEntryRequestList,
-    #[error("The asynchronous request returned with failure")]
-    AsyncRequestFailure,
     #[error("Failed synchronizing the file: {0}")]
     Fsync(AsyncIoError),
+    #[error("Failed submitting the requests: {0}")]
+    Submit(AsyncIoError),
+    #[error("Failed arming the flush timer: {0}")]
+    FlushTimer(io::Error),
     #[error("Failed adding used index: {0}")]
     QueueAddUsed(virtio_queue::Error),
     #[error("Failed creating an iterator over the queue: {0}")]
     QueueIterator(virtio_queue::Error),
     #[error("Failed to update request status: {0}")]
     RequestStatus(GuestMemoryError),
+    #[error("Failed to resize the disk: {0}")]
+    DiskResize(DiskFileError),
+    #[error("Failed to signal the configuration change: {0}")]
+    ConfigChangeSignal(io::Error),
+    #[error("Failed tracking the dirty blocks of the disk: {0}")]
+    DirtyTracking(DiskFileError),
+    #[error("Failed waiting for the completion of the requests: {0}")]
+    WaitCompletion(io::Error),
+    #[error("Failed creating the backend of the disk: {0}")]
+    CreateAsyncIo(DiskFileError),
+    #[error("Failed arming the write barrier timer: {0}")]
+    WriteBarrierTimer(io::Error),
+    #[error("Failed arming the idle flush timer: {0}")]
+    IdleFlushTimer(io::Error),
+    #[error("Failed notifying the queues of the write barrier: {0}")]
+    WriteBarrierEvent(io::Error),
+    #[error("Timed out draining the writes in flight")]
+    WriteBarrierTimeout,
+    #[error("Failed notifying the queues of the room made for the paused writes: {0}")]
+    OutOfSpaceEvent(io::Error),
+    #[error("Invalid number of queues {0}, the device has {1}")]
+    InvalidNumQueues(usize, usize),
+    #[error("The driver is using {0} queues")]
+    QueuesInUse(usize),
+    #[error("Failed reading the CPUs of host NUMA node {0}: {1}")]
+    HostNumaNode(u32, io::Error),
+    #[error("The disk is not removable")]
+    NotRemovable,
+    #[error("Failed getting the size of the medium: {0}")]
+    MediumSize(DiskFileError),
+    #[error("Failed notifying the queues of the medium change: {0}")]
+    MediumChangeEvent(io::Error),
+    #[error("The disk has no {0:?} rate limiter")]
+    NoRateLimiter(BlockRateLimiter),
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -97,6 +184,27 @@ pub struct BlockCounters {
     write_latency_min: Arc<AtomicU64>,
     write_latency_max: Arc<AtomicU64>,
     write_latency_avg: Arc<AtomicU64>,
+    // Compared with each other, these tell how many completions the
+    // backend signals at once.
+    completions: Arc<AtomicU64>,
+    completion_wakeups: Arc<AtomicU64>,
+    // Requests taken from the queues and not completed yet.
+    inflight_requests: Arc<AtomicU64>,
+    // Flush requests completed, including the ones served by the fsync of
+    // another request, which are also counted on their own, and the time
+    // spent in fsync, in microseconds.
+    flush_ops: Arc<AtomicU64>,
+    coalesced_flush_ops: Arc<AtomicU64>,
+    flush_time: Arc<AtomicU64>,
+    flush_time_max: Arc<AtomicU64>,
+    flush_rate: Arc<Mutex<FlushRate>>,
+    // Reads and writes whose buffers were copied through aligned ones, as
+    // the backend required, and the bytes copied.
+    bounced_ops: Arc<AtomicU64>,
+    bounced_bytes: Arc<AtomicU64>,
+    // Writes failed as the dm-thin pool backing the disk is out of data
+    // space.
+    thin_pool_full_errors: Arc<AtomicU64>,
 }
 
 impl Default for BlockCounters {
@@ -112,47 +220,448 @@ impl Default for BlockCounters {
             write_latency_min: Arc::new(AtomicU64::new(u64::MAX)),
             write_latency_max: Arc::new(AtomicU64::new(u64::MAX)),
             write_latency_avg: Arc::new(AtomicU64::new(u64::MAX)),
+            completions: Arc::new(AtomicU64::new(0)),
+            completion_wakeups: Arc::new(AtomicU64::new(0)),
+            inflight_requests: Arc::new(AtomicU64::new(0)),
+            flush_ops: Arc::new(AtomicU64::new(0)),
+            coalesced_flush_ops: Arc::new(AtomicU64::new(0)),
+            flush_time: Arc::new(AtomicU64::new(0)),
+            flush_time_max: Arc::new(AtomicU64::new(0)),
+            flush_rate: Arc::new(Mutex::new(FlushRate::new())),
+            bounced_ops: Arc::new(AtomicU64::new(0)),
+            bounced_bytes: Arc::new(AtomicU64::new(0)),
+            thin_pool_full_errors: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+impl BlockCounters {
+    // Accounts for an fsync having taken `latency` microseconds, serving a
+    // flush request along with `coalesced` other ones.
+    fn note_fsync(&self, latency: u64, coalesced: u64) {
+        let flushes = 1 + coalesced;
+        self.flush_ops.fetch_add(flushes, Ordering::AcqRel);
+        self.coalesced_flush_ops
+            .fetch_add(coalesced, Ordering::AcqRel);
+        self.flush_time.fetch_add(latency, Ordering::AcqRel);
+        self.flush_time_max.fetch_max(latency, Ordering::AcqRel);
+        self.flush_rate.lock().unwrap().add(flushes);
+    }
+}
+
+// Flush requests completed during the current second, and during the
+// previous one, which is the rate reported.
+struct FlushRate {
+    start: Instant,
+    second: u64,
+    current: u64,
+    previous: u64,
+}
+
+impl FlushRate {
+    fn new() -> Self {
+        FlushRate {
+            start: Instant::now(),
+            second: 0,
+            current: 0,
+            previous: 0,
+        }
+    }
+
+    fn rotate(&mut self) {
+        let second = self.start.elapsed().as_secs();
+        if second != self.second {
+            self.previous = if second == self.second + 1 {
+                self.current
+            } else {
+                0
+            };
+            self.current = 0;
+            self.second = second;
+        }
+    }
+
+    fn add(&mut self, flushes: u64) {
+        self.rotate();
+        self.current += flushes;
+    }
+
+    fn per_second(&mut self) -> u64 {
+        self.rotate();
+        self.previous
+    }
+}
+
+// Run of adjacent discard requests popped from the queue, handed over to the
+// backend as a single discard.
+struct PendingDiscard {
+    offset: u64,
+    length: u64,
+    heads: Vec<u16>,
+}
+
+impl PendingDiscard {
+    fn new(offset: u64, length: u64) -> Self {
+        PendingDiscard {
+            offset,
+            length,
+            heads: Vec::new(),
+        }
+    }
+
+    // Extends the run with the range of another discard request, as long as
+    // both ranges are contiguous or overlap, so that nothing the guest didn't
+    // ask for gets discarded, and the run stays within `max_length` bytes,
+    // 0 if unlimited.
+    fn merge(&mut self, offset: u64, length: u64, max_length: u64) -> bool {
+        let end = self.offset + self.length;
+        if offset > end || offset + length < self.offset {
+            return false;
+        }
+
+        let merged_start = self.offset.min(offset);
+        let merged_end = end.max(offset + length);
+        if max_length != 0 && merged_end - merged_start > max_length {
+            return false;
         }
+        self.offset = merged_start;
+        self.length = merged_end - merged_start;
+        true
+    }
+}
+
+// Read or write request popped from the queue, held until it goes through
+// the merge pass.
+struct PendingIo {
+    head: u16,
+    request_type: RequestType,
+    offset: u64,
+    length: u64,
+    iovecs: Vec<libc::iovec>,
+}
+
+// Flushes the disk once the guest stopped writing for a while, bounding the
+// data lost on a host crash when the guest rarely flushes.
+struct IdleFlush {
+    timer: TimerFd,
+    interval: Duration,
+    armed: bool,
+    // Time of the last write submitted since the disk was last flushed.
+    last_write: Option<Instant>,
+    in_flight: bool,
+}
+
+// Reports the requests in flight for longer than the timeout, checked
+// several times per timeout, and cancels them under the cancel policy.
+struct RequestTimeout {
+    timer: TimerFd,
+    timeout: Duration,
+    policy: TimeoutPolicy,
+    // Heads of the requests in flight reported already, so that each is
+    // reported once.
+    reported: HashSet<u16>,
+}
+
+impl RequestTimeout {
+    fn new(timeout: Duration, policy: TimeoutPolicy) -> vmm_sys_util::errno::Result<Self> {
+        let mut timer = TimerFd::new()?;
+        // A request is reported at most a quarter of the timeout late.
+        let interval = (timeout / 4).max(Duration::from_millis(1));
+        timer.reset(interval, Some(interval))?;
+
+        Ok(RequestTimeout {
+            timer,
+            timeout,
+            policy,
+            reported: HashSet::new(),
+        })
+    }
+
+    fn report(&self, id: &str, queue_index: u16, request: &Request, elapsed: Duration) {
+        let offset = request.sector << SECTOR_SHIFT;
+        warn!(
+            "{:?} request at offset {} on queue {} of disk {} in flight for {} ms",
+            request.request_type,
+            offset,
+            queue_index,
+            id,
+            elapsed.as_millis()
+        );
+        event!(
+            "block",
+            "request-timeout",
+            "id",
+            id,
+            "type",
+            format!("{:?}", request.request_type),
+            "offset",
+            offset.to_string()
+        );
     }
 }
 
+// Shared by the queues of a device to hold the writes of the guest, so
+// that the disk can be captured in a consistent state.
+#[derive(Default)]
+struct WriteBarrier {
+    state: Mutex<WriteBarrierState>,
+    // Signaled whenever a queue has drained its writes.
+    drained: Condvar,
+}
+
+#[derive(Default)]
+struct WriteBarrierState {
+    // How long the writes are held for at most, none if they go through.
+    timeout: Option<Duration>,
+    // Number of queues which drained their writes since the barrier was
+    // raised.
+    drained_queues: usize,
+}
+
+// Shared by the queues of a device to hold its writes once the host storage
+// is full, until the operator resumes the device.
+struct OutOfSpace {
+    // Id of the device, reported along with the event.
+    id: String,
+    paused: AtomicBool,
+    // Whether the dm-thin pool backing the disk was last found out of data
+    // space, reported once until a write succeeds again.
+    thin_pool_full: AtomicBool,
+}
+
+// Hands the medium inserted into a removable device over to a queue.
+struct MediumChange {
+    disk_image: Arc<Mutex<Option<Box<dyn AsyncIo>>>>,
+    ring_depth: u32,
+    evt: EventFd,
+}
+
 struct BlockEpollHandler {
     queue_index: u16,
     queue: Queue,
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
     disk_image: Box<dyn AsyncIo>,
-    disk_nsectors: u64,
+    disk_nsectors: Arc<AtomicU64>,
     interrupt_cb: Arc<dyn VirtioInterrupt>,
     serial: Vec<u8>,
+    // Lifetime of the host storage, read on activation, if the guest
+    // acknowledged VIRTIO_BLK_F_LIFETIME.
+    lifetime: Option<DiskLifetime>,
     kill_evt: EventFd,
     pause_evt: EventFd,
     writeback: Arc<AtomicBool>,
+    // Whether the host cache mode already makes every write durable, with
+    // the write cache turned off by the guest or not.
+    durable_writes: bool,
+    // Whether the discards are submitted as write zeroes with unmap, the
+    // disk not guaranteeing the ranges discarded to read back as zeroes.
+    zeroing_discards: bool,
     counters: BlockCounters,
     queue_evt: EventFd,
     inflight_requests: VecDeque<(u16, Request)>,
+    // Heads of the requests completed along with the merged request
+    // submitted on behalf of the head used as key.
+    merged_requests: HashMap<u16, Vec<u16>>,
+    // When set, contiguous reads or writes are submitted as a single
+    // request of up to this many bytes.
+    max_merge_size: Option<u64>,
+    // When set, no more descriptors are popped from the queue while this
+    // many requests are in flight, until completions make room for more.
+    max_inflight: Option<usize>,
+    // Whether descriptors were left on the avail ring because of the
+    // in-flight limit, or because the backend had no room left for them.
+    inflight_limit_reached: bool,
+    // When set, flush requests are held until the timer expires, and all
+    // of those held meanwhile are served by a single fsync.
+    flush_timer: Option<(TimerFd, Duration)>,
+    pending_flushes: Vec<u16>,
+    idle_flush: Option<IdleFlush>,
+    request_timeout: Option<RequestTimeout>,
     rate_limiter: Option<RateLimiterGroupHandle>,
+    read_rate_limiter: Option<RateLimiterGroupHandle>,
+    write_rate_limiter: Option<RateLimiterGroupHandle>,
     access_platform: Option<Arc<dyn AccessPlatform>>,
     read_only: bool,
+    // Largest number of buffers and buffer size in bytes advertised to the
+    // guest for a request, 0 if unlimited.
+    seg_max: u32,
+    size_max: u32,
+    // Largest discard and write zeroes in sectors advertised to the guest,
+    // 0 if the request isn't supported.
+    max_discard_sectors: u32,
+    max_write_zeroes_sectors: u32,
     host_cpus: Option<Vec<usize>>,
+    write_barrier: Arc<WriteBarrier>,
+    write_barrier_evt: EventFd,
+    // Lets the writes through once the barrier has been raised for too long,
+    // should the caller never lift it.
+    write_barrier_timer: TimerFd,
+    writes_held: bool,
+    // Writes popped from the queue while the barrier is raised or the
+    // device is paused out of space, along with their heads.
+    held_writes: VecDeque<(u16, Request)>,
+    enospc_policy: EnospcPolicy,
+    out_of_space: Arc<OutOfSpace>,
+    out_of_space_evt: EventFd,
+    // Medium inserted into a removable device, served once the requests in
+    // flight on the previous one complete.
+    new_medium: Arc<Mutex<Option<Box<dyn AsyncIo>>>>,
+    medium_evt: EventFd,
+    thin_pool: Option<Arc<ThinPool>>,
+}
+
+// Blocks until the backend signals new completions.
+fn wait_completions(disk_image: &dyn AsyncIo) -> Result<()> {
+    let mut pollfd = libc::pollfd {
+        fd: disk_image.notifier().as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    loop {
+        // SAFETY: FFI call with a valid pollfd
+        if unsafe { libc::poll(&mut pollfd, 1, -1) } >= 0 {
+            break;
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(Error::WaitCompletion(e));
+        }
+    }
+    // The completions are all picked up, the event can be consumed.
+    let _ = disk_image.notifier().read();
+
+    Ok(())
 }
 
 impl BlockEpollHandler {
-    fn process_queue_submit(&mut self) -> Result<bool> {
-        let queue = &mut self.queue;
+    fn request_bytes(request: &Request) -> u64 {
+        let mut bytes = Wrapping(0);
+        for (_, data_len) in &request.data_descriptors {
+            bytes += Wrapping(*data_len as u64);
+        }
+        bytes.0
+    }
+
+    // Whether the buffers of the request exceed the limits advertised to the
+    // guest, which the backend can't be trusted to handle.
+    fn exceeds_limits(&self, request: &Request) -> bool {
+        (self.seg_max != 0 && request.data_descriptors.len() > self.seg_max as usize)
+            || (self.size_max != 0
+                && request
+                    .data_descriptors
+                    .iter()
+                    .any(|(_, data_len)| *data_len > self.size_max))
+    }
+
+    // Whether the range of a discard or write zeroes request exceeds the
+    // size advertised to the guest. A segment which can't be read is left
+    // for the submission to fail.
+    fn exceeds_zeroing_limits(&self, request: &Request, mem: &GuestMemoryMmap) -> bool {
+        let max_sectors = if request.request_type == RequestType::Discard {
+            self.max_discard_sectors
+        } else {
+            self.max_write_zeroes_sectors
+        };
+        max_sectors != 0
+            && request
+                .discard_write_zeroes_segment(mem, self.disk_nsectors.load(Ordering::Acquire))
+                .is_ok_and(|segment| segment.num_sectors > max_sectors)
+    }
+
+    // Consume the budget needed by the request from the given rate limiter,
+    // returning false without consuming anything if the budget is exhausted.
+    fn consume_rate_limit(rate_limiter: &RateLimiterGroupHandle, request: &Request) -> bool {
+        // If limiter.consume() fails it means there is no more TokenType::Ops
+        // budget and rate limiting is in effect.
+        if !rate_limiter.consume(1, TokenType::Ops) {
+            return false;
+        }
+        // Exercise the rate limiter only if this request is of data transfer
+        // type. Discards and write zeroes carry no payload, their cost is the
+        // operation alone.
+        if request.request_type == RequestType::In || request.request_type == RequestType::Out {
+            // If limiter.consume() fails it means there is no more TokenType::Bytes
+            // budget and rate limiting is in effect.
+            if !rate_limiter.consume(Self::request_bytes(request), TokenType::Bytes) {
+                // Revert the OPS consume().
+                rate_limiter.manual_replenish(1, TokenType::Ops);
+                return false;
+            }
+        }
+        true
+    }
+
+    // Give back the budget consumed by consume_rate_limit().
+    fn replenish_rate_limit(rate_limiter: &RateLimiterGroupHandle, request: &Request) {
+        rate_limiter.manual_replenish(1, TokenType::Ops);
+        if request.request_type == RequestType::In || request.request_type == RequestType::Out {
+            rate_limiter.manual_replenish(Self::request_bytes(request), TokenType::Bytes);
+        }
+    }
 
+    fn process_queue_submit(&mut self) -> Result<bool> {
         let mut used_descs = false;
+        let mut pending_discard: Option<PendingDiscard> = None;
+        let mut pending_io: Vec<PendingIo> = Vec::new();
+
+        while let Some(mut desc_chain) = self.queue.pop_descriptor_chain(self.mem.memory()) {
+            // The requests held for the merge pass only reach the backend
+            // once the avail ring has been walked, so the room it has left
+            // must cover them as well.
+            let held = pending_io.len() + usize::from(pending_discard.is_some());
+            if self
+                .max_inflight
+                .is_some_and(|max| self.inflight_requests.len() >= max)
+                || self.disk_image.submission_room() <= held
+            {
+                // Leave the descriptor chain on the avail ring, the queue
+                // being processed again once some requests complete.
+                self.queue.go_to_previous_position();
+                self.inflight_limit_reached = true;
+                break;
+            }
 
-        while let Some(mut desc_chain) = queue.pop_descriptor_chain(self.mem.memory()) {
             let mut request = Request::parse(&mut desc_chain, self.access_platform.as_ref())
                 .map_err(Error::RequestParsing)?;
 
             // For virtio spec compliance
             // "A device MUST set the status byte to VIRTIO_BLK_S_IOERR for a write request
             // if the VIRTIO_BLK_F_RO feature if offered, and MUST NOT write any data."
-            if self.read_only
+            let read_only_violation = self.read_only
                 && (request.request_type == RequestType::Out
-                    || request.request_type == RequestType::Flush)
-            {
+                    || request.request_type == RequestType::Flush
+                    || request.request_type == RequestType::Discard
+                    || request.request_type == RequestType::WriteZeroes);
+            // Requests larger than advertised are failed rather than
+            // truncated or handed over to the backend.
+            let limits_violation = match request.request_type {
+                RequestType::In | RequestType::Out => self.exceeds_limits(&request),
+                RequestType::Discard | RequestType::WriteZeroes => {
+                    self.exceeds_zeroing_limits(&request, desc_chain.memory())
+                }
+                _ => false,
+            };
+            if limits_violation {
+                warn!(
+                    "Request exceeding the advertised limits: seg_max = {}, size_max = {}, \
+                     max_discard_sectors = {}, max_write_zeroes_sectors = {}",
+                    self.seg_max,
+                    self.size_max,
+                    self.max_discard_sectors,
+                    self.max_write_zeroes_sectors
+                );
+            }
+            // A misaligned range can't be fixed up without reading around it,
+            // so unlike the misaligned buffers, which get bounced, it fails.
+            let alignment = self.disk_image.required_alignment();
+            let alignment_violation = !request.is_aligned(alignment);
+            if alignment_violation {
+                warn!(
+                    "Request not aligned on the {} bytes required by the disk",
+                    alignment.offset
+                );
+            }
+            if read_only_violation || limits_violation || alignment_violation {
                 desc_chain
                     .memory()
                     .write_obj(VIRTIO_BLK_S_IOERR, request.status_addr)
@@ -160,168 +669,867 @@ impl BlockEpollHandler {
 
                 // If no asynchronous operation has been submitted, we can
                 // simply return the used descriptor.
-                queue
+                self.queue
                     .add_used(desc_chain.memory(), desc_chain.head_index(), 0)
                     .map_err(Error::QueueAddUsed)?;
                 used_descs = true;
                 continue;
             }
 
-            if let Some(rate_limiter) = &mut self.rate_limiter {
-                // If limiter.consume() fails it means there is no more TokenType::Ops
-                // budget and rate limiting is in effect.
-                if !rate_limiter.consume(1, TokenType::Ops) {
+            if let Some(rate_limiter) = &self.rate_limiter {
+                if !Self::consume_rate_limit(rate_limiter, &request) {
                     // Stop processing the queue and return this descriptor chain to the
                     // avail ring, for later processing.
-                    queue.go_to_previous_position();
+                    self.queue.go_to_previous_position();
                     break;
                 }
-                // Exercise the rate limiter only if this request is of data transfer type.
-                if request.request_type == RequestType::In
-                    || request.request_type == RequestType::Out
-                {
-                    let mut bytes = Wrapping(0);
-                    for (_, data_len) in &request.data_descriptors {
-                        bytes += Wrapping(*data_len as u64);
-                    }
+            }
 
-                    // If limiter.consume() fails it means there is no more TokenType::Bytes
-                    // budget and rate limiting is in effect.
-                    if !rate_limiter.consume(bytes.0, TokenType::Bytes) {
-                        // Revert the OPS consume().
-                        rate_limiter.manual_replenish(1, TokenType::Ops);
-                        // Stop processing the queue and return this descriptor chain to the
-                        // avail ring, for later processing.
-                        queue.go_to_previous_position();
-                        break;
+            // Discards and write zeroes modify the disk, so they count against
+            // the write operations, or a guest could issue as many as it likes
+            // under a write limit.
+            let directional_rate_limiter = match request.request_type {
+                RequestType::In => self.read_rate_limiter.as_ref(),
+                RequestType::Out | RequestType::Discard | RequestType::WriteZeroes => {
+                    self.write_rate_limiter.as_ref()
+                }
+                _ => None,
+            };
+            if let Some(rate_limiter) = directional_rate_limiter {
+                if !Self::consume_rate_limit(rate_limiter, &request) {
+                    // Revert what has been consumed from the disk wide budget.
+                    if let Some(rate_limiter) = &self.rate_limiter {
+                        Self::replenish_rate_limit(rate_limiter, &request);
                     }
-                };
+                    self.queue.go_to_previous_position();
+                    break;
+                }
             }
 
             request.set_writeback(self.writeback.load(Ordering::Acquire));
 
-            if request
-                .execute_async(
-                    desc_chain.memory(),
-                    self.disk_nsectors,
-                    self.disk_image.as_mut(),
-                    &self.serial,
-                    desc_chain.head_index() as u64,
-                )
-                .map_err(Error::RequestExecuting)?
+            // While the writes are held, the other requests go through, which
+            // the guest doesn't expect to be ordered with the writes anyway.
+            // The writes keep being held behind those left for lack of room
+            // in the backend.
+            if (self.writes_held_or_paused() || !self.held_writes.is_empty())
+                && request.request_type.modifies_disk()
             {
-                self.inflight_requests
+                self.held_writes
                     .push_back((desc_chain.head_index(), request));
-            } else {
-                desc_chain
-                    .memory()
-                    .write_obj(VIRTIO_BLK_S_OK, request.status_addr)
-                    .map_err(Error::RequestStatus)?;
+                continue;
+            }
 
-                // If no asynchronous operation has been submitted, we can
-                // simply return the used descriptor.
-                queue
-                    .add_used(desc_chain.memory(), desc_chain.head_index(), 0)
+            used_descs |= self.submit_request(
+                desc_chain.head_index(),
+                request,
+                &mut pending_discard,
+                &mut pending_io,
+            )?;
+        }
+
+        self.submit_io(pending_io)?;
+        used_descs |= self.submit_discard(pending_discard.take())?;
+
+        // Requests may only have been queued by the backend, hand them all
+        // over at once now that the available ring has been walked.
+        self.disk_image.submit().map_err(Error::Submit)?;
+
+        Ok(used_descs)
+    }
+
+    // Hands a request over to the backend, or holds it for the merge pass,
+    // returning whether used descriptors have been added to the queue.
+    fn submit_request(
+        &mut self,
+        head: u16,
+        mut request: Request,
+        pending_discard: &mut Option<PendingDiscard>,
+        pending_io: &mut Vec<PendingIo>,
+    ) -> Result<bool> {
+        let mem = self.mem.memory();
+        let mut used_descs = false;
+
+        if request.request_type.modifies_disk() {
+            self.note_write()?;
+        }
+
+        // Reads and writes are held for the merge pass until any other
+        // request shows up, so that nothing gets merged across a flush or
+        // a discard.
+        let mergeable = self.max_merge_size.is_some()
+            && (request.request_type == RequestType::In
+                || request.request_type == RequestType::Out);
+        if !mergeable {
+            self.submit_io(std::mem::take(pending_io))?;
+        }
+
+        // Adjacent discard requests, typically sent in bursts by fstrim,
+        // are merged into a single discard. A run of discards is never
+        // extended past any other request, preserving the ordering the
+        // guest relies on.
+        if request.request_type == RequestType::Discard {
+            let segment = request
+                .discard_write_zeroes_segment(
+                    mem.deref(),
+                    self.disk_nsectors.load(Ordering::Acquire),
+                )
+                .map_err(Error::RequestExecuting)?;
+            let offset = segment.sector << SECTOR_SHIFT;
+            let length = u64::from(segment.num_sectors) << SECTOR_SHIFT;
+
+            let max_length = u64::from(self.max_discard_sectors) << SECTOR_SHIFT;
+            let merged = pending_discard
+                .as_mut()
+                .is_some_and(|discard| discard.merge(offset, length, max_length));
+            if !merged {
+                used_descs |= self.submit_discard(pending_discard.take())?;
+                *pending_discard = Some(PendingDiscard::new(offset, length));
+            }
+            if let Some(discard) = pending_discard.as_mut() {
+                discard.heads.push(head);
+            }
+            self.add_inflight(head, request);
+            return Ok(used_descs);
+        }
+        used_descs |= self.submit_discard(pending_discard.take())?;
+
+        if mergeable {
+            let iovecs = request
+                .data_iovecs(
+                    mem.deref(),
+                    self.disk_nsectors.load(Ordering::Acquire),
+                    self.disk_image.required_alignment(),
+                )
+                .map_err(Error::RequestExecuting)?;
+            pending_io.push(PendingIo {
+                head,
+                request_type: request.request_type,
+                offset: request.sector << SECTOR_SHIFT,
+                length: Self::request_bytes(&request),
+                iovecs: iovecs.to_vec(),
+            });
+            self.add_inflight(head, request);
+            return Ok(used_descs);
+        }
+
+        // The flush is only submitted once the coalescing window expires,
+        // so that it covers all the writes completed until then.
+        if request.request_type == RequestType::Flush {
+            if let Some((timer, window)) = &mut self.flush_timer {
+                if self.pending_flushes.is_empty() {
+                    timer
+                        .reset(*window, None)
+                        .map_err(|e| Error::FlushTimer(e.into()))?;
+                }
+                self.pending_flushes.push(head);
+                self.add_inflight(head, request);
+                return Ok(used_descs);
+            }
+        }
+
+        let submitted = match request.execute_async(
+            mem.deref(),
+            self.disk_nsectors.load(Ordering::Acquire),
+            self.disk_image.as_mut(),
+            &self.serial,
+            self.lifetime.as_ref(),
+            head as u64,
+        ) {
+            Err(
+                e @ (ExecuteError::AsyncDiscard(AsyncIoError::DiscardNotSupported)
+                | ExecuteError::AsyncWriteZeroes(AsyncIoError::WriteZeroesNotSupported)),
+            ) => {
+                warn!("Unsupported request: {}", e);
+                mem.write_obj(e.status(), request.status_addr)
+                    .map_err(Error::RequestStatus)?;
+                self.queue
+                    .add_used(mem.deref(), head, 0)
                     .map_err(Error::QueueAddUsed)?;
-                used_descs = true;
+                return Ok(true);
             }
+            r => r.map_err(Error::RequestExecuting)?,
+        };
+
+        if submitted {
+            self.add_inflight(head, request);
+        } else {
+            mem.write_obj(VIRTIO_BLK_S_OK, request.status_addr)
+                .map_err(Error::RequestStatus)?;
+
+            // If no asynchronous operation has been submitted, we can
+            // simply return the used descriptor.
+            self.queue
+                .add_used(mem.deref(), head, 0)
+                .map_err(Error::QueueAddUsed)?;
+            used_descs = true;
         }
 
         Ok(used_descs)
     }
 
-    fn process_queue_submit_and_signal(&mut self) -> result::Result<(), EpollHelperError> {
-        let needs_notification = self.process_queue_submit().map_err(|e| {
-            EpollHelperError::HandleEvent(anyhow!("Failed to process queue (submit): {:?}", e))
-        })?;
+    // Submits the run of discard requests as a single discard, returning
+    // whether used descriptors have been added to the queue.
+    fn submit_discard(&mut self, discard: Option<PendingDiscard>) -> Result<bool> {
+        let discard = match discard {
+            Some(discard) => discard,
+            None => return Ok(false),
+        };
 
-        if needs_notification {
-            self.signal_used_queue().map_err(|e| {
-                EpollHelperError::HandleEvent(anyhow!("Failed to signal used queue: {:?}", e))
-            })?
+        // The merged discard completes on behalf of the first request. Should
+        // the disk not guarantee the range to read back as zeroes, the range
+        // is zeroed and unmapped instead.
+        let leader = discard.heads[0];
+        let offset = discard.offset as libc::off_t;
+        let submitted = if self.zeroing_discards {
+            self.disk_image
+                .write_zeroes(offset, discard.length, true, leader as u64)
+                .map_err(ExecuteError::AsyncWriteZeroes)
+        } else {
+            self.disk_image
+                .discard(offset, discard.length, leader as u64)
+                .map_err(ExecuteError::AsyncDiscard)
         };
+        match submitted {
+            Ok(()) => {
+                if discard.heads.len() > 1 {
+                    self.merged_requests
+                        .insert(leader, discard.heads[1..].to_vec());
+                }
+                Ok(false)
+            }
+            Err(
+                e @ (ExecuteError::AsyncDiscard(AsyncIoError::DiscardNotSupported)
+                | ExecuteError::AsyncWriteZeroes(AsyncIoError::WriteZeroesNotSupported)),
+            ) => {
+                warn!("Unsupported request: {}", e);
+                let mem = self.mem.memory();
+                for head in discard.heads {
+                    let request = self.find_inflight_request(head)?;
+                    mem.write_obj(e.status(), request.status_addr)
+                        .map_err(Error::RequestStatus)?;
+                    self.queue
+                        .add_used(mem.deref(), head, 0)
+                        .map_err(Error::QueueAddUsed)?;
+                }
+                Ok(true)
+            }
+            Err(e) => Err(Error::RequestExecuting(e)),
+        }
+    }
+
+    // Merge pass over the held reads and writes: sorted by offset, each run
+    // of contiguous requests in the same direction is handed over to the
+    // backend as a single request, whose buffers are those of the original
+    // requests.
+    fn submit_io(&mut self, mut pending_io: Vec<PendingIo>) -> Result<()> {
+        let max_merge_size = self.max_merge_size.unwrap_or_default();
+        pending_io.sort_by_key(|io| io.offset);
+
+        let mut pending_io = pending_io.into_iter().peekable();
+        while let Some(leader) = pending_io.next() {
+            let mut iovecs = leader.iovecs;
+            let mut end = leader.offset + leader.length;
+            let mut heads = Vec::new();
+            while let Some(io) = pending_io.next_if(|io| {
+                io.request_type == leader.request_type
+                    && io.offset == end
+                    && end + io.length - leader.offset <= max_merge_size
+                    && iovecs.len() + io.iovecs.len() <= MAX_MERGED_IOVECS
+            }) {
+                iovecs.extend(io.iovecs);
+                end += io.length;
+                heads.push(io.head);
+            }
+
+            let offset = leader.offset as libc::off_t;
+            let priority = leader.request_type.priority();
+            if leader.request_type == RequestType::In {
+                self.disk_image
+                    .read_vectored_with_priority(offset, &iovecs, leader.head as u64, priority)
+                    .map_err(|e| Error::RequestExecuting(ExecuteError::AsyncRead(e)))?;
+            } else {
+                self.disk_image
+                    .write_vectored_with_priority(offset, &iovecs, leader.head as u64, priority)
+                    .map_err(|e| Error::RequestExecuting(ExecuteError::AsyncWrite(e)))?;
+            }
+            if !heads.is_empty() {
+                self.merged_requests.insert(leader.head, heads);
+            }
+        }
 
         Ok(())
     }
 
-    #[inline]
-    fn find_inflight_request(&mut self, completed_head: u16) -> Result<Request> {
-        // This loop neatly handles the fast path where the completions are
-        // in order (it turns into just a pop_front()) and the 1% of the time
-        // (analysis during boot) where slight out of ordering has been
-        // observed e.g.
-        // Submissions: 1 2 3 4 5 6 7
-        // Completions: 2 1 3 5 4 7 6
-        // In this case find the corresponding item and swap it with the front
-        // This is a O(1) operation and is prepared for the future as it it likely
-        // the next completion would be for the one that was skipped which will
-        // now be the new front.
-        for (i, (head, _)) in self.inflight_requests.iter().enumerate() {
-            if head == &completed_head {
-                return Ok(self.inflight_requests.swap_remove_front(i).unwrap().1);
+    // Submits a single fsync on behalf of all the flush requests held during
+    // the coalescing window. A flush request arriving from now on waits for
+    // the next window, as this fsync may not cover the writes completed after
+    // it was submitted.
+    fn submit_flushes(&mut self) -> Result<()> {
+        if self.pending_flushes.is_empty() {
+            return Ok(());
+        }
+
+        let heads = std::mem::take(&mut self.pending_flushes);
+        let leader = heads[0];
+        // The time spent in fsync only starts once the window expired.
+        if let Some((_, request)) = self
+            .inflight_requests
+            .iter_mut()
+            .find(|(head, _)| *head == leader)
+        {
+            request.start = Instant::now();
+        }
+        self.disk_image
+            .fsync_with_priority(Some(leader as u64), RequestType::Flush.priority())
+            .map_err(|e| Error::RequestExecuting(ExecuteError::AsyncFlush(e)))?;
+        if heads.len() > 1 {
+            self.merged_requests.insert(leader, heads[1..].to_vec());
+        }
+        self.disk_image.submit().map_err(Error::Submit)
+    }
+
+    // Records a write for the idle flush, which is checked for once the
+    // interval expires rather than rearming the timer on every write.
+    fn note_write(&mut self) -> Result<()> {
+        if let Some(idle_flush) = &mut self.idle_flush {
+            idle_flush.last_write = Some(Instant::now());
+            if !idle_flush.armed {
+                idle_flush
+                    .timer
+                    .reset(idle_flush.interval, None)
+                    .map_err(|e| Error::IdleFlushTimer(e.into()))?;
+                idle_flush.armed = true;
             }
         }
 
-        Err(Error::MissingEntryRequestList)
+        Ok(())
     }
 
-    fn process_queue_complete(&mut self) -> Result<bool> {
-        let mut used_descs = false;
-        let mem = self.mem.memory();
-        let mut read_bytes = Wrapping(0);
-        let mut write_bytes = Wrapping(0);
-        let mut read_ops = Wrapping(0);
-        let mut write_ops = Wrapping(0);
+    // Flushes the disk if no write has been submitted for the idle flush
+    // interval, checking again later otherwise.
+    fn flush_idle_disk(&mut self) -> Result<()> {
+        // The guest isn't done while writes are in flight, and a flush in
+        // flight would be duplicated.
+        let busy = !self.pending_flushes.is_empty()
+            || self.inflight_requests.iter().any(|(_, request)| {
+                request.request_type == RequestType::Flush || request.request_type.modifies_disk()
+            });
+        let Some(idle_flush) = &mut self.idle_flush else {
+            return Ok(());
+        };
+        idle_flush.armed = false;
+        let Some(last_write) = idle_flush.last_write else {
+            return Ok(());
+        };
 
-        while let Some((user_data, result)) = self.disk_image.next_completed_request() {
-            let desc_index = user_data as u16;
+        let idle_time = last_write.elapsed();
+        if busy || idle_flush.in_flight || idle_time < idle_flush.interval {
+            let delay = idle_flush.interval.saturating_sub(idle_time);
+            idle_flush
+                .timer
+                .reset(
+                    if delay.is_zero() {
+                        idle_flush.interval
+                    } else {
+                        delay
+                    },
+                    None,
+                )
+                .map_err(|e| Error::IdleFlushTimer(e.into()))?;
+            idle_flush.armed = true;
+            return Ok(());
+        }
 
-            let mut request = self.find_inflight_request(desc_index)?;
+        idle_flush.last_write = None;
+        idle_flush.in_flight = true;
+        self.disk_image
+            .fsync(Some(IDLE_FLUSH_USER_DATA))
+            .map_err(Error::Fsync)?;
+        self.disk_image.submit().map_err(Error::Submit)
+    }
 
-            request.complete_async().map_err(Error::RequestCompleting)?;
+    fn complete_idle_flush(&mut self, result: i32) -> Result<()> {
+        if let Some(idle_flush) = &mut self.idle_flush {
+            idle_flush.in_flight = false;
+        }
+        if result < 0 {
+            warn!(
+                "Failed flushing the idle disk on queue {}: {}",
+                self.queue_index,
+                io::Error::from_raw_os_error(-result)
+            );
+            // Tried again once the interval expires.
+            self.note_write()?;
+        }
 
-            let latency = request.start.elapsed().as_micros() as u64;
-            let read_ops_last = self.counters.read_ops.load(Ordering::Relaxed);
-            let write_ops_last = self.counters.write_ops.load(Ordering::Relaxed);
-            let read_max = self.counters.read_latency_max.load(Ordering::Relaxed);
-            let write_max = self.counters.write_latency_max.load(Ordering::Relaxed);
-            let mut read_avg = self.counters.read_latency_avg.load(Ordering::Relaxed);
-            let mut write_avg = self.counters.write_latency_avg.load(Ordering::Relaxed);
-            let (status, len) = if result >= 0 {
-                match request.request_type {
-                    RequestType::In => {
-                        for (_, data_len) in &request.data_descriptors {
-                            read_bytes += Wrapping(*data_len as u64);
-                        }
-                        read_ops += Wrapping(1);
-                        if latency < self.counters.read_latency_min.load(Ordering::Relaxed) {
-                            self.counters
-                                .read_latency_min
-                                .store(latency, Ordering::Relaxed);
-                        }
-                        if latency > read_max || read_max == u64::MAX {
-                            self.counters
-                                .read_latency_max
-                                .store(latency, Ordering::Relaxed);
-                        }
+        Ok(())
+    }
 
-                        // Special case the first real latency report
-                        read_avg = if read_avg == u64::MAX {
-                            latency * LATENCY_SCALE
-                        } else {
-                            // Cumulative average is guaranteed to be
-                            // positive if being calculated properly
-                            (read_avg as i64
-                                + ((latency * LATENCY_SCALE) as i64 - read_avg as i64)
-                                    / (read_ops_last + read_ops.0) as i64)
-                                .try_into()
-                                .unwrap()
-                        };
-                    }
-                    RequestType::Out => {
-                        if !request.writeback {
-                            self.disk_image.fsync(None).map_err(Error::Fsync)?;
-                        }
+    // Blocks until the backend signals new completions.
+    fn wait_completions(&self) -> Result<()> {
+        wait_completions(self.disk_image.as_ref())
+    }
+
+    // Completes every request popped from the queue, returning whether used
+    // descriptors have been added to the queue.
+    fn drain_inflight(&mut self) -> Result<bool> {
+        self.submit_flushes()?;
+
+        let mut used_descs = false;
+        loop {
+            used_descs |= self.process_queue_complete()?;
+            let idle_flushing = self
+                .idle_flush
+                .as_ref()
+                .is_some_and(|idle_flush| idle_flush.in_flight);
+            if self.inflight_requests.is_empty() && !idle_flushing {
+                return Ok(used_descs);
+            }
+            self.wait_completions()?;
+        }
+    }
+
+    // Flushes the disk once nothing else is in flight, returning the result
+    // of the flush.
+    fn flush_disk(&mut self) -> Result<i32> {
+        self.disk_image
+            .fsync(Some(QUIESCE_FLUSH_USER_DATA))
+            .map_err(Error::Fsync)?;
+        self.disk_image.submit().map_err(Error::Submit)?;
+        loop {
+            if let Some((_, result)) = self.disk_image.next_completed_request() {
+                return Ok(result);
+            }
+            self.wait_completions()?;
+        }
+    }
+
+    // Completes every request popped from the queue and persists the data
+    // written, so that the used ring accounts for all the descriptors taken
+    // from the avail ring. Restoring a snapshot resumes processing the avail
+    // ring from the used index, a request straddling the snapshot would be
+    // either lost or completed twice.
+    fn quiesce_queue(&mut self) -> result::Result<(), EpollHelperError> {
+        let needs_notification = self.drain_inflight().map_err(|e| {
+            EpollHelperError::HandleEvent(anyhow!("Failed to drain the queue: {:?}", e))
+        })?;
+        if needs_notification {
+            self.signal_used_queue().map_err(|e| {
+                EpollHelperError::HandleEvent(anyhow!("Failed to signal used queue: {:?}", e))
+            })?;
+        }
+
+        let result = self.flush_disk().map_err(|e| {
+            EpollHelperError::HandleEvent(anyhow!("Failed to flush the disk: {:?}", e))
+        })?;
+        if result < 0 {
+            error!(
+                "Failed flushing the disk while quiescing queue {}: {}",
+                self.queue_index,
+                io::Error::from_raw_os_error(-result)
+            );
+        }
+
+        // The descriptors left on the avail ring because of the in-flight
+        // limit would not be processed again without completions, so kick
+        // the queue for them to be once resumed.
+        if self.inflight_limit_reached {
+            self.inflight_limit_reached = false;
+            self.queue_evt.write(1).map_err(|e| {
+                EpollHelperError::HandleEvent(anyhow!("Failed to kick the queue: {:?}", e))
+            })?;
+        }
+
+        Ok(())
+    }
+
+    // Submits the writes held by the barrier, returning whether used
+    // descriptors have been added to the queue.
+    fn submit_held_writes(&mut self) -> Result<bool> {
+        let mut used_descs = false;
+        let mut pending_discard: Option<PendingDiscard> = None;
+        let mut pending_io: Vec<PendingIo> = Vec::new();
+        // The writes the backend has no room for stay held, until some
+        // requests complete.
+        while self.disk_image.submission_room()
+            > pending_io.len() + usize::from(pending_discard.is_some())
+        {
+            let Some((head, request)) = self.held_writes.pop_front() else {
+                break;
+            };
+            used_descs |=
+                self.submit_request(head, request, &mut pending_discard, &mut pending_io)?;
+        }
+        self.submit_io(pending_io)?;
+        used_descs |= self.submit_discard(pending_discard.take())?;
+        self.disk_image.submit().map_err(Error::Submit)?;
+
+        Ok(used_descs)
+    }
+
+    // Submits the held writes, draining the requests in flight whenever the
+    // backend runs out of room for them, until each of them has been
+    // submitted once. Returns whether used descriptors have been added to
+    // the queue.
+    fn settle_held_writes(&mut self) -> Result<bool> {
+        let mut used_descs = false;
+        let mut unsubmitted = self.held_writes.len();
+        while unsubmitted > 0 {
+            let held = self.held_writes.len();
+            used_descs |= self.submit_held_writes()?;
+            unsubmitted = unsubmitted.saturating_sub(held - self.held_writes.len());
+            used_descs |= self.drain_inflight()?;
+        }
+
+        Ok(used_descs)
+    }
+
+    fn writes_held_or_paused(&self) -> bool {
+        self.writes_held || self.out_of_space.paused.load(Ordering::Acquire)
+    }
+
+    // Lets the writes through again, starting with the held ones.
+    fn release_writes(&mut self) -> result::Result<(), EpollHelperError> {
+        self.writes_held = false;
+        self.write_barrier_timer.clear().map_err(|e| {
+            EpollHelperError::HandleEvent(anyhow!(
+                "Failed to disarm the write barrier timer: {:?}",
+                e
+            ))
+        })?;
+
+        self.retry_held_writes()
+    }
+
+    // Submits the held writes, unless the barrier is raised or the device
+    // is paused out of space.
+    fn retry_held_writes(&mut self) -> result::Result<(), EpollHelperError> {
+        if self.writes_held_or_paused() {
+            return Ok(());
+        }
+
+        let needs_notification = self.submit_held_writes().map_err(|e| {
+            EpollHelperError::HandleEvent(anyhow!("Failed to submit the held writes: {:?}", e))
+        })?;
+        if needs_notification {
+            self.signal_used_queue().map_err(|e| {
+                EpollHelperError::HandleEvent(anyhow!("Failed to signal used queue: {:?}", e))
+            })?;
+        }
+
+        Ok(())
+    }
+
+    // Follows the state of the write barrier: once raised, the writes in
+    // flight are drained and flushed before the new ones get held.
+    fn update_write_barrier(&mut self) -> result::Result<(), EpollHelperError> {
+        let timeout = self.write_barrier.state.lock().unwrap().timeout;
+        match timeout {
+            Some(timeout) if !self.writes_held => {
+                self.quiesce_queue()?;
+                self.writes_held = true;
+                self.write_barrier_timer.reset(timeout, None).map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!(
+                        "Failed to arm the write barrier timer: {:?}",
+                        e
+                    ))
+                })?;
+                self.write_barrier.state.lock().unwrap().drained_queues += 1;
+                self.write_barrier.drained.notify_all();
+            }
+            None if self.writes_held => self.release_writes()?,
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn process_queue_submit_and_signal(&mut self) -> result::Result<(), EpollHelperError> {
+        let needs_notification = self.process_queue_submit().map_err(|e| {
+            EpollHelperError::HandleEvent(anyhow!("Failed to process queue (submit): {:?}", e))
+        })?;
+
+        if needs_notification {
+            self.signal_used_queue().map_err(|e| {
+                EpollHelperError::HandleEvent(anyhow!("Failed to signal used queue: {:?}", e))
+            })?
+        };
+
+        Ok(())
+    }
+
+    #[inline]
+    fn add_inflight(&mut self, head: u16, request: Request) {
+        self.counters
+            .inflight_requests
+            .fetch_add(1, Ordering::AcqRel);
+        let bounced_bytes = request.bounced_bytes();
+        if bounced_bytes > 0 {
+            self.counters.bounced_ops.fetch_add(1, Ordering::AcqRel);
+            self.counters
+                .bounced_bytes
+                .fetch_add(bounced_bytes, Ordering::AcqRel);
+        }
+        self.inflight_requests.push_back((head, request));
+    }
+
+    fn find_inflight_request(&mut self, completed_head: u16) -> Result<Request> {
+        // This loop neatly handles the fast path where the completions are
+        // in order (it turns into just a pop_front()) and the 1% of the time
+        // (analysis during boot) where slight out of ordering has been
+        // observed e.g.
+        // Submissions: 1 2 3 4 5 6 7
+        // Completions: 2 1 3 5 4 7 6
+        // In this case find the corresponding item and swap it with the front
+        // This is a O(1) operation and is prepared for the future as it it likely
+        // the next completion would be for the one that was skipped which will
+        // now be the new front.
+        for (i, (head, _)) in self.inflight_requests.iter().enumerate() {
+            if head == &completed_head {
+                self.counters
+                    .inflight_requests
+                    .fetch_sub(1, Ordering::AcqRel);
+                let request = self.inflight_requests.swap_remove_front(i).unwrap().1;
+                self.check_completion_time(completed_head, &request);
+                return Ok(request);
+            }
+        }
+
+        Err(Error::MissingEntryRequestList)
+    }
+
+    // Reports the requests in flight past the request timeout, skipping the
+    // flushes held for coalescing, which aren't submitted yet.
+    fn check_request_timeouts(&mut self) {
+        let Some(request_timeout) = &mut self.request_timeout else {
+            return;
+        };
+
+        for (head, request) in &self.inflight_requests {
+            let elapsed = request.start.elapsed();
+            if elapsed < request_timeout.timeout
+                || self.pending_flushes.contains(head)
+                || !request_timeout.reported.insert(*head)
+            {
+                continue;
+            }
+            request_timeout.report(&self.out_of_space.id, self.queue_index, request, elapsed);
+
+            if request_timeout.policy == TimeoutPolicy::Cancel {
+                // The request completes with an error if canceled, or as
+                // usual once it couldn't be.
+                match self.disk_image.cancel(*head as u64) {
+                    Ok(true) => (),
+                    Ok(false) => warn!(
+                        "The backend of disk {} can't cancel requests",
+                        self.out_of_space.id
+                    ),
+                    Err(e) => warn!("Failed canceling a request: {:?}", e),
+                }
+            }
+        }
+    }
+
+    // Reports a request completing past the request timeout, unless it was
+    // while in flight. The synchronous backends block the queue until the
+    // request completes, so they are only reported then.
+    fn check_completion_time(&mut self, head: u16, request: &Request) {
+        let Some(request_timeout) = &mut self.request_timeout else {
+            return;
+        };
+
+        let elapsed = request.start.elapsed();
+        if request_timeout.reported.remove(&head) {
+            info!(
+                "Request on queue {} of disk {} completed after {} ms",
+                self.queue_index,
+                self.out_of_space.id,
+                elapsed.as_millis()
+            );
+        } else if elapsed >= request_timeout.timeout {
+            request_timeout.report(&self.out_of_space.id, self.queue_index, request, elapsed);
+        }
+    }
+
+    // Holds a write which failed with the host storage full, along with the
+    // ones merged into it, to be retried once the device is resumed. The
+    // first queue running out of space pauses the writes of the whole
+    // device and reports it.
+    fn pause_out_of_space(
+        &mut self,
+        head: u16,
+        request: Request,
+        merged_heads: Option<Vec<u16>>,
+    ) -> Result<()> {
+        self.held_writes.push_back((head, request));
+        for head in merged_heads.into_iter().flatten() {
+            let mut request = self.find_inflight_request(head)?;
+            request.complete_async().map_err(Error::RequestCompleting)?;
+            self.held_writes.push_back((head, request));
+        }
+
+        if !self.out_of_space.paused.swap(true, Ordering::AcqRel) {
+            warn!(
+                "The host disk is full, pausing the writes to disk {}",
+                self.out_of_space.id
+            );
+            event!("block", "out-of-space", "id", &self.out_of_space.id);
+        }
+
+        Ok(())
+    }
+
+    // Tells the writes failing as the dm-thin pool backing the disk is out
+    // of data space, which the pool reports with either EIO or ENOSPC
+    // depending on its no-space policy, from the other failures. They are
+    // reported as the host storage being full, which lets them be paused.
+    fn thin_pool_result(&self, thin_pool: &ThinPool, result: i32) -> i32 {
+        if result >= 0 {
+            if self.out_of_space.thin_pool_full.load(Ordering::Relaxed) {
+                self.out_of_space
+                    .thin_pool_full
+                    .store(false, Ordering::Relaxed);
+            }
+            return result;
+        }
+        if result != -libc::EIO && result != -libc::ENOSPC {
+            return result;
+        }
+
+        match thin_pool.status() {
+            Ok(status) if status.out_of_data_space => {
+                self.counters
+                    .thin_pool_full_errors
+                    .fetch_add(1, Ordering::AcqRel);
+                if !self
+                    .out_of_space
+                    .thin_pool_full
+                    .swap(true, Ordering::AcqRel)
+                {
+                    let (major, minor) = thin_pool.device();
+                    error!(
+                        "The thin pool {}:{} backing disk {} is out of data space \
+                         ({}/{} blocks used), writes fail until it is grown",
+                        major,
+                        minor,
+                        self.out_of_space.id,
+                        status.data_used_blocks,
+                        status.data_total_blocks
+                    );
+                    event!(
+                        "block",
+                        "thin-pool-full",
+                        "id",
+                        &self.out_of_space.id,
+                        "pool",
+                        format!("{major}:{minor}")
+                    );
+                }
+                -libc::ENOSPC
+            }
+            Ok(_) => result,
+            Err(e) => {
+                warn!("Failed reading the status of the thin pool: {}", e);
+                result
+            }
+        }
+    }
+
+    fn process_queue_complete(&mut self) -> Result<bool> {
+        let mut used_descs = false;
+        let mem = self.mem.memory();
+        let mut read_bytes = Wrapping(0);
+        let mut write_bytes = Wrapping(0);
+        let mut read_ops = Wrapping(0);
+        let mut write_ops = Wrapping(0);
+        let mut completions = Wrapping(0);
+
+        while let Some((user_data, result)) = self.disk_image.next_completed_request() {
+            if user_data == IDLE_FLUSH_USER_DATA {
+                self.complete_idle_flush(result)?;
+                continue;
+            }
+            let desc_index = user_data as u16;
+            completions += Wrapping(1);
+
+            let mut request = self.find_inflight_request(desc_index)?;
+            let merged_heads = self.merged_requests.remove(&desc_index);
+
+            request.complete_async().map_err(Error::RequestCompleting)?;
+
+            let result = match &self.thin_pool {
+                Some(thin_pool) if request.request_type.modifies_disk() => {
+                    self.thin_pool_result(thin_pool, result)
+                }
+                _ => result,
+            };
+
+            if result == -libc::ENOSPC
+                && self.enospc_policy == EnospcPolicy::Pause
+                && request.request_type.modifies_disk()
+            {
+                self.pause_out_of_space(desc_index, request, merged_heads)?;
+                continue;
+            }
+
+            // With the write cache turned off by the guest, a write only
+            // completes once durable, failing otherwise.
+            let result = if result >= 0
+                && request.request_type == RequestType::Out
+                && !request.writeback
+                && !self.durable_writes
+            {
+                match self.disk_image.fsync(None) {
+                    Ok(()) => result,
+                    Err(e) => {
+                        error!("Failed to sync a write: {:?}", e);
+                        -libc::EIO
+                    }
+                }
+            } else {
+                result
+            };
+
+            let latency = request.start.elapsed().as_micros() as u64;
+            let read_ops_last = self.counters.read_ops.load(Ordering::Relaxed);
+            let write_ops_last = self.counters.write_ops.load(Ordering::Relaxed);
+            let read_max = self.counters.read_latency_max.load(Ordering::Relaxed);
+            let write_max = self.counters.write_latency_max.load(Ordering::Relaxed);
+            let mut read_avg = self.counters.read_latency_avg.load(Ordering::Relaxed);
+            let mut write_avg = self.counters.write_latency_avg.load(Ordering::Relaxed);
+            let (status, len) = if result >= 0 {
+                match request.request_type {
+                    RequestType::In => {
+                        for (_, data_len) in &request.data_descriptors {
+                            read_bytes += Wrapping(*data_len as u64);
+                        }
+                        read_ops += Wrapping(1);
+                        if latency < self.counters.read_latency_min.load(Ordering::Relaxed) {
+                            self.counters
+                                .read_latency_min
+                                .store(latency, Ordering::Relaxed);
+                        }
+                        if latency > read_max || read_max == u64::MAX {
+                            self.counters
+                                .read_latency_max
+                                .store(latency, Ordering::Relaxed);
+                        }
+
+                        // Special case the first real latency report
+                        read_avg = if read_avg == u64::MAX {
+                            latency * LATENCY_SCALE
+                        } else {
+                            // Cumulative average is guaranteed to be
+                            // positive if being calculated properly
+                            (read_avg as i64
+                                + ((latency * LATENCY_SCALE) as i64 - read_avg as i64)
+                                    / (read_ops_last + read_ops.0) as i64)
+                                .try_into()
+                                .unwrap()
+                        };
+                    }
+                    RequestType::Out => {
                         for (_, data_len) in &request.data_descriptors {
                             write_bytes += Wrapping(*data_len as u64);
                         }
@@ -350,6 +1558,10 @@ impl BlockEpollHandler {
                                 .unwrap()
                         }
                     }
+                    RequestType::Flush => {
+                        let coalesced = merged_heads.as_ref().map_or(0, |heads| heads.len());
+                        self.counters.note_fsync(latency, coalesced as u64);
+                    }
                     _ => {}
                 }
 
@@ -361,14 +1573,31 @@ impl BlockEpollHandler {
                     .write_latency_avg
                     .store(write_avg, Ordering::Relaxed);
 
-                (VIRTIO_BLK_S_OK, result as u32)
+                // A merged read or write transferred the data of all the
+                // requests it covers, each being completed with its own size.
+                let len = if merged_heads.is_some()
+                    && (request.request_type == RequestType::In
+                        || request.request_type == RequestType::Out)
+                {
+                    Self::request_bytes(&request) as u32
+                } else {
+                    result as u32
+                };
+
+                (VIRTIO_BLK_S_OK, len)
             } else {
-                error!(
-                    "Request failed: {:x?} {:?}",
-                    request,
-                    io::Error::from_raw_os_error(-result)
-                );
-                return Err(Error::AsyncRequestFailure);
+                // The failure is reported to the guest, whose queue keeps
+                // being served.
+                let e = io::Error::from_raw_os_error(-result);
+                if -result == libc::ENOSPC {
+                    error!(
+                        "Request failed, the host disk is full: {:x?} {:?}",
+                        request, e
+                    );
+                } else {
+                    error!("Request failed: {:x?} {:?}", request, e);
+                }
+                (completion_status(result), 0)
             };
 
             mem.write_obj(status, request.status_addr)
@@ -380,6 +1609,34 @@ impl BlockEpollHandler {
                 .add_used(mem.deref(), desc_index, len)
                 .map_err(Error::QueueAddUsed)?;
             used_descs = true;
+
+            // Each request merged into the completed one gets its own
+            // completion.
+            if let Some(heads) = merged_heads {
+                for head in heads {
+                    let mut request = self.find_inflight_request(head)?;
+                    request.complete_async().map_err(Error::RequestCompleting)?;
+                    let len = match request.request_type {
+                        RequestType::In | RequestType::Out if status == VIRTIO_BLK_S_OK => {
+                            let bytes = Self::request_bytes(&request);
+                            if request.request_type == RequestType::In {
+                                read_bytes += Wrapping(bytes);
+                                read_ops += Wrapping(1);
+                            } else {
+                                write_bytes += Wrapping(bytes);
+                                write_ops += Wrapping(1);
+                            }
+                            bytes as u32
+                        }
+                        _ => len,
+                    };
+                    mem.write_obj(status, request.status_addr)
+                        .map_err(Error::RequestStatus)?;
+                    self.queue
+                        .add_used(mem.deref(), head, len)
+                        .map_err(Error::QueueAddUsed)?;
+                }
+            }
         }
 
         self.counters
@@ -396,6 +1653,10 @@ impl BlockEpollHandler {
             .read_ops
             .fetch_add(read_ops.0, Ordering::AcqRel);
 
+        self.counters
+            .completions
+            .fetch_add(completions.0, Ordering::AcqRel);
+
         Ok(used_descs)
     }
 
@@ -454,30 +1715,161 @@ impl BlockEpollHandler {
         if let Some(rate_limiter) = &self.rate_limiter {
             helper.add_event(rate_limiter.as_raw_fd(), RATE_LIMITER_EVENT)?;
         }
+        if let Some(rate_limiter) = &self.read_rate_limiter {
+            helper.add_event(rate_limiter.as_raw_fd(), READ_RATE_LIMITER_EVENT)?;
+        }
+        if let Some(rate_limiter) = &self.write_rate_limiter {
+            helper.add_event(rate_limiter.as_raw_fd(), WRITE_RATE_LIMITER_EVENT)?;
+        }
+        if let Some((timer, _)) = &self.flush_timer {
+            helper.add_event(timer.as_raw_fd(), FLUSH_TIMER_EVENT)?;
+        }
+        if let Some(idle_flush) = &self.idle_flush {
+            helper.add_event(idle_flush.timer.as_raw_fd(), IDLE_FLUSH_TIMER_EVENT)?;
+        }
+        if let Some(request_timeout) = &self.request_timeout {
+            helper.add_event(request_timeout.timer.as_raw_fd(), REQUEST_TIMEOUT_EVENT)?;
+        }
+        helper.add_event(self.write_barrier_evt.as_raw_fd(), WRITE_BARRIER_EVENT)?;
+        helper.add_event(
+            self.write_barrier_timer.as_raw_fd(),
+            WRITE_BARRIER_TIMER_EVENT,
+        )?;
+        helper.add_event(self.out_of_space_evt.as_raw_fd(), OUT_OF_SPACE_EVENT)?;
+        helper.add_event(self.medium_evt.as_raw_fd(), MEDIUM_CHANGE_EVENT)?;
         self.set_queue_thread_affinity();
         helper.run(paused, paused_sync, self)?;
 
-        Ok(())
+        self.shutdown_queue()
     }
-}
-
-impl EpollHelperHandler for BlockEpollHandler {
-    fn handle_event(
-        &mut self,
-        _helper: &mut EpollHelper,
-        event: &epoll::Event,
-    ) -> result::Result<(), EpollHelperError> {
-        let ev_type = event.data as u16;
-        match ev_type {
-            QUEUE_AVAIL_EVENT => {
-                self.queue_evt.read().map_err(|e| {
-                    EpollHelperError::HandleEvent(anyhow!("Failed to get queue event: {:?}", e))
-                })?;
 
-                let rate_limit_reached =
-                    self.rate_limiter.as_ref().map_or(false, |r| r.is_blocked());
+    // Completes the requests popped from the queue and persists the data
+    // written before the queue is torn down, as the device is removed or
+    // reset, not to lose any write the backend has yet to acknowledge. The
+    // requests the guest submits meanwhile are left on the avail ring until
+    // then, and failed as there is no device left to serve them. So are the
+    // writes paused out of space, which can't wait for room anymore, while
+    // those held by the barrier can't wait for it to be lowered either.
+    fn shutdown_queue(&mut self) -> result::Result<(), EpollHelperError> {
+        self.enospc_policy = EnospcPolicy::Report;
+        self.writes_held = false;
+        self.settle_queue()?;
 
-                // Process the queue only when the rate limit is not reached
+        let needs_notification = self.reject_avail_requests().map_err(|e| {
+            EpollHelperError::HandleEvent(anyhow!("Failed to reject the requests: {:?}", e))
+        })?;
+        if needs_notification {
+            self.signal_used_queue().map_err(|e| {
+                EpollHelperError::HandleEvent(anyhow!("Failed to signal used queue: {:?}", e))
+            })?;
+        }
+
+        Ok(())
+    }
+
+    // Fails the requests left on the avail ring, returning whether used
+    // descriptors have been added to the queue.
+    fn reject_avail_requests(&mut self) -> Result<bool> {
+        let mut used_descs = false;
+        while let Some(mut desc_chain) = self.queue.pop_descriptor_chain(self.mem.memory()) {
+            let request = Request::parse(&mut desc_chain, self.access_platform.as_ref())
+                .map_err(Error::RequestParsing)?;
+            desc_chain
+                .memory()
+                .write_obj(VIRTIO_BLK_S_IOERR, request.status_addr)
+                .map_err(Error::RequestStatus)?;
+            self.queue
+                .add_used(desc_chain.memory(), desc_chain.head_index(), 0)
+                .map_err(Error::QueueAddUsed)?;
+            used_descs = true;
+        }
+
+        Ok(used_descs)
+    }
+
+    // Completes the requests popped from the queue, including the writes
+    // held for lack of room in the backend or out of space, and flushes the
+    // disk. Those held by the barrier stay held, not to reach the disk
+    // being captured, which is why the device refuses to pause while the
+    // barrier is raised.
+    fn settle_queue(&mut self) -> result::Result<(), EpollHelperError> {
+        // The held writes were popped from the queue, they must complete
+        // for the used ring to account for them. Those paused out of space
+        // are retried once, and held again should they still not fit.
+        let needs_notification = if self.writes_held {
+            false
+        } else {
+            self.settle_held_writes().map_err(|e| {
+                EpollHelperError::HandleEvent(anyhow!("Failed to submit the held writes: {:?}", e))
+            })?
+        };
+        if needs_notification {
+            self.signal_used_queue().map_err(|e| {
+                EpollHelperError::HandleEvent(anyhow!("Failed to signal used queue: {:?}", e))
+            })?;
+        }
+
+        self.quiesce_queue()
+    }
+
+    // Serves the next requests from the medium inserted, if any, once
+    // those in flight on the previous one completed.
+    fn swap_medium(&mut self, helper: &mut EpollHelper) -> result::Result<(), EpollHelperError> {
+        let Some(disk_image) = self.new_medium.lock().unwrap().take() else {
+            return Ok(());
+        };
+        self.quiesce_queue()?;
+
+        helper.del_event_custom(
+            self.disk_image.notifier().as_raw_fd(),
+            COMPLETION_EVENT,
+            epoll::Events::EPOLLIN,
+        )?;
+        self.disk_image = disk_image;
+        helper.add_event(self.disk_image.notifier().as_raw_fd(), COMPLETION_EVENT)?;
+        info!("Medium changed on queue {}", self.queue_index);
+
+        Ok(())
+    }
+}
+
+impl EpollHelperHandler for BlockEpollHandler {
+    fn quiesce(&mut self, _helper: &mut EpollHelper) -> result::Result<(), EpollHelperError> {
+        // The barrier may have been lowered before the pause, without the
+        // queue having handled it yet.
+        self.update_write_barrier()?;
+        self.settle_queue()
+    }
+
+    fn handle_event(
+        &mut self,
+        helper: &mut EpollHelper,
+        event: &epoll::Event,
+    ) -> result::Result<(), EpollHelperError> {
+        let ev_type = event.data as u16;
+        match ev_type {
+            QUEUE_AVAIL_EVENT => {
+                self.queue_evt.read().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to get queue event: {:?}", e))
+                })?;
+
+                // The requests submitted once the guest learned about a new
+                // medium are served from it, even if the queue is kicked
+                // before the medium change event is handled.
+                self.swap_medium(helper)?;
+
+                // A request blocked on the read or write budget is returned to
+                // the avail ring, so the queue is not processed again until the
+                // corresponding rate limiter wakes us up.
+                let rate_limit_reached = [
+                    &self.rate_limiter,
+                    &self.read_rate_limiter,
+                    &self.write_rate_limiter,
+                ]
+                .iter()
+                .any(|r| r.as_ref().map_or(false, |r| r.is_blocked()));
+
+                // Process the queue only when the rate limit is not reached
                 if !rate_limit_reached {
                     self.process_queue_submit_and_signal()?
                 }
@@ -486,6 +1878,9 @@ impl EpollHelperHandler for BlockEpollHandler {
                 self.disk_image.notifier().read().map_err(|e| {
                     EpollHelperError::HandleEvent(anyhow!("Failed to get queue event: {:?}", e))
                 })?;
+                self.counters
+                    .completion_wakeups
+                    .fetch_add(1, Ordering::AcqRel);
 
                 let needs_notification = self.process_queue_complete().map_err(|e| {
                     EpollHelperError::HandleEvent(anyhow!(
@@ -502,6 +1897,19 @@ impl EpollHelperHandler for BlockEpollHandler {
                         ))
                     })?;
                 }
+
+                // The guest won't notify about the descriptors left on the
+                // avail ring while the in-flight limit or the room of the
+                // backend was reached, so resume processing the queue now
+                // that there is room again. The writes held for lack of room
+                // go first, being older.
+                if !self.held_writes.is_empty() {
+                    self.retry_held_writes()?;
+                }
+                if self.inflight_limit_reached {
+                    self.inflight_limit_reached = false;
+                    self.process_queue_submit_and_signal()?
+                }
             }
             RATE_LIMITER_EVENT => {
                 if let Some(rate_limiter) = &mut self.rate_limiter {
@@ -521,6 +1929,115 @@ impl EpollHelperHandler for BlockEpollHandler {
                     )));
                 }
             }
+            READ_RATE_LIMITER_EVENT | WRITE_RATE_LIMITER_EVENT => {
+                let rate_limiter = if ev_type == READ_RATE_LIMITER_EVENT {
+                    &self.read_rate_limiter
+                } else {
+                    &self.write_rate_limiter
+                };
+                if let Some(rate_limiter) = rate_limiter {
+                    rate_limiter.event_handler().map_err(|e| {
+                        EpollHelperError::HandleEvent(anyhow!(
+                            "Failed to process rate limiter event: {:?}",
+                            e
+                        ))
+                    })?;
+
+                    self.process_queue_submit_and_signal()?
+                } else {
+                    return Err(EpollHelperError::HandleEvent(anyhow!(
+                        "Unexpected rate limiter event {} when the rate limiter is not enabled.",
+                        ev_type
+                    )));
+                }
+            }
+            FLUSH_TIMER_EVENT => {
+                if let Some((timer, _)) = &mut self.flush_timer {
+                    timer.wait().map_err(|e| {
+                        EpollHelperError::HandleEvent(anyhow!(
+                            "Failed to get flush timer event: {:?}",
+                            e
+                        ))
+                    })?;
+                }
+
+                self.submit_flushes().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to submit flushes: {:?}", e))
+                })?;
+            }
+            IDLE_FLUSH_TIMER_EVENT => {
+                if let Some(idle_flush) = &mut self.idle_flush {
+                    idle_flush.timer.wait().map_err(|e| {
+                        EpollHelperError::HandleEvent(anyhow!(
+                            "Failed to get idle flush timer event: {:?}",
+                            e
+                        ))
+                    })?;
+                }
+
+                self.flush_idle_disk().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to flush idle disk: {:?}", e))
+                })?;
+            }
+            WRITE_BARRIER_EVENT => {
+                self.write_barrier_evt.read().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!(
+                        "Failed to get write barrier event: {:?}",
+                        e
+                    ))
+                })?;
+
+                self.update_write_barrier()?;
+            }
+            WRITE_BARRIER_TIMER_EVENT => {
+                self.write_barrier_timer.wait().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!(
+                        "Failed to get write barrier timer event: {:?}",
+                        e
+                    ))
+                })?;
+
+                // The barrier is lifted for the whole device, the other
+                // queues letting their writes through on their own timer.
+                warn!(
+                    "Writes held on queue {} for too long, letting them through",
+                    self.queue_index
+                );
+                self.write_barrier.state.lock().unwrap().timeout = None;
+                self.release_writes()?;
+            }
+            OUT_OF_SPACE_EVENT => {
+                self.out_of_space_evt.read().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!(
+                        "Failed to get out of space event: {:?}",
+                        e
+                    ))
+                })?;
+
+                self.retry_held_writes()?;
+            }
+            REQUEST_TIMEOUT_EVENT => {
+                if let Some(request_timeout) = &mut self.request_timeout {
+                    request_timeout.timer.wait().map_err(|e| {
+                        EpollHelperError::HandleEvent(anyhow!(
+                            "Failed to get request timeout timer event: {:?}",
+                            e
+                        ))
+                    })?;
+                }
+
+                self.check_request_timeouts();
+            }
+            MEDIUM_CHANGE_EVENT => {
+                self.medium_evt.read().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!(
+                        "Failed to get medium change event: {:?}",
+                        e
+                    ))
+                })?;
+
+                self.swap_medium(helper)?;
+            }
             _ => {
                 return Err(EpollHelperError::HandleEvent(anyhow!(
                     "Unexpected event: {}",
@@ -532,22 +2049,112 @@ impl EpollHelperHandler for BlockEpollHandler {
     }
 }
 
+// Returns the CPUs of the host NUMA node holding the descriptor table of
+// `queue`, on hosts with several nodes.
+fn queue_node_cpus(queue: &Queue, mem: &GuestMemoryMmap) -> Option<Vec<usize>> {
+    if numa::host_nodes().len() < 2 {
+        return None;
+    }
+    let addr = mem
+        .get_host_address(GuestAddress(queue.desc_table()))
+        .ok()?;
+    let node = numa::memory_node(addr)
+        .map_err(|e| warn!("Failed looking up the host NUMA node of a queue: {}", e))
+        .ok()?;
+
+    numa::node_cpus(node).ok().filter(|cpus| !cpus.is_empty())
+}
+
+/// Rate limiter of a disk, throttling all of its requests, or only its reads
+/// or its writes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockRateLimiter {
+    All,
+    Read,
+    Write,
+}
+
 /// Virtio device for exposing block level read/write operations on a host file.
 pub struct Block {
     common: VirtioCommon,
     id: String,
     disk_image: Box<dyn DiskFile>,
     disk_path: PathBuf,
-    disk_nsectors: u64,
+    disk_nsectors: Arc<AtomicU64>,
     config: VirtioBlockConfig,
     writeback: Arc<AtomicBool>,
+    durable_writes: bool,
+    // Whether the ranges discarded read back as zeroes, as reported by the
+    // disk unless told otherwise.
+    discard_zeroes: bool,
+    // Whether the device was restored, with the config space of the guest.
+    restored: bool,
     counters: BlockCounters,
     seccomp_action: SeccompAction,
     rate_limiter: Option<Arc<RateLimiterGroup>>,
+    read_rate_limiter: Option<Arc<RateLimiterGroup>>,
+    write_rate_limiter: Option<Arc<RateLimiterGroup>>,
     exit_evt: EventFd,
     read_only: bool,
     serial: Vec<u8>,
     queue_affinity: BTreeMap<u16, Vec<usize>>,
+    // CPUs of the host NUMA node the queues without an affinity are pinned
+    // to, in place of the node holding their memory.
+    host_numa_node_cpus: Option<Vec<usize>>,
+    // CPUs each activated queue thread is pinned to.
+    queue_cpus: BTreeMap<u16, Vec<usize>>,
+    latency_collector: Option<LatencyCollector>,
+    readahead_stats: Option<Arc<ReadaheadStats>>,
+    flush_window: Option<Duration>,
+    idle_flush_interval: Option<Duration>,
+    request_timeout: Option<(Duration, TimeoutPolicy)>,
+    max_merge_size: Option<u64>,
+    max_inflight: Option<usize>,
+    fixed_buffers: bool,
+    scrubber: Option<Scrubber>,
+    write_barrier: Arc<WriteBarrier>,
+    // One per activated queue.
+    write_barrier_evts: Vec<EventFd>,
+    enospc_policy: EnospcPolicy,
+    out_of_space: Arc<OutOfSpace>,
+    // One per activated queue.
+    out_of_space_evts: Vec<EventFd>,
+    removable: bool,
+    // One per activated queue.
+    medium_changes: Vec<MediumChange>,
+    thin_pool: Option<Arc<ThinPool>>,
+}
+
+/// Statistics of a disk, counted since the device was created.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockStats {
+    pub inflight_requests: u64,
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+    pub read_ops: u64,
+    pub write_ops: u64,
+    /// Flush requests completed, including the ones served by the fsync
+    /// of another request, which are also counted in `coalesced_flush_ops`.
+    pub flush_ops: u64,
+    pub coalesced_flush_ops: u64,
+    /// Time spent in fsync in total, and in the longest one, in
+    /// microseconds.
+    pub flush_time: u64,
+    pub flush_time_max: u64,
+    /// Flush requests completed during the last second.
+    pub flushes_per_second: u64,
+    /// Reads and writes whose guest buffers weren't aligned as the backend
+    /// requires, and were copied through aligned buffers, along with the
+    /// bytes copied.
+    pub bounced_ops: u64,
+    pub bounced_bytes: u64,
+    /// Bytes read ahead of the sequential reads, those the guest then read,
+    /// and those it didn't before its reads stopped being sequential. All
+    /// are 0 without readahead.
+    pub readahead_bytes: u64,
+    pub readahead_hit_bytes: u64,
+    pub readahead_wasted_bytes: u64,
+    pub latency: Option<BlockLatencySnapshot>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -573,10 +2180,13 @@ impl Block {
         serial: Option<String>,
         seccomp_action: SeccompAction,
         rate_limiter: Option<Arc<RateLimiterGroup>>,
+        read_rate_limiter: Option<Arc<RateLimiterGroup>>,
+        write_rate_limiter: Option<Arc<RateLimiterGroup>>,
         exit_evt: EventFd,
         state: Option<BlockState>,
         queue_affinity: BTreeMap<u16, Vec<usize>>,
     ) -> io::Result<Self> {
+        let restored = state.is_some();
         let (disk_nsectors, avail_features, acked_features, config, paused) =
             if let Some(state) = state {
                 info!("Restoring virtio-block {}", id);
@@ -606,7 +2216,9 @@ impl Block {
                     | (1u64 << VIRTIO_BLK_F_FLUSH)
                     | (1u64 << VIRTIO_BLK_F_CONFIG_WCE)
                     | (1u64 << VIRTIO_BLK_F_BLK_SIZE)
-                    | (1u64 << VIRTIO_BLK_F_TOPOLOGY);
+                    | (1u64 << VIRTIO_BLK_F_TOPOLOGY)
+                    | (1u64 << VIRTIO_BLK_F_SEG_MAX)
+                    | (1u64 << VIRTIO_BLK_F_LIFETIME);
 
                 if iommu {
                     avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
@@ -614,6 +2226,13 @@ impl Block {
 
                 if read_only {
                     avail_features |= 1u64 << VIRTIO_BLK_F_RO;
+                } else {
+                    if disk_image.supports_discard() {
+                        avail_features |= 1u64 << VIRTIO_BLK_F_DISCARD;
+                    }
+                    if disk_image.supports_write_zeroes() {
+                        avail_features |= 1u64 << VIRTIO_BLK_F_WRITE_ZEROES;
+                    }
                 }
 
                 let topology = disk_image.topology();
@@ -633,17 +2252,62 @@ impl Block {
                     size <<= 1;
                 }
 
+                // The I/O hints are expressed in logical blocks, letting the
+                // guest align its requests on the host RAID stripe, if any.
+                let min_io_size = (topology.minimum_io_size / logical_block_size)
+                    .clamp(1, u64::from(u16::MAX)) as u16;
+                let opt_io_size =
+                    (topology.optimal_io_size / logical_block_size).min(u64::from(u32::MAX)) as u32;
+
                 let disk_nsectors = disk_size / SECTOR_SIZE;
                 let mut config = VirtioBlockConfig {
                     capacity: disk_nsectors,
                     writeback: 1,
                     blk_size: topology.logical_block_size as u32,
                     physical_block_exp,
-                    min_io_size: (topology.minimum_io_size / logical_block_size) as u16,
-                    opt_io_size: (topology.optimal_io_size / logical_block_size) as u32,
+                    min_io_size,
+                    opt_io_size,
+                    // The header and the status take a descriptor each,
+                    // leaving the rest of the queue to the data buffers.
+                    seg_max: topology
+                        .max_segments
+                        .min(u32::from(queue_size).saturating_sub(2))
+                        .max(1),
                     ..Default::default()
                 };
 
+                // Let the guest split what the storage can't take at once,
+                // rather than the host.
+                if topology.max_transfer_size != 0 {
+                    avail_features |= 1u64 << VIRTIO_BLK_F_SIZE_MAX;
+                    config.size_max = topology.max_transfer_size.min(u64::from(u32::MAX)) as u32;
+                }
+
+                // The largest discard and write zeroes are whole logical
+                // blocks, bounding the time the backend spends on each.
+                let zeroing_sectors = |max_size: u64| {
+                    let max_size = (max_size / logical_block_size).max(1) * logical_block_size;
+                    (max_size / SECTOR_SIZE).min(u64::from(u32::MAX)) as u32
+                };
+
+                if avail_features & (1u64 << VIRTIO_BLK_F_DISCARD) != 0 {
+                    // The discards may be served as write zeroes, whose
+                    // result must fit in an i32 as well.
+                    config.max_discard_sectors =
+                        zeroing_sectors(topology.max_discard_size.min(i32::MAX as u64));
+                    config.max_discard_seg = 1;
+                    config.discard_sector_alignment = (logical_block_size / SECTOR_SIZE) as u32;
+                }
+
+                if avail_features & (1u64 << VIRTIO_BLK_F_WRITE_ZEROES) != 0 {
+                    // The number of bytes written is reported through the
+                    // completion result, which must fit in an i32.
+                    config.max_write_zeroes_sectors =
+                        zeroing_sectors(topology.max_write_zeroes_size.min(i32::MAX as u64));
+                    config.max_write_zeroes_seg = 1;
+                    config.write_zeroes_may_unmap = 1;
+                }
+
                 if num_queues > 1 {
                     avail_features |= 1u64 << VIRTIO_BLK_F_MQ;
                     config.num_queues = num_queues as u16;
@@ -652,9 +2316,25 @@ impl Block {
                 (disk_nsectors, avail_features, 0, config, false)
             };
 
+        // Without a configured serial, the one of the host storage is
+        // reported, if any, so that the guest sees the same identity.
         let serial = serial
-            .map(Vec::from)
+            .or_else(|| disk_image.serial())
+            .map(|serial| {
+                if serial.len() > VIRTIO_BLK_ID_BYTES as usize {
+                    warn!(
+                        "Disk serial {} truncated to {} bytes",
+                        serial, VIRTIO_BLK_ID_BYTES
+                    );
+                }
+                serial_bytes(&serial)
+            })
             .unwrap_or_else(|| build_serial(&disk_path));
+        let out_of_space = Arc::new(OutOfSpace {
+            id: id.clone(),
+            paused: AtomicBool::new(false),
+            thin_pool_full: AtomicBool::new(false),
+        });
 
         Ok(Block {
             common: VirtioCommon {
@@ -668,252 +2348,2450 @@ impl Block {
                 ..Default::default()
             },
             id,
+            discard_zeroes: disk_image.discard_zeroes(),
             disk_image,
             disk_path,
-            disk_nsectors,
+            disk_nsectors: Arc::new(AtomicU64::new(disk_nsectors)),
             config,
             writeback: Arc::new(AtomicBool::new(true)),
+            durable_writes: false,
+            restored,
             counters: BlockCounters::default(),
             seccomp_action,
             rate_limiter,
+            read_rate_limiter,
+            write_rate_limiter,
             exit_evt,
             read_only,
             serial,
             queue_affinity,
+            host_numa_node_cpus: None,
+            queue_cpus: BTreeMap::new(),
+            latency_collector: None,
+            readahead_stats: None,
+            flush_window: None,
+            idle_flush_interval: None,
+            request_timeout: None,
+            max_merge_size: None,
+            max_inflight: None,
+            fixed_buffers: false,
+            scrubber: None,
+            write_barrier: Arc::new(WriteBarrier::default()),
+            write_barrier_evts: Vec::new(),
+            enospc_policy: EnospcPolicy::default(),
+            out_of_space,
+            out_of_space_evts: Vec::new(),
+            removable: false,
+            medium_changes: Vec::new(),
+            thin_pool: None,
         })
     }
 
-    fn state(&self) -> BlockState {
-        BlockState {
-            disk_path: self.disk_path.to_str().unwrap().to_owned(),
-            disk_nsectors: self.disk_nsectors,
-            avail_features: self.common.avail_features,
-            acked_features: self.common.acked_features,
-            config: self.config,
+    /// Tells the device about the host cache mode of the disk. The guest is
+    /// shown a write-through cache when the host already makes every write
+    /// durable, the writes not being synced again whichever mode the guest
+    /// then picks. A restored guest keeps the mode it picked.
+    pub fn set_cache_mode(&mut self, cache_mode: CacheMode) {
+        self.durable_writes = matches!(cache_mode, CacheMode::Writethrough | CacheMode::DirectSync);
+        if !self.restored {
+            self.config.writeback = u8::from(!self.durable_writes);
         }
     }
 
-    fn update_writeback(&mut self) {
-        // Use writeback from config if VIRTIO_BLK_F_CONFIG_WCE
-        let writeback = if self.common.feature_acked(VIRTIO_BLK_F_CONFIG_WCE.into()) {
-            self.config.writeback == 1
-        } else {
-            // Else check if VIRTIO_BLK_F_FLUSH negotiated
-            self.common.feature_acked(VIRTIO_BLK_F_FLUSH.into())
-        };
+    /// Tells the device whether the ranges discarded read back as zeroes,
+    /// in place of what the disk reports. When they may not, the discards of
+    /// every queue activated from now on are served as write zeroes with
+    /// unmap, provided the disk supports them.
+    pub fn set_discard_zeroes(&mut self, discard_zeroes: bool) {
+        self.discard_zeroes = discard_zeroes;
+    }
 
-        info!(
-            "Changing cache mode to {}",
-            if writeback {
-                "writeback"
-            } else {
-                "writethrough"
-            }
-        );
-        self.writeback.store(writeback, Ordering::Release);
+    /// Record the latency of the requests of every queue activated from now
+    /// on into the histograms of the given collector.
+    pub fn set_latency_collector(&mut self, latency_collector: LatencyCollector) {
+        self.latency_collector = Some(latency_collector);
     }
 
-    #[cfg(fuzzing)]
-    pub fn wait_for_epoll_threads(&mut self) {
-        self.common.wait_for_epoll_threads();
+    /// Report the bytes the disk reads ahead of the sequential reads of the
+    /// guest along with the other counters.
+    pub fn set_readahead_stats(&mut self, readahead_stats: Arc<ReadaheadStats>) {
+        self.readahead_stats = Some(readahead_stats);
     }
-}
 
-impl Drop for Block {
-    fn drop(&mut self) {
-        if let Some(kill_evt) = self.common.kill_evt.take() {
-            // Ignore the result because there is nothing we can do about it.
-            let _ = kill_evt.write(1);
-        }
-        self.common.wait_for_epoll_threads();
+    /// Coalesce the flush requests of every queue activated from now on
+    /// into a single fsync per `window`.
+    pub fn set_flush_window(&mut self, window: Duration) {
+        self.flush_window = Some(window);
     }
-}
 
-impl VirtioDevice for Block {
-    fn device_type(&self) -> u32 {
-        self.common.device_type
+    /// Flush the disk once no write has been received for `interval` by
+    /// every queue activated from now on, in the background.
+    pub fn set_idle_flush_interval(&mut self, interval: Duration) {
+        self.idle_flush_interval = Some(interval);
     }
 
-    fn queue_max_sizes(&self) -> &[u16] {
-        &self.common.queue_sizes
+    /// Report the requests of every queue activated from now on still in
+    /// flight after `timeout`, canceling them as well depending on `policy`.
+    pub fn set_request_timeout(&mut self, timeout: Duration, policy: TimeoutPolicy) {
+        self.request_timeout = Some((timeout, policy));
     }
 
-    fn features(&self) -> u64 {
-        self.common.avail_features
+    /// Merge the contiguous reads or writes of every queue activated from
+    /// now on into requests of up to `max_merge_size` bytes.
+    pub fn set_max_merge_size(&mut self, max_merge_size: u64) {
+        self.max_merge_size = Some(max_merge_size);
     }
 
-    fn ack_features(&mut self, value: u64) {
-        self.common.ack_features(value)
+    /// Limit every queue activated from now on to `max_inflight` requests
+    /// submitted to the backend and not completed yet.
+    pub fn set_max_inflight(&mut self, max_inflight: usize) {
+        self.max_inflight = Some(max_inflight);
     }
 
-    fn read_config(&self, offset: u64, data: &mut [u8]) {
-        self.read_config_from_slice(self.config.as_slice(), offset, data);
+    /// Pin the threads of every queue activated from now on without an
+    /// affinity to the CPUs of the host NUMA node `node`, rather than to the
+    /// node holding the memory of their queue.
+    pub fn set_host_numa_node(&mut self, node: u32) -> Result<()> {
+        let cpus = numa::node_cpus(node).map_err(|e| Error::HostNumaNode(node, e))?;
+        self.host_numa_node_cpus = Some(cpus);
+
+        Ok(())
     }
 
-    fn write_config(&mut self, offset: u64, data: &[u8]) {
-        // The "writeback" field is the only mutable field
-        let writeback_offset =
-            (&self.config.writeback as *const _ as u64) - (&self.config as *const _ as u64);
-        if offset != writeback_offset || data.len() != std::mem::size_of_val(&self.config.writeback)
-        {
-            error!(
-                "Attempt to write to read-only field: offset {:x} length {}",
-                offset,
-                data.len()
-            );
-            return;
-        }
+    /// CPUs the thread of each activated queue is pinned to, the queues
+    /// left to the scheduler being omitted.
+    pub fn queue_cpus(&self) -> &BTreeMap<u16, Vec<usize>> {
+        &self.queue_cpus
+    }
 
-        self.config.writeback = data[0];
-        self.update_writeback();
+    /// Handle the writes of every queue activated from now on failing with
+    /// the host storage full according to `policy`.
+    pub fn set_enospc_policy(&mut self, policy: EnospcPolicy) {
+        self.enospc_policy = policy;
     }
 
-    fn activate(
+    /// Backend serving the requests to the disk.
+    pub fn backend(&self) -> AsyncIoBackend {
+        self.disk_image.backend()
+    }
+
+    /// Register the guest memory with the backend of every queue activated
+    /// from now on, letting it skip mapping the request buffers each time.
+    pub fn set_fixed_buffers(&mut self, fixed_buffers: bool) {
+        self.fixed_buffers = fixed_buffers;
+    }
+
+    /// Read the whole disk image through `file` in the background, whenever
+    /// the guest isn't using the disk, optionally checking its content
+    /// against `manifest`.
+    pub fn start_scrubber(
         &mut self,
-        mem: GuestMemoryAtomic<GuestMemoryMmap>,
-        interrupt_cb: Arc<dyn VirtioInterrupt>,
-        mut queues: Vec<(usize, Queue, EventFd)>,
-    ) -> ActivateResult {
-        self.common.activate(&queues, &interrupt_cb)?;
+        file: File,
+        manifest: Option<ScrubManifest>,
+    ) -> io::Result<()> {
+        let read_ops = self.counters.read_ops.clone();
+        let write_ops = self.counters.write_ops.clone();
+        self.scrubber = Some(Scrubber::start(
+            format!("{}_scrub", self.id),
+            file,
+            self.disk_nsectors.load(Ordering::Acquire) * SECTOR_SIZE,
+            u64::from(self.config.blk_size).max(SECTOR_SIZE) * SCRUB_CHUNK_BLOCKS,
+            manifest,
+            move || read_ops.load(Ordering::Acquire) + write_ops.load(Ordering::Acquire),
+        )?);
 
-        self.update_writeback();
+        Ok(())
+    }
 
-        let mut epoll_threads = Vec::new();
-        for i in 0..queues.len() {
-            let (_, queue, queue_evt) = queues.remove(0);
-            let queue_size = queue.size();
-            let (kill_evt, pause_evt) = self.common.dup_eventfds();
-            let queue_idx = i as u16;
+    /// Start recording the blocks of `granularity` bytes the guest modifies,
+    /// so that a copy of the disk can be brought up to date incrementally.
+    pub fn start_dirty_tracking(&mut self, granularity: u64) -> Result<()> {
+        self.disk_image
+            .start_dirty_tracking(granularity)
+            .map_err(Error::DirtyTracking)
+    }
 
-            let mut handler = BlockEpollHandler {
-                queue_index: queue_idx,
-                queue,
-                mem: mem.clone(),
-                disk_image: self
-                    .disk_image
-                    .new_async_io(queue_size as u32)
-                    .map_err(|e| {
-                        error!("failed to create new AsyncIo: {}", e);
-                        ActivateError::BadActivate
-                    })?,
-                disk_nsectors: self.disk_nsectors,
-                interrupt_cb: interrupt_cb.clone(),
-                serial: self.serial.clone(),
-                kill_evt,
-                pause_evt,
-                writeback: self.writeback.clone(),
-                counters: self.counters.clone(),
-                queue_evt,
-                // Analysis during boot shows around ~40 maximum requests
-                // This gives head room for systems with slower I/O without
-                // compromising the cost of the reallocation or memory overhead
-                inflight_requests: VecDeque::with_capacity(64),
-                rate_limiter: self
-                    .rate_limiter
-                    .as_ref()
-                    .map(|r| r.new_handle())
-                    .transpose()
-                    .unwrap(),
-                access_platform: self.common.access_platform.clone(),
-                read_only: self.read_only,
-                host_cpus: self.queue_affinity.get(&queue_idx).cloned(),
-            };
+    pub fn stop_dirty_tracking(&mut self) {
+        self.disk_image.stop_dirty_tracking();
+    }
+
+    /// Returns the ranges of the disk modified since the previous call, or
+    /// since tracking started.
+    pub fn get_dirty_blocks(&mut self) -> Result<Vec<DiskExtent>> {
+        self.disk_image
+            .get_dirty_blocks()
+            .map_err(Error::DirtyTracking)
+    }
+
+    /// Persists the data written to the disk, returning once the flush has
+    /// completed. The requests still in flight aren't covered, the device
+    /// must be paused for the flush to account for all of them.
+    pub fn flush(&self) -> Result<()> {
+        let mut disk_image = self
+            .disk_image
+            .new_async_io(1)
+            .map_err(Error::CreateAsyncIo)?;
+        disk_image.fsync(Some(0)).map_err(Error::Fsync)?;
+        disk_image.submit().map_err(Error::Submit)?;
+        loop {
+            if let Some((_, result)) = disk_image.next_completed_request() {
+                if result < 0 {
+                    return Err(Error::Fsync(AsyncIoError::Fsync(
+                        io::Error::from_raw_os_error(-result),
+                    )));
+                }
+                return Ok(());
+            }
+            wait_completions(disk_image.as_ref())?;
+        }
+    }
+
+    /// Holds the writes of the guest, returning once the ones in flight
+    /// have completed and the disk has been flushed, so that its content
+    /// can be captured. The reads keep going through meanwhile.
+    ///
+    /// The writes are let through again by `resume_writes()`, or after
+    /// `timeout`, not to stall the guest if the caller never does. Failing
+    /// to drain the writes within `timeout` lets them through as well.
+    pub fn quiesce_writes(&self, timeout: Duration) -> Result<()> {
+        let mut state = self.write_barrier.state.lock().unwrap();
+        state.timeout = Some(timeout);
+        state.drained_queues = 0;
+        for evt in &self.write_barrier_evts {
+            evt.write(1).map_err(Error::WriteBarrierEvent)?;
+        }
+
+        let queues = self.write_barrier_evts.len();
+        let (state, _) = self
+            .write_barrier
+            .drained
+            .wait_timeout_while(state, timeout, |state| {
+                state.timeout.is_some() && state.drained_queues < queues
+            })
+            .unwrap();
+        // A queue may have let the writes through already, once its timer
+        // expired.
+        if state.timeout.is_none() || state.drained_queues < queues {
+            drop(state);
+            self.resume_writes()?;
+            return Err(Error::WriteBarrierTimeout);
+        }
+
+        Ok(())
+    }
+
+    /// Advertises `num_queues` queues to the guest, up to the number the
+    /// device was created with, and lets the guest know through a
+    /// configuration change interrupt.
+    ///
+    /// The driver only picks the new number up once it resets the device,
+    /// as when it is probed again, the queues it is using staying served
+    /// until then. Going below their number is refused meanwhile, as their
+    /// requests can't be drained without the driver letting go of them.
+    pub fn set_num_queues(&mut self, num_queues: usize) -> Result<()> {
+        let max_queues = self.common.queue_sizes.len();
+        if num_queues == 0 || num_queues > max_queues {
+            return Err(Error::InvalidNumQueues(num_queues, max_queues));
+        }
+        // One thread serves each of the queues the driver is using.
+        let active_queues = self
+            .common
+            .epoll_threads
+            .as_ref()
+            .map_or(0, |threads| threads.len());
+        if num_queues < active_queues {
+            return Err(Error::QueuesInUse(active_queues));
+        }
+
+        // The configuration space is packed, hence the copy.
+        let advertised = self.config.num_queues;
+        info!(
+            "Advertising {} queues on virtio-block {}, from {}",
+            num_queues, self.id, advertised
+        );
+        self.config.num_queues = num_queues as u16;
+
+        if let Some(interrupt_cb) = &self.common.interrupt_cb {
+            interrupt_cb
+                .trigger(VirtioInterruptType::Config)
+                .map_err(Error::ConfigChangeSignal)?;
+        }
+
+        Ok(())
+    }
+
+    /// Lets the writes held by `quiesce_writes()` through.
+    pub fn resume_writes(&self) -> Result<()> {
+        self.write_barrier.state.lock().unwrap().timeout = None;
+        for evt in &self.write_barrier_evts {
+            evt.write(1).map_err(Error::WriteBarrierEvent)?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether the writes are paused with the host storage full, waiting
+    /// for `resume_out_of_space()`.
+    pub fn out_of_space(&self) -> bool {
+        self.out_of_space.paused.load(Ordering::Acquire)
+    }
+
+    /// Bytes the host filesystem holding the disk image can still allocate,
+    /// for a management agent to make room before the writes pause with
+    /// `enospc=pause`. Only raw images on a filesystem report it.
+    pub fn available_space(&self) -> Option<u64> {
+        self.disk_image.available_space().unwrap_or_else(|e| {
+            warn!("Failed to read the space left for disk {}: {}", self.id, e);
+            None
+        })
+    }
+
+    /// Retries the writes paused with the host storage full, once room has
+    /// been made for them. The device pauses again, and reports it, should
+    /// they still not fit.
+    pub fn resume_out_of_space(&self) -> Result<()> {
+        if !self.out_of_space.paused.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+
+        info!("Retrying the paused writes to disk {}", self.id);
+        for evt in &self.out_of_space_evts {
+            evt.write(1).map_err(Error::OutOfSpaceEvent)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn latency_snapshot(&self) -> Option<BlockLatencySnapshot> {
+        self.latency_collector
+            .as_ref()
+            .map(|collector| collector.snapshot())
+    }
+
+    /// Current statistics of the disk, along with its latency distributions
+    /// if they are collected.
+    pub fn stats(&self) -> BlockStats {
+        let (readahead_bytes, readahead_hit_bytes, readahead_wasted_bytes) = self.readahead_bytes();
+        BlockStats {
+            inflight_requests: self.counters.inflight_requests.load(Ordering::Acquire),
+            read_bytes: self.counters.read_bytes.load(Ordering::Acquire),
+            write_bytes: self.counters.write_bytes.load(Ordering::Acquire),
+            read_ops: self.counters.read_ops.load(Ordering::Acquire),
+            write_ops: self.counters.write_ops.load(Ordering::Acquire),
+            flush_ops: self.counters.flush_ops.load(Ordering::Acquire),
+            coalesced_flush_ops: self.counters.coalesced_flush_ops.load(Ordering::Acquire),
+            flush_time: self.counters.flush_time.load(Ordering::Acquire),
+            flush_time_max: self.counters.flush_time_max.load(Ordering::Acquire),
+            flushes_per_second: self.counters.flush_rate.lock().unwrap().per_second(),
+            bounced_ops: self.counters.bounced_ops.load(Ordering::Acquire),
+            bounced_bytes: self.counters.bounced_bytes.load(Ordering::Acquire),
+            readahead_bytes,
+            readahead_hit_bytes,
+            readahead_wasted_bytes,
+            latency: self.latency_snapshot(),
+        }
+    }
+
+    // Bytes read ahead, then read by the guest, and wasted.
+    fn readahead_bytes(&self) -> (u64, u64, u64) {
+        self.readahead_stats.as_deref().map_or((0, 0, 0), |stats| {
+            (
+                stats.prefetched_bytes.load(Ordering::Relaxed),
+                stats.hit_bytes.load(Ordering::Relaxed),
+                stats.wasted_bytes.load(Ordering::Relaxed),
+            )
+        })
+    }
+
+    /// Number of queues currently advertised to the guest.
+    pub fn num_queues(&self) -> u16 {
+        // Left to 0 by single queue devices, not offering VIRTIO_BLK_F_MQ.
+        self.config.num_queues.max(1)
+    }
+
+    /// Block size reported to the guest, in bytes.
+    pub fn logical_block_size(&self) -> u32 {
+        self.config.blk_size
+    }
+
+    /// Size of the disk, in bytes.
+    pub fn capacity(&self) -> u64 {
+        self.disk_nsectors.load(Ordering::Acquire) << SECTOR_SHIFT
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn state(&self) -> BlockState {
+        BlockState {
+            disk_path: self.disk_path.to_str().unwrap().to_owned(),
+            disk_nsectors: self.disk_nsectors.load(Ordering::Acquire),
+            avail_features: self.common.avail_features,
+            acked_features: self.common.acked_features,
+            config: self.config,
+        }
+    }
+
+    /// Pick up the new size of a disk image that grew, and let the guest
+    /// know about its new capacity through a configuration change interrupt.
+    pub fn resize(&mut self) -> Result<()> {
+        let current_size = self.disk_nsectors.load(Ordering::Acquire) * SECTOR_SIZE;
+        let disk_size = self
+            .disk_image
+            .resize(current_size)
+            .map_err(Error::DiskResize)?;
+        if disk_size % SECTOR_SIZE != 0 {
+            warn!(
+                "Disk size {} is not a multiple of sector size {}; \
+                 the remainder will not be visible to the guest.",
+                disk_size, SECTOR_SIZE
+            );
+        }
+
+        let disk_nsectors = disk_size / SECTOR_SIZE;
+        info!(
+            "Resizing virtio-block {} from {} to {} sectors",
+            self.id,
+            self.disk_nsectors.load(Ordering::Acquire),
+            disk_nsectors
+        );
+        self.disk_nsectors.store(disk_nsectors, Ordering::Release);
+        self.config.capacity = disk_nsectors;
+
+        if let Some(interrupt_cb) = &self.common.interrupt_cb {
+            interrupt_cb
+                .trigger(VirtioInterruptType::Config)
+                .map_err(Error::ConfigChangeSignal)?;
+        }
+
+        Ok(())
+    }
+
+    /// Tells the device about the dm-thin pool backing the disk, whose
+    /// writes failing as the pool is out of data space are then reported as
+    /// the host storage being full.
+    pub fn set_thin_pool(&mut self, thin_pool: Option<ThinPool>) {
+        self.thin_pool = thin_pool.map(Arc::new);
+    }
+
+    /// Returns the utilization of the dm-thin pool backing the disk, if any.
+    pub fn thin_pool_status(&self) -> Option<ThinPoolStatus> {
+        self.thin_pool.as_ref()?.status().map_or_else(
+            |e| {
+                warn!("Failed reading the status of the thin pool: {}", e);
+                None
+            },
+            Some,
+        )
+    }
+
+    /// Lets the medium of the device be changed with `change_medium()`,
+    /// as the one of a CD-ROM drive.
+    pub fn set_removable(&mut self, removable: bool) {
+        self.removable = removable;
+    }
+
+    pub fn removable(&self) -> bool {
+        self.removable
+    }
+
+    /// Replaces the medium of a removable device with `medium`, the disk
+    /// image along with its path, or ejects it, leaving a device of no
+    /// capacity. Each queue completes the requests in flight on the
+    /// previous medium before serving the next ones from the new one, and
+    /// the guest is told about the new capacity through a configuration
+    /// change interrupt.
+    pub fn change_medium(&mut self, medium: Option<(Box<dyn DiskFile>, PathBuf)>) -> Result<()> {
+        if !self.removable {
+            return Err(Error::NotRemovable);
+        }
+
+        let (mut disk_image, disk_path) = match medium {
+            Some(medium) => medium,
+            None => (
+                Box::new(NullDiskFile::new(0, None)) as Box<dyn DiskFile>,
+                self.disk_path.clone(),
+            ),
+        };
+        let disk_size = disk_image.size().map_err(Error::MediumSize)?;
+        if disk_size % SECTOR_SIZE != 0 {
+            warn!(
+                "Disk size {} is not a multiple of sector size {}; \
+                 the remainder will not be visible to the guest.",
+                disk_size, SECTOR_SIZE
+            );
+        }
+
+        // The queues pick up their medium once it is ready for all of them.
+        for medium_change in &self.medium_changes {
+            let mut queue_disk_image = disk_image
+                .new_async_io(medium_change.ring_depth)
+                .map_err(Error::CreateAsyncIo)?;
+            if let Some(latency_collector) = &self.latency_collector {
+                queue_disk_image = Box::new(MeteredAsyncIo::new(
+                    queue_disk_image,
+                    latency_collector.clone(),
+                ));
+            }
+            *medium_change.disk_image.lock().unwrap() = Some(queue_disk_image);
+        }
+
+        let disk_nsectors = disk_size / SECTOR_SIZE;
+        info!(
+            "Changing the medium of virtio-block {} to {:?}, {} sectors",
+            self.id, disk_path, disk_nsectors
+        );
+        self.disk_image = disk_image;
+        self.disk_path = disk_path;
+        self.disk_nsectors.store(disk_nsectors, Ordering::Release);
+        self.config.capacity = disk_nsectors;
+        for medium_change in &self.medium_changes {
+            medium_change
+                .evt
+                .write(1)
+                .map_err(Error::MediumChangeEvent)?;
+        }
+        event!(
+            "block",
+            "medium-change",
+            "id",
+            &self.id,
+            "capacity",
+            (disk_nsectors << SECTOR_SHIFT).to_string()
+        );
+
+        if let Some(interrupt_cb) = &self.common.interrupt_cb {
+            interrupt_cb
+                .trigger(VirtioInterruptType::Config)
+                .map_err(Error::ConfigChangeSignal)?;
+        }
+
+        Ok(())
+    }
+
+    fn rate_limit_group(&self, limiter: BlockRateLimiter) -> Option<&Arc<RateLimiterGroup>> {
+        match limiter {
+            BlockRateLimiter::All => self.rate_limiter.as_ref(),
+            BlockRateLimiter::Read => self.read_rate_limiter.as_ref(),
+            BlockRateLimiter::Write => self.write_rate_limiter.as_ref(),
+        }
+    }
+
+    /// Returns the limits currently enforced by `limiter`, unset if the
+    /// device was created without it.
+    pub fn rate_limits(&self, limiter: BlockRateLimiter) -> Option<RateLimiterConfig> {
+        self.rate_limit_group(limiter).map(|group| {
+            let (bandwidth, ops) = group.buckets();
+            RateLimiterConfig {
+                bandwidth: bandwidth.as_ref().map(TokenBucketConfig::from),
+                ops: ops.as_ref().map(TokenBucketConfig::from),
+            }
+        })
+    }
+
+    /// Replaces the limits of `limiter` with `config`, and returns the ones
+    /// now enforced. The buckets keep the tokens left up to their new size,
+    /// the new limits taking effect as they refill, and the requests held
+    /// by the limiter staying queued until then.
+    pub fn update_rate_limits(
+        &self,
+        limiter: BlockRateLimiter,
+        config: &RateLimiterConfig,
+    ) -> Result<RateLimiterConfig> {
+        let group = self
+            .rate_limit_group(limiter)
+            .ok_or(Error::NoRateLimiter(limiter))?;
+        let (bandwidth, ops) = config.bucket_updates();
+        group.adjust_buckets(bandwidth, ops);
+
+        info!(
+            "Rate limits of disk {} ({:?}) changed to {:?}",
+            self.id, limiter, config
+        );
+
+        Ok(self.rate_limits(limiter).unwrap_or_default())
+    }
+
+    fn update_writeback(&mut self) {
+        // Use writeback from config if VIRTIO_BLK_F_CONFIG_WCE
+        let writeback = if self.common.feature_acked(VIRTIO_BLK_F_CONFIG_WCE.into()) {
+            self.config.writeback == 1
+        } else {
+            // Else check if VIRTIO_BLK_F_FLUSH negotiated
+            self.common.feature_acked(VIRTIO_BLK_F_FLUSH.into())
+        };
+
+        info!(
+            "Changing cache mode to {}",
+            if writeback {
+                "writeback"
+            } else {
+                "writethrough"
+            }
+        );
+        self.writeback.store(writeback, Ordering::Release);
+    }
+
+    #[cfg(fuzzing)]
+    pub fn wait_for_epoll_threads(&mut self) {
+        self.common.wait_for_epoll_threads();
+    }
+}
+
+impl Drop for Block {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.common.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+        self.common.wait_for_epoll_threads();
+    }
+}
+
+impl VirtioDevice for Block {
+    fn device_type(&self) -> u32 {
+        self.common.device_type
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.common.queue_sizes
+    }
+
+    fn features(&self) -> u64 {
+        self.common.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.common.ack_features(value)
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        self.read_config_from_slice(self.config.as_slice(), offset, data);
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        // The "writeback" field is the only mutable field
+        let writeback_offset =
+            (&self.config.writeback as *const _ as u64) - (&self.config as *const _ as u64);
+        if offset != writeback_offset || data.len() != std::mem::size_of_val(&self.config.writeback)
+        {
+            error!(
+                "Attempt to write to read-only field: offset {:x} length {}",
+                offset,
+                data.len()
+            );
+            return;
+        }
+
+        // Any other value than 0 turns the write cache on, the guest being
+        // told to read the field back if it didn't write 1.
+        self.config.writeback = u8::from(data[0] != 0);
+        self.update_writeback();
+        if self.config.writeback != data[0] {
+            if let Some(interrupt_cb) = &self.common.interrupt_cb {
+                if let Err(e) = interrupt_cb.trigger(VirtioInterruptType::Config) {
+                    error!("Failed to signal the config change: {:?}", e);
+                }
+            }
+        }
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        mut queues: Vec<(usize, Queue, EventFd)>,
+    ) -> ActivateResult {
+        self.common.activate(&queues, &interrupt_cb)?;
+        // The driver may use fewer queues than advertised.
+        self.common.paused_sync = Some(Arc::new(Barrier::new(queues.len() + 1)));
+
+        self.update_writeback();
+
+        // The wear of the storage is slow enough for the estimate read as
+        // the driver starts to stay relevant.
+        let lifetime = if self.common.feature_acked(VIRTIO_BLK_F_LIFETIME.into()) {
+            Some(self.disk_image.get_lifetime())
+        } else {
+            None
+        };
+
+        let zeroing_discards = self.common.feature_acked(VIRTIO_BLK_F_DISCARD.into())
+            && !self.discard_zeroes
+            && self.disk_image.supports_write_zeroes();
+        if zeroing_discards {
+            info!(
+                "Discards to disk {} may not read back as zeroes, zeroing them",
+                self.id
+            );
+        }
+
+        self.write_barrier_evts.clear();
+        self.out_of_space_evts.clear();
+        self.medium_changes.clear();
+        self.queue_cpus.clear();
+        let mut epoll_threads = Vec::new();
+        for i in 0..queues.len() {
+            let (_, queue, queue_evt) = queues.remove(0);
+            let queue_size = queue.size();
+            let (kill_evt, pause_evt) = self.common.dup_eventfds();
+            let queue_idx = i as u16;
+
+            let mut disk_image = self
+                .disk_image
+                .new_async_io(queue_size as u32)
+                .map_err(|e| {
+                    error!("failed to create new AsyncIo: {}", e);
+                    ActivateError::BadActivate
+                })?;
+            if self.fixed_buffers {
+                let regions: Vec<libc::iovec> = mem
+                    .memory()
+                    .iter()
+                    .map(|region| libc::iovec {
+                        iov_base: region.as_ptr() as *mut libc::c_void,
+                        iov_len: region.len() as usize,
+                    })
+                    .collect();
+                match disk_image.register_buffers(&regions) {
+                    Ok(true) => {}
+                    Ok(false) => warn!("Disk backend doesn't support fixed buffers"),
+                    Err(e) => warn!("Failed registering the guest memory: {}", e),
+                }
+            }
+            if let Some(latency_collector) = &self.latency_collector {
+                disk_image = Box::new(MeteredAsyncIo::new(disk_image, latency_collector.clone()));
+            }
+
+            let flush_timer = self
+                .flush_window
+                .map(|window| TimerFd::new().map(|timer| (timer, window)))
+                .transpose()
+                .map_err(|e| {
+                    error!("failed to create flush timer: {}", e);
+                    ActivateError::BadActivate
+                })?;
+            let idle_flush = self
+                .idle_flush_interval
+                .map(|interval| {
+                    TimerFd::new().map(|timer| IdleFlush {
+                        timer,
+                        interval,
+                        armed: false,
+                        last_write: None,
+                        in_flight: false,
+                    })
+                })
+                .transpose()
+                .map_err(|e| {
+                    error!("failed to create idle flush timer: {}", e);
+                    ActivateError::BadActivate
+                })?;
+            let request_timeout = self
+                .request_timeout
+                .map(|(timeout, policy)| RequestTimeout::new(timeout, policy))
+                .transpose()
+                .map_err(|e| {
+                    error!("failed to create request timeout timer: {}", e);
+                    ActivateError::BadActivate
+                })?;
+
+            let write_barrier_evt = EventFd::new(EFD_NONBLOCK).map_err(|e| {
+                error!("failed to create write barrier event: {}", e);
+                ActivateError::BadActivate
+            })?;
+            // The queue holds the writes from the start if the barrier has
+            // been raised already.
+            if self.write_barrier.state.lock().unwrap().timeout.is_some() {
+                write_barrier_evt.write(1).map_err(|e| {
+                    error!("failed to notify write barrier event: {}", e);
+                    ActivateError::BadActivate
+                })?;
+            }
+            self.write_barrier_evts
+                .push(write_barrier_evt.try_clone().map_err(|e| {
+                    error!("failed to clone write barrier event: {}", e);
+                    ActivateError::BadActivate
+                })?);
+            let write_barrier_timer = TimerFd::new().map_err(|e| {
+                error!("failed to create write barrier timer: {}", e);
+                ActivateError::BadActivate
+            })?;
+            let out_of_space_evt = EventFd::new(EFD_NONBLOCK).map_err(|e| {
+                error!("failed to create out of space event: {}", e);
+                ActivateError::BadActivate
+            })?;
+            self.out_of_space_evts
+                .push(out_of_space_evt.try_clone().map_err(|e| {
+                    error!("failed to clone out of space event: {}", e);
+                    ActivateError::BadActivate
+                })?);
+            let new_medium = Arc::new(Mutex::new(None));
+            let medium_evt = EventFd::new(EFD_NONBLOCK).map_err(|e| {
+                error!("failed to create medium change event: {}", e);
+                ActivateError::BadActivate
+            })?;
+            self.medium_changes.push(MediumChange {
+                disk_image: new_medium.clone(),
+                ring_depth: queue_size as u32,
+                evt: medium_evt.try_clone().map_err(|e| {
+                    error!("failed to clone medium change event: {}", e);
+                    ActivateError::BadActivate
+                })?,
+            });
+
+            // Without an affinity, the queue thread runs on the host node
+            // its memory lives on. The memory translated by an IOMMU isn't
+            // looked up.
+            let host_cpus = self
+                .queue_affinity
+                .get(&queue_idx)
+                .or(self.host_numa_node_cpus.as_ref())
+                .cloned()
+                .or_else(|| match self.common.access_platform {
+                    None => queue_node_cpus(&queue, &mem.memory()),
+                    Some(_) => None,
+                });
+            if let Some(host_cpus) = &host_cpus {
+                info!(
+                    "Pinning queue {} of virtio-block {} to host CPUs {:?}",
+                    queue_idx, self.id, host_cpus
+                );
+                self.queue_cpus.insert(queue_idx, host_cpus.clone());
+            }
+
+            let mut handler = BlockEpollHandler {
+                queue_index: queue_idx,
+                queue,
+                mem: mem.clone(),
+                disk_image,
+                disk_nsectors: self.disk_nsectors.clone(),
+                interrupt_cb: interrupt_cb.clone(),
+                serial: self.serial.clone(),
+                lifetime,
+                kill_evt,
+                pause_evt,
+                writeback: self.writeback.clone(),
+                durable_writes: self.durable_writes,
+                zeroing_discards,
+                counters: self.counters.clone(),
+                queue_evt,
+                // Analysis during boot shows around ~40 maximum requests
+                // This gives head room for systems with slower I/O without
+                // compromising the cost of the reallocation or memory overhead
+                inflight_requests: VecDeque::with_capacity(64),
+                merged_requests: HashMap::new(),
+                max_merge_size: self.max_merge_size,
+                max_inflight: self.max_inflight,
+                inflight_limit_reached: false,
+                flush_timer,
+                pending_flushes: Vec::new(),
+                idle_flush,
+                request_timeout,
+                rate_limiter: self
+                    .rate_limiter
+                    .as_ref()
+                    .map(|r| r.new_handle())
+                    .transpose()
+                    .unwrap(),
+                read_rate_limiter: self
+                    .read_rate_limiter
+                    .as_ref()
+                    .map(|r| r.new_handle())
+                    .transpose()
+                    .unwrap(),
+                write_rate_limiter: self
+                    .write_rate_limiter
+                    .as_ref()
+                    .map(|r| r.new_handle())
+                    .transpose()
+                    .unwrap(),
+                access_platform: self.common.access_platform.clone(),
+                read_only: self.read_only,
+                seg_max: if self.common.feature_acked(VIRTIO_BLK_F_SEG_MAX.into()) {
+                    self.config.seg_max
+                } else {
+                    0
+                },
+                size_max: if self.common.feature_acked(VIRTIO_BLK_F_SIZE_MAX.into()) {
+                    self.config.size_max
+                } else {
+                    0
+                },
+                max_discard_sectors: if self.common.feature_acked(VIRTIO_BLK_F_DISCARD.into()) {
+                    self.config.max_discard_sectors
+                } else {
+                    0
+                },
+                max_write_zeroes_sectors: if self
+                    .common
+                    .feature_acked(VIRTIO_BLK_F_WRITE_ZEROES.into())
+                {
+                    self.config.max_write_zeroes_sectors
+                } else {
+                    0
+                },
+                host_cpus,
+                write_barrier: self.write_barrier.clone(),
+                write_barrier_evt,
+                write_barrier_timer,
+                writes_held: false,
+                held_writes: VecDeque::new(),
+                enospc_policy: self.enospc_policy,
+                out_of_space: self.out_of_space.clone(),
+                out_of_space_evt,
+                new_medium,
+                medium_evt,
+                thin_pool: self.thin_pool.clone(),
+            };
 
             let paused = self.common.paused.clone();
             let paused_sync = self.common.paused_sync.clone();
 
-            spawn_virtio_thread(
-                &format!("{}_q{}", self.id.clone(), i),
-                &self.seccomp_action,
-                Thread::VirtioBlock,
-                &mut epoll_threads,
-                &self.exit_evt,
-                move || handler.run(paused, paused_sync.unwrap()),
-            )?;
+            spawn_virtio_thread(
+                &format!("{}_q{}", self.id.clone(), i),
+                &self.seccomp_action,
+                Thread::VirtioBlock,
+                &mut epoll_threads,
+                &self.exit_evt,
+                move || handler.run(paused, paused_sync.unwrap()),
+            )?;
+        }
+
+        self.common.epoll_threads = Some(epoll_threads);
+        event!("virtio-device", "activated", "id", &self.id);
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<Arc<dyn VirtioInterrupt>> {
+        let result = self.common.reset();
+        self.write_barrier_evts.clear();
+        // The paused writes were failed as the queues were torn down.
+        self.out_of_space_evts.clear();
+        self.medium_changes.clear();
+        self.queue_cpus.clear();
+        self.out_of_space.paused.store(false, Ordering::Release);
+        // The requests in flight were dropped along with the queues.
+        self.counters.inflight_requests.store(0, Ordering::Release);
+        event!("virtio-device", "reset", "id", &self.id);
+        result
+    }
+
+    fn counters(&self) -> Option<HashMap<&'static str, Wrapping<u64>>> {
+        let mut counters = HashMap::new();
+
+        counters.insert(
+            "read_bytes",
+            Wrapping(self.counters.read_bytes.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "write_bytes",
+            Wrapping(self.counters.write_bytes.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "read_ops",
+            Wrapping(self.counters.read_ops.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "write_ops",
+            Wrapping(self.counters.write_ops.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "write_latency_min",
+            Wrapping(self.counters.write_latency_min.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "write_latency_max",
+            Wrapping(self.counters.write_latency_max.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "write_latency_avg",
+            Wrapping(self.counters.write_latency_avg.load(Ordering::Acquire) / LATENCY_SCALE),
+        );
+        counters.insert(
+            "read_latency_min",
+            Wrapping(self.counters.read_latency_min.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "read_latency_max",
+            Wrapping(self.counters.read_latency_max.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "read_latency_avg",
+            Wrapping(self.counters.read_latency_avg.load(Ordering::Acquire) / LATENCY_SCALE),
+        );
+        counters.insert(
+            "flush_ops",
+            Wrapping(self.counters.flush_ops.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "coalesced_flush_ops",
+            Wrapping(self.counters.coalesced_flush_ops.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "flush_time",
+            Wrapping(self.counters.flush_time.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "flush_time_max",
+            Wrapping(self.counters.flush_time_max.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "completions",
+            Wrapping(self.counters.completions.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "completion_wakeups",
+            Wrapping(self.counters.completion_wakeups.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "bounced_ops",
+            Wrapping(self.counters.bounced_ops.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "bounced_bytes",
+            Wrapping(self.counters.bounced_bytes.load(Ordering::Acquire)),
+        );
+
+        if self.thin_pool.is_some() {
+            counters.insert(
+                "thin_pool_full_errors",
+                Wrapping(self.counters.thin_pool_full_errors.load(Ordering::Acquire)),
+            );
+        }
+        if let Some(status) = self.thin_pool_status() {
+            counters.insert(
+                "thin_pool_data_used_blocks",
+                Wrapping(status.data_used_blocks),
+            );
+            counters.insert("thin_pool_data_blocks", Wrapping(status.data_total_blocks));
+            counters.insert(
+                "thin_pool_metadata_used_blocks",
+                Wrapping(status.metadata_used_blocks),
+            );
+            counters.insert(
+                "thin_pool_metadata_blocks",
+                Wrapping(status.metadata_total_blocks),
+            );
+        }
+
+        if let Some(scrubber) = &self.scrubber {
+            counters.insert("scrub_total_bytes", Wrapping(scrubber.total_bytes()));
+            counters.insert("scrub_scanned_bytes", Wrapping(scrubber.scanned_bytes()));
+            counters.insert("scrub_mismatches", Wrapping(scrubber.mismatches()));
+        }
+
+        if self.readahead_stats.is_some() {
+            let (prefetched, hit, wasted) = self.readahead_bytes();
+            counters.insert("readahead_bytes", Wrapping(prefetched));
+            counters.insert("readahead_hit_bytes", Wrapping(hit));
+            counters.insert("readahead_wasted_bytes", Wrapping(wasted));
+        }
+
+        if let Some(snapshot) = self.latency_snapshot() {
+            for (name, value) in [
+                ("read_latency_p50", snapshot.read.p50),
+                ("read_latency_p90", snapshot.read.p90),
+                ("read_latency_p99", snapshot.read.p99),
+                ("read_latency_p999", snapshot.read.p999),
+                ("write_latency_p50", snapshot.write.p50),
+                ("write_latency_p90", snapshot.write.p90),
+                ("write_latency_p99", snapshot.write.p99),
+                ("write_latency_p999", snapshot.write.p999),
+                ("flush_latency_p50", snapshot.flush.p50),
+                ("flush_latency_p99", snapshot.flush.p99),
+            ] {
+                counters.insert(name, Wrapping(value));
+            }
+        }
+
+        Some(counters)
+    }
+
+    fn set_access_platform(&mut self, access_platform: Arc<dyn AccessPlatform>) {
+        self.common.set_access_platform(access_platform)
+    }
+}
+
+impl Pausable for Block {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        // The queues can't complete the writes held by the barrier without
+        // them reaching the disk being captured.
+        if self.write_barrier.state.lock().unwrap().timeout.is_some() {
+            return Err(MigratableError::Pause(anyhow!(
+                "Cannot pause virtio-block {} while its writes are held",
+                self.id
+            )));
+        }
+
+        self.common.pause()
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        self.common.resume()
+    }
+}
+
+impl Snapshottable for Block {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        Snapshot::new_from_state(&self.state())
+    }
+}
+impl Transportable for Block {}
+impl Migratable for Block {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block::async_io::{AsyncIoResult, DiskFileResult};
+    use block::{DiscardWriteZeroesSegment, DiskTopology};
+    use std::thread;
+    use virtio_bindings::virtio_ring::{VRING_DESC_F_NEXT, VRING_DESC_F_WRITE};
+    use vm_memory::Address;
+    use vm_virtio::queue::testing::VirtQueue as GuestQ;
+
+    const QUEUE_SIZE: u16 = 64;
+    const DISK_SIZE: usize = 1 << 20;
+    const MEM_SIZE: usize = 1 << 24;
+
+    struct NoopVirtioInterrupt {}
+
+    impl VirtioInterrupt for NoopVirtioInterrupt {
+        fn trigger(&self, _int_type: VirtioInterruptType) -> result::Result<(), io::Error> {
+            Ok(())
+        }
+    }
+
+    // Disk image held in memory. Its writes fail with ENOSPC while `full`
+    // is set, its reads complete after `read_delay`, when set, from another
+    // thread. The offsets and lengths of its reads and writes are recorded
+    // in `transfers`, the ranges it discards in `discards`, and its flushes
+    // in `flushes`.
+    struct TestDisk {
+        data: Arc<Mutex<Vec<u8>>>,
+        full: Arc<AtomicBool>,
+        read_delay: Option<Duration>,
+        // Requests taken before their completions are reaped, if bounded.
+        room: Option<usize>,
+        transfers: Arc<Mutex<Vec<(u64, usize)>>>,
+        discards: Arc<Mutex<Vec<(u64, u64)>>>,
+        flushes: Arc<AtomicU64>,
+        completions: Arc<Mutex<VecDeque<(u64, i32)>>>,
+        evt: EventFd,
+    }
+
+    impl TestDisk {
+        fn new(pattern: u8) -> Self {
+            TestDisk {
+                data: Arc::new(Mutex::new(vec![pattern; DISK_SIZE])),
+                full: Arc::new(AtomicBool::new(false)),
+                read_delay: None,
+                room: None,
+                transfers: Arc::new(Mutex::new(Vec::new())),
+                discards: Arc::new(Mutex::new(Vec::new())),
+                flushes: Arc::new(AtomicU64::new(0)),
+                completions: Arc::new(Mutex::new(VecDeque::new())),
+                evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            }
+        }
+
+        fn complete(&self, user_data: u64, result: i32) {
+            self.completions
+                .lock()
+                .unwrap()
+                .push_back((user_data, result));
+            self.evt.write(1).unwrap();
+        }
+    }
+
+    impl AsyncIo for TestDisk {
+        fn notifier(&self) -> &EventFd {
+            &self.evt
+        }
+
+        fn read_vectored(
+            &mut self,
+            offset: libc::off_t,
+            iovecs: &[libc::iovec],
+            user_data: u64,
+        ) -> AsyncIoResult<()> {
+            let data = self.data.lock().unwrap();
+            let mut len = 0;
+            for iovec in iovecs {
+                let start = offset as usize + len;
+                // SAFETY: the iovecs point to the guest memory of the test
+                let buf = unsafe {
+                    std::slice::from_raw_parts_mut(iovec.iov_base as *mut u8, iovec.iov_len)
+                };
+                buf.copy_from_slice(&data[start..start + iovec.iov_len]);
+                len += iovec.iov_len;
+            }
+
+            match self.read_delay {
+                Some(delay) => {
+                    let completions = self.completions.clone();
+                    let evt = self.evt.try_clone().unwrap();
+                    thread::spawn(move || {
+                        thread::sleep(delay);
+                        completions
+                            .lock()
+                            .unwrap()
+                            .push_back((user_data, len as i32));
+                        evt.write(1).unwrap();
+                    });
+                }
+                None => self.complete(user_data, len as i32),
+            }
+            self.transfers.lock().unwrap().push((offset as u64, len));
+
+            Ok(())
+        }
+
+        fn write_vectored(
+            &mut self,
+            offset: libc::off_t,
+            iovecs: &[libc::iovec],
+            user_data: u64,
+        ) -> AsyncIoResult<()> {
+            if self.full.load(Ordering::Acquire) {
+                self.complete(user_data, -libc::ENOSPC);
+                return Ok(());
+            }
+
+            let mut data = self.data.lock().unwrap();
+            let mut len = 0;
+            for iovec in iovecs {
+                let start = offset as usize + len;
+                // SAFETY: the iovecs point to the guest memory of the test
+                let buf = unsafe {
+                    std::slice::from_raw_parts(iovec.iov_base as *const u8, iovec.iov_len)
+                };
+                data[start..start + iovec.iov_len].copy_from_slice(buf);
+                len += iovec.iov_len;
+            }
+            drop(data);
+            self.transfers.lock().unwrap().push((offset as u64, len));
+            self.complete(user_data, len as i32);
+
+            Ok(())
+        }
+
+        fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+            self.flushes.fetch_add(1, Ordering::AcqRel);
+            if let Some(user_data) = user_data {
+                self.complete(user_data, 0);
+            }
+
+            Ok(())
         }
 
-        self.common.epoll_threads = Some(epoll_threads);
-        event!("virtio-device", "activated", "id", &self.id);
+        fn discard(
+            &mut self,
+            offset: libc::off_t,
+            length: u64,
+            user_data: u64,
+        ) -> AsyncIoResult<()> {
+            let start = offset as usize;
+            self.data.lock().unwrap()[start..start + length as usize].fill(0);
+            self.discards.lock().unwrap().push((offset as u64, length));
+            self.complete(user_data, 0);
 
-        Ok(())
+            Ok(())
+        }
+
+        fn next_completed_request(&mut self) -> Option<(u64, i32)> {
+            self.completions.lock().unwrap().pop_front()
+        }
+
+        fn submission_room(&self) -> usize {
+            self.room.map_or(usize::MAX, |room| {
+                room.saturating_sub(self.completions.lock().unwrap().len())
+            })
+        }
     }
 
-    fn reset(&mut self) -> Option<Arc<dyn VirtioInterrupt>> {
-        let result = self.common.reset();
-        event!("virtio-device", "reset", "id", &self.id);
-        result
+    struct TestContext<'a> {
+        mem: &'a GuestMemoryMmap,
+        guest_queue: GuestQ<'a>,
+        handler: BlockEpollHandler,
+        helper: EpollHelper,
     }
 
-    fn counters(&self) -> Option<HashMap<&'static str, Wrapping<u64>>> {
-        let mut counters = HashMap::new();
+    impl<'a> TestContext<'a> {
+        fn new(mem: &'a GuestMemoryMmap, disk_image: TestDisk) -> Self {
+            let guest_queue = GuestQ::new(GuestAddress(0), mem, QUEUE_SIZE);
+            let kill_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+            let pause_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+            let mut helper = EpollHelper::new(&kill_evt, &pause_evt).unwrap();
+            helper
+                .add_event(disk_image.notifier().as_raw_fd(), COMPLETION_EVENT)
+                .unwrap();
 
-        counters.insert(
-            "read_bytes",
-            Wrapping(self.counters.read_bytes.load(Ordering::Acquire)),
-        );
-        counters.insert(
-            "write_bytes",
-            Wrapping(self.counters.write_bytes.load(Ordering::Acquire)),
+            let handler = BlockEpollHandler {
+                queue_index: 0,
+                queue: guest_queue.create_queue(),
+                mem: GuestMemoryAtomic::new(mem.clone()),
+                disk_image: Box::new(disk_image),
+                disk_nsectors: Arc::new(AtomicU64::new(DISK_SIZE as u64 / SECTOR_SIZE)),
+                interrupt_cb: Arc::new(NoopVirtioInterrupt {}),
+                serial: Vec::new(),
+                lifetime: None,
+                kill_evt,
+                pause_evt,
+                writeback: Arc::new(AtomicBool::new(true)),
+                durable_writes: false,
+                zeroing_discards: false,
+                counters: BlockCounters::default(),
+                queue_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+                inflight_requests: VecDeque::new(),
+                merged_requests: HashMap::new(),
+                max_merge_size: None,
+                max_inflight: None,
+                inflight_limit_reached: false,
+                flush_timer: None,
+                pending_flushes: Vec::new(),
+                idle_flush: None,
+                request_timeout: None,
+                rate_limiter: None,
+                read_rate_limiter: None,
+                write_rate_limiter: None,
+                access_platform: None,
+                read_only: false,
+                seg_max: 0,
+                size_max: 0,
+                max_discard_sectors: 0,
+                max_write_zeroes_sectors: 0,
+                host_cpus: None,
+                write_barrier: Arc::new(WriteBarrier::default()),
+                write_barrier_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+                write_barrier_timer: TimerFd::new().unwrap(),
+                writes_held: false,
+                held_writes: VecDeque::new(),
+                enospc_policy: EnospcPolicy::Pause,
+                out_of_space: Arc::new(OutOfSpace {
+                    id: String::from("disk0"),
+                    paused: AtomicBool::new(false),
+                    thin_pool_full: AtomicBool::new(false),
+                }),
+                out_of_space_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+                new_medium: Arc::new(Mutex::new(None)),
+                medium_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+                thin_pool: None,
+            };
+
+            TestContext {
+                mem,
+                guest_queue,
+                handler,
+                helper,
+            }
+        }
+
+        // Adds a read, write or device ID request of a single sector to the
+        // avail ring, from the descriptors starting at `head`, and returns
+        // the address of its data buffer.
+        fn add_request(&self, head: u16, request_type: u32, sector: u64) -> GuestAddress {
+            let header = GuestAddress(0x10_0000 + u64::from(head) * 0x100);
+            let status = header.unchecked_add(0x10);
+            let data = GuestAddress(0x20_0000 + u64::from(head) * 0x1000);
+            self.mem.write_obj(request_type, header).unwrap();
+            self.mem.write_obj(sector, header.unchecked_add(8)).unwrap();
+            self.mem.write_obj(0xffu8, status).unwrap();
+
+            let data_flags =
+                if request_type == VIRTIO_BLK_T_IN || request_type == VIRTIO_BLK_T_GET_ID {
+                    VRING_DESC_F_WRITE | VRING_DESC_F_NEXT
+                } else {
+                    VRING_DESC_F_NEXT
+                };
+            let dtable = &self.guest_queue.dtable;
+            dtable[head as usize].set(header.0, 16, VRING_DESC_F_NEXT as u16, head + 1);
+            dtable[head as usize + 1].set(data.0, SECTOR_SIZE as u32, data_flags as u16, head + 2);
+            dtable[head as usize + 2].set(status.0, 1, VRING_DESC_F_WRITE as u16, 0);
+
+            let avail_idx = self.guest_queue.avail.idx.get();
+            self.guest_queue.avail.ring[(avail_idx % QUEUE_SIZE) as usize].set(head);
+            self.guest_queue.avail.idx.set(avail_idx.wrapping_add(1));
+
+            data
+        }
+
+        // Adds a flush to the avail ring, from the descriptors starting at
+        // `head`.
+        fn add_flush(&self, head: u16) {
+            let header = GuestAddress(0x10_0000 + u64::from(head) * 0x100);
+            let status = header.unchecked_add(0x10);
+            self.mem.write_obj(VIRTIO_BLK_T_FLUSH, header).unwrap();
+            self.mem.write_obj(0u64, header.unchecked_add(8)).unwrap();
+            self.mem.write_obj(0xffu8, status).unwrap();
+
+            let dtable = &self.guest_queue.dtable;
+            dtable[head as usize].set(header.0, 16, VRING_DESC_F_NEXT as u16, head + 1);
+            dtable[head as usize + 1].set(status.0, 1, VRING_DESC_F_WRITE as u16, 0);
+
+            let avail_idx = self.guest_queue.avail.idx.get();
+            self.guest_queue.avail.ring[(avail_idx % QUEUE_SIZE) as usize].set(head);
+            self.guest_queue.avail.idx.set(avail_idx.wrapping_add(1));
+        }
+
+        // Adds a discard of `num_sectors` from `sector` to the avail ring,
+        // from the descriptors starting at `head`.
+        fn add_discard(&self, head: u16, sector: u64, num_sectors: u32) {
+            let data = self.add_request(head, VIRTIO_BLK_T_DISCARD, 0);
+            let segment = DiscardWriteZeroesSegment {
+                sector,
+                num_sectors,
+                flags: 0,
+            };
+            self.mem.write_obj(segment, data).unwrap();
+            self.guest_queue.dtable[head as usize + 1].set(
+                data.0,
+                std::mem::size_of::<DiscardWriteZeroesSegment>() as u32,
+                VRING_DESC_F_NEXT as u16,
+                head + 2,
+            );
+        }
+
+        fn handle_event(&mut self, event: u16) {
+            let event = epoll::Event::new(epoll::Events::EPOLLIN, event as u64);
+            self.handler.handle_event(&mut self.helper, &event).unwrap();
+        }
+
+        fn kick(&mut self) {
+            self.handler.queue_evt.write(1).unwrap();
+            self.handle_event(QUEUE_AVAIL_EVENT);
+        }
+
+        // Heads and lengths of the requests completed, in order.
+        fn used_elems(&self) -> Vec<(u32, u32)> {
+            let used_idx = self.guest_queue.used.idx.get();
+            (0..used_idx)
+                .map(|i| {
+                    let elem = &self.guest_queue.used.ring[(i % QUEUE_SIZE) as usize];
+                    let head = self.mem.read_obj(elem.location).unwrap();
+                    let len = self.mem.read_obj(elem.location.unchecked_add(4)).unwrap();
+                    (head, len)
+                })
+                .collect()
+        }
+
+        fn used_heads(&self) -> Vec<u32> {
+            self.used_elems()
+                .into_iter()
+                .map(|(head, _)| head)
+                .collect()
+        }
+
+        fn status(&self, head: u16) -> u8 {
+            let status = GuestAddress(0x10_0000 + u64::from(head) * 0x100 + 0x10);
+            self.mem.read_obj(status).unwrap()
+        }
+
+        fn data(&self, data: GuestAddress) -> Vec<u8> {
+            let mut buf = vec![0u8; SECTOR_SIZE as usize];
+            self.mem.read_slice(&mut buf, data).unwrap();
+            buf
+        }
+    }
+
+    fn test_memory() -> GuestMemoryMmap {
+        GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap()
+    }
+
+    #[test]
+    fn test_medium_change() {
+        let mem = test_memory();
+        let mut ctx = TestContext::new(&mem, TestDisk::new(0xaa));
+
+        let data = ctx.add_request(0, VIRTIO_BLK_T_IN, 0);
+        ctx.kick();
+        ctx.handle_event(COMPLETION_EVENT);
+        assert_eq!(ctx.used_heads(), [0]);
+        assert!(ctx.data(data).iter().all(|b| *b == 0xaa));
+
+        // Once the queue picked up the new medium, the reads are served from
+        // it.
+        *ctx.handler.new_medium.lock().unwrap() = Some(Box::new(TestDisk::new(0xbb)));
+        ctx.handler.medium_evt.write(1).unwrap();
+        ctx.handle_event(MEDIUM_CHANGE_EVENT);
+        assert!(ctx.handler.new_medium.lock().unwrap().is_none());
+
+        let data = ctx.add_request(3, VIRTIO_BLK_T_IN, 0);
+        ctx.kick();
+        ctx.handle_event(COMPLETION_EVENT);
+        assert_eq!(ctx.used_heads(), [0, 3]);
+        assert_eq!(ctx.status(3), VIRTIO_BLK_S_OK as u8);
+        assert!(ctx.data(data).iter().all(|b| *b == 0xbb));
+    }
+
+    #[test]
+    fn test_medium_change_completes_old_reads() {
+        let mem = test_memory();
+        let mut old_medium = TestDisk::new(0xaa);
+        old_medium.read_delay = Some(Duration::from_millis(100));
+        let mut ctx = TestContext::new(&mem, old_medium);
+
+        let old_data = ctx.add_request(0, VIRTIO_BLK_T_IN, 0);
+        ctx.kick();
+        assert!(ctx.used_heads().is_empty());
+
+        // The queue is kicked for a request submitted after the medium
+        // change, before the medium change event is handled: the read in
+        // flight on the old medium completes before the new one is served
+        // from the new medium.
+        *ctx.handler.new_medium.lock().unwrap() = Some(Box::new(TestDisk::new(0xbb)));
+        let new_data = ctx.add_request(3, VIRTIO_BLK_T_IN, 0);
+        ctx.kick();
+        assert_eq!(ctx.used_heads(), [0]);
+        assert_eq!(ctx.status(0), VIRTIO_BLK_S_OK as u8);
+        assert!(ctx.data(old_data).iter().all(|b| *b == 0xaa));
+
+        ctx.handle_event(COMPLETION_EVENT);
+        assert_eq!(ctx.used_heads(), [0, 3]);
+        assert!(ctx.data(new_data).iter().all(|b| *b == 0xbb));
+    }
+
+    #[test]
+    fn test_out_of_space_pause() {
+        let mem = test_memory();
+        let disk_image = TestDisk::new(0xaa);
+        let full = disk_image.full.clone();
+        let data = disk_image.data.clone();
+        full.store(true, Ordering::Release);
+        let mut ctx = TestContext::new(&mem, disk_image);
+
+        // A write failing with ENOSPC is held, rather than completed.
+        let write_data = ctx.add_request(0, VIRTIO_BLK_T_OUT, 1);
+        mem.write_slice(&[0x55u8; SECTOR_SIZE as usize], write_data)
+            .unwrap();
+        ctx.kick();
+        ctx.handle_event(COMPLETION_EVENT);
+        assert!(ctx.used_heads().is_empty());
+        assert!(ctx.handler.out_of_space.paused.load(Ordering::Acquire));
+        assert_eq!(ctx.handler.held_writes.len(), 1);
+
+        // The reads go on while the writes are paused, the next writes being
+        // held along with the first one.
+        let read_data = ctx.add_request(3, VIRTIO_BLK_T_IN, 0);
+        ctx.add_request(6, VIRTIO_BLK_T_OUT, 2);
+        ctx.kick();
+        ctx.handle_event(COMPLETION_EVENT);
+        assert_eq!(ctx.used_heads(), [3]);
+        assert!(ctx.data(read_data).iter().all(|b| *b == 0xaa));
+        assert_eq!(ctx.handler.held_writes.len(), 2);
+
+        // Once resumed, the held writes are retried in order.
+        full.store(false, Ordering::Release);
+        ctx.handler
+            .out_of_space
+            .paused
+            .store(false, Ordering::Release);
+        ctx.handler.out_of_space_evt.write(1).unwrap();
+        ctx.handle_event(OUT_OF_SPACE_EVENT);
+        ctx.handle_event(COMPLETION_EVENT);
+        assert_eq!(ctx.used_heads(), [3, 0, 6]);
+        assert_eq!(ctx.status(0), VIRTIO_BLK_S_OK as u8);
+        assert_eq!(ctx.status(6), VIRTIO_BLK_S_OK as u8);
+        let sector = SECTOR_SIZE as usize;
+        assert!(data.lock().unwrap()[sector..2 * sector]
+            .iter()
+            .all(|b| *b == 0x55));
+    }
+
+    #[test]
+    fn test_directional_rate_limiters() {
+        let mem = test_memory();
+        let mut ctx = TestContext::new(&mem, TestDisk::new(0xaa));
+        let disk_limiter = RateLimiterGroup::new("disk0", 0, 0, 0, 5, 0, 10000).unwrap();
+        let write_limiter = RateLimiterGroup::new("disk0_write", 0, 0, 0, 1, 0, 10000).unwrap();
+        ctx.handler.rate_limiter = Some(disk_limiter.new_handle().unwrap());
+        ctx.handler.write_rate_limiter = Some(write_limiter.new_handle().unwrap());
+
+        // The reads are only limited by the disk wide budget.
+        for head in [0, 3, 6] {
+            ctx.add_request(head, VIRTIO_BLK_T_IN, 0);
+        }
+        ctx.kick();
+        ctx.handle_event(COMPLETION_EVENT);
+        assert_eq!(ctx.used_heads(), [0, 3, 6]);
+
+        // The second write exceeds the write budget, and is left on the avail
+        // ring.
+        ctx.add_request(9, VIRTIO_BLK_T_OUT, 0);
+        ctx.add_request(12, VIRTIO_BLK_T_OUT, 0);
+        ctx.kick();
+        ctx.handle_event(COMPLETION_EVENT);
+        assert_eq!(ctx.used_heads(), [0, 3, 6, 9]);
+        assert!(ctx
+            .handler
+            .write_rate_limiter
+            .as_ref()
+            .unwrap()
+            .is_blocked());
+
+        // What it consumed from the disk wide budget was given back.
+        let disk_budget = disk_limiter.new_handle().unwrap();
+        assert!(disk_budget.consume(1, TokenType::Ops));
+        assert!(!disk_budget.consume(1, TokenType::Ops));
+    }
+
+    #[test]
+    fn test_discard_rate_limit() {
+        let mem = test_memory();
+        let disk_image = TestDisk::new(0xaa);
+        let discards = disk_image.discards.clone();
+        let mut ctx = TestContext::new(&mem, disk_image);
+        let disk_limiter = RateLimiterGroup::new("disk0", 4096, 0, 10000, 0, 0, 0).unwrap();
+        let write_limiter = RateLimiterGroup::new("disk0_write", 0, 0, 0, 2, 0, 10000).unwrap();
+        ctx.handler.rate_limiter = Some(disk_limiter.new_handle().unwrap());
+        ctx.handler.write_rate_limiter = Some(write_limiter.new_handle().unwrap());
+
+        // Discards far larger than the bandwidth budget go through, but count
+        // against the write operations.
+        ctx.add_discard(0, 0, 512);
+        ctx.add_discard(3, 1024, 512);
+        ctx.add_discard(6, 1600, 8);
+        ctx.kick();
+        ctx.handle_event(COMPLETION_EVENT);
+        assert_eq!(ctx.used_heads(), [0, 3]);
+        assert_eq!(
+            *discards.lock().unwrap(),
+            [
+                (0, 512 * SECTOR_SIZE),
+                (1024 * SECTOR_SIZE, 512 * SECTOR_SIZE)
+            ]
         );
-        counters.insert(
-            "read_ops",
-            Wrapping(self.counters.read_ops.load(Ordering::Acquire)),
+        assert!(ctx
+            .handler
+            .write_rate_limiter
+            .as_ref()
+            .unwrap()
+            .is_blocked());
+
+        // None of the bandwidth budget was consumed.
+        let disk_budget = disk_limiter.new_handle().unwrap();
+        assert!(disk_budget.consume(4096, TokenType::Bytes));
+    }
+
+    fn test_block(disk_image: Box<dyn DiskFile>, read_only: bool, serial: Option<&str>) -> Block {
+        Block::new(
+            String::from("disk0"),
+            disk_image,
+            PathBuf::from("/dev/null"),
+            read_only,
+            false,
+            1,
+            QUEUE_SIZE,
+            serial.map(String::from),
+            SeccompAction::Allow,
+            None,
+            None,
+            None,
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+            None,
+            BTreeMap::new(),
+        )
+        .unwrap()
+    }
+
+    // Capacity in sectors, as read by the guest.
+    fn config_capacity(block: &Block) -> u64 {
+        let mut capacity = [0u8; 8];
+        block.read_config(0, &mut capacity);
+        u64::from_le_bytes(capacity)
+    }
+
+    #[test]
+    fn test_change_medium() {
+        let mut block = test_block(
+            Box::new(NullDiskFile::new(DISK_SIZE as u64, None)),
+            true,
+            None,
         );
-        counters.insert(
-            "write_ops",
-            Wrapping(self.counters.write_ops.load(Ordering::Acquire)),
+        assert!(matches!(
+            block.change_medium(None),
+            Err(Error::NotRemovable)
+        ));
+
+        // Each queue is handed an AsyncIo of the new medium.
+        block.set_removable(true);
+        let disk_image = Arc::new(Mutex::new(None));
+        let evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        block.medium_changes.push(MediumChange {
+            disk_image: disk_image.clone(),
+            ring_depth: QUEUE_SIZE as u32,
+            evt: evt.try_clone().unwrap(),
+        });
+        let medium = NullDiskFile::new(2 * DISK_SIZE as u64, None);
+        block
+            .change_medium(Some((Box::new(medium), PathBuf::from("/medium"))))
+            .unwrap();
+        assert_eq!(block.capacity(), 2 * DISK_SIZE as u64);
+        assert_eq!(block.disk_path, PathBuf::from("/medium"));
+        assert!(disk_image.lock().unwrap().take().is_some());
+        assert_eq!(evt.read().unwrap(), 1);
+
+        // Ejecting the medium leaves a device of no capacity.
+        block.change_medium(None).unwrap();
+        assert_eq!(block.capacity(), 0);
+        assert!(disk_image.lock().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_resize() {
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap();
+        file.as_file().set_len(DISK_SIZE as u64).unwrap();
+        let disk_image = block::raw_sync::RawFileDiskSync::new(
+            file.as_file().try_clone().unwrap(),
+            false,
+            CacheMode::Writeback,
+            None,
+        )
+        .unwrap();
+        let mut block = test_block(Box::new(disk_image), false, None);
+        assert_eq!(config_capacity(&block), DISK_SIZE as u64 / SECTOR_SIZE);
+
+        // The new size is picked up, the remainder of a partial sector being
+        // left out.
+        file.as_file().set_len(2 * DISK_SIZE as u64 + 100).unwrap();
+        block.resize().unwrap();
+        assert_eq!(block.capacity(), 2 * DISK_SIZE as u64);
+        assert_eq!(config_capacity(&block), 2 * DISK_SIZE as u64 / SECTOR_SIZE);
+
+        // Shrinking is refused, the guest keeping the capacity it knows.
+        file.as_file().set_len(DISK_SIZE as u64).unwrap();
+        assert!(matches!(
+            block.resize(),
+            Err(Error::DiskResize(DiskFileError::Shrunk(..)))
+        ));
+        assert_eq!(block.capacity(), 2 * DISK_SIZE as u64);
+    }
+
+    #[test]
+    fn test_flush() {
+        let disk = |file: File| {
+            let disk_image =
+                block::raw_sync::RawFileDiskSync::new(file, false, CacheMode::Writeback, None)
+                    .unwrap();
+            test_block(Box::new(disk_image), false, None)
+        };
+
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap();
+        disk(file.into_file()).flush().unwrap();
+
+        // The failure of the fsync is reported to the caller.
+        let null = std::fs::OpenOptions::new()
+            .write(true)
+            .open("/dev/null")
+            .unwrap();
+        assert!(matches!(
+            disk(null).flush(),
+            Err(Error::Fsync(AsyncIoError::Fsync(_)))
+        ));
+    }
+
+    #[test]
+    fn test_discard_merge() {
+        let mut discard = PendingDiscard::new(4096, 4096);
+        // Contiguous at either end, or overlapping.
+        assert!(discard.merge(8192, 4096, 0));
+        assert!(discard.merge(0, 4096, 0));
+        assert!(discard.merge(2048, 8192, 0));
+        assert_eq!((discard.offset, discard.length), (0, 3 * 4096));
+
+        // Not past a gap, which the guest didn't ask to discard.
+        assert!(!discard.merge(3 * 4096 + 512, 4096, 0));
+        // Nor past the largest discard.
+        assert!(!discard.merge(3 * 4096, 4096, 3 * 4096));
+        assert_eq!((discard.offset, discard.length), (0, 3 * 4096));
+    }
+
+    #[test]
+    fn test_discard_coalescing() {
+        let mem = test_memory();
+        let disk_image = TestDisk::new(0xaa);
+        let discards = disk_image.discards.clone();
+        let mut ctx = TestContext::new(&mem, disk_image);
+
+        // Two contiguous discards and an overlapping one are merged, but the
+        // discard contiguous to them following a read isn't.
+        ctx.add_discard(0, 0, 8);
+        ctx.add_discard(3, 8, 8);
+        ctx.add_discard(6, 4, 6);
+        let data = ctx.add_request(9, VIRTIO_BLK_T_IN, 0);
+        ctx.add_discard(12, 16, 8);
+        ctx.kick();
+        ctx.handle_event(COMPLETION_EVENT);
+        assert_eq!(
+            *discards.lock().unwrap(),
+            [(0, 16 * SECTOR_SIZE), (16 * SECTOR_SIZE, 8 * SECTOR_SIZE)]
         );
-        counters.insert(
-            "write_latency_min",
-            Wrapping(self.counters.write_latency_min.load(Ordering::Acquire)),
+
+        // Every request completed, the read after the discards.
+        let mut used_heads = ctx.used_heads();
+        used_heads.sort();
+        assert_eq!(used_heads, [0, 3, 6, 9, 12]);
+        for head in [0, 3, 6, 9, 12] {
+            assert_eq!(ctx.status(head), VIRTIO_BLK_S_OK as u8);
+        }
+        assert!(ctx.data(data).iter().all(|b| *b == 0));
+    }
+
+    // Null disk reporting the given topology and serial.
+    struct DescribedDisk {
+        inner: NullDiskFile,
+        topology: DiskTopology,
+        serial: Option<String>,
+    }
+
+    impl DescribedDisk {
+        fn new(topology: DiskTopology, serial: Option<&str>) -> Self {
+            DescribedDisk {
+                inner: NullDiskFile::new(DISK_SIZE as u64, None),
+                topology,
+                serial: serial.map(String::from),
+            }
+        }
+    }
+
+    impl DiskFile for DescribedDisk {
+        fn size(&mut self) -> DiskFileResult<u64> {
+            self.inner.size()
+        }
+
+        fn new_async_io(&self, ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+            self.inner.new_async_io(ring_depth)
+        }
+
+        fn topology(&mut self) -> DiskTopology {
+            self.topology.clone()
+        }
+
+        fn serial(&mut self) -> Option<String> {
+            self.serial.clone()
+        }
+    }
+
+    #[test]
+    fn test_io_size_hints() {
+        let guest_config = |topology| {
+            let disk_image = DescribedDisk::new(topology, None);
+            let block = test_block(Box::new(disk_image), false, None);
+            assert_ne!(
+                block.common.avail_features & (1u64 << VIRTIO_BLK_F_TOPOLOGY),
+                0
+            );
+            let mut config = VirtioBlockConfig::default();
+            block.read_config(0, config.as_mut_slice());
+            config
+        };
+
+        // The hints are expressed in logical blocks.
+        let config = guest_config(DiskTopology {
+            logical_block_size: 4096,
+            physical_block_size: 4096,
+            minimum_io_size: 64 << 10,
+            optimal_io_size: 1 << 20,
+            ..Default::default()
+        });
+        assert_eq!({ config.blk_size }, 4096);
+        assert_eq!({ config.min_io_size }, 16);
+        assert_eq!({ config.opt_io_size }, 256);
+
+        // Without any hint, the minimum is a single block and there is no
+        // optimal size.
+        let config = guest_config(DiskTopology::default());
+        assert_eq!({ config.min_io_size }, 1);
+        assert_eq!({ config.opt_io_size }, 0);
+    }
+
+    #[test]
+    fn test_flush_stats() {
+        let mem = test_memory();
+        let mut ctx = TestContext::new(&mem, TestDisk::new(0xaa));
+        let mut block = test_block(
+            Box::new(NullDiskFile::new(DISK_SIZE as u64, None)),
+            false,
+            None,
         );
-        counters.insert(
-            "write_latency_max",
-            Wrapping(self.counters.write_latency_max.load(Ordering::Acquire)),
+        block.counters = ctx.handler.counters.clone();
+
+        // Each flush request completed is counted, along with its fsync.
+        ctx.add_flush(0);
+        ctx.add_flush(2);
+        ctx.kick();
+        ctx.handle_event(COMPLETION_EVENT);
+        assert_eq!(ctx.used_heads(), [0, 2]);
+        let stats = block.stats();
+        assert_eq!((stats.flush_ops, stats.coalesced_flush_ops), (2, 0));
+        assert!(stats.flush_time_max <= stats.flush_time);
+
+        // The longest fsync is kept, the coalesced requests counted as
+        // flushes of their own.
+        let counters = BlockCounters::default();
+        block.counters = counters.clone();
+        counters.note_fsync(100, 0);
+        counters.note_fsync(40, 2);
+        let stats = block.stats();
+        assert_eq!((stats.flush_ops, stats.coalesced_flush_ops), (4, 2));
+        assert_eq!((stats.flush_time, stats.flush_time_max), (140, 100));
+
+        // The rate is the one of the previous second, not of the current
+        // one still counting.
+        let mut rate = FlushRate::new();
+        rate.add(3);
+        assert_eq!(rate.per_second(), 0);
+        rate.start -= Duration::from_secs(1);
+        assert_eq!(rate.per_second(), 3);
+        rate.add(1);
+        // Nothing was completed during the last second.
+        rate.start -= Duration::from_secs(2);
+        assert_eq!(rate.per_second(), 0);
+    }
+
+    #[test]
+    fn test_flush_coalescing() {
+        let mem = test_memory();
+        let disk_image = TestDisk::new(0xaa);
+        let flushes = disk_image.flushes.clone();
+        let mut ctx = TestContext::new(&mem, disk_image);
+        ctx.handler.flush_timer = Some((TimerFd::new().unwrap(), Duration::from_millis(5)));
+
+        // The flushes are held until the window expires, unlike the write.
+        ctx.add_request(0, VIRTIO_BLK_T_OUT, 0);
+        ctx.add_flush(3);
+        ctx.add_flush(6);
+        ctx.kick();
+        ctx.handle_event(COMPLETION_EVENT);
+        assert_eq!(ctx.used_heads(), [0]);
+        assert_eq!(flushes.load(Ordering::Acquire), 0);
+
+        // Then submitted as a single fsync, completing all of them.
+        ctx.handle_event(FLUSH_TIMER_EVENT);
+        assert_eq!(flushes.load(Ordering::Acquire), 1);
+        ctx.handle_event(COMPLETION_EVENT);
+        assert_eq!(ctx.used_heads(), [0, 3, 6]);
+        assert_eq!(ctx.status(3), VIRTIO_BLK_S_OK as u8);
+        assert_eq!(ctx.status(6), VIRTIO_BLK_S_OK as u8);
+        assert_eq!(
+            ctx.handler
+                .counters
+                .coalesced_flush_ops
+                .load(Ordering::Acquire),
+            1
         );
-        counters.insert(
-            "write_latency_avg",
-            Wrapping(self.counters.write_latency_avg.load(Ordering::Acquire) / LATENCY_SCALE),
+
+        // A flush arriving once the fsync was submitted waits for the next
+        // window, the writes it covers possibly having completed after it.
+        ctx.add_request(9, VIRTIO_BLK_T_OUT, 8);
+        ctx.add_flush(12);
+        ctx.kick();
+        ctx.handle_event(COMPLETION_EVENT);
+        assert_eq!(ctx.used_heads(), [0, 3, 6, 9]);
+        assert_eq!(flushes.load(Ordering::Acquire), 1);
+        ctx.handle_event(FLUSH_TIMER_EVENT);
+        ctx.handle_event(COMPLETION_EVENT);
+        assert_eq!(flushes.load(Ordering::Acquire), 2);
+        assert_eq!(ctx.used_heads(), [0, 3, 6, 9, 12]);
+    }
+
+    #[test]
+    fn test_failed_request_status() {
+        let mem = test_memory();
+        let disk_image = TestDisk::new(0xaa);
+        let full = disk_image.full.clone();
+        let mut ctx = TestContext::new(&mem, disk_image);
+        ctx.handler.enospc_policy = EnospcPolicy::Report;
+
+        // The write failing with ENOSPC gets an I/O error, without stopping
+        // the queue.
+        full.store(true, Ordering::Release);
+        ctx.add_request(0, VIRTIO_BLK_T_OUT, 0);
+        ctx.kick();
+        ctx.handle_event(COMPLETION_EVENT);
+        assert_eq!(ctx.used_heads(), [0]);
+        assert_eq!(ctx.status(0), VIRTIO_BLK_S_IOERR as u8);
+
+        let data = ctx.add_request(3, VIRTIO_BLK_T_IN, 0);
+        ctx.kick();
+        ctx.handle_event(COMPLETION_EVENT);
+        assert_eq!(ctx.used_heads(), [0, 3]);
+        assert_eq!(ctx.status(3), VIRTIO_BLK_S_OK as u8);
+        assert!(ctx.data(data).iter().all(|b| *b == 0xaa));
+    }
+
+    #[test]
+    fn test_request_merging() {
+        let mem = test_memory();
+        let disk_image = TestDisk::new(0xaa);
+        let transfers = disk_image.transfers.clone();
+        let mut ctx = TestContext::new(&mem, disk_image);
+        ctx.handler.max_merge_size = Some(4 * SECTOR_SIZE);
+
+        // Contiguous reads are merged whatever their order in the queue,
+        // but not with a write, across a flush, or beyond the largest merge.
+        let mut requests = vec![
+            (VIRTIO_BLK_T_IN, 2),
+            (VIRTIO_BLK_T_IN, 0),
+            (VIRTIO_BLK_T_IN, 1),
+            (VIRTIO_BLK_T_OUT, 3),
+            (VIRTIO_BLK_T_IN, 4),
+            (VIRTIO_BLK_T_FLUSH, 0),
+            (VIRTIO_BLK_T_IN, 5),
+        ];
+        requests.extend((8..13).map(|sector| (VIRTIO_BLK_T_IN, sector)));
+        let mut head = 0;
+        for (request_type, sector) in requests {
+            if request_type == VIRTIO_BLK_T_FLUSH {
+                ctx.add_flush(head);
+                head += 2;
+            } else {
+                ctx.add_request(head, request_type, sector);
+                head += 3;
+            }
+        }
+        ctx.kick();
+        ctx.handle_event(COMPLETION_EVENT);
+        assert_eq!(
+            *transfers.lock().unwrap(),
+            [
+                (0, 1536),
+                (1536, 512),
+                (2048, 512),
+                (2560, 512),
+                (4096, 2048),
+                (6144, 512)
+            ]
         );
-        counters.insert(
-            "read_latency_min",
-            Wrapping(self.counters.read_latency_min.load(Ordering::Acquire)),
+
+        // Each request completes on its own, with its own length.
+        let used_elems = ctx.used_elems();
+        assert_eq!(used_elems.len(), 12);
+        for (head, len) in used_elems {
+            assert_eq!(ctx.status(head as u16), VIRTIO_BLK_S_OK as u8);
+            // The flush is the sixth request.
+            if head != 15 {
+                assert_eq!(len, SECTOR_SIZE as u32);
+            }
+        }
+    }
+
+    #[test]
+    fn test_inflight_limit() {
+        let mem = test_memory();
+        let disk_image = TestDisk::new(0xaa);
+        let transfers = disk_image.transfers.clone();
+        let mut ctx = TestContext::new(&mem, disk_image);
+        ctx.handler.max_inflight = Some(2);
+
+        // Past the limit, the requests are left on the avail ring.
+        for head in [0, 3, 6, 9] {
+            ctx.add_request(head, VIRTIO_BLK_T_IN, u64::from(head));
+        }
+        ctx.kick();
+        assert_eq!(transfers.lock().unwrap().len(), 2);
+        assert!(ctx.used_heads().is_empty());
+
+        // Until completions make room for them.
+        ctx.handle_event(COMPLETION_EVENT);
+        assert_eq!(ctx.used_heads(), [0, 3]);
+        assert_eq!(transfers.lock().unwrap().len(), 4);
+        ctx.handle_event(COMPLETION_EVENT);
+        assert_eq!(ctx.used_heads(), [0, 3, 6, 9]);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut block = test_block(
+            Box::new(NullDiskFile::new(DISK_SIZE as u64, None)),
+            true,
+            None,
         );
-        counters.insert(
-            "read_latency_max",
-            Wrapping(self.counters.read_latency_max.load(Ordering::Acquire)),
+        assert!(block.read_only());
+        // Single queue devices don't advertise their number of queues.
+        assert_eq!(block.num_queues(), 1);
+        assert_eq!(block.logical_block_size(), SECTOR_SIZE as u32);
+        assert_eq!(block.capacity(), DISK_SIZE as u64);
+        assert_eq!(block.stats(), BlockStats::default());
+
+        // The requests are counted while in flight, and their bytes once
+        // completed.
+        let mem = test_memory();
+        let mut ctx = TestContext::new(&mem, TestDisk::new(0xaa));
+        block.counters = ctx.handler.counters.clone();
+        ctx.add_request(0, VIRTIO_BLK_T_IN, 0);
+        ctx.add_request(3, VIRTIO_BLK_T_OUT, 8);
+        ctx.kick();
+        assert_eq!(block.stats().inflight_requests, 2);
+        ctx.handle_event(COMPLETION_EVENT);
+        assert_eq!(
+            block.stats(),
+            BlockStats {
+                read_bytes: SECTOR_SIZE,
+                write_bytes: SECTOR_SIZE,
+                read_ops: 1,
+                write_ops: 1,
+                ..Default::default()
+            }
         );
-        counters.insert(
-            "read_latency_avg",
-            Wrapping(self.counters.read_latency_avg.load(Ordering::Acquire) / LATENCY_SCALE),
+    }
+
+    #[test]
+    fn test_set_num_queues() {
+        let mut block = Block::new(
+            String::from("disk0"),
+            Box::new(NullDiskFile::new(DISK_SIZE as u64, None)),
+            PathBuf::from("/dev/null"),
+            false,
+            false,
+            4,
+            QUEUE_SIZE,
+            None,
+            SeccompAction::Allow,
+            None,
+            None,
+            None,
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+            None,
+            BTreeMap::new(),
+        )
+        .unwrap();
+        let num_queues = |block: &Block| block.config.num_queues;
+
+        // Any number up to the one the device was created with.
+        block.set_num_queues(2).unwrap();
+        assert_eq!(num_queues(&block), 2);
+        block.set_num_queues(4).unwrap();
+        assert_eq!(num_queues(&block), 4);
+        assert!(matches!(
+            block.set_num_queues(5),
+            Err(Error::InvalidNumQueues(5, 4))
+        ));
+        assert!(matches!(
+            block.set_num_queues(0),
+            Err(Error::InvalidNumQueues(0, 4))
+        ));
+
+        // Not below the number of queues served for the driver.
+        block.common.epoll_threads = Some(vec![thread::spawn(|| {}), thread::spawn(|| {})]);
+        assert!(matches!(
+            block.set_num_queues(1),
+            Err(Error::QueuesInUse(2))
+        ));
+        block.set_num_queues(3).unwrap();
+        assert_eq!(num_queues(&block), 3);
+    }
+
+    #[test]
+    fn test_serial() {
+        let serial = |configured, host| {
+            let disk_image = DescribedDisk::new(DiskTopology::default(), host);
+            test_block(Box::new(disk_image), false, configured)
+                .serial
+                .clone()
+        };
+
+        // The configured serial takes precedence over the one of the host
+        // storage, both being padded or truncated to the virtio-blk ID.
+        let mut padded = b"configured".to_vec();
+        padded.resize(VIRTIO_BLK_ID_BYTES as usize, 0);
+        assert_eq!(serial(Some("configured"), Some("host")), padded);
+        let mut padded = b"host".to_vec();
+        padded.resize(VIRTIO_BLK_ID_BYTES as usize, 0);
+        assert_eq!(serial(None, Some("host")), padded);
+        assert_eq!(
+            serial(None, Some("naa.600508b1001c4d5a2f4e6b8c9d0e1f20")),
+            b"naa.600508b1001c4d5a"
         );
 
-        Some(counters)
+        // The ID is reported as is by GET_ID requests.
+        let mem = test_memory();
+        let mut ctx = TestContext::new(&mem, TestDisk::new(0xaa));
+        ctx.handler.serial = serial(None, Some("host"));
+        let data = ctx.add_request(0, VIRTIO_BLK_T_GET_ID, 0);
+        ctx.kick();
+        assert_eq!(ctx.used_heads(), [0]);
+        assert_eq!(ctx.status(0), VIRTIO_BLK_S_OK as u8);
+        assert_eq!(&ctx.data(data)[..VIRTIO_BLK_ID_BYTES as usize], padded);
     }
 
-    fn set_access_platform(&mut self, access_platform: Arc<dyn AccessPlatform>) {
-        self.common.set_access_platform(access_platform)
+    #[test]
+    fn test_quiesce() {
+        let mem = test_memory();
+        let mut disk_image = TestDisk::new(0xaa);
+        disk_image.read_delay = Some(Duration::from_millis(50));
+        let flushes = disk_image.flushes.clone();
+        let mut ctx = TestContext::new(&mem, disk_image);
+        ctx.handler.flush_timer = Some((TimerFd::new().unwrap(), Duration::from_secs(60)));
+
+        // Reads still in flight and a flush held for its window.
+        ctx.add_request(0, VIRTIO_BLK_T_IN, 0);
+        ctx.add_request(3, VIRTIO_BLK_T_IN, 1);
+        ctx.add_flush(6);
+        ctx.kick();
+        assert!(ctx.used_heads().is_empty());
+
+        // All of them are completed before the pause is acknowledged, and
+        // the disk is flushed.
+        ctx.handler.quiesce(&mut ctx.helper).unwrap();
+        let mut used_heads = ctx.used_heads();
+        used_heads.sort();
+        assert_eq!(used_heads, [0, 3, 6]);
+        assert_eq!(
+            ctx.guest_queue.used.idx.get(),
+            ctx.guest_queue.avail.idx.get()
+        );
+        assert!(ctx.handler.inflight_requests.is_empty());
+        assert_eq!(flushes.load(Ordering::Acquire), 2);
     }
-}
 
-impl Pausable for Block {
-    fn pause(&mut self) -> result::Result<(), MigratableError> {
-        self.common.pause()
+    #[test]
+    fn test_idle_flush() {
+        let mem = test_memory();
+        let disk_image = TestDisk::new(0xaa);
+        let flushes = disk_image.flushes.clone();
+        let mut ctx = TestContext::new(&mem, disk_image);
+        let interval = Duration::from_millis(20);
+        ctx.handler.idle_flush = Some(IdleFlush {
+            timer: TimerFd::new().unwrap(),
+            interval,
+            armed: false,
+            last_write: None,
+            in_flight: false,
+        });
+
+        // The disk isn't flushed before the guest stopped writing for the
+        // interval.
+        ctx.add_request(0, VIRTIO_BLK_T_OUT, 0);
+        ctx.kick();
+        ctx.handle_event(COMPLETION_EVENT);
+        assert!(ctx.handler.idle_flush.as_ref().unwrap().armed);
+        ctx.handler.flush_idle_disk().unwrap();
+        assert_eq!(flushes.load(Ordering::Acquire), 0);
+
+        thread::sleep(interval + Duration::from_millis(10));
+        ctx.handle_event(IDLE_FLUSH_TIMER_EVENT);
+        assert_eq!(flushes.load(Ordering::Acquire), 1);
+        assert!(ctx.handler.idle_flush.as_ref().unwrap().in_flight);
+
+        // Neither a flush in flight nor the writes in flight get another
+        // one issued.
+        ctx.add_request(3, VIRTIO_BLK_T_OUT, 8);
+        ctx.kick();
+        thread::sleep(interval + Duration::from_millis(10));
+        ctx.handler.flush_idle_disk().unwrap();
+        assert_eq!(flushes.load(Ordering::Acquire), 1);
+
+        // The write which came meanwhile is flushed once idle again.
+        ctx.handle_event(COMPLETION_EVENT);
+        assert_eq!(ctx.used_heads(), [0, 3]);
+        assert!(!ctx.handler.idle_flush.as_ref().unwrap().in_flight);
+        thread::sleep(interval + Duration::from_millis(10));
+        ctx.handle_event(IDLE_FLUSH_TIMER_EVENT);
+        assert_eq!(flushes.load(Ordering::Acquire), 2);
+        ctx.handle_event(COMPLETION_EVENT);
+        let idle_flush = ctx.handler.idle_flush.as_ref().unwrap();
+        assert!(!idle_flush.in_flight && !idle_flush.armed);
+        assert_eq!(ctx.used_heads(), [0, 3]);
     }
 
-    fn resume(&mut self) -> result::Result<(), MigratableError> {
-        self.common.resume()
+    #[test]
+    fn test_shutdown_queue() {
+        let mem = test_memory();
+        let mut disk_image = TestDisk::new(0xaa);
+        disk_image.read_delay = Some(Duration::from_millis(50));
+        let flushes = disk_image.flushes.clone();
+        let mut ctx = TestContext::new(&mem, disk_image);
+        ctx.handler.write_barrier.state.lock().unwrap().timeout = Some(Duration::from_secs(60));
+        ctx.handler.write_barrier_evt.write(1).unwrap();
+        ctx.handle_event(WRITE_BARRIER_EVENT);
+
+        // A read in flight, a write held by the barrier, and a read the
+        // guest submits once the queue is being torn down.
+        ctx.add_request(0, VIRTIO_BLK_T_IN, 0);
+        ctx.add_request(3, VIRTIO_BLK_T_OUT, 8);
+        ctx.kick();
+        ctx.add_request(6, VIRTIO_BLK_T_IN, 16);
+        assert!(ctx.used_heads().is_empty());
+
+        // The requests popped from the queue all complete, and the disk is
+        // flushed once they did, while the last one is failed.
+        ctx.handler.shutdown_queue().unwrap();
+        let mut used_heads = ctx.used_heads();
+        used_heads.sort();
+        assert_eq!(used_heads, [0, 3, 6]);
+        assert_eq!(ctx.status(0), VIRTIO_BLK_S_OK as u8);
+        assert_eq!(ctx.status(3), VIRTIO_BLK_S_OK as u8);
+        assert_eq!(ctx.status(6), VIRTIO_BLK_S_IOERR as u8);
+        assert!(ctx.handler.inflight_requests.is_empty());
+        assert!(ctx.handler.held_writes.is_empty());
+        assert_eq!(flushes.load(Ordering::Acquire), 2);
     }
-}
 
-impl Snapshottable for Block {
-    fn id(&self) -> String {
-        self.id.clone()
+    #[test]
+    fn test_write_barrier() {
+        let mem = test_memory();
+        let disk_image = TestDisk::new(0xaa);
+        let flushes = disk_image.flushes.clone();
+        let transfers = disk_image.transfers.clone();
+        let mut ctx = TestContext::new(&mem, disk_image);
+        let raise_barrier = |ctx: &mut TestContext, timeout: Option<Duration>| {
+            ctx.handler.write_barrier.state.lock().unwrap().timeout = timeout;
+            ctx.handler.write_barrier_evt.write(1).unwrap();
+            ctx.handle_event(WRITE_BARRIER_EVENT);
+        };
+
+        // The writes in flight complete, and the disk is flushed, before the
+        // barrier is acknowledged.
+        ctx.add_request(0, VIRTIO_BLK_T_OUT, 0);
+        ctx.kick();
+        raise_barrier(&mut ctx, Some(Duration::from_secs(60)));
+        assert!(ctx.handler.writes_held);
+        assert_eq!(ctx.used_heads(), [0]);
+        assert_eq!(flushes.load(Ordering::Acquire), 1);
+        assert_eq!(
+            ctx.handler
+                .write_barrier
+                .state
+                .lock()
+                .unwrap()
+                .drained_queues,
+            1
+        );
+
+        // The reads go through while the writes are held.
+        ctx.add_request(3, VIRTIO_BLK_T_OUT, 8);
+        ctx.add_request(6, VIRTIO_BLK_T_IN, 0);
+        ctx.kick();
+        ctx.handle_event(COMPLETION_EVENT);
+        assert_eq!(ctx.used_heads(), [0, 6]);
+        assert_eq!(ctx.handler.held_writes.len(), 1);
+
+        // Lifting the barrier lets the held writes through.
+        raise_barrier(&mut ctx, None);
+        ctx.handle_event(COMPLETION_EVENT);
+        assert!(!ctx.handler.writes_held);
+        assert_eq!(ctx.used_heads(), [0, 6, 3]);
+        assert_eq!(ctx.status(3), VIRTIO_BLK_S_OK as u8);
+        assert_eq!(
+            transfers.lock().unwrap().last(),
+            Some(&(8 * SECTOR_SIZE, SECTOR_SIZE as usize))
+        );
+
+        // The writes are let through on their own once held for as long as
+        // the barrier allows.
+        raise_barrier(&mut ctx, Some(Duration::from_millis(1)));
+        ctx.add_request(9, VIRTIO_BLK_T_OUT, 16);
+        ctx.kick();
+        assert_eq!(ctx.handler.held_writes.len(), 1);
+        ctx.handle_event(WRITE_BARRIER_TIMER_EVENT);
+        ctx.handle_event(COMPLETION_EVENT);
+        assert!(!ctx.handler.writes_held);
+        assert!(ctx
+            .handler
+            .write_barrier
+            .state
+            .lock()
+            .unwrap()
+            .timeout
+            .is_none());
+        assert_eq!(ctx.used_heads(), [0, 6, 3, 9]);
     }
 
-    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
-        Snapshot::new_from_state(&self.state())
+    #[test]
+    fn test_pause_write_barrier() {
+        // The device refuses to pause while its writes are held.
+        let mut block = test_block(
+            Box::new(NullDiskFile::new(DISK_SIZE as u64, None)),
+            false,
+            None,
+        );
+        block.write_barrier.state.lock().unwrap().timeout = Some(Duration::from_secs(60));
+        assert!(block.pause().is_err());
+        block.write_barrier.state.lock().unwrap().timeout = None;
+        block.pause().unwrap();
+
+        let mem = test_memory();
+        let mut disk_image = TestDisk::new(0xaa);
+        disk_image.room = Some(1);
+        let transfers = disk_image.transfers.clone();
+        let mut ctx = TestContext::new(&mem, disk_image);
+        ctx.handler.write_barrier.state.lock().unwrap().timeout = Some(Duration::from_secs(60));
+        ctx.handler.write_barrier_evt.write(1).unwrap();
+        ctx.handle_event(WRITE_BARRIER_EVENT);
+        ctx.add_request(0, VIRTIO_BLK_T_OUT, 0);
+        ctx.add_request(3, VIRTIO_BLK_T_OUT, 8);
+        ctx.kick();
+
+        // Quiescing the queue leaves the writes held by the barrier off the
+        // disk.
+        ctx.handler.quiesce(&mut ctx.helper).unwrap();
+        assert!(ctx.used_heads().is_empty());
+        assert_eq!(ctx.handler.held_writes.len(), 2);
+        assert!(transfers.lock().unwrap().is_empty());
+
+        // Once the barrier is lowered, even before the queue handled it, they
+        // all complete, the backend taking one of them at a time.
+        ctx.handler.write_barrier.state.lock().unwrap().timeout = None;
+        ctx.handler.quiesce(&mut ctx.helper).unwrap();
+        assert!(!ctx.handler.writes_held);
+        assert_eq!(ctx.used_heads(), [0, 3]);
+        assert_eq!(ctx.status(0), VIRTIO_BLK_S_OK as u8);
+        assert_eq!(ctx.status(3), VIRTIO_BLK_S_OK as u8);
+        assert!(ctx.handler.held_writes.is_empty());
+        assert_eq!(transfers.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_request_size_limits() {
+        // The limits of the storage are advertised, the segments being
+        // bounded by the room the queue leaves.
+        let disk_image = DescribedDisk::new(
+            DiskTopology {
+                max_transfer_size: 128 << 10,
+                max_segments: 1024,
+                ..Default::default()
+            },
+            None,
+        );
+        let block = test_block(Box::new(disk_image), false, None);
+        assert_ne!(
+            block.common.avail_features & (1u64 << VIRTIO_BLK_F_SIZE_MAX),
+            0
+        );
+        assert_eq!({ block.config.size_max }, 128 << 10);
+        assert_eq!({ block.config.seg_max }, u32::from(QUEUE_SIZE) - 2);
+
+        let disk_image = DescribedDisk::new(DiskTopology::default(), None);
+        let block = test_block(Box::new(disk_image), false, None);
+        assert_eq!(
+            block.common.avail_features & (1u64 << VIRTIO_BLK_F_SIZE_MAX),
+            0
+        );
+
+        // A request exceeding them anyway fails, without reaching the disk.
+        let mem = test_memory();
+        let disk_image = TestDisk::new(0xaa);
+        let transfers = disk_image.transfers.clone();
+        let mut ctx = TestContext::new(&mem, disk_image);
+        ctx.handler.size_max = SECTOR_SIZE as u32 / 2;
+        ctx.add_request(0, VIRTIO_BLK_T_IN, 0);
+        ctx.kick();
+        assert_eq!(ctx.used_heads(), [0]);
+        assert_eq!(ctx.status(0), VIRTIO_BLK_S_IOERR as u8);
+        assert!(transfers.lock().unwrap().is_empty());
+
+        ctx.handler.size_max = SECTOR_SIZE as u32;
+        ctx.add_request(3, VIRTIO_BLK_T_IN, 0);
+        ctx.kick();
+        ctx.handle_event(COMPLETION_EVENT);
+        assert_eq!(ctx.used_heads(), [0, 3]);
+        assert_eq!(ctx.status(3), VIRTIO_BLK_S_OK as u8);
     }
 }
-impl Transportable for Block {}
-impl Migratable for Block {}
@@ -77,6 +77,15 @@ pub trait EpollHelperHandler {
     ) -> Result<(), EpollHelperError> {
         Ok(())
     }
+
+    // This method is invoked when the device is being paused, before the
+    // pause is acknowledged. It lets the implementation settle the work in
+    // progress, so that the state of the device doesn't change while it is
+    // paused, for instance while a snapshot is taken. By default, it provides
+    // a no-op implementation.
+    fn quiesce(&mut self, _helper: &mut EpollHelper) -> Result<(), EpollHelperError> {
+        Ok(())
+    }
 }
 
 impl EpollHelper {
@@ -219,6 +228,12 @@ impl EpollHelper {
                     EPOLL_HELPER_EVENT_PAUSE => {
                         info!("PAUSE_EVENT received, pausing epoll loop");
 
+                        // The pause must be acknowledged even if the device
+                        // failed to settle, not to block the VMM forever.
+                        if let Err(e) = handler.quiesce(self) {
+                            error!("Failed quiescing the device before pausing: {:?}", e);
+                        }
+
                         // Acknowledge the pause is effective by using the
                         // paused_sync barrier.
                         paused_sync.wait();
@@ -291,6 +306,12 @@ impl EpollHelper {
                     EPOLL_HELPER_EVENT_PAUSE => {
                         info!("PAUSE_EVENT received, pausing epoll loop");
 
+                        // The pause must be acknowledged even if the device
+                        // failed to settle, not to block the VMM forever.
+                        if let Err(e) = handler.quiesce(self) {
+                            error!("Failed quiescing the device before pausing: {:?}", e);
+                        }
+
                         // Acknowledge the pause is effective by using the
                         // paused_sync barrier.
                         paused_sync.wait();
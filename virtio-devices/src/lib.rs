@@ -39,7 +39,7 @@ pub mod vsock;
 pub mod watchdog;
 
 pub use self::balloon::Balloon;
-pub use self::block::{Block, BlockState};
+pub use self::block::{Block, BlockRateLimiter, BlockState, BlockStats};
 pub use self::console::{Console, ConsoleResizer, Endpoint};
 pub use self::device::{
     DmaRemapping, UserspaceMapping, VirtioCommon, VirtioDevice, VirtioInterrupt,
@@ -155,6 +155,40 @@ impl TryInto<rate_limiter::RateLimiter> for RateLimiterConfig {
     }
 }
 
+impl TokenBucketConfig {
+    fn token_bucket(&self) -> Option<rate_limiter::TokenBucket> {
+        rate_limiter::TokenBucket::new(
+            self.size,
+            self.one_time_burst.unwrap_or(0),
+            self.refill_time,
+        )
+    }
+}
+
+impl From<&rate_limiter::TokenBucket> for TokenBucketConfig {
+    fn from(bucket: &rate_limiter::TokenBucket) -> Self {
+        TokenBucketConfig {
+            size: bucket.capacity(),
+            one_time_burst: Some(bucket.one_time_burst()).filter(|burst| *burst > 0),
+            refill_time: bucket.refill_time_ms(),
+        }
+    }
+}
+
+impl RateLimiterConfig {
+    /// Returns the updates turning the buckets of a rate limiter into the
+    /// ones configured, the token types left unset being disabled.
+    pub fn bucket_updates(&self) -> (rate_limiter::BucketUpdate, rate_limiter::BucketUpdate) {
+        let update = |bucket: Option<TokenBucketConfig>| {
+            bucket.and_then(|bucket| bucket.token_bucket()).map_or(
+                rate_limiter::BucketUpdate::Disabled,
+                rate_limiter::BucketUpdate::Update,
+            )
+        };
+        (update(self.bandwidth), update(self.ops))
+    }
+}
+
 /// Convert an absolute address into an address space (GuestMemory)
 /// to a host pointer and verify that the provided size define a valid
 /// range within a single memory region.
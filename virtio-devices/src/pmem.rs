@@ -154,6 +154,9 @@ struct PmemEpollHandler {
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
     queue: Queue,
     disk: File,
+    // Host address and size of the mapping synced on flush, the whole file
+    // being synced instead if not set.
+    msync: Option<(u64, usize)>,
     interrupt_cb: Arc<dyn VirtioInterrupt>,
     queue_evt: EventFd,
     kill_evt: EventFd,
@@ -167,7 +170,11 @@ impl PmemEpollHandler {
         while let Some(mut desc_chain) = self.queue.pop_descriptor_chain(self.mem.memory()) {
             let len = match Request::parse(&mut desc_chain, self.access_platform.as_ref()) {
                 Ok(ref req) if (req.type_ == RequestType::Flush) => {
-                    let status_code = match self.disk.sync_all() {
+                    let result = match self.msync {
+                        Some((addr, len)) => block::dax_disk::msync(addr, len),
+                        None => self.disk.sync_all(),
+                    };
+                    let status_code = match result {
                         Ok(()) => VIRTIO_PMEM_RESP_TYPE_OK,
                         Err(e) => {
                             error!("failed flushing disk image: {}", e);
@@ -271,6 +278,7 @@ pub struct Pmem {
     mapping: UserspaceMapping,
     seccomp_action: SeccompAction,
     exit_evt: EventFd,
+    msync: bool,
 
     // Hold ownership of the memory that is allocated for the device
     // which will be automatically dropped when the device is dropped
@@ -337,9 +345,16 @@ impl Pmem {
             seccomp_action,
             _region,
             exit_evt,
+            msync: false,
         })
     }
 
+    /// Serves the flushes of the guest with msync() on the mapping rather
+    /// than with fsync() on the file.
+    pub fn set_msync(&mut self, msync: bool) {
+        self.msync = msync;
+    }
+
     fn state(&self) -> PmemState {
         PmemState {
             avail_features: self.common.avail_features,
@@ -405,6 +420,9 @@ impl VirtioDevice for Pmem {
                 mem,
                 queue,
                 disk,
+                msync: self
+                    .msync
+                    .then_some((self.mapping.host_addr, self.mapping.len as usize)),
                 interrupt_cb,
                 queue_evt,
                 kill_evt,
@@ -56,6 +56,19 @@ const VFIO_IOMMU_UNMAP_DMA: u64 = 0x3b72;
 // See include/uapi/linux/if_tun.h in the kernel code.
 const TUNSETOFFLOAD: u64 = 0x4004_54d0;
 
+// See include/uapi/linux/fs.h in the kernel code.
+const BLKGETSIZE64: u64 = 0x8008_1272;
+
+// See include/uapi/linux/blkzoned.h in the kernel code.
+const BLKREPORTZONE: u64 = 0xc010_1282;
+const BLKRESETZONE: u64 = 0x4010_1283;
+const BLKOPENZONE: u64 = 0x4010_1286;
+const BLKCLOSEZONE: u64 = 0x4010_1287;
+const BLKFINISHZONE: u64 = 0x4010_1288;
+
+// See include/uapi/linux/dm-ioctl.h in the kernel code.
+const DM_TABLE_STATUS: u64 = 0xc138_fd0c;
+
 fn create_virtio_console_ioctl_seccomp_rule() -> Vec<SeccompRule> {
     or![and![Cond::new(1, ArgLen::Dword, Eq, TIOCGWINSZ).unwrap()]]
 }
@@ -78,10 +91,24 @@ fn virtio_balloon_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
     vec![(libc::SYS_fallocate, vec![])]
 }
 
+fn create_virtio_block_ioctl_seccomp_rule() -> Vec<SeccompRule> {
+    or![
+        and![Cond::new(1, ArgLen::Dword, Eq, BLKREPORTZONE).unwrap()],
+        and![Cond::new(1, ArgLen::Dword, Eq, BLKRESETZONE).unwrap()],
+        and![Cond::new(1, ArgLen::Dword, Eq, BLKOPENZONE).unwrap()],
+        and![Cond::new(1, ArgLen::Dword, Eq, BLKCLOSEZONE).unwrap()],
+        and![Cond::new(1, ArgLen::Dword, Eq, BLKFINISHZONE).unwrap()],
+        and![Cond::new(1, ArgLen::Dword, Eq, BLKGETSIZE64).unwrap()],
+        and![Cond::new(1, ArgLen::Dword, Eq, DM_TABLE_STATUS).unwrap()],
+    ]
+}
+
 fn virtio_block_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
     vec![
+        (libc::SYS_fadvise64, vec![]),
         (libc::SYS_fallocate, vec![]),
         (libc::SYS_fdatasync, vec![]),
+        (libc::SYS_fstat, vec![]),
         (libc::SYS_fsync, vec![]),
         (libc::SYS_ftruncate, vec![]),
         (libc::SYS_getrandom, vec![]),
@@ -89,9 +116,16 @@ fn virtio_block_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
         (libc::SYS_io_getevents, vec![]),
         (libc::SYS_io_submit, vec![]),
         (libc::SYS_io_uring_enter, vec![]),
+        (libc::SYS_ioctl, create_virtio_block_ioctl_seccomp_rule()),
         (libc::SYS_lseek, vec![]),
+        (libc::SYS_newfstatat, vec![]),
+        #[cfg(target_arch = "x86_64")]
+        (libc::SYS_poll, vec![]),
+        #[cfg(target_arch = "aarch64")]
+        (libc::SYS_ppoll, vec![]),
         (libc::SYS_pread64, vec![]),
         (libc::SYS_preadv, vec![]),
+        (libc::SYS_preadv2, vec![]),
         (libc::SYS_pwritev, vec![]),
         (libc::SYS_pwrite64, vec![]),
         (libc::SYS_sched_getaffinity, vec![]),
@@ -139,7 +173,7 @@ fn virtio_net_ctl_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
 }
 
 fn virtio_pmem_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
-    vec![(libc::SYS_fsync, vec![])]
+    vec![(libc::SYS_fsync, vec![]), (libc::SYS_msync, vec![])]
 }
 
 fn virtio_rng_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
@@ -190,11 +224,20 @@ fn virtio_vhost_net_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
 fn virtio_vhost_block_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
     vec![
         (libc::SYS_clock_nanosleep, vec![]),
+        (libc::SYS_clone, vec![]),
+        (libc::SYS_clone3, vec![]),
         (libc::SYS_connect, vec![]),
+        (libc::SYS_fcntl, vec![]),
+        (libc::SYS_getrandom, vec![]),
         (libc::SYS_nanosleep, vec![]),
+        (libc::SYS_recvfrom, vec![]),
         (libc::SYS_recvmsg, vec![]),
         (libc::SYS_sendmsg, vec![]),
+        (libc::SYS_set_robust_list, vec![]),
+        (libc::SYS_setsockopt, vec![]),
+        (libc::SYS_shutdown, vec![]),
         (libc::SYS_socket, vec![]),
+        (libc::SYS_socketpair, vec![]),
     ]
 }
 
@@ -63,10 +63,12 @@ pub struct Blk {
 }
 
 impl Blk {
-    /// Create a new vhost-user-blk device
+    /// Create a new vhost-user-blk device, authenticating the backend from
+    /// `auth_key` if any.
     pub fn new(
         id: String,
         vu_cfg: VhostUserConfig,
+        auth_key: Option<Vec<u8>>,
         seccomp_action: SeccompAction,
         exit_evt: EventFd,
         iommu: bool,
@@ -74,8 +76,13 @@ impl Blk {
     ) -> Result<Blk> {
         let num_queues = vu_cfg.num_queues;
 
-        let mut vu =
-            VhostUserHandle::connect_vhost_user(false, &vu_cfg.socket, num_queues as u64, false)?;
+        let mut vu = VhostUserHandle::connect_vhost_user(
+            false,
+            &vu_cfg.socket,
+            num_queues as u64,
+            false,
+            auth_key.as_deref(),
+        )?;
 
         let (
             avail_features,
@@ -190,6 +197,7 @@ impl Blk {
                 acked_protocol_features,
                 socket_path: vu_cfg.socket,
                 vu_num_queues,
+                auth_key,
                 ..Default::default()
             },
             id,
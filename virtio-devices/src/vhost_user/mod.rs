@@ -67,6 +67,8 @@ pub enum Error {
     VhostUserOpen(VhostError),
     #[error("Connection to socket failed")]
     VhostUserConnect,
+    #[error("Failed authenticating the backend: {0}")]
+    VhostUserAuthenticate(io::Error),
     #[error("Get features failed: {0}")]
     VhostUserGetFeatures(VhostError),
     #[error("Get queue max number failed: {0}")]
@@ -131,6 +133,8 @@ pub enum Error {
     MissingIrqFd,
     #[error("Failed getting the available index: {0}")]
     GetAvailableIndex(QueueError),
+    #[error("Failed getting the used index: {0}")]
+    GetUsedIndex(QueueError),
     #[error("Migration is not supported by this vhost-user device")]
     MigrationNotSupported,
     #[error("Failed creating memfd: {0}")]
@@ -174,6 +178,7 @@ pub struct VhostUserEpollHandler<S: VhostUserFrontendReqHandler> {
     pub acked_protocol_features: u64,
     pub socket_path: String,
     pub server: bool,
+    pub auth_key: Option<Vec<u8>>,
     pub backend_req_handler: Option<FrontendReqHandler<S>>,
     pub inflight: Option<Inflight>,
 }
@@ -212,6 +217,7 @@ impl<S: VhostUserFrontendReqHandler> VhostUserEpollHandler<S> {
             &self.socket_path,
             self.queues.len() as u64,
             true,
+            self.auth_key.as_deref(),
         )
         .map_err(|e| {
             EpollHelperError::IoError(std::io::Error::new(
@@ -300,6 +306,7 @@ pub struct VhostUserCommon {
     pub vu_num_queues: usize,
     pub migration_started: bool,
     pub server: bool,
+    pub auth_key: Option<Vec<u8>>,
 }
 
 impl VhostUserCommon {
@@ -353,6 +360,7 @@ impl VhostUserCommon {
             acked_protocol_features: self.acked_protocol_features,
             socket_path: self.socket_path.clone(),
             server: self.server,
+            auth_key: self.auth_key.clone(),
             backend_req_handler,
             inflight,
         })
@@ -364,6 +372,7 @@ impl VhostUserCommon {
             &self.socket_path,
             self.vu_num_queues as u64,
             false,
+            self.auth_key.as_deref(),
         )?;
 
         vu.set_protocol_features_vhost_user(acked_features, self.acked_protocol_features)?;
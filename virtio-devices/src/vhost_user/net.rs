@@ -80,8 +80,13 @@ impl Net {
     ) -> Result<Net> {
         let mut num_queues = vu_cfg.num_queues;
 
-        let mut vu =
-            VhostUserHandle::connect_vhost_user(server, &vu_cfg.socket, num_queues as u64, false)?;
+        let mut vu = VhostUserHandle::connect_vhost_user(
+            server,
+            &vu_cfg.socket,
+            num_queues as u64,
+            false,
+            None,
+        )?;
 
         let (
             avail_features,
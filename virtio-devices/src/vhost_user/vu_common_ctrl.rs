@@ -10,7 +10,7 @@ use crate::{
 use std::ffi;
 use std::fs::File;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
-use std::os::unix::net::UnixListener;
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::thread::sleep;
@@ -157,6 +157,28 @@ impl VhostUserHandle {
         acked_features: u64,
         backend_req_handler: &Option<FrontendReqHandler<S>>,
         inflight: Option<&mut Inflight>,
+    ) -> Result<()> {
+        self.setup(
+            mem,
+            queues,
+            virtio_interrupt,
+            acked_features,
+            backend_req_handler,
+            inflight,
+            false,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn setup<S: VhostUserFrontendReqHandler>(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        queues: Vec<(usize, Queue, EventFd)>,
+        virtio_interrupt: &Arc<dyn VirtioInterrupt>,
+        acked_features: u64,
+        backend_req_handler: &Option<FrontendReqHandler<S>>,
+        inflight: Option<&mut Inflight>,
+        reconnect: bool,
     ) -> Result<()> {
         self.vu
             .set_features(acked_features)
@@ -240,14 +262,22 @@ impl VhostUserHandle {
             self.vu
                 .set_vring_addr(*queue_index, &config_data)
                 .map_err(Error::VhostUserSetVringAddr)?;
+            // A backend reconnecting after a disconnection resumes from the
+            // last descriptor it completed. The ones it had fetched without
+            // completing them are either resubmitted from the inflight
+            // shared memory or fetched again, starting from the available
+            // index would drop them.
+            let vring_base = if reconnect {
+                queue
+                    .used_idx(mem, Ordering::Acquire)
+                    .map_err(Error::GetUsedIndex)?
+            } else {
+                queue
+                    .avail_idx(mem, Ordering::Acquire)
+                    .map_err(Error::GetAvailableIndex)?
+            };
             self.vu
-                .set_vring_base(
-                    *queue_index,
-                    queue
-                        .avail_idx(mem, Ordering::Acquire)
-                        .map_err(Error::GetAvailableIndex)?
-                        .0,
-                )
+                .set_vring_base(*queue_index, vring_base.0)
                 .map_err(Error::VhostUserSetVringBase)?;
 
             if let Some(eventfd) =
@@ -346,23 +376,29 @@ impl VhostUserHandle {
     ) -> Result<()> {
         self.set_protocol_features_vhost_user(acked_features, acked_protocol_features)?;
 
-        self.setup_vhost_user(
+        self.setup(
             mem,
             queues,
             virtio_interrupt,
             acked_features,
             backend_req_handler,
             inflight,
+            true,
         )
     }
 
+    /// Connects the backend listening on `socket_path`, or waits for it to
+    /// connect in `server` mode, authenticating it from `auth_key` if any
+    /// before any vhost-user message is exchanged. The messages of an
+    /// authenticated backend are then relayed with their MAC.
     pub fn connect_vhost_user(
         server: bool,
         socket_path: &str,
         num_queues: u64,
         unlink_socket: bool,
+        auth_key: Option<&[u8]>,
     ) -> Result<Self> {
-        if server {
+        let stream = if server {
             if unlink_socket {
                 std::fs::remove_file(socket_path).map_err(Error::RemoveSocketPath)?;
             }
@@ -371,48 +407,42 @@ impl VhostUserHandle {
             let listener = UnixListener::bind(socket_path).map_err(Error::BindSocket)?;
             info!("Waiting for incoming vhost-user connection...");
             let (stream, _) = listener.accept().map_err(Error::AcceptConnection)?;
-
-            Ok(VhostUserHandle {
-                vu: Frontend::from_stream(stream, num_queues),
-                ready: false,
-                supports_migration: false,
-                shm_log: None,
-                acked_features: 0,
-                vrings_info: None,
-                queue_indexes: Vec::new(),
-            })
+            stream
         } else {
             let now = Instant::now();
 
             // Retry connecting for a full minute
-            let err = loop {
-                let err = match Frontend::connect(socket_path, num_queues) {
-                    Ok(m) => {
-                        return Ok(VhostUserHandle {
-                            vu: m,
-                            ready: false,
-                            supports_migration: false,
-                            shm_log: None,
-                            acked_features: 0,
-                            vrings_info: None,
-                            queue_indexes: Vec::new(),
-                        })
+            loop {
+                match UnixStream::connect(socket_path) {
+                    Ok(stream) => break stream,
+                    Err(e) if now.elapsed().as_secs() >= 60 => {
+                        error!(
+                            "Failed connecting the backend after trying for 1 minute: {:?}",
+                            e
+                        );
+                        return Err(Error::VhostUserConnect);
                     }
-                    Err(e) => e,
-                };
-                sleep(Duration::from_millis(100));
-
-                if now.elapsed().as_secs() >= 60 {
-                    break err;
+                    Err(_) => sleep(Duration::from_millis(100)),
                 }
-            };
+            }
+        };
 
-            error!(
-                "Failed connecting the backend after trying for 1 minute: {:?}",
-                err
-            );
-            Err(Error::VhostUserConnect)
-        }
+        let stream = match auth_key {
+            Some(key) => vhost_user_auth::authenticate_backend(stream, key)
+                .and_then(|session| session.into_stream())
+                .map_err(Error::VhostUserAuthenticate)?,
+            None => stream,
+        };
+
+        Ok(VhostUserHandle {
+            vu: Frontend::from_stream(stream, num_queues),
+            ready: false,
+            supports_migration: false,
+            shm_log: None,
+            acked_features: 0,
+            vrings_info: None,
+            queue_indexes: Vec::new(),
+        })
     }
 
     pub fn socket_handle(&mut self) -> &mut Frontend {
@@ -9,11 +9,12 @@ use crate::api::http::{error_response, EndpointHandler, HttpError};
 use crate::api::VmCoredump;
 use crate::api::{
     AddDisk, ApiAction, ApiRequest, VmAddDevice, VmAddFs, VmAddNet, VmAddPmem, VmAddUserDevice,
-    VmAddVdpa, VmAddVsock, VmBoot, VmConfig, VmCounters, VmDelete, VmNmi, VmPause, VmPowerButton,
-    VmReboot, VmReceiveMigration, VmRemoveDevice, VmResize, VmResizeZone, VmRestore, VmResume,
-    VmSendMigration, VmShutdown, VmSnapshot,
+    VmAddVdpa, VmAddVsock, VmBoot, VmChangeMedium, VmConfig, VmCounters, VmDelete, VmDisks,
+    VmFlushDisks, VmNmi, VmPause, VmPowerButton, VmReboot, VmReceiveMigration, VmRemoveDevice,
+    VmResize, VmResizeZone, VmRestore, VmResume, VmResumeDisk, VmRotateDiskKey, VmSendMigration,
+    VmShutdown, VmSnapshot, VmUpdateDiskRateLimits,
 };
-use crate::config::{NetConfig, RestoreConfig};
+use crate::config::{DiskConfig, NetConfig, RestoreConfig};
 use micro_http::{Body, Method, Request, Response, StatusCode, Version};
 use std::fs::File;
 use std::os::unix::io::IntoRawFd;
@@ -52,6 +53,15 @@ impl EndpointHandler for VmCreate {
                             }
                         }
 
+                        if let Some(ref mut disks) = vm_config.disks {
+                            if disks.iter().any(|disk| disk.fd.is_some()) {
+                                warn!("Ignoring FDs sent via the HTTP request body");
+                            }
+                            for disk in disks {
+                                disk.fd = None;
+                            }
+                        }
+
                         match crate::api::VmCreate
                             .send(api_notifier, api_sender, Arc::new(Mutex::new(vm_config)))
                             .map_err(HttpError::ApiError)
@@ -164,6 +174,7 @@ macro_rules! vm_action_put_handler_body {
 }
 
 vm_action_get_handler!(VmCounters);
+vm_action_get_handler!(VmDisks);
 
 vm_action_put_handler!(VmBoot);
 vm_action_put_handler!(VmDelete);
@@ -173,15 +184,19 @@ vm_action_put_handler!(VmPause);
 vm_action_put_handler!(VmResume);
 vm_action_put_handler!(VmPowerButton);
 vm_action_put_handler!(VmNmi);
+vm_action_put_handler!(VmFlushDisks);
 
 vm_action_put_handler_body!(VmAddDevice);
-vm_action_put_handler_body!(AddDisk);
 vm_action_put_handler_body!(VmAddFs);
 vm_action_put_handler_body!(VmAddPmem);
 vm_action_put_handler_body!(VmAddVdpa);
 vm_action_put_handler_body!(VmAddVsock);
 vm_action_put_handler_body!(VmAddUserDevice);
 vm_action_put_handler_body!(VmRemoveDevice);
+vm_action_put_handler_body!(VmResumeDisk);
+vm_action_put_handler_body!(VmChangeMedium);
+vm_action_put_handler_body!(VmUpdateDiskRateLimits);
+vm_action_put_handler_body!(VmRotateDiskKey);
 vm_action_put_handler_body!(VmResize);
 vm_action_put_handler_body!(VmResizeZone);
 vm_action_put_handler_body!(VmSnapshot);
@@ -191,6 +206,36 @@ vm_action_put_handler_body!(VmSendMigration);
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 vm_action_put_handler_body!(VmCoredump);
 
+impl PutHandler for AddDisk {
+    fn handle_request(
+        &'static self,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+        body: &Option<Body>,
+        mut files: Vec<File>,
+    ) -> std::result::Result<Option<Body>, HttpError> {
+        if let Some(body) = body {
+            let mut disk_cfg: DiskConfig = serde_json::from_slice(body.raw())?;
+            if disk_cfg.fd.is_some() {
+                warn!("Ignoring FDs sent via the HTTP request body");
+                disk_cfg.fd = None;
+            }
+            // A disk is backed by a single file.
+            match files.len() {
+                0 => {}
+                1 => disk_cfg.fd = Some(files.remove(0).into_raw_fd()),
+                _ => return Err(HttpError::BadRequest),
+            }
+            self.send(api_notifier, api_sender, disk_cfg)
+                .map_err(HttpError::ApiError)
+        } else {
+            Err(HttpError::BadRequest)
+        }
+    }
+}
+
+impl GetHandler for AddDisk {}
+
 impl PutHandler for VmAddNet {
     fn handle_request(
         &'static self,
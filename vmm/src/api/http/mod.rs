@@ -8,9 +8,10 @@ use self::http_endpoint::{VmActionHandler, VmCreate, VmInfo, VmmPing, VmmShutdow
 use crate::api::VmCoredump;
 use crate::api::{
     AddDisk, ApiError, ApiRequest, VmAddDevice, VmAddFs, VmAddNet, VmAddPmem, VmAddUserDevice,
-    VmAddVdpa, VmAddVsock, VmBoot, VmCounters, VmDelete, VmNmi, VmPause, VmPowerButton, VmReboot,
-    VmReceiveMigration, VmRemoveDevice, VmResize, VmResizeZone, VmRestore, VmResume,
-    VmSendMigration, VmShutdown, VmSnapshot,
+    VmAddVdpa, VmAddVsock, VmBoot, VmChangeMedium, VmCounters, VmDelete, VmDisks, VmFlushDisks,
+    VmNmi, VmPause, VmPowerButton, VmReboot, VmReceiveMigration, VmRemoveDevice, VmResize,
+    VmResizeZone, VmRestore, VmResume, VmResumeDisk, VmRotateDiskKey, VmSendMigration, VmShutdown,
+    VmSnapshot, VmUpdateDiskRateLimits,
 };
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::{Error as VmmError, Result};
@@ -206,6 +207,10 @@ pub static HTTP_ROUTES: Lazy<HttpRoutes> = Lazy::new(|| {
     );
     r.routes
         .insert(endpoint!("/vm.create"), Box::new(VmCreate {}));
+    r.routes.insert(
+        endpoint!("/vm.disks"),
+        Box::new(VmActionHandler::new(&VmDisks)),
+    );
     r.routes.insert(
         endpoint!("/vm.delete"),
         Box::new(VmActionHandler::new(&VmDelete)),
@@ -270,6 +275,26 @@ pub static HTTP_ROUTES: Lazy<HttpRoutes> = Lazy::new(|| {
         .insert(endpoint!("/vmm.shutdown"), Box::new(VmmShutdown {}));
     r.routes
         .insert(endpoint!("/vm.nmi"), Box::new(VmActionHandler::new(&VmNmi)));
+    r.routes.insert(
+        endpoint!("/vm.flush-disks"),
+        Box::new(VmActionHandler::new(&VmFlushDisks)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.resume-disk"),
+        Box::new(VmActionHandler::new(&VmResumeDisk)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.change-medium"),
+        Box::new(VmActionHandler::new(&VmChangeMedium)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.update-disk-rate-limits"),
+        Box::new(VmActionHandler::new(&VmUpdateDiskRateLimits)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.rotate-disk-key"),
+        Box::new(VmActionHandler::new(&VmRotateDiskKey)),
+    );
 
     r
 });
@@ -44,14 +44,16 @@ use crate::config::{
 };
 use crate::device_tree::DeviceTree;
 use crate::vm::{Error as VmError, VmState};
-use crate::Error as VmmError;
+use crate::{DiskRateLimits, Error as VmmError};
 use core::fmt;
 use micro_http::Body;
 use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 use std::io;
+use std::path::PathBuf;
 use std::sync::mpsc::{channel, RecvError, SendError, Sender};
 use std::sync::{Arc, Mutex};
+use virtio_devices::RateLimiterConfig;
 use vm_migration::MigratableError;
 use vmm_sys_util::eventfd::EventFd;
 
@@ -162,6 +164,21 @@ pub enum ApiError {
 
     /// Error triggering NMI
     VmNmi(VmError),
+
+    /// Error flushing the disks
+    VmFlushDisks(VmError),
+
+    /// Error resuming a disk paused out of space
+    VmResumeDisk(VmError),
+
+    /// Error changing the medium of a removable disk
+    VmChangeMedium(VmError),
+
+    /// Error updating the rate limits of a disk
+    VmUpdateDiskRateLimits(VmError),
+
+    /// Error rotating the encryption key of a disk
+    VmRotateDiskKey(VmError),
 }
 pub type ApiResult<T> = Result<T, ApiError>;
 
@@ -204,6 +221,11 @@ impl Display for ApiError {
             VmSendMigration(migratable_error) => write!(f, "{}", migratable_error),
             VmPowerButton(vm_error) => write!(f, "{}", vm_error),
             VmNmi(vm_error) => write!(f, "{}", vm_error),
+            VmFlushDisks(vm_error) => write!(f, "{}", vm_error),
+            VmResumeDisk(vm_error) => write!(f, "{}", vm_error),
+            VmChangeMedium(vm_error) => write!(f, "{}", vm_error),
+            VmUpdateDiskRateLimits(vm_error) => write!(f, "{}", vm_error),
+            VmRotateDiskKey(vm_error) => write!(f, "{}", vm_error),
         }
     }
 }
@@ -242,6 +264,38 @@ pub struct VmRemoveDeviceData {
     pub id: String,
 }
 
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmResumeDiskData {
+    pub id: String,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmChangeMediumData {
+    pub id: String,
+    /// Image inserted into the disk, the medium being ejected if unset.
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmUpdateDiskRateLimitsData {
+    pub id: String,
+    /// Limits replacing those of all the requests, left as they are if
+    /// unset, the token buckets not given being disabled.
+    pub rate_limiter_config: Option<RateLimiterConfig>,
+    /// Limits replacing those of the reads.
+    pub read_rate_limiter_config: Option<RateLimiterConfig>,
+    /// Limits replacing those of the writes.
+    pub write_rate_limiter_config: Option<RateLimiterConfig>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmRotateDiskKeyData {
+    pub id: String,
+    /// File holding the new passphrase, so that it never goes through the
+    /// API.
+    pub passphrase_file: PathBuf,
+}
+
 #[derive(Clone, Deserialize, Serialize, Default, Debug)]
 pub struct VmSnapshotConfig {
     /// The snapshot destination URL
@@ -346,6 +400,8 @@ pub trait RequestHandler {
 
     fn vm_counters(&mut self) -> Result<Option<Vec<u8>>, VmError>;
 
+    fn vm_disks(&mut self) -> Result<Option<Vec<u8>>, VmError>;
+
     fn vm_power_button(&mut self) -> Result<(), VmError>;
 
     fn vm_receive_migration(
@@ -359,6 +415,20 @@ pub trait RequestHandler {
     ) -> Result<(), MigratableError>;
 
     fn vm_nmi(&mut self) -> Result<(), VmError>;
+
+    fn vm_flush_disks(&mut self) -> Result<(), VmError>;
+
+    fn vm_resume_disk(&mut self, id: String) -> Result<(), VmError>;
+
+    fn vm_change_medium(&mut self, id: String, path: Option<PathBuf>) -> Result<(), VmError>;
+
+    fn vm_update_disk_rate_limits(
+        &mut self,
+        id: String,
+        limits: DiskRateLimits,
+    ) -> Result<Option<Vec<u8>>, VmError>;
+
+    fn vm_rotate_disk_key(&mut self, id: String, passphrase_file: PathBuf) -> Result<(), VmError>;
 }
 
 /// It would be nice if we could pass around an object like this:
@@ -824,6 +894,39 @@ impl ApiAction for VmCounters {
     }
 }
 
+pub struct VmDisks;
+
+impl ApiAction for VmDisks {
+    type RequestBody = ();
+    type ResponseBody = Option<Body>;
+
+    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmDisks");
+
+            let response = vmm
+                .vm_disks()
+                .map_err(ApiError::VmInfo)
+                .map(ApiResponsePayload::VmAction);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
 pub struct VmCreate;
 
 impl ApiAction for VmCreate {
@@ -1455,3 +1558,192 @@ impl ApiAction for VmNmi {
         get_response_body(self, api_evt, api_sender, data)
     }
 }
+
+pub struct VmFlushDisks;
+
+impl ApiAction for VmFlushDisks {
+    type RequestBody = ();
+    type ResponseBody = Option<Body>;
+
+    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmFlushDisks");
+
+            let response = vmm
+                .vm_flush_disks()
+                .map_err(ApiError::VmFlushDisks)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmResumeDisk;
+
+impl ApiAction for VmResumeDisk {
+    type RequestBody = VmResumeDiskData;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        resume_disk_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmResumeDisk {:?}", resume_disk_data);
+
+            let response = vmm
+                .vm_resume_disk(resume_disk_data.id)
+                .map_err(ApiError::VmResumeDisk)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmChangeMedium;
+
+impl ApiAction for VmChangeMedium {
+    type RequestBody = VmChangeMediumData;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        change_medium_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmChangeMedium {:?}", change_medium_data);
+
+            let response = vmm
+                .vm_change_medium(change_medium_data.id, change_medium_data.path)
+                .map_err(ApiError::VmChangeMedium)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmUpdateDiskRateLimits;
+
+impl ApiAction for VmUpdateDiskRateLimits {
+    type RequestBody = VmUpdateDiskRateLimitsData;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        update_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!(
+                "API request event: VmUpdateDiskRateLimits {:?}",
+                update_data
+            );
+
+            let limits = DiskRateLimits {
+                rate_limiter_config: update_data.rate_limiter_config,
+                read_rate_limiter_config: update_data.read_rate_limiter_config,
+                write_rate_limiter_config: update_data.write_rate_limiter_config,
+            };
+            let response = vmm
+                .vm_update_disk_rate_limits(update_data.id, limits)
+                .map_err(ApiError::VmUpdateDiskRateLimits)
+                .map(ApiResponsePayload::VmAction);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmRotateDiskKey;
+
+impl ApiAction for VmRotateDiskKey {
+    type RequestBody = VmRotateDiskKeyData;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        rotate_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmRotateDiskKey {:?}", rotate_data);
+
+            let response = vmm
+                .vm_rotate_disk_key(rotate_data.id, rotate_data.passphrase_file)
+                .map_err(ApiError::VmRotateDiskKey)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
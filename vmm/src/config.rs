@@ -4,6 +4,10 @@
 //
 
 pub use crate::vm_config::*;
+use block::import::ImportCompression;
+use block::preallocate::PreallocationMode;
+use block::readahead::FadviseMode;
+use block::{AsyncIoBackend, CacheMode, EnospcPolicy, ImageType, TimeoutPolicy, SECTOR_SIZE};
 use clap::ArgMatches;
 use option_parser::{
     ByteSized, IntegerList, OptionParser, OptionParserError, StringList, Toggle, Tuple,
@@ -196,6 +200,123 @@ pub enum ValidationError {
     DefaultPciSegmentInvalidNode(u32),
     /// Invalid rate-limiter group
     InvalidRateLimiterGroup,
+    /// LUKS encrypted disks require the "luks" feature
+    LuksUnsupported,
+    /// Both a LUKS passphrase and a LUKS volume key provided
+    LuksPassphraseAndKey,
+    /// LUKS encrypted disks can't be used with vhost-user
+    LuksVhostUser,
+    /// The flush coalescing window must be at least 1ms
+    InvalidFlushWindow,
+    /// Flush coalescing can't be used with vhost-user
+    FlushWindowVhostUser,
+    /// The idle flush interval must be at least 1ms
+    InvalidIdleFlush,
+    /// Idle flushes can't be used with vhost-user
+    IdleFlushVhostUser,
+    /// Idle flushes are only needed with the writeback cache mode
+    IdleFlushCacheMode,
+    /// The request timeout must be at least 1ms
+    InvalidRequestTimeout,
+    /// Request timeouts can't be used with vhost-user
+    RequestTimeoutVhostUser,
+    /// A request timeout policy was provided without a request timeout
+    RequestTimeoutPolicyWithoutTimeout,
+    /// A scrubbing manifest was provided without enabling scrubbing
+    ScrubManifestWithoutScrub,
+    /// Disk scrubbing can't be used with vhost-user
+    ScrubVhostUser,
+    /// Disk path and scratch disk directory both provided
+    DiskPathAndTmpfile,
+    /// No size provided for the scratch disk
+    TmpfileMissingSize,
+    /// Disk size or preallocation provided without a scratch disk, or a
+    /// DAX disk for the size
+    SizeWithoutTmpfile,
+    /// Scratch disks can't be used with vhost-user
+    TmpfileVhostUser,
+    /// The maximum merge size must be a non-zero multiple of the sector size
+    InvalidMaxMergeSize(u64),
+    /// Request merging can't be used with vhost-user
+    MaxMergeSizeVhostUser,
+    /// At least one request must be allowed in flight
+    InvalidMaxInflight,
+    /// The in-flight requests limit can't be used with vhost-user
+    MaxInflightVhostUser,
+    /// Fixed buffers require the io_uring backend
+    FixedBuffersWithoutIoUring,
+    /// Compressed images import requires the "compressed_import" feature
+    ImportCompressionUnsupported,
+    /// Import compression provided without an import source
+    ImportCompressionWithoutSource,
+    /// No disk path provided to import the image to
+    ImportMissingPath,
+    /// Image import can't be used with vhost-user
+    ImportVhostUser,
+    /// The logical block size must be a power of two of at least 512 bytes
+    InvalidLogicalBlockSize(u64),
+    /// The physical block size must be a power of two of at least 512 bytes
+    InvalidPhysicalBlockSize(u64),
+    /// The physical block size must be a multiple of the logical block size
+    PhysicalBlockSizeMismatch(u64, u64),
+    /// Page cache hints can't be used with vhost-user
+    PageCacheHintsVhostUser,
+    /// The readahead window must be larger than 0
+    InvalidReadahead,
+    /// Reading ahead contradicts dropping the pages read from the cache
+    ReadaheadWithDontneed,
+    /// I/O retries can't be used with vhost-user
+    IoRetriesVhostUser,
+    /// Completion batching can't be used with vhost-user
+    CompletionBatchVhostUser,
+    /// The backend of vhost-user disks can't be chosen
+    BackendVhostUser,
+    /// The image type of vhost-user disks can't be given
+    ImageTypeVhostUser,
+    /// The writes to vhost-user disks can't be paused
+    EnospcPolicyVhostUser,
+    /// The queue threads of vhost-user disks can't be pinned to a host node
+    HostNumaNodeVhostUser,
+    /// Submission queue polling requires the io_uring backend
+    SqpollWithoutIoUring,
+    /// DAX disks can't be used with vhost-user
+    DaxVhostUser,
+    /// DAX disks can't be read-only
+    DaxReadonly,
+    /// Discarded disks can't be fully preallocated
+    DiscardOnStartPreallocated,
+    /// Read-only disks can't be discarded
+    DiscardOnStartReadonly,
+    /// vhost-user disks can't be discarded by the VMM
+    DiscardOnStartVhostUser,
+    /// The completions of vhost-user disks are ordered by their backend
+    OrderedCompletionsVhostUser,
+    /// The discards to vhost-user disks are served by their backend
+    DiscardZeroesVhostUser,
+    /// The medium of vhost-user disks is owned by their backend
+    RemovableVhostUser,
+    /// DAX disks are mapped into the guest, their medium can't be changed
+    RemovableDax,
+    /// Scratch and imported disks are RAW images
+    NonRawImageType,
+    /// Disk file descriptor provided along with a path or a scratch disk
+    DiskFdAndPath,
+    /// A disk file descriptor can't be used with vhost-user
+    DiskFdVhostUser,
+    /// Disk file descriptor is a reserved fd
+    DiskReservedFd,
+    /// A cgroup can't throttle vhost-user disks
+    IoCgroupVhostUser,
+    /// A cgroup throttles disks according to their read or write rate limiters
+    IoCgroupWithoutLimits,
+    /// The process can only be in a single cgroup
+    IoCgroupMismatch,
+    /// The fault injection rate is a percentage
+    InvalidFaultRate(u8),
+    /// Faults can't be injected into vhost-user disks
+    FaultInjectionVhostUser,
+    /// Authentication key provided for a disk not served by a vhost-user backend
+    VhostAuthKeyWithoutVhostUser,
     /// The specified I/O port was invalid. It should be provided in hex, such as `0xe9`.
     #[cfg(target_arch = "x86_64")]
     InvalidIoPortHex(String),
@@ -336,6 +457,185 @@ impl fmt::Display for ValidationError {
             InvalidRateLimiterGroup => {
                 write!(f, "Invalid rate-limiter group")
             }
+            LuksUnsupported => {
+                write!(f, "LUKS encrypted disks require the \"luks\" feature")
+            }
+            LuksPassphraseAndKey => {
+                write!(
+                    f,
+                    "Both a LUKS passphrase file and a LUKS key file provided"
+                )
+            }
+            LuksVhostUser => {
+                write!(f, "LUKS encrypted disks can't be used with vhost-user")
+            }
+            InvalidFlushWindow => {
+                write!(f, "The flush coalescing window must be at least 1ms")
+            }
+            FlushWindowVhostUser => {
+                write!(f, "Flush coalescing can't be used with vhost-user")
+            }
+            InvalidIdleFlush => {
+                write!(f, "The idle flush interval must be at least 1ms")
+            }
+            IdleFlushVhostUser => {
+                write!(f, "Idle flushes can't be used with vhost-user")
+            }
+            IdleFlushCacheMode => {
+                write!(f, "Idle flushes require cache=writeback")
+            }
+            InvalidRequestTimeout => {
+                write!(f, "The request timeout must be at least 1ms")
+            }
+            RequestTimeoutVhostUser => {
+                write!(f, "Request timeouts can't be used with vhost-user")
+            }
+            RequestTimeoutPolicyWithoutTimeout => {
+                write!(f, "A request timeout policy requires request_timeout")
+            }
+            ScrubManifestWithoutScrub => {
+                write!(f, "A scrubbing manifest requires scrub=on")
+            }
+            ScrubVhostUser => {
+                write!(f, "Disk scrubbing can't be used with vhost-user")
+            }
+            DiskPathAndTmpfile => {
+                write!(f, "Disk path and scratch disk directory both provided")
+            }
+            TmpfileMissingSize => write!(f, "No size provided for the scratch disk"),
+            SizeWithoutTmpfile => {
+                write!(
+                    f,
+                    "Disk size and preallocation only apply to scratch disks (tmpfile), and the size to DAX disks"
+                )
+            }
+            TmpfileVhostUser => write!(f, "Scratch disks can't be used with vhost-user"),
+            InvalidMaxMergeSize(s) => {
+                write!(
+                    f,
+                    "The maximum merge size must be a non-zero multiple of 512 bytes: {s}"
+                )
+            }
+            MaxMergeSizeVhostUser => {
+                write!(f, "Request merging can't be used with vhost-user")
+            }
+            InvalidMaxInflight => {
+                write!(f, "At least one request must be allowed in flight")
+            }
+            MaxInflightVhostUser => {
+                write!(
+                    f,
+                    "The in-flight requests limit can't be used with vhost-user"
+                )
+            }
+            FixedBuffersWithoutIoUring => {
+                write!(f, "Fixed buffers require the io_uring backend")
+            }
+            ImportCompressionUnsupported => {
+                write!(
+                    f,
+                    "Importing compressed images requires the \"compressed_import\" feature"
+                )
+            }
+            ImportCompressionWithoutSource => {
+                write!(f, "An import compression requires an import source")
+            }
+            ImportMissingPath => write!(f, "No disk path provided to import the image to"),
+            ImportVhostUser => write!(f, "Image import can't be used with vhost-user"),
+            InvalidLogicalBlockSize(s) => {
+                write!(
+                    f,
+                    "The logical block size must be a power of two of at least 512 bytes: {s}"
+                )
+            }
+            InvalidPhysicalBlockSize(s) => {
+                write!(
+                    f,
+                    "The physical block size must be a power of two of at least 512 bytes: {s}"
+                )
+            }
+            PhysicalBlockSizeMismatch(p, l) => {
+                write!(
+                    f,
+                    "The physical block size {p} must be a multiple of the logical block size {l}"
+                )
+            }
+            PageCacheHintsVhostUser => {
+                write!(f, "Page cache hints can't be used with vhost-user")
+            }
+            InvalidReadahead => write!(f, "The readahead window must be larger than 0"),
+            ReadaheadWithDontneed => {
+                write!(f, "A readahead window can't be used with fadvise=dontneed")
+            }
+            IoRetriesVhostUser => write!(f, "I/O retries can't be used with vhost-user"),
+            CompletionBatchVhostUser => {
+                write!(f, "Completion batching can't be used with vhost-user")
+            }
+            BackendVhostUser => write!(f, "The backend of vhost-user disks can't be chosen"),
+            ImageTypeVhostUser => {
+                write!(f, "The image type of vhost-user disks can't be given")
+            }
+            EnospcPolicyVhostUser => {
+                write!(f, "The writes to vhost-user disks can't be paused")
+            }
+            HostNumaNodeVhostUser => {
+                write!(
+                    f,
+                    "The queue threads of vhost-user disks can't be pinned to a host node"
+                )
+            }
+            SqpollWithoutIoUring => {
+                write!(f, "Submission queue polling requires the io_uring backend")
+            }
+            DaxVhostUser => write!(f, "DAX disks can't be used with vhost-user"),
+            DaxReadonly => write!(f, "DAX disks can't be read-only"),
+            DiscardOnStartPreallocated => {
+                write!(
+                    f,
+                    "Discarding a disk on start undoes its full preallocation"
+                )
+            }
+            DiscardOnStartReadonly => write!(f, "Read-only disks can't be discarded on start"),
+            DiscardOnStartVhostUser => {
+                write!(f, "vhost-user disks can't be discarded on start")
+            }
+            OrderedCompletionsVhostUser => {
+                write!(f, "vhost-user disks can't order their completions")
+            }
+            DiscardZeroesVhostUser => {
+                write!(f, "vhost-user disks can't zero their discards")
+            }
+            RemovableVhostUser => write!(f, "vhost-user disks can't be removable"),
+            RemovableDax => write!(f, "DAX disks can't be removable"),
+            NonRawImageType => write!(f, "Scratch and imported disks are RAW images"),
+            DiskFdAndPath => write!(
+                f,
+                "Disk file descriptor provided along with a path or a scratch disk directory"
+            ),
+            DiskFdVhostUser => write!(f, "A disk file descriptor can't be used with vhost-user"),
+            DiskReservedFd => write!(f, "Disk file descriptor is a reserved fd"),
+            IoCgroupVhostUser => write!(f, "A cgroup can't throttle vhost-user disks"),
+            IoCgroupWithoutLimits => {
+                write!(
+                    f,
+                    "A cgroup throttling a disk requires a read or write rate limiter"
+                )
+            }
+            IoCgroupMismatch => {
+                write!(f, "Every disk throttled by a cgroup must use the same one")
+            }
+            InvalidFaultRate(rate) => {
+                write!(f, "The fault injection rate {rate} is not a percentage")
+            }
+            FaultInjectionVhostUser => {
+                write!(f, "Faults can't be injected into vhost-user disks")
+            }
+            VhostAuthKeyWithoutVhostUser => {
+                write!(
+                    f,
+                    "The authentication key only applies to disks served by a vhost-user backend"
+                )
+            }
             #[cfg(target_arch = "x86_64")]
             InvalidIoPortHex(s) => {
                 write!(
@@ -1100,11 +1400,118 @@ impl DiskConfig {
     pub const SYNTAX: &'static str = "Disk parameters \
          \"path=<disk_image_path>,readonly=on|off,direct=on|off,iommu=on|off,\
          num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,\
-         vhost_user=on|off,socket=<vhost_user_socket_path>,\
+         vhost_user=on|off,socket=<vhost_user_socket_path>,vhost_auth_key=<shared_key_file_path>,\
          bw_size=<bytes>,bw_one_time_burst=<bytes>,bw_refill_time=<ms>,\
          ops_size=<io_ops>,ops_one_time_burst=<io_ops>,ops_refill_time=<ms>,\
+         read_bw_size=<bytes>,read_bw_one_time_burst=<bytes>,read_bw_refill_time=<ms>,\
+         read_ops_size=<io_ops>,read_ops_one_time_burst=<io_ops>,read_ops_refill_time=<ms>,\
+         write_bw_size=<bytes>,write_bw_one_time_burst=<bytes>,write_bw_refill_time=<ms>,\
+         write_ops_size=<io_ops>,write_ops_one_time_burst=<io_ops>,write_ops_refill_time=<ms>,\
          id=<device_id>,pci_segment=<segment_id>,rate_limit_group=<group_id>,\
-         queue_affinity=<list_of_queue_indices_with_their_associated_cpuset>";
+         queue_affinity=<list_of_queue_indices_with_their_associated_cpuset>,\
+         latency_histograms=on|off,\
+         luks_passphrase_file=<passphrase_file_path>,luks_key_file=<volume_key_file_path>,\
+         cache=none|writeback|writethrough|directsync,flush_window=<ms>,idle_flush=<ms>,\
+         scrub=on|off,scrub_manifest=<checksums_file_path>,\
+         tmpfile=<scratch_disk_directory>,size=<scratch_disk_size>,preallocate=off|metadata|full,\
+         max_merge_size=<bytes>,max_inflight=<requests_per_queue>,fixed_buffers=on|off,\
+         import_source=<image_stream_path>,import_compression=none|gzip|zstd,\
+         logical_block_size=<bytes>,physical_block_size=<bytes>,\
+         fadvise=normal|sequential|willneed|dontneed,readahead=<bytes>,io_retries=<count>,\
+         completion_batch=<completions>,rotational=on|off,io_cgroup=<cgroup_path>,backend=auto|io_uring|aio|sync,\
+         image_type=raw|qcow2|qed|vhd|vhdx,fd=<disk_file_descriptor>,enospc=report|pause,\
+         host_numa_node=<host_node_id>,sqpoll_cpu=<host_cpu>,dax=on|off,discard_on_start=on|off,\
+         ordered_completions=on|off,request_timeout=<ms>,request_timeout_policy=report|cancel,\
+         discard_zeroes=on|off,removable=on|off";
+
+    // Parse the bandwidth and operations token buckets whose options are
+    // named with the given prefix.
+    fn parse_rate_limiter_config(
+        parser: &OptionParser,
+        prefix: &str,
+    ) -> Result<Option<RateLimiterConfig>> {
+        let bw_size = parser
+            .convert(&format!("{prefix}bw_size"))
+            .map_err(Error::ParseDisk)?
+            .unwrap_or_default();
+        let bw_one_time_burst = parser
+            .convert(&format!("{prefix}bw_one_time_burst"))
+            .map_err(Error::ParseDisk)?
+            .unwrap_or_default();
+        let bw_refill_time = parser
+            .convert(&format!("{prefix}bw_refill_time"))
+            .map_err(Error::ParseDisk)?
+            .unwrap_or_default();
+        let ops_size = parser
+            .convert(&format!("{prefix}ops_size"))
+            .map_err(Error::ParseDisk)?
+            .unwrap_or_default();
+        let ops_one_time_burst = parser
+            .convert(&format!("{prefix}ops_one_time_burst"))
+            .map_err(Error::ParseDisk)?
+            .unwrap_or_default();
+        let ops_refill_time = parser
+            .convert(&format!("{prefix}ops_refill_time"))
+            .map_err(Error::ParseDisk)?
+            .unwrap_or_default();
+        let bw_tb_config = if bw_size != 0 && bw_refill_time != 0 {
+            Some(TokenBucketConfig {
+                size: bw_size,
+                one_time_burst: Some(bw_one_time_burst),
+                refill_time: bw_refill_time,
+            })
+        } else {
+            None
+        };
+        let ops_tb_config = if ops_size != 0 && ops_refill_time != 0 {
+            Some(TokenBucketConfig {
+                size: ops_size,
+                one_time_burst: Some(ops_one_time_burst),
+                refill_time: ops_refill_time,
+            })
+        } else {
+            None
+        };
+
+        Ok(if bw_tb_config.is_some() || ops_tb_config.is_some() {
+            Some(RateLimiterConfig {
+                bandwidth: bw_tb_config,
+                ops: ops_tb_config,
+            })
+        } else {
+            None
+        })
+    }
+
+    // Parse the faults injected for testing, not part of the syntax.
+    fn parse_fault_injection_config(parser: &OptionParser) -> Result<Option<FaultInjectionConfig>> {
+        let rate = parser
+            .convert::<u8>("_fault_rate")
+            .map_err(Error::ParseDisk)?;
+        let offsets = parser
+            .convert::<IntegerList>("_fault_offsets")
+            .map_err(Error::ParseDisk)?
+            .map(|v| v.0);
+        let after = parser
+            .convert::<u64>("_fault_after")
+            .map_err(Error::ParseDisk)?;
+        let latency = parser
+            .convert::<u64>("_fault_latency")
+            .map_err(Error::ParseDisk)?;
+
+        Ok(
+            if rate.is_some() || offsets.is_some() || after.is_some() || latency.is_some() {
+                Some(FaultInjectionConfig {
+                    rate: rate.unwrap_or_default(),
+                    offsets: offsets.unwrap_or_default(),
+                    after,
+                    latency: latency.unwrap_or_default(),
+                })
+            } else {
+                None
+            },
+        )
+    }
 
     pub fn parse(disk: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -1123,20 +1530,80 @@ impl DiskConfig {
             .add("ops_size")
             .add("ops_one_time_burst")
             .add("ops_refill_time")
+            .add("read_bw_size")
+            .add("read_bw_one_time_burst")
+            .add("read_bw_refill_time")
+            .add("read_ops_size")
+            .add("read_ops_one_time_burst")
+            .add("read_ops_refill_time")
+            .add("write_bw_size")
+            .add("write_bw_one_time_burst")
+            .add("write_bw_refill_time")
+            .add("write_ops_size")
+            .add("write_ops_one_time_burst")
+            .add("write_ops_refill_time")
             .add("id")
             .add("_disable_io_uring")
             .add("_disable_aio")
+            .add("_fault_rate")
+            .add("_fault_offsets")
+            .add("_fault_after")
+            .add("_fault_latency")
             .add("pci_segment")
             .add("serial")
             .add("rate_limit_group")
-            .add("queue_affinity");
+            .add("queue_affinity")
+            .add("latency_histograms")
+            .add("luks_passphrase_file")
+            .add("luks_key_file")
+            .add("cache")
+            .add("flush_window")
+            .add("idle_flush")
+            .add("scrub")
+            .add("scrub_manifest")
+            .add("tmpfile")
+            .add("size")
+            .add("preallocate")
+            .add("max_merge_size")
+            .add("max_inflight")
+            .add("fixed_buffers")
+            .add("import_source")
+            .add("import_compression")
+            .add("logical_block_size")
+            .add("physical_block_size")
+            .add("fadvise")
+            .add("readahead")
+            .add("io_retries")
+            .add("completion_batch")
+            .add("rotational")
+            .add("io_cgroup")
+            .add("backend")
+            .add("image_type")
+            .add("fd")
+            .add("enospc")
+            .add("host_numa_node")
+            .add("sqpoll_cpu")
+            .add("dax")
+            .add("vhost_auth_key")
+            .add("discard_on_start")
+            .add("ordered_completions")
+            .add("request_timeout")
+            .add("request_timeout_policy")
+            .add("discard_zeroes")
+            .add("removable");
         parser.parse(disk).map_err(Error::ParseDisk)?;
 
         let path = parser.get("path").map(PathBuf::from);
+        let removable = parser
+            .convert::<Toggle>("removable")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
+        // Removable disks are read-only unless told otherwise, as CD-ROMs.
         let readonly = parser
             .convert::<Toggle>("readonly")
             .map_err(Error::ParseDisk)?
-            .unwrap_or(Toggle(false))
+            .unwrap_or(Toggle(removable))
             .0;
         let direct = parser
             .convert::<Toggle>("direct")
@@ -1162,6 +1629,7 @@ impl DiskConfig {
             .unwrap_or(Toggle(false))
             .0;
         let vhost_socket = parser.get("socket");
+        let vhost_auth_key = parser.get("vhost_auth_key").map(PathBuf::from);
         let id = parser.get("id");
         let disable_io_uring = parser
             .convert::<Toggle>("_disable_io_uring")
@@ -1178,68 +1646,144 @@ impl DiskConfig {
             .map_err(Error::ParseDisk)?
             .unwrap_or_default();
         let rate_limit_group = parser.get("rate_limit_group");
-        let bw_size = parser
-            .convert("bw_size")
+        let serial = parser.get("serial");
+        let queue_affinity = parser
+            .convert::<Tuple<u16, Vec<usize>>>("queue_affinity")
+            .map_err(Error::ParseDisk)?
+            .map(|v| {
+                v.0.iter()
+                    .map(|(e1, e2)| VirtQueueAffinity {
+                        queue_index: *e1,
+                        host_cpus: e2.clone(),
+                    })
+                    .collect()
+            });
+        let latency_histograms = parser
+            .convert::<Toggle>("latency_histograms")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let luks_passphrase_file = parser.get("luks_passphrase_file").map(PathBuf::from);
+        let luks_key_file = parser.get("luks_key_file").map(PathBuf::from);
+        let cache = parser
+            .convert::<CacheMode>("cache")
             .map_err(Error::ParseDisk)?
             .unwrap_or_default();
-        let bw_one_time_burst = parser
-            .convert("bw_one_time_burst")
+        let flush_window = parser
+            .convert::<u64>("flush_window")
+            .map_err(Error::ParseDisk)?;
+        let idle_flush = parser
+            .convert::<u64>("idle_flush")
+            .map_err(Error::ParseDisk)?;
+        let scrub = parser
+            .convert::<Toggle>("scrub")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let scrub_manifest = parser.get("scrub_manifest").map(PathBuf::from);
+        let tmpfile = parser.get("tmpfile").map(PathBuf::from);
+        let size = parser
+            .convert::<ByteSized>("size")
+            .map_err(Error::ParseDisk)?
+            .map(|v| v.0);
+        let preallocate = parser
+            .convert::<PreallocationMode>("preallocate")
             .map_err(Error::ParseDisk)?
             .unwrap_or_default();
-        let bw_refill_time = parser
-            .convert("bw_refill_time")
+        let max_merge_size = parser
+            .convert::<ByteSized>("max_merge_size")
+            .map_err(Error::ParseDisk)?
+            .map(|v| v.0);
+        let max_inflight = parser
+            .convert::<usize>("max_inflight")
+            .map_err(Error::ParseDisk)?;
+        let fixed_buffers = parser
+            .convert::<Toggle>("fixed_buffers")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let import_source = parser.get("import_source").map(PathBuf::from);
+        let import_compression = parser
+            .convert::<ImportCompression>("import_compression")
             .map_err(Error::ParseDisk)?
             .unwrap_or_default();
-        let ops_size = parser
-            .convert("ops_size")
+        let logical_block_size = parser
+            .convert::<ByteSized>("logical_block_size")
+            .map_err(Error::ParseDisk)?
+            .map(|v| v.0);
+        let physical_block_size = parser
+            .convert::<ByteSized>("physical_block_size")
+            .map_err(Error::ParseDisk)?
+            .map(|v| v.0);
+        let fadvise = parser
+            .convert::<FadviseMode>("fadvise")
             .map_err(Error::ParseDisk)?
             .unwrap_or_default();
-        let ops_one_time_burst = parser
-            .convert("ops_one_time_burst")
+        let readahead = parser
+            .convert::<ByteSized>("readahead")
+            .map_err(Error::ParseDisk)?
+            .map(|v| v.0);
+        let io_retries = parser
+            .convert("io_retries")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(0);
+        let completion_batch = parser
+            .convert("completion_batch")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(0);
+        let rotational = parser
+            .convert::<Toggle>("rotational")
+            .map_err(Error::ParseDisk)?
+            .map(|v| v.0);
+        let io_cgroup = parser.get("io_cgroup").map(PathBuf::from);
+        let backend = parser
+            .convert::<AsyncIoBackend>("backend")
             .map_err(Error::ParseDisk)?
             .unwrap_or_default();
-        let ops_refill_time = parser
-            .convert("ops_refill_time")
+        let image_type = parser
+            .convert::<ImageType>("image_type")
+            .map_err(Error::ParseDisk)?;
+        let fd = parser.convert::<i32>("fd").map_err(Error::ParseDisk)?;
+        let enospc = parser
+            .convert::<EnospcPolicy>("enospc")
             .map_err(Error::ParseDisk)?
             .unwrap_or_default();
-        let serial = parser.get("serial");
-        let queue_affinity = parser
-            .convert::<Tuple<u16, Vec<usize>>>("queue_affinity")
+        let host_numa_node = parser
+            .convert::<u32>("host_numa_node")
+            .map_err(Error::ParseDisk)?;
+        let sqpoll_cpu = parser
+            .convert::<u32>("sqpoll_cpu")
+            .map_err(Error::ParseDisk)?;
+        let dax = parser
+            .convert::<Toggle>("dax")
             .map_err(Error::ParseDisk)?
-            .map(|v| {
-                v.0.iter()
-                    .map(|(e1, e2)| VirtQueueAffinity {
-                        queue_index: *e1,
-                        host_cpus: e2.clone(),
-                    })
-                    .collect()
-            });
-        let bw_tb_config = if bw_size != 0 && bw_refill_time != 0 {
-            Some(TokenBucketConfig {
-                size: bw_size,
-                one_time_burst: Some(bw_one_time_burst),
-                refill_time: bw_refill_time,
-            })
-        } else {
-            None
-        };
-        let ops_tb_config = if ops_size != 0 && ops_refill_time != 0 {
-            Some(TokenBucketConfig {
-                size: ops_size,
-                one_time_burst: Some(ops_one_time_burst),
-                refill_time: ops_refill_time,
-            })
-        } else {
-            None
-        };
-        let rate_limiter_config = if bw_tb_config.is_some() || ops_tb_config.is_some() {
-            Some(RateLimiterConfig {
-                bandwidth: bw_tb_config,
-                ops: ops_tb_config,
-            })
-        } else {
-            None
-        };
+            .unwrap_or(Toggle(false))
+            .0;
+        let discard_on_start = parser
+            .convert::<Toggle>("discard_on_start")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let ordered_completions = parser
+            .convert::<Toggle>("ordered_completions")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let discard_zeroes = parser
+            .convert::<Toggle>("discard_zeroes")
+            .map_err(Error::ParseDisk)?
+            .map(|toggle| toggle.0);
+        let request_timeout = parser
+            .convert::<u64>("request_timeout")
+            .map_err(Error::ParseDisk)?;
+        let request_timeout_policy = parser
+            .convert::<TimeoutPolicy>("request_timeout_policy")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or_default();
+        let rate_limiter_config = Self::parse_rate_limiter_config(&parser, "")?;
+        let read_rate_limiter_config = Self::parse_rate_limiter_config(&parser, "read_")?;
+        let write_rate_limiter_config = Self::parse_rate_limiter_config(&parser, "write_")?;
+        let fault_injection = Self::parse_fault_injection_config(&parser)?;
 
         Ok(DiskConfig {
             path,
@@ -1252,12 +1796,53 @@ impl DiskConfig {
             vhost_socket,
             rate_limit_group,
             rate_limiter_config,
+            read_rate_limiter_config,
+            write_rate_limiter_config,
             id,
             disable_io_uring,
             disable_aio,
+            fault_injection,
             pci_segment,
             serial,
             queue_affinity,
+            latency_histograms,
+            luks_passphrase_file,
+            luks_key_file,
+            cache,
+            flush_window,
+            idle_flush,
+            scrub,
+            scrub_manifest,
+            tmpfile,
+            size,
+            preallocate,
+            max_merge_size,
+            max_inflight,
+            fixed_buffers,
+            import_source,
+            import_compression,
+            logical_block_size,
+            physical_block_size,
+            fadvise,
+            readahead,
+            io_retries,
+            completion_batch,
+            rotational,
+            io_cgroup,
+            backend,
+            image_type,
+            fd,
+            enospc,
+            host_numa_node,
+            sqpoll_cpu,
+            dax,
+            vhost_auth_key,
+            discard_on_start,
+            ordered_completions,
+            request_timeout,
+            request_timeout_policy,
+            discard_zeroes,
+            removable,
         })
     }
 
@@ -1286,19 +1871,270 @@ impl DiskConfig {
             return Err(ValidationError::InvalidRateLimiterGroup);
         }
 
-        Ok(())
-    }
-}
+        if self.luks_passphrase_file.is_some() || self.luks_key_file.is_some() {
+            if cfg!(not(feature = "luks")) {
+                return Err(ValidationError::LuksUnsupported);
+            }
+            if self.luks_passphrase_file.is_some() && self.luks_key_file.is_some() {
+                return Err(ValidationError::LuksPassphraseAndKey);
+            }
+            if self.vhost_user {
+                return Err(ValidationError::LuksVhostUser);
+            }
+        }
 
-#[derive(Debug)]
-pub enum ParseVhostModeError {
-    InvalidValue(String),
-}
+        if let Some(flush_window) = self.flush_window {
+            if flush_window == 0 {
+                return Err(ValidationError::InvalidFlushWindow);
+            }
+            if self.vhost_user {
+                return Err(ValidationError::FlushWindowVhostUser);
+            }
+        }
 
-impl FromStr for VhostMode {
-    type Err = ParseVhostModeError;
+        if let Some(idle_flush) = self.idle_flush {
+            if idle_flush == 0 {
+                return Err(ValidationError::InvalidIdleFlush);
+            }
+            if self.vhost_user {
+                return Err(ValidationError::IdleFlushVhostUser);
+            }
+            if self.cache != CacheMode::Writeback {
+                return Err(ValidationError::IdleFlushCacheMode);
+            }
+        }
 
-    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Some(request_timeout) = self.request_timeout {
+            if request_timeout == 0 {
+                return Err(ValidationError::InvalidRequestTimeout);
+            }
+            if self.vhost_user {
+                return Err(ValidationError::RequestTimeoutVhostUser);
+            }
+        } else if self.request_timeout_policy != TimeoutPolicy::Report {
+            return Err(ValidationError::RequestTimeoutPolicyWithoutTimeout);
+        }
+
+        if self.scrub_manifest.is_some() && !self.scrub {
+            return Err(ValidationError::ScrubManifestWithoutScrub);
+        }
+        if self.scrub && self.vhost_user {
+            return Err(ValidationError::ScrubVhostUser);
+        }
+
+        if self.tmpfile.is_some() {
+            if self.path.is_some() {
+                return Err(ValidationError::DiskPathAndTmpfile);
+            }
+            if self.size.is_none() {
+                return Err(ValidationError::TmpfileMissingSize);
+            }
+            if self.vhost_user {
+                return Err(ValidationError::TmpfileVhostUser);
+            }
+        } else if (self.size.is_some() && !self.dax) || self.preallocate != PreallocationMode::Off {
+            return Err(ValidationError::SizeWithoutTmpfile);
+        }
+
+        if let Some(max_merge_size) = self.max_merge_size {
+            if max_merge_size == 0 || max_merge_size % SECTOR_SIZE != 0 {
+                return Err(ValidationError::InvalidMaxMergeSize(max_merge_size));
+            }
+            if self.vhost_user {
+                return Err(ValidationError::MaxMergeSizeVhostUser);
+            }
+        }
+
+        if let Some(max_inflight) = self.max_inflight {
+            if max_inflight == 0 {
+                return Err(ValidationError::InvalidMaxInflight);
+            }
+            if self.vhost_user {
+                return Err(ValidationError::MaxInflightVhostUser);
+            }
+        }
+
+        if self.fixed_buffers
+            && (self.vhost_user
+                || self.disable_io_uring
+                || matches!(self.backend, AsyncIoBackend::Aio | AsyncIoBackend::Sync))
+        {
+            return Err(ValidationError::FixedBuffersWithoutIoUring);
+        }
+
+        if self.sqpoll_cpu.is_some()
+            && (self.vhost_user
+                || self.disable_io_uring
+                || matches!(self.backend, AsyncIoBackend::Aio | AsyncIoBackend::Sync))
+        {
+            return Err(ValidationError::SqpollWithoutIoUring);
+        }
+
+        if self.import_source.is_some() {
+            if self.import_compression != ImportCompression::None
+                && cfg!(not(feature = "compressed_import"))
+            {
+                return Err(ValidationError::ImportCompressionUnsupported);
+            }
+            if self.path.is_none() {
+                return Err(ValidationError::ImportMissingPath);
+            }
+            if self.vhost_user {
+                return Err(ValidationError::ImportVhostUser);
+            }
+        } else if self.import_compression != ImportCompression::None {
+            return Err(ValidationError::ImportCompressionWithoutSource);
+        }
+
+        if let Some(logical_block_size) = self.logical_block_size {
+            if !logical_block_size.is_power_of_two() || logical_block_size < SECTOR_SIZE {
+                return Err(ValidationError::InvalidLogicalBlockSize(logical_block_size));
+            }
+        }
+        if let Some(physical_block_size) = self.physical_block_size {
+            if !physical_block_size.is_power_of_two() || physical_block_size < SECTOR_SIZE {
+                return Err(ValidationError::InvalidPhysicalBlockSize(
+                    physical_block_size,
+                ));
+            }
+            if let Some(logical_block_size) = self.logical_block_size {
+                if physical_block_size < logical_block_size {
+                    return Err(ValidationError::PhysicalBlockSizeMismatch(
+                        physical_block_size,
+                        logical_block_size,
+                    ));
+                }
+            }
+        }
+
+        if self.fadvise != FadviseMode::Normal || self.readahead.is_some() {
+            if self.vhost_user {
+                return Err(ValidationError::PageCacheHintsVhostUser);
+            }
+            if self.readahead == Some(0) {
+                return Err(ValidationError::InvalidReadahead);
+            }
+            if self.readahead.is_some() && self.fadvise == FadviseMode::Dontneed {
+                return Err(ValidationError::ReadaheadWithDontneed);
+            }
+        }
+
+        if self.io_retries != 0 && self.vhost_user {
+            return Err(ValidationError::IoRetriesVhostUser);
+        }
+
+        if self.completion_batch != 0 && self.vhost_user {
+            return Err(ValidationError::CompletionBatchVhostUser);
+        }
+
+        if self.backend != AsyncIoBackend::Auto && self.vhost_user {
+            return Err(ValidationError::BackendVhostUser);
+        }
+
+        if self.enospc != EnospcPolicy::Report && self.vhost_user {
+            return Err(ValidationError::EnospcPolicyVhostUser);
+        }
+
+        if self.host_numa_node.is_some() && self.vhost_user {
+            return Err(ValidationError::HostNumaNodeVhostUser);
+        }
+
+        if self.dax {
+            if self.vhost_user {
+                return Err(ValidationError::DaxVhostUser);
+            }
+            if self.readonly {
+                return Err(ValidationError::DaxReadonly);
+            }
+        }
+
+        if self.discard_on_start {
+            if self.preallocate == PreallocationMode::Full {
+                return Err(ValidationError::DiscardOnStartPreallocated);
+            }
+            if self.readonly {
+                return Err(ValidationError::DiscardOnStartReadonly);
+            }
+            if self.vhost_user {
+                return Err(ValidationError::DiscardOnStartVhostUser);
+            }
+        }
+
+        if self.ordered_completions && self.vhost_user {
+            return Err(ValidationError::OrderedCompletionsVhostUser);
+        }
+
+        if self.discard_zeroes.is_some() && self.vhost_user {
+            return Err(ValidationError::DiscardZeroesVhostUser);
+        }
+
+        if self.removable {
+            if self.vhost_user {
+                return Err(ValidationError::RemovableVhostUser);
+            }
+            if self.dax {
+                return Err(ValidationError::RemovableDax);
+            }
+        }
+
+        if let Some(image_type) = self.image_type {
+            if self.vhost_user {
+                return Err(ValidationError::ImageTypeVhostUser);
+            }
+            if image_type != ImageType::Raw
+                && (self.tmpfile.is_some() || self.import_source.is_some())
+            {
+                return Err(ValidationError::NonRawImageType);
+            }
+        }
+
+        if let Some(fd) = self.fd {
+            if self.path.is_some() || self.tmpfile.is_some() {
+                return Err(ValidationError::DiskFdAndPath);
+            }
+            if self.vhost_user {
+                return Err(ValidationError::DiskFdVhostUser);
+            }
+            if fd <= 2 {
+                return Err(ValidationError::DiskReservedFd);
+            }
+        }
+
+        if self.io_cgroup.is_some() {
+            if self.vhost_user {
+                return Err(ValidationError::IoCgroupVhostUser);
+            }
+            if self.read_rate_limiter_config.is_none() && self.write_rate_limiter_config.is_none() {
+                return Err(ValidationError::IoCgroupWithoutLimits);
+            }
+        }
+
+        if let Some(fault_injection) = &self.fault_injection {
+            if fault_injection.rate > 100 {
+                return Err(ValidationError::InvalidFaultRate(fault_injection.rate));
+            }
+            if self.vhost_user {
+                return Err(ValidationError::FaultInjectionVhostUser);
+            }
+        }
+
+        if self.vhost_auth_key.is_some() && !self.vhost_user {
+            return Err(ValidationError::VhostAuthKeyWithoutVhostUser);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseVhostModeError {
+    InvalidValue(String),
+}
+
+impl FromStr for VhostMode {
+    type Err = ParseVhostModeError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         match s.to_lowercase().as_str() {
             "client" => Ok(VhostMode::Client),
             "server" => Ok(VhostMode::Server),
@@ -2414,6 +3250,14 @@ impl VmConfig {
         }
 
         if let Some(disks) = &self.disks {
+            // The whole process is moved to the cgroup throttling a disk.
+            let mut io_cgroups = disks.iter().filter_map(|disk| disk.io_cgroup.as_ref());
+            if let Some(io_cgroup) = io_cgroups.next() {
+                if io_cgroups.any(|other| other != io_cgroup) {
+                    return Err(ValidationError::IoCgroupMismatch);
+                }
+            }
+
             for disk in disks {
                 if disk.vhost_socket.as_ref().and(disk.path.as_ref()).is_some() {
                     return Err(ValidationError::DiskSocketAndPath);
@@ -3222,11 +4066,52 @@ mod tests {
             id: None,
             disable_io_uring: false,
             disable_aio: false,
+            fault_injection: None,
             rate_limit_group: None,
             rate_limiter_config: None,
+            read_rate_limiter_config: None,
+            write_rate_limiter_config: None,
             pci_segment: 0,
             serial: None,
             queue_affinity: None,
+            latency_histograms: false,
+            luks_passphrase_file: None,
+            luks_key_file: None,
+            cache: CacheMode::Writeback,
+            flush_window: None,
+            idle_flush: None,
+            scrub: false,
+            scrub_manifest: None,
+            tmpfile: None,
+            size: None,
+            preallocate: PreallocationMode::Off,
+            max_merge_size: None,
+            max_inflight: None,
+            fixed_buffers: false,
+            import_source: None,
+            import_compression: ImportCompression::None,
+            logical_block_size: None,
+            physical_block_size: None,
+            fadvise: FadviseMode::Normal,
+            readahead: None,
+            io_retries: 0,
+            completion_batch: 0,
+            rotational: None,
+            io_cgroup: None,
+            backend: AsyncIoBackend::Auto,
+            image_type: None,
+            fd: None,
+            enospc: EnospcPolicy::Report,
+            host_numa_node: None,
+            sqpoll_cpu: None,
+            dax: false,
+            vhost_auth_key: None,
+            discard_on_start: false,
+            ordered_completions: false,
+            request_timeout: None,
+            request_timeout_policy: TimeoutPolicy::Report,
+            discard_zeroes: None,
+            removable: false,
         }
     }
 
@@ -3252,6 +4137,16 @@ mod tests {
                 ..disk_fixture()
             }
         );
+        assert_eq!(
+            DiskConfig::parse("vhost_user=on,socket=/tmp/sock,vhost_auth_key=/path/to_key")?,
+            DiskConfig {
+                path: None,
+                vhost_socket: Some(String::from("/tmp/sock")),
+                vhost_user: true,
+                vhost_auth_key: Some(PathBuf::from("/path/to_key")),
+                ..disk_fixture()
+            }
+        );
         assert_eq!(
             DiskConfig::parse("path=/path/to_file,iommu=on")?,
             DiskConfig {
@@ -3298,729 +4193,1660 @@ mod tests {
             }
         );
         assert_eq!(
-            DiskConfig::parse("path=/path/to_file,queue_affinity=[0@[1],1@[2],2@[3,4],3@[5-8]]")?,
+            DiskConfig::parse(
+                "path=/path/to_file,read_ops_size=5000,read_ops_refill_time=1000,\
+                 write_bw_size=209715200,write_bw_one_time_burst=4096,write_bw_refill_time=1000"
+            )?,
             DiskConfig {
-                queue_affinity: Some(vec![
-                    VirtQueueAffinity {
-                        queue_index: 0,
-                        host_cpus: vec![1],
-                    },
-                    VirtQueueAffinity {
-                        queue_index: 1,
-                        host_cpus: vec![2],
-                    },
-                    VirtQueueAffinity {
-                        queue_index: 2,
-                        host_cpus: vec![3, 4],
-                    },
-                    VirtQueueAffinity {
-                        queue_index: 3,
-                        host_cpus: vec![5, 6, 7, 8],
-                    }
-                ]),
+                read_rate_limiter_config: Some(RateLimiterConfig {
+                    bandwidth: None,
+                    ops: Some(TokenBucketConfig {
+                        size: 5000,
+                        one_time_burst: Some(0),
+                        refill_time: 1000,
+                    }),
+                }),
+                write_rate_limiter_config: Some(RateLimiterConfig {
+                    bandwidth: Some(TokenBucketConfig {
+                        size: 209715200,
+                        one_time_burst: Some(4096),
+                        refill_time: 1000,
+                    }),
+                    ops: None,
+                }),
                 ..disk_fixture()
             }
         );
-        Ok(())
-    }
-
-    fn net_fixture() -> NetConfig {
-        NetConfig {
-            tap: None,
-            ip: Ipv4Addr::new(192, 168, 249, 1),
-            mask: Ipv4Addr::new(255, 255, 255, 0),
-            mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
-            host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
-            mtu: None,
-            iommu: false,
-            num_queues: 2,
-            queue_size: 256,
-            vhost_user: false,
-            vhost_socket: None,
-            vhost_mode: VhostMode::Client,
-            id: None,
-            fds: None,
-            rate_limiter_config: None,
-            pci_segment: 0,
-            offload_tso: true,
-            offload_ufo: true,
-            offload_csum: true,
-        }
-    }
-
-    #[test]
-    fn test_net_parsing() -> Result<()> {
-        // mac address is random
         assert_eq!(
-            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef")?,
-            net_fixture(),
+            DiskConfig::parse("path=/path/to_file,latency_histograms=on")?,
+            DiskConfig {
+                latency_histograms: true,
+                ..disk_fixture()
+            }
         );
-
         assert_eq!(
-            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,id=mynet0")?,
-            NetConfig {
-                id: Some("mynet0".to_owned()),
-                ..net_fixture()
+            DiskConfig::parse("path=/path/to_file,luks_passphrase_file=/path/to_passphrase")?,
+            DiskConfig {
+                luks_passphrase_file: Some(PathBuf::from("/path/to_passphrase")),
+                ..disk_fixture()
             }
         );
-
         assert_eq!(
-            NetConfig::parse(
-                "mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,tap=tap0,ip=192.168.100.1,mask=255.255.255.128"
-            )?,
-            NetConfig {
-                tap: Some("tap0".to_owned()),
-                ip: "192.168.100.1".parse().unwrap(),
-                mask: "255.255.255.128".parse().unwrap(),
-                ..net_fixture()
+            DiskConfig::parse("path=/path/to_file,luks_key_file=/path/to_key")?,
+            DiskConfig {
+                luks_key_file: Some(PathBuf::from("/path/to_key")),
+                ..disk_fixture()
             }
         );
-
         assert_eq!(
-            NetConfig::parse(
-                "mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,vhost_user=true,socket=/tmp/sock"
-            )?,
-            NetConfig {
-                vhost_user: true,
-                vhost_socket: Some("/tmp/sock".to_owned()),
-                ..net_fixture()
+            DiskConfig::parse("path=/path/to_file,cache=writethrough")?,
+            DiskConfig {
+                cache: CacheMode::Writethrough,
+                ..disk_fixture()
             }
         );
-
         assert_eq!(
-            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,num_queues=4,queue_size=1024,iommu=on")?,
-            NetConfig {
-                num_queues: 4,
-                queue_size: 1024,
-                iommu: true,
-                ..net_fixture()
+            DiskConfig::parse("path=/path/to_file,cache=directsync")?,
+            DiskConfig {
+                cache: CacheMode::DirectSync,
+                ..disk_fixture()
             }
         );
-
+        assert!(DiskConfig::parse("path=/path/to_file,cache=bogus").is_err());
         assert_eq!(
-            NetConfig::parse("mac=de:ad:be:ef:12:34,fd=[3,7],num_queues=4")?,
-            NetConfig {
-                host_mac: None,
-                fds: Some(vec![3, 7]),
-                num_queues: 4,
-                ..net_fixture()
+            DiskConfig::parse("path=/path/to_file,flush_window=5")?,
+            DiskConfig {
+                flush_window: Some(5),
+                ..disk_fixture()
             }
         );
-
-        Ok(())
-    }
-
-    #[test]
-    fn test_parse_rng() -> Result<()> {
-        assert_eq!(RngConfig::parse("")?, RngConfig::default());
         assert_eq!(
-            RngConfig::parse("src=/dev/random")?,
-            RngConfig {
-                src: PathBuf::from("/dev/random"),
-                ..Default::default()
+            DiskConfig::parse("path=/path/to_file,idle_flush=1000")?,
+            DiskConfig {
+                idle_flush: Some(1000),
+                ..disk_fixture()
             }
         );
         assert_eq!(
-            RngConfig::parse("src=/dev/random,iommu=on")?,
-            RngConfig {
-                src: PathBuf::from("/dev/random"),
-                iommu: true,
+            DiskConfig::parse("path=/path/to_file,scrub=on,scrub_manifest=/path/to/manifest")?,
+            DiskConfig {
+                scrub: true,
+                scrub_manifest: Some(PathBuf::from("/path/to/manifest")),
+                ..disk_fixture()
             }
         );
         assert_eq!(
-            RngConfig::parse("iommu=on")?,
-            RngConfig {
-                iommu: true,
-                ..Default::default()
+            DiskConfig::parse("path=/path/to_file,max_merge_size=1M")?,
+            DiskConfig {
+                max_merge_size: Some(1 << 20),
+                ..disk_fixture()
             }
         );
-        Ok(())
-    }
-
-    fn fs_fixture() -> FsConfig {
-        FsConfig {
-            socket: PathBuf::from("/tmp/sock"),
-            tag: "mytag".to_owned(),
-            num_queues: 1,
-            queue_size: 1024,
-            id: None,
-            pci_segment: 0,
-        }
-    }
-
-    #[test]
-    fn test_parse_fs() -> Result<()> {
-        // "tag" and "socket" must be supplied
-        assert!(FsConfig::parse("").is_err());
-        assert!(FsConfig::parse("tag=mytag").is_err());
-        assert!(FsConfig::parse("socket=/tmp/sock").is_err());
-        assert_eq!(FsConfig::parse("tag=mytag,socket=/tmp/sock")?, fs_fixture());
         assert_eq!(
-            FsConfig::parse("tag=mytag,socket=/tmp/sock,num_queues=4,queue_size=1024")?,
-            FsConfig {
-                num_queues: 4,
-                queue_size: 1024,
-                ..fs_fixture()
+            DiskConfig::parse("path=/path/to_file,max_inflight=32")?,
+            DiskConfig {
+                max_inflight: Some(32),
+                ..disk_fixture()
             }
         );
-
-        Ok(())
-    }
-
-    fn pmem_fixture() -> PmemConfig {
-        PmemConfig {
-            file: PathBuf::from("/tmp/pmem"),
-            size: Some(128 << 20),
-            iommu: false,
-            discard_writes: false,
-            id: None,
-            pci_segment: 0,
-        }
-    }
-
-    #[test]
-    fn test_pmem_parsing() -> Result<()> {
-        // Must always give a file and size
-        assert!(PmemConfig::parse("").is_err());
-        assert!(PmemConfig::parse("size=128M").is_err());
         assert_eq!(
-            PmemConfig::parse("file=/tmp/pmem,size=128M")?,
-            pmem_fixture()
+            DiskConfig::parse("path=/path/to_file,fixed_buffers=on")?,
+            DiskConfig {
+                fixed_buffers: true,
+                ..disk_fixture()
+            }
         );
         assert_eq!(
-            PmemConfig::parse("file=/tmp/pmem,size=128M,id=mypmem0")?,
-            PmemConfig {
-                id: Some("mypmem0".to_owned()),
-                ..pmem_fixture()
+            DiskConfig::parse(
+                "path=/path/to_file,import_source=/path/to_fifo,import_compression=zstd"
+            )?,
+            DiskConfig {
+                import_source: Some(PathBuf::from("/path/to_fifo")),
+                import_compression: ImportCompression::Zstd,
+                ..disk_fixture()
             }
         );
         assert_eq!(
-            PmemConfig::parse("file=/tmp/pmem,size=128M,iommu=on,discard_writes=on")?,
-            PmemConfig {
-                discard_writes: true,
-                iommu: true,
-                ..pmem_fixture()
+            DiskConfig::parse("path=/path/to_file,direct=on,logical_block_size=4K")?,
+            DiskConfig {
+                direct: true,
+                logical_block_size: Some(4096),
+                ..disk_fixture()
             }
         );
-
-        Ok(())
-    }
-
-    #[test]
-    fn test_console_parsing() -> Result<()> {
-        assert!(ConsoleConfig::parse("").is_err());
-        assert!(ConsoleConfig::parse("badmode").is_err());
         assert_eq!(
-            ConsoleConfig::parse("off")?,
-            ConsoleConfig {
-                mode: ConsoleOutputMode::Off,
-                iommu: false,
-                file: None,
-                socket: None,
+            DiskConfig::parse("path=/path/to_file,logical_block_size=4K,physical_block_size=4K")?,
+            DiskConfig {
+                logical_block_size: Some(4096),
+                physical_block_size: Some(4096),
+                ..disk_fixture()
             }
         );
         assert_eq!(
-            ConsoleConfig::parse("pty")?,
-            ConsoleConfig {
-                mode: ConsoleOutputMode::Pty,
-                iommu: false,
-                file: None,
-                socket: None,
+            DiskConfig::parse("path=/path/to_file,fadvise=sequential,readahead=2M")?,
+            DiskConfig {
+                fadvise: FadviseMode::Sequential,
+                readahead: Some(2 << 20),
+                ..disk_fixture()
             }
         );
         assert_eq!(
-            ConsoleConfig::parse("tty")?,
-            ConsoleConfig {
-                mode: ConsoleOutputMode::Tty,
-                iommu: false,
-                file: None,
-                socket: None,
+            DiskConfig::parse("path=/path/to_file,io_retries=8")?,
+            DiskConfig {
+                io_retries: 8,
+                ..disk_fixture()
             }
         );
         assert_eq!(
-            ConsoleConfig::parse("null")?,
-            ConsoleConfig {
-                mode: ConsoleOutputMode::Null,
-                iommu: false,
-                file: None,
-                socket: None,
+            DiskConfig::parse("path=/path/to_file,completion_batch=16")?,
+            DiskConfig {
+                completion_batch: 16,
+                ..disk_fixture()
             }
         );
         assert_eq!(
-            ConsoleConfig::parse("file=/tmp/console")?,
-            ConsoleConfig {
-                mode: ConsoleOutputMode::File,
-                iommu: false,
-                file: Some(PathBuf::from("/tmp/console")),
-                socket: None,
+            DiskConfig::parse("path=/path/to_file,backend=io_uring")?,
+            DiskConfig {
+                backend: AsyncIoBackend::IoUring,
+                ..disk_fixture()
             }
         );
         assert_eq!(
-            ConsoleConfig::parse("null,iommu=on")?,
-            ConsoleConfig {
-                mode: ConsoleOutputMode::Null,
-                iommu: true,
-                file: None,
-                socket: None,
+            DiskConfig::parse("path=/path/to_file,image_type=qcow2")?,
+            DiskConfig {
+                image_type: Some(ImageType::Qcow2),
+                ..disk_fixture()
             }
         );
+        assert!(DiskConfig::parse("path=/path/to_file,image_type=vmdk").is_err());
         assert_eq!(
-            ConsoleConfig::parse("file=/tmp/console,iommu=on")?,
-            ConsoleConfig {
-                mode: ConsoleOutputMode::File,
-                iommu: true,
-                file: Some(PathBuf::from("/tmp/console")),
-                socket: None,
+            DiskConfig::parse("path=/path/to_file,enospc=pause")?,
+            DiskConfig {
+                enospc: EnospcPolicy::Pause,
+                ..disk_fixture()
             }
         );
+        assert!(DiskConfig::parse("path=/path/to_file,enospc=retry").is_err());
         assert_eq!(
-            ConsoleConfig::parse("socket=/tmp/serial.sock,iommu=on")?,
-            ConsoleConfig {
-                mode: ConsoleOutputMode::Socket,
-                iommu: true,
-                file: None,
-                socket: Some(PathBuf::from("/tmp/serial.sock")),
+            DiskConfig::parse("path=/path/to_file,host_numa_node=1,sqpoll_cpu=3")?,
+            DiskConfig {
+                host_numa_node: Some(1),
+                sqpoll_cpu: Some(3),
+                ..disk_fixture()
             }
         );
-        Ok(())
-    }
-
-    fn device_fixture() -> DeviceConfig {
-        DeviceConfig {
-            path: PathBuf::from("/path/to/device"),
-            id: None,
-            iommu: false,
-            pci_segment: 0,
-            x_nv_gpudirect_clique: None,
-        }
-    }
-
-    #[test]
-    fn test_device_parsing() -> Result<()> {
-        // Device must have a path provided
-        assert!(DeviceConfig::parse("").is_err());
         assert_eq!(
-            DeviceConfig::parse("path=/path/to/device")?,
-            device_fixture()
+            DiskConfig::parse("path=/path/to_file,dax=on,size=1G")?,
+            DiskConfig {
+                dax: true,
+                size: Some(1 << 30),
+                ..disk_fixture()
+            }
         );
-
         assert_eq!(
-            DeviceConfig::parse("path=/path/to/device,iommu=on")?,
-            DeviceConfig {
-                iommu: true,
-                ..device_fixture()
+            DiskConfig::parse("path=/path/to_file,discard_on_start=on")?,
+            DiskConfig {
+                discard_on_start: true,
+                ..disk_fixture()
             }
         );
-
         assert_eq!(
-            DeviceConfig::parse("path=/path/to/device,iommu=on,id=mydevice0")?,
-            DeviceConfig {
-                id: Some("mydevice0".to_owned()),
-                iommu: true,
-                ..device_fixture()
+            DiskConfig::parse("path=/path/to_file,ordered_completions=on")?,
+            DiskConfig {
+                ordered_completions: true,
+                ..disk_fixture()
             }
         );
-
-        Ok(())
-    }
-
-    fn vdpa_fixture() -> VdpaConfig {
-        VdpaConfig {
-            path: PathBuf::from("/dev/vhost-vdpa"),
-            num_queues: 1,
-            iommu: false,
-            id: None,
-            pci_segment: 0,
-        }
-    }
-
-    #[test]
-    fn test_vdpa_parsing() -> Result<()> {
-        // path is required
-        assert!(VdpaConfig::parse("").is_err());
-        assert_eq!(VdpaConfig::parse("path=/dev/vhost-vdpa")?, vdpa_fixture());
         assert_eq!(
-            VdpaConfig::parse("path=/dev/vhost-vdpa,num_queues=2,id=my_vdpa")?,
-            VdpaConfig {
-                num_queues: 2,
-                id: Some("my_vdpa".to_owned()),
-                ..vdpa_fixture()
+            DiskConfig::parse("path=/path/to_file,request_timeout=30000")?,
+            DiskConfig {
+                request_timeout: Some(30000),
+                ..disk_fixture()
             }
         );
-        Ok(())
-    }
-
-    #[test]
-    fn test_tpm_parsing() -> Result<()> {
-        // path is required
-        assert!(TpmConfig::parse("").is_err());
         assert_eq!(
-            TpmConfig::parse("socket=/var/run/tpm.sock")?,
-            TpmConfig {
-                socket: PathBuf::from("/var/run/tpm.sock"),
+            DiskConfig::parse(
+                "path=/path/to_file,request_timeout=30000,request_timeout_policy=cancel"
+            )?,
+            DiskConfig {
+                request_timeout: Some(30000),
+                request_timeout_policy: TimeoutPolicy::Cancel,
+                ..disk_fixture()
             }
         );
-        Ok(())
-    }
-
-    #[test]
-    fn test_vsock_parsing() -> Result<()> {
-        // socket and cid is required
-        assert!(VsockConfig::parse("").is_err());
+        assert!(DiskConfig::parse("path=/path/to_file,request_timeout_policy=abort").is_err());
         assert_eq!(
-            VsockConfig::parse("socket=/tmp/sock,cid=3")?,
-            VsockConfig {
-                cid: 3,
-                socket: PathBuf::from("/tmp/sock"),
-                iommu: false,
-                id: None,
-                pci_segment: 0,
+            DiskConfig::parse("path=/path/to_file,discard_zeroes=off")?,
+            DiskConfig {
+                discard_zeroes: Some(false),
+                ..disk_fixture()
             }
         );
         assert_eq!(
-            VsockConfig::parse("socket=/tmp/sock,cid=3,iommu=on")?,
-            VsockConfig {
-                cid: 3,
-                socket: PathBuf::from("/tmp/sock"),
-                iommu: true,
-                id: None,
-                pci_segment: 0,
+            DiskConfig::parse("path=/path/to_file,removable=on")?,
+            DiskConfig {
+                removable: true,
+                readonly: true,
+                ..disk_fixture()
             }
         );
-        Ok(())
-    }
-
-    #[test]
-    fn test_restore_parsing() -> Result<()> {
         assert_eq!(
-            RestoreConfig::parse("source_url=/path/to/snapshot")?,
-            RestoreConfig {
-                source_url: PathBuf::from("/path/to/snapshot"),
-                prefault: false,
-                net_fds: None,
+            DiskConfig::parse("path=/path/to_file,removable=on,readonly=off")?,
+            DiskConfig {
+                removable: true,
+                ..disk_fixture()
             }
         );
         assert_eq!(
-            RestoreConfig::parse(
-                "source_url=/path/to/snapshot,prefault=off,net_fds=[net0@[3,4],net1@[5,6,7,8]]"
-            )?,
-            RestoreConfig {
-                source_url: PathBuf::from("/path/to/snapshot"),
-                prefault: false,
-                net_fds: Some(vec![
-                    RestoredNetConfig {
-                        id: "net0".to_string(),
-                        num_fds: 2,
-                        fds: Some(vec![3, 4]),
+            DiskConfig::parse("fd=3")?,
+            DiskConfig {
+                path: None,
+                fd: Some(3),
+                ..disk_fixture()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,rotational=on")?,
+            DiskConfig {
+                rotational: Some(true),
+                ..disk_fixture()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse(
+                "path=/path/to_file,io_cgroup=/sys/fs/cgroup/vm,\
+                 write_bw_size=1048576,write_bw_refill_time=1000"
+            )?,
+            DiskConfig {
+                io_cgroup: Some(PathBuf::from("/sys/fs/cgroup/vm")),
+                write_rate_limiter_config: Some(RateLimiterConfig {
+                    bandwidth: Some(TokenBucketConfig {
+                        size: 1 << 20,
+                        one_time_burst: Some(0),
+                        refill_time: 1000,
+                    }),
+                    ops: None,
+                }),
+                ..disk_fixture()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("tmpfile=/tmp,size=1G,preallocate=on")?,
+            DiskConfig {
+                path: None,
+                tmpfile: Some(PathBuf::from("/tmp")),
+                size: Some(1 << 30),
+                preallocate: PreallocationMode::Full,
+                ..disk_fixture()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("tmpfile=/tmp,size=1G,preallocate=metadata")?,
+            DiskConfig {
+                path: None,
+                tmpfile: Some(PathBuf::from("/tmp")),
+                size: Some(1 << 30),
+                preallocate: PreallocationMode::Metadata,
+                ..disk_fixture()
+            }
+        );
+        assert!(DiskConfig::parse("tmpfile=/tmp,size=1G,preallocate=bogus").is_err());
+        assert_eq!(
+            DiskConfig::parse(
+                "path=/path/to_file,_fault_rate=5,_fault_offsets=[0,1048576],\
+                 _fault_after=1000,_fault_latency=10"
+            )?,
+            DiskConfig {
+                fault_injection: Some(FaultInjectionConfig {
+                    rate: 5,
+                    offsets: vec![0, 1 << 20],
+                    after: Some(1000),
+                    latency: 10,
+                }),
+                ..disk_fixture()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,_fault_after=0")?,
+            DiskConfig {
+                fault_injection: Some(FaultInjectionConfig {
+                    after: Some(0),
+                    ..Default::default()
+                }),
+                ..disk_fixture()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,queue_affinity=[0@[1],1@[2],2@[3,4],3@[5-8]]")?,
+            DiskConfig {
+                queue_affinity: Some(vec![
+                    VirtQueueAffinity {
+                        queue_index: 0,
+                        host_cpus: vec![1],
                     },
-                    RestoredNetConfig {
-                        id: "net1".to_string(),
-                        num_fds: 4,
-                        fds: Some(vec![5, 6, 7, 8]),
+                    VirtQueueAffinity {
+                        queue_index: 1,
+                        host_cpus: vec![2],
+                    },
+                    VirtQueueAffinity {
+                        queue_index: 2,
+                        host_cpus: vec![3, 4],
+                    },
+                    VirtQueueAffinity {
+                        queue_index: 3,
+                        host_cpus: vec![5, 6, 7, 8],
                     }
                 ]),
+                ..disk_fixture()
             }
         );
-        // Parsing should fail as source_url is a required field
-        assert!(RestoreConfig::parse("prefault=off").is_err());
         Ok(())
     }
 
-    #[test]
-    fn test_restore_config_validation() {
-        // interested in only VmConfig.net, so set rest to default values
-        let mut snapshot_vm_config = VmConfig {
-            cpus: CpusConfig::default(),
-            memory: MemoryConfig::default(),
-            payload: None,
-            rate_limit_groups: None,
-            disks: None,
-            rng: RngConfig::default(),
-            balloon: None,
-            fs: None,
-            pmem: None,
-            serial: default_serial(),
-            console: default_console(),
-            #[cfg(target_arch = "x86_64")]
-            debug_console: DebugConsoleConfig::default(),
-            devices: None,
-            user_devices: None,
-            vdpa: None,
-            vsock: None,
-            pvpanic: false,
+    fn net_fixture() -> NetConfig {
+        NetConfig {
+            tap: None,
+            ip: Ipv4Addr::new(192, 168, 249, 1),
+            mask: Ipv4Addr::new(255, 255, 255, 0),
+            mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+            host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
+            mtu: None,
             iommu: false,
-            #[cfg(target_arch = "x86_64")]
-            sgx_epc: None,
-            numa: None,
-            watchdog: false,
-            #[cfg(feature = "guest_debug")]
-            gdb: false,
-            pci_segments: None,
-            platform: None,
-            tpm: None,
-            preserved_fds: None,
-            net: Some(vec![
-                NetConfig {
-                    id: Some("net0".to_owned()),
-                    num_queues: 2,
-                    fds: Some(vec![-1, -1, -1, -1]),
-                    ..net_fixture()
-                },
-                NetConfig {
-                    id: Some("net1".to_owned()),
-                    num_queues: 1,
-                    fds: Some(vec![-1, -1]),
-                    ..net_fixture()
-                },
-                NetConfig {
-                    id: Some("net2".to_owned()),
-                    fds: None,
-                    ..net_fixture()
-                },
-            ]),
-        };
+            num_queues: 2,
+            queue_size: 256,
+            vhost_user: false,
+            vhost_socket: None,
+            vhost_mode: VhostMode::Client,
+            id: None,
+            fds: None,
+            rate_limiter_config: None,
+            pci_segment: 0,
+            offload_tso: true,
+            offload_ufo: true,
+            offload_csum: true,
+        }
+    }
 
-        let valid_config = RestoreConfig {
-            source_url: PathBuf::from("/path/to/snapshot"),
-            prefault: false,
-            net_fds: Some(vec![
-                RestoredNetConfig {
-                    id: "net0".to_string(),
-                    num_fds: 4,
-                    fds: Some(vec![3, 4, 5, 6]),
-                },
-                RestoredNetConfig {
-                    id: "net1".to_string(),
-                    num_fds: 2,
-                    fds: Some(vec![7, 8]),
-                },
-            ]),
-        };
-        assert!(valid_config.validate(&snapshot_vm_config).is_ok());
+    #[test]
+    fn test_net_parsing() -> Result<()> {
+        // mac address is random
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef")?,
+            net_fixture(),
+        );
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,id=mynet0")?,
+            NetConfig {
+                id: Some("mynet0".to_owned()),
+                ..net_fixture()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,tap=tap0,ip=192.168.100.1,mask=255.255.255.128"
+            )?,
+            NetConfig {
+                tap: Some("tap0".to_owned()),
+                ip: "192.168.100.1".parse().unwrap(),
+                mask: "255.255.255.128".parse().unwrap(),
+                ..net_fixture()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,vhost_user=true,socket=/tmp/sock"
+            )?,
+            NetConfig {
+                vhost_user: true,
+                vhost_socket: Some("/tmp/sock".to_owned()),
+                ..net_fixture()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,num_queues=4,queue_size=1024,iommu=on")?,
+            NetConfig {
+                num_queues: 4,
+                queue_size: 1024,
+                iommu: true,
+                ..net_fixture()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,fd=[3,7],num_queues=4")?,
+            NetConfig {
+                host_mac: None,
+                fds: Some(vec![3, 7]),
+                num_queues: 4,
+                ..net_fixture()
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_rng() -> Result<()> {
+        assert_eq!(RngConfig::parse("")?, RngConfig::default());
+        assert_eq!(
+            RngConfig::parse("src=/dev/random")?,
+            RngConfig {
+                src: PathBuf::from("/dev/random"),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            RngConfig::parse("src=/dev/random,iommu=on")?,
+            RngConfig {
+                src: PathBuf::from("/dev/random"),
+                iommu: true,
+            }
+        );
+        assert_eq!(
+            RngConfig::parse("iommu=on")?,
+            RngConfig {
+                iommu: true,
+                ..Default::default()
+            }
+        );
+        Ok(())
+    }
+
+    fn fs_fixture() -> FsConfig {
+        FsConfig {
+            socket: PathBuf::from("/tmp/sock"),
+            tag: "mytag".to_owned(),
+            num_queues: 1,
+            queue_size: 1024,
+            id: None,
+            pci_segment: 0,
+        }
+    }
+
+    #[test]
+    fn test_parse_fs() -> Result<()> {
+        // "tag" and "socket" must be supplied
+        assert!(FsConfig::parse("").is_err());
+        assert!(FsConfig::parse("tag=mytag").is_err());
+        assert!(FsConfig::parse("socket=/tmp/sock").is_err());
+        assert_eq!(FsConfig::parse("tag=mytag,socket=/tmp/sock")?, fs_fixture());
+        assert_eq!(
+            FsConfig::parse("tag=mytag,socket=/tmp/sock,num_queues=4,queue_size=1024")?,
+            FsConfig {
+                num_queues: 4,
+                queue_size: 1024,
+                ..fs_fixture()
+            }
+        );
+
+        Ok(())
+    }
+
+    fn pmem_fixture() -> PmemConfig {
+        PmemConfig {
+            file: PathBuf::from("/tmp/pmem"),
+            size: Some(128 << 20),
+            iommu: false,
+            discard_writes: false,
+            id: None,
+            pci_segment: 0,
+        }
+    }
+
+    #[test]
+    fn test_pmem_parsing() -> Result<()> {
+        // Must always give a file and size
+        assert!(PmemConfig::parse("").is_err());
+        assert!(PmemConfig::parse("size=128M").is_err());
+        assert_eq!(
+            PmemConfig::parse("file=/tmp/pmem,size=128M")?,
+            pmem_fixture()
+        );
+        assert_eq!(
+            PmemConfig::parse("file=/tmp/pmem,size=128M,id=mypmem0")?,
+            PmemConfig {
+                id: Some("mypmem0".to_owned()),
+                ..pmem_fixture()
+            }
+        );
+        assert_eq!(
+            PmemConfig::parse("file=/tmp/pmem,size=128M,iommu=on,discard_writes=on")?,
+            PmemConfig {
+                discard_writes: true,
+                iommu: true,
+                ..pmem_fixture()
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_console_parsing() -> Result<()> {
+        assert!(ConsoleConfig::parse("").is_err());
+        assert!(ConsoleConfig::parse("badmode").is_err());
+        assert_eq!(
+            ConsoleConfig::parse("off")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Off,
+                iommu: false,
+                file: None,
+                socket: None,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("pty")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Pty,
+                iommu: false,
+                file: None,
+                socket: None,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("tty")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Tty,
+                iommu: false,
+                file: None,
+                socket: None,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("null")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Null,
+                iommu: false,
+                file: None,
+                socket: None,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("file=/tmp/console")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::File,
+                iommu: false,
+                file: Some(PathBuf::from("/tmp/console")),
+                socket: None,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("null,iommu=on")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Null,
+                iommu: true,
+                file: None,
+                socket: None,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("file=/tmp/console,iommu=on")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::File,
+                iommu: true,
+                file: Some(PathBuf::from("/tmp/console")),
+                socket: None,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("socket=/tmp/serial.sock,iommu=on")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Socket,
+                iommu: true,
+                file: None,
+                socket: Some(PathBuf::from("/tmp/serial.sock")),
+            }
+        );
+        Ok(())
+    }
+
+    fn device_fixture() -> DeviceConfig {
+        DeviceConfig {
+            path: PathBuf::from("/path/to/device"),
+            id: None,
+            iommu: false,
+            pci_segment: 0,
+            x_nv_gpudirect_clique: None,
+        }
+    }
+
+    #[test]
+    fn test_device_parsing() -> Result<()> {
+        // Device must have a path provided
+        assert!(DeviceConfig::parse("").is_err());
+        assert_eq!(
+            DeviceConfig::parse("path=/path/to/device")?,
+            device_fixture()
+        );
+
+        assert_eq!(
+            DeviceConfig::parse("path=/path/to/device,iommu=on")?,
+            DeviceConfig {
+                iommu: true,
+                ..device_fixture()
+            }
+        );
+
+        assert_eq!(
+            DeviceConfig::parse("path=/path/to/device,iommu=on,id=mydevice0")?,
+            DeviceConfig {
+                id: Some("mydevice0".to_owned()),
+                iommu: true,
+                ..device_fixture()
+            }
+        );
+
+        Ok(())
+    }
+
+    fn vdpa_fixture() -> VdpaConfig {
+        VdpaConfig {
+            path: PathBuf::from("/dev/vhost-vdpa"),
+            num_queues: 1,
+            iommu: false,
+            id: None,
+            pci_segment: 0,
+        }
+    }
+
+    #[test]
+    fn test_vdpa_parsing() -> Result<()> {
+        // path is required
+        assert!(VdpaConfig::parse("").is_err());
+        assert_eq!(VdpaConfig::parse("path=/dev/vhost-vdpa")?, vdpa_fixture());
+        assert_eq!(
+            VdpaConfig::parse("path=/dev/vhost-vdpa,num_queues=2,id=my_vdpa")?,
+            VdpaConfig {
+                num_queues: 2,
+                id: Some("my_vdpa".to_owned()),
+                ..vdpa_fixture()
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tpm_parsing() -> Result<()> {
+        // path is required
+        assert!(TpmConfig::parse("").is_err());
+        assert_eq!(
+            TpmConfig::parse("socket=/var/run/tpm.sock")?,
+            TpmConfig {
+                socket: PathBuf::from("/var/run/tpm.sock"),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_vsock_parsing() -> Result<()> {
+        // socket and cid is required
+        assert!(VsockConfig::parse("").is_err());
+        assert_eq!(
+            VsockConfig::parse("socket=/tmp/sock,cid=3")?,
+            VsockConfig {
+                cid: 3,
+                socket: PathBuf::from("/tmp/sock"),
+                iommu: false,
+                id: None,
+                pci_segment: 0,
+            }
+        );
+        assert_eq!(
+            VsockConfig::parse("socket=/tmp/sock,cid=3,iommu=on")?,
+            VsockConfig {
+                cid: 3,
+                socket: PathBuf::from("/tmp/sock"),
+                iommu: true,
+                id: None,
+                pci_segment: 0,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_parsing() -> Result<()> {
+        assert_eq!(
+            RestoreConfig::parse("source_url=/path/to/snapshot")?,
+            RestoreConfig {
+                source_url: PathBuf::from("/path/to/snapshot"),
+                prefault: false,
+                net_fds: None,
+            }
+        );
+        assert_eq!(
+            RestoreConfig::parse(
+                "source_url=/path/to/snapshot,prefault=off,net_fds=[net0@[3,4],net1@[5,6,7,8]]"
+            )?,
+            RestoreConfig {
+                source_url: PathBuf::from("/path/to/snapshot"),
+                prefault: false,
+                net_fds: Some(vec![
+                    RestoredNetConfig {
+                        id: "net0".to_string(),
+                        num_fds: 2,
+                        fds: Some(vec![3, 4]),
+                    },
+                    RestoredNetConfig {
+                        id: "net1".to_string(),
+                        num_fds: 4,
+                        fds: Some(vec![5, 6, 7, 8]),
+                    }
+                ]),
+            }
+        );
+        // Parsing should fail as source_url is a required field
+        assert!(RestoreConfig::parse("prefault=off").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_config_validation() {
+        // interested in only VmConfig.net, so set rest to default values
+        let mut snapshot_vm_config = VmConfig {
+            cpus: CpusConfig::default(),
+            memory: MemoryConfig::default(),
+            payload: None,
+            rate_limit_groups: None,
+            disks: None,
+            rng: RngConfig::default(),
+            balloon: None,
+            fs: None,
+            pmem: None,
+            serial: default_serial(),
+            console: default_console(),
+            #[cfg(target_arch = "x86_64")]
+            debug_console: DebugConsoleConfig::default(),
+            devices: None,
+            user_devices: None,
+            vdpa: None,
+            vsock: None,
+            pvpanic: false,
+            iommu: false,
+            #[cfg(target_arch = "x86_64")]
+            sgx_epc: None,
+            numa: None,
+            watchdog: false,
+            #[cfg(feature = "guest_debug")]
+            gdb: false,
+            pci_segments: None,
+            platform: None,
+            tpm: None,
+            preserved_fds: None,
+            net: Some(vec![
+                NetConfig {
+                    id: Some("net0".to_owned()),
+                    num_queues: 2,
+                    fds: Some(vec![-1, -1, -1, -1]),
+                    ..net_fixture()
+                },
+                NetConfig {
+                    id: Some("net1".to_owned()),
+                    num_queues: 1,
+                    fds: Some(vec![-1, -1]),
+                    ..net_fixture()
+                },
+                NetConfig {
+                    id: Some("net2".to_owned()),
+                    fds: None,
+                    ..net_fixture()
+                },
+            ]),
+        };
+
+        let valid_config = RestoreConfig {
+            source_url: PathBuf::from("/path/to/snapshot"),
+            prefault: false,
+            net_fds: Some(vec![
+                RestoredNetConfig {
+                    id: "net0".to_string(),
+                    num_fds: 4,
+                    fds: Some(vec![3, 4, 5, 6]),
+                },
+                RestoredNetConfig {
+                    id: "net1".to_string(),
+                    num_fds: 2,
+                    fds: Some(vec![7, 8]),
+                },
+            ]),
+        };
+        assert!(valid_config.validate(&snapshot_vm_config).is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net_fds = Some(vec![RestoredNetConfig {
+            id: "netx".to_string(),
+            num_fds: 4,
+            fds: Some(vec![3, 4, 5, 6]),
+        }]);
+        assert_eq!(
+            invalid_config.validate(&snapshot_vm_config),
+            Err(ValidationError::RestoreMissingRequiredNetId(
+                "net0".to_string()
+            ))
+        );
+
+        invalid_config.net_fds = Some(vec![
+            RestoredNetConfig {
+                id: "net0".to_string(),
+                num_fds: 4,
+                fds: Some(vec![3, 4, 5, 6]),
+            },
+            RestoredNetConfig {
+                id: "net0".to_string(),
+                num_fds: 4,
+                fds: Some(vec![3, 4, 5, 6]),
+            },
+        ]);
+        assert_eq!(
+            invalid_config.validate(&snapshot_vm_config),
+            Err(ValidationError::IdentifierNotUnique("net0".to_string()))
+        );
+
+        invalid_config.net_fds = Some(vec![RestoredNetConfig {
+            id: "net0".to_string(),
+            num_fds: 4,
+            fds: Some(vec![3, 4, 5, 6]),
+        }]);
+        assert_eq!(
+            invalid_config.validate(&snapshot_vm_config),
+            Err(ValidationError::RestoreMissingRequiredNetId(
+                "net1".to_string()
+            ))
+        );
+
+        invalid_config.net_fds = Some(vec![RestoredNetConfig {
+            id: "net0".to_string(),
+            num_fds: 2,
+            fds: Some(vec![3, 4]),
+        }]);
+        assert_eq!(
+            invalid_config.validate(&snapshot_vm_config),
+            Err(ValidationError::RestoreNetFdCountMismatch(
+                "net0".to_string(),
+                2,
+                4
+            ))
+        );
+
+        let another_valid_config = RestoreConfig {
+            source_url: PathBuf::from("/path/to/snapshot"),
+            prefault: false,
+            net_fds: None,
+        };
+        snapshot_vm_config.net = Some(vec![NetConfig {
+            id: Some("net2".to_owned()),
+            fds: None,
+            ..net_fixture()
+        }]);
+        assert!(another_valid_config.validate(&snapshot_vm_config).is_ok());
+    }
+
+    fn platform_fixture() -> PlatformConfig {
+        PlatformConfig {
+            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
+            iommu_segments: None,
+            serial_number: None,
+            uuid: None,
+            oem_strings: None,
+            #[cfg(feature = "tdx")]
+            tdx: false,
+            #[cfg(feature = "sev_snp")]
+            sev_snp: false,
+        }
+    }
+
+    fn numa_fixture() -> NumaConfig {
+        NumaConfig {
+            guest_numa_id: 0,
+            cpus: None,
+            distances: None,
+            memory_zones: None,
+            #[cfg(target_arch = "x86_64")]
+            sgx_epc_sections: None,
+            pci_segments: None,
+        }
+    }
+
+    #[test]
+    fn test_config_validation() {
+        let mut valid_config = VmConfig {
+            cpus: CpusConfig {
+                boot_vcpus: 1,
+                max_vcpus: 1,
+                ..Default::default()
+            },
+            memory: MemoryConfig {
+                size: 536_870_912,
+                mergeable: false,
+                hotplug_method: HotplugMethod::Acpi,
+                hotplug_size: None,
+                hotplugged_size: None,
+                shared: false,
+                hugepages: false,
+                hugepage_size: None,
+                prefault: false,
+                zones: None,
+                thp: true,
+            },
+            payload: Some(PayloadConfig {
+                kernel: Some(PathBuf::from("/path/to/kernel")),
+                firmware: None,
+                cmdline: None,
+                initramfs: None,
+                #[cfg(feature = "igvm")]
+                igvm: None,
+                #[cfg(feature = "sev_snp")]
+                host_data: Some(
+                    "243eb7dc1a21129caa91dcbb794922b933baecb5823a377eb431188673288c07".to_string(),
+                ),
+            }),
+            rate_limit_groups: None,
+            disks: None,
+            net: None,
+            rng: RngConfig {
+                src: PathBuf::from("/dev/urandom"),
+                iommu: false,
+            },
+            balloon: None,
+            fs: None,
+            pmem: None,
+            serial: ConsoleConfig {
+                file: None,
+                mode: ConsoleOutputMode::Null,
+                iommu: false,
+                socket: None,
+            },
+            console: ConsoleConfig {
+                file: None,
+                mode: ConsoleOutputMode::Tty,
+                iommu: false,
+                socket: None,
+            },
+            #[cfg(target_arch = "x86_64")]
+            debug_console: DebugConsoleConfig::default(),
+            devices: None,
+            user_devices: None,
+            vdpa: None,
+            vsock: None,
+            pvpanic: false,
+            iommu: false,
+            #[cfg(target_arch = "x86_64")]
+            sgx_epc: None,
+            numa: None,
+            watchdog: false,
+            #[cfg(feature = "guest_debug")]
+            gdb: false,
+            pci_segments: None,
+            platform: None,
+            tpm: None,
+            preserved_fds: None,
+        };
+
+        assert!(valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.serial.mode = ConsoleOutputMode::Tty;
+        invalid_config.console.mode = ConsoleOutputMode::Tty;
+        assert!(valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.payload = None;
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::KernelMissing)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.serial.mode = ConsoleOutputMode::File;
+        invalid_config.serial.file = None;
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::ConsoleFileMissing)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.cpus.max_vcpus = 16;
+        invalid_config.cpus.boot_vcpus = 32;
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::CpusMaxLowerThanBoot)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.cpus.max_vcpus = 16;
+        invalid_config.cpus.boot_vcpus = 16;
+        invalid_config.cpus.topology = Some(CpuTopology {
+            threads_per_core: 2,
+            cores_per_die: 8,
+            dies_per_package: 1,
+            packages: 2,
+        });
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::CpuTopologyCount)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            path: Some(PathBuf::from("/path/to/image")),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::DiskSocketAndPath)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            luks_passphrase_file: Some(PathBuf::from("/path/to/passphrase")),
+            luks_key_file: Some(PathBuf::from("/path/to/key")),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(if cfg!(feature = "luks") {
+                ValidationError::LuksPassphraseAndKey
+            } else {
+                ValidationError::LuksUnsupported
+            })
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            flush_window: Some(0),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidFlushWindow)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            idle_flush: Some(0),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidIdleFlush)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            idle_flush: Some(1000),
+            cache: CacheMode::Writethrough,
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::IdleFlushCacheMode)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: None,
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            idle_flush: Some(1000),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::IdleFlushVhostUser)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            request_timeout: Some(0),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidRequestTimeout)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: None,
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            request_timeout: Some(30000),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::RequestTimeoutVhostUser)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            request_timeout_policy: TimeoutPolicy::Cancel,
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::RequestTimeoutPolicyWithoutTimeout)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            scrub_manifest: Some(PathBuf::from("/path/to/manifest")),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::ScrubManifestWithoutScrub)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            tmpfile: Some(PathBuf::from("/tmp")),
+            size: Some(1 << 30),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::DiskPathAndTmpfile)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: None,
+            tmpfile: Some(PathBuf::from("/tmp")),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::TmpfileMissingSize)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            max_merge_size: Some(1000),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidMaxMergeSize(1000))
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            max_inflight: Some(0),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidMaxInflight)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            fixed_buffers: true,
+            disable_io_uring: true,
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::FixedBuffersWithoutIoUring)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            import_compression: ImportCompression::Gzip,
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::ImportCompressionWithoutSource)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: None,
+            import_source: Some(PathBuf::from("/path/to_fifo")),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::ImportMissingPath)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            logical_block_size: Some(1000),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidLogicalBlockSize(1000))
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            physical_block_size: Some(256),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidPhysicalBlockSize(256))
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            logical_block_size: Some(4096),
+            physical_block_size: Some(512),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::PhysicalBlockSizeMismatch(512, 4096))
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: None,
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            fadvise: FadviseMode::Dontneed,
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::PageCacheHintsVhostUser)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            readahead: Some(0),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidReadahead)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            fadvise: FadviseMode::Dontneed,
+            readahead: Some(1 << 20),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::ReadaheadWithDontneed)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: None,
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            io_retries: 8,
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::IoRetriesVhostUser)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: None,
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            completion_batch: 16,
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::CompletionBatchVhostUser)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: None,
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            backend: AsyncIoBackend::Sync,
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::BackendVhostUser)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: None,
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            image_type: Some(ImageType::Raw),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::ImageTypeVhostUser)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: None,
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            enospc: EnospcPolicy::Pause,
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::EnospcPolicyVhostUser)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: None,
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            host_numa_node: Some(0),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::HostNumaNodeVhostUser)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            sqpoll_cpu: Some(0),
+            backend: AsyncIoBackend::Sync,
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::SqpollWithoutIoUring)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: None,
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            dax: true,
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::DaxVhostUser)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            readonly: true,
+            dax: true,
+            ..disk_fixture()
+        }]);
+        assert_eq!(invalid_config.validate(), Err(ValidationError::DaxReadonly));
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: None,
+            tmpfile: Some(PathBuf::from("/tmp")),
+            size: Some(1 << 30),
+            preallocate: PreallocationMode::Full,
+            discard_on_start: true,
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::DiscardOnStartPreallocated)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            readonly: true,
+            discard_on_start: true,
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::DiscardOnStartReadonly)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: None,
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            discard_on_start: true,
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::DiscardOnStartVhostUser)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: None,
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            ordered_completions: true,
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::OrderedCompletionsVhostUser)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: None,
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            discard_zeroes: Some(true),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::DiscardZeroesVhostUser)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: None,
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            removable: true,
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::RemovableVhostUser)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            dax: true,
+            size: Some(1 << 30),
+            removable: true,
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::RemovableDax)
+        );
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.disks = Some(vec![DiskConfig {
+            discard_on_start: true,
+            ..disk_fixture()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.disks = Some(vec![DiskConfig {
+            dax: true,
+            size: Some(1 << 30),
+            ..disk_fixture()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
 
         let mut invalid_config = valid_config.clone();
-        invalid_config.net_fds = Some(vec![RestoredNetConfig {
-            id: "netx".to_string(),
-            num_fds: 4,
-            fds: Some(vec![3, 4, 5, 6]),
+        invalid_config.disks = Some(vec![DiskConfig {
+            import_source: Some(PathBuf::from("/path/to/image.raw")),
+            image_type: Some(ImageType::Qcow2),
+            ..disk_fixture()
         }]);
         assert_eq!(
-            invalid_config.validate(&snapshot_vm_config),
-            Err(ValidationError::RestoreMissingRequiredNetId(
-                "net0".to_string()
-            ))
+            invalid_config.validate(),
+            Err(ValidationError::NonRawImageType)
         );
 
-        invalid_config.net_fds = Some(vec![
-            RestoredNetConfig {
-                id: "net0".to_string(),
-                num_fds: 4,
-                fds: Some(vec![3, 4, 5, 6]),
-            },
-            RestoredNetConfig {
-                id: "net0".to_string(),
-                num_fds: 4,
-                fds: Some(vec![3, 4, 5, 6]),
-            },
-        ]);
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            fd: Some(3),
+            ..disk_fixture()
+        }]);
         assert_eq!(
-            invalid_config.validate(&snapshot_vm_config),
-            Err(ValidationError::IdentifierNotUnique("net0".to_string()))
+            invalid_config.validate(),
+            Err(ValidationError::DiskFdAndPath)
         );
 
-        invalid_config.net_fds = Some(vec![RestoredNetConfig {
-            id: "net0".to_string(),
-            num_fds: 4,
-            fds: Some(vec![3, 4, 5, 6]),
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: None,
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            fd: Some(3),
+            ..disk_fixture()
         }]);
         assert_eq!(
-            invalid_config.validate(&snapshot_vm_config),
-            Err(ValidationError::RestoreMissingRequiredNetId(
-                "net1".to_string()
-            ))
+            invalid_config.validate(),
+            Err(ValidationError::DiskFdVhostUser)
         );
 
-        invalid_config.net_fds = Some(vec![RestoredNetConfig {
-            id: "net0".to_string(),
-            num_fds: 2,
-            fds: Some(vec![3, 4]),
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: None,
+            fd: Some(2),
+            ..disk_fixture()
         }]);
         assert_eq!(
-            invalid_config.validate(&snapshot_vm_config),
-            Err(ValidationError::RestoreNetFdCountMismatch(
-                "net0".to_string(),
-                2,
-                4
-            ))
+            invalid_config.validate(),
+            Err(ValidationError::DiskReservedFd)
         );
 
-        let another_valid_config = RestoreConfig {
-            source_url: PathBuf::from("/path/to/snapshot"),
-            prefault: false,
-            net_fds: None,
-        };
-        snapshot_vm_config.net = Some(vec![NetConfig {
-            id: Some("net2".to_owned()),
-            fds: None,
-            ..net_fixture()
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            fixed_buffers: true,
+            backend: AsyncIoBackend::Aio,
+            ..disk_fixture()
         }]);
-        assert!(another_valid_config.validate(&snapshot_vm_config).is_ok());
-    }
-
-    fn platform_fixture() -> PlatformConfig {
-        PlatformConfig {
-            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
-            iommu_segments: None,
-            serial_number: None,
-            uuid: None,
-            oem_strings: None,
-            #[cfg(feature = "tdx")]
-            tdx: false,
-            #[cfg(feature = "sev_snp")]
-            sev_snp: false,
-        }
-    }
-
-    fn numa_fixture() -> NumaConfig {
-        NumaConfig {
-            guest_numa_id: 0,
-            cpus: None,
-            distances: None,
-            memory_zones: None,
-            #[cfg(target_arch = "x86_64")]
-            sgx_epc_sections: None,
-            pci_segments: None,
-        }
-    }
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::FixedBuffersWithoutIoUring)
+        );
 
-    #[test]
-    fn test_config_validation() {
-        let mut valid_config = VmConfig {
-            cpus: CpusConfig {
-                boot_vcpus: 1,
-                max_vcpus: 1,
-                ..Default::default()
-            },
-            memory: MemoryConfig {
-                size: 536_870_912,
-                mergeable: false,
-                hotplug_method: HotplugMethod::Acpi,
-                hotplug_size: None,
-                hotplugged_size: None,
-                shared: false,
-                hugepages: false,
-                hugepage_size: None,
-                prefault: false,
-                zones: None,
-                thp: true,
-            },
-            payload: Some(PayloadConfig {
-                kernel: Some(PathBuf::from("/path/to/kernel")),
-                firmware: None,
-                cmdline: None,
-                initramfs: None,
-                #[cfg(feature = "igvm")]
-                igvm: None,
-                #[cfg(feature = "sev_snp")]
-                host_data: Some(
-                    "243eb7dc1a21129caa91dcbb794922b933baecb5823a377eb431188673288c07".to_string(),
-                ),
+        let write_rate_limiter_config = Some(RateLimiterConfig {
+            bandwidth: Some(TokenBucketConfig {
+                size: 1 << 20,
+                one_time_burst: None,
+                refill_time: 1000,
             }),
-            rate_limit_groups: None,
-            disks: None,
-            net: None,
-            rng: RngConfig {
-                src: PathBuf::from("/dev/urandom"),
-                iommu: false,
-            },
-            balloon: None,
-            fs: None,
-            pmem: None,
-            serial: ConsoleConfig {
-                file: None,
-                mode: ConsoleOutputMode::Null,
-                iommu: false,
-                socket: None,
-            },
-            console: ConsoleConfig {
-                file: None,
-                mode: ConsoleOutputMode::Tty,
-                iommu: false,
-                socket: None,
-            },
-            #[cfg(target_arch = "x86_64")]
-            debug_console: DebugConsoleConfig::default(),
-            devices: None,
-            user_devices: None,
-            vdpa: None,
-            vsock: None,
-            pvpanic: false,
-            iommu: false,
-            #[cfg(target_arch = "x86_64")]
-            sgx_epc: None,
-            numa: None,
-            watchdog: false,
-            #[cfg(feature = "guest_debug")]
-            gdb: false,
-            pci_segments: None,
-            platform: None,
-            tpm: None,
-            preserved_fds: None,
-        };
-
-        assert!(valid_config.validate().is_ok());
-
-        let mut invalid_config = valid_config.clone();
-        invalid_config.serial.mode = ConsoleOutputMode::Tty;
-        invalid_config.console.mode = ConsoleOutputMode::Tty;
-        assert!(valid_config.validate().is_ok());
-
+            ops: None,
+        });
         let mut invalid_config = valid_config.clone();
-        invalid_config.payload = None;
+        invalid_config.memory.shared = true;
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: None,
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            io_cgroup: Some(PathBuf::from("/sys/fs/cgroup/vm")),
+            write_rate_limiter_config,
+            ..disk_fixture()
+        }]);
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::KernelMissing)
+            Err(ValidationError::IoCgroupVhostUser)
         );
 
         let mut invalid_config = valid_config.clone();
-        invalid_config.serial.mode = ConsoleOutputMode::File;
-        invalid_config.serial.file = None;
+        invalid_config.disks = Some(vec![DiskConfig {
+            io_cgroup: Some(PathBuf::from("/sys/fs/cgroup/vm")),
+            ..disk_fixture()
+        }]);
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::ConsoleFileMissing)
+            Err(ValidationError::IoCgroupWithoutLimits)
         );
 
         let mut invalid_config = valid_config.clone();
-        invalid_config.cpus.max_vcpus = 16;
-        invalid_config.cpus.boot_vcpus = 32;
+        invalid_config.disks = Some(vec![
+            DiskConfig {
+                io_cgroup: Some(PathBuf::from("/sys/fs/cgroup/vm")),
+                write_rate_limiter_config,
+                ..disk_fixture()
+            },
+            DiskConfig {
+                io_cgroup: Some(PathBuf::from("/sys/fs/cgroup/other")),
+                write_rate_limiter_config,
+                ..disk_fixture()
+            },
+        ]);
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::CpusMaxLowerThanBoot)
+            Err(ValidationError::IoCgroupMismatch)
         );
 
         let mut invalid_config = valid_config.clone();
-        invalid_config.cpus.max_vcpus = 16;
-        invalid_config.cpus.boot_vcpus = 16;
-        invalid_config.cpus.topology = Some(CpuTopology {
-            threads_per_core: 2,
-            cores_per_die: 8,
-            dies_per_package: 1,
-            packages: 2,
-        });
+        invalid_config.disks = Some(vec![DiskConfig {
+            fault_injection: Some(FaultInjectionConfig {
+                rate: 101,
+                ..Default::default()
+            }),
+            ..disk_fixture()
+        }]);
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::CpuTopologyCount)
+            Err(ValidationError::InvalidFaultRate(101))
         );
 
         let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
         invalid_config.disks = Some(vec![DiskConfig {
+            path: None,
+            vhost_user: true,
             vhost_socket: Some("/path/to/sock".to_owned()),
-            path: Some(PathBuf::from("/path/to/image")),
+            fault_injection: Some(FaultInjectionConfig {
+                rate: 1,
+                ..Default::default()
+            }),
             ..disk_fixture()
         }]);
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::DiskSocketAndPath)
+            Err(ValidationError::FaultInjectionVhostUser)
         );
 
         let mut invalid_config = valid_config.clone();
@@ -4057,6 +5883,27 @@ mod tests {
         still_valid_config.memory.shared = true;
         assert!(still_valid_config.validate().is_ok());
 
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            vhost_auth_key: Some(PathBuf::from("/path/to/key")),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::VhostAuthKeyWithoutVhostUser)
+        );
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.disks = Some(vec![DiskConfig {
+            path: None,
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            vhost_auth_key: Some(PathBuf::from("/path/to/key")),
+            ..disk_fixture()
+        }]);
+        still_valid_config.memory.shared = true;
+        assert!(still_valid_config.validate().is_ok());
+
         let mut invalid_config = valid_config.clone();
         invalid_config.net = Some(vec![NetConfig {
             vhost_user: true,
@@ -11,7 +11,7 @@
 
 use crate::config::{
     ConsoleOutputMode, DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, UserDeviceConfig,
-    VdpaConfig, VhostMode, VmConfig, VsockConfig,
+    VdpaConfig, VhostMode, VirtQueueAffinity, VmConfig, VsockConfig,
 };
 use crate::console_devices::{ConsoleDeviceError, ConsoleInfo};
 use crate::cpu::{CpuManager, CPU_MANAGER_ACPI_SIZE};
@@ -23,8 +23,8 @@ use crate::pci_segment::PciSegment;
 use crate::serial_manager::{Error as SerialManagerError, SerialManager};
 use crate::vm_config::DEFAULT_PCI_SEGMENT_APERTURE_WEIGHT;
 use crate::GuestRegionMmap;
-use crate::PciDeviceInfo;
 use crate::{device_node, DEVICE_MANAGER_SNAPSHOT_ID};
+use crate::{DiskInfo, DiskRateLimits, PciDeviceInfo};
 use acpi_tables::sdt::GenericAddress;
 use acpi_tables::{aml, Aml};
 use anyhow::anyhow;
@@ -35,9 +35,19 @@ use arch::NumaNodes;
 #[cfg(target_arch = "aarch64")]
 use arch::{DeviceType, MmioDeviceInfo};
 use block::{
-    async_io::DiskFile, block_aio_is_supported, block_io_uring_is_supported, detect_image_type,
-    fixed_vhd_sync::FixedVhdDiskSync, qcow, qcow_sync::QcowDiskSync, raw_async_aio::RawFileDiskAio,
-    raw_sync::RawFileDiskSync, vhdx, vhdx_sync::VhdxDiskSync, ImageType,
+    async_io::DiskFile, async_io::DiskFileError, block_aio_is_supported,
+    block_io_uring_is_supported, dax_disk::DaxDisk, detect_image_type, dirty::DirtyTrackingDisk,
+    fault_injection::FaultInjectingDisk, fault_injection::FaultPlan,
+    fixed_vhd_sync::FixedVhdDiskSync, latency::LatencyCollector, ordered::OrderedDisk, qcow,
+    qcow_sync::QcowDiskSync, qed, qed_sync::QedDiskSync, raw_async_aio::RawFileDiskAio,
+    raw_sync::RawFileDiskSync, readahead::FadviseMode, scrubber, scrubber::ScrubManifest, vhdx,
+    vhdx_sync::VhdxDiskSync, AsyncIoBackend, CacheMode, ImageType,
+};
+#[cfg(feature = "luks")]
+use block::{
+    encrypted_disk::EncryptedDiskFile,
+    luks,
+    luks::{LuksKey, LuksVolume},
 };
 #[cfg(feature = "io_uring")]
 use block::{fixed_vhd_async::FixedVhdDiskAsync, raw_async::RawFileDisk};
@@ -71,10 +81,11 @@ use std::num::Wrapping;
 use std::os::fd::RawFd;
 use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::{AsRawFd, FromRawFd};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::result;
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::thread;
+use std::time::{Duration, Instant};
 use tracer::trace_scoped;
 use vfio_ioctls::{VfioContainer, VfioDevice, VfioDeviceFd};
 use virtio_devices::transport::VirtioTransport;
@@ -83,7 +94,9 @@ use virtio_devices::vhost_user::VhostUserConfig;
 use virtio_devices::{
     AccessPlatformMapping, ActivateError, VdpaDmaMapping, VirtioMemMappingSource,
 };
-use virtio_devices::{Endpoint, IommuMapping};
+use virtio_devices::{
+    BlockRateLimiter, Endpoint, IommuMapping, RateLimiterConfig, TokenBucketConfig,
+};
 use vm_allocator::{AddressAllocator, SystemAllocator};
 use vm_device::dma_mapping::ExternalDmaMapping;
 use vm_device::interrupt::{
@@ -92,7 +105,7 @@ use vm_device::interrupt::{
 use vm_device::{Bus, BusDevice, Resource};
 use vm_memory::guest_memory::FileOffset;
 use vm_memory::GuestMemoryRegion;
-use vm_memory::{Address, GuestAddress, GuestUsize, MmapRegion};
+use vm_memory::{bitmap::AtomicBitmap, Address, GuestAddress, GuestUsize, MmapRegion};
 #[cfg(target_arch = "x86_64")]
 use vm_memory::{GuestAddressSpace, GuestMemory};
 use vm_migration::{
@@ -144,6 +157,9 @@ pub enum DeviceManagerError {
     /// Cannot open disk path
     Disk(io::Error),
 
+    /// The disk file descriptor can't be used
+    DiskFd(io::Error),
+
     /// Cannot create vhost-user-net device
     CreateVhostUserNet(virtio_devices::vhost_user::Error),
 
@@ -437,9 +453,107 @@ pub enum DeviceManagerError {
     /// Failed to create QcowDiskSync
     CreateQcowDiskSync(qcow::Error),
 
+    /// Failed to create RawFileDiskSync
+    CreateRawFileDiskSync(DiskFileError),
+
+    /// Failed to create QedDiskSync
+    CreateQedDiskSync(qed::Error),
+
     /// Failed to create FixedVhdxDiskSync
     CreateFixedVhdxDiskSync(vhdx::VhdxError),
 
+    /// Cache mode not supported by the disk image format
+    UnsupportedCacheMode(CacheMode),
+
+    /// Page cache hints are only supported with RAW images
+    UnsupportedPageCacheHints,
+
+    /// I/O retries are only supported with RAW images
+    UnsupportedIoRetries,
+
+    /// Completion batching is only supported with RAW images
+    UnsupportedCompletionBatch,
+
+    /// Submission queue polling is only supported with RAW images served
+    /// by io_uring
+    UnsupportedSqpoll,
+
+    /// Failed to pin the queue threads to a host NUMA node
+    SetHostNumaNode(virtio_devices::block::Error),
+
+    /// DAX disks are only supported with RAW images
+    UnsupportedDax,
+
+    /// Failed to map the disk as a DAX window
+    CreateDaxDisk(DiskFileError),
+
+    /// Only RAW images can be discarded on start
+    UnsupportedDiscardOnStart,
+
+    /// Failed to discard the disk on start
+    DiscardDisk(io::Error),
+
+    /// Block size overrides are only supported with RAW images, and the
+    /// logical block size with qcow2 ones
+    UnsupportedBlockSizes,
+
+    /// The disk backend isn't supported by the host or the image
+    UnsupportedBackend(AsyncIoBackend),
+
+    /// Disk scrubbing is only supported with RAW images
+    UnsupportedScrub,
+
+    /// Failed to allocate the scratch disk
+    PreallocateTmpfile(io::Error),
+
+    /// Failed to open the stream the disk image is imported from
+    OpenImportSource(io::Error),
+
+    /// Failed to import the disk image
+    ImportDisk(block::import::Error),
+
+    /// Failed to load the disk scrubbing manifest
+    LoadScrubManifest(scrubber::Error),
+
+    /// Failed to start the disk scrubber
+    StartScrubber(io::Error),
+
+    /// Failed to flush some disks, given by id
+    FlushDisks(Vec<(String, virtio_devices::block::Error)>),
+
+    /// Failed to retry the writes paused out of space
+    ResumeDisk(virtio_devices::block::Error),
+
+    /// Failed to change the medium of a removable disk
+    ChangeMedium(virtio_devices::block::Error),
+
+    /// The medium of a removable disk is only supported as a RAW image
+    UnsupportedMediumImageType,
+
+    /// Failed to update the rate limits of a disk
+    UpdateRateLimits(virtio_devices::block::Error),
+
+    /// The rate limits of a disk are those of a group it shares, given by id
+    SharedRateLimitGroup(String),
+
+    /// Failed to read the LUKS passphrase or key file
+    #[cfg(feature = "luks")]
+    ReadLuksKey(io::Error),
+
+    /// Failed to read the key authenticating the vhost-user backend
+    ReadVhostAuthKey(io::Error),
+
+    /// Failed to unlock the LUKS volume
+    #[cfg(feature = "luks")]
+    OpenLuksVolume(luks::Error),
+
+    /// The disk, given by id, isn't LUKS encrypted
+    NotLuksDisk(String),
+
+    /// Failed to rotate the LUKS passphrase of a disk
+    #[cfg(feature = "luks")]
+    RotateLuksKey(luks::Error),
+
     /// Failed to add DMA mapping handler to virtio-mem device.
     AddDmaMappingHandlerVirtioMem(virtio_devices::mem::Error),
 
@@ -773,6 +887,28 @@ pub struct AcpiPlatformAddresses {
     pub sleep_status_reg_address: Option<GenericAddress>,
 }
 
+// Returns the format of the disk image, the one given in the configuration
+// winning over the detected one, so that a guest can't turn a RAW disk into
+// an image referring to host files by writing a header on it.
+fn disk_image_type(
+    disk_cfg: &DiskConfig,
+    file: &mut File,
+    disk_path: &Path,
+) -> DeviceManagerResult<ImageType> {
+    let detected = detect_image_type(file).map_err(DeviceManagerError::DetectImageType)?;
+    Ok(match disk_cfg.image_type {
+        Some(image_type) if image_type != detected => {
+            warn!(
+                "Disk {:?} is opened as a {:?} image while its content looks like a {:?} one",
+                disk_path, image_type, detected
+            );
+            image_type
+        }
+        Some(image_type) => image_type,
+        None => detected,
+    })
+}
+
 pub struct DeviceManager {
     // Manage address space related to devices
     address_manager: Arc<AddressManager>,
@@ -882,6 +1018,13 @@ pub struct DeviceManager {
     // Possible handle to the virtio-mem device
     virtio_mem_devices: Vec<Arc<Mutex<virtio_devices::Mem>>>,
 
+    // virtio-blk devices, by id
+    block_devices: Vec<(String, Arc<Mutex<virtio_devices::Block>>)>,
+
+    // LUKS volumes of the encrypted virtio-blk devices, by id
+    #[cfg(feature = "luks")]
+    luks_disks: HashMap<String, LuksDisk>,
+
     #[cfg(target_arch = "aarch64")]
     // GPIO device for AArch64
     gpio_device: Option<Arc<Mutex<devices::legacy::Gpio>>>,
@@ -1163,6 +1306,9 @@ impl DeviceManager {
             console_resize_pipe: None,
             original_termios_opt: Arc::new(Mutex::new(None)),
             virtio_mem_devices: Vec::new(),
+            block_devices: Vec::new(),
+            #[cfg(feature = "luks")]
+            luks_disks: HashMap::new(),
             #[cfg(target_arch = "aarch64")]
             gpio_device: None,
             pvpanic_device: None,
@@ -2218,6 +2364,69 @@ impl DeviceManager {
         supported
     }
 
+    // Create an anonymous RateLimiterGroup that is dropped when the device
+    // using it is dropped.
+    fn make_anonymous_rate_limit_group(
+        &self,
+        id: &str,
+        rate_limiter_cfg: &RateLimiterConfig,
+    ) -> DeviceManagerResult<Arc<RateLimiterGroup>> {
+        let bw = rate_limiter_cfg.bandwidth.unwrap_or_default();
+        let ops = rate_limiter_cfg.ops.unwrap_or_default();
+        let mut rate_limit_group = RateLimiterGroup::new(
+            id,
+            bw.size,
+            bw.one_time_burst.unwrap_or(0),
+            bw.refill_time,
+            ops.size,
+            ops.one_time_burst.unwrap_or(0),
+            ops.refill_time,
+        )
+        .map_err(DeviceManagerError::RateLimiterGroupCreate)?;
+
+        rate_limit_group
+            .start_thread(
+                self.exit_evt
+                    .try_clone()
+                    .map_err(DeviceManagerError::EventFd)?,
+            )
+            .unwrap();
+
+        Ok(Arc::new(rate_limit_group))
+    }
+
+    // Picks the backend of a disk among the ones supported by the host and
+    // the image, which an explicitly requested backend must be part of.
+    fn disk_backend(
+        &mut self,
+        disk_cfg: &DiskConfig,
+        image_type: &ImageType,
+        sync_backend: bool,
+    ) -> DeviceManagerResult<AsyncIoBackend> {
+        let io_uring = cfg!(feature = "io_uring")
+            && !sync_backend
+            && matches!(image_type, ImageType::FixedVhd | ImageType::Raw)
+            && self.io_uring_is_supported();
+        let aio = !sync_backend && matches!(image_type, ImageType::Raw) && self.aio_is_supported();
+
+        match disk_cfg.backend {
+            AsyncIoBackend::Auto => Ok(if io_uring && !disk_cfg.disable_io_uring {
+                AsyncIoBackend::IoUring
+            } else if aio && !disk_cfg.disable_aio {
+                AsyncIoBackend::Aio
+            } else {
+                AsyncIoBackend::Sync
+            }),
+            AsyncIoBackend::IoUring if !io_uring => Err(DeviceManagerError::UnsupportedBackend(
+                AsyncIoBackend::IoUring,
+            )),
+            AsyncIoBackend::Aio if !aio => {
+                Err(DeviceManagerError::UnsupportedBackend(AsyncIoBackend::Aio))
+            }
+            backend => Ok(backend),
+        }
+    }
+
     fn make_virtio_block_device(
         &mut self,
         disk_cfg: &mut DiskConfig,
@@ -2239,10 +2448,17 @@ impl DeviceManager {
                 num_queues: disk_cfg.num_queues,
                 queue_size: disk_cfg.queue_size,
             };
+            let auth_key = disk_cfg
+                .vhost_auth_key
+                .as_ref()
+                .map(std::fs::read)
+                .transpose()
+                .map_err(DeviceManagerError::ReadVhostAuthKey)?;
             let vhost_user_block = Arc::new(Mutex::new(
                 match virtio_devices::vhost_user::Blk::new(
                     id.clone(),
                     vu_cfg,
+                    auth_key,
                     self.seccomp_action.clone(),
                     self.exit_evt
                         .try_clone()
@@ -2266,34 +2482,244 @@ impl DeviceManager {
             let mut options = OpenOptions::new();
             options.read(true);
             options.write(!disk_cfg.readonly);
+            let mut flags = disk_cfg.cache.open_flags();
             if disk_cfg.direct {
-                options.custom_flags(libc::O_DIRECT);
+                flags |= libc::O_DIRECT;
+            }
+            if flags != 0 {
+                options.custom_flags(flags);
             }
-            // Open block device path
-            let mut file: File = options
-                .open(
-                    disk_cfg
-                        .path
+            // A scratch disk is an anonymous RAW file, only reachable through
+            // its file descriptor. It is reclaimed when the VMM exits, even if
+            // it crashes.
+            let (file, disk_path, image_type) = if let Some(dir) = &disk_cfg.tmpfile {
+                options.write(true).custom_flags(flags | libc::O_TMPFILE);
+                let file: File = options.open(dir).map_err(DeviceManagerError::Disk)?;
+                disk_cfg
+                    .preallocate
+                    .apply(&file, disk_cfg.size.unwrap_or_default())
+                    .map_err(DeviceManagerError::PreallocateTmpfile)?;
+                let disk_path = PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd()));
+                (file, disk_path, ImageType::Raw)
+            } else if let Some(fd) = disk_cfg.fd {
+                // The descriptor is duplicated for the disk, the original one
+                // staying with the configuration for the disk to be opened
+                // again on reboot.
+                let mut file = block::disk_file_from_fd(fd, flags, !disk_cfg.readonly)
+                    .map_err(DeviceManagerError::DiskFd)?;
+                // SAFETY: 'fd' is valid as it could be duplicated
+                unsafe {
+                    let mut config = self.config.lock().unwrap();
+                    if !config
+                        .preserved_fds
                         .as_ref()
-                        .ok_or(DeviceManagerError::NoDiskPath)?
-                        .clone(),
-                )
-                .map_err(DeviceManagerError::Disk)?;
-            let image_type =
-                detect_image_type(&mut file).map_err(DeviceManagerError::DetectImageType)?;
+                        .is_some_and(|fds| fds.contains(&fd))
+                    {
+                        config.add_preserved_fds(vec![fd]);
+                    }
+                }
+                let disk_path = PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd()));
+                let image_type = disk_image_type(disk_cfg, &mut file, &disk_path)?;
+                (file, disk_path, image_type)
+            } else {
+                // Open block device path
+                let disk_path = disk_cfg
+                    .path
+                    .as_ref()
+                    .ok_or(DeviceManagerError::NoDiskPath)?
+                    .clone();
+                // An interrupted import never leaves anything at the disk
+                // path, so an existing disk was completely imported already.
+                if let Some(source) = &disk_cfg.import_source {
+                    if disk_path.exists() {
+                        info!("Disk {:?} already imported", disk_path);
+                    } else {
+                        info!("Importing disk {:?} from {:?}", disk_path, source);
+                        let reader =
+                            File::open(source).map_err(DeviceManagerError::OpenImportSource)?;
+                        let size = block::import::import_image(
+                            reader,
+                            disk_cfg.import_compression,
+                            &disk_path,
+                            |bytes| info!("Imported {} MiB of disk {:?}", bytes >> 20, disk_path),
+                        )
+                        .map_err(DeviceManagerError::ImportDisk)?;
+                        info!("Imported disk {:?}: {} bytes", disk_path, size);
+                    }
+                }
+                let mut file: File = options.open(&disk_path).map_err(DeviceManagerError::Disk)?;
+                // The imported stream is always a RAW image.
+                let image_type = if disk_cfg.import_source.is_some() {
+                    ImageType::Raw
+                } else {
+                    disk_image_type(disk_cfg, &mut file, &disk_path)?
+                };
+                (file, disk_path, image_type)
+            };
+
+            // The disk is left as it was when restored, the guest having
+            // seen its content already.
+            let restoring = self
+                .snapshot
+                .as_ref()
+                .is_some_and(|s| s.snapshots.contains_key(&id));
+            if disk_cfg.discard_on_start && !restoring {
+                if !matches!(image_type, ImageType::Raw) {
+                    return Err(DeviceManagerError::UnsupportedDiscardOnStart);
+                }
+                info!("Discarding disk {:?}", disk_path);
+                block::preallocate::discard_all(&file).map_err(DeviceManagerError::DiscardDisk)?;
+            }
+
+            // A DAX disk is mapped into the guest as the memory of a
+            // virtio-pmem device, bypassing the queues of virtio-blk.
+            if disk_cfg.dax {
+                if !matches!(image_type, ImageType::Raw) {
+                    return Err(DeviceManagerError::UnsupportedDax);
+                }
+                let disk =
+                    DaxDisk::new(file, disk_cfg.size).map_err(DeviceManagerError::CreateDaxDisk)?;
+                info!(
+                    "Mapping disk {:?} as a DAX window of {} bytes",
+                    disk_path,
+                    disk.window_size()
+                );
+                let (region_base, _) =
+                    self.allocate_pmem_range(&id, disk.window_size(), disk_cfg.pci_segment)?;
+                let (file, mmap_region) = disk.into_parts();
+                return self.add_pmem_device(
+                    id,
+                    file,
+                    mmap_region,
+                    region_base,
+                    disk_cfg.iommu,
+                    disk_cfg.pci_segment,
+                    true,
+                );
+            }
+
+            // Only the synchronous RAW backend knows how to sync every write
+            // or to ignore the flushes.
+            let sync_cache_mode =
+                matches!(disk_cfg.cache, CacheMode::None | CacheMode::Writethrough);
+            if sync_cache_mode && !matches!(image_type, ImageType::Raw) {
+                return Err(DeviceManagerError::UnsupportedCacheMode(disk_cfg.cache));
+            }
+            // It is also the only one giving hints to the page cache.
+            let page_cache_hints =
+                disk_cfg.fadvise != FadviseMode::Normal || disk_cfg.readahead.is_some();
+            if page_cache_hints && !matches!(image_type, ImageType::Raw) {
+                return Err(DeviceManagerError::UnsupportedPageCacheHints);
+            }
+            // And to retry the requests failing transiently.
+            if disk_cfg.io_retries != 0 && !matches!(image_type, ImageType::Raw) {
+                return Err(DeviceManagerError::UnsupportedIoRetries);
+            }
+            // And to batch the completions it signals.
+            if disk_cfg.completion_batch != 0 && !matches!(image_type, ImageType::Raw) {
+                return Err(DeviceManagerError::UnsupportedCompletionBatch);
+            }
+            // And to report block sizes other than the ones of the host.
+            let block_sizes =
+                disk_cfg.logical_block_size.is_some() || disk_cfg.physical_block_size.is_some();
+            let block_sizes_supported = match image_type {
+                ImageType::Raw => true,
+                ImageType::Qcow2 => disk_cfg.physical_block_size.is_none(),
+                _ => false,
+            };
+            if block_sizes && !block_sizes_supported {
+                return Err(DeviceManagerError::UnsupportedBlockSizes);
+            }
+            let sync_backend = sync_cache_mode
+                || page_cache_hints
+                || disk_cfg.io_retries != 0
+                || disk_cfg.completion_batch != 0
+                || block_sizes
+                || disk_cfg.tmpfile.is_some()
+                || disk_cfg.import_source.is_some();
+
+            let backend = self.disk_backend(disk_cfg, &image_type, sync_backend)?;
+            if disk_cfg.sqpoll_cpu.is_some()
+                && (!matches!(image_type, ImageType::Raw) || backend != AsyncIoBackend::IoUring)
+            {
+                return Err(DeviceManagerError::UnsupportedSqpoll);
+            }
 
+            // The scrubber reads the image through its own handle, as the
+            // image takes ownership of the file.
+            let scrub_file = if disk_cfg.scrub {
+                if !matches!(image_type, ImageType::Raw) {
+                    return Err(DeviceManagerError::UnsupportedScrub);
+                }
+                Some(file.try_clone().map_err(DeviceManagerError::Disk)?)
+            } else {
+                None
+            };
+
+            // The host kernel throttles the disk when it can, the userspace
+            // rate limiters being used otherwise.
+            let cgroup_throttled = if let Some(cgroup) = &disk_cfg.io_cgroup {
+                let limits = io_max(
+                    disk_cfg.read_rate_limiter_config.as_ref(),
+                    disk_cfg.write_rate_limiter_config.as_ref(),
+                );
+                match block::cgroup::throttle(cgroup, &file, &limits) {
+                    Ok(()) => {
+                        info!("Disk {} throttled by cgroup {:?}: {:?}", id, cgroup, limits);
+                        true
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Disk {} can't be throttled by cgroup {:?}: {}",
+                            id, cgroup, e
+                        );
+                        false
+                    }
+                }
+            } else {
+                false
+            };
+
+            // The writes to a raw image on a dm-thin volume fail once its
+            // pool is out of data space, which is then told apart from the
+            // other failures.
+            let thin_pool = if matches!(image_type, ImageType::Raw) {
+                match block::dm_thin::ThinPool::probe(&file) {
+                    Ok(thin_pool) => {
+                        if let Some(thin_pool) = &thin_pool {
+                            let (major, minor) = thin_pool.device();
+                            info!("Disk {} is backed by thin pool {}:{}", id, major, minor);
+                        }
+                        thin_pool
+                    }
+                    Err(e) => {
+                        warn!("Disk {} can't be checked for a thin pool: {}", id, e);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+
+            // The LUKS header is read through its own handle, as the image
+            // takes ownership of the file.
+            #[cfg(feature = "luks")]
+            let luks_header =
+                if disk_cfg.luks_passphrase_file.is_some() || disk_cfg.luks_key_file.is_some() {
+                    Some(file.try_clone().map_err(DeviceManagerError::Disk)?)
+                } else {
+                    None
+                };
+
+            // Counted by the synchronous RAW backend reading ahead.
+            let mut readahead_stats = None;
             let image = match image_type {
                 ImageType::FixedVhd => {
-                    // Use asynchronous backend relying on io_uring if the
-                    // syscalls are supported.
-                    if cfg!(feature = "io_uring")
-                        && !disk_cfg.disable_io_uring
-                        && self.io_uring_is_supported()
-                    {
+                    if backend == AsyncIoBackend::IoUring {
                         info!("Using asynchronous fixed VHD disk file (io_uring)");
 
                         #[cfg(not(feature = "io_uring"))]
-                        unreachable!("Checked in if statement above");
+                        unreachable!("Checked when selecting the backend");
                         #[cfg(feature = "io_uring")]
                         {
                             Box::new(
@@ -2310,35 +2736,57 @@ impl DeviceManager {
                     }
                 }
                 ImageType::Raw => {
-                    // Use asynchronous backend relying on io_uring if the
-                    // syscalls are supported.
-                    if cfg!(feature = "io_uring")
-                        && !disk_cfg.disable_io_uring
-                        && self.io_uring_is_supported()
-                    {
+                    if backend == AsyncIoBackend::IoUring {
                         info!("Using asynchronous RAW disk file (io_uring)");
 
                         #[cfg(not(feature = "io_uring"))]
-                        unreachable!("Checked in if statement above");
+                        unreachable!("Checked when selecting the backend");
                         #[cfg(feature = "io_uring")]
                         {
-                            Box::new(RawFileDisk::new(file)) as Box<dyn DiskFile>
+                            let mut disk = RawFileDisk::new(file);
+                            if let Some(cpu) = disk_cfg.sqpoll_cpu {
+                                disk.set_sqpoll_cpu(cpu);
+                            }
+                            Box::new(disk) as Box<dyn DiskFile>
                         }
-                    } else if !disk_cfg.disable_aio && self.aio_is_supported() {
+                    } else if backend == AsyncIoBackend::Aio {
                         info!("Using asynchronous RAW disk file (aio)");
                         Box::new(RawFileDiskAio::new(file)) as Box<dyn DiskFile>
                     } else {
                         info!("Using synchronous RAW disk file");
-                        Box::new(RawFileDiskSync::new(file)) as Box<dyn DiskFile>
+                        let mut disk = RawFileDiskSync::new(
+                            file,
+                            disk_cfg.readonly,
+                            disk_cfg.cache,
+                            disk_cfg.logical_block_size,
+                        )
+                        .map_err(DeviceManagerError::CreateRawFileDiskSync)?;
+                        if page_cache_hints {
+                            disk.set_page_cache_hints(disk_cfg.fadvise, disk_cfg.readahead);
+                            if disk_cfg.readahead.is_some() {
+                                readahead_stats = Some(disk.readahead_stats());
+                            }
+                        }
+                        disk.set_io_retries(disk_cfg.io_retries);
+                        disk.set_completion_batch(disk_cfg.completion_batch);
+                        disk.set_physical_block_size(disk_cfg.physical_block_size);
+                        Box::new(disk) as Box<dyn DiskFile>
                     }
                 }
                 ImageType::Qcow2 => {
                     info!("Using synchronous QCOW disk file");
                     Box::new(
-                        QcowDiskSync::new(file, disk_cfg.direct)
+                        QcowDiskSync::new(file, disk_cfg.direct, disk_cfg.logical_block_size)
                             .map_err(DeviceManagerError::CreateQcowDiskSync)?,
                     ) as Box<dyn DiskFile>
                 }
+                ImageType::Qed => {
+                    info!("Using synchronous QED disk file");
+                    Box::new(
+                        QedDiskSync::new(file, disk_cfg.direct)
+                            .map_err(DeviceManagerError::CreateQedDiskSync)?,
+                    ) as Box<dyn DiskFile>
+                }
                 ImageType::Vhdx => {
                     info!("Using synchronous VHDX disk file");
                     Box::new(
@@ -2348,38 +2796,102 @@ impl DeviceManager {
                 }
             };
 
+            #[cfg(feature = "luks")]
+            let mut luks_disk = None;
+            #[cfg(feature = "luks")]
+            let image = if let Some(header) = luks_header {
+                info!("Using LUKS encrypted disk");
+                let image = EncryptedDiskFile::new(image, &header, &luks_key(disk_cfg)?)
+                    .map_err(DeviceManagerError::OpenLuksVolume)?;
+                luks_disk = Some(LuksDisk {
+                    header,
+                    volume: image.volume(),
+                    rotations: Wrapping(0),
+                    rotation_failures: Wrapping(0),
+                });
+                Box::new(image) as Box<dyn DiskFile>
+            } else {
+                image
+            };
+
+            let image = if let Some(fault_injection) = &disk_cfg.fault_injection {
+                warn!("Injecting faults into disk {}: {:?}", id, fault_injection);
+                Box::new(FaultInjectingDisk::new(
+                    image,
+                    FaultPlan {
+                        rate: fault_injection.rate,
+                        offsets: fault_injection.offsets.clone(),
+                        after: fault_injection.after,
+                        latency: Duration::from_millis(fault_injection.latency),
+                    },
+                )) as Box<dyn DiskFile>
+            } else {
+                image
+            };
+
+            let image = if disk_cfg.ordered_completions {
+                warn!(
+                    "Completing the requests of disk {} in order, at the cost of performance",
+                    id
+                );
+                Box::new(OrderedDisk::new(image)) as Box<dyn DiskFile>
+            } else {
+                image
+            };
+
+            // Let the blocks written by the guest be tracked while the disk
+            // content is copied.
+            let mut image = Box::new(DirtyTrackingDisk::new(image)) as Box<dyn DiskFile>;
+
+            // virtio-blk has no way to advertise it, so the guest must be
+            // told through its own configuration.
+            let rotational = disk_cfg.rotational.unwrap_or_else(|| image.rotational());
+            info!(
+                "Disk {} is {}",
+                id,
+                if rotational {
+                    "rotational"
+                } else {
+                    "non-rotational"
+                }
+            );
+
             let rate_limit_group =
                 if let Some(rate_limiter_cfg) = disk_cfg.rate_limiter_config.as_ref() {
-                    // Create an anonymous RateLimiterGroup that is dropped when the Disk
-                    // is dropped.
-                    let bw = rate_limiter_cfg.bandwidth.unwrap_or_default();
-                    let ops = rate_limiter_cfg.ops.unwrap_or_default();
-                    let mut rate_limit_group = RateLimiterGroup::new(
+                    Some(self.make_anonymous_rate_limit_group(
                         disk_cfg.id.as_ref().unwrap(),
-                        bw.size,
-                        bw.one_time_burst.unwrap_or(0),
-                        bw.refill_time,
-                        ops.size,
-                        ops.one_time_burst.unwrap_or(0),
-                        ops.refill_time,
-                    )
-                    .map_err(DeviceManagerError::RateLimiterGroupCreate)?;
-
-                    rate_limit_group
-                        .start_thread(
-                            self.exit_evt
-                                .try_clone()
-                                .map_err(DeviceManagerError::EventFd)?,
-                        )
-                        .unwrap();
-
-                    Some(Arc::new(rate_limit_group))
+                        rate_limiter_cfg,
+                    )?)
                 } else if let Some(rate_limit_group) = disk_cfg.rate_limit_group.as_ref() {
                     self.rate_limit_groups.get(rate_limit_group).cloned()
                 } else {
                     None
                 };
 
+            let read_rate_limit_group = disk_cfg
+                .read_rate_limiter_config
+                .as_ref()
+                .filter(|_| !cgroup_throttled)
+                .map(|cfg| {
+                    self.make_anonymous_rate_limit_group(
+                        &format!("{}_read", disk_cfg.id.as_ref().unwrap()),
+                        cfg,
+                    )
+                })
+                .transpose()?;
+
+            let write_rate_limit_group = disk_cfg
+                .write_rate_limiter_config
+                .as_ref()
+                .filter(|_| !cgroup_throttled)
+                .map(|cfg| {
+                    self.make_anonymous_rate_limit_group(
+                        &format!("{}_write", disk_cfg.id.as_ref().unwrap()),
+                        cfg,
+                    )
+                })
+                .transpose()?;
+
             let queue_affinity = if let Some(queue_affinity) = disk_cfg.queue_affinity.as_ref() {
                 queue_affinity
                     .iter()
@@ -2389,31 +2901,82 @@ impl DeviceManager {
                 BTreeMap::new()
             };
 
-            let virtio_block = Arc::new(Mutex::new(
-                virtio_devices::Block::new(
-                    id.clone(),
-                    image,
-                    disk_cfg
-                        .path
-                        .as_ref()
-                        .ok_or(DeviceManagerError::NoDiskPath)?
-                        .clone(),
-                    disk_cfg.readonly,
-                    self.force_iommu | disk_cfg.iommu,
-                    disk_cfg.num_queues,
-                    disk_cfg.queue_size,
-                    disk_cfg.serial.clone(),
-                    self.seccomp_action.clone(),
-                    rate_limit_group,
-                    self.exit_evt
-                        .try_clone()
-                        .map_err(DeviceManagerError::EventFd)?,
-                    state_from_id(self.snapshot.as_ref(), id.as_str())
-                        .map_err(DeviceManagerError::RestoreGetState)?,
-                    queue_affinity,
-                )
-                .map_err(DeviceManagerError::CreateVirtioBlock)?,
-            ));
+            let mut virtio_block = virtio_devices::Block::new(
+                id.clone(),
+                image,
+                disk_path,
+                disk_cfg.readonly,
+                self.force_iommu | disk_cfg.iommu,
+                disk_cfg.num_queues,
+                disk_cfg.queue_size,
+                disk_cfg.serial.clone(),
+                self.seccomp_action.clone(),
+                rate_limit_group,
+                read_rate_limit_group,
+                write_rate_limit_group,
+                self.exit_evt
+                    .try_clone()
+                    .map_err(DeviceManagerError::EventFd)?,
+                state_from_id(self.snapshot.as_ref(), id.as_str())
+                    .map_err(DeviceManagerError::RestoreGetState)?,
+                queue_affinity,
+            )
+            .map_err(DeviceManagerError::CreateVirtioBlock)?;
+            if disk_cfg.latency_histograms {
+                virtio_block.set_latency_collector(LatencyCollector::new());
+            }
+            if let Some(readahead_stats) = readahead_stats {
+                virtio_block.set_readahead_stats(readahead_stats);
+            }
+            if let Some(flush_window) = disk_cfg.flush_window {
+                virtio_block.set_flush_window(Duration::from_millis(flush_window));
+            }
+            if let Some(idle_flush) = disk_cfg.idle_flush {
+                virtio_block.set_idle_flush_interval(Duration::from_millis(idle_flush));
+            }
+            if let Some(request_timeout) = disk_cfg.request_timeout {
+                virtio_block.set_request_timeout(
+                    Duration::from_millis(request_timeout),
+                    disk_cfg.request_timeout_policy,
+                );
+            }
+            if let Some(max_merge_size) = disk_cfg.max_merge_size {
+                virtio_block.set_max_merge_size(max_merge_size);
+            }
+            if let Some(max_inflight) = disk_cfg.max_inflight {
+                virtio_block.set_max_inflight(max_inflight);
+            }
+            virtio_block.set_fixed_buffers(disk_cfg.fixed_buffers);
+            virtio_block.set_enospc_policy(disk_cfg.enospc);
+            if let Some(discard_zeroes) = disk_cfg.discard_zeroes {
+                virtio_block.set_discard_zeroes(discard_zeroes);
+            }
+            virtio_block.set_cache_mode(disk_cfg.cache);
+            virtio_block.set_removable(disk_cfg.removable);
+            virtio_block.set_thin_pool(thin_pool);
+            if let Some(node) = disk_cfg.host_numa_node {
+                virtio_block
+                    .set_host_numa_node(node)
+                    .map_err(DeviceManagerError::SetHostNumaNode)?;
+            }
+            if let Some(file) = scrub_file {
+                let manifest = disk_cfg
+                    .scrub_manifest
+                    .as_deref()
+                    .map(ScrubManifest::load)
+                    .transpose()
+                    .map_err(DeviceManagerError::LoadScrubManifest)?;
+                virtio_block
+                    .start_scrubber(file, manifest)
+                    .map_err(DeviceManagerError::StartScrubber)?;
+            }
+            let virtio_block = Arc::new(Mutex::new(virtio_block));
+            self.block_devices
+                .push((id.clone(), Arc::clone(&virtio_block)));
+            #[cfg(feature = "luks")]
+            if let Some(luks_disk) = luks_disk {
+                self.luks_disks.insert(id.clone(), luks_disk);
+            }
 
             (
                 Arc::clone(&virtio_block) as Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
@@ -2748,38 +3311,6 @@ impl DeviceManager {
 
         info!("Creating virtio-pmem device: {:?}", pmem_cfg);
 
-        let mut node = device_node!(id);
-
-        // Look for the id in the device tree. If it can be found, that means
-        // the device is being restored, otherwise it's created from scratch.
-        let region_range = if let Some(node) = self.device_tree.lock().unwrap().get(&id) {
-            info!("Restoring virtio-pmem {} resources", id);
-
-            let mut region_range: Option<(u64, u64)> = None;
-            for resource in node.resources.iter() {
-                match resource {
-                    Resource::MmioAddressRange { base, size } => {
-                        if region_range.is_some() {
-                            return Err(DeviceManagerError::ResourceAlreadyExists);
-                        }
-
-                        region_range = Some((*base, *size));
-                    }
-                    _ => {
-                        error!("Unexpected resource {:?} for {}", resource, id);
-                    }
-                }
-            }
-
-            if region_range.is_none() {
-                return Err(DeviceManagerError::MissingVirtioPmemResources);
-            }
-
-            region_range
-        } else {
-            None
-        };
-
         let (custom_flags, set_len) = if pmem_cfg.file.is_dir() {
             if pmem_cfg.size.is_none() {
                 return Err(DeviceManagerError::PmemWithDirectorySizeMissing);
@@ -2811,10 +3342,76 @@ impl DeviceManager {
             return Err(DeviceManagerError::PmemSizeNotAligned);
         }
 
+        let (region_base, region_size) =
+            self.allocate_pmem_range(&id, size, pmem_cfg.pci_segment)?;
+
+        let cloned_file = file.try_clone().map_err(DeviceManagerError::CloneFile)?;
+        let mmap_region = MmapRegion::build(
+            Some(FileOffset::new(cloned_file, 0)),
+            region_size as usize,
+            PROT_READ | PROT_WRITE,
+            MAP_NORESERVE
+                | if pmem_cfg.discard_writes {
+                    MAP_PRIVATE
+                } else {
+                    MAP_SHARED
+                },
+        )
+        .map_err(DeviceManagerError::NewMmapRegion)?;
+
+        self.add_pmem_device(
+            id,
+            file,
+            mmap_region,
+            region_base,
+            pmem_cfg.iommu,
+            pmem_cfg.pci_segment,
+            false,
+        )
+    }
+
+    // Allocates the guest range of the memory of a virtio-pmem device, the
+    // one it was given before if the device is being restored.
+    fn allocate_pmem_range(
+        &mut self,
+        id: &str,
+        size: u64,
+        pci_segment: u16,
+    ) -> DeviceManagerResult<(u64, u64)> {
+        // Look for the id in the device tree. If it can be found, that means
+        // the device is being restored, otherwise it's created from scratch.
+        let region_range = if let Some(node) = self.device_tree.lock().unwrap().get(id) {
+            info!("Restoring virtio-pmem {} resources", id);
+
+            let mut region_range: Option<(u64, u64)> = None;
+            for resource in node.resources.iter() {
+                match resource {
+                    Resource::MmioAddressRange { base, size } => {
+                        if region_range.is_some() {
+                            return Err(DeviceManagerError::ResourceAlreadyExists);
+                        }
+
+                        region_range = Some((*base, *size));
+                    }
+                    _ => {
+                        error!("Unexpected resource {:?} for {}", resource, id);
+                    }
+                }
+            }
+
+            if region_range.is_none() {
+                return Err(DeviceManagerError::MissingVirtioPmemResources);
+            }
+
+            region_range
+        } else {
+            None
+        };
+
         let (region_base, region_size) = if let Some((base, size)) = region_range {
             // The memory needs to be 2MiB aligned in order to support
             // hugepages.
-            self.pci_segments[pmem_cfg.pci_segment as usize]
+            self.pci_segments[pci_segment as usize]
                 .mem64_allocator
                 .lock()
                 .unwrap()
@@ -2829,7 +3426,7 @@ impl DeviceManager {
         } else {
             // The memory needs to be 2MiB aligned in order to support
             // hugepages.
-            let base = self.pci_segments[pmem_cfg.pci_segment as usize]
+            let base = self.pci_segments[pci_segment as usize]
                 .mem64_allocator
                 .lock()
                 .unwrap()
@@ -2839,19 +3436,26 @@ impl DeviceManager {
             (base.raw_value(), size)
         };
 
-        let cloned_file = file.try_clone().map_err(DeviceManagerError::CloneFile)?;
-        let mmap_region = MmapRegion::build(
-            Some(FileOffset::new(cloned_file, 0)),
-            region_size as usize,
-            PROT_READ | PROT_WRITE,
-            MAP_NORESERVE
-                | if pmem_cfg.discard_writes {
-                    MAP_PRIVATE
-                } else {
-                    MAP_SHARED
-                },
-        )
-        .map_err(DeviceManagerError::NewMmapRegion)?;
+        Ok((region_base, region_size))
+    }
+
+    // Maps `mmap_region`, holding the content of `file`, into the guest at
+    // `region_base`, as the memory of a new virtio-pmem device flushing it
+    // with msync() if `msync` is set.
+    #[allow(clippy::too_many_arguments)]
+    fn add_pmem_device(
+        &mut self,
+        id: String,
+        file: File,
+        mmap_region: MmapRegion<AtomicBitmap>,
+        region_base: u64,
+        iommu: bool,
+        pci_segment: u16,
+        msync: bool,
+    ) -> DeviceManagerResult<MetaVirtioDevice> {
+        let mut node = device_node!(id);
+
+        let region_size = mmap_region.size() as u64;
         let host_addr: u64 = mmap_region.as_ptr() as u64;
 
         let mem_slot = self
@@ -2869,23 +3473,23 @@ impl DeviceManager {
             mergeable: false,
         };
 
-        let virtio_pmem_device = Arc::new(Mutex::new(
-            virtio_devices::Pmem::new(
-                id.clone(),
-                file,
-                GuestAddress(region_base),
-                mapping,
-                mmap_region,
-                self.force_iommu | pmem_cfg.iommu,
-                self.seccomp_action.clone(),
-                self.exit_evt
-                    .try_clone()
-                    .map_err(DeviceManagerError::EventFd)?,
-                state_from_id(self.snapshot.as_ref(), id.as_str())
-                    .map_err(DeviceManagerError::RestoreGetState)?,
-            )
-            .map_err(DeviceManagerError::CreateVirtioPmem)?,
-        ));
+        let mut virtio_pmem_device = virtio_devices::Pmem::new(
+            id.clone(),
+            file,
+            GuestAddress(region_base),
+            mapping,
+            mmap_region,
+            self.force_iommu | iommu,
+            self.seccomp_action.clone(),
+            self.exit_evt
+                .try_clone()
+                .map_err(DeviceManagerError::EventFd)?,
+            state_from_id(self.snapshot.as_ref(), id.as_str())
+                .map_err(DeviceManagerError::RestoreGetState)?,
+        )
+        .map_err(DeviceManagerError::CreateVirtioPmem)?;
+        virtio_pmem_device.set_msync(msync);
+        let virtio_pmem_device = Arc::new(Mutex::new(virtio_pmem_device));
 
         // Update the device tree with correct resource information and with
         // the migratable device.
@@ -2899,9 +3503,9 @@ impl DeviceManager {
         Ok(MetaVirtioDevice {
             virtio_device: Arc::clone(&virtio_pmem_device)
                 as Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
-            iommu: pmem_cfg.iommu,
+            iommu,
             id,
-            pci_segment: pmem_cfg.pci_segment,
+            pci_segment,
             dma_handler: None,
         })
     }
@@ -4204,6 +4808,9 @@ impl DeviceManager {
 
             self.virtio_devices
                 .retain(|handler| !Arc::ptr_eq(&handler.virtio_device, &virtio_device));
+            self.block_devices.retain(|(block_id, _)| *block_id != id);
+            #[cfg(feature = "luks")]
+            self.luks_disks.remove(&id);
         }
 
         event!(
@@ -4329,6 +4936,301 @@ impl DeviceManager {
         self.hotplug_virtio_pci_device(device)
     }
 
+    /// Flushes every virtio-blk disk at once, returning once all of them are
+    /// done. The disks failing to flush are all reported, so that the data
+    /// of a VM spread over several disks isn't assumed to be persisted when
+    /// only part of it is.
+    pub fn flush_disks(&self) -> DeviceManagerResult<()> {
+        let failures: Vec<(String, virtio_devices::block::Error)> = thread::scope(|s| {
+            let flushes: Vec<_> = self
+                .block_devices
+                .iter()
+                .map(|(id, block)| (id, s.spawn(|| block.lock().unwrap().flush())))
+                .collect();
+            flushes
+                .into_iter()
+                .filter_map(|(id, flush)| match flush.join().unwrap() {
+                    Ok(()) => None,
+                    Err(e) => {
+                        error!("Failed flushing disk {}: {}", id, e);
+                        Some((id.clone(), e))
+                    }
+                })
+                .collect()
+        });
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(DeviceManagerError::FlushDisks(failures))
+        }
+    }
+
+    /// Retries the writes to the virtio-blk disk `id` paused with the host
+    /// storage full.
+    pub fn resume_disk(&self, id: &str) -> DeviceManagerResult<()> {
+        let (_, block) = self
+            .block_devices
+            .iter()
+            .find(|(block_id, _)| block_id == id)
+            .ok_or_else(|| DeviceManagerError::UnknownDeviceId(id.to_owned()))?;
+
+        block
+            .lock()
+            .unwrap()
+            .resume_out_of_space()
+            .map_err(DeviceManagerError::ResumeDisk)
+    }
+
+    /// Inserts the RAW image at `path` into the removable virtio-blk disk
+    /// `id`, in place of its current medium, or ejects the medium if none
+    /// is given. The image is opened as the disk was, and served by the
+    /// synchronous RAW backend.
+    pub fn change_medium(&self, id: &str, path: Option<PathBuf>) -> DeviceManagerResult<()> {
+        let (_, block) = self
+            .block_devices
+            .iter()
+            .find(|(block_id, _)| block_id == id)
+            .ok_or_else(|| DeviceManagerError::UnknownDeviceId(id.to_owned()))?;
+
+        let mut config = self.config.lock().unwrap();
+        let disk_cfg = config
+            .disks
+            .iter_mut()
+            .flatten()
+            .find(|disk_cfg| disk_cfg.id.as_deref() == Some(id))
+            .ok_or_else(|| DeviceManagerError::UnknownDeviceId(id.to_owned()))?;
+
+        let medium = if let Some(path) = &path {
+            let mut options = OpenOptions::new();
+            options.read(true);
+            options.write(!disk_cfg.readonly);
+            let mut flags = disk_cfg.cache.open_flags();
+            if disk_cfg.direct {
+                flags |= libc::O_DIRECT;
+            }
+            if flags != 0 {
+                options.custom_flags(flags);
+            }
+            let mut file = options.open(path).map_err(DeviceManagerError::Disk)?;
+            if !matches!(disk_image_type(disk_cfg, &mut file, path)?, ImageType::Raw) {
+                return Err(DeviceManagerError::UnsupportedMediumImageType);
+            }
+
+            let disk = RawFileDiskSync::new(
+                file,
+                disk_cfg.readonly,
+                disk_cfg.cache,
+                disk_cfg.logical_block_size,
+            )
+            .map_err(DeviceManagerError::CreateRawFileDiskSync)?;
+            let image = Box::new(DirtyTrackingDisk::new(Box::new(disk))) as Box<dyn DiskFile>;
+            Some((image, path.clone()))
+        } else {
+            None
+        };
+
+        block
+            .lock()
+            .unwrap()
+            .change_medium(medium)
+            .map_err(DeviceManagerError::ChangeMedium)?;
+        // A disk opened from its path reboots with the medium it was left
+        // with.
+        if path.is_some() {
+            disk_cfg.path = path;
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the rate limits of the virtio-blk disk `id` with the ones
+    /// given, and returns those now enforced. The limits are changed in
+    /// place, without detaching the disk nor dropping the requests held.
+    pub fn update_disk_rate_limits(
+        &self,
+        id: &str,
+        limits: DiskRateLimits,
+    ) -> DeviceManagerResult<DiskRateLimits> {
+        let (_, block) = self
+            .block_devices
+            .iter()
+            .find(|(block_id, _)| block_id == id)
+            .ok_or_else(|| DeviceManagerError::UnknownDeviceId(id.to_owned()))?;
+
+        let mut config = self.config.lock().unwrap();
+        let disk_cfg = config
+            .disks
+            .iter_mut()
+            .flatten()
+            .find(|disk_cfg| disk_cfg.id.as_deref() == Some(id))
+            .ok_or_else(|| DeviceManagerError::UnknownDeviceId(id.to_owned()))?;
+
+        // The limits of a named group are shared with the other disks in it.
+        if limits.rate_limiter_config.is_some() && disk_cfg.rate_limiter_config.is_none() {
+            if let Some(rate_limit_group) = &disk_cfg.rate_limit_group {
+                return Err(DeviceManagerError::SharedRateLimitGroup(
+                    rate_limit_group.clone(),
+                ));
+            }
+        }
+
+        let block = block.lock().unwrap();
+        // None of the limits change unless they all can.
+        for (limiter, requested) in [
+            (BlockRateLimiter::All, &limits.rate_limiter_config),
+            (BlockRateLimiter::Read, &limits.read_rate_limiter_config),
+            (BlockRateLimiter::Write, &limits.write_rate_limiter_config),
+        ] {
+            if requested.is_some() && block.rate_limits(limiter).is_none() {
+                return Err(DeviceManagerError::UpdateRateLimits(
+                    virtio_devices::block::Error::NoRateLimiter(limiter),
+                ));
+            }
+        }
+        for (limiter, requested, configured) in [
+            (
+                BlockRateLimiter::All,
+                limits.rate_limiter_config,
+                &mut disk_cfg.rate_limiter_config,
+            ),
+            (
+                BlockRateLimiter::Read,
+                limits.read_rate_limiter_config,
+                &mut disk_cfg.read_rate_limiter_config,
+            ),
+            (
+                BlockRateLimiter::Write,
+                limits.write_rate_limiter_config,
+                &mut disk_cfg.write_rate_limiter_config,
+            ),
+        ] {
+            if let Some(requested) = requested {
+                block
+                    .update_rate_limits(limiter, &requested)
+                    .map_err(DeviceManagerError::UpdateRateLimits)?;
+                // The disk reboots with the limits it was left with.
+                *configured = Some(requested);
+            }
+        }
+
+        Ok(DiskRateLimits {
+            rate_limiter_config: block.rate_limits(BlockRateLimiter::All),
+            read_rate_limiter_config: block.rate_limits(BlockRateLimiter::Read),
+            write_rate_limiter_config: block.rate_limits(BlockRateLimiter::Write),
+        })
+    }
+
+    /// Wraps the volume key of the LUKS encrypted disk `id` in a new keyslot
+    /// unlocked by the passphrase read from `passphrase_file`, and wipes the
+    /// keyslot the disk was unlocked with, or added by the previous rotation.
+    /// The data isn't re-encrypted, so the guest keeps using the disk
+    /// throughout.
+    #[cfg(feature = "luks")]
+    pub fn rotate_disk_key(
+        &mut self,
+        id: &str,
+        passphrase_file: PathBuf,
+    ) -> DeviceManagerResult<()> {
+        let luks_disk = self
+            .luks_disks
+            .get_mut(id)
+            .ok_or_else(|| DeviceManagerError::NotLuksDisk(id.to_owned()))?;
+        let passphrase: luks::SecretBytes = std::fs::read(&passphrase_file)
+            .map_err(DeviceManagerError::ReadLuksKey)?
+            .into();
+
+        // The header is written through its own open file, not sharing the
+        // O_DIRECT flag of the disk.
+        let result = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(format!("/proc/self/fd/{}", luks_disk.header.as_raw_fd()))
+            .map_err(DeviceManagerError::Disk)
+            .and_then(|header| {
+                luks_disk
+                    .volume
+                    .rotate_passphrase(&header, &passphrase)
+                    .map_err(DeviceManagerError::RotateLuksKey)
+            });
+        let keyslot = match result {
+            Ok(keyslot) => keyslot,
+            Err(e) => {
+                luks_disk.rotation_failures += Wrapping(1);
+                return Err(e);
+            }
+        };
+        luks_disk.rotations += Wrapping(1);
+        info!(
+            "Rotated the LUKS passphrase of disk {}, now in keyslot {}",
+            id, keyslot
+        );
+
+        // A disk unlocked with its passphrase reboots with the new one, the
+        // previous one no longer unlocking it. A volume key stays valid.
+        let mut config = self.config.lock().unwrap();
+        if let Some(disk_cfg) = config
+            .disks
+            .iter_mut()
+            .flatten()
+            .find(|disk_cfg| disk_cfg.id.as_deref() == Some(id))
+        {
+            if disk_cfg.luks_passphrase_file.is_some() {
+                disk_cfg.luks_passphrase_file = Some(passphrase_file);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "luks"))]
+    pub fn rotate_disk_key(
+        &mut self,
+        id: &str,
+        _passphrase_file: PathBuf,
+    ) -> DeviceManagerResult<()> {
+        Err(DeviceManagerError::NotLuksDisk(id.to_owned()))
+    }
+
+    /// Describes every virtio-blk disk, as configured and as currently
+    /// served.
+    pub fn disks(&self) -> Vec<DiskInfo> {
+        let config = self.config.lock().unwrap();
+        self.block_devices
+            .iter()
+            .map(|(id, block)| {
+                let disk_cfg = config
+                    .disks
+                    .iter()
+                    .flatten()
+                    .find(|disk_cfg| disk_cfg.id.as_ref() == Some(id));
+                let block = block.lock().unwrap();
+                DiskInfo {
+                    id: id.clone(),
+                    path: disk_cfg.and_then(|disk_cfg| disk_cfg.path.clone()),
+                    readonly: block.read_only(),
+                    cache: disk_cfg.map(|disk_cfg| disk_cfg.cache).unwrap_or_default(),
+                    backend: block.backend(),
+                    num_queues: block.num_queues(),
+                    logical_block_size: block.logical_block_size(),
+                    capacity: block.capacity(),
+                    out_of_space: block.out_of_space(),
+                    removable: block.removable(),
+                    available_space: block.available_space(),
+                    queue_affinity: block
+                        .queue_cpus()
+                        .iter()
+                        .map(|(queue_index, host_cpus)| VirtQueueAffinity {
+                            queue_index: *queue_index,
+                            host_cpus: host_cpus.clone(),
+                        })
+                        .collect(),
+                    stats: block.stats(),
+                }
+            })
+            .collect()
+    }
+
     pub fn counters(&self) -> HashMap<String, HashMap<&'static str, Wrapping<u64>>> {
         let mut counters = HashMap::new();
 
@@ -4339,6 +5241,14 @@ impl DeviceManager {
             }
         }
 
+        #[cfg(feature = "luks")]
+        for (id, luks_disk) in &self.luks_disks {
+            if let Some(device_counters) = counters.get_mut(id) {
+                device_counters.insert("luks_key_rotations", luks_disk.rotations);
+                device_counters.insert("luks_key_rotation_failures", luks_disk.rotation_failures);
+            }
+        }
+
         counters
     }
 
@@ -4426,6 +5336,48 @@ impl DeviceManager {
     }
 }
 
+// Unlocked LUKS volume of a virtio-blk device, with the handle its header is
+// read through.
+#[cfg(feature = "luks")]
+struct LuksDisk {
+    header: File,
+    volume: Arc<LuksVolume>,
+    rotations: Wrapping<u64>,
+    rotation_failures: Wrapping<u64>,
+}
+
+// Reads the key material unlocking the LUKS volume of the disk. Like
+// cryptsetup --key-file, the whole passphrase file is used, including any
+// trailing newline.
+#[cfg(feature = "luks")]
+fn luks_key(disk_cfg: &DiskConfig) -> DeviceManagerResult<LuksKey> {
+    if let Some(path) = disk_cfg.luks_key_file.as_ref() {
+        let key = std::fs::read(path).map_err(DeviceManagerError::ReadLuksKey)?;
+        return Ok(LuksKey::VolumeKey(key.into()));
+    }
+
+    let path = disk_cfg.luks_passphrase_file.as_ref().unwrap();
+    let passphrase = std::fs::read(path).map_err(DeviceManagerError::ReadLuksKey)?;
+    Ok(LuksKey::Passphrase(passphrase.into()))
+}
+
+// Turns the read and write rate limiters of a disk into the limits of the
+// io controller, which have no burst.
+fn io_max(
+    read: Option<&RateLimiterConfig>,
+    write: Option<&RateLimiterConfig>,
+) -> block::cgroup::IoMax {
+    let rate = |bucket: Option<TokenBucketConfig>| {
+        bucket.map(|bucket| bucket.size * 1000 / bucket.refill_time.max(1))
+    };
+    block::cgroup::IoMax {
+        rbps: rate(read.and_then(|cfg| cfg.bandwidth)),
+        wbps: rate(write.and_then(|cfg| cfg.bandwidth)),
+        riops: rate(read.and_then(|cfg| cfg.ops)),
+        wiops: rate(write.and_then(|cfg| cfg.ops)),
+    }
+}
+
 fn numa_node_id_from_memory_zone_id(numa_nodes: &NumaNodes, memory_zone_id: &str) -> Option<u32> {
     for (numa_node_id, numa_node) in numa_nodes.iter() {
         if numa_node.memory_zones.contains(&memory_zone_id.to_owned()) {
@@ -4871,6 +5823,7 @@ impl Drop for DeviceManager {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::Write;
 
     #[test]
     fn test_create_mmio_allocators() {
@@ -4923,4 +5876,33 @@ mod tests {
             vm_memory::GuestAddress(0x3fffff)
         );
     }
+
+    #[test]
+    fn test_disk_image_type() {
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap();
+        let mut header = vec![0u8; 4096];
+        header[..4].copy_from_slice(b"QFI\xfb");
+        file.as_file().write_all(&header).unwrap();
+        let image_type = |disk: &str| {
+            let disk_cfg = DiskConfig::parse(disk).unwrap();
+            disk_image_type(
+                &disk_cfg,
+                &mut File::open(file.as_path()).unwrap(),
+                file.as_path(),
+            )
+            .unwrap()
+        };
+
+        // The image type is detected from the content of the file, unless
+        // one is given.
+        assert_eq!(image_type("path=/path/to/disk.img"), ImageType::Qcow2);
+        assert_eq!(
+            image_type("path=/path/to/disk.img,image_type=raw"),
+            ImageType::Raw
+        );
+        assert_eq!(
+            image_type("path=/path/to/disk.img,image_type=qcow2"),
+            ImageType::Qcow2
+        );
+    }
 }
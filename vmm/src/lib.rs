@@ -14,7 +14,7 @@ use crate::api::{
 };
 use crate::config::{
     add_to_config, DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, RestoreConfig,
-    UserDeviceConfig, VdpaConfig, VmConfig, VsockConfig,
+    UserDeviceConfig, VdpaConfig, VirtQueueAffinity, VmConfig, VsockConfig,
 };
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use crate::coredump::GuestDebuggable;
@@ -299,8 +299,50 @@ impl Serialize for PciDeviceInfo {
     }
 }
 
+/// Description of a virtio-blk disk attached to the VM, along with its
+/// current statistics.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DiskInfo {
+    pub id: String,
+    /// Image backing the disk, unset for scratch disks and disks opened from
+    /// a file descriptor.
+    pub path: Option<PathBuf>,
+    pub readonly: bool,
+    pub cache: block::CacheMode,
+    pub backend: block::AsyncIoBackend,
+    pub num_queues: u16,
+    pub logical_block_size: u32,
+    /// Size of the disk, in bytes.
+    pub capacity: u64,
+    /// Whether the writes are paused with the host storage full.
+    pub out_of_space: bool,
+    /// Whether the medium of the disk can be changed, a removable disk
+    /// without medium having a capacity of 0.
+    pub removable: bool,
+    /// Bytes the host filesystem holding the image can still allocate, unset
+    /// for block devices and for the image formats other than raw.
+    pub available_space: Option<u64>,
+    /// Host CPUs the thread of each active queue is pinned to, the queues
+    /// left to the scheduler being omitted.
+    pub queue_affinity: Vec<VirtQueueAffinity>,
+    pub stats: virtio_devices::BlockStats,
+}
+
+/// Rate limits of a virtio-blk disk, the limiters it lacks being unset.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct DiskRateLimits {
+    /// Limits of all the requests of the disk.
+    pub rate_limiter_config: Option<virtio_devices::RateLimiterConfig>,
+    /// Limits of the reads only.
+    pub read_rate_limiter_config: Option<virtio_devices::RateLimiterConfig>,
+    /// Limits of the writes only.
+    pub write_rate_limiter_config: Option<virtio_devices::RateLimiterConfig>,
+}
+
 pub fn feature_list() -> Vec<String> {
     vec![
+        #[cfg(feature = "compressed_import")]
+        "compressed_import".to_string(),
         #[cfg(feature = "dbus_api")]
         "dbus_api".to_string(),
         #[cfg(feature = "dhat-heap")]
@@ -313,8 +355,12 @@ pub fn feature_list() -> Vec<String> {
         "io_uring".to_string(),
         #[cfg(feature = "kvm")]
         "kvm".to_string(),
+        #[cfg(feature = "luks")]
+        "luks".to_string(),
         #[cfg(feature = "mshv")]
         "mshv".to_string(),
+        #[cfg(feature = "qcow_compression")]
+        "qcow_compression".to_string(),
         #[cfg(feature = "sev_snp")]
         "sev_snp".to_string(),
         #[cfg(feature = "tdx")]
@@ -1881,6 +1927,17 @@ impl RequestHandler for Vmm {
         }
     }
 
+    fn vm_disks(&mut self) -> result::Result<Option<Vec<u8>>, VmError> {
+        if let Some(ref mut vm) = self.vm {
+            let disks = vm.disks();
+            serde_json::to_vec(&disks)
+                .map(Some)
+                .map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
     fn vm_power_button(&mut self) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
             vm.power_button()
@@ -1897,6 +1954,61 @@ impl RequestHandler for Vmm {
         }
     }
 
+    fn vm_flush_disks(&mut self) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            vm.flush_disks()
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_resume_disk(&mut self, id: String) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            vm.resume_disk(&id)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_change_medium(
+        &mut self,
+        id: String,
+        path: Option<PathBuf>,
+    ) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            vm.change_medium(&id, path)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_update_disk_rate_limits(
+        &mut self,
+        id: String,
+        limits: DiskRateLimits,
+    ) -> result::Result<Option<Vec<u8>>, VmError> {
+        if let Some(ref vm) = self.vm {
+            let limits = vm.update_disk_rate_limits(&id, limits)?;
+            serde_json::to_vec(&limits)
+                .map(Some)
+                .map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_rotate_disk_key(
+        &mut self,
+        id: String,
+        passphrase_file: PathBuf,
+    ) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            vm.rotate_disk_key(&id, passphrase_file)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
     fn vm_receive_migration(
         &mut self,
         receive_data_migration: VmReceiveMigrationData,
@@ -56,6 +56,21 @@ const BLKSSZGET: u64 = 0x1268;
 const BLKPBSZGET: u64 = 0x127b;
 const BLKIOMIN: u64 = 0x1278;
 const BLKIOOPT: u64 = 0x1279;
+const BLKGETSIZE64: u64 = 0x8008_1272;
+
+// See include/uapi/linux/blkzoned.h in the kernel code.
+const BLKGETZONESZ: u64 = 0x8004_1284;
+const BLKGETNRZONES: u64 = 0x8004_1285;
+
+// See include/uapi/linux/dm-ioctl.h in the kernel code.
+const DM_TABLE_STATUS: u64 = 0xc138_fd0c;
+
+// See include/scsi/sg.h in the kernel code.
+const SG_GET_VERSION_NUM: u64 = 0x2282;
+const SG_IO: u64 = 0x2285;
+
+// See include/uapi/linux/nvme_ioctl.h in the kernel code.
+const NVME_IOCTL_ADMIN_CMD: u64 = 0xc048_4e41;
 
 // See include/uapi/linux/if_tun.h in the kernel code.
 const TUNGETIFF: u64 = 0x8004_54d2;
@@ -310,6 +325,13 @@ fn create_vmm_ioctl_seccomp_rule_common(
         and![Cond::new(1, ArgLen::Dword, Eq, BLKPBSZGET)?],
         and![Cond::new(1, ArgLen::Dword, Eq, BLKIOMIN)?],
         and![Cond::new(1, ArgLen::Dword, Eq, BLKIOOPT)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, BLKGETSIZE64)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, BLKGETZONESZ)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, BLKGETNRZONES)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, DM_TABLE_STATUS)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, SG_GET_VERSION_NUM)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, SG_IO)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, NVME_IOCTL_ADMIN_CMD)?],
         and![Cond::new(1, ArgLen::Dword, Eq, FIOCLEX)?],
         and![Cond::new(1, ArgLen::Dword, Eq, FIONBIO)?],
         and![Cond::new(1, ArgLen::Dword, Eq, SIOCGIFFLAGS)?],
@@ -585,6 +607,7 @@ fn vmm_thread_rules(
         #[cfg(target_arch = "aarch64")]
         (libc::SYS_newfstatat, vec![]),
         (libc::SYS_futex, vec![]),
+        (libc::SYS_get_mempolicy, vec![]),
         (libc::SYS_getdents64, vec![]),
         (libc::SYS_getpgid, vec![]),
         #[cfg(target_arch = "x86_64")]
@@ -606,6 +629,7 @@ fn vmm_thread_rules(
         (libc::SYS_io_uring_enter, vec![]),
         (libc::SYS_io_uring_setup, vec![]),
         (libc::SYS_io_uring_register, vec![]),
+        (libc::SYS_ioprio_set, vec![]),
         (libc::SYS_kill, vec![]),
         (libc::SYS_listen, vec![]),
         (libc::SYS_lseek, vec![]),
@@ -629,6 +653,7 @@ fn vmm_thread_rules(
         (libc::SYS_prctl, vec![]),
         (libc::SYS_pread64, vec![]),
         (libc::SYS_preadv, vec![]),
+        (libc::SYS_preadv2, vec![]),
         (libc::SYS_prlimit64, vec![]),
         (libc::SYS_pwrite64, vec![]),
         (libc::SYS_pwritev, vec![]),
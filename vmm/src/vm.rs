@@ -38,7 +38,8 @@ use crate::migration::url_to_file;
 use crate::migration::{url_to_path, SNAPSHOT_CONFIG_FILE, SNAPSHOT_STATE_FILE};
 use crate::GuestMemoryMmap;
 use crate::{
-    PciDeviceInfo, CPU_MANAGER_SNAPSHOT_ID, DEVICE_MANAGER_SNAPSHOT_ID, MEMORY_MANAGER_SNAPSHOT_ID,
+    DiskInfo, DiskRateLimits, PciDeviceInfo, CPU_MANAGER_SNAPSHOT_ID, DEVICE_MANAGER_SNAPSHOT_ID,
+    MEMORY_MANAGER_SNAPSHOT_ID,
 };
 use anyhow::anyhow;
 use arch::get_host_cpu_phys_bits;
@@ -81,6 +82,7 @@ use std::mem::size_of;
 use std::num::Wrapping;
 use std::ops::Deref;
 use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex, RwLock};
 use std::time::Instant;
 use std::{result, str, thread};
@@ -1721,6 +1723,10 @@ impl Vm {
         Ok(self.device_manager.lock().unwrap().counters())
     }
 
+    pub fn disks(&self) -> Vec<DiskInfo> {
+        self.device_manager.lock().unwrap().disks()
+    }
+
     #[cfg(feature = "tdx")]
     fn extract_tdvf_sections(&mut self) -> Result<(Vec<TdvfSection>, bool)> {
         use arch::x86_64::tdx::*;
@@ -2432,6 +2438,78 @@ impl Vm {
             + size_of::<elf::Elf64_Phdr>() as u64 * phdr_num as u64
     }
 
+    /// Flushes every disk, returning once all of them are done. The devices
+    /// of a running VM are paused meanwhile, for no write to be in flight.
+    pub fn flush_disks(&mut self) -> Result<()> {
+        let running = self.get_state()? == VmState::Running;
+        if running {
+            self.device_manager
+                .lock()
+                .unwrap()
+                .pause()
+                .map_err(Error::Pause)?;
+        }
+        let result = self
+            .device_manager
+            .lock()
+            .unwrap()
+            .flush_disks()
+            .map_err(Error::DeviceManager);
+        if running {
+            self.device_manager
+                .lock()
+                .unwrap()
+                .resume()
+                .map_err(Error::Resume)?;
+        }
+
+        result
+    }
+
+    /// Retries the writes to the disk `id` paused with the host storage
+    /// full, once room has been made for them.
+    pub fn resume_disk(&self, id: &str) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .resume_disk(id)
+            .map_err(Error::DeviceManager)
+    }
+
+    /// Changes the medium of the removable disk `id` to the image at
+    /// `path`, or ejects it.
+    pub fn change_medium(&self, id: &str, path: Option<PathBuf>) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .change_medium(id, path)
+            .map_err(Error::DeviceManager)
+    }
+
+    /// Replaces the rate limits of the disk `id`, and returns the ones now
+    /// enforced.
+    pub fn update_disk_rate_limits(
+        &self,
+        id: &str,
+        limits: DiskRateLimits,
+    ) -> Result<DiskRateLimits> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .update_disk_rate_limits(id, limits)
+            .map_err(Error::DeviceManager)
+    }
+
+    /// Rotates the passphrase of the LUKS encrypted disk `id` to the one
+    /// read from `passphrase_file`.
+    pub fn rotate_disk_key(&self, id: &str, passphrase_file: PathBuf) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .rotate_disk_key(id, passphrase_file)
+            .map_err(Error::DeviceManager)
+    }
+
     pub fn nmi(&self) -> Result<()> {
         return self
             .cpu_manager
@@ -2,6 +2,10 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 //
+use block::{
+    import::ImportCompression, preallocate::PreallocationMode, readahead::FadviseMode,
+    AsyncIoBackend, CacheMode, EnospcPolicy, ImageType, TimeoutPolicy,
+};
 use net_util::MacAddr;
 use serde::{Deserialize, Serialize};
 use std::{net::Ipv4Addr, path::PathBuf};
@@ -209,6 +213,25 @@ pub struct VirtQueueAffinity {
     pub host_cpus: Vec<usize>,
 }
 
+/// Faults injected into the requests to a disk, to test how the guest copes
+/// with misbehaving host storage.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FaultInjectionConfig {
+    /// Percentage of the requests failed at random.
+    #[serde(default)]
+    pub rate: u8,
+    /// Offsets in bytes failing the requests whose range covers one of them.
+    #[serde(default)]
+    pub offsets: Vec<u64>,
+    /// Number of requests let through before all of them fail.
+    #[serde(default)]
+    pub after: Option<u64>,
+    /// Delay in milliseconds every request is handed over to the backend
+    /// after.
+    #[serde(default)]
+    pub latency: u64,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct DiskConfig {
     pub path: Option<PathBuf>,
@@ -230,6 +253,10 @@ pub struct DiskConfig {
     #[serde(default)]
     pub rate_limiter_config: Option<RateLimiterConfig>,
     #[serde(default)]
+    pub read_rate_limiter_config: Option<RateLimiterConfig>,
+    #[serde(default)]
+    pub write_rate_limiter_config: Option<RateLimiterConfig>,
+    #[serde(default)]
     pub id: Option<String>,
     // For testing use only. Not exposed in API.
     #[serde(default)]
@@ -237,12 +264,180 @@ pub struct DiskConfig {
     // For testing use only. Not exposed in API.
     #[serde(default)]
     pub disable_aio: bool,
+    // For testing use only. Not exposed in API.
+    #[serde(default)]
+    pub fault_injection: Option<FaultInjectionConfig>,
     #[serde(default)]
     pub pci_segment: u16,
     #[serde(default)]
     pub serial: Option<String>,
     #[serde(default)]
     pub queue_affinity: Option<Vec<VirtQueueAffinity>>,
+    #[serde(default)]
+    pub latency_histograms: bool,
+    /// File holding the passphrase unlocking the LUKS2 volume of the disk.
+    #[serde(default)]
+    pub luks_passphrase_file: Option<PathBuf>,
+    /// File holding the raw volume key of the LUKS2 volume of the disk.
+    #[serde(default)]
+    pub luks_key_file: Option<PathBuf>,
+    #[serde(default)]
+    pub cache: CacheMode,
+    /// Window in milliseconds during which the guest flushes are coalesced
+    /// into a single fsync.
+    #[serde(default)]
+    pub flush_window: Option<u64>,
+    /// Interval in milliseconds without any write after which the disk is
+    /// flushed in the background.
+    #[serde(default)]
+    pub idle_flush: Option<u64>,
+    /// Read the whole image in the background, while the disk is idle.
+    #[serde(default)]
+    pub scrub: bool,
+    /// Checksums the image read by the scrubber is checked against.
+    #[serde(default)]
+    pub scrub_manifest: Option<PathBuf>,
+    /// Directory in which to create an anonymous scratch disk, instead of
+    /// opening an existing image.
+    #[serde(default)]
+    pub tmpfile: Option<PathBuf>,
+    /// Size in bytes of the scratch disk.
+    #[serde(default)]
+    pub size: Option<u64>,
+    /// How much of the scratch disk is allocated upfront.
+    #[serde(default)]
+    pub preallocate: PreallocationMode,
+    /// Largest request in bytes contiguous reads or writes are merged into.
+    #[serde(default)]
+    pub max_merge_size: Option<u64>,
+    /// Maximum number of requests in flight on each queue of the disk.
+    #[serde(default)]
+    pub max_inflight: Option<usize>,
+    /// Register the guest memory with the io_uring backend.
+    #[serde(default)]
+    pub fixed_buffers: bool,
+    /// Stream, such as a named pipe, the RAW image is imported from when
+    /// the disk path doesn't exist yet.
+    #[serde(default)]
+    pub import_source: Option<PathBuf>,
+    /// Compression of the imported stream.
+    #[serde(default)]
+    pub import_compression: ImportCompression,
+    /// Logical block size in bytes reported to the guest for a RAW disk,
+    /// also used as the alignment of the requests with O_DIRECT, detected
+    /// from the file when not provided.
+    #[serde(default)]
+    pub logical_block_size: Option<u64>,
+    /// Physical block size in bytes reported to the guest for a RAW disk.
+    #[serde(default)]
+    pub physical_block_size: Option<u64>,
+    /// Access pattern advertised to the host page cache for a RAW disk.
+    #[serde(default)]
+    pub fadvise: FadviseMode,
+    /// Bytes read ahead of the sequential reads of a RAW disk.
+    #[serde(default)]
+    pub readahead: Option<u64>,
+    /// Retries of the RAW disk requests failing with EAGAIN or EINTR.
+    #[serde(default)]
+    pub io_retries: u32,
+    /// Completions of a RAW disk signaled at once, 0 signaling each of them.
+    #[serde(default)]
+    pub completion_batch: u32,
+    /// Whether the disk is rotational, detected from the host storage if
+    /// not set.
+    #[serde(default)]
+    pub rotational: Option<bool>,
+    /// cgroup v2 throttling the disk according to its read and write rate
+    /// limiters, in place of the userspace ones.
+    #[serde(default)]
+    pub io_cgroup: Option<PathBuf>,
+    /// Backend serving the requests to the disk, chosen according to the
+    /// host and the image by default.
+    #[serde(default)]
+    pub backend: AsyncIoBackend,
+    /// Format the disk image is opened as, in place of the one detected from
+    /// its content.
+    #[serde(default)]
+    pub image_type: Option<ImageType>,
+    /// Descriptor of the disk, opened by the parent process in place of the
+    /// path, and only meaningful within this process.
+    #[serde(
+        default,
+        serialize_with = "serialize_diskconfig_fd",
+        deserialize_with = "deserialize_diskconfig_fd"
+    )]
+    pub fd: Option<i32>,
+    /// Whether the writes failing with the host storage full are reported to
+    /// the guest, or held until the disk is resumed.
+    #[serde(default)]
+    pub enospc: EnospcPolicy,
+    /// Host NUMA node the queue threads without an affinity are pinned to,
+    /// the node holding the memory of each queue if not set.
+    #[serde(default)]
+    pub host_numa_node: Option<u32>,
+    /// Host CPU the kernel polls the submission queue of each io_uring
+    /// instance from, the requests being submitted by the queue threads if
+    /// not set.
+    #[serde(default)]
+    pub sqpoll_cpu: Option<u32>,
+    /// Map the disk into the guest as the memory of a virtio-pmem device,
+    /// rather than serving it through the queues of a virtio-blk device.
+    #[serde(default)]
+    pub dax: bool,
+    /// Path to the key shared with the vhost-user backend, from which both
+    /// ends authenticate each other when connecting.
+    #[serde(default)]
+    pub vhost_auth_key: Option<PathBuf>,
+    /// Release all the blocks of the disk file as the device is created,
+    /// for the guest to find it zeroed.
+    #[serde(default)]
+    pub discard_on_start: bool,
+    /// Complete the requests of each queue in the order they were submitted
+    /// to the backend, for debugging.
+    #[serde(default)]
+    pub ordered_completions: bool,
+    /// Time in milliseconds after which the requests still in flight are
+    /// reported.
+    #[serde(default)]
+    pub request_timeout: Option<u64>,
+    /// Whether the requests in flight past the request timeout are only
+    /// reported, or also canceled.
+    #[serde(default)]
+    pub request_timeout_policy: TimeoutPolicy,
+    /// Whether the ranges discarded read back as zeroes, in place of what
+    /// the backend reports. When they may not, the discards are served as
+    /// write zeroes.
+    #[serde(default)]
+    pub discard_zeroes: Option<bool>,
+    /// Whether the medium of the disk can be changed at runtime, as the one
+    /// of a CD-ROM drive.
+    #[serde(default)]
+    pub removable: bool,
+}
+
+fn serialize_diskconfig_fd<S>(x: &Option<i32>, s: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    if x.is_some() {
+        warn!("'DiskConfig' contains an FD that can't be serialized correctly. Serializing it as an invalid FD.");
+        s.serialize_some(&-1)
+    } else {
+        s.serialize_none()
+    }
+}
+
+fn deserialize_diskconfig_fd<'de, D>(d: D) -> Result<Option<i32>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let invalid_fd: Option<i32> = Option::deserialize(d)?;
+    if invalid_fd.is_some() {
+        warn!("'DiskConfig' contains an FD that can't be deserialized correctly. Deserializing it as an invalid FD.");
+        Ok(Some(-1))
+    } else {
+        Ok(None)
+    }
 }
 
 pub const DEFAULT_DISK_NUM_QUEUES: usize = 1;